@@ -57,6 +57,41 @@ fn bench_fastcdc(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_fastcdc2020(c: &mut Criterion) {
+    let (zeros, random) = get_test_data(SIZE);
+    // Intentinally leak zeros and random to get an &'static
+    let zeros: &'static [u8] = Box::leak(Box::new(zeros));
+    let random: &'static [u8] = Box::leak(Box::new(random));
+    let mut group = c.benchmark_group("fastcdc2020");
+
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.measurement_time(Duration::new(30, 0));
+    group.sample_size(30);
+
+    group.bench_function("boxed zeros", |b| {
+        b.iter(|| chunk_boxed(black_box(zeros), FastCDC2020::default()))
+    });
+
+    for (name, normalization) in &[
+        ("NC1", Normalization::Level1),
+        ("NC2", Normalization::Level2),
+        ("NC3", Normalization::Level3),
+    ] {
+        let chunker = FastCDC2020 {
+            normalization: *normalization,
+            ..FastCDC2020::default()
+        };
+        group.bench_function(format!("boxed random {}", name), |b| {
+            b.iter(|| chunk_boxed(black_box(random), chunker))
+        });
+        group.bench_function(format!("sliced random {}", name), |b| {
+            b.iter(|| chunk_slice(random, chunker))
+        });
+    }
+
+    group.finish();
+}
+
 fn bench_buzhash(c: &mut Criterion) {
     let (zeros, random) = get_test_data(SIZE);
     // Intentinally leak zeros and random to get an &'static
@@ -85,5 +120,5 @@ fn bench_buzhash(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_fastcdc, bench_buzhash);
+criterion_group!(benches, bench_fastcdc, bench_fastcdc2020, bench_buzhash);
 criterion_main!(benches);
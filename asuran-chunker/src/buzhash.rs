@@ -1,9 +1,8 @@
-use super::{Chunker, ChunkerError};
+use super::{ChunkIterator, Chunker, ChunkerError, ChunkerState};
 
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 
-use std::collections::VecDeque;
 use std::io::Read;
 
 /// Settings for a `BuzHash` `Chunker`
@@ -33,8 +32,29 @@ impl BuzHash {
         for (index, item) in table.iter_mut().enumerate() {
             *item = TABLE[index] ^ random_value;
         }
+        Self::with_table(&table, window_size, mask_bits)
+    }
+
+    /// Creates a `BuzHash` with every entry of its lookup table generated from `seed`
+    ///
+    /// `new` only mixes a 64 bit nonce into a fixed table, so chunk boundaries are only as
+    /// unpredictable as that nonce. Here the whole table is drawn from a `ChaCha20` stream
+    /// keyed with `seed`, which should be secret key material, so an attacker without the
+    /// seed can not predict where boundaries fall.
+    ///
+    /// The same seed always produces the same table, and so the same chunks.
+    pub fn with_seed(seed: [u8; 32], window_size: u32, mask_bits: u32) -> BuzHash {
+        let mut table = [0_u64; 256];
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        for item in &mut table {
+            *item = rng.gen();
+        }
+        Self::with_table(&table, window_size, mask_bits)
+    }
+
+    fn with_table(table: &[u64; 256], window_size: u32, mask_bits: u32) -> BuzHash {
         BuzHash {
-            table,
+            table: *table,
             window_size,
             min_size: 2_usize.pow(mask_bits - 2),
             max_size: 2_usize.pow(mask_bits + 2),
@@ -56,85 +76,274 @@ impl BuzHash {
 
 impl Chunker for BuzHash {
     type Chunks = BuzHashChunker;
-    fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
+    /// The rolling hash carries over chunk boundaries, so the state must include its
+    /// window
+    fn chunk_from_boxed(
+        &self,
+        read: Box<dyn Read + Send + 'static>,
+        state: ChunkerState,
+    ) -> Self::Chunks {
+        let position = state.window.len();
         BuzHashChunker {
             settings: *self,
+            kernel: Kernel::detect(),
             read,
-            buffer: VecDeque::new(),
-            hash_buffer: VecDeque::new(),
-            count: 0,
-            hash: 0,
+            data: state.window,
+            position,
+            hash: state.hash,
             eof: false,
+            offset: state.offset,
+        }
+    }
+}
+
+/// The number of bytes hashed per block by the vectorized inner loop
+const BLOCK_SIZE: usize = 64;
+
+/// The implementation used to compute the per byte updates to the rolling hash
+///
+/// Each byte entering the window changes the hash by the table entry of the byte entering,
+/// combined with the rotated table entry of the byte leaving. These updates do not depend on
+/// the hash, so they are computed a block at a time, leaving only a rotate and an xor per byte
+/// in the serial part of the loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kernel {
+    /// Plain table lookups, one byte at a time
+    #[cfg_attr(target_arch = "x86_64", allow(dead_code))]
+    Scalar,
+    /// Two updates at a time with SSE2
+    #[cfg(target_arch = "x86_64")]
+    Sse2,
+    /// Four updates at a time with AVX2 gathers
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+}
+
+impl Kernel {
+    /// Picks the fastest implementation the running CPU supports
+    fn detect() -> Kernel {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                Kernel::Avx2
+            } else {
+                // SSE2 is part of the x86_64 baseline
+                Kernel::Sse2
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            Kernel::Scalar
+        }
+    }
+
+    /// Computes the hash updates for a block of bytes entering the window, along with the
+    /// bytes leaving it
+    fn updates(
+        self,
+        table: &[u64; 256],
+        rotation: u32,
+        incoming: &[u8],
+        outgoing: &[u8],
+        output: &mut [u64; BLOCK_SIZE],
+    ) {
+        assert!(incoming.len() >= BLOCK_SIZE && outgoing.len() >= BLOCK_SIZE);
+        match self {
+            Kernel::Scalar => updates_scalar(table, rotation, incoming, outgoing, output),
+            // Safety: SSE2 is always available on x86_64
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => unsafe {
+                x86::updates_sse2(table, rotation, incoming, outgoing, output);
+            },
+            // Safety: this kernel is only selected when AVX2 support has been detected
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe {
+                x86::updates_avx2(table, rotation, incoming, outgoing, output);
+            },
+        }
+    }
+}
+
+fn updates_scalar(
+    table: &[u64; 256],
+    rotation: u32,
+    incoming: &[u8],
+    outgoing: &[u8],
+    output: &mut [u64; BLOCK_SIZE],
+) {
+    for (index, update) in output.iter_mut().enumerate() {
+        *update =
+            table[incoming[index] as usize] ^ table[outgoing[index] as usize].rotate_left(rotation);
+    }
+}
+
+// The intrinsics take signed integers where the bits are all that matter, and the stores
+// used do not require alignment
+#[cfg(target_arch = "x86_64")]
+#[allow(clippy::cast_possible_wrap, clippy::cast_ptr_alignment)]
+mod x86 {
+    use super::BLOCK_SIZE;
+
+    use std::arch::x86_64::{
+        __m128i, __m256i, _mm256_cvtepu8_epi64, _mm256_i64gather_epi64, _mm256_or_si256,
+        _mm256_sll_epi64, _mm256_srl_epi64, _mm256_storeu_si256, _mm256_xor_si256,
+        _mm_cvtsi32_si128, _mm_or_si128, _mm_set_epi64x, _mm_sll_epi64, _mm_srl_epi64,
+        _mm_storeu_si128, _mm_xor_si128,
+    };
+
+    /// SSE2 has no gathers, so the lookups are still done one at a time, but the rotations
+    /// and xors are done in pairs
+    ///
+    /// # Safety
+    ///
+    /// Both slices must hold at least `BLOCK_SIZE` bytes
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn updates_sse2(
+        table: &[u64; 256],
+        rotation: u32,
+        incoming: &[u8],
+        outgoing: &[u8],
+        output: &mut [u64; BLOCK_SIZE],
+    ) {
+        // Shifting by 64 produces zero, so a rotation of zero still works out
+        let left = _mm_cvtsi32_si128(rotation as i32);
+        let right = _mm_cvtsi32_si128(64 - rotation as i32);
+        for index in (0..BLOCK_SIZE).step_by(2) {
+            let entering = _mm_set_epi64x(
+                table[incoming[index + 1] as usize] as i64,
+                table[incoming[index] as usize] as i64,
+            );
+            let leaving = _mm_set_epi64x(
+                table[outgoing[index + 1] as usize] as i64,
+                table[outgoing[index] as usize] as i64,
+            );
+            let leaving = _mm_or_si128(_mm_sll_epi64(leaving, left), _mm_srl_epi64(leaving, right));
+            _mm_storeu_si128(
+                output.as_mut_ptr().add(index).cast::<__m128i>(),
+                _mm_xor_si128(entering, leaving),
+            );
+        }
+    }
+
+    /// Looks up four table entries at a time with a gather
+    ///
+    /// # Safety
+    ///
+    /// The CPU must support AVX2, and both slices must hold at least `BLOCK_SIZE` bytes
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn updates_avx2(
+        table: &[u64; 256],
+        rotation: u32,
+        incoming: &[u8],
+        outgoing: &[u8],
+        output: &mut [u64; BLOCK_SIZE],
+    ) {
+        let left = _mm_cvtsi32_si128(rotation as i32);
+        let right = _mm_cvtsi32_si128(64 - rotation as i32);
+        let base = table.as_ptr().cast::<i64>();
+        for index in (0..BLOCK_SIZE).step_by(4) {
+            let entering = _mm256_i64gather_epi64::<8>(base, widen(&incoming[index..index + 4]));
+            let leaving = _mm256_i64gather_epi64::<8>(base, widen(&outgoing[index..index + 4]));
+            let leaving = _mm256_or_si256(
+                _mm256_sll_epi64(leaving, left),
+                _mm256_srl_epi64(leaving, right),
+            );
+            _mm256_storeu_si256(
+                output.as_mut_ptr().add(index).cast::<__m256i>(),
+                _mm256_xor_si256(entering, leaving),
+            );
         }
     }
+
+    /// Zero extends four bytes into four 64 bit lanes
+    #[target_feature(enable = "avx2")]
+    unsafe fn widen(bytes: &[u8]) -> __m256i {
+        let packed = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        _mm256_cvtepu8_epi64(_mm_cvtsi32_si128(packed))
+    }
 }
 
 pub struct BuzHashChunker {
     /// Settings for this `Chunker`
     settings: BuzHash,
+    /// The implementation used to compute hash updates
+    kernel: Kernel,
     /// The reader this `Chunker` is slicing
     read: Box<dyn Read + Send + 'static>,
-    /// The in memory buffer used for reading and popping bytes
-    buffer: VecDeque<u8>,
-    /// The buffer used by the rolling hash
-    hash_buffer: VecDeque<u8>,
-    /// Bytes in the hash buffer
-    count: u32,
+    /// The bytes in the rolling hash's window, followed by the bytes read but not yet hashed
+    data: Vec<u8>,
+    /// The index in `data` of the first byte not yet hashed, which is also the number of
+    /// bytes in the window
+    position: usize,
     /// The current hash value
     hash: u64,
     eof: bool,
+    /// The number of bytes of input chunked so far
+    offset: u64,
 }
 
 impl BuzHashChunker {
-    /// Hashes one byte and returns the new hash value
-    fn hash_byte(&mut self, byte: u8) -> u64 {
-        // determine if removal is needed
-        if self.count >= self.settings.window_size {
-            let hash = self.hash.rotate_left(1);
-            // This unwrap should be infallible
-            // We always fill the buffer before we get here
-            let head = self.hash_buffer.pop_front().unwrap();
-            let head = self.settings.table[head as usize].rotate_left(self.settings.window_size);
-            let tail = self.settings.table[byte as usize];
-            self.hash = hash ^ head ^ tail;
-        } else {
-            self.count += 1;
-            let hash = self.hash.rotate_left(1);
-            let tail = self.settings.table[byte as usize];
-            self.hash = hash ^ tail;
-        }
-
-        self.hash_buffer.push_back(byte);
-        self.hash
-    }
-
-    /// Reads up to `max_size` bytes into the internal buffer
+    /// Reads until there are at least `max_size` bytes that have not been hashed yet in the
+    /// buffer, or the reader hits `EoF`
     fn top_off_buffer(&mut self) -> Result<(), ChunkerError> {
-        // Check to see if we need topping off
-        if self.buffer.len() >= self.settings.max_size {
-            Ok(())
-        } else {
-            // Create a temporary buffer that allows for the number of bytes needed to fill the
-            // buffer. The result of this should not underflow as the buffer should never exceed
-            // max_size in size.
-            let tmp_buffer_size = self.settings.max_size - self.buffer.len();
-            let mut tmp_buffer: Vec<u8> = vec![0_u8; tmp_buffer_size];
-            let mut bytes_read = 0;
-            while !self.eof && bytes_read < tmp_buffer_size {
-                let local_bytes_read = self.read.read(&mut tmp_buffer[bytes_read..])?;
-                // Update the length
-                bytes_read += local_bytes_read;
-                // If the number of bytes read was zero, set the eof flag
-                if local_bytes_read == 0 {
+        let target = self.position + self.settings.max_size;
+        if self.data.len() < target {
+            let mut length = self.data.len();
+            self.data.resize(target, 0);
+            while !self.eof && length < target {
+                let bytes_read = self.read.read(&mut self.data[length..])?;
+                length += bytes_read;
+                if bytes_read == 0 {
                     self.eof = true;
                 }
             }
-            // Push the elements we read from the local buffer to the actual buffer
-            for byte in tmp_buffer.iter().take(bytes_read) {
-                self.buffer.push_back(*byte);
+            self.data.truncate(length);
+        }
+        Ok(())
+    }
+
+    /// Hashes bytes starting at `self.position` until a split point is found, or `limit` is
+    /// reached, returning the end of the chunk
+    fn find_split(&mut self, limit: usize) -> usize {
+        let window_size = self.settings.window_size as usize;
+        let rotation = self.settings.window_size % 64;
+        let table = &self.settings.table;
+        let mask = self.settings.mask;
+        let min_end = self.position + self.settings.min_size;
+        let mut hash = self.hash;
+        let mut index = self.position;
+        let mut updates = [0_u64; BLOCK_SIZE];
+        while index < limit {
+            if index >= window_size && limit - index >= BLOCK_SIZE {
+                self.kernel.updates(
+                    table,
+                    rotation,
+                    &self.data[index..],
+                    &self.data[index - window_size..],
+                    &mut updates,
+                );
+                for update in &updates {
+                    hash = hash.rotate_left(1) ^ update;
+                    index += 1;
+                    if hash & mask == 0 && index >= min_end {
+                        self.hash = hash;
+                        return index;
+                    }
+                }
+            } else {
+                // The window is not full yet, or the block would run past the limit
+                hash = hash.rotate_left(1) ^ table[self.data[index] as usize];
+                if index >= window_size {
+                    hash ^= table[self.data[index - window_size] as usize].rotate_left(rotation);
+                }
+                index += 1;
+                if hash & mask == 0 && index >= min_end {
+                    break;
+                }
             }
-            Ok(())
         }
+        self.hash = hash;
+        index
     }
 
     /// Attempts to get another slice from the reader
@@ -142,34 +351,34 @@ impl BuzHashChunker {
         // Attempt to top off the buffer, this will ensure that we have either hit EoF or that there
         // are at least max_size bytes in the buffer
         self.top_off_buffer()?;
+        let available = self.data.len() - self.position;
         // Check to see if there are any bytes in the buffer first. Since we just attempted to top
         // off the buffer, if we are still empty, that is because there are no more bytes to read.
-        if self.buffer.is_empty() {
+        if available == 0 {
             // Go ahead and flag an empty status
             Err(ChunkerError::Empty)
+        } else if self.eof && available <= self.settings.min_size {
+            // In this case, there are no more bytes to read, and the remaining number of bytes
+            // in the buffer is less that the minimum size slice we are allowed to produce, so
+            // we just gather up those bytes and return them, without hashing them
+            let output = self.data.split_off(self.position);
+            self.offset += output.len() as u64;
+            Ok(output)
         } else {
-            // Check to see if we have flagged EoF, and the buffer is smaller than min_size
-            if self.eof && self.buffer.len() <= self.settings.min_size {
-                // In this case, there are no more bytes to read, and the remaining number of bytes
-                // in the buffer is less that the minimum size slice we are allowed to produce, so
-                // we just gather up those bytes and return them
-                Ok(self.buffer.drain(..).collect())
-            } else {
-                let mut output = Vec::<u8>::new();
-                let mut split = false;
-                while !split && output.len() < self.settings.max_size && !self.buffer.is_empty() {
-                    // Get the next byte and add it to the output
-                    // This unwrap is valid because we ensure the buffer isnt empty in the loop
-                    // conditional
-                    let byte = self.buffer.pop_front().unwrap();
-                    output.push(byte);
-                    // Hash it
-                    let hash = self.hash_byte(byte);
-                    split = (hash & self.settings.mask == 0)
-                        && (output.len() >= self.settings.min_size);
-                }
-                Ok(output)
+            let start = self.position;
+            let end = self.find_split(start + available.min(self.settings.max_size));
+            let output = self.data[start..end].to_vec();
+            self.position = end;
+            self.offset += output.len() as u64;
+            // Only keep as much of the hashed data as the window holds
+            let window_size = self.settings.window_size as usize;
+            if self.position > window_size {
+                let drop = self.position - window_size;
+                self.data.copy_within(drop.., 0);
+                self.data.truncate(self.data.len() - drop);
+                self.position = window_size;
             }
+            Ok(output)
         }
     }
 }
@@ -186,6 +395,16 @@ impl Iterator for BuzHashChunker {
     }
 }
 
+impl ChunkIterator for BuzHashChunker {
+    fn state(&self) -> ChunkerState {
+        ChunkerState {
+            offset: self.offset,
+            hash: self.hash,
+            window: self.data[..self.position].to_vec(),
+        }
+    }
+}
+
 /// static lookup table for the buzhash chunker. Gets xored with a random number before using to
 /// prevent fingerprinting.
 #[rustfmt::skip]
@@ -315,6 +534,31 @@ mod tests {
         assert_eq!(chunks1, chunks2);
     }
 
+    // Seeded tables should be deterministic, differ between seeds, and still chunk properly
+    #[test]
+    fn seeded_table() {
+        let data = get_test_data();
+        let chunk = |chunker: BuzHash| {
+            chunker
+                .chunk(Cursor::new(data.clone()))
+                .map(|x| x.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let first = BuzHash::with_seed([1; 32], 4095, 14);
+        let second = BuzHash::with_seed([2; 32], 4095, 14);
+        assert_eq!(
+            first.table[..],
+            BuzHash::with_seed([1; 32], 4095, 14).table[..]
+        );
+        assert_ne!(first.table[..], second.table[..]);
+        assert_ne!(first.table[..], BuzHash::with_default_testing(0).table[..]);
+        let chunks = chunk(first);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), data);
+        assert_eq!(chunks, chunk(first));
+        assert_ne!(chunks, chunk(second));
+    }
+
     // Verifies that this `Chunker` does not produce chunks larger than its max size
     #[test]
     fn max_size() {
@@ -351,4 +595,94 @@ mod tests {
 
         assert!(undersized_count <= 1);
     }
+
+    // The original byte at a time implementation, which the block based one has to produce
+    // identical chunks to, so that existing repositories keep deduplicating
+    fn reference_chunks(settings: &BuzHash, data: &[u8]) -> Vec<Vec<u8>> {
+        let window_size = settings.window_size as usize;
+        let mut window = std::collections::VecDeque::new();
+        let mut hash = 0_u64;
+        let mut chunks = Vec::new();
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            if remaining.len() <= settings.min_size {
+                chunks.push(remaining.to_vec());
+                break;
+            }
+            let mut length = 0;
+            while length < settings.max_size && length < remaining.len() {
+                let byte = remaining[length];
+                hash = hash.rotate_left(1) ^ settings.table[byte as usize];
+                if window.len() >= window_size {
+                    let head: u8 = window.pop_front().unwrap();
+                    hash ^= settings.table[head as usize].rotate_left(settings.window_size);
+                }
+                window.push_back(byte);
+                length += 1;
+                if hash & settings.mask == 0 && length >= settings.min_size {
+                    break;
+                }
+            }
+            chunks.push(remaining[..length].to_vec());
+            remaining = &remaining[length..];
+        }
+        chunks
+    }
+
+    // Every kernel should produce the same chunks as the reference implementation
+    #[test]
+    fn matches_reference() {
+        let mut data = get_test_data();
+        // Include some runs without split points, to exercise the max size
+        let max_size = BuzHash::with_default_testing(0).max_size;
+        data.extend(vec![0_u8; max_size * 2]);
+        data.extend(get_test_data());
+        let chunker = BuzHash::with_default_testing(7);
+        let expected = reference_chunks(&chunker, &data);
+        let mut kernels = vec![Kernel::Scalar];
+        #[cfg(target_arch = "x86_64")]
+        {
+            kernels.push(Kernel::Sse2);
+            if is_x86_feature_detected!("avx2") {
+                kernels.push(Kernel::Avx2);
+            }
+        }
+        for kernel in kernels {
+            let mut chunks = chunker.chunk(Cursor::new(data.clone()));
+            chunks.kernel = kernel;
+            let chunks = chunks.map(|x| x.unwrap()).collect::<Vec<_>>();
+            assert_eq!(chunks, expected, "{kernel:?}");
+        }
+    }
+
+    // Resuming from the state captured after a chunk should produce the same chunks as
+    // chunking the whole input
+    #[test]
+    fn resume_from_state() {
+        let data = get_test_data();
+        let chunker = BuzHash::with_default_testing(0);
+        let mut chunks = chunker.chunk(Cursor::new(data.clone()));
+        let mut expected = Vec::new();
+        let mut state = None;
+        while let Some(chunk) = chunks.next() {
+            expected.push(chunk.unwrap());
+            if expected.len() == 3 {
+                state = Some(chunks.state());
+            }
+        }
+        let state = state.unwrap();
+        let offset = expected[..3].iter().map(Vec::len).sum::<usize>();
+        assert_eq!(state.offset, offset as u64);
+        let resumed = chunker
+            .chunk_from(Cursor::new(data[offset..].to_vec()), state.clone())
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(resumed, expected[3..].to_vec());
+        let seeked = chunker
+            .chunk_seekable(Cursor::new(data), state)
+            .unwrap()
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(seeked, expected[3..].to_vec());
+    }
 }
@@ -0,0 +1,136 @@
+use super::{ChunkIterator, ChunkerError, ChunkerState};
+
+/// Number of evenly spaced windows sampled from each chunk
+const SAMPLE_WINDOWS: usize = 16;
+/// Length of each sampled window
+const WINDOW_LENGTH: usize = 1024;
+
+/// A cheap estimate of how well a chunk will compress
+///
+/// This is the Shannon entropy of the bytes in a handful of windows spread evenly across
+/// the chunk, rather than of the whole chunk, so it can be computed alongside chunking
+/// without noticeably slowing it down. Text and other highly redundant data comes in well
+/// below the maximum of 8 bits per byte, while compressed and encrypted data comes in just
+/// under it.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Default)]
+pub struct Compressibility {
+    /// Estimated entropy of the chunk, in bits per byte, between 0 and 8
+    pub entropy: f64,
+}
+
+impl Compressibility {
+    /// Estimates the compressibility of `data`
+    ///
+    /// Chunks no larger than the sample are measured in full.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn estimate(data: &[u8]) -> Compressibility {
+        let mut counts = [0_usize; 256];
+        let mut sampled = 0;
+        if data.len() <= SAMPLE_WINDOWS * WINDOW_LENGTH {
+            for byte in data {
+                counts[*byte as usize] += 1;
+            }
+            sampled = data.len();
+        } else {
+            let stride = (data.len() - WINDOW_LENGTH) / (SAMPLE_WINDOWS - 1);
+            for window in 0..SAMPLE_WINDOWS {
+                let start = window * stride;
+                for byte in &data[start..start + WINDOW_LENGTH] {
+                    counts[*byte as usize] += 1;
+                }
+                sampled += WINDOW_LENGTH;
+            }
+        }
+        if sampled == 0 {
+            return Compressibility::default();
+        }
+        let length = sampled as f64;
+        let entropy = counts
+            .iter()
+            .filter(|x| **x > 0)
+            .map(|x| {
+                let p = *x as f64 / length;
+                -p * p.log2()
+            })
+            .sum();
+        Compressibility { entropy }
+    }
+}
+
+/// Wraps the chunks produced by a `Chunker`, pairing each one with an estimate of its
+/// compressibility
+///
+/// Created by `ChunkIterator::with_compressibility`.
+pub struct EstimatedChunks<I> {
+    chunks: I,
+}
+
+impl<I> EstimatedChunks<I> {
+    pub(crate) fn new(chunks: I) -> EstimatedChunks<I> {
+        EstimatedChunks { chunks }
+    }
+}
+
+impl<I: ChunkIterator> EstimatedChunks<I> {
+    /// Returns the state of the wrapped chunker, as `ChunkIterator::state` does
+    pub fn state(&self) -> ChunkerState {
+        self.chunks.state()
+    }
+}
+
+impl<I: ChunkIterator> Iterator for EstimatedChunks<I> {
+    type Item = Result<(Vec<u8>, Compressibility), ChunkerError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.chunks.next()?;
+        Some(chunk.map(|data| {
+            let compressibility = Compressibility::estimate(&data);
+            (data, compressibility)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Chunker, FastCDC};
+    use rand::prelude::*;
+    use std::io::Cursor;
+
+    // Random data should look incompressible, text and runs of one byte should not
+    #[test]
+    fn orders_data() {
+        let mut random = vec![0_u8; 100_000];
+        rand::thread_rng().fill_bytes(&mut random);
+        let text = "The quick brown fox jumps over the lazy dog. "
+            .repeat(2000)
+            .into_bytes();
+        let random = Compressibility::estimate(&random);
+        let text = Compressibility::estimate(&text);
+        let zeros = Compressibility::estimate(&vec![0_u8; 100_000]);
+        assert!(random.entropy > 7.95);
+        assert!(text.entropy < 5.0);
+        assert!(zeros.entropy.abs() < f64::EPSILON);
+        assert!(Compressibility::estimate(&[]).entropy.abs() < f64::EPSILON);
+    }
+
+    // Estimating should not change the chunks themselves
+    #[test]
+    fn estimated_chunks_match() {
+        let mut data = vec![0_u8; 1_000_000];
+        rand::thread_rng().fill_bytes(&mut data);
+        let chunker = FastCDC::default();
+        let expected = chunker
+            .chunk(Cursor::new(data.clone()))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        let mut estimated = chunker.chunk(Cursor::new(data)).with_compressibility();
+        let mut chunks = Vec::new();
+        for chunk in &mut estimated {
+            let (chunk, compressibility) = chunk.unwrap();
+            assert_eq!(compressibility, Compressibility::estimate(&chunk));
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks, expected);
+        assert_eq!(estimated.state().offset, 1_000_000);
+    }
+}
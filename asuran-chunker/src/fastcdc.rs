@@ -1,4 +1,4 @@
-use super::{Chunker, ChunkerError};
+use super::{ChunkIterator, Chunker, ChunkerError, ChunkerState};
 
 use std::io::Read;
 
@@ -21,13 +21,20 @@ pub struct FastCDC {
 
 impl Chunker for FastCDC {
     type Chunks = FastCDCChunker;
-    fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
+    /// `FastCDC` starts its hash over at every chunk boundary, so only the offset of the
+    /// state is used
+    fn chunk_from_boxed(
+        &self,
+        read: Box<dyn Read + Send + 'static>,
+        state: ChunkerState,
+    ) -> Self::Chunks {
         FastCDCChunker {
             settings: *self,
             buffer: vec![0_u8; self.max_size],
             length: 0,
             read,
             eof: false,
+            offset: state.offset,
         }
     }
 }
@@ -55,6 +62,8 @@ pub struct FastCDCChunker {
     read: Box<dyn Read + Send + 'static>,
     /// Has the reader hit EoF?
     eof: bool,
+    /// The number of bytes of input chunked so far
+    offset: u64,
 }
 
 impl FastCDCChunker {
@@ -159,6 +168,7 @@ impl FastCDCChunker {
             );
             if let Some(chunk) = slicer.next() {
                 let result = self.drain_bytes(chunk.length)?;
+                self.offset += result.len() as u64;
                 Ok(result)
             } else {
                 // We really shouldn't be here, since we ruled out the empty case, earlier but we
@@ -182,6 +192,12 @@ impl Iterator for FastCDCChunker {
     }
 }
 
+impl ChunkIterator for FastCDCChunker {
+    fn state(&self) -> ChunkerState {
+        ChunkerState::at_offset(self.offset)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -277,4 +293,29 @@ mod tests {
 
         assert!(undersized_count <= 1);
     }
+
+    // Resuming from the state captured after a chunk should produce the same chunks as
+    // chunking the whole input
+    #[test]
+    fn resume_from_state() {
+        let data = get_test_data();
+        let chunker = FastCDC::default();
+        let mut chunks = chunker.chunk(Cursor::new(data.clone()));
+        let mut expected = Vec::new();
+        let mut state = None;
+        while let Some(chunk) = chunks.next() {
+            expected.push(chunk.unwrap());
+            if expected.len() == 3 {
+                state = Some(chunks.state());
+            }
+        }
+        let state = state.unwrap();
+        let offset = expected[..3].iter().map(Vec::len).sum::<usize>();
+        assert_eq!(state.offset, offset as u64);
+        let resumed = chunker
+            .chunk_from(Cursor::new(data[offset..].to_vec()), state)
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(resumed, expected[3..].to_vec());
+    }
 }
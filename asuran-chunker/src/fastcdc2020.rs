@@ -0,0 +1,375 @@
+use super::{ChunkIterator, Chunker, ChunkerError, ChunkerState};
+
+use std::io::Read;
+
+/// How strongly `FastCDC2020` pulls chunk sizes towards the average
+///
+/// Before reaching the average size, cut points are searched for with a mask that has
+/// `level` more bits set than the average size calls for, making them less likely, and after
+/// it with one that has `level` fewer bits set, making them more likely. Higher levels produce
+/// chunk sizes more tightly clustered around the average, at some cost in deduplication of
+/// shifted data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Normalization {
+    /// Normalized chunking level 1 (NC1)
+    #[default]
+    Level1,
+    /// Normalized chunking level 2 (NC2)
+    Level2,
+    /// Normalized chunking level 3 (NC3)
+    Level3,
+}
+
+impl Normalization {
+    /// The number of bits the masks differ from the average size's by
+    fn bits(self) -> u32 {
+        match self {
+            Normalization::Level1 => 1,
+            Normalization::Level2 => 2,
+            Normalization::Level3 => 3,
+        }
+    }
+}
+
+/// Settings for a `Chunker` implementing the 2020 revision of `FastCDC`
+///
+/// This uses a gear hash, with cut points checked against masks chosen by the normalization
+/// level, and skips hashing the first `min_size` bytes of every chunk, as described in
+/// "The Design of Fast Content-Defined Chunking for Data Deduplication Based Storage Systems"
+/// (Xia et al., 2020).
+///
+/// The chunk boundaries it produces differ from those of `FastCDC`, so switching a repository
+/// between the two will not deduplicate against data stored with the other.
+///
+/// Like `FastCDC`, this does not attempt to mitigate chunk size fingerprinting attacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FastCDC2020 {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+    pub normalization: Normalization,
+}
+
+impl Default for FastCDC2020 {
+    fn default() -> Self {
+        FastCDC2020 {
+            min_size: 32_768,
+            avg_size: 65_536,
+            max_size: 131_072,
+            normalization: Normalization::default(),
+        }
+    }
+}
+
+impl FastCDC2020 {
+    /// Returns the masks used before and after the average size has been reached
+    fn masks(&self) -> (u64, u64) {
+        // The base two logarithm of the average size, rounded down
+        let bits = usize::BITS - 1 - self.avg_size.max(1).leading_zeros();
+        let level = self.normalization.bits();
+        (mask(bits + level), mask(bits.saturating_sub(level)))
+    }
+
+    /// Finds the length of the first chunk of `source`
+    ///
+    /// `source` is expected to hold at least `max_size` bytes, unless the end of the input
+    /// has been reached.
+    fn cut(&self, source: &[u8], mask_small: u64, mask_large: u64) -> usize {
+        let mut remaining = source.len();
+        if remaining <= self.min_size {
+            return remaining;
+        }
+        let mut center = self.avg_size;
+        if remaining > self.max_size {
+            remaining = self.max_size;
+        } else if remaining < center {
+            center = remaining;
+        }
+        let mut hash = 0_u64;
+        let mut index = self.min_size;
+        while index < center {
+            hash = (hash << 1).wrapping_add(GEAR[source[index] as usize]);
+            if hash & mask_small == 0 {
+                return index + 1;
+            }
+            index += 1;
+        }
+        while index < remaining {
+            hash = (hash << 1).wrapping_add(GEAR[source[index] as usize]);
+            if hash & mask_large == 0 {
+                return index + 1;
+            }
+            index += 1;
+        }
+        remaining
+    }
+}
+
+/// Returns a mask with the given number of its most significant bits set
+///
+/// The most significant bits of a gear hash depend on the most bytes, up to the last 64 seen.
+fn mask(bits: u32) -> u64 {
+    match bits {
+        0 => 0,
+        bits if bits >= 64 => u64::MAX,
+        bits => u64::MAX << (64 - bits),
+    }
+}
+
+impl Chunker for FastCDC2020 {
+    type Chunks = FastCDC2020Chunker;
+    /// The hash starts over at every chunk boundary, so only the offset of the state is used
+    fn chunk_from_boxed(
+        &self,
+        read: Box<dyn Read + Send + 'static>,
+        state: ChunkerState,
+    ) -> Self::Chunks {
+        let (mask_small, mask_large) = self.masks();
+        FastCDC2020Chunker {
+            settings: *self,
+            mask_small,
+            mask_large,
+            buffer: vec![0_u8; self.max_size],
+            length: 0,
+            read,
+            eof: false,
+            offset: state.offset,
+        }
+    }
+}
+
+pub struct FastCDC2020Chunker {
+    /// The settings used for this `Chunker`
+    settings: FastCDC2020,
+    /// Mask used to find cut points before the average size
+    mask_small: u64,
+    /// Mask used to find cut points after the average size
+    mask_large: u64,
+    /// Buffer holding the next `max_size` bytes of input
+    buffer: Vec<u8>,
+    /// The length of the data in the buffer
+    length: usize,
+    /// The reader this `Chunker` is slicing
+    read: Box<dyn Read + Send + 'static>,
+    /// Has the reader hit `EoF`?
+    eof: bool,
+    /// The number of bytes of input chunked so far
+    offset: u64,
+}
+
+impl FastCDC2020Chunker {
+    /// Fills the buffer back up with bytes from the reader, until it is full or the reader
+    /// hits `EoF`
+    ///
+    /// # Errors
+    ///
+    /// Returns `ChunkerError::IOError` if the reader provides any error value during reading
+    fn read_bytes(&mut self) -> Result<(), ChunkerError> {
+        while !self.eof && self.length < self.buffer.len() {
+            let bytes_read = self.read.read(&mut self.buffer[self.length..])?;
+            self.length += bytes_read;
+            if bytes_read == 0 {
+                self.eof = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Produces the next chunk of data
+    ///
+    /// # Errors
+    ///
+    /// Returns `ChunkerError::Empty` if `EoF` has been hit
+    fn next_chunk(&mut self) -> Result<Vec<u8>, ChunkerError> {
+        self.read_bytes()?;
+        if self.length == 0 {
+            return Err(ChunkerError::Empty);
+        }
+        let length = self.settings.cut(
+            &self.buffer[..self.length],
+            self.mask_small,
+            self.mask_large,
+        );
+        let output = self.buffer[..length].to_vec();
+        self.buffer.copy_within(length..self.length, 0);
+        self.length -= length;
+        self.offset += length as u64;
+        Ok(output)
+    }
+}
+
+impl Iterator for FastCDC2020Chunker {
+    type Item = Result<Vec<u8>, ChunkerError>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, ChunkerError>> {
+        let slice = self.next_chunk();
+        if let Err(ChunkerError::Empty) = slice {
+            None
+        } else {
+            Some(slice)
+        }
+    }
+}
+
+impl ChunkIterator for FastCDC2020Chunker {
+    fn state(&self) -> ChunkerState {
+        ChunkerState::at_offset(self.offset)
+    }
+}
+
+/// Generates the gear table from a fixed seed with splitmix64, so it stays the same across
+/// platforms and versions of this crate
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0_u64; 256];
+    let mut state: u64 = 0x6173_7572_616e_4344;
+    let mut index = 0;
+    while index < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[index] = value ^ (value >> 31);
+        index += 1;
+    }
+    table
+}
+
+/// Lookup table for the gear hash
+const GEAR: [u64; 256] = gear_table();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use std::io::Cursor;
+
+    // Provides a test slice 10 times the default max size in length
+    fn get_test_data() -> Vec<u8> {
+        let size = FastCDC2020::default().max_size * 10;
+        let mut vec = vec![0_u8; size];
+        rand::thread_rng().fill_bytes(&mut vec);
+        vec
+    }
+
+    fn chunk(chunker: FastCDC2020, data: Vec<u8>) -> Vec<Vec<u8>> {
+        chunker
+            .chunk(Cursor::new(data))
+            .map(|x| x.unwrap())
+            .collect()
+    }
+
+    // Data should be split into one or more chunks.
+    //
+    // In this case, the data is larger than `max_size`, so it should be more than one chunk
+    #[test]
+    fn one_or_more_chunks() {
+        let chunks = chunk(FastCDC2020::default(), get_test_data());
+        assert!(chunks.len() > 1);
+    }
+
+    // Data should be identical after reassembaly by simple concatenation
+    #[test]
+    fn reassemble_data() {
+        let data = get_test_data();
+        let chunks = chunk(FastCDC2020::default(), data.clone());
+        assert_eq!(data, chunks.concat());
+    }
+
+    // Running the chunker over the same data twice should result in identical chunks
+    #[test]
+    fn identical_chunks() {
+        let data = get_test_data();
+        let chunks1 = chunk(FastCDC2020::default(), data.clone());
+        let chunks2 = chunk(FastCDC2020::default(), data);
+        assert_eq!(chunks1, chunks2);
+    }
+
+    // Verifies that this `Chunker` does not produce chunks larger than its max size
+    #[test]
+    fn max_size() {
+        let max_size = FastCDC2020::default().max_size;
+        for chunk in chunk(FastCDC2020::default(), get_test_data()) {
+            assert!(chunk.len() <= max_size);
+        }
+        // Data without any cut points has to be cut at the max size
+        for chunk in chunk(FastCDC2020::default(), vec![0_u8; max_size * 3]) {
+            assert_eq!(chunk.len(), max_size);
+        }
+    }
+
+    // Verifies that this `Chunker`, at most, produces 1 under-sized chunk
+    #[test]
+    fn min_size() {
+        let min_size = FastCDC2020::default().min_size;
+        let undersized = chunk(FastCDC2020::default(), get_test_data())
+            .iter()
+            .filter(|chunk| chunk.len() < min_size)
+            .count();
+        assert!(undersized <= 1);
+    }
+
+    // Higher normalization levels should cluster chunk sizes more tightly around the average
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn normalization() {
+        let data = {
+            let mut data = vec![0_u8; 16_000_000];
+            rand::thread_rng().fill_bytes(&mut data);
+            data
+        };
+        let spread = |normalization| {
+            let chunker = FastCDC2020 {
+                normalization,
+                ..FastCDC2020::default()
+            };
+            let sizes = chunk(chunker, data.clone())
+                .iter()
+                .map(|chunk| chunk.len() as f64)
+                .collect::<Vec<_>>();
+            let mean = sizes.iter().sum::<f64>() / sizes.len() as f64;
+            sizes.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / sizes.len() as f64
+        };
+        assert!(spread(Normalization::Level1) > spread(Normalization::Level3));
+    }
+
+    // Content defined chunking should resynchronize after an insertion, leaving most chunks
+    // unchanged
+    #[test]
+    fn shifted_data() {
+        let data = get_test_data();
+        let mut shifted = vec![1, 2, 3];
+        shifted.extend_from_slice(&data);
+        let original = chunk(FastCDC2020::default(), data);
+        let shifted = chunk(FastCDC2020::default(), shifted);
+        let shared = shifted
+            .iter()
+            .filter(|chunk| original.contains(chunk))
+            .count();
+        assert!(shared * 2 > original.len());
+    }
+
+    // Resuming from the state captured after a chunk should produce the same chunks as
+    // chunking the whole input
+    #[test]
+    fn resume_from_state() {
+        let data = get_test_data();
+        let chunker = FastCDC2020::default();
+        let mut chunks = chunker.chunk(Cursor::new(data.clone()));
+        let mut expected = Vec::new();
+        let mut state = None;
+        while let Some(chunk) = chunks.next() {
+            expected.push(chunk.unwrap());
+            if expected.len() == 3 {
+                state = Some(chunks.state());
+            }
+        }
+        let state = state.unwrap();
+        let offset = expected[..3].iter().map(Vec::len).sum::<usize>();
+        assert_eq!(state.offset, offset as u64);
+        let resumed = chunker
+            .chunk_from(Cursor::new(data[offset..].to_vec()), state)
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(resumed, expected[3..].to_vec());
+    }
+}
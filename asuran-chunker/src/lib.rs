@@ -8,11 +8,15 @@
 #![allow(clippy::missing_errors_doc)]
 
 pub mod buzhash;
+pub mod compressibility;
 pub mod fastcdc;
+pub mod fastcdc2020;
 pub mod static_size;
 
 pub use self::buzhash::*;
+pub use self::compressibility::*;
 pub use self::fastcdc::*;
+pub use self::fastcdc2020::*;
 pub use self::static_size::*;
 
 use thiserror::Error;
@@ -38,7 +42,94 @@ pub enum ChunkerError {
     Empty,
 }
 
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+/// The state of a `Chunker` at a chunk boundary, from which chunking can be resumed
+///
+/// Chunkers whose rolling hash is reset at every chunk boundary only need the offset, the
+/// others additionally carry the trailing window of input their hash covers. States can
+/// only be used with the same settings they were captured with.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct ChunkerState {
+    /// The number of bytes of input chunked so far, which is also the offset at which the
+    /// next chunk starts
+    pub offset: u64,
+    /// The value of the rolling hash
+    pub hash: u64,
+    /// The bytes of input currently inside the rolling hash's window, oldest first
+    pub window: Vec<u8>,
+}
+
+impl ChunkerState {
+    /// Returns a state for resuming at `offset`, for chunkers that do not carry any hash
+    /// state across chunk boundaries
+    pub fn at_offset(offset: u64) -> ChunkerState {
+        ChunkerState {
+            offset,
+            ..ChunkerState::default()
+        }
+    }
+}
+
+/// An iterator over the chunks produced by a `Chunker`
+pub trait ChunkIterator: Iterator<Item = Result<Vec<u8>, ChunkerError>> {
+    /// Returns the state of the chunker at the end of the most recently produced chunk
+    ///
+    /// The final chunk of the input ends where the input does, rather than at a boundary
+    /// picked by the chunker. To pick up data appended to the input later, such as with a
+    /// growing log file, resume from the state before the final chunk.
+    fn state(&self) -> ChunkerState;
+    /// Pairs each chunk with an estimate of how well it will compress
+    fn with_compressibility(self) -> EstimatedChunks<Self>
+    where
+        Self: Sized,
+    {
+        EstimatedChunks::new(self)
+    }
+    /// Pairs each chunk with the offset in the input it starts at
+    ///
+    /// Offsets count from the start of the whole input, so chunking resumed from a state
+    /// continues from `state.offset`.
+    fn with_offsets(self) -> OffsetChunks<Self>
+    where
+        Self: Sized,
+    {
+        let offset = self.state().offset;
+        OffsetChunks {
+            chunks: self,
+            offset,
+        }
+    }
+}
+
+/// Wraps the chunks produced by a `Chunker`, pairing each one with the offset in the input
+/// it starts at
+///
+/// Created by `Chunker::chunk_with_offsets` and `ChunkIterator::with_offsets`.
+pub struct OffsetChunks<I> {
+    chunks: I,
+    /// The offset the next chunk starts at
+    offset: u64,
+}
+
+impl<I: ChunkIterator> OffsetChunks<I> {
+    /// Returns the state of the wrapped chunker, as `ChunkIterator::state` does
+    pub fn state(&self) -> ChunkerState {
+        self.chunks.state()
+    }
+}
+
+impl<I: ChunkIterator> Iterator for OffsetChunks<I> {
+    type Item = Result<(u64, Vec<u8>), ChunkerError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.chunks.next()?;
+        Some(chunk.map(|data| {
+            let start = self.offset;
+            self.offset += data.len() as u64;
+            (start, data)
+        }))
+    }
+}
 
 /// Describes something that can slice objects in a defined, repeatable manner
 ///
@@ -66,9 +157,41 @@ pub trait Chunker: Clone {
     /// input.
     ///
     /// The returned iterator must be owned, hence the 'static bound.
-    type Chunks: Iterator<Item = Result<Vec<u8>, ChunkerError>> + 'static;
+    type Chunks: ChunkIterator + 'static;
     /// Core function, takes a boxed owned Read and produces an iterator of Vec<u8> over it
-    fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks;
+    fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
+        self.chunk_from_boxed(read, ChunkerState::default())
+    }
+    /// Resumes chunking from a previously captured state
+    ///
+    /// `read` must provide the input starting at `state.offset`, and `state` must have been
+    /// captured from the same input, with the same settings. The chunks produced are then
+    /// identical to those chunking the whole input from the start would produce after that
+    /// point, without having to reread the input before it.
+    fn chunk_from_boxed(
+        &self,
+        read: Box<dyn Read + Send + 'static>,
+        state: ChunkerState,
+    ) -> Self::Chunks;
+    /// Convenience function that boxes a bare Read for you, and passes it to
+    /// `chunk_from_boxed`
+    fn chunk_from<R: Read + Send + 'static>(&self, read: R, state: ChunkerState) -> Self::Chunks {
+        let boxed: Box<dyn Read + Send + 'static> = Box::new(read);
+        self.chunk_from_boxed(boxed, state)
+    }
+    /// Seeks a reader over the whole input to `state.offset`, and resumes chunking there
+    ///
+    /// # Errors
+    ///
+    /// Returns `ChunkerError::IOError` if seeking fails
+    fn chunk_seekable<R: Read + Seek + Send + 'static>(
+        &self,
+        mut read: R,
+        state: ChunkerState,
+    ) -> Result<Self::Chunks, ChunkerError> {
+        read.seek(SeekFrom::Start(state.offset))?;
+        Ok(self.chunk_from(read, state))
+    }
     /// Convenience function that boxes a bare Read for you, and passes it to `chunk_boxed`
     ///
     /// This will be the primary source of interaction wth the API for most use cases
@@ -76,6 +199,10 @@ pub trait Chunker: Clone {
         let boxed: Box<dyn Read + Send + 'static> = Box::new(read);
         self.chunk_boxed(boxed)
     }
+    /// Like `chunk`, but pairs each chunk with the offset in the input it starts at
+    fn chunk_with_offsets<R: Read + Send + 'static>(&self, read: R) -> OffsetChunks<Self::Chunks> {
+        self.chunk(read).with_offsets()
+    }
     /// Convenience function that boxes an AsRef<[u8]> wrapped in a cursor and passes it to
     /// `chunk_boxed`. Implementations are encouraged to overwrite when sensible.
     ///
@@ -115,6 +242,22 @@ pub trait AsyncChunker: Chunker + Send + Sync {
         slice: R,
         queue_depth: usize,
     ) -> mpsc::Receiver<Result<Vec<u8>, ChunkerError>>;
+    /// Async version of `Chunker::chunk_with_offsets`
+    fn async_chunk_with_offsets<R: Read + Send + 'static>(
+        &self,
+        read: R,
+        queue_depth: usize,
+    ) -> mpsc::Receiver<Result<(u64, Vec<u8>), ChunkerError>>;
+    /// Async version of `Chunker::chunk_with_offsets`, additionally pairing each chunk with
+    /// an estimate of how well it will compress
+    ///
+    /// The estimate is made on the chunking thread, see `ChunkIterator::with_compressibility`.
+    #[allow(clippy::type_complexity)]
+    fn async_chunk_estimated<R: Read + Send + 'static>(
+        &self,
+        read: R,
+        queue_depth: usize,
+    ) -> mpsc::Receiver<Result<(u64, Vec<u8>, Compressibility), ChunkerError>>;
 }
 
 #[cfg(feature = "streams")]
@@ -171,4 +314,42 @@ where
         });
         output
     }
+    fn async_chunk_with_offsets<R: Read + Send + 'static>(
+        &self,
+        read: R,
+        queue_depth: usize,
+    ) -> mpsc::Receiver<Result<(u64, Vec<u8>), ChunkerError>> {
+        let (mut input, output) = mpsc::channel(queue_depth);
+        let iter = self.chunk_with_offsets(read);
+        thread::spawn(move || {
+            for chunk in iter {
+                // If we are here, and sending to the channel fails, we have no sensible way to
+                // recover, as we have lost communication with the outside world
+                block_on(input.send(chunk)).expect("Chunker to communicate with outside world.");
+            }
+        });
+        output
+    }
+    #[allow(clippy::type_complexity)]
+    fn async_chunk_estimated<R: Read + Send + 'static>(
+        &self,
+        read: R,
+        queue_depth: usize,
+    ) -> mpsc::Receiver<Result<(u64, Vec<u8>, Compressibility), ChunkerError>> {
+        let (mut input, output) = mpsc::channel(queue_depth);
+        let iter = self.chunk_with_offsets(read).map(|chunk| {
+            chunk.map(|(start, data)| {
+                let compressibility = Compressibility::estimate(&data);
+                (start, data, compressibility)
+            })
+        });
+        thread::spawn(move || {
+            for chunk in iter {
+                // If we are here, and sending to the channel fails, we have no sensible way to
+                // recover, as we have lost communication with the outside world
+                block_on(input.send(chunk)).expect("Chunker to communicate with outside world.");
+            }
+        });
+        output
+    }
 }
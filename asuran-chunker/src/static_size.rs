@@ -1,4 +1,4 @@
-use super::{Chunker, ChunkerError};
+use super::{ChunkIterator, Chunker, ChunkerError, ChunkerState};
 
 use std::io::{BufReader, Bytes, Read};
 
@@ -14,11 +14,17 @@ pub struct StaticSize {
 
 impl Chunker for StaticSize {
     type Chunks = StaticSizeChunker;
-    fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
+    /// Static chunking has no hash, so only the offset of the state is used
+    fn chunk_from_boxed(
+        &self,
+        read: Box<dyn Read + Send + 'static>,
+        state: ChunkerState,
+    ) -> Self::Chunks {
         StaticSizeChunker {
             settings: *self,
             internal: BufReader::new(read).bytes(),
             next: None,
+            offset: state.offset,
         }
     }
 }
@@ -36,6 +42,8 @@ pub struct StaticSizeChunker {
     /// `Read` this `Chunker` is slicing over
     internal: Bytes<BufReader<Box<dyn Read + Send + 'static>>>,
     next: Option<std::io::Result<u8>>,
+    /// The number of bytes of input chunked so far
+    offset: u64,
 }
 
 impl Iterator for StaticSizeChunker {
@@ -61,11 +69,18 @@ impl Iterator for StaticSizeChunker {
             None
         } else {
             self.next = next;
+            self.offset += buffer.len() as u64;
             Some(Ok(buffer))
         }
     }
 }
 
+impl ChunkIterator for StaticSizeChunker {
+    fn state(&self) -> ChunkerState {
+        ChunkerState::at_offset(self.offset)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -159,4 +174,56 @@ mod tests {
 
         assert!(undersized_count <= 1);
     }
+
+    // Resuming from the state captured after a chunk should produce the same chunks as
+    // chunking the whole input
+    #[test]
+    fn resume_from_state() {
+        let data = get_test_data();
+        let chunker = StaticSize::default();
+        let mut chunks = chunker.chunk(Cursor::new(data.clone()));
+        let mut expected = Vec::new();
+        let mut state = None;
+        while let Some(chunk) = chunks.next() {
+            expected.push(chunk.unwrap());
+            if expected.len() == 3 {
+                state = Some(chunks.state());
+            }
+        }
+        let state = state.unwrap();
+        let offset = expected[..3].iter().map(Vec::len).sum::<usize>();
+        assert_eq!(state.offset, offset as u64);
+        let resumed = chunker
+            .chunk_from(Cursor::new(data[offset..].to_vec()), state)
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(resumed, expected[3..].to_vec());
+    }
+
+    // Offsets should be where each chunk starts in the input, including when resuming
+    #[test]
+    fn chunk_offsets() {
+        let data = get_test_data();
+        let chunker = StaticSize::default();
+        let chunks = chunker
+            .chunk_with_offsets(Cursor::new(data.clone()))
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        let mut offset = 0;
+        for (start, chunk) in &chunks {
+            assert_eq!(*start, offset as u64);
+            assert_eq!(&chunk[..], &data[offset..offset + chunk.len()]);
+            offset += chunk.len();
+        }
+        assert_eq!(offset, data.len());
+        // Resume after the first two chunks
+        let offset = chunks[0].1.len() + chunks[1].1.len();
+        let state = ChunkerState::at_offset(chunks[2].0);
+        let resumed = chunker
+            .chunk_from(Cursor::new(data[offset..].to_vec()), state)
+            .with_offsets()
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(resumed, chunks[2..].to_vec());
+    }
 }
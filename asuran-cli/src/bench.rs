@@ -1,14 +1,19 @@
 use asuran::prelude::*;
 
 use anyhow::Result;
-use prettytable::{cell, row, Table};
+use prettytable::{row, Table};
 
 use std::collections::HashMap;
 use std::io::{self, Write};
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
 const ONE_MIB: usize = 1_048_576;
 const REPETITIONS: usize = 100;
+/// The slower compression algorithms would take minutes to get through `REPETITIONS`
+const COMPRESSION_REPETITIONS: usize = 10;
+/// The size of the synthetic samples the chunkers are run over
+const CHUNKER_SAMPLE_SIZE: usize = 64 * ONE_MIB;
 
 /// Runs each encryption/hmac pair over 1MiB of zeros, 100 times
 ///
@@ -34,6 +39,78 @@ pub fn bench_with_settings(encryption: Encryption, hmac: HMAC) -> f64 {
     (REPETITIONS as f64) / elapsed
 }
 
+/// Generates 1MiB of text-like data, drawn from a small vocabulary, so that compression
+/// has something realistic to work with
+fn sample_text() -> Vec<u8> {
+    const WORDS: &[&str] = &[
+        "the",
+        "of",
+        "and",
+        "a",
+        "to",
+        "in",
+        "is",
+        "archive",
+        "chunk",
+        "repository",
+        "backup",
+        "asuran",
+        "data",
+        "file",
+        "with",
+        "that",
+        "for",
+        "on",
+        "are",
+        "be",
+        "this",
+        "from",
+        "compression",
+        "encryption",
+        "key",
+        "index",
+        "segment",
+        "manifest",
+        "which",
+        "an",
+        "or",
+        "not",
+    ];
+    let mut state = 0x2545_F491_4F6C_DD1D_u64;
+    let mut output = Vec::with_capacity(ONE_MIB + 16);
+    while output.len() < ONE_MIB {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        output.extend_from_slice(WORDS[(state % WORDS.len() as u64) as usize].as_bytes());
+        output.push(if state & 0xF == 0 { b'\n' } else { b' ' });
+    }
+    output.truncate(ONE_MIB);
+    output
+}
+
+/// Runs the compression over 1MiB of text-like data, 10 times
+///
+/// Produces the speed in MiB/s, and the compressed size as a fraction of the original
+pub fn bench_compression(compression: Compression) -> (f64, f64) {
+    let bytes = sample_text();
+    let mut total_duration = Duration::new(0, 0);
+    let mut compressed_length = 0;
+    for _ in 0..COMPRESSION_REPETITIONS {
+        // Clone the input
+        let x = bytes.clone();
+        // Time the compression
+        let start = Instant::now();
+        compressed_length = compression.compress(x).len();
+        total_duration += start.elapsed();
+    }
+    let elapsed = total_duration.as_secs_f64();
+    (
+        (COMPRESSION_REPETITIONS as f64) / elapsed,
+        compressed_length as f64 / ONE_MIB as f64,
+    )
+}
+
 pub async fn bench_crypto() -> Result<()> {
     // Print the info
     println!(
@@ -41,26 +118,22 @@ pub async fn bench_crypto() -> Result<()> {
                         
 This command will provide benchmarks of the raw single threaded performance of
 Encryption and HMAC operations with each of Asuran's supported crypto
-primitives.
+primitives, followed by each of its supported compression algorithms.
 
 These benchmarks are *not* the final throughput of asuran. Compression and
 chunker settings are likely to have a far greater impact on final throughput
-than any of these.
+than the crypto primitives, and how well data compresses depends heavily on
+the data itself.
 
                           === Beginning Benchmarks ===\n"
     );
+    print_hardware_support();
     // Flush the output before doing anything
     io::stdout().flush()?;
 
     let mut map: HashMap<Encryption, Vec<(HMAC, f64)>> = HashMap::new();
     let encryptions = vec![Encryption::new_aes256ctr(), Encryption::new_chacha20()];
-    let hmacs = vec![
-        HMAC::SHA256,
-        HMAC::Blake2b,
-        HMAC::Blake2bp,
-        HMAC::Blake3,
-        HMAC::SHA3,
-    ];
+    let hmacs = HMAC::supported();
     for enc in encryptions.clone() {
         let mut results: Vec<(HMAC, f64)> = Vec::new();
         for hmac in &hmacs {
@@ -77,26 +150,57 @@ than any of these.
     table.set_titles(row![
         "       Encryption Type        ",
         "       HMAC Type      ",
-        "       Speed      "
+        "       Speed      ",
+        " Hardware Accelerated "
     ]);
     for enc in encryptions {
         let mut first = true;
         let results = map.remove(&enc).unwrap();
         for (hmac, result) in results {
-            let enc = if first {
-                encryption_to_str(&enc).to_string()
+            let (name, accelerated) = if first {
+                (
+                    encryption_to_str(&enc).to_string(),
+                    yes_no(enc.hardware_accelerated()).to_string(),
+                )
             } else {
-                "".to_string()
+                ("".to_string(), "".to_string())
             };
             first = false;
             table.add_row(row![
-                enc,
+                name,
                 hmac_to_str(hmac).to_string(),
-                format!("{:.2} MiB/s", result)
+                format!("{:.2} MiB/s", result),
+                accelerated
             ]);
         }
     }
     table.printstd();
+
+    println!("\n                          === Benchmarking Compression ===\n");
+    let mut results = Vec::new();
+    for compression in Compression::supported() {
+        if compression == Compression::NoCompression {
+            continue;
+        }
+        results.push((compression, bench_compression(compression)));
+        print!("*");
+        io::stdout().flush()?;
+    }
+    println!("\n                                === Results ===\n");
+    let mut table = Table::new();
+    table.set_titles(row![
+        "       Compression Type       ",
+        "       Speed      ",
+        "   Compressed Size   "
+    ]);
+    for (compression, (speed, ratio)) in results {
+        table.add_row(row![
+            compression_to_string(compression),
+            format!("{:.2} MiB/s", speed),
+            format!("{:.1}%", ratio * 100.0)
+        ]);
+    }
+    table.printstd();
     println!(
         "\n                              === Authors Note ===
 
@@ -113,6 +217,185 @@ choose the fastest combination that does not include SHA2."
     Ok(())
 }
 
+/// Reports which crypto instructions the CPU has, and whether this build makes use of
+/// them
+fn print_hardware_support() {
+    let cpu = CpuFeatures::detect();
+    let aes = Encryption::new_aes256ctr();
+    let chacha = Encryption::new_chacha20();
+    let mut table = Table::new();
+    table.set_titles(row![
+        "       Instructions       ",
+        " Supported by CPU ",
+        " Used by this build "
+    ]);
+    table.add_row(row![
+        "AES (AES-NI/ARMv8 Crypto)",
+        yes_no(cpu.aes),
+        yes_no(aes.hardware_accelerated())
+    ]);
+    table.add_row(row![
+        "SIMD (SSE2/AVX2/NEON)",
+        yes_no(cpu.simd),
+        yes_no(chacha.hardware_accelerated())
+    ]);
+    table.printstd();
+    if cpu.aes && !aes.hardware_accelerated() {
+        println!(
+            "
+This CPU supports hardware AES, but this build of asuran-cli does not use it.
+Rebuilding with RUSTFLAGS=\"-C target-feature=+aes,+ssse3\" will enable it."
+        );
+    }
+    println!(
+        "
+`--encryption auto` will select {} on this machine.\n",
+        encryption_to_str(&Encryption::auto())
+    );
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "Yes"
+    } else {
+        "No"
+    }
+}
+
+/// Generates incompressible data without any repetition for the chunkers to find
+fn sample_random(size: usize) -> Vec<u8> {
+    let mut state = 0x9E37_79B9_7F4A_7C15_u64;
+    let mut output = Vec::with_capacity(size + 8);
+    while output.len() < size {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        output.extend_from_slice(&state.wrapping_mul(0x2545_F491_4F6C_DD1D).to_le_bytes());
+    }
+    output.truncate(size);
+    output
+}
+
+/// The size of the chunks a chunker produced over a sample, and how long it took
+struct ChunkerResult {
+    speed: f64,
+    sizes: Vec<usize>,
+}
+
+/// Runs the chunker over the sample once
+///
+/// Produces the speed in MiB/s, along with the size of every chunk produced
+fn bench_chunker_with(chunker: impl Chunker, sample: &[u8]) -> Result<ChunkerResult> {
+    // Copy the input before starting the timer
+    let x = sample.to_vec();
+    let start = Instant::now();
+    let sizes = chunker
+        .chunk_slice(x)
+        .map(|chunk| chunk.map(|chunk| chunk.len()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let elapsed = start.elapsed().as_secs_f64();
+    Ok(ChunkerResult {
+        speed: (sample.len() as f64 / ONE_MIB as f64) / elapsed,
+        sizes,
+    })
+}
+
+/// Runs every chunker over the sample, in the order of `CHUNKER_NAMES`
+fn bench_chunkers(sample: &[u8]) -> Result<Vec<ChunkerResult>> {
+    let fastcdc2020 = |normalization| FastCDC2020 {
+        normalization,
+        ..FastCDC2020::default()
+    };
+    let results = vec![
+        bench_chunker_with(FastCDC::default(), sample)?,
+        bench_chunker_with(fastcdc2020(Normalization::Level1), sample)?,
+        bench_chunker_with(fastcdc2020(Normalization::Level2), sample)?,
+        bench_chunker_with(fastcdc2020(Normalization::Level3), sample)?,
+        bench_chunker_with(BuzHash::with_default(0), sample)?,
+        bench_chunker_with(StaticSize::default(), sample)?,
+    ];
+    Ok(results)
+}
+
+const CHUNKER_NAMES: &[&str] = &[
+    "FastCDC",
+    "FastCDC 2020 (NC1)",
+    "FastCDC 2020 (NC2)",
+    "FastCDC 2020 (NC3)",
+    "BuzHash",
+    "Static Size",
+];
+
+fn format_size(size: usize) -> String {
+    format!("{:.1} KiB", size as f64 / 1024.0)
+}
+
+pub async fn bench_chunker(samples: Vec<PathBuf>) -> Result<()> {
+    println!(
+        "                       === asuran-cli bench-chunker ===
+
+This command will provide benchmarks of the raw single threaded performance of
+each of Asuran's chunkers, with their default settings, along with the sizes of
+the chunks they produce.
+
+Random data shows each chunker's best case speed and its natural chunk size
+distribution, while text-like data and your own sample files give a better idea
+of how it will behave on real backups. Smaller chunks deduplicate better, at the
+cost of a larger index.
+
+                          === Beginning Benchmarks ===\n"
+    );
+    io::stdout().flush()?;
+
+    let random = sample_random(CHUNKER_SAMPLE_SIZE);
+    let text = sample_text().repeat(CHUNKER_SAMPLE_SIZE / ONE_MIB);
+    let mut inputs = vec![
+        ("Random data".to_string(), random),
+        ("Text-like data".to_string(), text),
+    ];
+    for sample in samples {
+        let data = std::fs::read(&sample)?;
+        inputs.push((sample.display().to_string(), data));
+    }
+
+    for (name, sample) in inputs {
+        let results = bench_chunkers(&sample)?;
+        println!(
+            "\n=== {} ({:.2} MiB) ===\n",
+            name,
+            sample.len() as f64 / ONE_MIB as f64
+        );
+        let mut table = Table::new();
+        table.set_titles(row![
+            "      Chunker      ",
+            "     Speed     ",
+            " Chunks ",
+            "   Min   ",
+            "  Median  ",
+            "   Mean   ",
+            "   Max   "
+        ]);
+        for (chunker, result) in CHUNKER_NAMES.iter().zip(results) {
+            let mut sizes = result.sizes;
+            sizes.sort_unstable();
+            let count = sizes.len();
+            let mean = sizes.iter().sum::<usize>() / count.max(1);
+            table.add_row(row![
+                chunker,
+                format!("{:.2} MiB/s", result.speed),
+                count,
+                format_size(sizes.first().copied().unwrap_or(0)),
+                format_size(sizes.get(count / 2).copied().unwrap_or(0)),
+                format_size(mean),
+                format_size(sizes.last().copied().unwrap_or(0))
+            ]);
+        }
+        table.printstd();
+        io::stdout().flush()?;
+    }
+    Ok(())
+}
+
 fn encryption_to_str(encryption: &Encryption) -> &'static str {
     match encryption {
         Encryption::AES256CTR { .. } => "AES256-CTR",
@@ -121,6 +404,18 @@ fn encryption_to_str(encryption: &Encryption) -> &'static str {
     }
 }
 
+fn compression_to_string(compression: Compression) -> String {
+    match compression {
+        Compression::NoCompression => "None".to_string(),
+        Compression::ZStd { level } => format!("ZStd (level {})", level),
+        Compression::LZ4 { level } => format!("LZ4 (level {})", level),
+        Compression::LZ4HC { level } => format!("LZ4-HC (level {})", level),
+        Compression::LZMA { level } => format!("LZMA (level {})", level),
+        Compression::Brotli { level } => format!("Brotli (level {})", level),
+        Compression::ZStdDict { level, .. } => format!("ZStd + dictionary (level {})", level),
+    }
+}
+
 fn hmac_to_str(hmac: HMAC) -> &'static str {
     match hmac {
         HMAC::SHA256 => "SHA2",
@@ -128,5 +423,6 @@ fn hmac_to_str(hmac: HMAC) -> &'static str {
         HMAC::Blake2bp => "BLAKE2bp",
         HMAC::Blake3 => "BLAKE3",
         HMAC::SHA3 => "SHA3",
+        HMAC::Unknown(_) => "Unknown",
     }
 }
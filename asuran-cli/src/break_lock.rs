@@ -0,0 +1,41 @@
+use crate::cli::{Opt, RepositoryType};
+
+use asuran::repository::backend::multifile::lock;
+
+use anyhow::{anyhow, Result};
+
+/// Removes every lock held on a repository, reporting who held each of them
+///
+/// With `list` set, only reports the locks, without removing them.
+pub fn break_lock(options: Opt, list: bool) -> Result<()> {
+    let repo_opts = options.repo_opts();
+    if !matches!(repo_opts.repository_type, RepositoryType::MultiFile) {
+        return Err(anyhow!(
+            "Breaking locks is only supported for MultiFile repositories"
+        ));
+    }
+    let locks = if list {
+        lock::locks(&repo_opts.repo)?
+    } else {
+        lock::break_locks(&repo_opts.repo)?
+    };
+    if list || !options.quiet {
+        for lock in &locks {
+            match &lock.holder {
+                Some(holder) => println!(
+                    "{} lock {}, held by {}",
+                    lock.kind,
+                    lock.path.display(),
+                    holder
+                ),
+                None => println!("{} lock {}", lock.kind, lock.path.display()),
+            }
+        }
+        if locks.is_empty() {
+            println!("The repository is not locked");
+        } else if !list {
+            println!("Removed {} locks", locks.len());
+        }
+    }
+    Ok(())
+}
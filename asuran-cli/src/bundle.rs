@@ -0,0 +1,103 @@
+use crate::cli::{self_tested, Opt};
+use crate::new;
+
+use asuran::repository::bundle::*;
+use asuran::repository::*;
+
+use anyhow::{anyhow, Context, Result};
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+/// Exports the entire repository as a bundle, written to `output`, or stdout if
+/// `output` is `-`.
+///
+/// If `volume_size` is provided, the bundle is split into numbered volumes of at most
+/// that many bytes, named after `output`.
+pub async fn create(options: Opt, output: PathBuf, volume_size: Option<u64>) -> Result<()> {
+    // First, open a connection to the repository
+    let (backend, key) = options.open_repo_backend().await?;
+    let encrypted_key = backend
+        .read_key()
+        .await
+        .with_context(|| "Unable to read repository key material")?;
+    let chunk_settings = options.get_chunk_settings();
+    let repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    let mut repo = self_tested(repo, options.read_only).await?;
+
+    let to_stdout = output.to_str() == Some("-");
+    let writer: Box<dyn Write> = match volume_size {
+        Some(0) => return Err(anyhow!("Volume size must be greater than zero")),
+        Some(_) if to_stdout => {
+            return Err(anyhow!("Bundles written to stdout can not be split"));
+        }
+        Some(volume_size) => Box::new(VolumeWriter::new(&output, volume_size)),
+        None if to_stdout => Box::new(io::stdout()),
+        None => Box::new(
+            File::create(&output)
+                .with_context(|| format!("Unable to create bundle at {:?}", output))?,
+        ),
+    };
+    let stats = export_bundle(&mut repo, &encrypted_key, BufWriter::new(writer)).await?;
+    repo.close().await;
+
+    if !options.quiet && !to_stdout {
+        println!(
+            "Wrote {} chunks and {} archives ({} bytes) to bundle",
+            stats.chunks, stats.archives, stats.bytes
+        );
+    }
+    Ok(())
+}
+
+/// Creates a new repository from the bundle at `input`, or read from stdin if `input`
+/// is `-`.
+///
+/// If `input` does not exist, but its first volume does, the bundle is read from its
+/// volumes.
+pub async fn restore(options: Opt, input: PathBuf) -> Result<()> {
+    let reader: Box<dyn Read> = if input.to_str() == Some("-") {
+        Box::new(io::stdin())
+    } else if input.exists() {
+        Box::new(File::open(&input).with_context(|| format!("Unable to open {:?}", input))?)
+    } else if volume_path(&input, 0).exists() {
+        Box::new(VolumeReader::open(&input)?)
+    } else {
+        return Err(anyhow!("No bundle or bundle volumes found at {:?}", input));
+    };
+    let reader = BundleReader::open(BufReader::new(reader))?;
+    let key = reader
+        .encrypted_key()
+        .decrypt(options.repo_opts().password()?.as_bytes())
+        .with_context(|| "Unable to decrypt key material, possibly due to an invalid password")?;
+
+    // The new repository must use the same key as the old one, or none of the chunks
+    // in the bundle will be readable
+    let chunk_settings = options.get_chunk_settings();
+    new::create(
+        &options,
+        key,
+        reader.encrypted_key().clone(),
+        chunk_settings,
+        false,
+        None,
+        None,
+    )
+    .await?;
+    let (backend, key) = options.open_repo_backend().await?;
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    let stats = reader.import(&mut repo).await;
+    repo.close().await;
+    let stats = stats.with_context(|| {
+        "Failed to import bundle, the partially restored repository should be discarded"
+    })?;
+
+    if !options.quiet {
+        println!(
+            "Restored {} chunks and {} archives from bundle",
+            stats.chunks, stats.archives
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use crate::cli::{Opt, RepositoryType};
+use crate::log;
+
+use asuran::manifest::integrity::{verify_integrity, IntegrityReport};
+use asuran::manifest::Manifest;
+use asuran::repository::audit::Operation;
+use asuran::repository::backend::flatfile::FlatFile;
+use asuran::repository::*;
+
+use anyhow::{anyhow, Result};
+
+/// Verifies every chunk in a repository, optionally repairing any damage that can
+/// be rebuilt from parity, and reports what was found.
+pub async fn check(options: Opt, repair: bool) -> Result<()> {
+    if repair && options.read_only {
+        return Err(anyhow!("Unable to repair a repository opened read only"));
+    }
+    // A torn FlatFile will not open at all, so it has to be dealt with first
+    let flatfile = matches!(
+        options.repo_opts().repository_type,
+        RepositoryType::FlatFile
+    );
+    if flatfile {
+        check_flatfile_tail(&options, repair)?;
+    }
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    // A read only repository can still be checked, it just can not record having been
+    if !options.read_only {
+        log::record(&mut repo, Operation::Check, None).await?;
+    }
+    // FlatFiles do not support verifying their chunks, getting this far is all there is to it
+    if flatfile {
+        repo.close().await;
+        if !options.quiet {
+            println!(
+                "Repository opened successfully, FlatFile chunks can not be checked individually"
+            );
+        }
+        return Ok(());
+    }
+    let report = repo.check(repair).await;
+    let integrity = match &report {
+        Ok(_) => {
+            let archives = Manifest::load(&repo).archives().await;
+            Some(verify_integrity(&mut repo, &archives).await)
+        }
+        Err(_) => None,
+    };
+    repo.close().await;
+    let report = report?;
+    for location in &report.repairable {
+        println!(
+            "Repairable: chunk {} in segment {}",
+            location.start, location.segment_id
+        );
+    }
+    for location in &report.unrecoverable {
+        println!(
+            "Unrecoverable: chunk {} in segment {}",
+            location.start, location.segment_id
+        );
+    }
+    if !options.quiet {
+        println!(
+            "Checked {} chunks, repaired {}",
+            report.chunks_checked, report.repaired
+        );
+    }
+    if !report.is_clean() {
+        return Err(anyhow!(
+            "Found {} damaged chunks",
+            report.repairable.len() + report.unrecoverable.len()
+        ));
+    }
+    match integrity {
+        Some(integrity) => report_integrity(&options, &integrity?),
+        None => Ok(()),
+    }
+}
+
+/// Prints the findings of `verify_integrity`, returning an error if there are any
+fn report_integrity(options: &Opt, report: &IntegrityReport) -> Result<()> {
+    for archive in &report.tampered_records {
+        println!(
+            "Tampered: integrity record of archive {} ({})",
+            archive.name(),
+            archive.timestamp().to_rfc2822()
+        );
+    }
+    for id in &report.missing {
+        println!("Missing: chunk {}", hex(*id));
+    }
+    for id in &report.replaced {
+        println!("Replaced: chunk {}", hex(*id));
+    }
+    if !options.quiet && report.archives_checked > 0 {
+        println!(
+            "Checked the integrity records of {} archives, covering {} chunks",
+            report.archives_checked, report.chunks_checked
+        );
+    }
+    if report.is_clean() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Found signs of tampering: {} missing chunks, {} replaced chunks, {} tampered records",
+            report.missing.len(),
+            report.replaced.len(),
+            report.tampered_records.len()
+        ))
+    }
+}
+
+/// Formats a chunk ID as hex
+fn hex(id: ChunkID) -> String {
+    id.get_id()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Looks for an entry left torn at the end of a FlatFile by an interrupted write, cutting
+/// it off if `repair` is set
+fn check_flatfile_tail(options: &Opt, repair: bool) -> Result<()> {
+    let repo_opts = options.repo_opts();
+    let key = repo_opts.flatfile_key()?;
+    if repair {
+        let removed = FlatFile::repair_tail(&repo_opts.repo, &key)?;
+        if removed > 0 && !options.quiet {
+            println!(
+                "Removed {} bytes of a torn entry from the end of the repository",
+                removed
+            );
+        }
+    } else if let Some(offset) = FlatFile::check_tail(&repo_opts.repo, &key)? {
+        return Err(anyhow!(
+            "Repository ends with a torn entry at byte {}, left behind by an interrupted write. \
+             Run check with --repair to remove it.",
+            offset
+        ));
+    }
+    Ok(())
+}
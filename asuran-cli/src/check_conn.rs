@@ -0,0 +1,238 @@
+use crate::cli::Opt;
+
+use asuran::manifest::Manifest;
+use asuran::repository::backend::{Backend, BackendError, Index};
+use asuran::repository::*;
+
+use anyhow::{anyhow, Result};
+use prettytable::{row, Table};
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Size of the scratch chunk written to the repository
+const SCRATCH_SIZE: usize = 4096;
+/// Number of times the key is read to measure the round trip time
+const LATENCY_SAMPLES: usize = 5;
+
+/// How one of the checks went
+enum Outcome {
+    Passed(String),
+    Skipped(String),
+    Failed(String),
+}
+
+/// The outcomes of the checks, in the order they were run
+#[derive(Default)]
+struct Report {
+    checks: Vec<(&'static str, Outcome, Option<Duration>)>,
+}
+
+impl Report {
+    fn record(&mut self, check: &'static str, outcome: Outcome, elapsed: Option<Duration>) {
+        self.checks.push((check, outcome, elapsed));
+    }
+
+    /// Prints the report, returning an error if any of the checks failed
+    fn finish(self) -> Result<()> {
+        let mut table = Table::new();
+        table.add_row(row!["Check", "Result", "Time", "Details"]);
+        let mut failed = 0;
+        for (check, outcome, elapsed) in &self.checks {
+            let (result, details) = match outcome {
+                Outcome::Passed(details) => ("ok", details),
+                Outcome::Skipped(details) => ("skipped", details),
+                Outcome::Failed(details) => {
+                    failed += 1;
+                    ("FAILED", details)
+                }
+            };
+            let time = elapsed.map(millis).unwrap_or_default();
+            table.add_row(row![check, result, time, details]);
+        }
+        table.printstd();
+        if failed > 0 {
+            Err(anyhow!(
+                "{} of {} connection checks failed",
+                failed,
+                self.checks.len()
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Exercises each thing a backup needs the repository's backend to do, printing a report of
+/// how each went and how long it took
+pub async fn check_conn(options: Opt) -> Result<()> {
+    let mut report = Report::default();
+    // Opening the repository reads and decrypts its key, so this covers authentication
+    let start = Instant::now();
+    let (backend, key) = match options.open_repo_backend().await {
+        Ok(opened) => {
+            report.record(
+                "connect",
+                Outcome::Passed(format!(
+                    "Opened the {:?} repository and decrypted its key",
+                    options.repo_opts().repository_type
+                )),
+                Some(start.elapsed()),
+            );
+            opened
+        }
+        Err(error) => {
+            report.record(
+                "connect",
+                Outcome::Failed(format!("{:#}", error)),
+                Some(start.elapsed()),
+            );
+            return report.finish();
+        }
+    };
+    // Keep a handle on the backend, so its errors can be reported rather than unwrapped
+    let mut probe = backend.clone();
+    let chunk_settings = options.get_chunk_settings();
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+
+    let start = Instant::now();
+    let archives = Manifest::load(&repo).archives().await.len();
+    let chunks = repo.count_chunk().await;
+    report.record(
+        "list",
+        Outcome::Passed(format!("Found {} archives and {} chunks", archives, chunks)),
+        Some(start.elapsed()),
+    );
+
+    if options.read_only {
+        for check in &["write", "read", "delete"] {
+            report.record(
+                check,
+                Outcome::Skipped("The repository was opened read only".to_string()),
+                None,
+            );
+        }
+    } else {
+        check_scratch(&mut repo, &mut probe, &mut report).await;
+    }
+
+    let mut samples = Vec::with_capacity(LATENCY_SAMPLES);
+    let mut error = None;
+    for _ in 0..LATENCY_SAMPLES {
+        let start = Instant::now();
+        if let Err(e) = probe.read_key().await {
+            error = Some(e);
+            break;
+        }
+        samples.push(start.elapsed());
+    }
+    match error {
+        Some(error) => report.record("latency", Outcome::Failed(error.to_string()), None),
+        None => {
+            let mean = samples.iter().sum::<Duration>() / LATENCY_SAMPLES as u32;
+            report.record(
+                "latency",
+                Outcome::Passed(format!(
+                    "Read the key {} times, taking between {} and {}",
+                    LATENCY_SAMPLES,
+                    millis(*samples.iter().min().unwrap()),
+                    millis(*samples.iter().max().unwrap())
+                )),
+                Some(mean),
+            );
+        }
+    }
+
+    repo.close().await;
+    report.finish()
+}
+
+/// Writes a scratch chunk to the repository, reads it back, and removes it again, recording
+/// how each step went
+async fn check_scratch(
+    repo: &mut Repository<impl BackendClone>,
+    probe: &mut impl Backend,
+    report: &mut Report,
+) {
+    // Make the chunk unique, so it can not already be in the repository
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut data = format!(
+        "asuran-cli check-conn scratch chunk, process {} at {}",
+        std::process::id(),
+        nanos
+    )
+    .into_bytes();
+    data.resize(SCRATCH_SIZE, 0);
+
+    // Writing isn't done until the chunk is flushed and indexed
+    let start = Instant::now();
+    let written = match repo.write_chunk(data.clone()).await {
+        Ok((id, present)) => match probe.flush().await {
+            Ok(()) => probe
+                .get_index()
+                .commit_index()
+                .await
+                .map(|_| (id, present))
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+        Err(e) => Err(e.to_string()),
+    };
+    let (id, present) = match written {
+        Ok(written) => {
+            report.record(
+                "write",
+                Outcome::Passed(format!("Wrote a {} byte scratch chunk", SCRATCH_SIZE)),
+                Some(start.elapsed()),
+            );
+            written
+        }
+        Err(error) => {
+            report.record("write", Outcome::Failed(error), Some(start.elapsed()));
+            for check in &["read", "delete"] {
+                report.record(
+                    check,
+                    Outcome::Skipped("The scratch chunk could not be written".to_string()),
+                    None,
+                );
+            }
+            return;
+        }
+    };
+
+    let start = Instant::now();
+    let outcome = match repo.read_chunk(id).await {
+        Ok(read) if read == data => Outcome::Passed("Read back the scratch chunk".to_string()),
+        Ok(_) => Outcome::Failed("Read back different data than was written".to_string()),
+        Err(e) => Outcome::Failed(e.to_string()),
+    };
+    report.record("read", outcome, Some(start.elapsed()));
+
+    if present {
+        report.record(
+            "delete",
+            Outcome::Skipped("The scratch chunk was already in the repository".to_string()),
+            None,
+        );
+        return;
+    }
+    let start = Instant::now();
+    let mut chunks = HashSet::new();
+    chunks.insert(id);
+    let outcome = match probe.remove_chunks(chunks).await {
+        Ok(_) => Outcome::Passed("Removed the scratch chunk".to_string()),
+        Err(e @ BackendError::Unsupported(_)) | Err(e @ BackendError::AppendOnly(_)) => {
+            Outcome::Skipped(format!("{}, the scratch chunk was left in place", e))
+        }
+        Err(e) => Outcome::Failed(e.to_string()),
+    };
+    report.record("delete", outcome, Some(start.elapsed()));
+}
+
+/// Formats a duration in milliseconds
+fn millis(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,20 @@
+use crate::cli::Opt;
+
+use anyhow::Result;
+
+/// Squashes the manifest of a repository into a single checkpoint, reporting how many
+/// transactions were replaced.
+pub async fn checkpoint(options: Opt, drop: bool) -> Result<()> {
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    let stats = repo.checkpoint(!drop).await;
+    repo.close().await;
+    let stats = stats?;
+    if !options.quiet {
+        println!(
+            "Squashed {} transactions from {} files into a checkpoint of {} archives",
+            stats.transactions_squashed, stats.files_removed, stats.archives
+        );
+    }
+    Ok(())
+}
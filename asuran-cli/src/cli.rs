@@ -3,16 +3,24 @@ The `cli` module provides the data types used for parsing the command line
 arguements, as well as some utility functions for converting those types to
 their equivlants in `asuran` proper.
 */
+use crate::os_keyring;
+use crate::password::Password;
+
+use asuran::manifest::retention::RetentionPolicy;
+use asuran::manifest::signing::{SignaturePolicy, VerifyingKey};
+use asuran::repository::backend::common::sync_backend::BackendHandle;
 use asuran::repository::backend::object_wrappers::BackendObject;
 use asuran::repository::{self, Backend, Key};
+use asuran::Error;
 
 use anyhow::{anyhow, Context, Result};
 use clap::{arg_enum, AppSettings};
-use repository::backend::{flatfile, multifile};
+use repository::backend::{flatfile, multifile, sftp};
 use structopt::StructOpt;
 
 use std::env;
 use std::fs::metadata;
+use std::io::BufWriter;
 use std::path::PathBuf;
 
 /// The version + git commit + build date string the program idenitifes itself
@@ -35,6 +43,8 @@ arg_enum! {
         MultiFile,
         FlatFile,
         SFTP,
+        Remote,
+        WebDAV,
     }
 }
 
@@ -44,11 +54,15 @@ arg_enum! {
     /// These are, more or less, a 1-to-1 corrospondance with the name of the
     /// `Encryption` enum variant in the `asuran` crate, but these do not carry
     /// an IV with them.
+    ///
+    /// `Auto` selects AES256CTR if this build's AES implementation is hardware
+    /// accelerated, and ChaCha20 otherwise.
     #[derive(Debug, Clone)]
     pub enum Encryption {
         AES256CBC,
         AES256CTR,
         ChaCha20,
+        Auto,
         None,
     }
 }
@@ -58,15 +72,80 @@ arg_enum! {
    /// These are, more or less, a 1-to-1 corrospondance with the name of the
    /// `Compression` enum variant in the `asuran` crate, but these do not carry
    /// a compression level with them.
+   ///
+   /// `ZStdDict` does not carry a dictionary either, the one recorded in the
+   /// repository's default settings by `train-dictionary` is used.
    #[derive(Debug, Clone)]
    pub enum Compression {
        ZStd,
        LZ4,
        LZMA,
-       None
+       None,
+       ZStdDict,
+       Brotli,
+       LZ4HC
    }
 }
 
+arg_enum! {
+    /// The compression to apply to an exported tar stream
+    #[derive(Debug, Clone)]
+    pub enum TarCompression {
+        None,
+        Gzip,
+        ZStd,
+    }
+}
+
+arg_enum! {
+    /// The format to export metrics in
+    #[derive(Debug, Clone)]
+    pub enum MetricsFormat {
+        Prometheus,
+        OTLP,
+    }
+}
+
+arg_enum! {
+    /// The padding the user has selected
+    ///
+    /// These are a 1-to-1 corrospondance with the `Padding` enum variant in the
+    /// `asuran` crate
+    #[derive(Debug, Clone)]
+    pub enum Padding {
+        None,
+        Padme,
+        PowerOfTwo,
+    }
+}
+
+arg_enum! {
+    /// The chunker the user has selected
+    ///
+    /// These correspond to the variants of `ChunkerSettings` in the `asuran` crate, but
+    /// do not carry any parameters with them.
+    #[derive(Debug, Clone)]
+    pub enum ChunkerType {
+        FastCDC,
+        FastCDC2020,
+        BuzHash,
+        KeyedBuzHash,
+    }
+}
+
+arg_enum! {
+    /// How often local backends sync written data to disk
+    ///
+    /// These are a 1-to-1 corrospondance with the `Durability` enum variant in the
+    /// `asuran` crate
+    #[derive(Debug, Clone)]
+    pub enum Durability {
+        Always,
+        OnCommit,
+        Never,
+    }
+}
+
 arg_enum! {
     /// The HMAC algorithim the user has selected
     ///
@@ -82,6 +161,41 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    /// The algorithm used to derive chunk IDs
+    ///
+    /// These are a 1-to-1 corrospondance with the `ChunkIDAlgorithm` enum variant in
+    /// the `asuran` crate
+    #[derive(Debug, Clone)]
+    pub enum ChunkIDAlgorithm {
+        HMAC,
+        Blake3,
+    }
+}
+
+arg_enum! {
+    /// The algorithm the full contents of each stored file are hashed with
+    ///
+    /// These are a 1-to-1 corrospondance with the `ObjectHashAlgorithm` enum variant in
+    /// the `asuran` crate
+    #[derive(Debug, Clone)]
+    pub enum ObjectHash {
+        BLAKE3,
+        SHA256,
+    }
+}
+
+arg_enum! {
+    /// The kind of filesystem snapshot to store from
+    #[derive(Debug, Clone)]
+    pub enum SnapshotKind {
+        Btrfs,
+        ZFS,
+        LVM,
+        VSS,
+    }
+}
+
 /// A high performance, de-duplicating archiver, with no-compromises security.
 #[derive(StructOpt, Debug, Clone)]
 pub enum Command {
@@ -89,6 +203,12 @@ pub enum Command {
     List {
         #[structopt(flatten)]
         repo_opts: RepoOpt,
+        #[structopt(flatten)]
+        signature_opts: SignatureOpt,
+        /// Only list archives carrying this tag. May be given more than once, in which
+        /// case archives must carry every tag
+        #[structopt(short, long)]
+        tag: Vec<String>,
     },
     /// Creates a new archive in a repository
     Store {
@@ -100,6 +220,89 @@ pub enum Command {
         /// Name for the new archive. Defaults to an ISO date/time stamp
         #[structopt(short, long)]
         name: Option<String>,
+        /// Shell command to scan each file with before it is stored
+        ///
+        /// The command is fed the content of the file on stdin, and receives its path
+        /// as its first argument. Each line the command prints is stored as a tag on
+        /// the file. Files for which the command exits unsuccessfully are not stored.
+        #[structopt(long)]
+        scan_command: Option<String>,
+        /// Patterns, relative to TARGET, of paths to leave out of the archive
+        #[structopt(short = "E", long)]
+        exclude: Vec<String>,
+        /// Leave out directories containing a CACHEDIR.TAG file, as placed in cache
+        /// directories by many build tools and package managers
+        #[structopt(long)]
+        exclude_caches: bool,
+        /// Leave out directories containing a file with this name. May be given more than
+        /// once
+        #[structopt(long)]
+        exclude_if_present: Vec<String>,
+        /// Do not descend into directories on a different filesystem than TARGET
+        #[structopt(long)]
+        one_file_system: bool,
+        /// Follow symbolic links, storing what they point to, rather than storing the links
+        /// themselves
+        #[structopt(long)]
+        dereference: bool,
+        /// Read the contents of block devices, such as LVM snapshots, rather than storing
+        /// just the device nodes
+        #[structopt(long)]
+        read_special: bool,
+        /// Store the alternate data streams of files, on Windows
+        #[structopt(long)]
+        alternate_streams: bool,
+        /// List every path that was not stored, and why
+        #[structopt(long)]
+        list_skipped: bool,
+        /// Tag to record on the archive. May be given more than once
+        #[structopt(short, long)]
+        tag: Vec<String>,
+        /// Metadata to record on the archive, in the form KEY=VALUE. May be given more than
+        /// once
+        #[structopt(long, parse(try_from_str = parse_key_value))]
+        meta: Vec<(String, String)>,
+        /// Commit a checkpoint of the archive at least this often, such as 30m or 1h, so
+        /// that an interrupted backup can be resumed
+        #[structopt(long, parse(try_from_str = parse_interval))]
+        checkpoint_interval: Option<std::time::Duration>,
+        /// Commit a checkpoint of the archive after storing this much data, such as 500M or
+        /// 2G, so that an interrupted backup can be resumed
+        #[structopt(long, parse(try_from_str = parse_size))]
+        checkpoint_size: Option<u64>,
+        /// Record the chunks stored along with the archive, so `check` can detect chunks
+        /// that were later deleted or replaced by someone with access to the storage
+        #[structopt(long)]
+        integrity: bool,
+        /// Sign the archive with the key in this file, as written by generate-signing-key.
+        /// Can also be specified with the ASURAN_SIGNING_KEY_FILE enviroment variable
+        #[structopt(long, env = "ASURAN_SIGNING_KEY_FILE")]
+        signing_key: Option<PathBuf>,
+        /// Algorithm to hash the contents of each file with. The hash is stored in the
+        /// listing, and checked when the file is extracted or the archive is verified
+        #[structopt(
+            long,
+            default_value = "BLAKE3",
+            case_insensitive(true),
+            possible_values(&ObjectHash::variants())
+        )]
+        object_hash: ObjectHash,
+        /// Read and chunk everything as usual, but only report how much would be uploaded,
+        /// without writing anything to the repository
+        #[structopt(long)]
+        dry_run: bool,
+        /// Store from a read-only snapshot of the filesystem TARGET is on, taken right
+        /// before the backup starts and removed once it finishes
+        #[structopt(
+            long,
+            case_insensitive(true),
+            possible_values(&SnapshotKind::variants())
+        )]
+        snapshot: Option<SnapshotKind>,
+        /// Size of the copy-on-write area of LVM snapshots, optionally followed by K, M,
+        /// or G. Defaults to 1G
+        #[structopt(long, parse(try_from_str = parse_size))]
+        snapshot_size: Option<u64>,
     },
     /// Extracts an archive from a repository
     Extract {
@@ -107,10 +310,13 @@ pub enum Command {
         repo_opts: RepoOpt,
         #[structopt(flatten)]
         glob_opts: GlobOpt,
+        #[structopt(flatten)]
+        signature_opts: SignatureOpt,
         /// Location to restore to
         #[structopt(name = "TARGET")]
         target: PathBuf,
-        /// Name or ID of the archive to be restored
+        /// The archive to restore, by name, index, `latest`, `latest~N`, or a unique
+        /// prefix of its name or ID
         #[structopt(name = "ARCHIVE")]
         archive: String,
         /// Preview an extraction without actually performing it
@@ -119,27 +325,425 @@ pub enum Command {
         /// restore command.
         #[structopt(short = "P", long)]
         preview: bool,
+        /// Resolve every path against TARGET before writing to it, refusing anything that
+        /// would end up outside of it
+        ///
+        /// Use this when extracting archives from repositories you do not trust. On Linux,
+        /// files are opened with openat2, so the kernel itself refuses to follow symbolic
+        /// links or leave TARGET.
+        #[structopt(long)]
+        hardened: bool,
+        /// Only restore these paths from the archive, along with the contents of any
+        /// directories among them
+        ///
+        /// Paths are looked up directly in the archive's listing, and are restored to the
+        /// same place under TARGET they would be in a full restore. Include and exclude
+        /// globs still apply.
+        #[structopt(long)]
+        paths: Option<Vec<String>>,
     },
     /// Creates a new repository
     New {
         #[structopt(flatten)]
         repo_opts: RepoOpt,
+        /// Create the repository in append only mode
+        ///
+        /// Append only repositories refuse any operation that would delete or
+        /// rewrite existing data. Not supported for SFTP repositories.
+        #[structopt(long)]
+        append_only: bool,
+        /// Write this many Reed-Solomon parity shards along with every chunk
+        ///
+        /// Chunks damaged in up to this many shards can be repaired with `check
+        /// --repair`. Only supported for MultiFile repositories. Disabled by default.
+        #[structopt(long, default_value = "0")]
+        parity_shards: usize,
+        /// The number of data shards each chunk is split into when writing parity
+        #[structopt(long, default_value = "10")]
+        data_shards: usize,
+        /// Split the repository into volumes of this size, such as 4G or 700M, so it fits
+        /// on size limited media. Only supported for FlatFile repositories.
+        #[structopt(long, parse(try_from_str = parse_size))]
+        volume_size: Option<u64>,
     },
-    /// Runs benchmarks on all combinations of asuran's supported crypto primitives.
+    /// Generates a key for signing archives, and prints the key to verify them with
+    ///
+    /// Pass the key file to store with --signing-key, and the printed key to list and
+    /// extract with --trusted-key. The file is never overwritten.
+    GenerateSigningKey {
+        /// File to write the signing key to
+        #[structopt(name = "OUTPUT")]
+        output: PathBuf,
+    },
+    /// Runs benchmarks on all combinations of asuran's supported crypto primitives, as
+    /// well as each supported compression algorithm.
     BenchCrypto,
+    /// Runs benchmarks on each of asuran's chunkers, reporting their speed and the
+    /// distribution of the chunk sizes they produce.
+    ///
+    /// Each chunker is run over synthetic data, as well as over any sample files provided.
+    BenchChunker {
+        /// Files to use as additional samples, each is read in to memory in full
+        samples: Vec<PathBuf>,
+    },
     /// Lists the contents of an archive, with optional glob filters
     Contents {
         #[structopt(flatten)]
         repo_opts: RepoOpt,
         #[structopt(flatten)]
         glob_opts: GlobOpt,
-        /// Name or ID of the archive to list the contents of
+        /// The archive to list the contents of, by name, index, `latest`, `latest~N`, or a unique
+        /// prefix of its name or ID
+        #[structopt(name = "ARCHIVE")]
+        archive: String,
+    },
+    /// Searches the listings of every archive for objects with paths matching a pattern
+    ///
+    /// Each match is printed along with the archive it was found in, the archive's
+    /// creation time, and the object's size, making it easy to find which archives still
+    /// have a copy of a deleted file.
+    Find {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Glob to match object paths against, such as '*.pdf' or 'home/*/notes.txt'
+        #[structopt(name = "PATTERN")]
+        pattern: String,
+        /// Only search archives with names matching this glob
+        #[structopt(short, long)]
+        archive: Option<String>,
+        /// Also match the pattern against the tags stored on each file by a scan command
+        #[structopt(long)]
+        tags: bool,
+    },
+    /// Exports the contents of an archive as a tar stream
+    ExportTar {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// The archive to export, by name, index, `latest`, `latest~N`, or a unique
+        /// prefix of its name or ID
         #[structopt(name = "ARCHIVE")]
         archive: String,
+        /// File to write the tar to. Writes to stdout if omitted or set to -
+        #[structopt(name = "FILE")]
+        output: Option<PathBuf>,
+        /// Compression to apply to the tar stream
+        #[structopt(
+            long,
+            default_value = "None",
+            case_insensitive(true),
+            possible_values(&TarCompression::variants())
+        )]
+        tar_compression: TarCompression,
+    },
+    /// Compares an archive against a live directory, reporting any drift
+    ///
+    /// Exits unsuccessfully if any differences are found.
+    Compare {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// The archive to compare against, by name, index, `latest`, `latest~N`, or a unique
+        /// prefix of its name or ID
+        #[structopt(name = "ARCHIVE")]
+        archive: String,
+        /// Location of the directory to compare
+        #[structopt(name = "TARGET")]
+        target: PathBuf,
+    },
+    /// Rewrites underutilized segments, reclaiming the space used by dead chunks
+    ///
+    /// Requires that no other connections to the repository are open. Only supported
+    /// for MultiFile repositories.
+    Compact {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Segments where live chunks make up less than this fraction of their data
+        /// are rewritten
+        #[structopt(long, default_value = "0.5")]
+        threshold: f64,
+    },
+    /// Verifies every chunk in the repository, reporting any damage
+    ///
+    /// Only supported for MultiFile repositories.
+    Check {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Rebuild any damaged chunks that can be recovered from their parity, and cut
+        /// off any entry a FlatFile repository was left with by an interrupted write
+        #[structopt(long)]
+        repair: bool,
+    },
+    /// Tests the connection to a repository, exercising each thing a backup needs the
+    /// backend to do, and prints a report of how each went and how long it took
+    ///
+    /// Opens the repository and decrypts its key, lists its archives and chunks, writes,
+    /// reads back, and removes a small scratch chunk, and measures the round trip time of
+    /// reading the key. The scratch chunk is not written if the repository is opened read
+    /// only. Exits unsuccessfully if any of the checks fail.
+    CheckConn {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+    },
+    /// Performs a restore of archives without writing anything, reporting any objects
+    /// that could not be restored
+    ///
+    /// Every chunk the archives refer to is fetched, authenticated, decrypted, and
+    /// decompressed, and the length of every object is checked against the archive's
+    /// listing. Where check verifies the repository, verify makes sure archives can
+    /// actually be restored from it.
+    Verify {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// The archive to verify, by name, index, `latest`, `latest~N`, or a unique prefix
+        /// of its name or ID. Every archive is verified if not given
+        #[structopt(name = "ARCHIVE")]
+        archive: Option<String>,
+    },
+    /// Squashes the manifest's transactions into a single checkpoint, keeping the time
+    /// taken to open the repository bounded
+    ///
+    /// Requires that no other connections to the repository are open. Only supported
+    /// for MultiFile repositories.
+    Checkpoint {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Delete the squashed transactions, instead of keeping them as an audit trail
+        #[structopt(long)]
+        drop: bool,
+    },
+    /// Lists the heads of the manifest's branches, which diverge when several
+    /// connections commit archives at the same time
+    ///
+    /// Only supported for MultiFile repositories.
+    Heads {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Join the heads back into a single branch, if there is more than one
+        #[structopt(long)]
+        merge: bool,
+    },
+    /// Trains a zstd dictionary on a sample of the repository's chunks, and makes
+    /// dictionary compression the repository's default
+    ///
+    /// Dictionaries mostly help repositories made up of many small, similar chunks.
+    TrainDictionary {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Number of chunks to sample
+        #[structopt(long, default_value = "1000")]
+        samples: usize,
+        /// Maximum size of the dictionary, in bytes
+        #[structopt(long, default_value = "112640")]
+        max_size: usize,
+    },
+    /// Imports the snapshots of a restic repository as archives
+    ///
+    /// Each snapshot is stored as an archive named restic- followed by its short ID.
+    /// Snapshots that have already been imported are skipped.
+    ImportRestic {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Location of the restic repository, which must be on the local filesystem
+        #[structopt(name = "RESTIC_REPO")]
+        restic_repo: PathBuf,
+        /// Password for the restic repository. Can also be specified with the
+        /// RESTIC_PASSWORD enviroment variable. Prompted for if not given
+        #[structopt(long, env = "RESTIC_PASSWORD", hide_env_values = true)]
+        restic_password: Option<Password>,
+        /// IDs, or prefixes of IDs, of the snapshots to import. Imports every snapshot
+        /// if omitted
+        #[structopt(long)]
+        snapshot: Vec<String>,
+    },
+    /// Removes archives according to a retention policy, along with any data no longer
+    /// referenced by the remaining archives
+    ///
+    /// Only supported for MultiFile repositories.
+    Prune {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        #[structopt(flatten)]
+        retention_opts: RetentionOpt,
+        /// Report which archives would be kept and removed, without changing anything
+        #[structopt(long)]
+        dry_run: bool,
+        /// Segments where live chunks make up less than this fraction of their data
+        /// are rewritten after pruning
+        #[structopt(long, default_value = "0.5")]
+        threshold: f64,
+        /// Load every remaining archive to find the data still referenced, instead of
+        /// using the repository's reference counts, also collecting data left behind by
+        /// interrupted backups
+        #[structopt(long)]
+        full_gc: bool,
+    },
+    /// Rewrites every chunk in the repository with the compression and encryption
+    /// selected with --compression, --compression-level, and --encryption, and makes them
+    /// the repository's defaults
+    ///
+    /// Chunks keep their IDs, so no archive has to be stored again. Chunks already stored
+    /// with the new settings are skipped, so an interrupted migration can be resumed by
+    /// running it again. The space taken up by the old copies is reclaimed by compact.
+    Migrate {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Migrate even if archives have integrity records, which will then report every
+        /// migrated chunk as replaced
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Prints the repository's audit log, recording who stored, pruned, or checked it,
+    /// from where, and when
+    Log {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+    },
+    /// Removes every lock held on a repository, to recover from a crashed or killed
+    /// process that had it open
+    ///
+    /// Only use this when no other process is using the repository. Only supported for
+    /// MultiFile repositories.
+    BreakLock {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// List the locks and who holds them, without removing anything
+        #[structopt(long)]
+        list: bool,
+    },
+    /// Removes the repository's password from the platform keyring, where it was stored
+    /// by --use-keyring
+    ForgetPassword {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+    },
+    /// Copies archives to another repository, transferring only the data it does not
+    /// already contain
+    ///
+    /// Chunks are re-encrypted with the destination's key and packed with its settings.
+    /// Archives the destination already contains, with the same name and timestamp, are
+    /// skipped.
+    Copy {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Location of the repository to copy to
+        #[structopt(name = "DST_REPO")]
+        dst_repo: PathBuf,
+        /// Names or indexes of the archives to copy. Copies every archive if omitted
+        #[structopt(name = "ARCHIVE")]
+        archives: Vec<String>,
+        /// Password for the destination repository. Defaults to the password of the
+        /// source repository. Can also be specified with the ASURAN_DST_PASSWORD
+        /// enviroment variable
+        #[structopt(long, env = "ASURAN_DST_PASSWORD", hide_env_values = true)]
+        dst_password: Option<Password>,
+        /// Type of the destination repository. Defaults to the type of the source
+        /// repository
+        #[structopt(
+            long,
+            case_insensitive(true),
+            possible_values(&RepositoryType::variants())
+        )]
+        dst_repository_type: Option<RepositoryType>,
+    },
+    /// Exports or restores an entire repository as a single portable bundle
+    Bundle(BundleCommand),
+    /// Runs a backup job defined in the configuration file
+    ///
+    /// Runs the job's pre hooks, stores each of its sources as an archive tagged with
+    /// "job:NAME" and "source:PATH", prunes each source's archives according to the
+    /// job's retention rules, if it has any, and finally runs the job's post hooks.
+    /// Post hooks run even if an earlier step failed.
+    RunJob {
+        /// Name of the job to run
+        #[structopt(name = "JOB")]
+        job: String,
+        /// Location of the configuration file. Defaults to $ASURAN_CONFIG, then
+        /// $XDG_CONFIG_HOME/asuran/config.toml
+        #[structopt(long)]
+        config: Option<PathBuf>,
     },
 }
 
+/// Operations on repository bundles
+#[derive(StructOpt, Debug, Clone)]
+pub enum BundleCommand {
+    /// Exports every chunk and archive in a repository, along with its key, to a bundle
+    ///
+    /// The contents of the bundle remain encrypted with the repository's key.
+    Create {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// File to write the bundle to. Writes to stdout if set to -
+        #[structopt(name = "BUNDLE")]
+        output: PathBuf,
+        /// Split the bundle into volumes of at most this many bytes
+        ///
+        /// Volumes are named after BUNDLE, with .000, .001, and so on appended.
+        #[structopt(long)]
+        volume_size: Option<u64>,
+    },
+    /// Creates a new repository from a bundle
+    ///
+    /// The new repository keeps the key of the repository the bundle was exported
+    /// from, so the password must be the one of that repository.
+    Restore {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Bundle to restore from. Reads from stdin if set to -, and from the volumes of
+        /// the bundle if BUNDLE itself does not exist
+        #[structopt(name = "BUNDLE")]
+        input: PathBuf,
+    },
+}
+
+impl BundleCommand {
+    pub fn repo_opts(&self) -> &RepoOpt {
+        match self {
+            Self::Create { repo_opts, .. } | Self::Restore { repo_opts, .. } => repo_opts,
+        }
+    }
+
+    pub fn repo_opts_mut(&mut self) -> &mut RepoOpt {
+        match self {
+            Self::Create { repo_opts, .. } | Self::Restore { repo_opts, .. } => repo_opts,
+        }
+    }
+}
+
 impl Command {
+    /// The name of this subcommand, as given on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::List { .. } => "list",
+            Self::Store { .. } => "store",
+            Self::Extract { .. } => "extract",
+            Self::New { .. } => "new",
+            Self::GenerateSigningKey { .. } => "generate-signing-key",
+            Self::BenchCrypto => "bench-crypto",
+            Self::BenchChunker { .. } => "bench-chunker",
+            Self::Contents { .. } => "contents",
+            Self::Find { .. } => "find",
+            Self::ExportTar { .. } => "export-tar",
+            Self::Compare { .. } => "compare",
+            Self::Compact { .. } => "compact",
+            Self::Check { .. } => "check",
+            Self::CheckConn { .. } => "check-conn",
+            Self::Verify { .. } => "verify",
+            Self::Checkpoint { .. } => "checkpoint",
+            Self::Heads { .. } => "heads",
+            Self::TrainDictionary { .. } => "train-dictionary",
+            Self::ImportRestic { .. } => "import-restic",
+            Self::Prune { .. } => "prune",
+            Self::Migrate { .. } => "migrate",
+            Self::Log { .. } => "log",
+            Self::BreakLock { .. } => "break-lock",
+            Self::ForgetPassword { .. } => "forget-password",
+            Self::Copy { .. } => "copy",
+            Self::Bundle(BundleCommand::Create { .. }) => "bundle create",
+            Self::Bundle(BundleCommand::Restore { .. }) => "bundle restore",
+            Self::RunJob { .. } => "run-job",
+        }
+    }
+
     pub fn repo_opts(&self) -> &RepoOpt {
         match self {
             Self::List { repo_opts, .. } => repo_opts,
@@ -147,7 +751,119 @@ impl Command {
             Self::Extract { repo_opts, .. } => repo_opts,
             Self::New { repo_opts, .. } => repo_opts,
             Self::Contents {repo_opts, ..} => repo_opts,
-            Self::BenchCrypto => unimplemented!("asuran-cli bench does not interact with a repository, and does not have repository options."),
+            Self::Find { repo_opts, .. } => repo_opts,
+            Self::ExportTar { repo_opts, .. } => repo_opts,
+            Self::Compare { repo_opts, .. } => repo_opts,
+            Self::Compact { repo_opts, .. } => repo_opts,
+            Self::Check { repo_opts, .. } => repo_opts,
+            Self::CheckConn { repo_opts } => repo_opts,
+            Self::Verify { repo_opts, .. } => repo_opts,
+            Self::Checkpoint { repo_opts, .. } => repo_opts,
+            Self::Heads { repo_opts, .. } => repo_opts,
+            Self::TrainDictionary { repo_opts, .. } => repo_opts,
+            Self::ImportRestic { repo_opts, .. } => repo_opts,
+            Self::Prune { repo_opts, .. } => repo_opts,
+            Self::Migrate { repo_opts, .. } => repo_opts,
+            Self::Log { repo_opts, .. } => repo_opts,
+            Self::BreakLock { repo_opts, .. } => repo_opts,
+            Self::ForgetPassword { repo_opts } => repo_opts,
+            Self::Copy { repo_opts, .. } => repo_opts,
+            Self::Bundle(bundle) => bundle.repo_opts(),
+            Self::BenchCrypto | Self::BenchChunker { .. } => unimplemented!("asuran-cli bench does not interact with a repository, and does not have repository options."),
+            Self::GenerateSigningKey { .. } => unimplemented!("asuran-cli generate-signing-key does not interact with a repository, and does not have repository options."),
+            Self::RunJob { .. } => unimplemented!("asuran-cli run-job takes its repository options from the job definition."),
+        }
+    }
+
+    pub fn repo_opts_mut(&mut self) -> &mut RepoOpt {
+        match self {
+            Self::List { repo_opts, .. } => repo_opts,
+            Self::Store { repo_opts, .. } => repo_opts,
+            Self::Extract { repo_opts, .. } => repo_opts,
+            Self::New { repo_opts, .. } => repo_opts,
+            Self::Contents {repo_opts, ..} => repo_opts,
+            Self::Find { repo_opts, .. } => repo_opts,
+            Self::ExportTar { repo_opts, .. } => repo_opts,
+            Self::Compare { repo_opts, .. } => repo_opts,
+            Self::Compact { repo_opts, .. } => repo_opts,
+            Self::Check { repo_opts, .. } => repo_opts,
+            Self::CheckConn { repo_opts } => repo_opts,
+            Self::Verify { repo_opts, .. } => repo_opts,
+            Self::Checkpoint { repo_opts, .. } => repo_opts,
+            Self::Heads { repo_opts, .. } => repo_opts,
+            Self::TrainDictionary { repo_opts, .. } => repo_opts,
+            Self::ImportRestic { repo_opts, .. } => repo_opts,
+            Self::Prune { repo_opts, .. } => repo_opts,
+            Self::Migrate { repo_opts, .. } => repo_opts,
+            Self::Log { repo_opts, .. } => repo_opts,
+            Self::BreakLock { repo_opts, .. } => repo_opts,
+            Self::ForgetPassword { repo_opts } => repo_opts,
+            Self::Copy { repo_opts, .. } => repo_opts,
+            Self::Bundle(bundle) => bundle.repo_opts_mut(),
+            Self::BenchCrypto | Self::BenchChunker { .. } => unimplemented!("asuran-cli bench does not interact with a repository, and does not have repository options."),
+            Self::GenerateSigningKey { .. } => unimplemented!("asuran-cli generate-signing-key does not interact with a repository, and does not have repository options."),
+            Self::RunJob { .. } => unimplemented!("asuran-cli run-job takes its repository options from the job definition."),
+        }
+    }
+
+    /// Returns true if this command only reads archives, and so should keep seeing the
+    /// repository as it was when it was opened while other processes commit to it
+    pub fn reads_snapshot(&self) -> bool {
+        matches!(
+            self,
+            Self::List { .. }
+                | Self::Extract { .. }
+                | Self::Contents { .. }
+                | Self::Find { .. }
+                | Self::ExportTar { .. }
+                | Self::Compare { .. }
+                | Self::Copy { .. }
+        )
+    }
+}
+
+/// Retention policy options
+#[derive(Debug, StructOpt, Clone)]
+pub struct RetentionOpt {
+    /// Keep this many of the most recent archives
+    #[structopt(long, default_value = "0")]
+    pub keep_last: usize,
+    /// Keep the newest archive of this many of the most recent days
+    #[structopt(long, default_value = "0")]
+    pub keep_daily: usize,
+    /// Keep the newest archive of this many of the most recent weeks
+    #[structopt(long, default_value = "0")]
+    pub keep_weekly: usize,
+    /// Keep the newest archive of this many of the most recent months
+    #[structopt(long, default_value = "0")]
+    pub keep_monthly: usize,
+    /// Keep the newest archive of this many of the most recent years
+    #[structopt(long, default_value = "0")]
+    pub keep_yearly: usize,
+    /// Keep every archive younger than this, e.g. 12h, 7d, 2w, 6m, or 1y
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub keep_within: Option<std::time::Duration>,
+    /// Only prune archives carrying this tag. May be given more than once, in which
+    /// case archives must carry every tag
+    #[structopt(short, long)]
+    pub tag: Vec<String>,
+    /// Only prune archives whose names start with this prefix
+    #[structopt(long)]
+    pub prefix: Option<String>,
+}
+
+impl RetentionOpt {
+    /// Converts the options into a retention policy
+    pub fn policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            keep_last: self.keep_last,
+            keep_daily: self.keep_daily,
+            keep_weekly: self.keep_weekly,
+            keep_monthly: self.keep_monthly,
+            keep_yearly: self.keep_yearly,
+            keep_within: self.keep_within,
+            tags: self.tag.clone(),
+            prefix: self.prefix.clone(),
         }
     }
 }
@@ -170,16 +886,73 @@ pub struct GlobOpt {
     pub exclude: Option<Vec<String>>,
 }
 
+/// Options for checking the signatures of archives
+#[derive(Debug, StructOpt, Clone)]
+pub struct SignatureOpt {
+    /// Key of a client trusted to sign archives, as printed by generate-signing-key. May
+    /// be given more than once
+    #[structopt(long)]
+    pub trusted_key: Vec<VerifyingKey>,
+    /// Refuse archives that are not signed by one of the trusted keys
+    ///
+    /// Archives with an invalid signature are always refused.
+    #[structopt(long)]
+    pub require_signed: bool,
+}
+
+impl SignatureOpt {
+    /// Converts the options into a signature policy
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if signatures are required, but no key is trusted
+    pub fn policy(&self) -> Result<SignaturePolicy> {
+        if self.require_signed && self.trusted_key.is_empty() {
+            return Err(anyhow!(
+                "--require-signed needs at least one key to trust, given with --trusted-key"
+            ));
+        }
+        Ok(SignaturePolicy {
+            trusted: self.trusted_key.clone(),
+            require_signed: self.require_signed,
+        })
+    }
+}
+
 /// Options that are shared among all repository commands
 #[derive(Debug, StructOpt, Clone)]
 pub struct RepoOpt {
     /// Location of the Asuran repository
     #[structopt(name = "REPO")]
     pub repo: PathBuf,
-    /// Password for the repository. Can also be specified with the PASSWORD
-    /// enviroment variable
+    /// Password for the repository. Can also be specified with the ASURAN_PASSWORD
+    /// enviroment variable. Takes precedence over the password file and command, and is
+    /// prompted for if none of them are given
     #[structopt(short, long, env = "ASURAN_PASSWORD", hide_env_values = true)]
-    pub password: String,
+    pub password: Option<Password>,
+    /// Read the password for the repository from this file. Can also be specified
+    /// with the ASURAN_PASSWORD_FILE enviroment variable
+    #[structopt(long, env = "ASURAN_PASSWORD_FILE")]
+    pub password_file: Option<PathBuf>,
+    /// Run this shell command, and use what it prints as the password for the
+    /// repository. Can also be specified with the ASURAN_PASSWORD_COMMAND enviroment
+    /// variable
+    #[structopt(long, env = "ASURAN_PASSWORD_COMMAND")]
+    pub password_command: Option<String>,
+    /// Look the password for the repository up in the platform keyring, and store it
+    /// there once it is known to be correct
+    ///
+    /// The stored password is used in place of prompting for one, so other password
+    /// options still take precedence, and replace the stored password.
+    #[structopt(long)]
+    pub use_keyring: bool,
+    /// Set when the password was read from the keyring
+    #[structopt(skip)]
+    pub password_from_keyring: bool,
+    /// Set when the password should be stored in the keyring once the repository has
+    /// been opened with it
+    #[structopt(skip)]
+    pub remember_password: bool,
     /// Type of repository to use
     #[structopt(
         short,
@@ -211,6 +984,25 @@ pub struct RepoOpt {
     /// "middle" setting
     #[structopt(short = "l", long)]
     pub compression_level: Option<u32>,
+    /// Picks the compression level of each chunk stored to suit an estimate of how well
+    /// it compresses.
+    ///
+    /// Text and other redundant data is compressed at a high level, dense data at a fast
+    /// one, and data that looks incompressible is stored as is.
+    #[structopt(long)]
+    pub adaptive_compression: bool,
+    /// Pads chunks before encrypting them, to hide their exact sizes from anyone who can
+    /// see the repository's storage.
+    ///
+    /// Padme wastes at most 12% of space, PowerOfTwo leaks less, but can waste up to half
+    /// of it. Chunks record their padding, so this can be changed at any time.
+    #[structopt(
+        long,
+        default_value = "None",
+        case_insensitive(true),
+        possible_values(&Padding::variants())
+    )]
+    pub padding: Padding,
     /// Sets the HMAC algorthim used. Note: this will not change the HMAC
     /// algorthim used on an existing repository
     #[structopt(
@@ -221,20 +1013,112 @@ pub struct RepoOpt {
         possible_values(&HMAC::variants())
     )]
     pub hmac: HMAC,
+    /// Selects the algorithm used to derive chunk IDs.
+    ///
+    /// HMAC uses the selected HMAC algorithm, while Blake3 uses BLAKE3's native keyed
+    /// mode, which is much faster than the SHA2 and SHA3 based HMACs. Chunks are only
+    /// deduplicated against chunks with IDs derived the same way, so this should be the
+    /// same every time a repository is used.
+    #[structopt(
+        long,
+        default_value = "HMAC",
+        case_insensitive(true),
+        possible_values(&ChunkIDAlgorithm::variants())
+    )]
+    pub id_algorithm: ChunkIDAlgorithm,
+    /// Number of bytes of the hash kept in chunk IDs, between 16 and 32.
+    ///
+    /// Like the ID algorithm, this should be the same every time a repository is used.
+    #[structopt(long, default_value = "32", parse(try_from_str = parse_id_length))]
+    pub id_length: usize,
+    /// Selects the chunker used to split files into chunks.
+    ///
+    /// The chunker and chunk sizes are recorded in the repository when it is created,
+    /// and reused when these options are not given. Data split with a different chunker,
+    /// or different chunk sizes, will not deduplicate against data already in the
+    /// repository. Defaults to FastCDC for new repositories.
+    #[structopt(
+        long,
+        case_insensitive(true),
+        possible_values(&ChunkerType::variants())
+    )]
+    pub chunker: Option<ChunkerType>,
+    /// Minimum chunk size for the FastCDC chunkers, optionally followed by K, M, or G.
+    /// Defaults to 32K
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub chunk_size_min: Option<u64>,
+    /// Average chunk size, optionally followed by K, M, or G. Defaults to 64K for the
+    /// FastCDC chunkers, and 2M for the BuzHash chunkers, where it must be a power of two
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub chunk_size_avg: Option<u64>,
+    /// Maximum chunk size for the FastCDC chunkers, optionally followed by K, M, or G.
+    /// Defaults to 128K
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub chunk_size_max: Option<u64>,
+    /// Size of the rolling hash window for the BuzHash chunkers, in bytes. Defaults to
+    /// 4095
+    #[structopt(long)]
+    pub buzhash_window: Option<u32>,
     /// Password to use for SFTP connection for SFTP backend.
     ///
-    /// Will attempt to use ssh-agent authentication if not set.
+    /// Only used if authenticating with the private key file or ssh-agent fails.
     #[structopt(long, env = "ASURAN_SFTP_PASSWORD", hide_env_values = true)]
     pub sftp_password: Option<String>,
+    /// Private key file to authenticate the SFTP connection with.
+    ///
+    /// Will attempt to use ssh-agent authentication if not set.
+    #[structopt(long, env = "ASURAN_SFTP_KEY_FILE")]
+    pub sftp_key_file: Option<PathBuf>,
+    /// Passphrase the SFTP private key file is encrypted with.
+    #[structopt(long, env = "ASURAN_SFTP_KEY_PASSPHRASE", hide_env_values = true)]
+    pub sftp_key_passphrase: Option<String>,
+    /// OpenSSH known hosts file to check the key of the SFTP server against.
+    ///
+    /// Will default to ~/.ssh/known_hosts if not specified. Servers that are not in
+    /// it yet are added to it.
+    #[structopt(long, env = "ASURAN_SFTP_KNOWN_HOSTS")]
+    pub sftp_known_hosts: Option<PathBuf>,
+    /// Refuse to connect to SFTP servers that are not in the known hosts file,
+    /// rather than adding them to it.
+    #[structopt(long)]
+    pub sftp_strict_host_keys: bool,
+    /// Number of additional SFTP sessions to read and write chunks over in parallel.
+    ///
+    /// Keeping several chunks in flight at once greatly speeds up high latency
+    /// links. Set to 0 to do everything over a single session.
+    #[structopt(long, default_value = "4", env = "ASURAN_SFTP_SESSIONS")]
+    pub sftp_sessions: usize,
     /// Port to use for the SFTP connection to the SFTP backend.
     ///
     /// Will default to 22 if not specified
     #[structopt(long, env = "ASURAN_SFTP_PORT")]
     pub sftp_port: Option<u16>,
+    /// Token to present to the asuran-server for the Remote backend.
+    #[structopt(long, env = "ASURAN_REMOTE_TOKEN", hide_env_values = true)]
+    pub remote_token: Option<String>,
+    /// Username to authenticate to the share with for the WebDAV backend.
+    #[structopt(long, env = "ASURAN_WEBDAV_USER")]
+    pub webdav_user: Option<String>,
+    /// Password to authenticate to the share with for the WebDAV backend.
+    #[structopt(long, env = "ASURAN_WEBDAV_PASSWORD", hide_env_values = true)]
+    pub webdav_password: Option<String>,
+    /// When the MultiFile and FlatFile backends sync written data to disk.
+    ///
+    /// OnCommit syncs everything written so far whenever the index or manifest is
+    /// committed, Always additionally syncs after every write, and Never leaves it
+    /// entirely up to the operating system, which is much faster for small chunks, but
+    /// can lose or corrupt recent data on a crash or power loss.
+    #[structopt(
+        long,
+        default_value = "OnCommit",
+        case_insensitive(true),
+        possible_values(&Durability::variants())
+    )]
+    pub durability: Durability,
 }
 
 /// Struct for holding the options the user has selected
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Clone, StructOpt)]
 #[structopt(
     name = "Asuran-CLI",
     about = "Deduplicating, encrypting, tamper evident archiver",
@@ -251,11 +1135,91 @@ pub struct Opt {
     /// Squelch non-logging operations
     #[structopt(short, long, global = true)]
     pub quiet: bool,
-    /// Number of tasks to spawn for the chunk processing pipeline.
+    /// Number of tasks to spawn for the chunk processing pipeline, which is also the
+    /// number of files `extract` restores at once.
     ///
     /// Defaults to 0, which corresponds to the number of CPUs on the system.
     #[structopt(short = "T", long, default_value = "0", global = true)]
     pub pipeline_tasks: usize,
+    /// Operate in low memory mode, for use on memory constrained devices.
+    ///
+    /// Uses a single pipeline task and executor thread, shrinks all internal
+    /// queues, processes only a couple of files at a time, keeps fewer segment
+    /// handles cached, and looks chunks up from sorted pages of the index
+    /// written to the temporary directory, rather than holding the index in
+    /// memory. This trades throughput for a much smaller and more predictable
+    /// memory footprint. Overrides --pipeline-tasks.
+    #[structopt(long, global = true)]
+    pub low_memory: bool,
+    /// Open the repository read only, such as from a read only mount.
+    ///
+    /// No locks are taken and no files are created or written to, so commands that
+    /// modify the repository fail. Only supported by the MultiFile and FlatFile
+    /// backends.
+    #[structopt(long, global = true)]
+    pub read_only: bool,
+    /// Limits the chunk data being stored at once to this many bytes, optionally followed
+    /// by K, M, or G.
+    ///
+    /// Reading new data pauses while chunks using up the limit are compressed,
+    /// encrypted, and handed to the backend. Unlimited by default.
+    #[structopt(long, global = true, parse(try_from_str = parse_size))]
+    pub memory_limit: Option<u64>,
+    /// Number of chunks to fetch ahead of the one being restored.
+    ///
+    /// Keeps several reads in flight while restoring, which helps a great deal
+    /// with high latency backends, such as SFTP. Set to 0 to fetch chunks one
+    /// at a time. Low memory mode lowers this to 1.
+    #[structopt(long, default_value = "8", global = true)]
+    pub read_ahead: usize,
+    /// Number of consecutive chunks whose reads are sorted into the order they are
+    /// stored in while restoring.
+    ///
+    /// Reading in storage order keeps spinning disks from seeking back and forth. Up to
+    /// twice this many chunks are held in memory. Set to 0 to read chunks in the order
+    /// they appear in files. Low memory mode turns this off.
+    #[structopt(long, default_value = "64", global = true)]
+    pub reorder_window: usize,
+    /// Keeps the plaintext of up to this many bytes of recently read chunks in memory
+    /// while restoring, optionally followed by K, M, or G.
+    ///
+    /// Chunks that appear more than once in an archive are then only read from the
+    /// backend and decrypted the first time. Off by default.
+    #[structopt(long, global = true, parse(try_from_str = parse_size))]
+    pub read_cache: Option<u64>,
+    /// Report errors as a single line of JSON on stderr
+    ///
+    /// The object has the fields "error", a stable name for the kind of error,
+    /// "exit_code", and "message". The exit code is set the same way either way.
+    #[structopt(long, global = true)]
+    pub json_errors: bool,
+    /// Write metrics describing the run here once the command finishes
+    ///
+    /// Takes a file path, or "-" for stdout. Files are replaced atomically, so
+    /// they can be read by the textfile collector of the Prometheus node
+    /// exporter. With the OTLP format, this may also be the http:// URL of an
+    /// OpenTelemetry collector, such as http://localhost:4318/v1/metrics, which
+    /// the metrics are then posted to.
+    #[structopt(long, global = true)]
+    pub metrics: Option<String>,
+    /// The format to export metrics in
+    ///
+    /// OTLP metrics are encoded as JSON.
+    #[structopt(
+        long,
+        default_value = "Prometheus",
+        case_insensitive(true),
+        possible_values(&MetricsFormat::variants()),
+        global = true
+    )]
+    pub metrics_format: MetricsFormat,
+    /// Serve the progress of store and extract on a Unix socket at this path
+    ///
+    /// Clients send one of the commands status, pause, resume, or abort per line, and
+    /// get a line of JSON back for each. Can also be specified with the
+    /// ASURAN_STATUS_SOCKET enviroment variable.
+    #[structopt(long, global = true, env = "ASURAN_STATUS_SOCKET")]
+    pub status_socket: Option<PathBuf>,
 }
 
 impl Opt {
@@ -265,27 +1229,192 @@ impl Opt {
     pub async fn open_repo_backend(&self) -> Result<(BackendObject, Key)> {
         self.command
             .repo_opts()
-            .open_repo_backend(self.pipeline_tasks() * 8)
+            .open_repo_backend(
+                self.queue_depth(),
+                self.low_memory,
+                self.read_only,
+                self.command.reads_snapshot(),
+            )
             .await
     }
+    /// Opens the repository with the chunk settings given on the command line, checking
+    /// that this build can work with it, see `self_tested`
+    pub async fn open_repo(&self) -> Result<repository::Repository<BackendObject>> {
+        let (backend, key) = self.open_repo_backend().await?;
+        let repo = repository::Repository::with(
+            backend,
+            self.get_chunk_settings(),
+            key,
+            self.pipeline_tasks(),
+        )?;
+        self_tested(repo, self.read_only).await
+    }
     pub fn repo_opts(&self) -> &RepoOpt {
         self.command.repo_opts()
     }
+    /// Works out the passwords the command needs, reading them from their files or
+    /// commands, or prompting for them, so each is only asked for once
+    ///
+    /// A new repository's password has to be entered twice when prompted for.
+    pub fn resolve_passwords(&mut self) -> Result<()> {
+        // Streaming a repository to stdout creates it
+        let confirm = match &self.command {
+            Command::New { .. } => true,
+            Command::Store { repo_opts, .. } => repo_opts.is_stdout(),
+            _ => false,
+        };
+        match &mut self.command {
+            Command::BenchCrypto
+            | Command::BenchChunker { .. }
+            | Command::GenerateSigningKey { .. }
+            | Command::BreakLock { .. }
+            | Command::ForgetPassword { .. }
+            | Command::RunJob { .. } => Ok(()),
+            Command::ImportRestic {
+                repo_opts,
+                restic_password,
+                ..
+            } => {
+                if restic_password.is_none() {
+                    *restic_password = Some(Password::prompt("Restic password: ", false)?);
+                }
+                repo_opts.resolve_password(false)
+            }
+            command => command.repo_opts_mut().resolve_password(confirm),
+        }
+    }
     pub fn pipeline_tasks(&self) -> usize {
-        if self.pipeline_tasks == 0 {
+        if self.low_memory {
+            1
+        } else if self.pipeline_tasks == 0 {
             num_cpus::get()
         } else {
             self.pipeline_tasks
         }
     }
+    /// The depth of the queues used for communicating with the backend
+    pub fn queue_depth(&self) -> usize {
+        if self.low_memory {
+            2
+        } else {
+            self.pipeline_tasks() * 8
+        }
+    }
+    /// The number of chunks to fetch ahead of the one being restored
+    pub fn read_ahead(&self) -> usize {
+        if self.low_memory {
+            self.read_ahead.min(1)
+        } else {
+            self.read_ahead
+        }
+    }
+    /// The number of chunks whose reads are put in storage order while restoring
+    pub fn reorder_window(&self) -> usize {
+        if self.low_memory {
+            0
+        } else {
+            self.reorder_window
+        }
+    }
+    /// The maximum number of objects to process concurrently
+    pub fn max_queue_len(&self) -> usize {
+        if self.low_memory {
+            2
+        } else {
+            30
+        }
+    }
 }
 
 impl RepoOpt {
+    /// Returns the password for the repository
+    ///
+    /// # Errors
+    ///
+    /// Will return Err if the password has not been resolved yet
+    pub fn password(&self) -> Result<&Password> {
+        self.password
+            .as_ref()
+            .ok_or_else(|| anyhow!("No password was given for the repository"))
+    }
+
+    /// Fills in the password from the password file or command, the keyring, or by
+    /// prompting for it, unless it was given directly
+    ///
+    /// With `--use-keyring`, a password that did not come from the keyring, and differs
+    /// from the one stored there, is marked to be stored once it has opened the
+    /// repository.
+    pub fn resolve_password(&mut self, confirm: bool) -> Result<()> {
+        let stored = if self.use_keyring {
+            os_keyring::get_password(self)?
+        } else {
+            None
+        };
+        if self.password.is_none() {
+            let password = if let Some(file) = &self.password_file {
+                Password::from_file(file)?
+            } else if let Some(command) = &self.password_command {
+                Password::from_command(command)?
+            } else if let Some(stored) = &stored {
+                self.password_from_keyring = true;
+                stored.clone()
+            } else {
+                Password::prompt("Password: ", confirm)?
+            };
+            self.password = Some(password);
+        }
+        let matches_stored = match (&stored, &self.password) {
+            (Some(stored), Some(password)) => stored.as_bytes() == password.as_bytes(),
+            _ => false,
+        };
+        self.remember_password = self.use_keyring && !matches_stored;
+        Ok(())
+    }
+
+    /// Stores the password in the keyring, if it was marked to be stored by
+    /// `resolve_password`
+    ///
+    /// This should only be called once the password is known to be correct.
+    pub fn remember_password(&self) -> Result<()> {
+        if self.remember_password {
+            os_keyring::set_password(self, self.password()?)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the user asked for compression with the repository's zstd
+    /// dictionary
+    ///
+    /// `get_chunk_settings` can not resolve the dictionary on its own, and returns
+    /// plain zstd at the requested level in this case.
+    pub fn uses_dictionary(&self) -> bool {
+        matches!(self.compression, Compression::ZStdDict)
+    }
+
+    /// Converts the selected padding into its equivalent in `asuran`
+    pub fn get_padding(&self) -> repository::Padding {
+        match self.padding {
+            Padding::None => repository::Padding::NoPadding,
+            Padding::Padme => repository::Padding::Padme,
+            Padding::PowerOfTwo => repository::Padding::PowerOfTwo,
+        }
+    }
+
+    /// Converts the selected durability into its equivalent in `asuran`
+    pub fn get_durability(&self) -> repository::backend::Durability {
+        match self.durability {
+            Durability::Always => repository::backend::Durability::Always,
+            Durability::OnCommit => repository::backend::Durability::OnCommit,
+            Durability::Never => repository::backend::Durability::Never,
+        }
+    }
+
     /// Generates an `asuran::repostiory::ChunkSettings` from the options the
     /// user has selected
     pub fn get_chunk_settings(&self) -> repository::ChunkSettings {
         let compression = match self.compression {
-            Compression::ZStd => self
+            // The dictionary has to be looked up in the repository, see `uses_dictionary`
+            Compression::ZStd | Compression::ZStdDict => self
                 .compression_level
                 .map(|x| repository::Compression::ZStd { level: x as i32 })
                 .unwrap_or(repository::Compression::ZStd { level: 3 }),
@@ -298,12 +1427,21 @@ impl RepoOpt {
                 .compression_level
                 .map(|x| repository::Compression::LZMA { level: x })
                 .unwrap_or(repository::Compression::LZMA { level: 6 }),
+            Compression::Brotli => self
+                .compression_level
+                .map(|x| repository::Compression::Brotli { level: x })
+                .unwrap_or(repository::Compression::Brotli { level: 6 }),
+            Compression::LZ4HC => self
+                .compression_level
+                .map(|x| repository::Compression::LZ4HC { level: x })
+                .unwrap_or(repository::Compression::LZ4HC { level: 9 }),
         };
 
         let encryption = match self.encryption {
             Encryption::AES256CBC => repository::Encryption::new_aes256cbc(),
             Encryption::AES256CTR => repository::Encryption::new_aes256ctr(),
             Encryption::ChaCha20 => repository::Encryption::new_chacha20(),
+            Encryption::Auto => repository::Encryption::auto(),
             Encryption::None => repository::Encryption::NoEncryption,
         };
 
@@ -315,16 +1453,212 @@ impl RepoOpt {
             HMAC::SHA3 => repository::HMAC::SHA3,
         };
 
+        let id = repository::ChunkIDSettings {
+            algorithm: match self.id_algorithm {
+                ChunkIDAlgorithm::HMAC => repository::ChunkIDAlgorithm::HMAC,
+                ChunkIDAlgorithm::Blake3 => repository::ChunkIDAlgorithm::Blake3,
+            },
+            length: self.id_length,
+        };
+
         repository::ChunkSettings {
             compression,
             encryption,
             hmac,
+            id,
+            chunker: None,
         }
     }
 
+    /// Returns the chunker settings the user selected, or `None` if they did not select
+    /// a chunker or any chunk sizes
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the selected options do not describe a valid chunker
+    pub fn chunker_settings(&self) -> Result<Option<repository::ChunkerSettings>> {
+        if self.chunker.is_none()
+            && self.chunk_size_min.is_none()
+            && self.chunk_size_avg.is_none()
+            && self.chunk_size_max.is_none()
+            && self.buzhash_window.is_none()
+        {
+            return Ok(None);
+        }
+        let settings = match self.chunker.clone().unwrap_or(ChunkerType::FastCDC) {
+            chunker @ ChunkerType::FastCDC | chunker @ ChunkerType::FastCDC2020 => {
+                if self.buzhash_window.is_some() {
+                    return Err(anyhow!(
+                        "--buzhash-window only applies to the BuzHash chunkers"
+                    ));
+                }
+                let min_size = self.chunk_size_min.unwrap_or(32_768);
+                let avg_size = self.chunk_size_avg.unwrap_or(65_536);
+                let max_size = self.chunk_size_max.unwrap_or(131_072);
+                // The limits the fastcdc crate places on chunk sizes
+                if !(64..=67_108_864).contains(&min_size)
+                    || !(256..=268_435_456).contains(&avg_size)
+                    || !(1024..=1_073_741_824).contains(&max_size)
+                {
+                    return Err(anyhow!(
+                        "FastCDC chunk sizes must be between 64 and 64M for the minimum, \
+                         256 and 256M for the average, and 1K and 1G for the maximum"
+                    ));
+                }
+                if min_size > avg_size || avg_size > max_size {
+                    return Err(anyhow!(
+                        "Chunk sizes must satisfy minimum <= average <= maximum, got {}, {}, {}",
+                        min_size,
+                        avg_size,
+                        max_size
+                    ));
+                }
+                if let ChunkerType::FastCDC = chunker {
+                    repository::ChunkerSettings::FastCDC {
+                        min_size,
+                        avg_size,
+                        max_size,
+                    }
+                } else {
+                    repository::ChunkerSettings::FastCDC2020 {
+                        min_size,
+                        avg_size,
+                        max_size,
+                        normalization: 1,
+                    }
+                }
+            }
+            chunker @ ChunkerType::BuzHash | chunker @ ChunkerType::KeyedBuzHash => {
+                if self.chunk_size_min.is_some() || self.chunk_size_max.is_some() {
+                    return Err(anyhow!(
+                        "BuzHash derives its minimum and maximum chunk sizes from \
+                         --chunk-size-avg, they can not be set separately"
+                    ));
+                }
+                let avg_size = self.chunk_size_avg.unwrap_or(1 << 21);
+                if !avg_size.is_power_of_two() || !(1024..=1 << 30).contains(&avg_size) {
+                    return Err(anyhow!(
+                        "BuzHash average chunk sizes must be a power of two between 1K and 1G"
+                    ));
+                }
+                let window_size = self.buzhash_window.unwrap_or(4095);
+                if window_size == 0 {
+                    return Err(anyhow!("The BuzHash window can not be empty"));
+                }
+                let mask_bits = avg_size.trailing_zeros();
+                if let ChunkerType::BuzHash = chunker {
+                    repository::ChunkerSettings::BuzHash {
+                        window_size,
+                        mask_bits,
+                    }
+                } else {
+                    repository::ChunkerSettings::KeyedBuzHash {
+                        window_size,
+                        mask_bits,
+                    }
+                }
+            }
+        };
+        Ok(Some(settings))
+    }
+
+    /// Attempts to read and decrypt the key of a FlatFile repository
+    ///
+    /// This only reads the initial header of the file, so works even if the rest of
+    /// the repository is damaged.
+    ///
+    /// # Errors
+    ///
+    /// Will return Err if the key can not be read, or the password is wrong
+    pub fn flatfile_key(&self) -> Result<Key> {
+        let key = flatfile::FlatFile::load_encrypted_key(&self.repo)
+            .with_context(|| "Failed to read key from flatfile.")?;
+        Ok(key
+            .decrypt(self.password()?.as_bytes())
+            .map_err(|_| Error::WrongPassword)?)
+    }
+
+    /// Returns true if this is a FlatFile repository with a path of `-`, which is
+    /// streamed to stdout
+    pub fn is_stdout(&self) -> bool {
+        matches!(self.repository_type, RepositoryType::FlatFile) && self.repo.as_os_str() == "-"
+    }
+
+    /// Starts streaming a new FlatFile repository to stdout, protected by a new key
+    ///
+    /// The key is created the same way `new` creates one, using the chunk settings and
+    /// chunker the user selected.
+    ///
+    /// # Errors
+    ///
+    /// Will return Err if the chunker settings are invalid, or writing the initial
+    /// headers fails
+    pub fn stream_to_stdout(
+        &self,
+        queue_depth: usize,
+    ) -> Result<(BackendHandle<flatfile::FlatFileStream>, Key)> {
+        let mut settings = self.get_chunk_settings();
+        settings.chunker = Some(self.chunker_settings()?.unwrap_or_default());
+        let key = Key::random(settings.encryption.key_length());
+        let encrypted_key = repository::EncryptedKey::encrypt_defaults(
+            &key,
+            settings.encryption,
+            self.password()?.as_bytes(),
+        );
+        let stream = flatfile::FlatFileStream::new(
+            BufWriter::new(std::io::stdout()),
+            settings,
+            encrypted_key,
+            key.clone(),
+            queue_depth,
+        )
+        .with_context(|| "Unable to start streaming a flatfile to stdout.")?;
+        Ok((stream, key))
+    }
+
+    /// Works out the settings for connecting to an SFTP repository
+    ///
+    /// The known hosts file defaults to the user's OpenSSH one, if their home directory
+    /// can be found.
+    pub fn sftp_settings(&self) -> Result<sftp::SFTPSettings> {
+        let repo_str = self.repo.to_str().context("Non utf-8 in sftp path")?;
+        let (username, hostname, path) = parse_ssh_path(repo_str)?;
+        let known_hosts = self.sftp_known_hosts.clone().or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".ssh").join("known_hosts"))
+        });
+        let host_key_policy = if self.sftp_strict_host_keys {
+            sftp::HostKeyPolicy::Strict
+        } else {
+            sftp::HostKeyPolicy::AcceptNew
+        };
+        Ok(sftp::SFTPSettings {
+            hostname,
+            port: self.sftp_port,
+            username,
+            password: self.sftp_password.clone(),
+            key_file: self.sftp_key_file.clone(),
+            key_passphrase: self.sftp_key_passphrase.clone(),
+            known_hosts,
+            host_key_policy,
+            path,
+        })
+    }
+
     /// Attempts to open up a connection to the repostiory, based on the information
     /// passed in the Options
     ///
+    /// If `low_memory` is set, backends that support it will be opened with
+    /// their reduced memory usage settings.
+    ///
+    /// If `read_only` is set, the repository is opened without taking any locks or
+    /// writing to it, which only the MultiFile and FlatFile backends support.
+    ///
+    /// If `snapshot` is set, backends that support it only serve the archives and chunks
+    /// that were committed when the repository was opened.
+    ///
+    /// Once the repository is open, the password is stored in the keyring if it was
+    /// marked to be, see `remember_password`.
+    ///
     /// # Errors
     ///
     /// Will return Err if
@@ -332,14 +1666,55 @@ impl RepoOpt {
     /// 1. The give repository path is of the wrong type (i.e a folder when a FlatFile
     ///    was requested)
     /// 2. Some other error defined in the repostiory implementation occurs trying to open it
-    pub async fn open_repo_backend(&self, queue_depth: usize) -> Result<(BackendObject, Key)> {
+    pub async fn open_repo_backend(
+        &self,
+        queue_depth: usize,
+        low_memory: bool,
+        read_only: bool,
+        snapshot: bool,
+    ) -> Result<(BackendObject, Key)> {
+        match self
+            .open_backend(queue_depth, low_memory, read_only, snapshot)
+            .await
+        {
+            Ok(opened) => {
+                self.remember_password()?;
+                Ok(opened)
+            }
+            Err(e)
+                if self.password_from_keyring
+                    && matches!(e.downcast_ref::<Error>(), Some(Error::WrongPassword)) =>
+            {
+                Err(e.context(
+                    "The password stored in the keyring did not open the repository, remove \
+                     it with forget-password",
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn open_backend(
+        &self,
+        queue_depth: usize,
+        low_memory: bool,
+        read_only: bool,
+        snapshot: bool,
+    ) -> Result<(BackendObject, Key)> {
+        let local = matches!(
+            self.repository_type,
+            RepositoryType::MultiFile | RepositoryType::FlatFile
+        );
+        if read_only && !local {
+            return Err(anyhow!(
+                "Only MultiFile and FlatFile repositories can be opened read only"
+            ));
+        }
         match self.repository_type {
             RepositoryType::MultiFile => {
                 // Ensure that the repository path exsits and is a folder
                 if !self.repo.exists() {
-                    return Err(anyhow!(
-                        "Attempted to open a repository at a path that does not exist."
-                    ));
+                    return Err(Error::RepositoryNotFound(self.repo.display().to_string()).into());
                 }
                 let md = metadata(&self.repo).with_context(|| {
                     format!(
@@ -357,30 +1732,44 @@ impl RepoOpt {
 
                 // Attempt to decrypt the key
                 let key = multifile_key
-                    .decrypt(self.password.as_bytes())
-                    .with_context(|| {
-                        "Unable to decrypt key material, possibly due to an invalid password"
-                    })?;
+                    .decrypt(self.password()?.as_bytes())
+                    .map_err(|_| Error::WrongPassword)?;
 
                 // Actually open the repository, and wrap it in a dynamic backend
-                let chunk_settings = self.get_chunk_settings();
-                let multifile = multifile::MultiFile::open_defaults(
+                //
+                // The stored default chunk settings are left alone, commands apply their
+                // own through `Repository::with`
+                let settings = multifile::MultiFileSettings {
+                    durability: self.get_durability(),
+                    read_only,
+                    snapshot,
+                    ..if low_memory {
+                        multifile::MultiFileSettings::low_memory()
+                    } else {
+                        multifile::MultiFileSettings::default()
+                    }
+                };
+                let multifile = multifile::MultiFile::open_with_settings(
                     &self.repo,
-                    Some(chunk_settings),
+                    None,
                     &key,
                     queue_depth,
+                    settings,
                 )
                 .await
                 .with_context(|| "Exeprienced an internal backend error.")?;
                 Ok((multifile.get_object_handle(), key))
             }
             RepositoryType::FlatFile => {
-                // First, make sure the repository exists and is a file
-                if !self.repo.exists() {
+                if self.is_stdout() {
                     return Err(anyhow!(
-                        "Attempted to open a repository path that does not exist"
+                        "A FlatFile repository can only be streamed to stdout by store"
                     ));
                 }
+                // First, make sure the repository exists and is a file
+                if !self.repo.exists() {
+                    return Err(Error::RepositoryNotFound(self.repo.display().to_string()).into());
+                }
                 let md = metadata(&self.repo).with_context(|| {
                     format!(
                         "IO error when attempting to open FlatFile at {:?}",
@@ -393,49 +1782,175 @@ impl RepoOpt {
 
                 // Attempt to open up the flatfile backend
                 let chunk_settings = self.get_chunk_settings();
-                // Attempt to read and decrypt the key
-                let key = flatfile::FlatFile::load_encrypted_key(&self.repo)
-                    .with_context(|| "Failed to read key from flatfile.")?;
-                let key = key.decrypt(self.password.as_bytes()).with_context(|| {
-                    "Unable to decrypt key material, possibly due to an invalid password"
-                })?;
-                let flatfile = flatfile::FlatFile::new(
-                    &self.repo,
-                    Some(chunk_settings),
-                    None,
-                    key.clone(),
-                    queue_depth,
-                )
+                let key = self.flatfile_key()?;
+                let flatfile = if read_only {
+                    flatfile::FlatFile::open_read_only(&self.repo, key.clone(), queue_depth)
+                } else {
+                    flatfile::FlatFile::new_with_durability(
+                        &self.repo,
+                        Some(chunk_settings),
+                        None,
+                        key.clone(),
+                        queue_depth,
+                        self.get_durability(),
+                    )
+                }
                 .with_context(|| "Internal backen d error opening flatfile.")?;
                 let flatfile = flatfile.get_object_handle();
                 Ok((flatfile, key))
             }
             RepositoryType::SFTP => {
                 use asuran::repository::backend::sftp::*;
-                let repo_str = self.repo.to_str().context("Non utf-8 in sftp path")?;
-                let (username, hostname, path) = parse_ssh_path(repo_str)?;
-                let settings = SFTPSettings {
-                    hostname,
-                    port: self.sftp_port,
-                    username,
-                    password: self.sftp_password.clone(),
-                    path,
-                };
+                let settings = self.sftp_settings()?;
                 let key = SFTP::read_key(settings.clone())
                     .context("Unable to read repository key material")?
-                    .decrypt(self.password.as_bytes())
-                    .context(
-                        "Failed to decrypt key material, possibly due to an invalid password",
-                    )?;
-                let chunk_settings = self.get_chunk_settings();
-                let sftp = SFTP::connect(settings, key.clone(), Some(chunk_settings), queue_depth)
-                    .context("Failed to connect to SFTP backend")?;
+                    .decrypt(self.password()?.as_bytes())
+                    .map_err(|_| Error::WrongPassword)?;
+                let sftp = pool::SFTPPool::connect(
+                    settings,
+                    key.clone(),
+                    None,
+                    queue_depth,
+                    self.sftp_sessions,
+                )
+                .await
+                .context("Failed to connect to SFTP backend")?;
                 Ok((sftp.get_object_handle(), key))
             }
+            RepositoryType::Remote => {
+                use asuran::repository::backend::remote::*;
+                let address = self.repo.to_str().context("Non utf-8 in remote address")?;
+                let token = self
+                    .remote_token
+                    .clone()
+                    .context("A token is required to connect to a Remote repository")?;
+                let settings = RemoteSettings {
+                    address: address.to_string(),
+                    token,
+                };
+                let remote = Remote::connect(settings, queue_depth)
+                    .context("Failed to connect to Remote backend")?;
+                let key = remote
+                    .read_key()
+                    .await
+                    .context("Unable to read repository key material")?
+                    .decrypt(self.password()?.as_bytes())
+                    .map_err(|_| Error::WrongPassword)?;
+                Ok((remote.get_object_handle(), key))
+            }
+            RepositoryType::WebDAV => {
+                use asuran::repository::backend::webdav::*;
+                let url = self.repo.to_str().context("Non utf-8 in WebDAV URL")?;
+                let settings = WebDavSettings {
+                    url: url.to_string(),
+                    username: self.webdav_user.clone(),
+                    password: self.webdav_password.clone(),
+                };
+                let key = WebDav::read_key(&settings)
+                    .context("Unable to read repository key material")?
+                    .decrypt(self.password()?.as_bytes())
+                    .map_err(|_| Error::WrongPassword)?;
+                let webdav = WebDav::connect(&settings, &key, None, queue_depth)
+                    .context("Failed to connect to WebDAV backend")?;
+                Ok((webdav.get_object_handle(), key))
+            }
         }
     }
 }
 
+/// Parses a chunk ID length, making sure it is one `asuran` supports
+fn parse_id_length(input: &str) -> Result<usize> {
+    let length = input.parse()?;
+    repository::ChunkIDSettings::new(repository::ChunkIDAlgorithm::HMAC, length)?;
+    Ok(length)
+}
+
+/// Parses a duration made up of a number and a unit, one of h(ours), d(ays), w(eeks),
+/// m(onths, of 30 days), or y(ears, of 365 days)
+fn parse_duration(input: &str) -> Result<std::time::Duration> {
+    let error = || {
+        anyhow!(
+            "Expected a number followed by h, d, w, m, or y, got {:?}",
+            input
+        )
+    };
+    let unit = input.chars().last().ok_or_else(error)?;
+    let count: u64 = input[..input.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| error())?;
+    let hours = match unit {
+        'h' => 1,
+        'd' => 24,
+        'w' => 7 * 24,
+        'm' => 30 * 24,
+        'y' => 365 * 24,
+        _ => return Err(error()),
+    };
+    count
+        .checked_mul(hours * 60 * 60)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(error)
+}
+
+/// Parses a short interval, a number followed by s, m, or h
+fn parse_interval(input: &str) -> Result<std::time::Duration> {
+    let error = || anyhow!("Expected a number followed by s, m, or h, got {:?}", input);
+    let unit = input.chars().last().ok_or_else(error)?;
+    let count: u64 = input[..input.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| error())?;
+    match unit {
+        's' => Ok(std::time::Duration::from_secs(count)),
+        'm' => Ok(std::time::Duration::from_secs(count * 60)),
+        'h' => Ok(std::time::Duration::from_secs(count * 60 * 60)),
+        _ => Err(error()),
+    }
+}
+
+/// Makes sure this build can actually work with a freshly opened repository, before
+/// anything else is done with it
+///
+/// Repositories opened read only are tested without storing a canary chunk in them.
+pub async fn self_tested<T: repository::BackendClone + 'static>(
+    mut repo: repository::Repository<T>,
+    read_only: bool,
+) -> Result<repository::Repository<T>> {
+    if read_only {
+        repo.self_test_read_only().await?;
+    } else {
+        repo.self_test().await?;
+    }
+    Ok(repo)
+}
+
+/// Parses a number of bytes, optionally followed by K, M, G, or T
+fn parse_size(input: &str) -> Result<u64> {
+    let error = || {
+        anyhow!(
+            "Expected a number optionally followed by K, M, G, or T, got {:?}",
+            input
+        )
+    };
+    let (count, multiplier) = match input.chars().last().ok_or_else(error)? {
+        'K' => (&input[..input.len() - 1], 1 << 10),
+        'M' => (&input[..input.len() - 1], 1 << 20),
+        'G' => (&input[..input.len() - 1], 1 << 30),
+        'T' => (&input[..input.len() - 1], 1 << 40),
+        _ => (input, 1),
+    };
+    let count: u64 = count.parse().map_err(|_| error())?;
+    count.checked_mul(multiplier).ok_or_else(error)
+}
+
+/// Splits a `KEY=VALUE` pair at the first `=`
+fn parse_key_value(input: &str) -> Result<(String, String)> {
+    let mut parts = input.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(anyhow!("Expected KEY=VALUE, got {:?}", input)),
+    }
+}
+
 /// Takes a string of type user@host:/path, with optional user, and returns a tuple of strings of
 /// rom (user, host, path). Will default to the username this program is running as
 ///
@@ -0,0 +1,20 @@
+use crate::cli::Opt;
+
+use anyhow::Result;
+
+/// Rewrites the underutilized segments of a repository, reporting how much space
+/// was reclaimed.
+pub async fn compact(options: Opt, threshold: f64) -> Result<()> {
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    let stats = repo.compact(threshold).await;
+    repo.close().await;
+    let stats = stats?;
+    if !options.quiet {
+        println!(
+            "Removed {} segments, moving {} chunks and reclaiming {} bytes",
+            stats.segments_removed, stats.chunks_moved, stats.bytes_reclaimed
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use crate::cli::Opt;
+
+use asuran::chunker::*;
+use asuran::manifest::compare::{compare_archive, Drift};
+use asuran::manifest::target::*;
+use asuran::manifest::*;
+
+use anyhow::{anyhow, Result};
+
+use std::path::PathBuf;
+
+/// Compares a particular archive against the live contents of a directory, printing
+/// each difference found.
+pub async fn compare(options: Opt, archive_name: String, target: PathBuf) -> Result<()> {
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    repo.read_ahead = options.read_ahead();
+    repo.reorder_window = options.reorder_window();
+    if let Some(bytes) = options.read_cache {
+        repo.set_read_cache(bytes);
+    }
+    // Load the manifest
+    let mut manifest = Manifest::load(&repo);
+    // Find the archive the user asked for, and load it
+    let archive = manifest
+        .resolve_archive(&mut repo, &archive_name)
+        .await?
+        .load(&mut repo)
+        .await?;
+
+    // Use the same chunker store does, so unchanged files can be checked without
+    // fetching their chunks
+    let chunker = AnyChunker::from_settings(manifest.chunk_settings().await.chunker(), repo.key());
+    let live_target = FileSystemTarget::new(
+        target
+            .to_str()
+            .ok_or_else(|| anyhow!("Target path contained non-utf8"))?,
+    );
+    let drift = compare_archive(&mut repo, &chunker, &archive, &live_target).await?;
+    for difference in &drift {
+        match difference {
+            Drift::Added(node) => println!("Added: {}", node.path),
+            Drift::Removed(node) => println!("Removed: {}", node.path),
+            Drift::TypeChanged { path, .. } => println!("Type Changed: {}", path),
+            Drift::SizeChanged {
+                path,
+                archived,
+                live,
+            } => println!("Size Changed: {} ({} -> {} bytes)", path, archived, live),
+            Drift::ContentChanged(path) => println!("Content Changed: {}", path),
+        }
+    }
+
+    repo.close().await;
+    if drift.is_empty() {
+        if !options.quiet {
+            println!("No differences found");
+        }
+        Ok(())
+    } else {
+        Err(anyhow!("Found {} differences", drift.len()))
+    }
+}
@@ -0,0 +1,146 @@
+/*!
+The `config` module provides the data types for asuran-cli's TOML
+configuration file, which holds the definitions of backup jobs.
+
+The configuration file is looked for at `$ASURAN_CONFIG`, then at
+`$XDG_CONFIG_HOME/asuran/config.toml`, falling back to
+`~/.config/asuran/config.toml`, or `%APPDATA%\asuran\config.toml` on Windows.
+*/
+use crate::password::Password;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The contents of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The backup jobs, keyed by name
+    pub jobs: BTreeMap<String, Job>,
+}
+
+/// A backup job, bundling everything needed to take a backup of a set of paths and prune
+/// the ones that are no longer wanted
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Job {
+    /// Location of the repository to back up to
+    pub repository: PathBuf,
+    /// Additional command line options for opening the repository, such as
+    /// `["--repository-type", "FlatFile"]`
+    pub repository_options: Vec<String>,
+    /// Password of the repository. If none of the password options are set, they are taken
+    /// from the environment, or prompted for once for the whole job
+    pub password: Option<Password>,
+    /// File to read the password of the repository from
+    pub password_file: Option<PathBuf>,
+    /// Shell command printing the password of the repository
+    pub password_command: Option<String>,
+    /// Look the password of the repository up in the platform keyring, storing it there
+    /// the first time it is entered
+    pub use_keyring: bool,
+    /// The paths to back up, each of which is stored as its own archive
+    pub sources: Vec<PathBuf>,
+    /// Patterns, relative to each source, of paths to leave out
+    pub exclude: Vec<String>,
+    /// Leave out directories tagged with a CACHEDIR.TAG
+    pub exclude_caches: bool,
+    /// Leave out directories containing a file with any of these names
+    pub exclude_if_present: Vec<String>,
+    /// Do not descend into directories on a different filesystem than the source
+    pub one_file_system: bool,
+    /// Follow symbolic links, storing what they point to
+    pub dereference: bool,
+    /// Read the contents of block devices, rather than storing just the device nodes
+    pub read_special: bool,
+    /// Shell command to scan each file with before it is stored
+    pub scan_command: Option<String>,
+    /// Tags to record on each archive, in addition to the job's own tags
+    pub tags: Vec<String>,
+    /// Additional command line options for `store`, such as
+    /// `["--checkpoint-interval", "30m"]`
+    pub store_options: Vec<String>,
+    /// Shell commands to run, in order, before anything is stored
+    pub pre: Vec<String>,
+    /// Shell commands to run, in order, after the job has finished, whether or not it
+    /// succeeded
+    pub post: Vec<String>,
+    /// Which archives of this job to keep when pruning after each run
+    pub retention: Retention,
+}
+
+/// The retention rules of a job, see `asuran-cli prune`
+///
+/// Archives are only pruned after a run if at least one rule is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    pub keep_yearly: usize,
+    /// Keep every archive younger than this, e.g. 12h, 7d, 2w, 6m, or 1y
+    pub keep_within: Option<String>,
+    /// Segments where live chunks make up less than this fraction of their data are
+    /// rewritten after pruning
+    pub threshold: Option<f64>,
+}
+
+impl Retention {
+    /// Returns true if any rule is set
+    pub fn has_rules(&self) -> bool {
+        self.keep_last > 0
+            || self.keep_daily > 0
+            || self.keep_weekly > 0
+            || self.keep_monthly > 0
+            || self.keep_yearly > 0
+            || self.keep_within.is_some()
+    }
+}
+
+impl Config {
+    /// Loads the configuration from `path`, or the default location if none is given
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => Config::default_path()?,
+        };
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read configuration file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Invalid configuration file {}", path.display()))
+    }
+
+    /// Returns the default location of the configuration file
+    pub fn default_path() -> Result<PathBuf> {
+        if let Some(path) = env::var_os("ASURAN_CONFIG") {
+            return Ok(PathBuf::from(path));
+        }
+        let base = if cfg!(windows) {
+            env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        };
+        base.map(|base| base.join("asuran").join("config.toml"))
+            .ok_or_else(|| anyhow!("Unable to find the configuration directory, use --config"))
+    }
+
+    /// Looks up the job with the given name
+    pub fn job(&self, name: &str) -> Result<&Job> {
+        self.jobs.get(name).ok_or_else(|| {
+            anyhow!(
+                "No job named {:?} in the configuration file. Defined jobs: {}",
+                name,
+                self.jobs.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })
+    }
+}
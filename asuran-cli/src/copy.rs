@@ -0,0 +1,144 @@
+use crate::cli::{self_tested, Opt, RepositoryType};
+use crate::password::Password;
+
+use asuran::manifest::copy::*;
+use asuran::manifest::*;
+use asuran::repository::*;
+
+use anyhow::{anyhow, Result};
+
+use std::path::PathBuf;
+
+/// Copies archives from the repository to another one, transferring only the chunks the
+/// destination does not already contain.
+///
+/// Archives already in the destination, with the same name and timestamp, are skipped, so
+/// the same command can be rerun to keep an offsite copy up to date.
+pub async fn copy(
+    options: Opt,
+    dst_repo: PathBuf,
+    dst_password: Option<Password>,
+    dst_repository_type: Option<RepositoryType>,
+    archive_names: Vec<String>,
+) -> Result<()> {
+    // Open the source repository
+    let mut source = options.open_repo().await?;
+    let mut source_manifest = Manifest::load(&source);
+
+    // Open the destination repository, packing chunks with its own recorded settings
+    let mut dst_opts = options.repo_opts().clone();
+    dst_opts.repo = dst_repo;
+    if let Some(password) = dst_password {
+        dst_opts.password = Some(password);
+    }
+    if let Some(repository_type) = dst_repository_type {
+        dst_opts.repository_type = repository_type;
+    }
+    // The destination is written to even when the source is opened read only, and has to
+    // see the archives other processes commit to it to skip them
+    let (backend, key) = dst_opts
+        .open_repo_backend(options.queue_depth(), options.low_memory, false, false)
+        .await?;
+    let settings = {
+        let repo = Repository::with(backend.clone(), source.chunk_settings(), key.clone(), 1)?;
+        Manifest::load(&repo).chunk_settings().await
+    };
+    let destination = Repository::with(backend, settings, key, options.pipeline_tasks())?;
+    let mut destination = self_tested(destination, false).await?;
+    let mut destination_manifest = Manifest::load(&destination);
+
+    let result = copy_archives(
+        &options,
+        &archive_names,
+        &mut source,
+        &mut source_manifest,
+        &mut destination,
+        &mut destination_manifest,
+    )
+    .await;
+    source.close().await;
+    destination.close().await;
+    result
+}
+
+async fn copy_archives<S, D>(
+    options: &Opt,
+    archive_names: &[String],
+    source: &mut Repository<S>,
+    source_manifest: &mut Manifest<S>,
+    destination: &mut Repository<D>,
+    destination_manifest: &mut Manifest<D>,
+) -> Result<()>
+where
+    S: BackendClone + 'static,
+    D: BackendClone + 'static,
+{
+    // Find the archives to copy, by name or index, keeping the order of the manifest
+    let archives = source_manifest.archives().await;
+    let mut selected = Vec::new();
+    for name in archive_names {
+        let found = archives
+            .iter()
+            .enumerate()
+            .find(|(index, archive)| index.to_string() == *name || archive.name() == name);
+        match found {
+            Some((index, _)) => selected.push(index),
+            None => return Err(anyhow!("Unable to find archive '{}' in repository", name)),
+        }
+    }
+    selected.sort_unstable();
+    selected.dedup();
+    let archives = if archive_names.is_empty() {
+        archives
+    } else {
+        selected
+            .into_iter()
+            .map(|index| archives[index].clone())
+            .collect()
+    };
+
+    let source_chunker = source_manifest.chunk_settings().await.chunker();
+    let destination_chunker = destination_manifest.chunk_settings().await.chunker();
+    if source_chunker != destination_chunker && !options.quiet {
+        eprintln!(
+            "Warning: The source repository was chunked with {}, but the destination with {}. \
+             Data stored in the destination will not deduplicate against the copied archives.",
+            source_chunker, destination_chunker
+        );
+    }
+    if !shares_ids(source, destination) && !options.quiet {
+        println!("Repositories use different keys or chunk IDs, every chunk will be read");
+    }
+
+    let existing = destination_manifest.archives().await;
+    let mut total = CopyStats::default();
+    for archive in archives {
+        let exists = existing
+            .iter()
+            .any(|x| x.name() == archive.name() && x.timestamp() == archive.timestamp());
+        if exists {
+            if !options.quiet {
+                println!("Skipping {}, already in the destination", archive.name());
+            }
+            continue;
+        }
+        let stats = copy_archive(source, &archive, destination, destination_manifest).await?;
+        if !options.quiet {
+            println!(
+                "Copied {}: {} chunks ({} bytes) transferred, {} already present",
+                archive.name(),
+                stats.chunks_copied,
+                stats.bytes_copied,
+                stats.chunks_skipped
+            );
+        }
+        total += stats;
+    }
+    if !options.quiet {
+        println!(
+            "Transferred {} chunks ({} bytes) in total",
+            total.chunks_copied, total.bytes_copied
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use crate::cli::{Opt, TarCompression};
+
+use asuran::interop::tar::export_archive;
+use asuran::manifest::Manifest;
+
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// Exports the contents of an archive as a tar stream, to either a file or
+/// stdout, without extracting it to disk first.
+pub async fn export_tar(
+    options: Opt,
+    archive_name: String,
+    output: Option<PathBuf>,
+    compression: TarCompression,
+) -> Result<()> {
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    repo.read_ahead = options.read_ahead();
+    repo.reorder_window = options.reorder_window();
+    if let Some(bytes) = options.read_cache {
+        repo.set_read_cache(bytes);
+    }
+    // Load the manifest
+    let mut manifest = Manifest::load(&repo);
+    // Find the archive the user asked for, and load it
+    let archive = manifest
+        .resolve_archive(&mut repo, &archive_name)
+        .await?
+        .load(&mut repo)
+        .await?;
+
+    // Open up the output, treating a missing path or - as stdout
+    let output: Box<dyn Write> = match output {
+        Some(path) if path.to_str() != Some("-") => Box::new(
+            File::create(&path)
+                .with_context(|| format!("Unable to create output file {:?}", path))?,
+        ),
+        _ => Box::new(io::stdout()),
+    };
+    let output = BufWriter::new(output);
+
+    match compression {
+        TarCompression::None => {
+            export_archive(&mut repo, &archive, output).await?;
+        }
+        TarCompression::Gzip => {
+            let encoder = GzEncoder::new(output, flate2::Compression::default());
+            // The trailer written by finish is still sitting in the buffer
+            let mut output = export_archive(&mut repo, &archive, encoder)
+                .await?
+                .finish()?;
+            output.flush()?;
+        }
+        TarCompression::ZStd => {
+            let encoder = zstd::Encoder::new(output, 0)?;
+            // The trailer written by finish is still sitting in the buffer
+            let mut output = export_archive(&mut repo, &archive, encoder)
+                .await?
+                .finish()?;
+            output.flush()?;
+        }
+    }
+
+    repo.close().await;
+    Ok(())
+}
@@ -1,96 +1,187 @@
 use crate::cli::{GlobOpt, Opt};
+use crate::status::Status;
 
 use asuran::manifest::driver::*;
+use asuran::manifest::signing::{SignaturePolicy, SignatureStatus};
 use asuran::manifest::target::*;
 use asuran::manifest::*;
-use asuran::repository::*;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use globset::{Glob, GlobSetBuilder};
 
+use std::collections::HashSet;
 use std::path::PathBuf;
 
+/// Number of objects restored between checks for the extract having been paused,
+/// aborted, or interrupted
+const RESTORE_BATCH: usize = 256;
+
 /// Drives a repository and extracts the files from the user provided archive to
 /// the user provided location
+///
+/// The archive is refused if its signature does not satisfy `policy`.
+///
+/// Progress is served on the status socket, if one was given, see `crate::status`. If
+/// the extract is aborted through it, or interrupted, `Aborted` is returned once the
+/// objects in flight have been restored.
+#[allow(clippy::too_many_arguments)]
 pub async fn extract(
     options: Opt,
     target: PathBuf,
     archive_name: String,
     glob_opts: GlobOpt,
+    paths: Option<Vec<String>>,
     preview: bool,
+    hardened: bool,
+    policy: SignaturePolicy,
 ) -> Result<()> {
     // Open the repository
-    let (backend, key) = options.open_repo_backend().await?;
-    let chunk_settings = options.get_chunk_settings();
-    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks());
+    let mut repo = options.open_repo().await?;
+    repo.read_ahead = options.read_ahead();
+    repo.reorder_window = options.reorder_window();
+    if let Some(bytes) = options.read_cache {
+        repo.set_read_cache(bytes);
+    }
     // load the manifest
     let mut manifest = Manifest::load(&repo);
-    // Load the list of archives
-    let mut archives: Vec<ActiveArchive> = Vec::new();
-    for stored_archive in manifest.archives().await {
-        let archive = stored_archive.load(&mut repo).await?;
-        archives.push(archive);
-    }
-
-    // Idenitify matching archives, and use the first one that matches the
-    // string the user has provided us (on either its index in the list, or its
-    // name)
-    let mut matching_archives: Vec<ActiveArchive> = Vec::new();
-    for (index, archive) in archives.into_iter().enumerate() {
-        if index.to_string() == archive_name || archive.name() == archive_name {
-            matching_archives.push(archive);
+    // Find the archive the user asked for
+    let stored_archive = match manifest.resolve_archive(&mut repo, &archive_name).await {
+        Ok(stored_archive) => stored_archive,
+        Err(error) => {
+            repo.close().await;
+            return Err(error.into());
+        }
+    };
+    let signature = match policy.check(&stored_archive) {
+        Ok(signature) => signature,
+        Err(error) => {
+            repo.close().await;
+            return Err(error.into());
         }
+    };
+    println!(
+        "Using archive {} taken at {}",
+        stored_archive.name(),
+        stored_archive.timestamp().to_rfc2822()
+    );
+    if signature != SignatureStatus::Unsigned {
+        println!("Signature: {}", signature);
     }
-
-    // TODO (#36): Prompt the user when there are multiple matching archives
-    // For now, just use the first match
-    if matching_archives.is_empty() {
-        println!("No matching archives found.");
-    } else {
-        let archive = &matching_archives[0];
-        println!(
-            "Using archive {} taken at {}",
-            archive.name(),
-            archive.timestamp().to_rfc2822()
-        );
-        // Build the includes glob
-        let includes = if let Some(include_vec) = glob_opts.include {
-            let mut builder = GlobSetBuilder::new();
-            for include_string in include_vec {
-                builder.add(Glob::new(&include_string)?);
+    let archive = stored_archive.load(&mut repo).await?;
+    let (status, _status_socket) =
+        match Status::serve(options.status_socket.as_deref(), "extract", archive.name()) {
+            Ok(status) => status,
+            Err(error) => {
+                repo.close().await;
+                return Err(error);
             }
-            Some(builder.build()?)
-        } else {
-            None
         };
-        // Build the excludes glob
-        let excludes = if let Some(exclude_vec) = glob_opts.exclude {
-            let mut builder = GlobSetBuilder::new();
-            for exclude_string in exclude_vec {
-                builder.add(Glob::new(&exclude_string)?);
+    // Build the includes glob
+    let includes = if let Some(include_vec) = glob_opts.include {
+        let mut builder = GlobSetBuilder::new();
+        for include_string in include_vec {
+            builder.add(Glob::new(&include_string)?);
+        }
+        Some(builder.build()?)
+    } else {
+        None
+    };
+    // Build the excludes glob
+    let excludes = if let Some(exclude_vec) = glob_opts.exclude {
+        let mut builder = GlobSetBuilder::new();
+        for exclude_string in exclude_vec {
+            builder.add(Glob::new(&exclude_string)?);
+        }
+        Some(builder.build()?)
+    } else {
+        None
+    };
+    // Load listing and setup target
+    let listing = archive.listing().await;
+    // Resolve any requested paths before touching the target, so a typo does not
+    // leave a partial restore behind
+    let nodes: Vec<Node> = if let Some(paths) = paths {
+        let mut seen = HashSet::new();
+        let mut nodes = Vec::new();
+        let mut missing = Vec::new();
+        for path in &paths {
+            let path = path.trim_start_matches("./").trim_end_matches('/');
+            let subtree = listing.subtree(path);
+            if subtree.is_empty() {
+                missing.push(path.to_string());
             }
-            Some(builder.build()?)
-        } else {
-            None
-        };
-        // Load listing and setup target
-        let listing = archive.listing().await;
-        let f_target = FileSystemTarget::load_listing(target.to_str().unwrap(), listing).await;
-        let paths = f_target
-            .restore_listing()
-            .await
-            .into_iter()
-            .filter(|x| includes.as_ref().map_or(true, |y| y.is_match(&x.path)))
-            .filter(|x| excludes.as_ref().map_or(true, |y| !y.is_match(&x.path)));
+            for node in subtree {
+                if seen.insert(node.path.clone()) {
+                    nodes.push(node.clone());
+                }
+            }
+        }
+        if !missing.is_empty() {
+            repo.close().await;
+            return Err(anyhow!(
+                "Paths not found in archive {}: {}",
+                archive.name(),
+                missing.join(", ")
+            ));
+        }
+        nodes
+    } else {
+        listing.iter().cloned().collect()
+    };
+    let mut f_target = FileSystemTarget::load_listing(target.to_str().unwrap(), listing).await;
+    f_target.set_hardened(hardened);
+    let paths = nodes
+        .into_iter()
+        .filter(|x| includes.as_ref().is_none_or(|y| y.is_match(&x.path)))
+        .filter(|x| excludes.as_ref().is_none_or(|y| !y.is_match(&x.path)));
+    if preview {
         for node in paths {
             if !options.quiet {
                 println!("Restoring file: {}", node.path);
             }
-            // TODO (#36): properly utilize tasks here
-            if !preview {
-                f_target.retrieve_object(&mut repo, &archive, node).await?;
+        }
+    } else {
+        let paths: Vec<Node> = paths.collect();
+        let (mut files, mut bytes) = (0, 0);
+        for node in paths.iter().filter(|x| x.is_file()) {
+            files += 1;
+            bytes += node.total_size;
+        }
+        status.set_totals(files, bytes);
+        // Many files are restored at once, so restores of lots of small files are not held
+        // up waiting on the backend for each one in turn
+        let quiet = options.quiet;
+        // Restoring in batches lets pauses, aborts, and interruptions take effect between
+        // them, and the repository be closed cleanly, releasing its lock
+        for batch in paths.chunks(RESTORE_BATCH) {
+            if !status.proceed().await {
+                f_target.finish_restore().await;
+                repo.close().await;
+                return Err(status.aborted().into());
             }
+            f_target
+                .retrieve_objects(
+                    &repo,
+                    &archive,
+                    batch.to_vec(),
+                    options.pipeline_tasks(),
+                    |node| {
+                        // Objects are only heard about once they are restored
+                        if node.is_file() {
+                            status.start_file(&node.path);
+                            status.finish_file(node.total_size);
+                        }
+                        if !quiet {
+                            println!("Restored file: {}", node.path);
+                        }
+                    },
+                )
+                .await?;
         }
+        f_target.finish_restore().await;
+    }
+    for entry in f_target.refused_paths().await {
+        eprintln!("Refused to restore {}: {}", entry.path, entry.reason);
     }
     repo.close().await;
     Ok(())
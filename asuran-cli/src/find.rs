@@ -0,0 +1,131 @@
+use crate::cli::Opt;
+
+use asuran::manifest::scan;
+use asuran::manifest::target::Node;
+use asuran::manifest::*;
+use asuran::repository::*;
+use asuran::time::Timestamp;
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use prettytable::{row, Table};
+
+use std::convert::TryFrom;
+
+/// Number of 100 nanosecond intervals in a second
+const FILETIME_TICKS: u64 = 10_000_000;
+/// Seconds between the `FILETIME` epoch, January 1, 1601, and the Unix epoch
+const FILETIME_UNIX_OFFSET: i64 = 11_644_473_600;
+
+/// Converts a Windows `FILETIME` into a timestamp, if it is representable
+fn filetime(ticks: u64) -> Option<Timestamp> {
+    let seconds = i64::try_from(ticks / FILETIME_TICKS).ok()? - FILETIME_UNIX_OFFSET;
+    let nanos = u32::try_from(ticks % FILETIME_TICKS).ok()? * 100;
+    Timestamp::from_unix(seconds, nanos)
+}
+
+/// Returns true if the node's path, or with `tags` set any tag a scan command stored on
+/// it, matches
+async fn node_matches(
+    repo: &mut Repository<impl BackendClone>,
+    archive: &ActiveArchive,
+    node: &Node,
+    pattern: &GlobMatcher,
+    tags: bool,
+) -> Result<bool> {
+    if pattern.is_match(&node.path) {
+        return Ok(true);
+    }
+    if tags && node.is_file() {
+        let stored = scan::read_tags(repo, archive, &node.path)
+            .await
+            .with_context(|| format!("Unable to read the tags of {}", node.path))?;
+        return Ok(stored.is_some_and(|stored| stored.iter().any(|tag| pattern.is_match(tag))));
+    }
+    Ok(false)
+}
+
+/// Searches the listings of every archive in the repository for objects with paths
+/// matching `pattern`, printing each match along with the archive it was found in
+///
+/// Only archives with names matching the `archive` glob are searched, if one is
+/// provided. With `tags` set, objects carrying a tag from a scan command that matches
+/// `pattern` are reported as well.
+///
+/// Listings do not record modification times outside of Windows, so the time the
+/// archive was created is the best guide to the age of most matches.
+pub async fn find(
+    options: Opt,
+    pattern: String,
+    archive: Option<String>,
+    tags: bool,
+) -> Result<()> {
+    let pattern = Glob::new(&pattern)?.compile_matcher();
+    let archive_pattern = archive
+        .map(|x| Glob::new(&x).map(|x| x.compile_matcher()))
+        .transpose()?;
+    // Open the repository
+    let mut repo = options.open_repo().await?;
+    // Load the manifest, and the archives we were asked to search
+    let mut manifest = Manifest::load(&repo);
+    let archives = manifest
+        .load_archives(&mut repo)
+        .await?
+        .into_iter()
+        .filter(|archive| {
+            archive_pattern
+                .as_ref()
+                .is_none_or(|x| x.is_match(archive.name()))
+        })
+        .collect::<Vec<_>>();
+
+    let mut table = Table::new();
+    table.add_row(row!["Archive", "Created", "Path", "Size", "Modified"]);
+    let mut matches = 0;
+    let mut matching_archives = 0;
+    for archive in &archives {
+        let mut found = false;
+        for node in archive.listing().await {
+            if !node_matches(&mut repo, archive, &node, &pattern, tags).await? {
+                continue;
+            }
+            let size = if node.is_file() {
+                node.total_length.to_string()
+            } else {
+                "-".to_string()
+            };
+            let modified = node
+                .metadata
+                .windows
+                .as_ref()
+                .and_then(|x| filetime(x.last_write_time))
+                .map_or_else(|| "-".to_string(), |x| x.to_rfc2822());
+            table.add_row(row![
+                archive.name(),
+                archive.timestamp().to_rfc2822(),
+                node.path,
+                size,
+                modified
+            ]);
+            matches += 1;
+            found = true;
+        }
+        if found {
+            matching_archives += 1;
+        }
+    }
+    repo.close().await;
+
+    if matches > 0 {
+        table.printstd();
+    }
+    if !options.quiet {
+        println!(
+            "Found {} matches in {} of {} archives searched",
+            matches,
+            matching_archives,
+            archives.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use crate::cli::Opt;
+
+use asuran::repository::*;
+
+use anyhow::Result;
+use prettytable::{row, Table};
+
+/// Lists the heads of a repository's manifest, optionally merging them into one
+pub async fn heads(options: Opt, merge: bool) -> Result<()> {
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    let result = list_and_merge(&mut repo, merge, options.quiet).await;
+    repo.close().await;
+    result
+}
+
+async fn list_and_merge(
+    repo: &mut Repository<impl BackendClone>,
+    merge: bool,
+    quiet: bool,
+) -> Result<()> {
+    let heads = repo.heads().await?;
+    if !quiet {
+        println!("Number of heads in manifest: {}", heads.len());
+        let mut table = Table::new();
+        table.add_row(row!["ID", "Written", "Archive", "Diverged Transactions"]);
+        for head in &heads {
+            let name = head.name.as_deref().unwrap_or("(merge or checkpoint)");
+            table.add_row(row![
+                &head.id.to_hex()[..16],
+                &head.timestamp.to_rfc2822(),
+                name,
+                head.diverged
+            ]);
+        }
+        table.printstd();
+    }
+    if merge {
+        match repo.merge_heads().await? {
+            Some(id) if !quiet => println!("Merged {} heads into {}", heads.len(), id.to_hex()),
+            None if !quiet => println!("Manifest has not diverged, nothing to merge"),
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use crate::cli::Opt;
+use crate::password::Password;
+use crate::store::select_chunker;
+
+use asuran::interop::restic::{import_snapshot, ResticRepository};
+use asuran::manifest::*;
+use asuran::repository::*;
+
+use anyhow::{anyhow, Context, Result};
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Re-stores the snapshots of a restic repository as archives
+///
+/// Each snapshot becomes an archive named `restic-` followed by the short ID of the
+/// snapshot. Snapshots that already have an archive by that name are skipped, so an
+/// interrupted import can simply be run again.
+pub async fn import_restic(
+    options: Opt,
+    restic_repo: PathBuf,
+    restic_password: Option<Password>,
+    snapshots: Vec<String>,
+) -> Result<()> {
+    let restic_password = restic_password
+        .ok_or_else(|| anyhow!("No password was given for the restic repository"))?;
+    // Open the restic repository first, so a bad password does not leave a connection open
+    let restic = ResticRepository::open(&restic_repo, restic_password.as_bytes())
+        .with_context(|| format!("Unable to open restic repository at {:?}", restic_repo))?;
+    let restic = Arc::new(restic);
+    // Then, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    if let Some(limit) = options.memory_limit {
+        repo.set_memory_limit(limit);
+    }
+    repo.adaptive_compression = options.repo_opts().adaptive_compression;
+    repo.padding = options.repo_opts().get_padding();
+    let result = import(&options, &restic, &mut repo, &snapshots).await;
+    repo.close().await;
+    let imported = result?;
+    if !options.quiet {
+        println!("Imported {} snapshots", imported);
+    }
+    Ok(())
+}
+
+async fn import(
+    options: &Opt,
+    restic: &Arc<ResticRepository>,
+    repo: &mut Repository<impl BackendClone>,
+    wanted: &[String],
+) -> Result<usize> {
+    let mut manifest = Manifest::load(repo);
+    let existing: Vec<String> = manifest
+        .archives()
+        .await
+        .into_iter()
+        .map(|archive| archive.name().to_string())
+        .collect();
+    let snapshots: Vec<_> = restic
+        .snapshots()?
+        .into_iter()
+        .filter(|snapshot| {
+            let id = snapshot.id.to_string();
+            wanted.is_empty() || wanted.iter().any(|prefix| id.starts_with(prefix))
+        })
+        .collect();
+    if snapshots.is_empty() && !wanted.is_empty() {
+        return Err(anyhow!(
+            "None of the provided snapshot IDs match a snapshot in the restic repository"
+        ));
+    }
+    let chunker = select_chunker(options, repo, &mut manifest, true).await?;
+    let mut imported = 0;
+    for snapshot in snapshots {
+        let name = format!("restic-{}", snapshot.id.short());
+        if existing.contains(&name) {
+            if !options.quiet {
+                println!(
+                    "Skipping snapshot {}, {} already exists",
+                    snapshot.id.short(),
+                    name
+                );
+            }
+            continue;
+        }
+        if !options.quiet {
+            println!(
+                "Importing snapshot {} of {} from {} as {}",
+                snapshot.id.short(),
+                snapshot.paths.join(", "),
+                snapshot.time,
+                name
+            );
+        }
+        let mut archive = ActiveArchive::new(&name);
+        import_snapshot(restic, &snapshot, repo, &chunker, &mut archive)
+            .await
+            .with_context(|| format!("Failed to import snapshot {}", snapshot.id))?;
+        manifest.commit_archive(repo, archive).await?;
+        imported += 1;
+    }
+    Ok(imported)
+}
@@ -1,25 +1,25 @@
 use crate::cli::Opt;
 
+use asuran::manifest::signing::{SignaturePolicy, SignatureStatus};
 use asuran::manifest::*;
-use asuran::repository::*;
 
 use anyhow::Result;
-use prettytable::{cell, row, Table};
+use prettytable::{row, Table};
 
 /// Iterates through a repository's manifest and pretty prints all the archives
-pub async fn list(options: Opt) -> Result<()> {
+///
+/// If any tags are provided, only the archives carrying all of them are listed.
+///
+/// The signature of each listed archive is checked against `policy`. Archives it refuses
+/// are still listed, but make the command fail.
+pub async fn list(options: Opt, tags: &[String], policy: SignaturePolicy) -> Result<()> {
     // Open the repository
-    let (backend, key) = options.open_repo_backend().await?;
-    let chunk_settings = options.get_chunk_settings();
-    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks());
+    let mut repo = options.open_repo().await?;
     // load the manifest
     let mut manifest = Manifest::load(&repo);
     // Get the list of archives and extract them from the repository
-    let mut archives: Vec<ActiveArchive> = Vec::new();
-    for stored_archive in manifest.archives().await {
-        let archive = stored_archive.load(&mut repo).await?;
-        archives.push(archive);
-    }
+    let stored_archives = manifest.archives().await;
+    let archives: Vec<ActiveArchive> = manifest.load_archives(&mut repo).await?;
     // Print out basic archive stats
     println!("Number of archives in repository: {}", archives.len());
     println!(
@@ -28,15 +28,51 @@ pub async fn list(options: Opt) -> Result<()> {
     );
     // Iterate through the list of archives, and print them out in a nice table
     let mut table = Table::new();
-    table.add_row(row!["Index", "Name", "Creation Time"]);
-    for (index, archive) in archives.into_iter().enumerate() {
+    table.add_row(row![
+        "Index",
+        "ID",
+        "Name",
+        "Creation Time",
+        "Compression",
+        "Tags",
+        "Signature"
+    ]);
+    // Indexes are kept relative to the full list, so they stay meaningful when filtering
+    let matching = stored_archives
+        .iter()
+        .zip(archives)
+        .enumerate()
+        .filter(|(_, (_, archive))| archive.metadata().has_tags(tags.iter().map(String::as_str)));
+    let mut refused = None;
+    for (index, (stored_archive, archive)) in matching {
+        let signature = SignatureStatus::of(stored_archive, &policy.trusted);
+        if let Err(error) = policy.check(stored_archive) {
+            refused.get_or_insert(error);
+        }
+        let compression = archive
+            .chunk_settings()
+            .map_or_else(|| "Default".to_string(), |x| format!("{:?}", x.compression));
+        let tags = archive
+            .metadata()
+            .tags
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
         table.add_row(row![
             index,
+            &stored_archive.id().to_hex()[..12],
             archive.name(),
-            &archive.timestamp().to_rfc2822()
+            &archive.timestamp().to_rfc2822(),
+            compression,
+            tags,
+            signature.to_string()
         ]);
     }
     table.printstd();
     repo.close().await;
-    Ok(())
+    match refused {
+        Some(error) => Err(error.into()),
+        None => Ok(()),
+    }
 }
@@ -0,0 +1,52 @@
+use crate::cli::Opt;
+
+use asuran::repository::audit::{self, AuditEntry, Operation};
+use asuran::repository::*;
+
+use anyhow::Result;
+use prettytable::{row, Table};
+
+/// Prints the repository's audit log, oldest entry first
+pub async fn log(options: Opt) -> Result<()> {
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    let entries = audit::read_log(&mut repo).await;
+    repo.close().await;
+    let mut table = Table::new();
+    table.add_row(row![
+        "#",
+        "Time",
+        "Operation",
+        "Archive",
+        "User",
+        "Host",
+        "Client"
+    ]);
+    for entry in entries? {
+        table.add_row(row![
+            entry.sequence,
+            entry.timestamp.to_rfc2822(),
+            entry.operation,
+            entry.archive.as_deref().unwrap_or(""),
+            entry.user,
+            entry.hostname,
+            entry.client_version,
+        ]);
+    }
+    table.printstd();
+    Ok(())
+}
+
+/// Records `operation` in the repository's audit log, as performed by this client
+pub async fn record(
+    repo: &mut Repository<impl BackendClone>,
+    operation: Operation,
+    archive: Option<String>,
+) -> Result<()> {
+    let entry = AuditEntry {
+        client_version: format!("asuran-cli {}", env!("CARGO_PKG_VERSION")),
+        ..AuditEntry::new(operation, archive)
+    };
+    audit::append(repo, &entry).await?;
+    Ok(())
+}
@@ -9,57 +9,373 @@ mod cli;
 #[cfg_attr(tarpaulin, skip)]
 mod bench;
 #[cfg_attr(tarpaulin, skip)]
+mod break_lock;
+#[cfg_attr(tarpaulin, skip)]
+mod bundle;
+#[cfg_attr(tarpaulin, skip)]
+mod check;
+#[cfg_attr(tarpaulin, skip)]
+mod check_conn;
+#[cfg_attr(tarpaulin, skip)]
+mod checkpoint;
+#[cfg_attr(tarpaulin, skip)]
+mod compact;
+#[cfg_attr(tarpaulin, skip)]
+mod compare;
+#[cfg_attr(tarpaulin, skip)]
+mod config;
+#[cfg_attr(tarpaulin, skip)]
 mod contents;
 #[cfg_attr(tarpaulin, skip)]
+mod copy;
+#[cfg_attr(tarpaulin, skip)]
+mod export_tar;
+#[cfg_attr(tarpaulin, skip)]
 mod extract;
 #[cfg_attr(tarpaulin, skip)]
+mod find;
+#[cfg_attr(tarpaulin, skip)]
+mod heads;
+#[cfg_attr(tarpaulin, skip)]
+mod import_restic;
+#[cfg_attr(tarpaulin, skip)]
 mod list;
 #[cfg_attr(tarpaulin, skip)]
+mod log;
+#[cfg_attr(tarpaulin, skip)]
+mod metrics;
+#[cfg_attr(tarpaulin, skip)]
+mod migrate;
+#[cfg_attr(tarpaulin, skip)]
 mod new;
 #[cfg_attr(tarpaulin, skip)]
+mod os_keyring;
+#[cfg_attr(tarpaulin, skip)]
+mod password;
+#[cfg_attr(tarpaulin, skip)]
+mod prune;
+#[cfg_attr(tarpaulin, skip)]
+mod run_job;
+#[cfg_attr(tarpaulin, skip)]
+mod scan;
+#[cfg_attr(tarpaulin, skip)]
+mod signing;
+#[cfg_attr(tarpaulin, skip)]
+mod snapshot;
+#[cfg_attr(tarpaulin, skip)]
+mod status;
+#[cfg_attr(tarpaulin, skip)]
 mod store;
+#[cfg_attr(tarpaulin, skip)]
+mod train_dictionary;
+#[cfg_attr(tarpaulin, skip)]
+mod verify;
 
 use anyhow::Result;
-use cli::{Command, Opt};
+use asuran::manifest::ArchiveMetadata;
+use asuran::ErrorKind;
+use cli::{BundleCommand, Command, Opt};
+use futures::future::{FutureExt, LocalBoxFuture};
+use metrics::Metrics;
+use std::process;
 use std::thread;
+use std::time::{Instant, SystemTime};
 use structopt::StructOpt;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Exit code of a `store` that committed its archive, but had to leave out files it could
+/// not read
+const WARNINGS_EXIT_CODE: i32 = 3;
+/// Exit code of a `store` or `extract` that was aborted before it finished
+const ABORTED_EXIT_CODE: i32 = 4;
 
 #[cfg_attr(tarpaulin, skip)]
-fn main() -> Result<()> {
-    let num_threads = num_cpus::get_physical();
+fn main() {
+    // Parse the options up front, so we know how many executor threads to spawn
+    let options = Opt::from_args();
+    let json_errors = options.json_errors;
+    let metrics_target = options.metrics.clone();
+    let metrics_format = options.metrics_format.clone();
+    let command_name = options.command.name();
+    let metrics = Metrics::new();
+    if metrics_target.is_some() {
+        let subscriber = tracing_subscriber::Registry::default().with(metrics.clone());
+        tracing::subscriber::set_global_default(subscriber)
+            .expect("Unable to install metrics subscriber");
+    }
+    // Let store and extract stop cleanly when interrupted
+    if let Err(error) = status::handle_interrupts() {
+        eprintln!("Warning: {:#}", error);
+    }
+    let start = Instant::now();
+    let num_threads = if options.low_memory {
+        1
+    } else {
+        num_cpus::get_physical()
+    };
     let (s, r) = piper::chan::<()>(0);
     let mut threads = Vec::new();
     for _ in 0..num_threads {
         let r = r.clone();
         threads.push(thread::spawn(move || smol::run(r.recv())));
     }
-    let result = smol::block_on(async {
-        // Our task in main is dead simple, we only need to parse the options and
-        // match on the subcommand
-        let options = Opt::from_args();
+    let result = smol::block_on(run(options));
+    drop(s);
+
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    if let Some(target) = metrics_target {
+        let labels = [("command", command_name)];
+        metrics.set_gauge(
+            "asuran_cli_duration_seconds",
+            &labels,
+            start.elapsed().as_secs_f64(),
+        );
+        let finished = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|x| x.as_secs_f64())
+            .unwrap_or(0.0);
+        metrics.set_gauge("asuran_cli_finished_timestamp_seconds", &labels, finished);
+        let success = if result.is_ok() { 1.0 } else { 0.0 };
+        metrics.set_gauge("asuran_cli_success", &labels, success);
+        if let Err(error) = metrics.export(&target, &metrics_format) {
+            eprintln!("Unable to export metrics: {:?}", error);
+        }
+    }
+
+    if let Err(error) = result {
+        // Completing with warnings is not an error, so it gets its own code
+        let warnings = error.downcast_ref::<store::CompletedWithWarnings>().is_some();
+        let (name, exit_code) = if warnings {
+            ("completed_with_warnings", WARNINGS_EXIT_CODE)
+        } else if error.downcast_ref::<status::Aborted>().is_some() {
+            ("aborted", ABORTED_EXIT_CODE)
+        } else {
+            let kind = error_kind(&error);
+            (kind.as_str(), kind.exit_code())
+        };
+        if json_errors {
+            let report = serde_json::json!({
+                "error": name,
+                "exit_code": exit_code,
+                "message": format!("{:#}", error),
+            });
+            eprintln!("{}", report);
+        } else if warnings {
+            eprintln!("Warning: {}", error);
+        } else {
+            eprintln!("Error: {:?}", error);
+        }
+        process::exit(exit_code);
+    }
+}
+
+/// Runs the subcommand the user selected
+///
+/// The future is boxed, as `run-job` runs other subcommands through this.
+fn run(mut options: Opt) -> LocalBoxFuture<'static, Result<()>> {
+    async move {
+        options.resolve_passwords()?;
+        // Our task in main is dead simple, we only need to match on the subcommand
         let command = options.command.clone();
         match command {
-            Command::New { .. } => new::new(options).await,
-            Command::Store { target, name, .. } => store::store(options, target, name).await,
-            Command::List { .. } => list::list(options).await,
+            Command::New {
+                append_only,
+                parity_shards,
+                data_shards,
+                volume_size,
+                ..
+            } => {
+                new::new(
+                    options,
+                    append_only,
+                    data_shards,
+                    parity_shards,
+                    volume_size,
+                )
+                .await
+            }
+            Command::Store {
+                target,
+                name,
+                scan_command,
+                exclude,
+                exclude_caches,
+                exclude_if_present,
+                one_file_system,
+                dereference,
+                read_special,
+                alternate_streams,
+                list_skipped,
+                tag,
+                meta,
+                checkpoint_interval,
+                checkpoint_size,
+                integrity,
+                signing_key,
+                object_hash,
+                dry_run,
+                snapshot,
+                snapshot_size,
+                ..
+            } => {
+                let snapshot = snapshot.map(|kind| snapshot::SnapshotSettings {
+                    kind,
+                    lvm_size: snapshot_size.unwrap_or(snapshot::DEFAULT_LVM_SNAPSHOT_SIZE),
+                });
+                let mut metadata = ArchiveMetadata::default();
+                metadata.tags.extend(tag);
+                metadata.values.extend(meta);
+                let excludes = store::ExcludeSettings {
+                    patterns: exclude,
+                    caches: exclude_caches,
+                    if_present: exclude_if_present,
+                };
+                let checkpoints = store::CheckpointSettings {
+                    interval: checkpoint_interval,
+                    size: checkpoint_size,
+                };
+                store::store(
+                    options,
+                    target,
+                    name,
+                    scan_command,
+                    &excludes,
+                    one_file_system,
+                    dereference,
+                    read_special,
+                    alternate_streams,
+                    list_skipped,
+                    metadata,
+                    checkpoints,
+                    integrity,
+                    signing_key,
+                    object_hash,
+                    dry_run,
+                    snapshot,
+                )
+                .await
+            }
+            Command::List {
+                tag,
+                signature_opts,
+                ..
+            } => list::list(options, &tag, signature_opts.policy()?).await,
             Command::Extract {
                 target,
                 archive,
                 glob_opts,
                 preview,
+                hardened,
+                paths,
+                signature_opts,
                 ..
-            } => extract::extract(options, target, archive, glob_opts, preview).await,
+            } => {
+                let policy = signature_opts.policy()?;
+                extract::extract(
+                    options, target, archive, glob_opts, paths, preview, hardened, policy,
+                )
+                .await
+            }
+            Command::GenerateSigningKey { output } => {
+                signing::generate_signing_key(&output, options.quiet)
+            }
             Command::BenchCrypto => bench::bench_crypto().await,
+            Command::BenchChunker { samples } => bench::bench_chunker(samples).await,
             Command::Contents {
                 archive, glob_opts, ..
             } => contents::contents(options, archive, glob_opts).await,
+            Command::Find {
+                pattern,
+                archive,
+                tags,
+                ..
+            } => find::find(options, pattern, archive, tags).await,
+            Command::ExportTar {
+                archive,
+                output,
+                tar_compression,
+                ..
+            } => export_tar::export_tar(options, archive, output, tar_compression).await,
+            Command::Compare {
+                archive, target, ..
+            } => compare::compare(options, archive, target).await,
+            Command::Compact { threshold, .. } => compact::compact(options, threshold).await,
+            Command::Check { repair, .. } => check::check(options, repair).await,
+            Command::CheckConn { .. } => check_conn::check_conn(options).await,
+            Command::Verify { archive, .. } => verify::verify(options, archive).await,
+            Command::Checkpoint { drop, .. } => checkpoint::checkpoint(options, drop).await,
+            Command::Heads { merge, .. } => heads::heads(options, merge).await,
+            Command::TrainDictionary {
+                samples, max_size, ..
+            } => train_dictionary::train_dictionary(options, samples, max_size).await,
+            Command::ImportRestic {
+                restic_repo,
+                restic_password,
+                snapshot,
+                ..
+            } => {
+                import_restic::import_restic(options, restic_repo, restic_password, snapshot).await
+            }
+            Command::Prune {
+                retention_opts,
+                dry_run,
+                threshold,
+                full_gc,
+                ..
+            } => {
+                prune::prune(
+                    options,
+                    retention_opts.policy(),
+                    dry_run,
+                    threshold,
+                    full_gc,
+                )
+                .await
+            }
+            Command::Log { .. } => log::log(options).await,
+            Command::Migrate { force, .. } => migrate::migrate(options, force).await,
+            Command::BreakLock { list, .. } => break_lock::break_lock(options, list),
+            Command::ForgetPassword { .. } => {
+                os_keyring::forget_password(options.repo_opts(), options.quiet)
+            }
+            Command::Copy {
+                dst_repo,
+                archives,
+                dst_password,
+                dst_repository_type,
+                ..
+            } => {
+                copy::copy(
+                    options,
+                    dst_repo,
+                    dst_password,
+                    dst_repository_type,
+                    archives,
+                )
+                .await
+            }
+            Command::Bundle(BundleCommand::Create {
+                output,
+                volume_size,
+                ..
+            }) => bundle::create(options, output, volume_size).await,
+            Command::Bundle(BundleCommand::Restore { input, .. }) => {
+                bundle::restore(options, input).await
+            }
+            Command::RunJob { job, config } => run_job::run_job(options, job, config).await,
         }
-    });
-    drop(s);
-
-    for t in threads {
-        t.join().unwrap();
     }
+    .boxed_local()
+}
 
-    result
+/// Finds the kind of the most specific `asuran` error among the causes of an error
+fn error_kind(error: &anyhow::Error) -> ErrorKind {
+    error
+        .chain()
+        .map(ErrorKind::of)
+        .find(|kind| *kind != ErrorKind::Other)
+        .unwrap_or(ErrorKind::Other)
 }
@@ -0,0 +1,408 @@
+/*!
+The `metrics` module collects the metrics `asuran` reports through `tracing`,
+and exports them once the command has finished.
+
+See the `metrics` module in `asuran` for how metrics are reported.
+*/
+use crate::cli::MetricsFormat;
+
+use anyhow::{anyhow, Context, Result};
+use asuran::metrics::TARGET;
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_subscriber::layer::{self, Layer};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The upper bounds of the buckets of every histogram, in seconds
+const BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The name of a metric, along with its labels, sorted by name
+type Series = (String, Vec<(String, String)>);
+
+#[derive(Default, Clone, Debug)]
+struct Histogram {
+    /// The number of values falling into each bucket, followed by the number larger than the
+    /// last bucket
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, value: f64) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// The number of values at or below each bucket's bound, followed by the total
+    fn cumulative(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .scan(0, |total, count| {
+                *total += count;
+                Some(*total)
+            })
+            .collect()
+    }
+}
+
+#[derive(Default, Debug)]
+struct Registry {
+    counters: BTreeMap<Series, u64>,
+    gauges: BTreeMap<Series, f64>,
+    histograms: BTreeMap<Series, Histogram>,
+}
+
+/// Collects the values recorded on a single metrics event
+#[derive(Default)]
+struct Visitor {
+    counters: Vec<(String, u64)>,
+    histograms: Vec<(String, f64)>,
+    labels: Vec<(String, String)>,
+}
+
+impl Visit for Visitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if let Some(name) = field.name().strip_prefix("monotonic_counter.") {
+            self.counters.push((name.to_string(), value));
+        } else if let Some(name) = field.name().strip_prefix("histogram.") {
+            #[allow(clippy::cast_precision_loss)]
+            self.histograms.push((name.to_string(), value as f64));
+        } else {
+            self.labels
+                .push((field.name().to_string(), value.to_string()));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.labels
+            .push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        if let Some(name) = field.name().strip_prefix("histogram.") {
+            if let Ok(value) = value.parse() {
+                self.histograms.push((name.to_string(), value));
+            }
+        } else if let Some(name) = field.name().strip_prefix("monotonic_counter.") {
+            if let Ok(value) = value.parse() {
+                self.counters.push((name.to_string(), value));
+            }
+        } else {
+            self.labels.push((field.name().to_string(), value));
+        }
+    }
+}
+
+/// A `tracing_subscriber` layer aggregating the metrics reported by `asuran`
+///
+/// Clones share the same metrics, so one can be kept around for exporting them after another
+/// has been installed as part of the global subscriber.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+    start: SystemTime,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            registry: Arc::new(Mutex::new(Registry::default())),
+            start: SystemTime::now(),
+        }
+    }
+
+    /// Sets the gauge `name` with the given labels to `value`
+    pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let series = series(
+            name,
+            labels.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+        self.registry.lock().unwrap().gauges.insert(series, value);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut output = String::new();
+        let mut last_name = "";
+        for ((name, labels), value) in &registry.counters {
+            if name != last_name {
+                output.push_str(&format!("# TYPE {}_total counter\n", name));
+                last_name = name;
+            }
+            output.push_str(&format!(
+                "{}_total{} {}\n",
+                name,
+                prometheus_labels(labels),
+                value
+            ));
+        }
+        for ((name, labels), value) in &registry.gauges {
+            if name != last_name {
+                output.push_str(&format!("# TYPE {} gauge\n", name));
+                last_name = name;
+            }
+            output.push_str(&format!(
+                "{}{} {}\n",
+                name,
+                prometheus_labels(labels),
+                value
+            ));
+        }
+        for ((name, labels), histogram) in &registry.histograms {
+            if name != last_name {
+                output.push_str(&format!("# TYPE {} histogram\n", name));
+                last_name = name;
+            }
+            let bounds = BUCKETS
+                .iter()
+                .map(ToString::to_string)
+                .chain(std::iter::once("+Inf".to_string()));
+            for (bound, count) in bounds.zip(histogram.cumulative()) {
+                let mut labels = labels.clone();
+                labels.push(("le".to_string(), bound));
+                output.push_str(&format!(
+                    "{}_bucket{} {}\n",
+                    name,
+                    prometheus_labels(&labels),
+                    count
+                ));
+            }
+            let labels = prometheus_labels(labels);
+            output.push_str(&format!("{}_sum{} {}\n", name, labels, histogram.sum));
+            output.push_str(&format!("{}_count{} {}\n", name, labels, histogram.count));
+        }
+        output
+    }
+
+    /// Renders the metrics as an OTLP `ExportMetricsServiceRequest`, in its JSON encoding
+    pub fn otlp(&self) -> Value {
+        let registry = self.registry.lock().unwrap();
+        let start = unix_nanos(self.start);
+        let now = unix_nanos(SystemTime::now());
+        let mut metrics: BTreeMap<&str, (&str, Vec<Value>)> = BTreeMap::new();
+        for ((name, labels), value) in &registry.counters {
+            let point = json!({
+                "attributes": otlp_attributes(labels),
+                "startTimeUnixNano": start,
+                "timeUnixNano": now,
+                "asInt": value.to_string(),
+            });
+            metrics
+                .entry(name)
+                .or_insert(("sum", Vec::new()))
+                .1
+                .push(point);
+        }
+        for ((name, labels), value) in &registry.gauges {
+            let point = json!({
+                "attributes": otlp_attributes(labels),
+                "timeUnixNano": now,
+                "asDouble": value,
+            });
+            metrics
+                .entry(name)
+                .or_insert(("gauge", Vec::new()))
+                .1
+                .push(point);
+        }
+        for ((name, labels), histogram) in &registry.histograms {
+            let point = json!({
+                "attributes": otlp_attributes(labels),
+                "startTimeUnixNano": start,
+                "timeUnixNano": now,
+                "count": histogram.count.to_string(),
+                "sum": histogram.sum,
+                "bucketCounts": histogram.counts.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                "explicitBounds": BUCKETS.to_vec(),
+            });
+            metrics
+                .entry(name)
+                .or_insert(("histogram", Vec::new()))
+                .1
+                .push(point);
+        }
+        let metrics: Vec<Value> = metrics
+            .into_iter()
+            .map(|(name, (kind, points))| match kind {
+                "sum" => json!({
+                    "name": name,
+                    "sum": {
+                        "dataPoints": points,
+                        // Cumulative
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                    },
+                }),
+                "gauge" => json!({
+                    "name": name,
+                    "gauge": { "dataPoints": points },
+                }),
+                _ => json!({
+                    "name": name,
+                    "unit": "s",
+                    "histogram": {
+                        "dataPoints": points,
+                        "aggregationTemporality": 2,
+                    },
+                }),
+            })
+            .collect();
+        json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": otlp_attributes(&[("service.name".to_string(), "asuran-cli".to_string())]),
+                },
+                "scopeMetrics": [{
+                    "scope": { "name": "asuran", "version": asuran::VERSION },
+                    "metrics": metrics,
+                }],
+            }],
+        })
+    }
+
+    /// Exports the metrics in the given format to `target`
+    ///
+    /// `target` is either a file path, "-" for stdout, or, for OTLP, the http:// URL of a
+    /// collector to post the metrics to.
+    pub fn export(&self, target: &str, format: &MetricsFormat) -> Result<()> {
+        let body = match format {
+            MetricsFormat::Prometheus => self.prometheus(),
+            MetricsFormat::OTLP => self.otlp().to_string(),
+        };
+        if target == "-" {
+            print!("{}", body);
+            Ok(())
+        } else if let Some(url) = target.strip_prefix("http://") {
+            match format {
+                MetricsFormat::OTLP => post(url, &body),
+                MetricsFormat::Prometheus => Err(anyhow!(
+                    "Prometheus metrics can only be written to a file or stdout"
+                )),
+            }
+        } else {
+            // Write to a temporary file first, so readers never see a partial file
+            let temporary = format!("{}.tmp", target);
+            fs::write(&temporary, body)
+                .with_context(|| format!("Unable to write metrics to {}", temporary))?;
+            fs::rename(&temporary, target)
+                .with_context(|| format!("Unable to write metrics to {}", target))
+        }
+    }
+
+    fn record(&self, visitor: Visitor) {
+        let mut registry = self.registry.lock().unwrap();
+        for (name, value) in visitor.counters {
+            let series = series(&name, visitor.labels.iter().cloned());
+            *registry.counters.entry(series).or_insert(0) += value;
+        }
+        for (name, value) in visitor.histograms {
+            let series = series(&name, visitor.labels.iter().cloned());
+            registry.histograms.entry(series).or_default().record(value);
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Metrics {
+    // Nothing but metrics is of interest, so every other span and event is disabled outright
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: layer::Context<'_, S>) -> bool {
+        metadata.target() == TARGET
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: layer::Context<'_, S>) {
+        if event.metadata().target() == TARGET {
+            let mut visitor = Visitor::default();
+            event.record(&mut visitor);
+            self.record(visitor);
+        }
+    }
+}
+
+fn series(name: &str, labels: impl Iterator<Item = (String, String)>) -> Series {
+    let mut labels: Vec<_> = labels.collect();
+    labels.sort();
+    (name.to_string(), labels)
+}
+
+fn prometheus_labels(labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn otlp_attributes(labels: &[(String, String)]) -> Value {
+    labels
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+/// Posts a JSON body to a plain HTTP url, given without its scheme
+fn post(url: &str, body: &str) -> Result<()> {
+    let (host, path) = match url.find('/') {
+        Some(index) => url.split_at(index),
+        None => (url, "/v1/metrics"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let mut stream = TcpStream::connect(&address)
+        .with_context(|| format!("Unable to connect to metrics collector at {}", address))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or_default();
+    if status
+        .split_whitespace()
+        .nth(1)
+        .is_some_and(|code| code.starts_with('2'))
+    {
+        Ok(())
+    } else {
+        Err(anyhow!("Metrics collector responded with: {}", status))
+    }
+}
@@ -0,0 +1,64 @@
+use crate::cli::Opt;
+use crate::log;
+use crate::store::resolve_dictionary;
+
+use asuran::manifest::*;
+use asuran::repository::audit::Operation;
+use asuran::repository::*;
+
+use anyhow::{anyhow, Result};
+
+/// Rewrites every chunk in the repository with the compression, encryption, and padding
+/// selected on the command line, and records the first two as the repository's defaults
+///
+/// Refuses to migrate repositories with integrity records, unless `force` is set.
+pub async fn migrate(options: Opt, force: bool) -> Result<()> {
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    repo.padding = options.repo_opts().get_padding();
+    let result = migrate_repository(&options, &mut repo, force).await;
+    repo.close().await;
+    result
+}
+
+async fn migrate_repository(
+    options: &Opt,
+    repo: &mut Repository<impl BackendClone>,
+    force: bool,
+) -> Result<()> {
+    let mut manifest = Manifest::load(repo);
+    let mut settings = manifest.chunk_settings().await;
+    let selected = options.get_chunk_settings();
+    let compression = if options.repo_opts().uses_dictionary() {
+        resolve_dictionary(selected.compression, settings)?
+    } else {
+        selected.compression
+    };
+    if !force {
+        let recorded = manifest
+            .archives()
+            .await
+            .iter()
+            .filter(|archive| archive.integrity.is_some())
+            .count();
+        if recorded > 0 {
+            return Err(anyhow!(
+                "{} archives have integrity records, which would report every migrated chunk \
+                 as replaced. Pass --force to migrate anyway",
+                recorded
+            ));
+        }
+    }
+    let stats = repo.migrate(compression, selected.encryption).await?;
+    settings.compression = compression;
+    settings.encryption = selected.encryption;
+    manifest.set_chunk_settings(settings).await?;
+    log::record(repo, Operation::Migrate, None).await?;
+    if !options.quiet {
+        println!(
+            "Migrated {} chunks, {} were already stored with the new settings",
+            stats.chunks_migrated, stats.chunks_skipped
+        );
+    }
+    Ok(())
+}
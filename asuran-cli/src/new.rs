@@ -1,9 +1,10 @@
 use crate::cli::{Opt, RepositoryType};
 
+use asuran::repository::backend::common::parity::ParitySettings;
 use asuran::repository::backend::flatfile::FlatFile;
-use asuran::repository::backend::multifile::MultiFile;
+use asuran::repository::backend::multifile::{MultiFile, MultiFileConfig, MultiFileSettings};
 use asuran::repository::backend::Backend;
-use asuran::repository::{EncryptedKey, Key};
+use asuran::repository::{ChunkSettings, EncryptedKey, Key};
 
 use anyhow::{anyhow, Context, Result};
 
@@ -11,18 +12,60 @@ use std::fs::create_dir_all;
 use std::path::PathBuf;
 
 /// Creates a new repository with the user specified settings ad the user
-/// specified location
-pub async fn new(options: Opt) -> Result<()> {
-    // Ensure that the repository path does not exist
-    if options.repo_opts().repo.exists() {
+/// specified location, optionally in append only mode
+///
+/// If `parity_shards` is non-zero, Reed-Solomon parity will be written along with
+/// every chunk. If `volume_size` is set, a FlatFile repository is split into volumes of
+/// that size. With `--use-keyring`, the password is stored in the keyring once the
+/// repository has been created.
+pub async fn new(
+    options: Opt,
+    append_only: bool,
+    data_shards: usize,
+    parity_shards: usize,
+    volume_size: Option<u64>,
+) -> Result<()> {
+    if append_only {
+        match options.repo_opts().repository_type {
+            RepositoryType::SFTP => {
+                return Err(anyhow!(
+                    "Append only mode is not supported for SFTP repositories"
+                ));
+            }
+            RepositoryType::WebDAV => {
+                return Err(anyhow!(
+                    "Append only mode is not supported for WebDAV repositories"
+                ));
+            }
+            _ => (),
+        }
+    }
+    let parity = if parity_shards > 0 {
+        if let RepositoryType::MultiFile = options.repo_opts().repository_type {
+            Some(ParitySettings::new(data_shards, parity_shards)?)
+        } else {
+            return Err(anyhow!(
+                "Parity is only supported for MultiFile repositories"
+            ));
+        }
+    } else {
+        None
+    };
+    if volume_size.is_some()
+        && !matches!(
+            options.repo_opts().repository_type,
+            RepositoryType::FlatFile
+        )
+    {
         return Err(anyhow!(
-            "Repository location already exists! {:?}",
-            &options.repo_opts().repo
+            "Splitting into volumes is only supported for FlatFile repositories"
         ));
     }
 
     // Figure out what encryption type the user wants to use and get the encryption length
-    let settings = options.get_chunk_settings();
+    let mut settings = options.get_chunk_settings();
+    // Record the chunker, so later runs split data the same way
+    settings.chunker = Some(options.repo_opts().chunker_settings()?.unwrap_or_default());
     let key_length = settings.encryption.key_length();
     // Make them a new random key
     let key = Key::random(key_length);
@@ -30,20 +73,64 @@ pub async fn new(options: Opt) -> Result<()> {
     let encrypted_key = EncryptedKey::encrypt_defaults(
         &key,
         settings.encryption,
-        options.repo_opts().password.as_bytes(),
+        options.repo_opts().password()?.as_bytes(),
     );
 
+    create(
+        &options,
+        key,
+        encrypted_key,
+        settings,
+        append_only,
+        parity,
+        volume_size,
+    )
+    .await?;
+    options.repo_opts().remember_password()
+}
+
+/// Creates a new repository at the user specified location, protected by an already
+/// existing key
+pub async fn create(
+    options: &Opt,
+    key: Key,
+    encrypted_key: EncryptedKey,
+    settings: ChunkSettings,
+    append_only: bool,
+    parity: Option<ParitySettings>,
+    volume_size: Option<u64>,
+) -> Result<()> {
+    // Ensure that the repository path does not exist
+    if options.repo_opts().repo.exists() {
+        return Err(anyhow!(
+            "Repository location already exists! {:?}",
+            &options.repo_opts().repo
+        ));
+    }
+
     // Figure out which type of repository they want, and create it
     match options.repo_opts().repository_type {
         RepositoryType::MultiFile => {
             // Create the directory
             create_dir_all(&options.repo_opts().repo)?;
+            // Always write out the configuration, so the format version is recorded
+            MultiFileConfig {
+                append_only,
+                parity,
+                ..MultiFileConfig::default()
+            }
+            .store(&options.repo_opts().repo)
+            .with_context(|| "Failed to write repository configuration.")?;
             // Open the repository and set the key
-            let mut mf = MultiFile::open_defaults(
+            let mut mf = MultiFile::open_with_settings(
                 &options.repo_opts().repo,
                 Some(settings),
                 &key,
                 options.pipeline_tasks() * 2,
+                MultiFileSettings {
+                    durability: options.repo_opts().get_durability(),
+                    ..MultiFileSettings::default()
+                },
             )
             .await
             .with_context(|| "Unable to create MultiFile directory.")?;
@@ -55,34 +142,42 @@ pub async fn new(options: Opt) -> Result<()> {
         }
         RepositoryType::FlatFile => {
             // Open the repository setting the key
-            let mut ff = FlatFile::new(
-                &options.repo_opts().repo,
-                Some(settings),
-                Some(encrypted_key),
-                key,
-                options.pipeline_tasks() * 2,
-            )
+            let mut ff = if let Some(volume_size) = volume_size {
+                FlatFile::new_with_volumes(
+                    &options.repo_opts().repo,
+                    settings,
+                    encrypted_key,
+                    key.clone(),
+                    options.pipeline_tasks() * 2,
+                    options.repo_opts().get_durability(),
+                    volume_size,
+                )
+            } else {
+                FlatFile::new_with_durability(
+                    &options.repo_opts().repo,
+                    Some(settings),
+                    Some(encrypted_key),
+                    key.clone(),
+                    options.pipeline_tasks() * 2,
+                    options.repo_opts().get_durability(),
+                )
+            }
             .with_context(|| "Unable to create flatfile.")?;
             ff.close().await;
+            if append_only {
+                FlatFile::set_append_only(&options.repo_opts().repo, key)
+                    .with_context(|| "Failed to put flatfile in append only mode.")?;
+            }
             Ok(())
         }
         RepositoryType::SFTP => {
             use asuran::repository::backend::sftp::*;
-            let opts = options.repo_opts();
-            let path = opts
-                .repo
-                .to_str()
-                .context("user/hostname/path string contained non-utf-8")?;
-            let (username, hostname, path) = crate::cli::parse_ssh_path(path)
-                .context("Unable to parse user/hostname/path string")?;
             let chunk_settings = settings;
-            let settings = SFTPSettings {
-                hostname,
-                port: opts.sftp_port,
-                username,
-                password: opts.sftp_password.clone(),
-                path: path.clone(),
-            };
+            let settings = options
+                .repo_opts()
+                .sftp_settings()
+                .context("Unable to parse user/hostname/path string")?;
+            let path = settings.path.clone();
             let mut connection: SFTPConnection = settings.clone().into();
             connection
                 .connect()
@@ -118,5 +213,35 @@ pub async fn new(options: Opt) -> Result<()> {
             sftp.close().await;
             Ok(())
         }
+        RepositoryType::WebDAV => {
+            use asuran::repository::backend::webdav::*;
+            let opts = options.repo_opts();
+            let url = opts.repo.to_str().context("WebDAV URL contained non-utf-8")?;
+            let chunk_settings = settings;
+            let settings = WebDavSettings {
+                url: url.to_string(),
+                username: opts.webdav_user.clone(),
+                password: opts.webdav_password.clone(),
+            };
+            let mut webdav = WebDav::connect(
+                &settings,
+                &key,
+                Some(chunk_settings),
+                options.pipeline_tasks() * 2,
+            )
+            .context("Failed to connect to WebDAV backend")?;
+
+            webdav
+                .write_key(&encrypted_key)
+                .await
+                .context("Failed to write key material to repository")?;
+
+            webdav.close().await;
+            Ok(())
+        }
+        // Remote repositories are created and configured by the server's administrator
+        RepositoryType::Remote => Err(anyhow!(
+            "Remote repositories must be created on the server, with a local repository type"
+        )),
     }
 }
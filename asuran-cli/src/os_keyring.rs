@@ -0,0 +1,114 @@
+/*!
+The `os_keyring` module stores repository passwords in the platform's keyring,
+the Secret Service on Linux, the Keychain on macOS, and the Credential Manager on
+Windows, so scheduled backups do not need the password in plain text.
+
+Passwords are stored under the `asuran` service, with the type and location of the
+repository as the account name. Local repositories are identified by their absolute
+path, so the same password is found no matter which directory `asuran-cli` is run
+from.
+*/
+use crate::cli::{RepoOpt, RepositoryType};
+use crate::password::Password;
+
+use anyhow::{anyhow, Result};
+
+/// The service passwords are stored under
+#[cfg(feature = "os-keyring")]
+const SERVICE: &str = "asuran";
+
+/// The account name the repository's password is stored under
+fn account(repo_opts: &RepoOpt) -> String {
+    let location = match repo_opts.repository_type {
+        RepositoryType::MultiFile | RepositoryType::FlatFile => repo_opts
+            .repo
+            .canonicalize()
+            .or_else(|_| std::env::current_dir().map(|x| x.join(&repo_opts.repo)))
+            .unwrap_or_else(|_| repo_opts.repo.clone()),
+        _ => repo_opts.repo.clone(),
+    };
+    format!("{}:{}", repo_opts.repository_type, location.display())
+}
+
+/// Looks up the password stored for the repository
+///
+/// Returns `None` if there is no password stored for it.
+#[cfg(feature = "os-keyring")]
+pub fn get_password(repo_opts: &RepoOpt) -> Result<Option<Password>> {
+    use keyring::{Keyring, KeyringError};
+    use zeroize::Zeroizing;
+    let account = account(repo_opts);
+    match Keyring::new(SERVICE, &account).get_password() {
+        Ok(password) => Ok(Some(Password::new(Zeroizing::new(password)))),
+        Err(KeyringError::NoPasswordFound) => Ok(None),
+        Err(e) => Err(anyhow!(
+            "Unable to read the repository password from the keyring: {}",
+            e
+        )),
+    }
+}
+
+/// Stores `password` as the repository's password, replacing any already stored
+#[cfg(feature = "os-keyring")]
+pub fn set_password(repo_opts: &RepoOpt, password: &Password) -> Result<()> {
+    use keyring::Keyring;
+    let account = account(repo_opts);
+    Keyring::new(SERVICE, &account)
+        .set_password(password.as_str())
+        .map_err(|e| {
+            anyhow!(
+                "Unable to store the repository password in the keyring: {}",
+                e
+            )
+        })
+}
+
+/// Removes the password stored for the repository
+///
+/// Returns false if there was no password stored for it.
+#[cfg(feature = "os-keyring")]
+pub fn delete_password(repo_opts: &RepoOpt) -> Result<bool> {
+    use keyring::{Keyring, KeyringError};
+    let account = account(repo_opts);
+    match Keyring::new(SERVICE, &account).delete_password() {
+        Ok(()) => Ok(true),
+        Err(KeyringError::NoPasswordFound) => Ok(false),
+        Err(e) => Err(anyhow!(
+            "Unable to remove the repository password from the keyring: {}",
+            e
+        )),
+    }
+}
+
+#[cfg(not(feature = "os-keyring"))]
+pub fn get_password(_repo_opts: &RepoOpt) -> Result<Option<Password>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "os-keyring"))]
+pub fn set_password(_repo_opts: &RepoOpt, _password: &Password) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "os-keyring"))]
+pub fn delete_password(_repo_opts: &RepoOpt) -> Result<bool> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "os-keyring"))]
+fn unsupported() -> anyhow::Error {
+    anyhow!("This build of asuran-cli does not support the keyring")
+}
+
+/// Removes the password stored in the keyring for the repository
+pub fn forget_password(repo_opts: &RepoOpt, quiet: bool) -> Result<()> {
+    let removed = delete_password(repo_opts)?;
+    if !quiet {
+        if removed {
+            println!("Removed the password for {}", account(repo_opts));
+        } else {
+            println!("No password was stored for {}", account(repo_opts));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+/*!
+The `password` module reads repository passwords from files, commands, or an
+interactive prompt, and keeps them in memory that is zeroed once they are no
+longer needed.
+*/
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Deserializer};
+use zeroize::Zeroizing;
+
+use std::convert::Infallible;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+/// A password, which is zeroed from memory when dropped
+///
+/// The `Debug` implementation does not reveal the password.
+#[derive(Clone)]
+pub struct Password(Zeroizing<String>);
+
+impl Password {
+    #[cfg(feature = "os-keyring")]
+    pub fn new(password: Zeroizing<String>) -> Password {
+        Password(password)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    #[cfg(feature = "os-keyring")]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Reads a password from a file, without its trailing newline
+    pub fn from_file(path: &Path) -> Result<Password> {
+        let mut file = File::open(path)
+            .with_context(|| format!("Unable to open password file {}", path.display()))?;
+        // Reserve the space up front, so reading does not leave copies behind in reallocations
+        let length = file.metadata().map(|x| x.len()).unwrap_or(0);
+        #[allow(clippy::cast_possible_truncation)]
+        let mut password = Zeroizing::new(String::with_capacity(length as usize + 1));
+        file.read_to_string(&mut password)
+            .with_context(|| format!("Unable to read password file {}", path.display()))?;
+        Ok(Password::without_newline(password))
+    }
+
+    /// Runs a shell command, taking what it prints, without the trailing newline, as the
+    /// password
+    pub fn from_command(command: &str) -> Result<Password> {
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C").arg(command);
+            shell
+        } else {
+            let mut shell = Command::new("sh");
+            shell.arg("-c").arg(command);
+            shell
+        };
+        let output = shell
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("Unable to run password command {:?}", command))?;
+        let stdout = Zeroizing::new(output.stdout);
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Password command {:?} failed with {}",
+                command,
+                output.status
+            ));
+        }
+        let password = std::str::from_utf8(&stdout)
+            .map_err(|_| anyhow!("Password command {:?} printed invalid UTF-8", command))?;
+        Ok(Password::without_newline(Zeroizing::new(
+            password.to_string(),
+        )))
+    }
+
+    /// Prompts for a password on the terminal, without echoing it
+    ///
+    /// With `confirm` set, the password has to be entered a second time, and is rejected if
+    /// the two do not match.
+    pub fn prompt(prompt: &str, confirm: bool) -> Result<Password> {
+        let read = |prompt: &str| {
+            rpassword::read_password_from_tty(Some(prompt))
+                .map(Zeroizing::new)
+                .context(
+                    "No password was given, and unable to prompt for one. Use --password-file, \
+                     --password-command, or ASURAN_PASSWORD",
+                )
+        };
+        let password = read(prompt)?;
+        if confirm && *read("Confirm password: ")? != *password {
+            return Err(anyhow!("Passwords do not match"));
+        }
+        Ok(Password(password))
+    }
+
+    fn without_newline(mut password: Zeroizing<String>) -> Password {
+        if password.ends_with('\n') {
+            password.pop();
+            if password.ends_with('\r') {
+                password.pop();
+            }
+        }
+        Password(password)
+    }
+}
+
+impl FromStr for Password {
+    type Err = Infallible;
+    fn from_str(input: &str) -> Result<Password, Infallible> {
+        Ok(Password(Zeroizing::new(input.to_string())))
+    }
+}
+
+impl<'de> Deserialize<'de> for Password {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Password, D::Error> {
+        String::deserialize(deserializer).map(|x| Password(Zeroizing::new(x)))
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Password(..)")
+    }
+}
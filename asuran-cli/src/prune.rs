@@ -0,0 +1,101 @@
+use crate::cli::Opt;
+use crate::log;
+
+use asuran::manifest::retention::RetentionPolicy;
+use asuran::manifest::*;
+use asuran::repository::audit::Operation;
+use asuran::repository::*;
+use asuran::time::Timestamp;
+
+use anyhow::{anyhow, Result};
+
+/// Removes the archives a retention policy does not keep, collects the chunks no
+/// longer referenced by any archive, and then compacts the repository to reclaim
+/// their space.
+///
+/// With `dry_run` set, only reports which archives would be kept and removed. With
+/// `full_gc` set, every remaining archive is loaded to find the chunks still referenced,
+/// rather than using the repository's reference counts.
+pub async fn prune(
+    options: Opt,
+    policy: RetentionPolicy,
+    dry_run: bool,
+    threshold: f64,
+    full_gc: bool,
+) -> Result<()> {
+    if !policy.has_rules() {
+        return Err(anyhow!(
+            "No retention rules given, refusing to prune. Pass at least one --keep option"
+        ));
+    }
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    let result = prune_repository(&options, &mut repo, &policy, dry_run, threshold, full_gc).await;
+    repo.close().await;
+    result
+}
+
+async fn prune_repository(
+    options: &Opt,
+    repo: &mut Repository<impl BackendClone>,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+    threshold: f64,
+    full_gc: bool,
+) -> Result<()> {
+    let mut manifest = Manifest::load(repo);
+    let archives = manifest.archives().await;
+    let now = Timestamp::now();
+    let selection = policy.select(&archives, now);
+    if dry_run || !options.quiet {
+        for (archive, reasons) in &selection.keep {
+            let reasons = reasons
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!(
+                "Keep:   {} ({}) [{}]",
+                archive.name(),
+                archive.timestamp().to_rfc2822(),
+                reasons
+            );
+        }
+        for archive in &selection.remove {
+            println!(
+                "Remove: {} ({})",
+                archive.name(),
+                archive.timestamp().to_rfc2822()
+            );
+        }
+    }
+    if dry_run {
+        println!(
+            "Would remove {} archives, keeping {}",
+            selection.remove.len(),
+            selection.keep.len()
+        );
+        return Ok(());
+    }
+    let stats = if full_gc {
+        manifest.prune_full(repo, &selection.remove).await?
+    } else {
+        manifest.prune(repo, &selection.remove).await?
+    };
+    log::record(repo, Operation::Prune, None).await?;
+    let compaction = repo.compact(threshold).await?;
+    if !options.quiet {
+        println!(
+            "Removed {} archives and {} unreferenced chunks, keeping {} archives",
+            stats.archives_removed, stats.chunks_removed, stats.archives_kept
+        );
+        if stats.full_collection {
+            println!("Found the unreferenced chunks by loading every remaining archive");
+        }
+        println!(
+            "Removed {} segments, moving {} chunks and reclaiming {} bytes",
+            compaction.segments_removed, compaction.chunks_moved, compaction.bytes_reclaimed
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,229 @@
+/*!
+Runs the backup jobs defined in the configuration file.
+
+Each step of a job is run as the equivalent `asuran-cli` subcommand, with the
+same global options as `run-job` was given.
+*/
+use crate::cli::{Command, Opt};
+use crate::config::{Config, Job};
+use crate::password::Password;
+use crate::store::CompletedWithWarnings;
+
+use anyhow::{anyhow, Context, Result};
+use structopt::StructOpt;
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Runs the job `name` from the configuration file at `config`, or the default location
+///
+/// The job's pre hooks are run first, then each of its sources is stored, and pruned if the
+/// job has any retention rules. The post hooks are run last, even if an earlier step failed,
+/// with `ASURAN_JOB_STATUS` set to `success`, `failure`, or `warning` if the job completed,
+/// but some files could not be stored.
+pub async fn run_job(options: Opt, name: String, config: Option<PathBuf>) -> Result<()> {
+    let config = Config::load(config.as_deref())?;
+    let job = config.job(&name)?;
+    if job.sources.is_empty() {
+        return Err(anyhow!("Job {} does not have any sources", name));
+    }
+    let result = run_steps(&options, &name, job).await;
+    let status = match &result {
+        Ok(()) => "success",
+        Err(error) if error.is::<CompletedWithWarnings>() => "warning",
+        Err(_) => "failure",
+    };
+    let post = run_hooks(&options, &name, &job.post, Some(status));
+    match (result, post) {
+        (Ok(()), post) => post,
+        (Err(error), Ok(())) => Err(error),
+        (Err(error), Err(post_error)) => {
+            eprintln!("Job {}: {:#}", name, post_error);
+            Err(error)
+        }
+    }
+}
+
+/// Runs the pre hooks, stores every source, and prunes them
+async fn run_steps(options: &Opt, name: &str, job: &Job) -> Result<()> {
+    run_hooks(options, name, &job.pre, None)?;
+    // Worked out by the first step, and reused by the rest, so it is only prompted for once
+    let mut password = job.password.clone();
+    // Files that could not be read do not stop the job, but are reported at the end
+    let mut failed = 0;
+    for source in &job.sources {
+        if !options.quiet {
+            println!("Job {}: storing {}", name, source.display());
+        }
+        let mut args = repository_args("store", job);
+        args.push(source.into());
+        for exclude in &job.exclude {
+            args.push("--exclude".into());
+            args.push(exclude.into());
+        }
+        if job.exclude_caches {
+            args.push("--exclude-caches".into());
+        }
+        for name in &job.exclude_if_present {
+            args.push("--exclude-if-present".into());
+            args.push(name.into());
+        }
+        if job.one_file_system {
+            args.push("--one-file-system".into());
+        }
+        if job.dereference {
+            args.push("--dereference".into());
+        }
+        if job.read_special {
+            args.push("--read-special".into());
+        }
+        if let Some(scan_command) = &job.scan_command {
+            args.push("--scan-command".into());
+            args.push(scan_command.into());
+        }
+        for tag in job.tags.iter().chain(&job_tags(name, source)) {
+            args.push("--tag".into());
+            args.push(tag.into());
+        }
+        args.extend(job.store_options.iter().map(OsString::from));
+        if let Err(error) = run_step(options, args, &mut password).await {
+            match error.downcast::<CompletedWithWarnings>() {
+                Ok(warnings) => failed += warnings.failed,
+                Err(error) => {
+                    return Err(error.context(format!("Failed to store {}", source.display())))
+                }
+            }
+        }
+    }
+    if job.retention.has_rules() {
+        for source in &job.sources {
+            if !options.quiet {
+                println!("Job {}: pruning archives of {}", name, source.display());
+            }
+            let mut args = repository_args("prune", job);
+            let retention = &job.retention;
+            for (flag, count) in &[
+                ("--keep-last", retention.keep_last),
+                ("--keep-daily", retention.keep_daily),
+                ("--keep-weekly", retention.keep_weekly),
+                ("--keep-monthly", retention.keep_monthly),
+                ("--keep-yearly", retention.keep_yearly),
+            ] {
+                args.push(flag.into());
+                args.push(count.to_string().into());
+            }
+            if let Some(within) = &retention.keep_within {
+                args.push("--keep-within".into());
+                args.push(within.into());
+            }
+            if let Some(threshold) = retention.threshold {
+                args.push("--threshold".into());
+                args.push(threshold.to_string().into());
+            }
+            for tag in &job_tags(name, source) {
+                args.push("--tag".into());
+                args.push(tag.into());
+            }
+            run_step(options, args, &mut password)
+                .await
+                .with_context(|| format!("Failed to prune archives of {}", source.display()))?;
+        }
+    }
+    if failed > 0 {
+        return Err(CompletedWithWarnings { failed }.into());
+    }
+    Ok(())
+}
+
+/// The tags identifying the archives a job stores of `source`
+fn job_tags(name: &str, source: &Path) -> [String; 2] {
+    [
+        format!("job:{}", name),
+        format!("source:{}", source.display()),
+    ]
+}
+
+/// Builds the arguments for running `subcommand` against the job's repository
+fn repository_args(subcommand: &str, job: &Job) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["asuran-cli".into(), subcommand.into()];
+    args.extend(job.repository_options.iter().map(OsString::from));
+    if let Some(password_file) = &job.password_file {
+        args.push("--password-file".into());
+        args.push(password_file.into());
+    }
+    if let Some(password_command) = &job.password_command {
+        args.push("--password-command".into());
+        args.push(password_command.into());
+    }
+    if job.use_keyring {
+        args.push("--use-keyring".into());
+    }
+    args.push(job.repository.as_os_str().to_owned());
+    args
+}
+
+/// Parses `args` as a subcommand and runs it with the global options of `options`
+///
+/// Uses `password` for the repository if it is set, and otherwise sets it to the password
+/// the step ended up using.
+async fn run_step(
+    options: &Opt,
+    args: Vec<OsString>,
+    password: &mut Option<Password>,
+) -> Result<()> {
+    let mut command = Command::from_iter_safe(args).map_err(|e| anyhow!("{}", e.message))?;
+    if password.is_some() {
+        command.repo_opts_mut().password = password.clone();
+    }
+    let mut options = Opt {
+        command,
+        ..options.clone()
+    };
+    options.resolve_passwords()?;
+    *password = options.repo_opts().password.clone();
+    crate::run(options).await
+}
+
+/// Runs each of the given shell commands in order
+///
+/// Every hook is given the name of the job in `ASURAN_JOB`, and, if provided, the status of
+/// the job in `ASURAN_JOB_STATUS`. Stops at the first hook that fails, unless the job has
+/// already run, in which case the remaining hooks are still run, so they can clean up.
+fn run_hooks(options: &Opt, name: &str, hooks: &[String], status: Option<&str>) -> Result<()> {
+    let mut result = Ok(());
+    for hook in hooks {
+        if !options.quiet {
+            println!("Job {}: running {}", name, hook);
+        }
+        let mut command = if cfg!(windows) {
+            let mut command = process::Command::new("cmd");
+            command.arg("/C").arg(hook);
+            command
+        } else {
+            let mut command = process::Command::new("sh");
+            command.arg("-c").arg(hook);
+            command
+        };
+        command.env("ASURAN_JOB", name);
+        if let Some(status) = status {
+            command.env("ASURAN_JOB_STATUS", status);
+        }
+        let hook_result = match command.status() {
+            Ok(exit) if exit.success() => Ok(()),
+            Ok(exit) => Err(anyhow!("Hook {:?} failed with {}", hook, exit)),
+            Err(e) => Err(anyhow!("Unable to run hook {:?}: {}", hook, e)),
+        };
+        if let Err(error) = hook_result {
+            if status.is_none() {
+                return Err(error);
+            } else if result.is_ok() {
+                result = Err(error);
+            } else {
+                // Only the first failure is returned, so report the rest here
+                eprintln!("Job {}: {:#}", name, error);
+            }
+        }
+    }
+    result
+}
@@ -0,0 +1,144 @@
+/*!
+Provides a `ScanHook` that runs an external command against the content of each
+file being stored.
+*/
+use asuran::manifest::scan::{FileScanner, ScanHook, ScanVerdict};
+use asuran::manifest::target::Node;
+
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// Runs a shell command once for each file being stored, feeding it the file's
+/// content on stdin.
+///
+/// The path of the file is passed to the command as its first argument. If the
+/// command exits successfully, each non-empty line it printed to stdout is recorded
+/// as a tag on the file. If the command exits unsuccessfully, or can not be run at
+/// all, the file is vetoed.
+///
+/// The command's output is collected on background threads while it runs, so it
+/// may print as much as it likes before it has read all of its input.
+pub struct CommandScanHook {
+    command: String,
+}
+
+impl CommandScanHook {
+    pub fn new(command: &str) -> CommandScanHook {
+        CommandScanHook {
+            command: command.to_string(),
+        }
+    }
+}
+
+impl ScanHook for CommandScanHook {
+    fn begin(&self, node: &Node) -> Option<Box<dyn FileScanner>> {
+        let mut command = if cfg!(windows) {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(&self.command);
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&self.command).arg("asuran-scan");
+            command
+        };
+        let child = command
+            .arg(&node.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map(|mut child| {
+                let stdout = drain(child.stdout.take());
+                let stderr = drain(child.stderr.take());
+                RunningScan {
+                    child,
+                    stdout,
+                    stderr,
+                }
+            })
+            .map_err(|e| format!("Unable to run scan command: {}", e));
+        Some(Box::new(CommandScanner { child: Some(child) }))
+    }
+}
+
+/// Reads everything from one of a command's output pipes on its own thread, so the
+/// command never blocks on a full pipe while it is still being fed its input
+fn drain(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            // Whatever was read before an error is still worth reporting
+            let _ = pipe.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+/// A running scan command, along with the threads collecting its output
+struct RunningScan {
+    child: Child,
+    stdout: JoinHandle<Vec<u8>>,
+    stderr: JoinHandle<Vec<u8>>,
+}
+
+struct CommandScanner {
+    child: Option<Result<RunningScan, String>>,
+}
+
+impl FileScanner for CommandScanner {
+    fn update(&mut self, data: &[u8]) {
+        if let Some(Ok(RunningScan { child, .. })) = &mut self.child {
+            // If the command has stopped reading its input, it has already made up its
+            // mind, so stop feeding it
+            let failed = match child.stdin.as_mut() {
+                Some(stdin) => stdin.write_all(data).is_err(),
+                None => false,
+            };
+            if failed {
+                child.stdin = None;
+            }
+        }
+    }
+    fn finish(&mut self) -> ScanVerdict {
+        let RunningScan {
+            mut child,
+            stdout,
+            stderr,
+        } = match self.child.take() {
+            Some(Ok(running)) => running,
+            Some(Err(e)) => return ScanVerdict::Veto(e),
+            None => return ScanVerdict::Veto("Scanner was already finished".to_string()),
+        };
+        // Close stdin so the command sees the end of the file
+        child.stdin = None;
+        let status = child.wait();
+        // The pipes close once the command exits, so these finish right after it
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => return ScanVerdict::Veto(format!("Unable to run scan command: {}", e)),
+        };
+        if status.success() {
+            let tags: Vec<String> = String::from_utf8_lossy(&stdout)
+                .lines()
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(str::to_string)
+                .collect();
+            if tags.is_empty() {
+                ScanVerdict::Accept
+            } else {
+                ScanVerdict::Tag(tags)
+            }
+        } else {
+            let reason = String::from_utf8_lossy(&stderr).trim().to_string();
+            if reason.is_empty() {
+                ScanVerdict::Veto(format!("Scan command failed with {}", status))
+            } else {
+                ScanVerdict::Veto(reason)
+            }
+        }
+    }
+}
@@ -0,0 +1,49 @@
+/*!
+The `signing` module generates and loads the keys archives are signed with.
+*/
+use asuran::manifest::signing::SigningKey;
+
+use anyhow::{Context, Result};
+use zeroize::Zeroizing;
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Generates a new signing key, writing it to `output`, and prints the key its
+/// signatures are verified with
+///
+/// The file is only readable by the current user, and is never overwritten.
+pub fn generate_signing_key(output: &Path, quiet: bool) -> Result<()> {
+    let key = SigningKey::generate();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(output)
+        .with_context(|| format!("Unable to create signing key file {}", output.display()))?;
+    writeln!(file, "{}", *key.encode())
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Unable to write signing key file {}", output.display()))?;
+    if quiet {
+        println!("{}", key.verifying_key());
+    } else {
+        println!("Wrote signing key to {}", output.display());
+        println!("Verifying key: {}", key.verifying_key());
+    }
+    Ok(())
+}
+
+/// Loads a signing key written by `generate_signing_key`
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let encoded = Zeroizing::new(
+        fs::read_to_string(path)
+            .with_context(|| format!("Unable to read signing key file {}", path.display()))?,
+    );
+    SigningKey::decode(&encoded)
+        .with_context(|| format!("Invalid signing key in {}", path.display()))
+}
@@ -0,0 +1,324 @@
+/*!
+Takes read-only filesystem snapshots to store from, so files that are being written
+to during a backup, such as live databases, are captured in a consistent state.
+
+The snapshots are taken and removed with the filesystem's own command line tools,
+or on Windows through PowerShell, which usually need to be run as root or an
+administrator.
+*/
+use crate::cli::SnapshotKind;
+
+use anyhow::{anyhow, Context, Result};
+
+use std::fs;
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::process::Command;
+
+/// Size of the copy-on-write area of LVM snapshots, if the user did not pick one
+pub const DEFAULT_LVM_SNAPSHOT_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// How to snapshot the filesystem being stored
+#[derive(Debug, Clone)]
+pub struct SnapshotSettings {
+    pub kind: SnapshotKind,
+    /// Size of the copy-on-write area for LVM snapshots, in bytes
+    pub lvm_size: u64,
+}
+
+/// Something to undo when the snapshot is removed, in the order they were done
+#[derive(Debug)]
+enum Teardown {
+    BtrfsSubvolume(PathBuf),
+    ZfsSnapshot(String),
+    LvmVolume(String),
+    ShadowCopy(String),
+    Unmount(PathBuf),
+    RemoveDir(PathBuf),
+}
+
+/// A read-only snapshot of the filesystem a path lives on
+///
+/// The snapshot is removed when this is dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    /// Where the path being stored can be found in the snapshot
+    path: PathBuf,
+    teardown: Vec<Teardown>,
+}
+
+/// The mounted filesystem a path lives on, as reported by `findmnt`
+struct Mount {
+    /// The device, or for ZFS the dataset, that is mounted
+    source: String,
+    /// Where it is mounted
+    target: PathBuf,
+    fstype: String,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the filesystem `path` lives on
+    pub fn create(settings: &SnapshotSettings, path: &Path) -> Result<Snapshot> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Unable to find {}", path.display()))?;
+        // Unique enough that two backups running at once do not collide
+        let name = format!("asuran-snapshot-{}", std::process::id());
+        let mut snapshot = Snapshot {
+            path: PathBuf::new(),
+            teardown: Vec::new(),
+        };
+        // Where the path is relative to the root of its filesystem, and so to the root of
+        // the snapshot
+        let (root, relative) = if let SnapshotKind::VSS = settings.kind {
+            let (volume, relative) = split_volume(&path)?;
+            (snapshot.vss(&volume)?, relative)
+        } else {
+            let mount = find_mount(&path)?;
+            let relative = path
+                .strip_prefix(&mount.target)
+                .expect("Path was not inside of the filesystem it is mounted on")
+                .to_path_buf();
+            let root = match settings.kind {
+                SnapshotKind::Btrfs => snapshot.btrfs(&mount, &name)?,
+                SnapshotKind::ZFS => snapshot.zfs(&mount, &name)?,
+                SnapshotKind::LVM => snapshot.lvm(&mount, &name, settings.lvm_size)?,
+                SnapshotKind::VSS => unreachable!(),
+            };
+            (root, relative)
+        };
+        snapshot.path = root.join(relative);
+        Ok(snapshot)
+    }
+
+    /// Where the snapshotted path can be read from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Snapshots the btrfs subvolume mounted at the mount point
+    ///
+    /// The snapshot is placed in the subvolume itself, and does not contain any nested
+    /// subvolumes.
+    fn btrfs(&mut self, mount: &Mount, name: &str) -> Result<PathBuf> {
+        check_fstype(mount, "btrfs")?;
+        let snapshot = mount.target.join(format!(".{}", name));
+        run(Command::new("btrfs")
+            .args(["subvolume", "snapshot", "-r"])
+            .arg(&mount.target)
+            .arg(&snapshot))?;
+        self.teardown.push(Teardown::BtrfsSubvolume(snapshot.clone()));
+        Ok(snapshot)
+    }
+
+    /// Snapshots the ZFS dataset, reading it back through the dataset's `.zfs` directory
+    fn zfs(&mut self, mount: &Mount, name: &str) -> Result<PathBuf> {
+        check_fstype(mount, "zfs")?;
+        let snapshot = format!("{}@{}", mount.source, name);
+        run(Command::new("zfs").arg("snapshot").arg(&snapshot))?;
+        self.teardown.push(Teardown::ZfsSnapshot(snapshot));
+        Ok(mount.target.join(".zfs").join("snapshot").join(name))
+    }
+
+    /// Snapshots the logical volume, with a copy-on-write area of `size` bytes, and mounts
+    /// the snapshot read-only in a temporary directory
+    fn lvm(&mut self, mount: &Mount, name: &str, size: u64) -> Result<PathBuf> {
+        let volume_group = output(
+            Command::new("lvs")
+                .args(["--noheadings", "-o", "vg_name"])
+                .arg(&mount.source),
+        )
+        .with_context(|| format!("{} is not an LVM logical volume", mount.source))?;
+        run(Command::new("lvcreate")
+            .args(["--snapshot", "--permission", "r", "--name", name])
+            .arg("--size")
+            .arg(format!("{}b", size))
+            .arg(&mount.source))?;
+        self.teardown
+            .push(Teardown::LvmVolume(format!("{}/{}", volume_group, name)));
+        let directory = std::env::temp_dir().join(name);
+        fs::create_dir(&directory)
+            .with_context(|| format!("Unable to create {}", directory.display()))?;
+        self.teardown.push(Teardown::RemoveDir(directory.clone()));
+        // XFS refuses to mount a second filesystem with the same UUID
+        let options = if mount.fstype == "xfs" {
+            "ro,nouuid"
+        } else {
+            "ro"
+        };
+        run(Command::new("mount")
+            .args(["-o", options])
+            .arg(format!("/dev/{}/{}", volume_group, name))
+            .arg(&directory))?;
+        self.teardown.push(Teardown::Unmount(directory.clone()));
+        Ok(directory)
+    }
+
+    /// Creates a Volume Shadow Copy of the volume, reading it back through the shadow
+    /// copy's device
+    fn vss(&mut self, volume: &str) -> Result<PathBuf> {
+        // Win32_ShadowCopy.Create is used rather than vssadmin, as the latter can only
+        // create shadow copies on server editions of Windows
+        let script = format!(
+            "$result = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+             if ($result.ReturnValue -ne 0) {{ \
+                 [Console]::Error.WriteLine('Error code ' + $result.ReturnValue); exit 1 \
+             }}; \
+             $result.ShadowID",
+            volume
+        );
+        let id = powershell(&script)?;
+        self.teardown.push(Teardown::ShadowCopy(id.clone()));
+        let device = powershell(&format!(
+            "(Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{}' }}).DeviceObject",
+            id
+        ))?;
+        // The device is a directory, but is only treated as one with a trailing separator
+        Ok(PathBuf::from(format!("{}\\", device)))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        while let Some(step) = self.teardown.pop() {
+            let result = match &step {
+                Teardown::BtrfsSubvolume(path) => {
+                    run(Command::new("btrfs").args(["subvolume", "delete"]).arg(path))
+                }
+                Teardown::ZfsSnapshot(snapshot) => {
+                    run(Command::new("zfs").arg("destroy").arg(snapshot))
+                }
+                Teardown::LvmVolume(volume) => {
+                    run(Command::new("lvremove").arg("--force").arg(volume))
+                }
+                Teardown::ShadowCopy(id) => powershell(&format!(
+                    "Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{}' }} | \
+                     ForEach-Object {{ $_.Delete() }}",
+                    id
+                ))
+                .map(|_| ()),
+                Teardown::Unmount(path) => run(Command::new("umount").arg(path)),
+                Teardown::RemoveDir(path) => fs::remove_dir(path)
+                    .with_context(|| format!("Unable to remove {}", path.display())),
+            };
+            if let Err(error) = result {
+                eprintln!("Warning: failed to remove snapshot: {:#}", error);
+            }
+        }
+    }
+}
+
+/// Finds the filesystem mounted closest to `path`
+fn find_mount(path: &Path) -> Result<Mount> {
+    let found = output(
+        Command::new("findmnt")
+            .args(["--noheadings", "--raw", "--output", "SOURCE,TARGET,FSTYPE"])
+            .arg("--target")
+            .arg(path),
+    )?;
+    let mut fields = found.split_whitespace().map(unescape);
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(source), Some(target), Some(fstype)) => Ok(Mount {
+            source,
+            target: target.into(),
+            fstype,
+        }),
+        _ => Err(anyhow!("Unable to find the filesystem {} is on", path.display())),
+    }
+}
+
+/// Undoes the `\x20` style escaping `findmnt --raw` applies to whitespace and other
+/// special characters
+fn unescape(field: &str) -> String {
+    let mut unescaped = Vec::new();
+    let mut bytes = field.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'\\' {
+            let mut escape = bytes.clone();
+            if escape.next() == Some(b'x') {
+                let digits = [escape.next(), escape.next()];
+                if let [Some(high), Some(low)] = digits {
+                    let hex = [high, low];
+                    let hex = std::str::from_utf8(&hex).unwrap_or("");
+                    if let Ok(value) = u8::from_str_radix(hex, 16) {
+                        unescaped.push(value);
+                        bytes = escape;
+                        continue;
+                    }
+                }
+            }
+        }
+        unescaped.push(byte);
+    }
+    String::from_utf8_lossy(&unescaped).to_string()
+}
+
+/// Splits a Windows path into the root of the volume it is on, such as `C:\`, and the
+/// rest of the path
+fn split_volume(path: &Path) -> Result<(String, PathBuf)> {
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                // Skip over the root directory
+                components.next();
+                Ok((
+                    format!("{}:\\", char::from(letter)),
+                    components.as_path().to_path_buf(),
+                ))
+            }
+            _ => Err(anyhow!(
+                "Shadow copies can only be made of volumes with a drive letter, not {}",
+                path.display()
+            )),
+        },
+        _ => Err(anyhow!(
+            "Shadow copies are only supported on Windows, {} is not a Windows path",
+            path.display()
+        )),
+    }
+}
+
+/// Runs a PowerShell script, returning what it printed
+fn powershell(script: &str) -> Result<String> {
+    output(Command::new("powershell").args([
+        "-NoProfile",
+        "-NonInteractive",
+        "-Command",
+        script,
+    ]))
+}
+
+fn check_fstype(mount: &Mount, expected: &str) -> Result<()> {
+    if mount.fstype == expected {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} is a {} filesystem, not {}",
+            mount.target.display(),
+            mount.fstype,
+            expected
+        ))
+    }
+}
+
+/// Runs a command, failing with its error output if it does not succeed
+fn run(command: &mut Command) -> Result<()> {
+    output(command).map(|_| ())
+}
+
+/// Runs a command, returning what it printed, trimmed of whitespace
+fn output(command: &mut Command) -> Result<String> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .output()
+        .with_context(|| format!("Unable to run {}", program))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        Err(anyhow!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
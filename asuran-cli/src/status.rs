@@ -0,0 +1,336 @@
+/*!
+The `status` module serves the progress of a running `store` or `extract` over a local
+socket, so graphical frontends and service managers can supervise it.
+
+Clients connect to the socket given with `--status-socket`, and send one command per
+line, each of which is answered with a single line of JSON:
+
+- `status` replies with the progress of the operation
+- `pause` stops new files from being started, letting those already in flight finish
+- `resume` continues a paused operation
+- `abort` stops the operation once the files in flight have finished
+
+The control commands reply with the progress as of after the command took effect.
+
+Interrupting `asuran-cli` with SIGINT or SIGTERM (or Ctrl-C on Windows) aborts a running
+`store` or `extract` the same way `abort` does, so it can finish writing what it has in
+flight, commit a checkpoint, and release its locks, rather than leaving a torn segment
+and a stale lock behind. Interrupting it again, or while nothing is running that can be
+stopped cleanly, exits right away.
+*/
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use serde_json::json;
+use smol::Timer;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often a paused operation checks whether it has been resumed
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Exit code used when exiting right away on an interruption, as shells do
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Number of times the process has been interrupted
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+/// Number of operations running that can be stopped cleanly when interrupted
+static CANCELLABLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs the handler for SIGINT and SIGTERM, or Ctrl-C on Windows
+///
+/// # Errors
+///
+/// Will return `Err` if a handler could not be installed.
+pub fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| {
+        let earlier = INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+        if earlier > 0 || CANCELLABLE.load(Ordering::SeqCst) == 0 {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        eprintln!(
+            "Interrupted, stopping once the files in flight are finished. Interrupt again to \
+             exit right away."
+        );
+    })
+    .context("Unable to install the interrupt handler")
+}
+
+/// Returned by an operation that was aborted, through its status socket or by being
+/// interrupted
+#[derive(Debug)]
+pub struct Aborted {
+    /// The operation that was aborted
+    pub operation: &'static str,
+}
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The {} was aborted before it finished", self.operation)
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// Whether the operation is running, or has been asked to pause or stop
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum State {
+    Running,
+    Paused,
+    Aborting,
+}
+
+/// The progress of the operation, as reported by the `status` command
+#[derive(Serialize, Debug)]
+struct Progress {
+    operation: &'static str,
+    archive: String,
+    state: State,
+    /// The file most recently started
+    current_file: Option<String>,
+    files_done: u64,
+    files_total: u64,
+    bytes_done: u64,
+    bytes_total: u64,
+    elapsed_seconds: u64,
+    /// Estimated from the rate bytes have been processed at so far
+    eta_seconds: Option<u64>,
+    #[serde(skip)]
+    started: Instant,
+    /// Set once the operation is over, telling the socket to stop accepting connections
+    #[serde(skip)]
+    closed: bool,
+}
+
+impl Progress {
+    /// Aborts the operation if the process has been interrupted
+    fn check_interrupted(&mut self) {
+        if INTERRUPTS.load(Ordering::SeqCst) > 0 {
+            self.state = State::Aborting;
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        CANCELLABLE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tracks the progress of an operation, and whether it has been paused or aborted
+///
+/// Clones share the same progress. Progress is tracked whether or not there is a socket
+/// to report it on.
+#[derive(Clone, Debug)]
+pub struct Status {
+    progress: Arc<Mutex<Progress>>,
+}
+
+impl Status {
+    fn new(operation: &'static str, archive: &str) -> Status {
+        CANCELLABLE.fetch_add(1, Ordering::SeqCst);
+        Status {
+            progress: Arc::new(Mutex::new(Progress {
+                operation,
+                archive: archive.to_string(),
+                state: State::Running,
+                current_file: None,
+                files_done: 0,
+                files_total: 0,
+                bytes_done: 0,
+                bytes_total: 0,
+                elapsed_seconds: 0,
+                eta_seconds: None,
+                started: Instant::now(),
+                closed: false,
+            })),
+        }
+    }
+
+    /// Creates the status of an operation, serving it on the socket at `path`, if there
+    /// is one
+    ///
+    /// The socket is removed again when the returned `StatusSocket` is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the socket could not be created, or if another process is
+    /// already serving its status on it.
+    pub fn serve(
+        path: Option<&Path>,
+        operation: &'static str,
+        archive: &str,
+    ) -> Result<(Status, Option<StatusSocket>)> {
+        let status = Status::new(operation, archive);
+        let socket = path
+            .map(|path| StatusSocket::bind(path, status.clone()))
+            .transpose()?;
+        Ok((status, socket))
+    }
+
+    /// Sets the number of files and bytes the operation will process in total
+    pub fn set_totals(&self, files: u64, bytes: u64) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.files_total = files;
+        progress.bytes_total = bytes;
+    }
+
+    /// Records that the operation has started on the file at `path`
+    pub fn start_file(&self, path: &str) {
+        self.progress.lock().unwrap().current_file = Some(path.to_string());
+    }
+
+    /// Records that the operation has finished with a file of `bytes` bytes
+    pub fn finish_file(&self, bytes: u64) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.files_done += 1;
+        progress.bytes_done += bytes;
+    }
+
+    /// Waits for the operation to be resumed if it is paused
+    ///
+    /// Returns false if the operation has been aborted or interrupted, and should not
+    /// start anything new.
+    pub async fn proceed(&self) -> bool {
+        loop {
+            match self.state() {
+                State::Running => return true,
+                State::Aborting => return false,
+                State::Paused => {
+                    Timer::after(PAUSE_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Returns the error an aborted operation finishes with
+    pub fn aborted(&self) -> Aborted {
+        Aborted {
+            operation: self.progress.lock().unwrap().operation,
+        }
+    }
+
+    fn state(&self) -> State {
+        let mut progress = self.progress.lock().unwrap();
+        progress.check_interrupted();
+        progress.state
+    }
+
+    /// Runs a command received on the socket, returning the reply to it
+    fn command(&self, command: &str) -> String {
+        let mut progress = self.progress.lock().unwrap();
+        progress.check_interrupted();
+        let aborting = progress.state == State::Aborting;
+        match command {
+            "status" => (),
+            // There is no coming back from an abort
+            "pause" | "resume" | "abort" if aborting => (),
+            "pause" => progress.state = State::Paused,
+            "resume" => progress.state = State::Running,
+            "abort" => progress.state = State::Aborting,
+            command => {
+                return json!({ "error": format!("Unknown command: {}", command) }).to_string()
+            }
+        }
+        let elapsed = progress.started.elapsed();
+        progress.elapsed_seconds = elapsed.as_secs();
+        progress.eta_seconds = if progress.bytes_done > 0 {
+            let remaining = progress.bytes_total.saturating_sub(progress.bytes_done);
+            let eta = elapsed.as_millis() * u128::from(remaining) / u128::from(progress.bytes_done);
+            u64::try_from(eta / 1000).ok()
+        } else {
+            None
+        };
+        serde_json::to_string(&*progress).expect("Unable to serialize progress")
+    }
+}
+
+/// The socket an operation's status is served on
+///
+/// Stops serving, and removes the socket, when dropped.
+pub struct StatusSocket {
+    path: PathBuf,
+    status: Status,
+}
+
+impl StatusSocket {
+    #[cfg(unix)]
+    fn bind(path: &Path, status: Status) -> Result<StatusSocket> {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::{UnixListener, UnixStream};
+        use std::thread;
+
+        // Replace sockets left behind by runs that were killed, but not ones still in use
+        if let Ok(metadata) = path.symlink_metadata() {
+            if !metadata.file_type().is_socket() {
+                return Err(anyhow!("{} exists and is not a socket", path.display()));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(anyhow!(
+                    "Another process is already serving its status on {}",
+                    path.display()
+                ));
+            }
+            std::fs::remove_file(path)
+                .with_context(|| format!("Unable to remove stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Unable to create status socket {}", path.display()))?;
+        let server_status = status.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if server_status.progress.lock().unwrap().closed {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let status = server_status.clone();
+                thread::spawn(move || {
+                    let mut writer = match stream.try_clone() {
+                        Ok(writer) => writer,
+                        Err(_) => return,
+                    };
+                    for line in BufReader::new(stream).lines() {
+                        let line = match line {
+                            Ok(line) => line,
+                            Err(_) => return,
+                        };
+                        let reply = status.command(line.trim());
+                        if writeln!(writer, "{}", reply).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(StatusSocket {
+            path: path.to_path_buf(),
+            status,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn bind(_path: &Path, _status: Status) -> Result<StatusSocket> {
+        Err(anyhow!(
+            "Status sockets are not supported on this platform, only on Unix-like systems"
+        ))
+    }
+}
+
+impl Drop for StatusSocket {
+    fn drop(&mut self) {
+        self.status.progress.lock().unwrap().closed = true;
+        // Wake the listener up, so it sees that it has been closed
+        #[cfg(unix)]
+        let _ = std::os::unix::net::UnixStream::connect(&self.path);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
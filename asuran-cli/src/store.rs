@@ -1,41 +1,500 @@
-use crate::cli::Opt;
+use crate::cli::{self_tested, ObjectHash, Opt};
+use crate::log;
+use crate::scan::CommandScanHook;
+use crate::signing::load_signing_key;
+use crate::snapshot::{Snapshot, SnapshotSettings};
+use crate::status::Status;
 
 use asuran::chunker::*;
 use asuran::manifest::driver::*;
+use asuran::manifest::hash::ObjectHashAlgorithm;
+use asuran::manifest::scan::ScanVerdict;
 use asuran::manifest::target::*;
 use asuran::manifest::*;
+use asuran::repository::audit::Operation;
+use asuran::repository::backend::common::sync_backend::BackendHandle;
+use asuran::repository::backend::flatfile::FlatFileStream;
 use asuran::repository::*;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use chrono::prelude::*;
 use futures::future::select_all;
 use smol::Task;
 
+use std::fmt;
+use std::mem::discriminant;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Metadata key recording the path a checkpoint was taken of, so that an unnamed backup
+/// of the same path can pick up where it left off
+const CHECKPOINT_SOURCE: &str = "asuran:source";
+
+/// When to commit checkpoints of the archive being stored
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CheckpointSettings {
+    /// Commit a checkpoint once this long has passed since the last one
+    pub interval: Option<Duration>,
+    /// Commit a checkpoint once this many bytes have been queued since the last one
+    pub size: Option<u64>,
+}
+
+impl CheckpointSettings {
+    fn due(&self, last: Instant, bytes: u64) -> bool {
+        self.interval
+            .is_some_and(|interval| last.elapsed() >= interval)
+            || self.size.is_some_and(|size| bytes >= size)
+    }
+}
+
+/// Which paths to leave out of the archive
+#[derive(Debug, Clone, Default)]
+pub struct ExcludeSettings {
+    /// Globs, relative to the directory being stored, of paths to leave out
+    pub patterns: Vec<String>,
+    /// Leave out directories tagged with a `CACHEDIR.TAG`
+    pub caches: bool,
+    /// Leave out directories containing a file with any of these names
+    pub if_present: Vec<String>,
+}
+
+/// Returned by `store` when the archive was committed, but some files could not be read
+/// while storing them, and were left out
+#[derive(Debug)]
+pub struct CompletedWithWarnings {
+    /// The number of files that were left out
+    pub failed: usize,
+}
+
+impl fmt::Display for CompletedWithWarnings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Completed with warnings, {} files could not be read and were not stored",
+            self.failed
+        )
+    }
+}
+
+impl std::error::Error for CompletedWithWarnings {}
+
+/// Running totals of a store, updated as each node is stored
+#[derive(Debug, Default)]
+struct Progress {
+    /// The files vetoed by the scan command
+    vetoed: Vec<SkippedEntry>,
+    /// The number of files stored
+    files: u64,
+    /// The chunks stored so far, along with the files that could not be read
+    totals: StoreReport,
+}
+
+/// Reports the outcome of storing a node to the user, adding it to the running totals,
+/// and recording it as skipped if it was vetoed
+fn report(
+    options: &Opt,
+    node: &Node,
+    (verdict, stored): (ScanVerdict, StoreReport),
+    progress: &mut Progress,
+    status: &Status,
+    dry_run: bool,
+) {
+    let action = if dry_run { "Would Store" } else { "Stored" };
+    progress.totals.merge(&stored);
+    if node.is_file() {
+        status.finish_file(node.total_size);
+    }
+    // Failures are printed even when quiet, as they leave holes in the archive
+    if let Some(failure) = stored.failed.first() {
+        eprintln!("Failed File: {} ({})", failure.path, failure.reason);
+        return;
+    }
+    match verdict {
+        ScanVerdict::Accept => {
+            if !options.quiet {
+                println!("{} File: {}", action, node.path);
+            }
+        }
+        ScanVerdict::Tag(tags) => {
+            if !options.quiet {
+                println!("{} File: {} [{}]", action, node.path, tags.join(", "));
+            }
+        }
+        ScanVerdict::Veto(reason) => {
+            if !options.quiet {
+                println!("Vetoed File: {} ({})", node.path, reason);
+            }
+            progress.vetoed.push(SkippedEntry {
+                path: node.path.clone(),
+                reason: SkipReason::Vetoed(reason),
+            });
+            return;
+        }
+    }
+    if node.is_file() {
+        progress.files += 1;
+    }
+}
+
+/// Summarizes what a dry run would have stored
+fn report_dry_run(progress: &Progress) {
+    let totals = &progress.totals;
+    println!(
+        "Dry run, nothing was written. Storing would add {} files, uploading {} new chunks \
+         totalling {} bytes",
+        progress.files, totals.chunks_written, totals.bytes_written
+    );
+    println!(
+        "{} chunks, holding {} of the {} bytes read, are already in the repository",
+        totals.chunks_deduplicated, totals.bytes_deduplicated, totals.bytes_read
+    );
+}
+
+/// Summarizes how much data the backup read, and how much of it had to be written
+fn report_totals(totals: &StoreReport) {
+    println!(
+        "Chunks: {} new, {} deduplicated",
+        totals.chunks_written, totals.chunks_deduplicated
+    );
+    println!(
+        "Read {} bytes, {} of which were deduplicated, and wrote {} bytes to the repository",
+        totals.bytes_read, totals.bytes_deduplicated, totals.bytes_written
+    );
+    if let (Some(compression), Some(effective)) =
+        (totals.compression_ratio(), totals.effective_ratio())
+    {
+        println!(
+            "Compression ratio: {:.2}, including deduplication: {:.2}",
+            compression, effective
+        );
+    }
+}
+
+/// Summarizes the entries that were not stored, optionally listing each one
+fn report_skipped(skipped: &[SkippedEntry], list_skipped: bool, quiet: bool) {
+    if list_skipped {
+        for entry in skipped {
+            println!("Skipped: {} ({})", entry.path, entry.reason);
+        }
+    }
+    let counts = SkipCounts::tally(skipped);
+    if !quiet && counts.total() > 0 {
+        let reasons: Vec<String> = [
+            (counts.excluded, "excluded by pattern"),
+            (counts.unreadable, "unreadable"),
+            (counts.special_file, "special"),
+            (counts.other_filesystem, "on another filesystem"),
+            (counts.marked, "marked as not to be backed up"),
+            (counts.vetoed, "vetoed"),
+            (counts.failed, "failed to read"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, reason)| format!("{} {}", count, reason))
+        .collect();
+        println!("Skipped {} entries: {}", counts.total(), reasons.join(", "));
+    }
+}
+
+/// Writes out the index of a repository being streamed to stdout, ending the stream, so
+/// that any errors doing so are reported
+async fn finish_stream<T: BackendClone>(
+    repo: &Repository<T>,
+    stream: Option<BackendHandle<FlatFileStream>>,
+) -> Result<()> {
+    if let Some(mut stream) = stream {
+        repo.commit_index().await;
+        stream
+            .with_backend(FlatFileStream::finish)
+            .await
+            .context("Unable to finish streaming the repository to stdout")?;
+    }
+    Ok(())
+}
+
+/// Sets the listing of the archive to everything stored so far, without any vetoed files
+/// or files that could not be read
+async fn update_listing(
+    archive: &ActiveArchive,
+    backup_target: &FileSystemTarget,
+    progress: &Progress,
+) {
+    let mut listing = backup_target.backup_listing().await;
+    for entry in progress.vetoed.iter().chain(&progress.totals.failed) {
+        listing.remove(&entry.path);
+    }
+    archive.set_listing(listing).await;
+}
+
+/// Commits a checkpoint of everything stored so far, recording the path being stored so
+/// that a later backup of it can resume from the checkpoint
+async fn commit_checkpoint<T: BackendClone>(
+    repo: &mut Repository<T>,
+    manifest: &mut Manifest<T>,
+    archive: &ActiveArchive,
+    backup_target: &FileSystemTarget,
+    progress: &Progress,
+    source: &str,
+) -> Result<()> {
+    update_listing(archive, backup_target, progress).await;
+    let mut checkpoint = archive.clone();
+    checkpoint.set_metadata(CHECKPOINT_SOURCE, source);
+    manifest.commit_checkpoint(repo, checkpoint).await?;
+    Ok(())
+}
+
+/// Works out which chunker to split files with
+///
+/// This is the chunker recorded in the repository, unless the user selected a different
+/// one, which then gets recorded as the repository's chunker after warning the user.
+/// Repositories that have not recorded a chunker were written with the default one, which
+/// gets recorded for them on their first store, unless they are append only.
+///
+/// Nothing is recorded if `record` is false, as for dry runs.
+pub async fn select_chunker<T: BackendClone>(
+    options: &Opt,
+    repo: &Repository<T>,
+    manifest: &mut Manifest<T>,
+    record: bool,
+) -> Result<AnyChunker> {
+    let mut defaults = manifest.chunk_settings().await;
+    let selected = options.repo_opts().chunker_settings()?;
+    let settings = selected.unwrap_or_else(|| defaults.chunker());
+    if let Err(e) = defaults.check_chunker(settings) {
+        if !options.quiet {
+            eprintln!(
+                "Warning: {}. New data will not deduplicate against data already in the \
+                 repository.",
+                e
+            );
+        }
+    }
+    if record && defaults.chunker != Some(settings) {
+        defaults.chunker = Some(settings);
+        match manifest.set_chunk_settings(defaults).await {
+            Ok(()) => (),
+            // Repositories that have not recorded their chunker are recorded as using the
+            // default one, append only repositories can be left as they are
+            Err(backend::BackendError::AppendOnly(_)) if selected.is_none() => (),
+            Err(e) => return Err(e).context("Failed to record the chunker in the repository"),
+        }
+    }
+    Ok(AnyChunker::from_settings(settings, repo.key()))
+}
+
+/// Swaps plain zstd compression for compression with the dictionary recorded in the
+/// repository's default settings
+pub fn resolve_dictionary(
+    compression: Compression,
+    defaults: ChunkSettings,
+) -> Result<Compression> {
+    match (compression, defaults.compression) {
+        (Compression::ZStd { level }, Compression::ZStdDict { dict_id, .. }) => {
+            Ok(Compression::ZStdDict { level, dict_id })
+        }
+        _ => Err(anyhow!(
+            "The repository does not have a zstd dictionary, run train-dictionary first"
+        )),
+    }
+}
 
 /// Creates a new archive in a repository and inserts the files from the user
-/// provided location
-pub async fn store(options: Opt, target: PathBuf, name: Option<String>) -> Result<()> {
-    // Open the repository
-    let (backend, key) = options.open_repo_backend().await?;
-    let chunk_settings = options.get_chunk_settings();
-    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks());
-    // Make sure we have a name for the archive, defaulting to the current
-    // date/time if the user did not provide us one
-    let name = name.unwrap_or_else(|| {
-        Local::now()
-            .with_timezone(Local::now().offset())
-            .to_rfc2822()
-    });
-    // Load the manifest and create the archive
+/// provided location, optionally scanning each file with a user provided command
+///
+/// Paths excluded by `excludes`, and with `one_file_system` set any directories on
+/// another filesystem, are left out. The archive is tagged with the
+/// provided metadata.
+///
+/// Checkpoints of the archive are committed as configured by `checkpoints`. If a
+/// checkpoint of an earlier, interrupted, backup with the same name (or without a name,
+/// of the same path) is found, the backup is resumed under its name, with everything
+/// the checkpoint stored being deduplicated against instead of uploaded again.
+///
+/// With `integrity` set, the archive and its checkpoints record the chunks they store, see
+/// `asuran::manifest::integrity`. With `signing_key` set, they are signed with the key in
+/// that file.
+///
+/// The contents of every file are hashed with `object_hash`, and the hash stored in the
+/// listing, see `asuran::manifest::hash`.
+///
+/// With `dry_run` set, files are read and chunked as usual, but no chunks are written,
+/// and the archive is not committed. What would have been uploaded is reported instead.
+///
+/// With `snapshot` set, the files are read from a snapshot of the filesystem `target` is
+/// on, which is removed again once the backup finishes, successfully or not.
+///
+/// Files that can not be read while storing them are reported and left out, without
+/// stopping the backup. The archive is still committed, but `CompletedWithWarnings` is
+/// returned.
+///
+/// Progress is served on the status socket, if one was given, see `crate::status`. If
+/// the backup is aborted through it, or interrupted, the files in flight are finished,
+/// and a checkpoint of everything stored is committed in place of the archive, before
+/// `Aborted` is returned.
+///
+/// If the repository is a FlatFile with a path of `-`, a new repository holding just
+/// this archive is streamed to stdout, see `RepoOpt::stream_to_stdout`. As stdout then
+/// carries the repository, nothing else is printed to it.
+#[allow(clippy::too_many_arguments)]
+pub async fn store(
+    mut options: Opt,
+    target: PathBuf,
+    name: Option<String>,
+    scan_command: Option<String>,
+    excludes: &ExcludeSettings,
+    one_file_system: bool,
+    dereference: bool,
+    read_special: bool,
+    alternate_streams: bool,
+    list_skipped: bool,
+    metadata: ArchiveMetadata,
+    checkpoints: CheckpointSettings,
+    integrity: bool,
+    signing_key: Option<PathBuf>,
+    object_hash: ObjectHash,
+    dry_run: bool,
+    snapshot: Option<SnapshotSettings>,
+) -> Result<()> {
+    // Only dry runs can get by without writing to the repository
+    if options.read_only && !dry_run {
+        return Err(anyhow!(
+            "Unable to store into a repository opened read only, only dry runs are possible"
+        ));
+    }
+    let streaming = options.repo_opts().is_stdout();
+    if streaming {
+        if dry_run || list_skipped {
+            return Err(anyhow!(
+                "Dry runs and listing skipped entries would print over the repository \
+                 streamed to stdout"
+            ));
+        }
+        options.quiet = true;
+    }
+    // Open the repository, or start streaming a new one
+    let (backend, key, stream) = if streaming {
+        let (stream, key) = options
+            .repo_opts()
+            .stream_to_stdout(options.queue_depth())?;
+        (stream.get_object_handle(), key, Some(stream))
+    } else {
+        let (backend, key) = options.open_repo_backend().await?;
+        (backend, key, None)
+    };
+    let mut chunk_settings = options.get_chunk_settings();
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    if let Some(limit) = options.memory_limit {
+        repo.set_memory_limit(limit);
+    }
+    repo.adaptive_compression = options.repo_opts().adaptive_compression;
+    repo.padding = options.repo_opts().get_padding();
+    let mut repo = self_tested(repo, options.read_only).await?;
+    // Checkpoints would have to be written, so dry runs do without them
+    let checkpoints = if dry_run {
+        repo.simulate_writes();
+        CheckpointSettings::default()
+    } else {
+        checkpoints
+    };
+    if integrity {
+        repo.record_integrity();
+    }
     let mut manifest = Manifest::load(&repo);
-    let archive = ActiveArchive::new(&name);
-    // TOOD: Allow chunker configuration
-    let chunker = FastCDC::default();
+    if let Some(path) = signing_key {
+        manifest.sign_with(load_signing_key(&path)?);
+    }
+    // Look for a checkpoint of an interrupted backup to resume
+    let source = target.to_string_lossy().to_string();
+    let resumed = manifest
+        .checkpoints()
+        .await
+        .into_iter()
+        .find(|checkpoint| match &name {
+            Some(name) => checkpoint.name() == name,
+            None => checkpoint.metadata().values.get(CHECKPOINT_SOURCE) == Some(&source),
+        });
+    if let Some(checkpoint) = &resumed {
+        if !options.quiet {
+            println!(
+                "Resuming from checkpoint of {} ({})",
+                checkpoint.name(),
+                checkpoint.timestamp().to_rfc2822()
+            );
+        }
+    }
+    // Make sure we have a name for the archive, defaulting to that of the resumed
+    // checkpoint, or the current date/time if the user did not provide us one
+    let name = name
+        .or_else(|| resumed.map(|checkpoint| checkpoint.name().to_string()))
+        .unwrap_or_else(|| {
+            Local::now()
+                .with_timezone(Local::now().offset())
+                .to_rfc2822()
+        });
+    // Create the archive
+    let mut archive = ActiveArchive::new(&name);
+    archive.set_object_hash_algorithm(match object_hash {
+        ObjectHash::BLAKE3 => ObjectHashAlgorithm::Blake3,
+        ObjectHash::SHA256 => ObjectHashAlgorithm::Sha256,
+    });
+    for tag in &metadata.tags {
+        archive.add_tag(tag);
+    }
+    for (key, value) in &metadata.values {
+        archive.set_metadata(key, value);
+    }
+    // If this run stores data differently than the repository's defaults, record that
+    // in the archive
+    let defaults = manifest.chunk_settings().await;
+    if options.repo_opts().uses_dictionary() {
+        chunk_settings.compression = resolve_dictionary(chunk_settings.compression, defaults)?;
+        repo = repo.with_chunk_settings(chunk_settings);
+    }
+    if chunk_settings.compression != defaults.compression
+        || discriminant(&chunk_settings.encryption) != discriminant(&defaults.encryption)
+    {
+        archive.set_chunk_settings(chunk_settings);
+    }
+    let chunker = select_chunker(&options, &repo, &mut manifest, !dry_run).await?;
+    // Take the snapshot as late as possible, so it is as fresh as possible
+    let snapshot = snapshot
+        .map(|settings| Snapshot::create(&settings, &target))
+        .transpose()
+        .context("Unable to take a snapshot of the filesystem")?;
+    let root = match &snapshot {
+        Some(snapshot) => {
+            if !options.quiet {
+                println!("Storing from snapshot at {}", snapshot.path().display());
+            }
+            snapshot.path().to_path_buf()
+        }
+        None => target,
+    };
+    let (status, _status_socket) = Status::serve(options.status_socket.as_deref(), "store", &name)?;
     // Load the target
-    let backup_target = FileSystemTarget::new(target.to_str().unwrap());
+    let mut backup_target = FileSystemTarget::new(root.to_str().unwrap());
+    backup_target.set_excludes(&excludes.patterns)?;
+    backup_target.set_exclude_caches(excludes.caches);
+    backup_target.set_exclude_if_present(&excludes.if_present);
+    backup_target.set_one_file_system(one_file_system);
+    backup_target.set_dereference(dereference);
+    backup_target.set_read_special(read_special);
+    backup_target.set_alternate_streams(alternate_streams);
+    if options.low_memory {
+        backup_target.set_walk_threads(1);
+    }
+    // Set up the scanner, if requested
+    let hook = scan_command.map(|command| Arc::new(CommandScanHook::new(&command)));
+    let mut progress = Progress::default();
     // Run the backup
     let paths = backup_target.backup_paths().await;
+    let (mut files, mut bytes) = (0, 0);
+    for node in paths.iter().filter(|x| x.is_file()) {
+        files += 1;
+        bytes += node.total_size;
+    }
+    status.set_totals(files, bytes);
     // Here, we maintain a vector of JoinHandles for the tasks we are spawning.
     // Whenever the vector is larger in size than max_queue_len, we use select
     // all to drain the first future from the queue to complete before
@@ -50,50 +509,142 @@ pub async fn store(options: Opt, target: PathBuf, name: Option<String>) -> Resul
     // TODO: Either adapt max_queue_len based on the number and size of files,
     // or allow the user to set it. Higher numbers do better with lots of small
     // files, and smaller numbers do better with a small number of large files.
-    let max_queue_len = 30;
+    let max_queue_len = options.max_queue_len();
     let mut task_queue = Vec::new();
+    let mut last_checkpoint = Instant::now();
+    let mut queued_bytes = 0;
+    let mut aborted = false;
     for node in paths {
+        // Wait out pauses, and stop starting new files once aborted, letting those in
+        // flight finish
+        if !status.proceed().await {
+            aborted = true;
+            break;
+        }
+        if node.is_file() {
+            status.start_file(&node.path);
+        }
+        queued_bytes += node.total_size;
         // Create clones of the values our task will need
         //
         // Spawining these tasks should really be backup_target's job, but
         // another alternative would be to elect to leak a refrence to these
         // values
-        let mut repo = repo.clone();
-        let archive = archive.clone();
-        let backup_target = backup_target.clone();
+        let mut task_repo = repo.clone();
+        let task_archive = archive.clone();
+        let task_target = backup_target.clone();
+        let hook = hook.clone();
         // Spawn a task and ask the target to store an object
         task_queue.push(Task::spawn(async move {
-            (
-                node.clone(),
-                backup_target
-                    .store_object(&mut repo, chunker.clone(), &archive, node)
-                    .await,
-            )
+            let result = if let Some(hook) = hook {
+                task_target
+                    .store_object_scanned(
+                        &mut task_repo,
+                        chunker,
+                        &task_archive,
+                        node.clone(),
+                        hook.as_ref(),
+                    )
+                    .await
+            } else {
+                task_target
+                    .store_object(&mut task_repo, chunker, &task_archive, node.clone())
+                    .await
+                    .map(|stored| (ScanVerdict::Accept, stored))
+            };
+            (node, result)
         }));
         // Perform queue draining if we are over full.
         if task_queue.len() > max_queue_len {
             let (result, _, new_queue) = select_all(task_queue).await;
             let (node, x) = result;
-            x?;
+            report(&options, &node, x?, &mut progress, &status, dry_run);
+            task_queue = new_queue;
+        }
+        // Commit a checkpoint if one is due, once everything in flight has been stored,
+        // so it only lists complete files
+        if checkpoints.due(last_checkpoint, queued_bytes) {
+            for future in task_queue.drain(..) {
+                let (node, x) = future.await;
+                report(&options, &node, x?, &mut progress, &status, dry_run);
+            }
+            commit_checkpoint(
+                &mut repo,
+                &mut manifest,
+                &archive,
+                &backup_target,
+                &progress,
+                &source,
+            )
+            .await?;
             if !options.quiet {
-                println!("Stored File: {}", node.path);
+                println!("Committed checkpoint");
             }
-            task_queue = new_queue;
+            last_checkpoint = Instant::now();
+            queued_bytes = 0;
         }
     }
     // Drain any remaining futures in the queue
     for future in task_queue {
         let (node, x) = future.await;
-        x?;
-        if !options.quiet {
-            println!("Stored File: {}", node.path);
+        report(&options, &node, x?, &mut progress, &status, dry_run);
+    }
+    if aborted {
+        // Record what was stored, so that running the same backup again picks up from here
+        if !dry_run && progress.files > 0 {
+            let checkpointed = commit_checkpoint(
+                &mut repo,
+                &mut manifest,
+                &archive,
+                &backup_target,
+                &progress,
+                &source,
+            )
+            .await;
+            match checkpointed {
+                Ok(()) => {
+                    if !options.quiet {
+                        println!("Committed checkpoint, storing again will resume from it");
+                    }
+                }
+                Err(error) => eprintln!("Unable to commit a checkpoint: {:#}", error),
+            }
         }
+        finish_stream(&repo, stream).await?;
+        repo.close().await;
+        return Err(status.aborted().into());
     }
-    // Add the backup listing to the archive
-    let listing = backup_target.backup_listing().await;
-    archive.set_listing(listing).await;
-    // Commit the backup
-    manifest.commit_archive(&mut repo, archive).await?;
+    if !dry_run {
+        // Add the backup listing to the archive, without any vetoed or failed files
+        update_listing(&archive, &backup_target, &progress).await;
+        // Commit the backup
+        manifest.commit_archive(&mut repo, archive).await?;
+        log::record(&mut repo, Operation::Store, Some(name.clone())).await?;
+        // The checkpoints are no longer needed now that the backup is complete, failing to
+        // remove them is harmless, as pruning will clean them up later
+        if let Err(error) = manifest.remove_checkpoints(&mut repo, &name).await {
+            if !options.quiet {
+                println!("Unable to remove checkpoints: {}", error);
+            }
+        }
+    }
+    finish_stream(&repo, stream).await?;
     repo.close().await;
-    Ok(())
+    let mut skipped = backup_target.skipped_paths().await;
+    skipped.extend(progress.vetoed.iter().cloned());
+    skipped.extend(progress.totals.failed.iter().cloned());
+    report_skipped(&skipped, list_skipped, options.quiet);
+    if dry_run {
+        report_dry_run(&progress);
+    } else if !options.quiet {
+        report_totals(&progress.totals);
+    }
+    if progress.totals.failed.is_empty() {
+        Ok(())
+    } else {
+        Err(CompletedWithWarnings {
+            failed: progress.totals.failed.len(),
+        }
+        .into())
+    }
 }
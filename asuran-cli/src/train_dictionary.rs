@@ -0,0 +1,54 @@
+use crate::cli::Opt;
+
+use asuran::manifest::*;
+use asuran::repository::*;
+
+use anyhow::{Context, Result};
+
+/// Trains a zstd dictionary on a sample of the repository's chunks, stores it, and makes
+/// compression with it the repository's default.
+pub async fn train_dictionary(options: Opt, samples: usize, max_size: usize) -> Result<()> {
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    let result = train(&options, &mut repo, samples, max_size).await;
+    repo.close().await;
+    let (dictionary, level) = result?;
+    if !options.quiet {
+        println!(
+            "Trained dictionary {} ({} bytes), new chunks will be compressed with it at level {}",
+            dictionary.id(),
+            dictionary.as_bytes().len(),
+            level
+        );
+    }
+    Ok(())
+}
+
+async fn train(
+    options: &Opt,
+    repo: &mut Repository<impl BackendClone>,
+    samples: usize,
+    max_size: usize,
+) -> Result<(ZStdDictionary, i32)> {
+    let dictionary = repo
+        .train_dictionary(samples, max_size)
+        .await
+        .with_context(|| "Unable to train a dictionary, the repository may be too small")?;
+    repo.add_dictionary(&dictionary).await?;
+    repo.commit_index().await;
+    let level = options
+        .repo_opts()
+        .compression_level
+        .map_or(3, |level| level as i32);
+    let mut manifest = Manifest::load(repo);
+    let mut settings = manifest.chunk_settings().await;
+    settings.compression = Compression::ZStdDict {
+        level,
+        dict_id: dictionary.id(),
+    };
+    manifest
+        .set_chunk_settings(settings)
+        .await
+        .with_context(|| "Unable to record the dictionary in the repository's settings")?;
+    Ok((dictionary, level))
+}
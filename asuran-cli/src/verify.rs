@@ -0,0 +1,73 @@
+use crate::cli::Opt;
+
+use asuran::manifest::verify::verify_archive;
+use asuran::manifest::*;
+
+use anyhow::{anyhow, Result};
+
+/// Restores archives without writing anything, printing each object that could not
+/// be restored.
+///
+/// Verifies only the archive `archive_name` refers to, if one is provided, otherwise
+/// every archive in the repository.
+pub async fn verify(options: Opt, archive_name: Option<String>) -> Result<()> {
+    // First, open a connection to the repository
+    let mut repo = options.open_repo().await?;
+    repo.read_ahead = options.read_ahead();
+    repo.reorder_window = options.reorder_window();
+    if let Some(bytes) = options.read_cache {
+        repo.set_read_cache(bytes);
+    }
+    // Load the manifest, and the archives we were asked to verify
+    let mut manifest = Manifest::load(&repo);
+    let archives = if let Some(archive_name) = &archive_name {
+        let stored_archive = match manifest.resolve_archive(&mut repo, archive_name).await {
+            Ok(stored_archive) => stored_archive,
+            Err(error) => {
+                repo.close().await;
+                return Err(error.into());
+            }
+        };
+        vec![stored_archive.load(&mut repo).await?]
+    } else {
+        manifest.load_archives(&mut repo).await?
+    };
+
+    let mut objects = 0;
+    let mut bytes = 0;
+    let mut unreadable = 0;
+    for archive in &archives {
+        if !options.quiet {
+            println!("Verifying archive {}", archive.name());
+        }
+        let report = verify_archive(&mut repo, archive).await;
+        for object in &report.unreadable {
+            if object.namespace.is_empty() {
+                println!("Unreadable: {}: {}", object.path, object.reason);
+            } else {
+                println!(
+                    "Unreadable: {} ({}): {}",
+                    object.path, object.namespace, object.reason
+                );
+            }
+        }
+        objects += report.objects_verified;
+        bytes += report.bytes_verified;
+        unreadable += report.unreadable.len();
+    }
+    repo.close().await;
+
+    if !options.quiet {
+        println!(
+            "Verified {} objects ({} bytes) in {} archives",
+            objects,
+            bytes,
+            archives.len()
+        );
+    }
+    if unreadable == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!("Found {} unreadable objects", unreadable))
+    }
+}
@@ -18,3 +18,4 @@ the iconically format of that objects on-disk representation.
 
 pub mod manifest;
 pub mod repository;
+pub mod time;
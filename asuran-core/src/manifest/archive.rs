@@ -1,11 +1,11 @@
 use crate::manifest::listing::Listing;
-use crate::repository::ChunkID;
+use crate::repository::{ChunkID, ChunkSettings};
+use crate::time::Timestamp;
 
-use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 /// A pointer to a `Chunk`, annotated with information on what part of the object it
 /// makes up
@@ -28,6 +28,35 @@ impl Ord for ChunkLocation {
     }
 }
 
+/// User supplied tags and free-form key/value metadata describing an archive, such as
+/// the host it was taken on, or the backup job that made it
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ArchiveMetadata {
+    pub tags: BTreeSet<String>,
+    pub values: BTreeMap<String, String>,
+}
+
+impl ArchiveMetadata {
+    /// Tag reserved for checkpoint archives, partial archives committed periodically
+    /// during a long backup so that an interrupted backup can be resumed
+    pub const CHECKPOINT_TAG: &'static str = "asuran:checkpoint";
+
+    /// Returns true if there are no tags or values
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.values.is_empty()
+    }
+
+    /// Returns true if every one of the given tags is present
+    pub fn has_tags<'a>(&self, tags: impl IntoIterator<Item = &'a str>) -> bool {
+        tags.into_iter().all(|tag| self.tags.contains(tag))
+    }
+
+    /// Returns true if the archive is a checkpoint of an unfinished backup
+    pub fn is_checkpoint(&self) -> bool {
+        self.tags.contains(Self::CHECKPOINT_TAG)
+    }
+}
+
 /// An Archive, as stored in the repository
 #[derive(Serialize, Deserialize)]
 pub struct Archive {
@@ -38,10 +67,29 @@ pub struct Archive {
     /// The namespace this archive is currently viewing
     pub namespace: Vec<String>,
     /// The timestamp of the archive's creation
-    pub timestamp: DateTime<FixedOffset>,
+    pub timestamp: Timestamp,
     /// The listing of objects in the repository, maintaining their relative structure,
     /// such as the layout of directories and folders.
+    ///
+    /// Empty if the listing is stored as a tree of chunks, see `listing_root`.
     pub listing: Listing,
+    /// The chunk settings this archive's objects were stored with, if they were
+    /// overridden from the repository's defaults
+    #[serde(default)]
+    pub chunk_settings: Option<ChunkSettings>,
+    /// The tags and metadata the archive was stored with
+    #[serde(default)]
+    pub metadata: ArchiveMetadata,
+    /// The first chunk of the root directory of the listing, if it is stored as a tree of
+    /// chunks rather than in `listing`
+    #[serde(default)]
+    pub listing_root: Option<ChunkID>,
+    /// Set if every chunk location starts exactly where the chunk's data starts
+    ///
+    /// Archives stored without this placed each chunk after the first in an extent one
+    /// byte further along than the chunk before it ended.
+    #[serde(default)]
+    pub exact_locations: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
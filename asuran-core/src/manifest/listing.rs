@@ -6,7 +6,7 @@ use crate::manifest::archive::Extent;
 
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 /// The type of node in the listing
 ///
@@ -22,6 +22,20 @@ pub enum NodeType {
     ///
     /// Contains the paths of any child members a node may have
     Directory { children: Vec<String> },
+    /// A node that only has associated metadata, describing a special file, such as a named
+    /// pipe or a device node, that is recreated rather than read
+    Special { kind: SpecialFile },
+}
+
+/// The kind of a special file
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SpecialFile {
+    /// A named pipe
+    Fifo,
+    /// A character device, with its major and minor device numbers
+    CharDevice { major: u32, minor: u32 },
+    /// A block device, with its major and minor device numbers
+    BlockDevice { major: u32, minor: u32 },
 }
 
 /// A node is a description of an object in the listing
@@ -41,6 +55,92 @@ pub struct Node {
     pub extents: Option<Vec<Extent>>,
     /// the type of the node
     pub node_type: NodeType,
+    /// Platform specific metadata of the object
+    #[serde(default)]
+    pub metadata: NodeMetadata,
+    /// Hash over the full contents of the object, as they were read when it was stored
+    ///
+    /// This will be None for objects that are not files, and for objects that were stored
+    /// without one, such as by older versions of asuran.
+    #[serde(default)]
+    pub hash: Option<ObjectHash>,
+}
+
+/// The algorithm the contents of objects are hashed with
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum ObjectHashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
+/// A hash over the full contents of an object
+///
+/// For sparse objects, this covers the contents of each extent, in order of their
+/// starting offsets, without the holes between them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ObjectHash {
+    /// The algorithm that produced the digest
+    pub algorithm: ObjectHashAlgorithm,
+    /// The digest itself
+    #[serde(with = "serde_bytes")]
+    pub digest: Vec<u8>,
+}
+
+/// Metadata of an object beyond its contents and place in the listing
+///
+/// Metadata is captured by the platform the object was stored on, and is ignored when
+/// restoring on a platform that does not support it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub struct NodeMetadata {
+    /// Metadata from a Windows filesystem
+    #[serde(default)]
+    pub windows: Option<WindowsMetadata>,
+    /// The path a symbolic link points to, exactly as it was read from the link
+    #[serde(default)]
+    pub link_target: Option<String>,
+}
+
+/// Metadata of a file or directory on a Windows filesystem
+///
+/// Times are `FILETIME`s, the number of 100 nanosecond intervals since January 1, 1601
+/// (UTC).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub struct WindowsMetadata {
+    /// The `FILE_ATTRIBUTE_*` flags of the object
+    pub attributes: u32,
+    pub creation_time: u64,
+    pub last_access_time: u64,
+    pub last_write_time: u64,
+    /// The link this object is, if it is a symbolic link or junction
+    pub reparse_point: Option<ReparsePoint>,
+    /// The alternate data streams of a file, if they were stored
+    pub streams: Vec<AlternateStream>,
+}
+
+/// A Windows reparse point that links to another path
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ReparsePoint {
+    /// A symbolic link, `directory` being set for links to directories
+    Symlink { target: String, directory: bool },
+    /// A directory junction, which always points to an absolute path
+    Junction { target: String },
+}
+
+/// A named alternate data stream of a file on a Windows filesystem
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AlternateStream {
+    /// The name of the stream, without the leading colon or the stream type
+    pub name: String,
+    /// The length of the stream's contents
+    pub length: u64,
+}
+
+impl AlternateStream {
+    /// Returns the namespace the contents of the stream are stored in
+    pub fn namespace(&self) -> String {
+        format!("windows:streams:{}", self.name)
+    }
 }
 
 impl Node {
@@ -60,6 +160,11 @@ impl Node {
         }
     }
 
+    /// Returns true if the Node is a special file
+    pub fn is_special(&self) -> bool {
+        matches!(self.node_type, NodeType::Special { .. })
+    }
+
     /// Returns a copy of self with any children (in a `NodeType::Directory`) removed
     pub fn drain_children(&self) -> Node {
         let node_type = match &self.node_type {
@@ -114,6 +219,78 @@ impl Listing {
         }
     }
 
+    /// Removes the node with the specified path, along with any of its children
+    ///
+    /// Returns the removed node, or None if no node with that path exists
+    pub fn remove(&mut self, path: &str) -> Option<Node> {
+        let node = self.nodes.remove(path)?;
+        // Remove any references to the node from the root or its parent
+        self.root.retain(|x| x != path);
+        for parent in self.nodes.values_mut() {
+            if let NodeType::Directory { children } = &mut parent.node_type {
+                children.retain(|x| x != path);
+            }
+        }
+        // Remove its children
+        if let NodeType::Directory { children } = &node.node_type {
+            for child in children {
+                self.remove(child);
+            }
+        }
+        Some(node)
+    }
+
+    /// Returns the node with the specified path, if there is one
+    pub fn get(&self, path: &str) -> Option<&Node> {
+        self.nodes.get(path)
+    }
+
+    /// Returns a mutable reference to the node with the specified path, if there is one
+    ///
+    /// The path and type of the node must not be changed through this reference.
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut Node> {
+        self.nodes.get_mut(path)
+    }
+
+    /// Returns the direct children of the directory with the specified path, or the nodes
+    /// in the root of the listing if the path is empty
+    ///
+    /// Returns an empty `Vec` if no directory with that path exists. Children added more
+    /// than once are only returned the first time.
+    pub fn children(&self, path: &str) -> Vec<&Node> {
+        let paths = if path.is_empty() {
+            &self.root
+        } else {
+            match self.nodes.get(path).map(|x| &x.node_type) {
+                Some(NodeType::Directory { children }) => children,
+                _ => return Vec::new(),
+            }
+        };
+        let mut seen = HashSet::new();
+        paths
+            .iter()
+            .filter(|x| seen.insert(x.as_str()))
+            .filter_map(|x| self.nodes.get(x))
+            .collect()
+    }
+
+    /// Returns the node with the specified path along with all of its descendants
+    ///
+    /// Only the subtree rooted at that node is visited. Nodes are returned in
+    /// breadth-first order, so directories always come before their contents. Returns
+    /// an empty `Vec` if no node with that path exists.
+    pub fn subtree(&self, path: &str) -> Vec<&Node> {
+        let mut output = Vec::new();
+        let mut queue: VecDeque<&Node> = self.nodes.get(path).into_iter().collect();
+        while let Some(node) = queue.pop_front() {
+            if let NodeType::Directory { children } = &node.node_type {
+                queue.extend(children.iter().filter_map(|x| self.nodes.get(x)));
+            }
+            output.push(node);
+        }
+        output
+    }
+
     /// Creates a by-reference iterator over the Nodes in this listing
     // This is excluded from tarpaulin, since its just a pass through to into_iter
     #[cfg_attr(tarpaulin, skip)]
@@ -244,6 +421,8 @@ mod tests {
             total_length: 1234,
             total_size: 1234,
             extents: None,
+            metadata: NodeMetadata::default(),
+            hash: None,
             node_type: NodeType::Directory {
                 children: ["test1", "test2", "test3"]
                     .iter()
@@ -264,6 +443,8 @@ mod tests {
             total_length: 1234,
             total_size: 1234,
             extents: None,
+            metadata: NodeMetadata::default(),
+            hash: None,
             node_type: NodeType::Directory {
                 children: ["test1", "test2", "test3"]
                     .iter()
@@ -284,6 +465,148 @@ mod tests {
         assert_ne!(listing, Listing::default());
     }
 
+    // Tests that removing a node also removes its children and any references to it
+    #[test]
+    fn listing_remove() {
+        let file = |path: &str| Node {
+            path: path.to_owned(),
+            total_length: 1234,
+            total_size: 1234,
+            extents: None,
+            metadata: NodeMetadata::default(),
+            hash: None,
+            node_type: NodeType::File,
+        };
+        let directory = Node {
+            path: "dir".to_owned(),
+            total_length: 0,
+            total_size: 0,
+            extents: None,
+            metadata: NodeMetadata::default(),
+            hash: None,
+            node_type: NodeType::Directory {
+                children: Vec::new(),
+            },
+        };
+
+        let mut listing = Listing::default();
+        listing.add_child("", file("keep"));
+        listing.add_child("", directory);
+        listing.add_child("dir", file("dir/file1"));
+        listing.add_child("dir", file("dir/file2"));
+
+        // Removing a single file should only remove that file
+        assert_eq!(listing.remove("dir/file1"), Some(file("dir/file1")));
+        let paths: HashSet<String> = listing.iter().map(|x| x.path.clone()).collect();
+        let expected: HashSet<String> = ["keep", "dir", "dir/file2"]
+            .iter()
+            .map(|&x| x.to_owned())
+            .collect();
+        assert_eq!(paths, expected);
+
+        // Removing a directory should remove its children
+        assert!(listing.remove("dir").is_some());
+        let paths: Vec<String> = listing.iter().map(|x| x.path.clone()).collect();
+        assert_eq!(paths, vec!["keep".to_owned()]);
+
+        // Removing a missing node does nothing
+        assert_eq!(listing.remove("dir"), None);
+    }
+
+    // Tests that the children of a directory are returned once each, in the order added
+    #[test]
+    fn listing_children() {
+        let file = |path: &str| Node {
+            path: path.to_owned(),
+            total_length: 1234,
+            total_size: 1234,
+            extents: None,
+            metadata: NodeMetadata::default(),
+            hash: None,
+            node_type: NodeType::File,
+        };
+        let directory = Node {
+            path: "dir".to_owned(),
+            total_length: 0,
+            total_size: 0,
+            extents: None,
+            metadata: NodeMetadata::default(),
+            hash: None,
+            node_type: NodeType::Directory {
+                children: Vec::new(),
+            },
+        };
+
+        let mut listing = Listing::default();
+        listing.add_child("", directory);
+        listing.add_child("", file("keep"));
+        listing.add_child("dir", file("dir/file2"));
+        listing.add_child("dir", file("dir/file1"));
+        listing.add_child("dir", file("dir/file2"));
+
+        let paths = |path| {
+            listing
+                .children(path)
+                .into_iter()
+                .map(|x| x.path.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(""), ["dir", "keep"]);
+        assert_eq!(paths("dir"), ["dir/file2", "dir/file1"]);
+        assert!(paths("keep").is_empty());
+        assert!(paths("missing").is_empty());
+    }
+
+    // Tests that looking up a subtree only returns that node and its descendants,
+    // parents first
+    #[test]
+    fn listing_subtree() {
+        let file = |path: &str| Node {
+            path: path.to_owned(),
+            total_length: 1234,
+            total_size: 1234,
+            extents: None,
+            metadata: NodeMetadata::default(),
+            hash: None,
+            node_type: NodeType::File,
+        };
+        let directory = |path: &str| Node {
+            path: path.to_owned(),
+            total_length: 0,
+            total_size: 0,
+            extents: None,
+            metadata: NodeMetadata::default(),
+            hash: None,
+            node_type: NodeType::Directory {
+                children: Vec::new(),
+            },
+        };
+
+        let mut listing = Listing::default();
+        listing.add_child("", file("other"));
+        listing.add_child("", directory("dir"));
+        listing.add_child("dir", file("dir/file"));
+        listing.add_child("dir", directory("dir/sub"));
+        listing.add_child("dir/sub", file("dir/sub/file"));
+
+        assert_eq!(listing.get("dir/file"), Some(&file("dir/file")));
+        assert_eq!(listing.get("missing"), None);
+
+        let paths: Vec<&str> = listing
+            .subtree("dir")
+            .into_iter()
+            .map(|x| x.path.as_str())
+            .collect();
+        assert_eq!(paths, vec!["dir", "dir/file", "dir/sub", "dir/sub/file"]);
+        let paths: Vec<&str> = listing
+            .subtree("dir/file")
+            .into_iter()
+            .map(|x| x.path.as_str())
+            .collect();
+        assert_eq!(paths, vec!["dir/file"]);
+        assert!(listing.subtree("missing").is_empty());
+    }
+
     // Test the by reference iterator
     #[test]
     fn listing_to_iter_ref() {
@@ -295,6 +618,8 @@ mod tests {
                 total_length: 1234,
                 total_size: 1234,
                 extents: None,
+                metadata: NodeMetadata::default(),
+                hash: None,
                 node_type: NodeType::File,
             })
             .collect();
@@ -325,6 +650,8 @@ mod tests {
                 total_length: 1234,
                 total_size: 1234,
                 extents: None,
+                metadata: NodeMetadata::default(),
+                hash: None,
                 node_type: NodeType::File,
             })
             .collect();
@@ -343,4 +670,55 @@ mod tests {
 
         assert_eq!(test_nodes, post_nodes);
     }
+
+    // Nodes stored before metadata was recorded should load with empty metadata, and
+    // Windows metadata should survive a round trip on any platform
+    #[test]
+    fn node_metadata_serialization() {
+        #[derive(Serialize)]
+        struct LegacyNode {
+            path: String,
+            total_length: u64,
+            total_size: u64,
+            extents: Option<Vec<Extent>>,
+            node_type: NodeType,
+        }
+        let legacy = LegacyNode {
+            path: "file".to_string(),
+            total_length: 10,
+            total_size: 10,
+            extents: Some(vec![Extent { start: 0, end: 9 }]),
+            node_type: NodeType::File,
+        };
+        let bytes = rmp_serde::to_vec(&legacy).unwrap();
+        let node: Node = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(node.path, "file");
+        assert_eq!(node.metadata, NodeMetadata::default());
+
+        let windows = WindowsMetadata {
+            attributes: 0x22,
+            creation_time: 132_000_000_000_000_000,
+            last_access_time: 132_000_000_010_000_000,
+            last_write_time: 132_000_000_020_000_000,
+            reparse_point: Some(ReparsePoint::Junction {
+                target: r"C:\Users\Public".to_string(),
+            }),
+            streams: vec![AlternateStream {
+                name: "Zone.Identifier".to_string(),
+                length: 26,
+            }],
+        };
+        let node = Node {
+            metadata: NodeMetadata {
+                windows: Some(windows),
+                link_target: Some(r"C:\Users\Public".to_string()),
+            },
+            ..node
+        };
+        let bytes = rmp_serde::to_vec(&node).unwrap();
+        let restored: Node = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(restored, node);
+        let streams = &restored.metadata.windows.unwrap().streams;
+        assert_eq!(streams[0].namespace(), "windows:streams:Zone.Identifier");
+    }
 }
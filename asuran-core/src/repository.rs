@@ -8,9 +8,11 @@ pub mod compression;
 pub mod encryption;
 pub mod hmac;
 pub mod key;
+pub mod padding;
 
 pub use self::hmac::*;
 pub use chunk::*;
 pub use compression::*;
 pub use encryption::*;
 pub use key::*;
+pub use padding::*;
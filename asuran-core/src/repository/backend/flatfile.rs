@@ -3,9 +3,9 @@ This module contains data structures describing components of the `FlatFile`
 on-disk representation.
 */
 use crate::repository::{Chunk, ChunkHeader, ChunkID, ChunkSettings, EncryptedKey, Key};
+use crate::time::Timestamp;
 
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
-use chrono::{DateTime, FixedOffset};
 use rmp_serde as rmps;
 use semver::Version;
 use serde::{Deserialize, Serialize};
@@ -17,6 +17,10 @@ use std::convert::TryInto;
 use std::io::{Read, Write};
 
 pub const MAGIC_NUMBER: [u8; 8] = *b"ASURAN_F";
+/// The magic number ending a `FlatFile` that was written sequentially, see `StreamTail`
+pub const STREAM_MAGIC_NUMBER: [u8; 8] = *b"ASURAN_S";
+/// The magic number starting each volume of a `FlatFile` split into volumes, see `VolumeHeader`
+pub const VOLUME_MAGIC_NUMBER: [u8; 8] = *b"ASURAN_V";
 
 /// An error for things that go wrong with interacting with flatfile transactions and headers
 #[derive(Error, Debug)]
@@ -31,6 +35,8 @@ pub enum FlatFileError {
     KeyTooLong,
     #[error("Magic number was not correct for Asuran FlatFile format")]
     InvalidMagicNumber,
+    #[error("Streamed FlatFile did not end with a stream tail, it may have been cut short")]
+    MissingStreamTail,
     #[error("Semver component {0} too high: {1}")]
     SemverToHigh(u64, Version),
     #[error("Chunk decryption failed: {0}")]
@@ -167,6 +173,10 @@ impl FlatFileHeader {
 ///
 /// This will typically be initially written to the file with the `footer_offset`
 /// and `next_header_offset` as 0, and then be updated when writing is closed.
+///
+/// An entry written to a stream, which can not be seeked back to, instead has both
+/// offsets set to `EntryHeader::STREAMED`, and its real offsets are recorded in the
+/// `StreamTail` at the very end of the file.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct EntryHeader {
     pub semver_major: u16,
@@ -178,6 +188,9 @@ pub struct EntryHeader {
 }
 
 impl EntryHeader {
+    /// The value of both offsets in the header of an entry written to a stream
+    pub const STREAMED: u64 = u64::MAX;
+
     /// Creates a new `EntryHeader` with the given information.
     ///
     /// # Errors
@@ -226,6 +239,12 @@ impl EntryHeader {
         Uuid::from_bytes(self.uuid_bytes)
     }
 
+    /// Returns true if this is the header of an entry written to a stream, whose offsets
+    /// are in the `StreamTail` of the file
+    pub fn is_streamed(&self) -> bool {
+        self.footer_offset == Self::STREAMED && self.next_header_offset == Self::STREAMED
+    }
+
     /// Reads an `EntryHeader` from the provided `Read`
     ///
     /// The provided `Read` must be seeked to the start of the `EntryHeader`.
@@ -268,6 +287,139 @@ impl EntryHeader {
     }
 }
 
+/// The trailer of a `FlatFile` that was written sequentially, to a stream that could not
+/// be seeked back to
+///
+/// A streamed `FlatFile` holds a single entry, whose header has both of its offsets set
+/// to `EntryHeader::STREAMED`. The entry is followed by the usual blank `EntryHeader`,
+/// and then by this tail, made of two `u64`s and a magic number:
+///
+/// 1. The offset in the file of the footer of the entry
+/// 2. The offset in the file of the blank header following the entry
+/// 3. The magic number `b"ASURAN_S"`
+///
+/// Writers opening a streamed `FlatFile` patch the header of the entry with these
+/// offsets before appending to it, after which the tail is no longer needed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StreamTail {
+    pub footer_offset: u64,
+    pub next_header_offset: u64,
+}
+
+impl StreamTail {
+    /// The length of a `StreamTail`, in bytes
+    pub const LENGTH: u64 = 24;
+
+    /// Reads a `StreamTail` from the provided `Read`
+    ///
+    /// The provided `Read` must be seeked to `StreamTail::LENGTH` bytes before the end
+    /// of the file.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(MissingStreamTail)` if the magic number of the tail is not
+    /// correct, or `Err` if there is an underlying I/O error.
+    pub fn from_read(mut read: impl Read) -> Result<StreamTail> {
+        let footer_offset = read.read_u64::<NetworkEndian>()?;
+        let next_header_offset = read.read_u64::<NetworkEndian>()?;
+        let mut magic_number = [0_u8; 8];
+        read.read_exact(&mut magic_number)?;
+        if magic_number != STREAM_MAGIC_NUMBER {
+            return Err(FlatFileError::MissingStreamTail);
+        }
+        Ok(StreamTail {
+            footer_offset,
+            next_header_offset,
+        })
+    }
+
+    /// Writes this `StreamTail` to the provided `Write`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there is an underlying I/O error
+    pub fn to_write(&self, mut write: impl Write) -> Result<()> {
+        write.write_u64::<NetworkEndian>(self.footer_offset)?;
+        write.write_u64::<NetworkEndian>(self.next_header_offset)?;
+        write.write_all(&STREAM_MAGIC_NUMBER)?;
+        Ok(())
+    }
+}
+
+/// The header starting each volume of a `FlatFile` that has been split across several
+/// files of a fixed size
+///
+/// The volumes of a set each hold the next part of a single `FlatFile`, following this
+/// header. The header contains, in order:
+///
+/// 1. The magic number `b"ASURAN_V"`
+/// 2. The 16-byte UUID of the set, shared by all of its volumes
+/// 3. The index of the volume within the set, starting at 0, as a `u64`
+/// 4. The size of every volume in the set, including this header, as a `u64`
+///
+/// Every volume but the last is filled up to the size of the set.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VolumeHeader {
+    pub set_uuid_bytes: [u8; 16],
+    pub index: u64,
+    pub volume_size: u64,
+}
+
+impl VolumeHeader {
+    /// The length of a `VolumeHeader`, in bytes
+    pub const LENGTH: u64 = 40;
+
+    /// Creates the header of the volume with the given index, in the given set
+    pub fn new(set_uuid: Uuid, index: u64, volume_size: u64) -> VolumeHeader {
+        VolumeHeader {
+            set_uuid_bytes: *set_uuid.as_bytes(),
+            index,
+            volume_size,
+        }
+    }
+
+    /// Returns the UUID of the set this volume belongs to
+    pub fn set_uuid(&self) -> Uuid {
+        Uuid::from_bytes(self.set_uuid_bytes)
+    }
+
+    /// Reads a `VolumeHeader` from the provided `Read`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(InvalidMagicNumber)` if the magic number is not correct, or
+    /// `Err` if there is an underlying I/O error.
+    pub fn from_read(mut read: impl Read) -> Result<VolumeHeader> {
+        let mut magic_number = [0_u8; 8];
+        read.read_exact(&mut magic_number)?;
+        if magic_number != VOLUME_MAGIC_NUMBER {
+            return Err(FlatFileError::InvalidMagicNumber);
+        }
+        let mut set_uuid_bytes = [0_u8; 16];
+        read.read_exact(&mut set_uuid_bytes)?;
+        let index = read.read_u64::<NetworkEndian>()?;
+        let volume_size = read.read_u64::<NetworkEndian>()?;
+        Ok(VolumeHeader {
+            set_uuid_bytes,
+            index,
+            volume_size,
+        })
+    }
+
+    /// Writes this `VolumeHeader` to the provided `Write`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there is an underlying I/O error
+    pub fn to_write(&self, mut write: impl Write) -> Result<()> {
+        write.write_all(&VOLUME_MAGIC_NUMBER)?;
+        write.write_all(&self.set_uuid_bytes)?;
+        write.write_u64::<NetworkEndian>(self.index)?;
+        write.write_u64::<NetworkEndian>(self.volume_size)?;
+        Ok(())
+    }
+}
+
 /// A struct representation of the repository metadata associated with this entry.
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EntryFooterData {
@@ -278,9 +430,14 @@ pub struct EntryFooterData {
     /// The headers of the `Chunk`s added by this entry.
     pub chunk_headers: HashMap<ChunkID, ChunkHeader>,
     /// The `ChunkID`s of the archive's added by this entry.
-    pub archives: Vec<(ChunkID, DateTime<FixedOffset>)>,
+    pub archives: Vec<(ChunkID, Timestamp)>,
     /// The current default `ChunkSettings` of this repository
     pub chunk_settings: ChunkSettings,
+    /// Whether or not this repository is in append only mode
+    ///
+    /// Once any entry has set this, the repository remains append only.
+    #[serde(default)]
+    pub append_only: bool,
 }
 
 impl EntryFooterData {
@@ -291,6 +448,7 @@ impl EntryFooterData {
             archives: Vec::new(),
             chunk_settings,
             chunk_headers: HashMap::new(),
+            append_only: false,
         }
     }
     /// Adds a chunk to the `chunk_locations` list
@@ -302,7 +460,7 @@ impl EntryFooterData {
         self.chunk_headers.insert(id, header);
     }
     /// Adds an archive to the `archives` list
-    pub fn add_archive(&mut self, id: ChunkID, timestamp: DateTime<FixedOffset>) {
+    pub fn add_archive(&mut self, id: ChunkID, timestamp: Timestamp) {
         self.archives.push((id, timestamp))
     }
     /// Returns true if any of the internal structures have data in them
@@ -7,12 +7,14 @@ contents (different keys are used for both HMACs).
 
 They can contain any arbitrary sequence of bytes.
 */
-use super::{Compression, Encryption, Key, HMAC};
+use super::{Compression, Encryption, Key, Padding, ZStdDictionary, HMAC};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use std::cmp;
+use std::fmt::{self, Write as _};
+use std::io::Write;
 
 /// Error for all the various things that can go wrong with handling chunks
 #[derive(Error, Debug)]
@@ -25,6 +27,17 @@ pub enum ChunkError {
     KeyError(#[from] super::KeyError),
     #[error("HMAC Vailidation Failed")]
     HMACValidationFailed,
+    #[error("Chunk padding is malformed")]
+    InvalidPadding,
+    #[error("HMAC algorithm {0} is not supported by this build")]
+    UnsupportedHMAC(super::HMAC),
+    #[error("Chunk IDs must be between 16 and 32 bytes long, {0} were requested")]
+    InvalidIDLength(usize),
+    #[error("Repository was chunked with {recorded}, refusing to write with {requested}")]
+    ChunkerMismatch {
+        recorded: ChunkerSettings,
+        requested: ChunkerSettings,
+    },
 }
 
 type Result<T> = std::result::Result<T, ChunkError>;
@@ -77,11 +90,61 @@ impl ChunkID {
         }
     }
 
+    /// Returns the key as a lowercase hexadecimal string
+    pub fn to_hex(&self) -> String {
+        let mut hex = String::with_capacity(64);
+        for byte in &self.id {
+            write!(hex, "{byte:02x}").expect("Writing to a String can not fail");
+        }
+        hex
+    }
+
     /// Returns the special all-zero key used for the manifest
     pub fn manifest_id() -> ChunkID {
         ChunkID { id: [0_u8; 32] }
     }
 
+    /// Returns the special key used to store the zstd dictionary with the given id
+    ///
+    /// Dictionaries are stored under a fixed key, rather than one derived from their
+    /// contents, so they can be found from the dictionary id recorded in a `Chunk`'s
+    /// compression.
+    pub fn dictionary_id(dict_id: u32) -> ChunkID {
+        let mut id = [0_u8; 32];
+        id[..16].copy_from_slice(b"zstd-dictionary\0");
+        id[16..20].copy_from_slice(&dict_id.to_le_bytes());
+        ChunkID { id }
+    }
+
+    /// Returns true if this is the special key of a zstd dictionary
+    pub fn is_dictionary(&self) -> bool {
+        self.id[..16] == *b"zstd-dictionary\0" && self.id[20..].iter().all(|x| *x == 0)
+    }
+
+    /// Returns the special key used to store the audit log entry with the given sequence
+    /// number
+    ///
+    /// As chunks are never written over, each entry can only be written once, keeping the
+    /// log append only.
+    pub fn audit_log_id(sequence: u64) -> ChunkID {
+        let mut id = [0_u8; 32];
+        id[..16].copy_from_slice(b"audit-log-entry\0");
+        id[16..24].copy_from_slice(&sequence.to_le_bytes());
+        ChunkID { id }
+    }
+
+    /// Returns the sequence number of the audit log entry this is the special key of, if
+    /// it is one
+    pub fn audit_log_sequence(&self) -> Option<u64> {
+        if self.id[..16] == *b"audit-log-entry\0" && self.id[24..].iter().all(|x| *x == 0) {
+            let mut sequence = [0_u8; 8];
+            sequence.copy_from_slice(&self.id[16..24]);
+            Some(u64::from_le_bytes(sequence))
+        } else {
+            None
+        }
+    }
+
     /// Returns a random id, used for testing
     pub fn random_id() -> ChunkID {
         let id = rand::random();
@@ -89,12 +152,184 @@ impl ChunkID {
     }
 }
 
+/// The algorithm used to derive a `ChunkID` from the plaintext of a chunk
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq, Hash)]
+pub enum ChunkIDAlgorithm {
+    /// An HMAC using the same algorithm as the chunk's integrity HMAC
+    HMAC,
+    /// BLAKE3 in its native keyed mode, regardless of the chunk's HMAC algorithm
+    ///
+    /// This is considerably faster than the HMAC constructions over the SHA2 and SHA3
+    /// families.
+    Blake3,
+}
+
+/// Controls how `ChunkID`s are derived
+///
+/// Chunks are only deduplicated against chunks with IDs derived the same way, so
+/// these should not be changed on an existing repository.
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkIDSettings {
+    pub algorithm: ChunkIDAlgorithm,
+    /// The number of bytes of the hash output kept in the ID, the rest of the ID is
+    /// filled with zeros
+    pub length: usize,
+}
+
+impl ChunkIDSettings {
+    /// The shortest ID length that can be selected
+    pub const MIN_LENGTH: usize = 16;
+    /// The longest ID length that can be selected, and the length of a `ChunkID`
+    pub const MAX_LENGTH: usize = 32;
+
+    /// Creates a new `ChunkIDSettings`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(InvalidIDLength)` if `length` is shorter than `MIN_LENGTH` or
+    /// longer than `MAX_LENGTH`
+    pub fn new(algorithm: ChunkIDAlgorithm, length: usize) -> Result<ChunkIDSettings> {
+        if (Self::MIN_LENGTH..=Self::MAX_LENGTH).contains(&length) {
+            Ok(ChunkIDSettings { algorithm, length })
+        } else {
+            Err(ChunkError::InvalidIDLength(length))
+        }
+    }
+
+    /// Derives the `ChunkID` of some plaintext, using the section of the key material
+    /// reserved for `ChunkID` generation
+    ///
+    /// `hmac` is the integrity HMAC algorithm of the chunk, which is only used with
+    /// `ChunkIDAlgorithm::HMAC`.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if support for the selected algorithm has not been compiled in. `Repository`
+    /// refuses to be constructed with such an algorithm, see `ChunkSettings::check_supported`.
+    pub fn derive(&self, data: &[u8], hmac: HMAC, key: &Key) -> ChunkID {
+        let mut id = match self.algorithm {
+            ChunkIDAlgorithm::HMAC => hmac.id(data, key),
+            // The BLAKE3 "HMAC" is already its native keyed mode
+            ChunkIDAlgorithm::Blake3 => HMAC::Blake3.id(data, key),
+        };
+        id.truncate(self.length);
+        ChunkID::new(&id)
+    }
+}
+
+impl Default for ChunkIDSettings {
+    /// IDs are a full length HMAC, which is how IDs were derived before they were
+    /// configurable
+    fn default() -> ChunkIDSettings {
+        ChunkIDSettings {
+            algorithm: ChunkIDAlgorithm::HMAC,
+            length: Self::MAX_LENGTH,
+        }
+    }
+}
+
+/// Describes the chunker used to split objects into chunks, along with its parameters
+///
+/// Data split with different chunkers, or the same chunker with different parameters,
+/// will almost never produce matching chunks, so these should not be changed on an existing
+/// repository.
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq, Hash)]
+pub enum ChunkerSettings {
+    /// The original `FastCDC` algorithm
+    FastCDC {
+        min_size: u64,
+        avg_size: u64,
+        max_size: u64,
+    },
+    /// The 2020 revision of `FastCDC`, `normalization` being the normalized chunking level,
+    /// from 1 to 3
+    FastCDC2020 {
+        min_size: u64,
+        avg_size: u64,
+        max_size: u64,
+        normalization: u8,
+    },
+    /// `BuzHash`, with a lookup table derived from the key's chunker nonce
+    ///
+    /// Chunks average `2^mask_bits` bytes, with a minimum of a quarter of that, and a
+    /// maximum of four times that.
+    BuzHash { window_size: u32, mask_bits: u32 },
+    /// `BuzHash`, with every entry of its lookup table derived from the repository's secret
+    /// key material
+    ///
+    /// Chunk sizes are the same as for `BuzHash`, but boundaries can not be predicted
+    /// without the key, making chunk size based fingerprinting attacks much harder.
+    KeyedBuzHash { window_size: u32, mask_bits: u32 },
+}
+
+impl Default for ChunkerSettings {
+    /// `FastCDC` with 32KiB/64KiB/128KiB chunks, which `asuran-cli` used for all
+    /// repositories before the chunker was configurable
+    fn default() -> ChunkerSettings {
+        ChunkerSettings::FastCDC {
+            min_size: 32_768,
+            avg_size: 65_536,
+            max_size: 131_072,
+        }
+    }
+}
+
+impl fmt::Display for ChunkerSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkerSettings::FastCDC {
+                min_size,
+                avg_size,
+                max_size,
+            } => write!(
+                f,
+                "FastCDC (min {min_size}, avg {avg_size}, max {max_size})"
+            ),
+            ChunkerSettings::FastCDC2020 {
+                min_size,
+                avg_size,
+                max_size,
+                normalization,
+            } => write!(
+                f,
+                "FastCDC 2020 (min {min_size}, avg {avg_size}, max {max_size}, NC{normalization})"
+            ),
+            ChunkerSettings::BuzHash {
+                window_size,
+                mask_bits,
+            } => write!(
+                f,
+                "BuzHash (window {}, avg {})",
+                window_size,
+                1_u64 << mask_bits
+            ),
+            ChunkerSettings::KeyedBuzHash {
+                window_size,
+                mask_bits,
+            } => write!(
+                f,
+                "Keyed BuzHash (window {}, avg {})",
+                window_size,
+                1_u64 << mask_bits
+            ),
+        }
+    }
+}
+
 /// Encapsulates the Encryption, Compression, and HMAC tags for a chunk
 #[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq)]
 pub struct ChunkSettings {
     pub compression: Compression,
     pub encryption: Encryption,
     pub hmac: HMAC,
+    /// How the IDs of new chunks are derived
+    #[serde(default)]
+    pub id: ChunkIDSettings,
+    /// The chunker objects are split with
+    ///
+    /// This is `None` for repositories that have not recorded their chunker.
+    #[serde(default)]
+    pub chunker: Option<ChunkerSettings>,
 }
 
 impl ChunkSettings {
@@ -108,6 +343,55 @@ impl ChunkSettings {
             compression: Compression::NoCompression,
             encryption: Encryption::NoEncryption,
             hmac: HMAC::Blake2b,
+            id: ChunkIDSettings::default(),
+            chunker: None,
+        }
+    }
+
+    /// Returns the chunker objects are split with
+    ///
+    /// Repositories that have not recorded their chunker are assumed to use the default one,
+    /// as every repository created before the chunker was recorded did.
+    pub fn chunker(&self) -> ChunkerSettings {
+        self.chunker.unwrap_or_default()
+    }
+
+    /// Checks that this build can produce the HMACs and chunk IDs of chunks packed with these
+    /// settings
+    ///
+    /// # Errors
+    ///
+    /// Returns `ChunkError::UnsupportedHMAC` if support for the HMAC algorithm, or the algorithm
+    /// chunk IDs are derived with, was not compiled in, or the HMAC algorithm is not known to
+    /// this build at all
+    pub fn check_supported(&self) -> Result<()> {
+        let id_hmac = match self.id.algorithm {
+            ChunkIDAlgorithm::HMAC => self.hmac,
+            ChunkIDAlgorithm::Blake3 => HMAC::Blake3,
+        };
+        for hmac in &[self.hmac, id_hmac] {
+            if !hmac.is_supported() {
+                return Err(ChunkError::UnsupportedHMAC(*hmac));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that objects split with `requested` will deduplicate against those already
+    /// in the repository
+    ///
+    /// # Errors
+    ///
+    /// Returns `ChunkError::ChunkerMismatch` if `requested` differs from the recorded chunker
+    pub fn check_chunker(&self, requested: ChunkerSettings) -> Result<()> {
+        let recorded = self.chunker();
+        if recorded == requested {
+            Ok(())
+        } else {
+            Err(ChunkError::ChunkerMismatch {
+                recorded,
+                requested,
+            })
         }
     }
 }
@@ -121,6 +405,15 @@ pub struct ChunkHeader {
     hmac: HMAC,
     mac: Vec<u8>,
     id: ChunkID,
+    #[serde(default, skip_serializing_if = "Padding::is_none")]
+    padding: Padding,
+}
+
+impl ChunkHeader {
+    /// Returns the `ChunkID` of the chunk this header was split from
+    pub fn id(&self) -> ChunkID {
+        self.id
+    }
 }
 
 /// A split representation of a `Chunk`'s body, or contained data
@@ -154,12 +447,21 @@ pub struct Chunk {
     mac: Vec<u8>,
     /// `ChunkID`, used for indexing in the repository and deduplication
     id: ChunkID,
+    /// Padding applied to the compressed data before encryption
+    ///
+    /// Left out when there is none, so unpadded chunks are stored exactly as they were
+    /// before padding existed.
+    #[serde(default, skip_serializing_if = "Padding::is_none")]
+    padding: Padding,
 }
 
 impl Chunk {
     /// Produces a `Chunk` from the given data, using the specified
     /// encryption, and hmac algorithms, as well as the supplied key material.
     ///
+    /// Data that already appears to be compressed or encrypted is stored with
+    /// `NoCompression`, regardless of the requested compression.
+    ///
     /// # Panics
     ///
     /// Will panic if any of the compression, encryption, or `HMAC` operations fail.
@@ -173,8 +475,7 @@ impl Chunk {
         hmac: HMAC,
         key: &Key,
     ) -> Chunk {
-        let id_mac = hmac.id(&data, key);
-        let id = ChunkID::new(&id_mac);
+        let id = ChunkIDSettings::default().derive(&data, hmac, key);
         Chunk::pack_with_id(data, compression, encryption, hmac, key, id)
     }
 
@@ -189,6 +490,7 @@ impl Chunk {
         hmac: HMAC,
         mac: Vec<u8>,
         id: ChunkID,
+        padding: Padding,
     ) -> Chunk {
         Chunk {
             data,
@@ -197,6 +499,7 @@ impl Chunk {
             hmac,
             mac,
             id,
+            padding,
         }
     }
 
@@ -206,15 +509,67 @@ impl Chunk {
     /// This has the potential to do serious damage to a repository if used incorrectly,
     /// and should be avoided if another method is available.
     pub fn pack_with_id(
+        data: Vec<u8>,
+        compression: Compression,
+        encryption: Encryption,
+        hmac: HMAC,
+        key: &Key,
+        id: ChunkID,
+    ) -> Chunk {
+        Chunk::pack_with_dictionary(data, compression, encryption, hmac, key, id, None)
+    }
+
+    /// Produces a `Chunk` in the same way as `pack_with_id`, providing the dictionary
+    /// for `ZStdDict` compression.
+    ///
+    /// If the requested compression needs a dictionary, and `dictionary` is not that
+    /// dictionary, the chunk is compressed with plain zstd at the same level instead.
+    pub fn pack_with_dictionary(
+        data: Vec<u8>,
+        compression: Compression,
+        encryption: Encryption,
+        hmac: HMAC,
+        key: &Key,
+        id: ChunkID,
+        dictionary: Option<&ZStdDictionary>,
+    ) -> Chunk {
+        Chunk::pack_padded(
+            data,
+            compression,
+            encryption,
+            hmac,
+            key,
+            id,
+            dictionary,
+            Padding::NoPadding,
+        )
+    }
+
+    /// Produces a `Chunk` in the same way as `pack_with_dictionary`, padding the compressed
+    /// data with `padding` before it is encrypted.
+    ///
+    /// The padding is recorded in the chunk, and stripped again when it is unpacked.
+    #[allow(clippy::too_many_arguments)]
+    pub fn pack_padded(
         data: Vec<u8>,
         compression: Compression,
         mut encryption: Encryption,
         hmac: HMAC,
         key: &Key,
         id: ChunkID,
+        dictionary: Option<&ZStdDictionary>,
+        padding: Padding,
     ) -> Chunk {
-        let compressed_data = compression.compress(data);
-        let data = encryption.encrypt(&compressed_data, key);
+        // Don't waste time compressing data that is already compressed or encrypted
+        let mut compression = compression.for_data(&data);
+        if let Compression::ZStdDict { level, dict_id } = compression {
+            if dictionary.map(ZStdDictionary::id) != Some(dict_id) {
+                compression = Compression::ZStd { level };
+            }
+        }
+        let compressed_data = compression.compress_with_dictionary(data, dictionary);
+        let padded_data = padding.pad(compressed_data);
+        let data = encryption.encrypt(&padded_data, key);
         let mac = hmac.mac(&data, key);
         Chunk {
             data,
@@ -223,6 +578,7 @@ impl Chunk {
             hmac,
             mac,
             id,
+            padding,
         }
     }
 
@@ -230,18 +586,47 @@ impl Chunk {
     ///
     /// # Errors
     ///
+    /// Will return `Err(UnsupportedHMAC)` if the chunk was tagged with an HMAC algorithm
+    /// this build does not support.
+    ///
     /// Will return `Err(HMACVailidationFailed)` if the chunk fails validation.
     ///
     /// Will return `Err(EncryptionError)` if decryption fails.
     ///
+    /// Will return `Err(InvalidPadding)` if the padding can not be stripped.
+    ///
     /// Will return `Err(CompressionError)` if decompression fails.
     ///
     /// All of these error values indicate that the `Chunk` is corrupted or otherwise
     /// malformed.
     pub fn unpack(&self, key: &Key) -> Result<Vec<u8>> {
-        if self.hmac.verify_hmac(&self.mac, &self.data, key) {
+        self.unpack_with_dictionary(key, None)
+    }
+
+    /// Validates, decrypts, and decompresses the data in a `Chunk`, providing the
+    /// dictionary needed for `ZStdDict` compression.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `unpack`. Decompression will also fail if the chunk
+    /// needs a dictionary, and `dictionary` is not that dictionary.
+    pub fn unpack_with_dictionary(
+        &self,
+        key: &Key,
+        dictionary: Option<&ZStdDictionary>,
+    ) -> Result<Vec<u8>> {
+        if !self.hmac.is_supported() {
+            return Err(ChunkError::UnsupportedHMAC(self.hmac));
+        }
+        if self.verify_mac(key) {
             let decrypted_data = self.encryption.decrypt(&self.data, key)?;
-            let decompressed_data = self.compression.decompress(decrypted_data)?;
+            let decrypted_data = self
+                .padding
+                .strip(decrypted_data)
+                .ok_or(ChunkError::InvalidPadding)?;
+            let decompressed_data = self
+                .compression
+                .decompress_with_dictionary(decrypted_data, dictionary)?;
 
             Ok(decompressed_data)
         } else {
@@ -249,6 +634,55 @@ impl Chunk {
         }
     }
 
+    /// Validates, decrypts, and decompresses the data in a `Chunk`, writing the plaintext
+    /// into `output` and returning its length
+    ///
+    /// Decryption and decompression happen through bounded buffers as the data is written,
+    /// so unlike `unpack`, the plaintext of the chunk is never held in memory all at once.
+    /// Padded chunks are the exception, as the padding can only be found once the whole
+    /// chunk has been decrypted.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `unpack_with_dictionary`, as well as any errors writing
+    /// to `output`. If decompression fails partway through, some of the plaintext may
+    /// already have been written.
+    pub fn unpack_into<W: Write + ?Sized>(
+        &self,
+        key: &Key,
+        dictionary: Option<&ZStdDictionary>,
+        output: &mut W,
+    ) -> Result<u64> {
+        if !self.hmac.is_supported() {
+            return Err(ChunkError::UnsupportedHMAC(self.hmac));
+        }
+        if self.verify_mac(key) {
+            if !self.padding.is_none() {
+                let decrypted = self.encryption.decrypt(&self.data, key)?;
+                let decrypted = self
+                    .padding
+                    .strip(decrypted)
+                    .ok_or(ChunkError::InvalidPadding)?;
+                return Ok(self
+                    .compression
+                    .decompress_into(&decrypted[..], dictionary, output)?);
+            }
+            let decrypted = self.encryption.decrypt_reader(&self.data, key)?;
+            Ok(self
+                .compression
+                .decompress_into(decrypted, dictionary, output)?)
+        } else {
+            Err(ChunkError::HMACValidationFailed)
+        }
+    }
+
+    /// Checks the `Chunk`'s MAC against its data, without decrypting it
+    ///
+    /// Returns false if the `Chunk` has been corrupted or tampered with.
+    pub fn verify_mac(&self, key: &Key) -> bool {
+        self.hmac.verify_hmac(&self.mac, &self.data, key)
+    }
+
     #[cfg_attr(tarpaulin, skip)]
     /// Returns the length of the data in the `Chunk`
     pub fn len(&self) -> usize {
@@ -285,6 +719,7 @@ impl Chunk {
             hmac: self.hmac,
             mac: self.mac,
             id: self.id,
+            padding: self.padding,
         };
         let body = ChunkBody(self.data);
 
@@ -300,6 +735,7 @@ impl Chunk {
             hmac: header.hmac,
             mac: header.mac,
             id: header.id,
+            padding: header.padding,
         }
     }
 
@@ -308,6 +744,16 @@ impl Chunk {
         self.encryption
     }
 
+    /// Returns the compression used for the chunk
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Returns the padding applied to the chunk
+    pub fn padding(&self) -> Padding {
+        self.padding
+    }
+
     #[cfg(test)]
     #[cfg_attr(tarpaulin, skip)]
     /// Testing only function used to corrupt the data
@@ -347,6 +793,14 @@ mod tests {
         let output_bytes = packed.unpack(&key).expect("Failed to unpack output bytes");
 
         assert_eq!(data_string.as_bytes().to_vec(), output_bytes);
+
+        // Streaming the chunk out must produce the same bytes
+        let mut streamed = Vec::new();
+        let length = packed
+            .unpack_into(&key, None, &mut streamed)
+            .expect("Failed to stream output bytes");
+        assert_eq!(length, output_bytes.len() as u64);
+        assert_eq!(streamed, output_bytes);
     }
 
     #[test]
@@ -356,6 +810,8 @@ mod tests {
             Compression::ZStd { level: 1 },
             Compression::LZ4 { level: 1 },
             Compression::LZMA { level: 1 },
+            Compression::Brotli { level: 1 },
+            Compression::LZ4HC { level: 3 },
         ];
         let encryptions = [
             Encryption::NoEncryption,
@@ -395,6 +851,118 @@ mod tests {
         let result = packed.unpack(&key);
 
         assert!(result.is_err());
+        // Nothing gets written out for a chunk that fails validation
+        let mut output = Vec::new();
+        assert!(packed.unpack_into(&key, None, &mut output).is_err());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn unsupported_hmac() {
+        let data_bytes = b"I am but a humble test string".to_vec();
+        let key = Key::random(32);
+        let mut packed = Chunk::pack(
+            data_bytes,
+            Compression::NoCompression,
+            Encryption::NoEncryption,
+            HMAC::SHA256,
+            &key,
+        );
+        // Pretend the chunk was written by a future version with a new algorithm
+        packed.hmac = HMAC::Unknown(42);
+        let bytes = rmp_serde::to_vec(&packed).unwrap();
+        let packed: Chunk = rmp_serde::from_slice(&bytes).unwrap();
+
+        assert!(!packed.verify_mac(&key));
+        match packed.unpack(&key) {
+            Err(ChunkError::UnsupportedHMAC(HMAC::Unknown(42))) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn chunk_id_settings() {
+        let data = b"I am but a humble test string";
+        let key = Key::random(32);
+        assert!(ChunkIDSettings::new(ChunkIDAlgorithm::HMAC, 15).is_err());
+        assert!(ChunkIDSettings::new(ChunkIDAlgorithm::HMAC, 33).is_err());
+
+        // The default settings must produce the same IDs they always have
+        let id = ChunkIDSettings::default().derive(data, HMAC::SHA256, &key);
+        assert_eq!(id, ChunkID::new(&HMAC::SHA256.id(data, &key)));
+
+        // Native BLAKE3 IDs do not depend on the HMAC algorithm
+        let settings = ChunkIDSettings::new(ChunkIDAlgorithm::Blake3, 32).unwrap();
+        let blake3_id = settings.derive(data, HMAC::SHA256, &key);
+        assert_eq!(blake3_id, settings.derive(data, HMAC::SHA3, &key));
+        assert_ne!(blake3_id, id);
+
+        // Truncated IDs keep the prefix of the full ID, and are padded with zeros
+        let settings = ChunkIDSettings::new(ChunkIDAlgorithm::Blake3, 20).unwrap();
+        let short_id = settings.derive(data, HMAC::SHA256, &key);
+        assert_eq!(short_id.get_id()[..20], blake3_id.get_id()[..20]);
+        assert!(short_id.get_id()[20..].iter().all(|x| *x == 0));
+    }
+
+    // Settings serialized before the chunker was recorded must still load, and be treated as
+    // using the default chunker
+    #[test]
+    fn legacy_chunk_settings() {
+        #[derive(Serialize)]
+        struct LegacyChunkSettings {
+            compression: Compression,
+            encryption: Encryption,
+            hmac: HMAC,
+            id: ChunkIDSettings,
+        }
+        let legacy = LegacyChunkSettings {
+            compression: Compression::NoCompression,
+            encryption: Encryption::NoEncryption,
+            hmac: HMAC::Blake2b,
+            id: ChunkIDSettings::default(),
+        };
+        let bytes = rmp_serde::to_vec(&legacy).unwrap();
+        let settings: ChunkSettings = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(settings, ChunkSettings::lightweight());
+        assert_eq!(settings.chunker(), ChunkerSettings::default());
+        assert!(settings.check_chunker(ChunkerSettings::default()).is_ok());
+
+        // The recorded chunker must survive a round trip, and be enforced
+        let buzhash = ChunkerSettings::BuzHash {
+            window_size: 4095,
+            mask_bits: 21,
+        };
+        let settings = ChunkSettings {
+            chunker: Some(buzhash),
+            ..settings
+        };
+        let bytes = rmp_serde::to_vec(&settings).unwrap();
+        let settings: ChunkSettings = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(settings.chunker(), buzhash);
+        match settings.check_chunker(ChunkerSettings::default()) {
+            Err(ChunkError::ChunkerMismatch {
+                recorded,
+                requested,
+            }) => {
+                assert_eq!(recorded, buzhash);
+                assert_eq!(requested, ChunkerSettings::default());
+            }
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        // The keyed table splits differently, so must not match the plain one
+        let keyed = ChunkerSettings::KeyedBuzHash {
+            window_size: 4095,
+            mask_bits: 21,
+        };
+        assert!(settings.check_chunker(keyed).is_err());
+        let settings = ChunkSettings {
+            chunker: Some(keyed),
+            ..settings
+        };
+        let bytes = rmp_serde::to_vec(&settings).unwrap();
+        let settings: ChunkSettings = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(settings.chunker(), keyed);
     }
 
     #[test]
@@ -406,6 +974,36 @@ mod tests {
         assert!(!id.verify(&data2));
     }
 
+    #[test]
+    fn dictionary_ids() {
+        assert!(ChunkID::dictionary_id(7).is_dictionary());
+        assert!(!ChunkID::manifest_id().is_dictionary());
+        assert!(!ChunkID::new(&[1_u8; 32]).is_dictionary());
+    }
+
+    #[test]
+    fn unsupported_settings() {
+        let settings = ChunkSettings::lightweight();
+        assert!(settings.check_supported().is_ok());
+        let unknown = ChunkSettings {
+            hmac: HMAC::Unknown(99),
+            ..settings
+        };
+        assert!(matches!(
+            unknown.check_supported(),
+            Err(ChunkError::UnsupportedHMAC(HMAC::Unknown(99)))
+        ));
+    }
+
+    #[test]
+    fn audit_log_ids() {
+        let id = ChunkID::audit_log_id(u64::MAX - 1);
+        assert_eq!(id.audit_log_sequence(), Some(u64::MAX - 1));
+        assert!(!id.is_dictionary());
+        assert_eq!(ChunkID::dictionary_id(7).audit_log_sequence(), None);
+        assert_eq!(ChunkID::manifest_id().audit_log_sequence(), None);
+    }
+
     #[test]
     fn split_unsplit() {
         let data_string = "I am but a humble test string";
@@ -424,4 +1022,52 @@ mod tests {
 
         assert!(result.is_ok());
     }
+
+    // Padded chunks should be stored at their padded length, and unpack to the original data
+    #[test]
+    fn padded_chunks() {
+        let key = Key::random(32);
+        let data = vec![7_u8; 1000];
+        let encryptions = [Encryption::NoEncryption, Encryption::new_aes256ctr()];
+        for padding in &[Padding::NoPadding, Padding::Padme, Padding::PowerOfTwo] {
+            for encryption in &encryptions {
+                let id = ChunkID::new(&[1; 32]);
+                let packed = Chunk::pack_padded(
+                    data.clone(),
+                    Compression::NoCompression,
+                    *encryption,
+                    HMAC::Blake3,
+                    &key,
+                    id,
+                    None,
+                    *padding,
+                );
+                assert_eq!(packed.len(), padding.padded_length(data.len()));
+                assert_eq!(packed.padding(), *padding);
+                assert_eq!(packed.unpack(&key).unwrap(), data);
+                let mut streamed = Vec::new();
+                let length = packed.unpack_into(&key, None, &mut streamed).unwrap();
+                assert_eq!(length, 1000);
+                assert_eq!(streamed, data);
+                // The padding must be carried through storage
+                let (header, body) = packed.split();
+                let bytes = rmp_serde::to_vec(&header).unwrap();
+                let header: ChunkHeader = rmp_serde::from_slice(&bytes).unwrap();
+                let packed = Chunk::unsplit(header, body);
+                assert_eq!(packed.unpack(&key).unwrap(), data);
+            }
+        }
+        // Unpadded chunks should serialize exactly as they did before padding existed
+        let packed = Chunk::pack(
+            data,
+            Compression::NoCompression,
+            Encryption::NoEncryption,
+            HMAC::Blake3,
+            &key,
+        );
+        let (header, _) = packed.split();
+        let bytes = rmp_serde::to_vec(&header).unwrap();
+        let fields: Vec<serde::de::IgnoredAny> = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(fields.len(), 5);
+    }
 }
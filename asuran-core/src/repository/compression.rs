@@ -6,39 +6,347 @@ use thiserror::Error;
 #[cfg(feature = "xz2")]
 use xz2::read::{XzDecoder, XzEncoder};
 
+use std::fmt;
 #[allow(unused_imports)]
 use std::io::copy;
 #[allow(unused_imports)]
 use std::io::Cursor;
+#[allow(unused_imports)]
+use std::io::Read;
+use std::io::Write;
 
 /// Error describing things that can go wrong with compression/decompression
 #[derive(Error, Debug)]
 pub enum CompressionError {
     #[error("I/O Error")]
     IOError(#[from] std::io::Error),
+    #[error("Zstd dictionary {0} is required, but was not provided")]
+    MissingDictionary(u32),
+    #[error("Data is not a valid zstd dictionary")]
+    InvalidDictionary,
 }
 
 type Result<T> = std::result::Result<T, CompressionError>;
 
+/// Magic numbers, and the offsets they appear at, of common formats whose contents are
+/// already compressed
+const COMPRESSED_MAGIC: &[(usize, &[u8])] = &[
+    // JPEG
+    (0, &[0xFF, 0xD8, 0xFF]),
+    // PNG
+    (0, &[0x89, b'P', b'N', b'G']),
+    // GIF
+    (0, b"GIF8"),
+    // WebP
+    (8, b"WEBP"),
+    // MP4, MOV, and other ISO base media files
+    (4, b"ftyp"),
+    // Matroska and WebM
+    (0, &[0x1A, 0x45, 0xDF, 0xA3]),
+    // Ogg
+    (0, b"OggS"),
+    // FLAC
+    (0, b"fLaC"),
+    // MP3 with an ID3 tag
+    (0, b"ID3"),
+    // Zip, and formats built on it (docx, jar, apk, ...)
+    (0, &[b'P', b'K', 0x03, 0x04]),
+    // Gzip
+    (0, &[0x1F, 0x8B]),
+    // Bzip2
+    (0, b"BZh"),
+    // Xz
+    (0, &[0xFD, b'7', b'z', b'X', b'Z', 0x00]),
+    // Zstd
+    (0, &[0x28, 0xB5, 0x2F, 0xFD]),
+    // 7z
+    (0, &[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C]),
+    // Rar
+    (0, b"Rar!"),
+];
+
+/// Data shorter than this is too short for a meaningful entropy estimate
+const MIN_ENTROPY_SAMPLE: usize = 8 * 1024;
+/// Maximum number of bytes to sample when estimating entropy
+const MAX_ENTROPY_SAMPLE: usize = 64 * 1024;
+/// Entropy, in bits per byte, above which data is assumed to be incompressible
+///
+/// Compressed and encrypted data comes in just under the maximum of 8.
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.95;
+/// Entropy, in bits per byte, below which data is redundant enough, like text, to be worth
+/// compressing at a high level
+const REDUNDANT_ENTROPY: f64 = 5.0;
+/// Entropy, in bits per byte, above which data is dense enough that only a fast level is
+/// worth the time
+const DENSE_ENTROPY: f64 = 7.0;
+
+/// Magic number at the start of every zstd dictionary, followed by the dictionary's id
+const ZSTD_DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xA4, 0x30, 0xEC];
+
+/// A zstd dictionary, trained on a sample of a repository's chunks
+///
+/// Dictionaries give zstd a head start on small chunks, which are otherwise too short
+/// for it to build up much useful context. Chunks compressed with a dictionary can only
+/// be decompressed with that same dictionary, which is identified by the id zstd
+/// embeds in it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ZStdDictionary {
+    id: u32,
+    data: Vec<u8>,
+}
+
+impl ZStdDictionary {
+    /// Trains a dictionary of at most `max_size` bytes on the provided samples
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if training fails, most commonly because there were too few
+    /// samples to train on.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if support for zstd has not been compiled in.
+    #[allow(unused_variables)]
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<ZStdDictionary> {
+        cfg_if! {
+            if #[cfg(feature = "zstd")] {
+                let data = zstd::dict::from_samples(samples, max_size)?;
+                ZStdDictionary::from_bytes(data)
+            } else {
+                unimplemented!("Asuran was not compiled with zstd support")
+            }
+        }
+    }
+
+    /// Wraps the raw bytes of a previously trained dictionary
+    ///
+    /// # Errors
+    ///
+    /// Will return `CompressionError::InvalidDictionary` if the bytes do not start with
+    /// a zstd dictionary header.
+    pub fn from_bytes(data: Vec<u8>) -> Result<ZStdDictionary> {
+        if data.len() < 8 || data[..4] != ZSTD_DICTIONARY_MAGIC {
+            return Err(CompressionError::InvalidDictionary);
+        }
+        let mut id = [0_u8; 4];
+        id.copy_from_slice(&data[4..8]);
+        Ok(ZStdDictionary {
+            id: u32::from_le_bytes(id),
+            data,
+        })
+    }
+
+    /// Returns the id of this dictionary
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the raw bytes of this dictionary
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for ZStdDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZStdDictionary")
+            .field("id", &self.id)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+/// Lowest level at which LZ4 uses its high compression mode
+const LZ4HC_MIN_LEVEL: u32 = 3;
+/// Highest level LZ4's high compression mode supports
+const LZ4HC_MAX_LEVEL: u32 = 12;
+/// Size of the buffers used while streaming data through brotli
+#[allow(dead_code)]
+const BROTLI_BUFFER_SIZE: usize = 4096;
+/// Base 2 logarithm of the window size used for brotli, the reference encoder's default
+#[allow(dead_code)]
+const BROTLI_WINDOW: u32 = 22;
+
 /// Marker for the type of compression used by a particular chunk
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 pub enum Compression {
     NoCompression,
-    ZStd { level: i32 },
-    LZ4 { level: u32 },
-    LZMA { level: u32 },
+    ZStd {
+        level: i32,
+    },
+    LZ4 {
+        level: u32,
+    },
+    LZMA {
+        level: u32,
+    },
+    /// Zstd, primed with the repository's dictionary with the given id
+    ZStdDict {
+        level: i32,
+        dict_id: u32,
+    },
+    /// Brotli, with a quality between 0 and 11
+    ///
+    /// Brotli does particularly well on text-heavy data.
+    Brotli {
+        level: u32,
+    },
+    /// LZ4's high compression mode, with a level between 3 and 12
+    ///
+    /// This compresses noticeably better than plain LZ4, at the cost of compression
+    /// speed, while decompressing just as quickly. Levels outside of the supported range
+    /// are clamped to it.
+    LZ4HC {
+        level: u32,
+    },
 }
 
 impl Compression {
+    /// Returns every compression algorithm support was compiled in for, at its default
+    /// level
+    pub fn supported() -> Vec<Compression> {
+        let mut supported = vec![Compression::NoCompression];
+        if cfg!(feature = "zstd") {
+            supported.push(Compression::ZStd { level: 3 });
+        }
+        if cfg!(feature = "lz4") {
+            supported.push(Compression::LZ4 { level: 4 });
+            supported.push(Compression::LZ4HC { level: 9 });
+        }
+        if cfg!(feature = "xz2") {
+            supported.push(Compression::LZMA { level: 6 });
+        }
+        if cfg!(feature = "brotli") {
+            supported.push(Compression::Brotli { level: 6 });
+        }
+        supported
+    }
+
+    /// Guesses if the data is already compressed or encrypted, and would not benefit
+    /// from further compression
+    ///
+    /// Data is considered incompressible if it starts with the magic number of a common
+    /// compressed format, or if its leading bytes are close to uniformly distributed.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn is_incompressible(data: &[u8]) -> bool {
+        let magic = COMPRESSED_MAGIC
+            .iter()
+            .any(|(offset, magic)| data.get(*offset..offset + magic.len()) == Some(*magic));
+        if magic {
+            return true;
+        }
+        if data.len() < MIN_ENTROPY_SAMPLE {
+            return false;
+        }
+        let sample = &data[..data.len().min(MAX_ENTROPY_SAMPLE)];
+        let mut counts = [0_usize; 256];
+        for byte in sample {
+            counts[*byte as usize] += 1;
+        }
+        let length = sample.len() as f64;
+        let entropy: f64 = counts
+            .iter()
+            .filter(|x| **x > 0)
+            .map(|x| {
+                let p = *x as f64 / length;
+                -p * p.log2()
+            })
+            .sum();
+        entropy > INCOMPRESSIBLE_ENTROPY
+    }
+
+    /// Returns the compression that should actually be applied to the data
+    ///
+    /// This is `NoCompression` if the data looks incompressible, as compressing it would
+    /// only waste time, and `self` otherwise.
+    #[must_use]
+    pub fn for_data(self, data: &[u8]) -> Compression {
+        if self != Compression::NoCompression && Compression::is_incompressible(data) {
+            Compression::NoCompression
+        } else {
+            self
+        }
+    }
+
+    /// Returns the compression that should be applied to data with the given estimated
+    /// entropy, in bits per byte
+    ///
+    /// Redundant data, such as text, is compressed with at least a high level of the same
+    /// algorithm, as it pays off in size, while dense data is compressed with at most a
+    /// fast level, as it would not shrink much further. Data that looks incompressible is
+    /// stored without compression. Everything in between keeps the level of `self`.
+    #[must_use]
+    pub fn for_entropy(self, entropy: f64) -> Compression {
+        if entropy > INCOMPRESSIBLE_ENTROPY {
+            Compression::NoCompression
+        } else if entropy < REDUNDANT_ENTROPY {
+            self.map_level(|level| level.max(9), |level| level.max(12))
+        } else if entropy > DENSE_ENTROPY {
+            self.map_level(|level| level.min(1), |level| level.min(1))
+        } else {
+            self
+        }
+    }
+
+    /// Replaces the level of `self`, using `zstd` for the zstd based algorithms, and
+    /// `other` for the rest
+    ///
+    /// Plain LZ4 has no meaningful levels, and is left alone, while LZ4HC's levels are
+    /// kept within its supported range.
+    fn map_level(self, other: impl Fn(u32) -> u32, zstd: impl Fn(i32) -> i32) -> Compression {
+        match self {
+            Compression::NoCompression | Compression::LZ4 { .. } => self,
+            Compression::ZStd { level } => Compression::ZStd { level: zstd(level) },
+            Compression::ZStdDict { level, dict_id } => Compression::ZStdDict {
+                level: zstd(level),
+                dict_id,
+            },
+            Compression::LZMA { level } => Compression::LZMA {
+                level: other(level),
+            },
+            Compression::Brotli { level } => Compression::Brotli {
+                level: other(level),
+            },
+            Compression::LZ4HC { level } => Compression::LZ4HC {
+                level: other(level).clamp(3, 12),
+            },
+        }
+    }
+
+    /// Returns the id of the dictionary this compression requires, if any
+    pub fn dictionary_id(self) -> Option<u32> {
+        match self {
+            Compression::ZStdDict { dict_id, .. } => Some(dict_id),
+            _ => None,
+        }
+    }
+
     /// Compresses the data with the algorithm indicated and level by the variant of
     /// `self`
     ///
     /// # Panics
     ///
     /// Will panic if the user selects a compression algorithm for which support has not
-    /// been compiled in, or if compression otherwise fails.
-    #[allow(unused_variables)]
+    /// been compiled in, if `self` requires a dictionary, or if compression otherwise
+    /// fails.
     pub fn compress(self, data: Vec<u8>) -> Vec<u8> {
+        self.compress_with_dictionary(data, None)
+    }
+
+    /// Compresses the data with the algorithm indicated and level by the variant of
+    /// `self`, using the provided dictionary if `self` requires one
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the user selects a compression algorithm for which support has not
+    /// been compiled in, if `self` requires a dictionary other than the one provided,
+    /// or if compression otherwise fails.
+    #[allow(unused_variables)]
+    pub fn compress_with_dictionary(
+        self,
+        data: Vec<u8>,
+        dictionary: Option<&ZStdDictionary>,
+    ) -> Vec<u8> {
         match self {
             Compression::NoCompression => data,
             Compression::ZStd { level } => {
@@ -56,7 +364,12 @@ impl Compression {
                     }
                 }
             }
-            Compression::LZ4 { level } => {
+            Compression::LZ4 { level } | Compression::LZ4HC { level } => {
+                let level = if let Compression::LZ4HC { .. } = self {
+                    level.clamp(LZ4HC_MIN_LEVEL, LZ4HC_MAX_LEVEL)
+                } else {
+                    level
+                };
                 cfg_if! {
                     if #[cfg(feature = "lz4")] {
                         let ouput = Vec::<u8>::with_capacity(data.len());
@@ -91,6 +404,50 @@ impl Compression {
                     }
                 }
             }
+            Compression::ZStdDict { level, dict_id } => {
+                let dictionary = match dictionary {
+                    Some(dictionary) if dictionary.id() == dict_id => dictionary,
+                    _ => panic!(
+                        "Zstd dictionary {} was not provided for compression",
+                        dict_id
+                    ),
+                };
+                cfg_if! {
+                    if #[cfg(feature = "zstd")] {
+                        let mut output = Vec::<u8>::with_capacity(data.len());
+                        let mut encoder = zstd::stream::read::Encoder::with_dictionary(
+                            data.as_slice(),
+                            level,
+                            dictionary.as_bytes(),
+                        )
+                        .expect("Failed to build a zstd encoder. Check for OOM or an invalid dictionary.");
+                        // As above, reading into a Vec<u8> can only fail on OOM
+                        encoder.read_to_end(&mut output).unwrap();
+                        output
+                    } else {
+                        unimplemented!("Asuran was not compiled with zstd support.")
+                    }
+                }
+            }
+            Compression::Brotli { level } => {
+                cfg_if! {
+                    if #[cfg(feature = "brotli")] {
+                        let mut output = Vec::<u8>::with_capacity(data.len());
+                        let mut compressor = brotli::CompressorReader::new(
+                            data.as_slice(),
+                            BROTLI_BUFFER_SIZE,
+                            level,
+                            BROTLI_WINDOW,
+                        );
+                        compressor
+                            .read_to_end(&mut output)
+                            .expect("Failed to compress data with Brotli. Check for OOM.");
+                        output
+                    } else {
+                        unimplemented!("Asuran was not compiled with brotli support")
+                    }
+                }
+            }
         }
     }
 
@@ -99,14 +456,34 @@ impl Compression {
     ///
     /// # Errors
     ///
-    /// Will return `Err` if decompression fails.
+    /// Will return `Err` if decompression fails, or if `self` requires a dictionary.
     ///
     /// # Panics
     ///
     /// Will panic if the user selects a compression algorithm for which support has not
     /// been compiled in.
-    #[allow(unused_variables)]
     pub fn decompress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.decompress_with_dictionary(data, None)
+    }
+
+    /// Decompresses the given data with the algorithm specified by the variant of
+    /// `self`, using the provided dictionary if `self` requires one
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if decompression fails, or `CompressionError::MissingDictionary`
+    /// if `self` requires a dictionary other than the one provided.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the user selects a compression algorithm for which support has not
+    /// been compiled in.
+    #[allow(unused_variables)]
+    pub fn decompress_with_dictionary(
+        self,
+        data: Vec<u8>,
+        dictionary: Option<&ZStdDictionary>,
+    ) -> Result<Vec<u8>> {
         match self {
             Compression::NoCompression => Ok(data),
             Compression::ZStd { .. } => {
@@ -120,7 +497,8 @@ impl Compression {
                     }
                 }
             }
-            Compression::LZ4 { .. } => {
+            // LZ4's high compression mode produces ordinary LZ4 frames
+            Compression::LZ4 { .. } | Compression::LZ4HC { .. } => {
                 cfg_if! {
                     if #[cfg(feature = "lz4")] {
                         let mut output = Cursor::new(Vec::<u8>::new());
@@ -147,6 +525,126 @@ impl Compression {
                     }
                 }
             }
+            Compression::ZStdDict { dict_id, .. } => {
+                let dictionary = match dictionary {
+                    Some(dictionary) if dictionary.id() == dict_id => dictionary,
+                    _ => return Err(CompressionError::MissingDictionary(dict_id)),
+                };
+                cfg_if! {
+                    if #[cfg(feature = "zstd")] {
+                        let mut output = Vec::<u8>::new();
+                        let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+                            data.as_slice(),
+                            dictionary.as_bytes(),
+                        )?;
+                        decoder.read_to_end(&mut output)?;
+                        Ok(output)
+                    } else {
+                        unimplemented!("Asuran was not compiled with zstd support")
+                    }
+                }
+            }
+            Compression::Brotli { .. } => {
+                cfg_if! {
+                    if #[cfg(feature = "brotli")] {
+                        let mut output = Vec::<u8>::new();
+                        let mut decompressor =
+                            brotli::Decompressor::new(data.as_slice(), BROTLI_BUFFER_SIZE);
+                        decompressor.read_to_end(&mut output)?;
+                        Ok(output)
+                    } else {
+                        unimplemented!("Asuran was not compiled with brotli support")
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decompresses data read from `input` into `output`, using the provided dictionary
+    /// if `self` requires one, returning the number of bytes written
+    ///
+    /// Data is decompressed through bounded buffers, so the full decompressed data is
+    /// never held in memory.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if decompression fails, if reading or writing fails, or
+    /// `CompressionError::MissingDictionary` if `self` requires a dictionary other than
+    /// the one provided.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the user selects a compression algorithm for which support has not
+    /// been compiled in.
+    #[allow(unused_variables, unused_mut)]
+    pub fn decompress_into<R: Read, W: Write + ?Sized>(
+        self,
+        mut input: R,
+        dictionary: Option<&ZStdDictionary>,
+        output: &mut W,
+    ) -> Result<u64> {
+        match self {
+            Compression::NoCompression => Ok(copy(&mut input, output)?),
+            Compression::ZStd { .. } => {
+                cfg_if! {
+                    if #[cfg(feature = "zstd")] {
+                        let mut decoder = zstd::stream::read::Decoder::new(input)?;
+                        Ok(copy(&mut decoder, output)?)
+                    } else {
+                        unimplemented!("Asuran was not compiled with zstd support")
+                    }
+                }
+            }
+            Compression::LZ4 { .. } | Compression::LZ4HC { .. } => {
+                cfg_if! {
+                    if #[cfg(feature = "lz4")] {
+                        let mut decoder = Decoder::new(input)?;
+                        let written = copy(&mut decoder, output)?;
+                        let (_input, result) = decoder.finish();
+                        result?;
+                        Ok(written)
+                    } else {
+                        unimplemented!("Asuran was not compiled with lz4 support")
+                    }
+                }
+            }
+            Compression::LZMA { .. } => {
+                cfg_if! {
+                    if #[cfg(feature = "xz2")] {
+                        let mut decompressor = XzDecoder::new(input);
+                        Ok(copy(&mut decompressor, output)?)
+                    } else {
+                        unimplemented!("Asuran was not compiled with lzma support")
+                    }
+                }
+            }
+            Compression::ZStdDict { dict_id, .. } => {
+                let dictionary = match dictionary {
+                    Some(dictionary) if dictionary.id() == dict_id => dictionary,
+                    _ => return Err(CompressionError::MissingDictionary(dict_id)),
+                };
+                cfg_if! {
+                    if #[cfg(feature = "zstd")] {
+                        let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+                            std::io::BufReader::new(input),
+                            dictionary.as_bytes(),
+                        )?;
+                        Ok(copy(&mut decoder, output)?)
+                    } else {
+                        unimplemented!("Asuran was not compiled with zstd support")
+                    }
+                }
+            }
+            Compression::Brotli { .. } => {
+                cfg_if! {
+                    if #[cfg(feature = "brotli")] {
+                        let mut decompressor = brotli::Decompressor::new(input, BROTLI_BUFFER_SIZE);
+                        Ok(copy(&mut decompressor, output)?)
+                    } else {
+                        unimplemented!("Asuran was not compiled with brotli support")
+                    }
+                }
+            }
         }
     }
 }
@@ -179,4 +677,123 @@ mod tests {
 
         assert_eq!(data_string, decompressed_string);
     }
+
+    #[test]
+    fn skip_incompressible() {
+        let compression = Compression::ZStd { level: 6 };
+        // Uniformly distributed bytes, standing in for compressed or encrypted data
+        let mut state = 0x2545_F491_4F6C_DD1D_u64;
+        let random: Vec<u8> = (0..32 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_le_bytes()[0]
+            })
+            .collect();
+        assert_eq!(compression.for_data(&random), Compression::NoCompression);
+        // A small file with a JPEG header
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F'];
+        assert_eq!(compression.for_data(&jpeg), Compression::NoCompression);
+        // Text compresses well, and should be left alone
+        let text = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(500);
+        assert_eq!(compression.for_data(text.as_bytes()), compression);
+        // As should data too short to judge
+        assert_eq!(compression.for_data(&random[..1024]), compression);
+    }
+
+    #[test]
+    fn level_for_entropy() {
+        let zstd = Compression::ZStd { level: 6 };
+        assert_eq!(zstd.for_entropy(7.99), Compression::NoCompression);
+        assert_eq!(zstd.for_entropy(7.5), Compression::ZStd { level: 1 });
+        assert_eq!(zstd.for_entropy(6.0), zstd);
+        assert_eq!(zstd.for_entropy(4.0), Compression::ZStd { level: 12 });
+        // Levels already past the one picked are kept
+        let zstd = Compression::ZStd { level: 19 };
+        assert_eq!(zstd.for_entropy(4.0), zstd);
+        let dict = Compression::ZStdDict {
+            level: 3,
+            dict_id: 7,
+        };
+        assert_eq!(
+            dict.for_entropy(4.0),
+            Compression::ZStdDict {
+                level: 12,
+                dict_id: 7
+            }
+        );
+        let lz4hc = Compression::LZ4HC { level: 9 };
+        assert_eq!(lz4hc.for_entropy(7.5), Compression::LZ4HC { level: 3 });
+        assert_eq!(lz4hc.for_entropy(4.0), lz4hc);
+        let lz4 = Compression::LZ4 { level: 4 };
+        assert_eq!(lz4.for_entropy(4.0), lz4);
+        let none = Compression::NoCompression;
+        assert_eq!(none.for_entropy(4.0), none);
+    }
+
+    #[test]
+    fn brotli_and_lz4hc() {
+        let text = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(200);
+        let data = text.as_bytes().to_vec();
+        let lz4 = Compression::LZ4 { level: 1 }.compress(data.clone());
+        for compression in &[
+            Compression::Brotli { level: 6 },
+            Compression::LZ4HC { level: 9 },
+            // Clamped into the supported range
+            Compression::LZ4HC { level: 0 },
+            Compression::LZ4HC { level: 100 },
+        ] {
+            let compressed = compression.compress(data.clone());
+            assert!(compressed.len() < data.len());
+            assert!(compressed.len() <= lz4.len());
+            let decompressed = compression
+                .decompress(compressed)
+                .expect("Failed to decompress data");
+            assert_eq!(data, decompressed);
+        }
+        assert!(Compression::supported().contains(&Compression::Brotli { level: 6 }));
+    }
+
+    fn dictionary_samples() -> Vec<Vec<u8>> {
+        (0..1000)
+            .map(|i| {
+                format!(
+                    "{{\"id\": {}, \"name\": \"user{}\", \"email\": \"user{}@example.com\", \"active\": {}}}",
+                    i,
+                    i * 7,
+                    i * 13,
+                    i % 2 == 0
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zstd_dictionary() {
+        let samples = dictionary_samples();
+        let dictionary = ZStdDictionary::train(&samples, 4096).expect("Failed to train");
+        let reloaded = ZStdDictionary::from_bytes(dictionary.as_bytes().to_vec()).unwrap();
+        assert_eq!(dictionary, reloaded);
+
+        let compression = Compression::ZStdDict {
+            level: 3,
+            dict_id: dictionary.id(),
+        };
+        let data = b"{\"id\": 5000, \"name\": \"user35000\", \"email\": \"user65000@example.com\", \"active\": true}".to_vec();
+        let compressed = compression.compress_with_dictionary(data.clone(), Some(&dictionary));
+        let plain = Compression::ZStd { level: 3 }.compress(data.clone());
+        assert!(compressed.len() < plain.len());
+        let decompressed = compression
+            .decompress_with_dictionary(compressed.clone(), Some(&dictionary))
+            .expect("Failed to decompress");
+        assert_eq!(data, decompressed);
+        // Without the dictionary, decompression must fail cleanly
+        assert!(matches!(
+            compression.decompress(compressed),
+            Err(CompressionError::MissingDictionary(_))
+        ));
+        assert!(ZStdDictionary::from_bytes(data).is_err());
+    }
 }
@@ -33,6 +33,9 @@ use thiserror::Error;
 #[allow(unused_imports)]
 use zeroize::Zeroize;
 
+#[allow(unused_imports)]
+use std::io::{self, Cursor, Read};
+
 use crate::repository::Key;
 
 /// Error describing things that can go wrong with encryption/decryption
@@ -77,6 +80,43 @@ impl Encryption {
         Encryption::ChaCha20 { iv }
     }
 
+    /// Picks the fastest supported encryption method for this machine
+    ///
+    /// This is `AES256CTR` if this build's AES implementation is hardware accelerated,
+    /// and `ChaCha20` otherwise, as software AES is both slower than `ChaCha20` and
+    /// prone to cache timing attacks.
+    pub fn auto() -> Encryption {
+        let aes = Encryption::new_aes256ctr();
+        let prefer_aes = aes.hardware_accelerated() || !cfg!(feature = "chacha20");
+        if cfg!(feature = "aes-ctr") && prefer_aes {
+            aes
+        } else {
+            Encryption::new_chacha20()
+        }
+    }
+
+    /// Returns true if this build's implementation of this encryption method is
+    /// hardware accelerated
+    ///
+    /// The implementations are chosen at compile time, so this depends on the target
+    /// features asuran was built with, e.g. `RUSTFLAGS="-C target-feature=+aes,+ssse3"`,
+    /// rather than on the features of the CPU it is running on. See
+    /// `CpuFeatures::detect` for those.
+    pub fn hardware_accelerated(&self) -> bool {
+        match self {
+            Encryption::NoEncryption => false,
+            Encryption::AES256CBC { .. } | Encryption::AES256CTR { .. } => cfg!(all(
+                any(target_arch = "x86", target_arch = "x86_64"),
+                target_feature = "aes",
+                target_feature = "sse2"
+            )),
+            Encryption::ChaCha20 { .. } => cfg!(all(
+                any(target_arch = "x86", target_arch = "x86_64"),
+                any(target_feature = "sse2", target_feature = "avx2")
+            )),
+        }
+    }
+
     /// Returns the key length of this encryption method in bytes
     ///
     /// `NoEncryption` has a key length of 16 bytes, as some things rely on a non-zero key
@@ -266,6 +306,56 @@ impl Encryption {
         }
     }
 
+    /// Returns a reader over the decrypted contents of `data`
+    ///
+    /// Stream ciphers decrypt the data as it is read, so a full decrypted copy is never
+    /// held in memory. AES-CBC can not be read this way, and is decrypted up front.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if decryption fails
+    ///
+    /// # Panics
+    ///
+    /// Panics if the user selects an encryption method for which support has not been
+    /// compiled in.
+    #[allow(unused_variables)]
+    pub fn decrypt_reader<'a>(&self, data: &'a [u8], key: &Key) -> Result<Box<dyn Read + 'a>> {
+        let key = key.key();
+        match self {
+            Encryption::NoEncryption => Ok(Box::new(data)),
+            Encryption::AES256CBC { .. } => {
+                Ok(Box::new(Cursor::new(self.decrypt_bytes(data, key)?)))
+            }
+            Encryption::AES256CTR { iv } => {
+                cfg_if::cfg_if! {
+                    if #[cfg(feature = "aes-ctr")] {
+                        let cipher = Aes256Ctr::new(
+                            GenericArray::from_slice(key),
+                            GenericArray::from_slice(&iv[..]),
+                        );
+                        Ok(Box::new(KeystreamReader { data, cipher }))
+                    } else {
+                        unimplemented!("Asuran has not been compiled with AES support")
+                    }
+                }
+            }
+            Encryption::ChaCha20 { iv } => {
+                cfg_if::cfg_if! {
+                    if #[cfg(feature = "chacha20")] {
+                        let cipher = ChaCha20::new(
+                            GenericArray::from_slice(key),
+                            GenericArray::from_slice(&iv[..]),
+                        );
+                        Ok(Box::new(KeystreamReader { data, cipher }))
+                    } else {
+                        unimplemented!("Asuran has not been compiled with ChaCha20 support")
+                    }
+                }
+            }
+        }
+    }
+
     /// Conviencence function to get a new tag from an old one, specifying the
     /// same algorithim, but with a new, securely generated IV
     pub fn new_iv(self) -> Encryption {
@@ -278,6 +368,59 @@ impl Encryption {
     }
 }
 
+/// Cryptographic instruction set extensions, as detected on the running CPU
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub struct CpuFeatures {
+    /// AES instructions, AES-NI on x86, or the `ARMv8` cryptography extension
+    pub aes: bool,
+    /// SIMD instructions usable by `ChaCha20`, SSE2 or AVX2 on x86, NEON on ARM
+    pub simd: bool,
+}
+
+impl CpuFeatures {
+    /// Detects the features of the CPU asuran is running on
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn detect() -> CpuFeatures {
+        CpuFeatures {
+            aes: is_x86_feature_detected!("aes"),
+            simd: is_x86_feature_detected!("sse2") || is_x86_feature_detected!("avx2"),
+        }
+    }
+
+    /// Detects the features of the CPU asuran is running on
+    #[cfg(target_arch = "aarch64")]
+    pub fn detect() -> CpuFeatures {
+        CpuFeatures {
+            aes: std::arch::is_aarch64_feature_detected!("aes"),
+            simd: std::arch::is_aarch64_feature_detected!("neon"),
+        }
+    }
+
+    /// Detects the features of the CPU asuran is running on
+    ///
+    /// Detection is not supported on this architecture, so no features are reported.
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn detect() -> CpuFeatures {
+        CpuFeatures::default()
+    }
+}
+
+/// Applies a stream cipher's keystream to data as it is read
+#[cfg(any(feature = "aes-ctr", feature = "chacha20"))]
+struct KeystreamReader<'a, C> {
+    data: &'a [u8],
+    cipher: C,
+}
+
+#[cfg(any(feature = "aes-ctr", feature = "chacha20"))]
+impl<C: SyncStreamCipher> Read for KeystreamReader<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.data.read(buf)?;
+        self.cipher.apply_keystream(&mut buf[..read]);
+        Ok(read)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -313,4 +456,47 @@ mod tests {
         let enc = Encryption::new_aes256ctr();
         test_encryption(enc);
     }
+
+    #[test]
+    fn auto_selection() {
+        let auto = Encryption::auto();
+        if Encryption::new_aes256ctr().hardware_accelerated() {
+            assert!(matches!(auto, Encryption::AES256CTR { .. }));
+        } else {
+            assert!(matches!(auto, Encryption::ChaCha20 { .. }));
+        }
+        assert!(!Encryption::NoEncryption.hardware_accelerated());
+        // An accelerated implementation can only be compiled in for a CPU that has it
+        let cpu = CpuFeatures::detect();
+        if auto.hardware_accelerated() {
+            assert!(cpu.aes || cpu.simd);
+        }
+        test_encryption(auto);
+    }
+
+    #[test]
+    fn decrypt_reader() {
+        let key = Key::random(32);
+        let data: Vec<u8> = (0..10_000_u32).map(|x| (x % 251) as u8).collect();
+        for mut enc in [
+            Encryption::NoEncryption,
+            Encryption::new_aes256cbc(),
+            Encryption::new_aes256ctr(),
+            Encryption::new_chacha20(),
+        ] {
+            let encrypted = enc.encrypt(&data, &key);
+            let mut reader = enc.decrypt_reader(&encrypted, &key).unwrap();
+            // Read in small, odd sized pieces, to make sure the keystream lines up
+            let mut decrypted = Vec::new();
+            let mut buffer = [0_u8; 7];
+            loop {
+                let read = reader.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                decrypted.extend_from_slice(&buffer[..read]);
+            }
+            assert_eq!(decrypted, data, "{enc:?}");
+        }
+    }
 }
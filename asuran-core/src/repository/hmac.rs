@@ -8,6 +8,13 @@ algorithm.
 
 As such, at least one HMAC algorithm feature must be enabled, or else you will
 get a compile time error.
+
+Every algorithm has a stable numeric identifier, which is what gets written to disk,
+so that algorithms can be added without breaking existing repositories. The
+identifiers, along with which algorithms support was compiled in for, are listed in
+the registry returned by `HMAC::registry`. Identifiers this build does not know about
+deserialize into `HMAC::Unknown`, so that a chunk written by a newer version of
+asuran can be reported as unsupported, rather than failing to parse.
 */
 #[cfg(feature = "blake2b_simd")]
 use blake2b_simd::blake2bp;
@@ -16,15 +23,20 @@ use blake2b_simd::Params;
 use cfg_if::cfg_if;
 #[allow(unused_imports)]
 use hmac::{Hmac, Mac};
-use serde::{Deserialize, Serialize};
+use serde::de::{self, Deserializer, EnumAccess, VariantAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
 #[cfg(feature = "sha2")]
 use sha2::Sha256;
 #[cfg(feature = "sha3")]
 use sha3::Sha3_256;
+#[cfg(feature = "blake3")]
+use zeroize::Zeroize;
 
 use crate::repository::Key;
 
 use std::cmp::min;
+use std::convert::TryFrom;
+use std::fmt;
 
 #[cfg(not(any(
     feature = "blake2b_simd",
@@ -40,16 +52,123 @@ type HmacSha256 = Hmac<Sha256>;
 type HmacSHA3 = Hmac<Sha3_256>;
 
 /// Tag for the HMAC algorithim used by a particular `Chunk`
-#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HMAC {
     SHA256,
     Blake2b,
     Blake2bp,
     Blake3,
     SHA3,
+    /// An algorithm this build of asuran does not know about, such as one added in a
+    /// later version, identified by its numeric identifier
+    Unknown(u32),
+}
+
+/// An entry in the registry of HMAC algorithms known to this build of asuran
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HMACAlgorithm {
+    pub hmac: HMAC,
+    /// The identifier the algorithm is stored as
+    pub id: u32,
+    pub name: &'static str,
+    /// Whether or not support for the algorithm was compiled in
+    pub supported: bool,
 }
 
+/// All the HMAC algorithms known to this build
+///
+/// Identifiers must never be changed or reused, as they are written to disk. New
+/// algorithms must be given the next unused identifier.
+const REGISTRY: [HMACAlgorithm; 5] = [
+    HMACAlgorithm {
+        hmac: HMAC::SHA256,
+        id: 0,
+        name: "SHA256",
+        supported: cfg!(feature = "sha2"),
+    },
+    HMACAlgorithm {
+        hmac: HMAC::Blake2b,
+        id: 1,
+        name: "Blake2b",
+        supported: cfg!(feature = "blake2b_simd"),
+    },
+    HMACAlgorithm {
+        hmac: HMAC::Blake2bp,
+        id: 2,
+        name: "Blake2bp",
+        supported: cfg!(feature = "blake2b_simd"),
+    },
+    HMACAlgorithm {
+        hmac: HMAC::Blake3,
+        id: 3,
+        name: "Blake3",
+        supported: cfg!(feature = "blake3"),
+    },
+    HMACAlgorithm {
+        hmac: HMAC::SHA3,
+        id: 4,
+        name: "SHA3",
+        supported: cfg!(feature = "sha3"),
+    },
+];
+
+/// The names of the known algorithms, in the order of their identifiers
+const NAMES: [&str; 5] = ["SHA256", "Blake2b", "Blake2bp", "Blake3", "SHA3"];
+
 impl HMAC {
+    /// Returns the registry of every HMAC algorithm known to this build, including the
+    /// ones support was not compiled in for
+    pub fn registry() -> &'static [HMACAlgorithm] {
+        &REGISTRY
+    }
+
+    /// Returns every algorithm this build can produce and verify HMACs with
+    pub fn supported() -> Vec<HMAC> {
+        REGISTRY
+            .iter()
+            .filter(|x| x.supported)
+            .map(|x| x.hmac)
+            .collect()
+    }
+
+    /// Looks up this algorithm in the registry, returning `None` for `Unknown`
+    fn entry(self) -> Option<&'static HMACAlgorithm> {
+        REGISTRY.iter().find(|x| x.hmac == self)
+    }
+
+    /// Returns the stable numeric identifier of this algorithm
+    pub fn algorithm_id(self) -> u32 {
+        match self {
+            HMAC::Unknown(id) => id,
+            // Every other variant has an entry in the registry
+            hmac => hmac.entry().map_or(u32::MAX, |x| x.id),
+        }
+    }
+
+    /// Finds the algorithm with the provided identifier, returning `HMAC::Unknown` if this
+    /// build does not know of it
+    pub fn from_algorithm_id(id: u32) -> HMAC {
+        REGISTRY
+            .iter()
+            .find(|x| x.id == id)
+            .map_or(HMAC::Unknown(id), |x| x.hmac)
+    }
+
+    /// Finds the known algorithm with the provided name
+    pub fn from_name(name: &str) -> Option<HMAC> {
+        REGISTRY.iter().find(|x| x.name == name).map(|x| x.hmac)
+    }
+
+    /// Returns the name of this algorithm
+    pub fn name(self) -> &'static str {
+        self.entry().map_or("Unknown", |x| x.name)
+    }
+
+    /// Returns true if this build can produce and verify HMACs with this algorithm
+    pub fn is_supported(self) -> bool {
+        self.entry().is_some_and(|x| x.supported)
+    }
+
     /// Produces an HMAC for the given data with the given key, using the algorithm
     /// specified by the variant of `self`.
     ///
@@ -106,7 +225,9 @@ impl HMAC {
                         let mut tmp_key = [0_u8; 32];
                         let len = min(32, key.len());
                         tmp_key[..len].copy_from_slice(&key[..len]);
-                        blake3::keyed_hash(&tmp_key, data).as_bytes().to_vec()
+                        let hash = blake3::keyed_hash(&tmp_key, data).as_bytes().to_vec();
+                        tmp_key.zeroize();
+                        hash
                     } else {
                         unimplemented!("Asuran was not compiled with BLAKE3 support")
                     }
@@ -124,6 +245,7 @@ impl HMAC {
                     }
                 }
             }
+            HMAC::Unknown(id) => unimplemented!("Asuran does not know of an HMAC with id {}", id),
         }
     }
 
@@ -133,7 +255,8 @@ impl HMAC {
     /// # Panics
     ///
     /// Will panic if the user has selected an algorithm for which support has not been
-    /// compiled in.
+    /// compiled in. `Repository` refuses to be constructed with such an algorithm, see
+    /// `ChunkSettings::check_supported`.
     pub fn mac(self, data: &[u8], key: &Key) -> Vec<u8> {
         let key = key.hmac_key();
         self.internal_mac(data, key)
@@ -145,7 +268,8 @@ impl HMAC {
     /// # Panics
     ///
     /// Will panic if the user has selected an algorithm for which support has not been
-    /// compiled in.
+    /// compiled in. `Repository` refuses to be constructed with such an algorithm, see
+    /// `ChunkSettings::check_supported`.
     pub fn id(self, data: &[u8], key: &Key) -> Vec<u8> {
         let key = key.id_key();
         self.internal_mac(data, key)
@@ -156,12 +280,13 @@ impl HMAC {
     /// of `self`, and verifies it against the supplied HMAC, using constant time
     /// comparisons where possible.
     ///
-    /// # Panics
-    ///
-    /// Panics if the user has selected an algorithm for which support has not been
-    /// compiled in.
-    #[allow(unused_variables)]
+    /// Will always return false for algorithms this build does not support.
+    // The unsupported arms are unreachable, as they are checked for up front
+    #[allow(unused_variables, clippy::missing_panics_doc)]
     pub fn verify_hmac(self, input_mac: &[u8], data: &[u8], key: &Key) -> bool {
+        if !self.is_supported() {
+            return false;
+        }
         let key = key.hmac_key();
         match self {
             HMAC::SHA256 => {
@@ -206,6 +331,7 @@ impl HMAC {
                         tmp_key.copy_from_slice(&key[..32]);
                         let input_hash = blake3::Hash::from(tmp_hash);
                         let output_hash = blake3::keyed_hash(&tmp_key, data);
+                        tmp_key.zeroize();
                         output_hash.eq(&input_hash)
                     } else {
                         unimplemented!("Asuran was not compiled with BLAKE3 support")
@@ -225,6 +351,130 @@ impl HMAC {
                     }
                 }
             }
+            HMAC::Unknown(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for HMAC {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HMAC::Unknown(id) => write!(f, "Unknown (id {id})"),
+            hmac => write!(f, "{}", hmac.name()),
+        }
+    }
+}
+
+/// Serialized as a unit variant with the algorithm's identifier as its index, which
+/// matches the layout of the derived implementation this replaced.
+impl Serialize for HMAC {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit_variant("HMAC", self.algorithm_id(), self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for HMAC {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HMAC, D::Error> {
+        deserializer.deserialize_enum("HMAC", &NAMES, HMACVisitor)
+    }
+}
+
+struct HMACVisitor;
+
+impl<'de> Visitor<'de> for HMACVisitor {
+    type Value = HMAC;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("an HMAC algorithm")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<HMAC, A::Error> {
+        let (HMACIdentifier(hmac), variant) = data.variant()?;
+        variant.unit_variant()?;
+        Ok(hmac)
+    }
+}
+
+/// The variant identifier of a serialized `HMAC`, either its numeric identifier or its
+/// name, depending on the format
+struct HMACIdentifier(HMAC);
+
+impl<'de> Deserialize<'de> for HMACIdentifier {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HMACIdentifier, D::Error> {
+        deserializer.deserialize_identifier(HMACIdentifierVisitor)
+    }
+}
+
+struct HMACIdentifierVisitor;
+
+impl Visitor<'_> for HMACIdentifierVisitor {
+    type Value = HMACIdentifier;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("an HMAC algorithm identifier or name")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<HMACIdentifier, E> {
+        let id = u32::try_from(value).map_err(|_| {
+            E::invalid_value(de::Unexpected::Unsigned(value), &"a 32 bit identifier")
+        })?;
+        Ok(HMACIdentifier(HMAC::from_algorithm_id(id)))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<HMACIdentifier, E> {
+        HMAC::from_name(value)
+            .map(HMACIdentifier)
+            .ok_or_else(|| E::unknown_variant(value, &NAMES))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_ids_are_unique_and_ordered() {
+        for (index, entry) in HMAC::registry().iter().enumerate() {
+            assert_eq!(entry.id as usize, index);
+            assert_eq!(entry.name, NAMES[index]);
+            assert_eq!(HMAC::from_algorithm_id(entry.id), entry.hmac);
+            assert_eq!(entry.hmac.algorithm_id(), entry.id);
+            assert_eq!(HMAC::from_name(entry.name), Some(entry.hmac));
+        }
+        assert_eq!(HMAC::from_algorithm_id(1000), HMAC::Unknown(1000));
+        assert!(!HMAC::Unknown(1000).is_supported());
+    }
+
+    /// A copy of `HMAC` as it was before the registry, with a derived implementation
+    #[derive(Serialize)]
+    enum Legacy {
+        SHA256,
+        Blake2b,
+        Blake2bp,
+        Blake3,
+        SHA3,
+    }
+
+    /// The serialized form must stay identical to the one produced by the derived
+    /// implementation, which encoded variants by their index
+    #[test]
+    fn serialization_is_stable() {
+        let pairs = vec![
+            (Legacy::SHA256, HMAC::SHA256),
+            (Legacy::Blake2b, HMAC::Blake2b),
+            (Legacy::Blake2bp, HMAC::Blake2bp),
+            (Legacy::Blake3, HMAC::Blake3),
+            (Legacy::SHA3, HMAC::SHA3),
+        ];
+        for (legacy, hmac) in pairs {
+            let legacy_bytes = rmp_serde::to_vec(&legacy).unwrap();
+            assert_eq!(rmp_serde::to_vec(&hmac).unwrap(), legacy_bytes);
+            let output: HMAC = rmp_serde::from_slice(&legacy_bytes).unwrap();
+            assert_eq!(output, hmac);
         }
+        // Identifiers from the future survive a round trip
+        let bytes = rmp_serde::to_vec(&HMAC::Unknown(77)).unwrap();
+        let output: HMAC = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(output, HMAC::Unknown(77));
     }
 }
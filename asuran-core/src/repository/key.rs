@@ -10,9 +10,10 @@ use rmp_serde::{Deserializer, Serializer};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::{error, trace};
-use zeroize::Zeroize;
+use zeroize::{Zeroize, Zeroizing};
 
 use std::convert::TryInto;
+use std::fmt;
 
 /// Error describing things that can go wrong with key handling
 #[derive(Error, Debug)]
@@ -46,7 +47,10 @@ type Result<T> = std::result::Result<T, KeyError>;
 /// - `chunker_nonce`:
 ///
 /// A random `u64` used for chunker randomization with supported chunking algorithms
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Zeroize)]
+///
+/// The key material is wiped from memory when the `Key` is dropped, and is left out of its
+/// `Debug` output, so it does not end up in logs or traces.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Zeroize)]
 #[zeroize(drop)]
 pub struct Key {
     key: Vec<u8>,
@@ -62,9 +66,12 @@ impl Key {
     ///
     /// Does not perform any padding.
     pub fn from_bytes(bytes: &[u8], chunker_nonce: u64) -> Key {
-        let mut buffer1 = Vec::new();
-        let mut buffer2 = Vec::new();
-        let mut buffer3 = Vec::new();
+        // Allocate the buffers up front, as growing them would leave copies of the key
+        // material behind in freed memory
+        let length = bytes.len().div_ceil(3);
+        let mut buffer1 = Vec::with_capacity(length);
+        let mut buffer2 = Vec::with_capacity(length);
+        let mut buffer3 = Vec::with_capacity(length);
         for (i, byte) in bytes.iter().enumerate() {
             match i % 3 {
                 0 => buffer1.push(*byte),
@@ -122,6 +129,14 @@ impl Key {
     }
 }
 
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("length", &self.key.len())
+            .finish_non_exhaustive()
+    }
+}
+
 /// Stores the key, encrypted with another key derived from the user specified
 /// password/passphrase
 ///
@@ -141,7 +156,7 @@ pub struct EncryptedKey {
 
 impl EncryptedKey {
     /// Produces an encrypted key from the specified user key and encryption method
-    #[tracing::instrument(level = "trace")]
+    #[tracing::instrument(level = "trace", skip(user_key))]
     pub fn encrypt(
         key: &Key,
         mem_cost: u32,
@@ -149,11 +164,13 @@ impl EncryptedKey {
         mut encryption: Encryption,
         user_key: &[u8],
     ) -> EncryptedKey {
-        // Serialize the key
-        let mut key_buffer = Vec::<u8>::new();
+        // Serialize the key, into a buffer that is wiped once the key has been encrypted.
+        // Reserve enough room up front, so growing the buffer does not leave copies behind
+        let capacity = key.key.len() + key.hmac_key.len() + key.id_key.len() + 64;
+        let mut key_buffer = Zeroizing::new(Vec::<u8>::with_capacity(capacity));
         // Since were are serializing to a Vec::<u8>, and Key does not contain any types that
         // can fail to serialize, this call to unwrap should be infallible
-        key.serialize(&mut Serializer::new(&mut key_buffer))
+        key.serialize(&mut Serializer::new(&mut *key_buffer))
             .unwrap();
         // Generate a salt
         let mut salt = [0; 32];
@@ -174,8 +191,10 @@ impl EncryptedKey {
                 .expect("Key length was too large (larger than usize)"),
         };
 
-        let generated_key_bytes = argon2::hash_raw(&user_key, &salt, &config)
-            .expect("Unable to hash password with argon2, most likely due to invalid settings.");
+        let generated_key_bytes =
+            Zeroizing::new(argon2::hash_raw(user_key, &salt, &config).expect(
+                "Unable to hash password with argon2, most likely due to invalid settings.",
+            ));
         let encrypted_bytes = encryption.encrypt_bytes(&key_buffer, &generated_key_bytes);
         trace!("Encrypted key");
         EncryptedKey {
@@ -195,7 +214,7 @@ impl EncryptedKey {
     /// - `mem_cost`: 65536
     /// - `time_cost`: 10
     #[cfg_attr(tarpaulin, skip)]
-    #[tracing::instrument(level = "trace")]
+    #[tracing::instrument(level = "trace", skip(user_key))]
     pub fn encrypt_defaults(key: &Key, encryption: Encryption, user_key: &[u8]) -> EncryptedKey {
         trace!("Encrypting key with default settings");
         EncryptedKey::encrypt(key, 65536, 10, encryption, user_key)
@@ -206,7 +225,7 @@ impl EncryptedKey {
     /// # Errors:
     ///
     /// Will return `Err(KeyError)` if key decryption fails
-    #[tracing::instrument(level = "error")]
+    #[tracing::instrument(level = "error", skip(user_key))]
     pub fn decrypt(&self, user_key: &[u8]) -> Result<Key> {
         // Derive the key from the user key
         let config = Config {
@@ -224,11 +243,12 @@ impl EncryptedKey {
                 .try_into()
                 .expect("Key length was too large (larger than usize)"),
         };
-        let generated_key_bytes = argon2::hash_raw(&user_key, &self.salt, &config)?;
-        // Decrypt the key
-        let key_bytes = self
-            .encryption
-            .decrypt_bytes(&self.encrypted_bytes, &generated_key_bytes)?;
+        let generated_key_bytes = Zeroizing::new(argon2::hash_raw(user_key, &self.salt, &config)?);
+        // Decrypt the key, wiping the serialized copy once it has been deserialized
+        let key_bytes = Zeroizing::new(
+            self.encryption
+                .decrypt_bytes(&self.encrypted_bytes, &generated_key_bytes)?,
+        );
         // Deserialize the key
         let mut de = Deserializer::new(&key_bytes[..]);
         let key: Key = Deserialize::deserialize(&mut de)?;
@@ -261,4 +281,11 @@ mod tests {
         assert_eq!(key.id_key, [3, 3, 3]);
         assert_eq!(key.chunker_nonce(), 4);
     }
+
+    #[test]
+    fn debug_hides_key_material() {
+        let key = Key::from_bytes(&[171; 9], 4);
+        let debug = format!("{key:?}");
+        assert!(!debug.contains("171"));
+    }
 }
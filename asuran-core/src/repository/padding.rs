@@ -0,0 +1,148 @@
+/*!
+This module contains the padding schemes that can be applied to chunks before
+encryption, to obscure their exact sizes.
+
+Even with encryption, the size of each chunk stored in a repository is visible to
+anyone who can see the backend, and the sizes of the chunks a file is split into can
+be enough to identify that file. Padding rounds the size of the encrypted data up, so
+that many different chunk sizes become the same stored size.
+
+Padding is applied after compression, and takes the form of a single `0x80` byte
+followed by as many zero bytes as are needed to reach the padded length. This is
+stripped again after decryption.
+*/
+use serde::{Deserialize, Serialize};
+
+/// Marks the start of the padding
+const PADDING_MARKER: u8 = 0x80;
+
+/// The padding scheme applied to a chunk
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub enum Padding {
+    /// Chunks are stored at their exact size
+    #[default]
+    NoPadding,
+    /// Padmé padding, which rounds lengths up so that only the top bits of the length
+    /// remain significant
+    ///
+    /// This wastes at most 12% of space, less for larger chunks, while leaving only
+    /// `O(log log n)` bits of information in the length.
+    Padme,
+    /// Lengths are rounded up to the next power of two
+    ///
+    /// This leaks less about the chunk than `Padme`, at the cost of wasting up to half of
+    /// the space used.
+    PowerOfTwo,
+}
+
+impl Padding {
+    /// Returns true if this is `NoPadding`
+    pub fn is_none(&self) -> bool {
+        *self == Padding::NoPadding
+    }
+
+    /// Returns the length `length` bytes of data, plus the padding marker, are rounded
+    /// up to
+    pub fn padded_length(self, length: usize) -> usize {
+        match self {
+            Padding::NoPadding => length,
+            Padding::Padme => padme(length + 1),
+            Padding::PowerOfTwo => (length + 1).next_power_of_two(),
+        }
+    }
+
+    /// Pads `data` to its padded length
+    pub fn pad(self, mut data: Vec<u8>) -> Vec<u8> {
+        if self.is_none() {
+            return data;
+        }
+        let length = self.padded_length(data.len());
+        data.reserve_exact(length - data.len());
+        data.push(PADDING_MARKER);
+        data.resize(length, 0);
+        data
+    }
+
+    /// Removes the padding from `data`
+    ///
+    /// Returns `None` if the padding is malformed.
+    pub fn strip(self, mut data: Vec<u8>) -> Option<Vec<u8>> {
+        if self.is_none() {
+            return Some(data);
+        }
+        let marker = data.iter().rposition(|x| *x != 0)?;
+        if data[marker] != PADDING_MARKER {
+            return None;
+        }
+        data.truncate(marker);
+        Some(data)
+    }
+}
+
+/// Rounds `length` up to the nearest Padmé length
+///
+/// See [Reducing Metadata Leakage from Encrypted Files and Communication with
+/// PURBs](https://arxiv.org/abs/1806.03160).
+fn padme(length: usize) -> usize {
+    if length < 2 {
+        return length;
+    }
+    // Number of bits needed for the exponent of the length, the rest are rounded away
+    let exponent = usize::BITS - 1 - length.leading_zeros();
+    let exponent_bits = u32::BITS - exponent.leading_zeros();
+    let mask = (1_usize << (exponent - exponent_bits)) - 1;
+    (length + mask) & !mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padme_lengths() {
+        let expected = [
+            (0, 0),
+            (1, 1),
+            (2, 2),
+            (9, 10),
+            (100, 104),
+            (1000, 1024),
+            (65_537, 67_584),
+        ];
+        for (length, padded) in &expected {
+            assert_eq!(padme(*length), *padded);
+        }
+        // Padmé never wastes more than 12%
+        for length in (2..1_000_000).step_by(997) {
+            let padded = padme(length);
+            assert!(padded >= length);
+            assert!((padded - length) * 100 <= length * 12);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let paddings = [Padding::NoPadding, Padding::Padme, Padding::PowerOfTwo];
+        for padding in &paddings {
+            for length in &[0_usize, 1, 7, 100, 1000, 4095, 4096, 70_000] {
+                // Trailing zeros in the data must survive
+                let mut data = vec![1_u8; *length];
+                if let Some(last) = data.last_mut() {
+                    *last = 0;
+                }
+                let padded = padding.pad(data.clone());
+                assert_eq!(padded.len(), padding.padded_length(*length));
+                assert_eq!(padding.strip(padded), Some(data));
+            }
+        }
+        assert_eq!(Padding::PowerOfTwo.pad(vec![1; 1000]).len(), 1024);
+        assert_eq!(Padding::PowerOfTwo.pad(vec![1; 1024]).len(), 2048);
+    }
+
+    #[test]
+    fn malformed_padding() {
+        assert_eq!(Padding::Padme.strip(vec![0; 16]), None);
+        assert_eq!(Padding::Padme.strip(vec![1, 2, 3, 0]), None);
+        assert_eq!(Padding::Padme.strip(Vec::new()), None);
+    }
+}
@@ -0,0 +1,250 @@
+/*!
+Timestamps, as recorded in repositories
+
+Every point in time asuran records, such as when an archive was started or when a lock
+was taken, is a `Timestamp`. A timestamp is an instant in UTC, and is compared, ordered,
+and displayed as one, no matter what timezone the machine that recorded it was in.
+
+Repositories written by older versions of asuran recorded timestamps in the local
+timezone of the machine making them. Those still decode, and the offset they were
+recorded with is kept around, but only so that they encode back to exactly the same bytes,
+keeping the HMACs and signatures covering them valid. New timestamps are always recorded
+in UTC.
+
+Conversions to and from `SystemTime`, Unix time, RFC 3339, and `chrono` are provided, so
+consumers of asuran do not have to depend on any particular time library.
+*/
+use chrono::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime};
+
+/// Error for a string that could not be parsed as a timestamp
+#[derive(Error, Debug)]
+#[error("Invalid timestamp: {0}")]
+pub struct TimestampError(String);
+
+/// An instant in time, in UTC
+#[derive(Clone, Copy)]
+pub struct Timestamp {
+    instant: DateTime<Utc>,
+    /// The offset from UTC the timestamp was recorded with, which is only ever used to
+    /// encode it back the way it was decoded
+    recorded_offset: FixedOffset,
+}
+
+impl Timestamp {
+    /// Returns the current time
+    pub fn now() -> Timestamp {
+        Timestamp::from_chrono(&Utc::now())
+    }
+
+    /// Creates a timestamp from the number of seconds and nanoseconds since the Unix epoch
+    ///
+    /// Returns `None` if the time is out of range, or `nanos` is not less than a second.
+    pub fn from_unix(seconds: i64, nanos: u32) -> Option<Timestamp> {
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        Utc.timestamp_opt(seconds, nanos)
+            .single()
+            .map(|instant| Timestamp::from_chrono(&instant))
+    }
+
+    /// Returns the number of whole seconds since the Unix epoch, negative for times before it
+    pub fn unix_seconds(&self) -> i64 {
+        self.instant.timestamp()
+    }
+
+    /// Returns the number of nanoseconds past the whole second
+    pub fn subsec_nanos(&self) -> u32 {
+        self.instant.timestamp_subsec_nanos()
+    }
+
+    /// Creates a timestamp from a `SystemTime`
+    pub fn from_system_time(time: SystemTime) -> Timestamp {
+        Timestamp::from_chrono(&DateTime::<Utc>::from(time))
+    }
+
+    /// Converts the timestamp to a `SystemTime`
+    pub fn to_system_time(&self) -> SystemTime {
+        SystemTime::from(self.instant)
+    }
+
+    /// Parses an RFC 3339 timestamp, such as `2020-03-01T12:00:00+01:00`
+    ///
+    /// The timestamp may be given in any timezone, and is converted to UTC.
+    pub fn parse_rfc3339(input: &str) -> Result<Timestamp, TimestampError> {
+        DateTime::parse_from_rfc3339(input)
+            .map(|time| Timestamp::from_chrono(&time))
+            .map_err(|error| TimestampError(format!("{input}: {error}")))
+    }
+
+    /// Formats the timestamp as an RFC 3339 timestamp in UTC
+    pub fn to_rfc3339(&self) -> String {
+        self.instant.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+    }
+
+    /// Formats the timestamp as an RFC 2822 timestamp in UTC, as used in email headers
+    pub fn to_rfc2822(&self) -> String {
+        self.instant.to_rfc2822()
+    }
+
+    /// Returns the time that has passed between `earlier` and this timestamp, or `None` if
+    /// `earlier` is later than this timestamp
+    pub fn duration_since(&self, earlier: Timestamp) -> Option<Duration> {
+        self.instant
+            .signed_duration_since(earlier.instant)
+            .to_std()
+            .ok()
+    }
+
+    /// Returns the time that has passed since this timestamp, or zero if it is in the future
+    pub fn elapsed(&self) -> Duration {
+        Timestamp::now().duration_since(*self).unwrap_or_default()
+    }
+
+    /// Returns the timestamp `duration` after this one, or `None` if it is out of range
+    pub fn checked_add(&self, duration: Duration) -> Option<Timestamp> {
+        let duration = chrono::Duration::from_std(duration).ok()?;
+        self.instant
+            .checked_add_signed(duration)
+            .map(|instant| Timestamp::from_chrono(&instant))
+    }
+
+    /// Returns the timestamp `duration` before this one, or `None` if it is out of range
+    pub fn checked_sub(&self, duration: Duration) -> Option<Timestamp> {
+        let duration = chrono::Duration::from_std(duration).ok()?;
+        self.instant
+            .checked_sub_signed(duration)
+            .map(|instant| Timestamp::from_chrono(&instant))
+    }
+
+    /// Creates a timestamp from a `chrono` date and time in any timezone
+    pub fn from_chrono<Tz: TimeZone>(time: &DateTime<Tz>) -> Timestamp {
+        Timestamp {
+            instant: time.with_timezone(&Utc),
+            recorded_offset: FixedOffset::east(0),
+        }
+    }
+
+    /// Converts the timestamp to a `chrono` date and time in UTC
+    ///
+    /// Use `DateTime::with_timezone` to display it in another timezone, such as `Local`.
+    pub fn to_chrono(&self) -> DateTime<Utc> {
+        self.instant
+    }
+}
+
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Timestamp) -> bool {
+        self.instant == other.instant
+    }
+}
+
+impl Eq for Timestamp {}
+
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Timestamp) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Timestamp) -> Ordering {
+        self.instant.cmp(&other.instant)
+    }
+}
+
+impl Hash for Timestamp {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.instant.hash(state);
+    }
+}
+
+impl fmt::Debug for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timestamp({})", self.to_rfc3339())
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_rfc3339())
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Timestamp {
+        Timestamp::from_system_time(time)
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> SystemTime {
+        timestamp.to_system_time()
+    }
+}
+
+/// Timestamps are encoded the same way `chrono` encodes a `DateTime<FixedOffset>`, which
+/// is what they were recorded as before this type existed
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.instant
+            .with_timezone(&self.recorded_offset)
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        let time = DateTime::<FixedOffset>::deserialize(deserializer)?;
+        Ok(Timestamp {
+            instant: time.with_timezone(&Utc),
+            recorded_offset: *time.offset(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Timestamps recorded in a local timezone should encode back to the exact same bytes,
+    // while comparing equal to the same instant recorded in UTC
+    #[test]
+    fn offsets_round_trip() {
+        let local = DateTime::parse_from_rfc3339("2020-03-01T13:30:00.5+01:30").unwrap();
+        let bytes = rmp_serde::encode::to_vec(&local).unwrap();
+        let timestamp: Timestamp = rmp_serde::decode::from_slice(&bytes).unwrap();
+        assert_eq!(rmp_serde::encode::to_vec(&timestamp).unwrap(), bytes);
+        let utc = Timestamp::parse_rfc3339("2020-03-01T12:00:00.5Z").unwrap();
+        assert_eq!(timestamp, utc);
+        assert_eq!(timestamp.to_rfc3339(), "2020-03-01T12:00:00.500Z");
+        // New timestamps are recorded in UTC
+        let bytes = rmp_serde::encode::to_vec(&utc).unwrap();
+        let decoded: DateTime<FixedOffset> = rmp_serde::decode::from_slice(&bytes).unwrap();
+        assert_eq!(decoded.offset(), &FixedOffset::east(0));
+    }
+
+    #[test]
+    fn conversions() {
+        let timestamp = Timestamp::from_unix(1_583_064_000, 250).unwrap();
+        assert_eq!(timestamp.unix_seconds(), 1_583_064_000);
+        assert_eq!(timestamp.subsec_nanos(), 250);
+        assert_eq!(
+            Timestamp::from_system_time(timestamp.to_system_time()),
+            timestamp
+        );
+        assert!(Timestamp::from_unix(0, 1_000_000_000).is_none());
+        let later = timestamp.checked_add(Duration::from_secs(90)).unwrap();
+        assert_eq!(later.duration_since(timestamp), Some(Duration::from_secs(90)));
+        assert_eq!(timestamp.duration_since(later), None);
+        assert_eq!(later.checked_sub(Duration::from_secs(90)), Some(timestamp));
+        assert!(Timestamp::parse_rfc3339("yesterday").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * C bindings for the asuran archiver
+ *
+ * All handles are opaque, and must only be created and destroyed through the functions in
+ * this header. Every function taking a handle requires it to be valid, and not concurrently
+ * used from another thread. Every string argument must be a valid, NUL terminated, utf-8
+ * string. Every fallible function returns an asuran_status, with a description of the last
+ * failure on the calling thread available from asuran_last_error.
+ */
+#ifndef ASURAN_H
+#define ASURAN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum asuran_status {
+    ASURAN_OK = 0,
+    /* A required pointer argument was null */
+    ASURAN_NULL_ARGUMENT = 1,
+    /* A string argument was not valid utf-8 */
+    ASURAN_INVALID_UTF8 = 2,
+    /* The repository key could not be decrypted with the provided password */
+    ASURAN_BAD_PASSWORD = 3,
+    /* The repository, archive, or object does not exist */
+    ASURAN_NOT_FOUND = 4,
+    /* A read or write callback reported failure */
+    ASURAN_CALLBACK = 5,
+    /* The repository or its backend reported an error */
+    ASURAN_REPOSITORY = 6,
+    /* The library panicked, the handles involved should be considered poisoned */
+    ASURAN_PANIC = 7,
+} asuran_status;
+
+typedef struct AsuranRepository asuran_repository;
+typedef struct AsuranArchive asuran_archive;
+typedef struct AsuranArchiveList asuran_archive_list;
+
+/*
+ * Supplies up to length bytes of an object being stored, returning the number of bytes
+ * written to buffer, 0 at the end of the stream, or a negative value on error.
+ */
+typedef intptr_t (*asuran_read_fn)(void *user_data, uint8_t *buffer, size_t length);
+
+/*
+ * Receives length bytes of an object being extracted, returning 0 on success or any other
+ * value on error.
+ */
+typedef int (*asuran_write_fn)(void *user_data, const uint8_t *buffer, size_t length);
+
+/*
+ * Returns a description of the last error on the calling thread, or NULL if the last call
+ * succeeded. The string remains valid until the next call into the library on this thread.
+ */
+const char *asuran_last_error(void);
+
+/*
+ * Opens the repository at path, storing a handle in *out. Directories are opened as
+ * MultiFile repositories, and files as FlatFile repositories.
+ */
+asuran_status asuran_repository_open(const char *path, const char *password,
+                                     asuran_repository **out);
+
+/* Closes a repository, flushing pending writes, and frees its handle. NULL is a no-op. */
+void asuran_repository_close(asuran_repository *repo);
+
+/* Lists the archives in a repository, oldest first, storing the list in *out */
+asuran_status asuran_repository_archives(asuran_repository *repo, asuran_archive_list **out);
+
+/* Returns the number of archives in a list */
+size_t asuran_archive_list_len(const asuran_archive_list *list);
+
+/*
+ * Returns the name of the archive at index, or NULL if index is out of range. The string is
+ * owned by the list.
+ */
+const char *asuran_archive_list_name(const asuran_archive_list *list, size_t index);
+
+/* Returns the time the archive at index was taken, in seconds since the unix epoch */
+int64_t asuran_archive_list_timestamp(const asuran_archive_list *list, size_t index);
+
+/* Frees an archive list. NULL is a no-op. */
+void asuran_archive_list_free(asuran_archive_list *list);
+
+/* Starts a new, empty archive, storing a handle in *out */
+asuran_status asuran_archive_new(const char *name, asuran_archive **out);
+
+/* Loads the most recent archive called name from a repository, storing a handle in *out */
+asuran_status asuran_archive_open(asuran_repository *repo, const char *name,
+                                  asuran_archive **out);
+
+/* Frees an archive without committing it. NULL is a no-op. */
+void asuran_archive_free(asuran_archive *archive);
+
+/*
+ * Stores an object at path in an archive, with its contents supplied by read. read may be
+ * called from a thread other than the caller's, but never concurrently, and is not called
+ * after this function returns.
+ */
+asuran_status asuran_archive_store(asuran_repository *repo, asuran_archive *archive,
+                                   const char *path, asuran_read_fn read, void *user_data);
+
+/*
+ * Stores an object at path in an archive, with the length bytes at data as its contents.
+ * data may be NULL if length is 0.
+ */
+asuran_status asuran_archive_store_buffer(asuran_repository *repo, asuran_archive *archive,
+                                          const char *path, const uint8_t *data,
+                                          size_t length);
+
+/* Writes an archive to the repository, and frees its handle, even if committing fails */
+asuran_status asuran_archive_commit(asuran_repository *repo, asuran_archive *archive);
+
+/* Extracts the object at path in an archive, passing its contents to write */
+asuran_status asuran_archive_extract(asuran_repository *repo, asuran_archive *archive,
+                                     const char *path, asuran_write_fn write,
+                                     void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ASURAN_H */
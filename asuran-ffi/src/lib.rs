@@ -0,0 +1,789 @@
+/*!
+The `asuran-ffi` crate exposes a small, stable C ABI over `asuran`, so that applications
+written in other languages can embed the engine rather than shelling out to `asuran-cli`.
+
+Every object handed across the boundary is an opaque handle, created and destroyed by this
+library. Every fallible function returns an [`AsuranStatus`], with a description of the last
+failure on the calling thread available from [`asuran_last_error`]. Panics are caught before
+they can unwind into foreign code, and reported as [`AsuranStatus::Panic`].
+
+The matching C header lives in `include/asuran.h`.
+ */
+#![allow(clippy::missing_safety_doc)] // The safety requirements are documented in the header
+
+use asuran::chunker::FastCDC;
+use asuran::manifest::archive::ArchiveError;
+use asuran::manifest::{ActiveArchive, Manifest};
+use asuran::repository::backend::flatfile::FlatFile;
+use asuran::repository::backend::multifile::MultiFile;
+use asuran::repository::backend::{Backend, BackendObject, Manifest as _};
+use asuran::repository::Repository;
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Result codes returned by every fallible function
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AsuranStatus {
+    /// The operation succeeded
+    Ok = 0,
+    /// A required pointer argument was null
+    NullArgument = 1,
+    /// A string argument was not valid utf-8
+    InvalidUtf8 = 2,
+    /// The repository key could not be decrypted with the provided password
+    BadPassword = 3,
+    /// The repository, archive, or object does not exist
+    NotFound = 4,
+    /// A read or write callback reported failure
+    Callback = 5,
+    /// The repository or its backend reported an error
+    Repository = 6,
+    /// The library panicked, the handles involved should be considered poisoned
+    Panic = 7,
+}
+
+/// Callback used to supply the contents of an object being stored
+///
+/// Must fill up to `length` bytes of `buffer`, returning the number of bytes written, `0` at
+/// the end of the stream, or a negative value on error.
+pub type AsuranReadFn =
+    unsafe extern "C" fn(user_data: *mut c_void, buffer: *mut u8, length: usize) -> isize;
+
+/// Callback used to receive the contents of an object being extracted
+///
+/// Must consume all `length` bytes of `buffer`, returning `0` on success or any other value on
+/// error.
+pub type AsuranWriteFn =
+    unsafe extern "C" fn(user_data: *mut c_void, buffer: *const u8, length: usize) -> c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Records `message` as the last error on this thread, and returns `status`
+fn fail(status: AsuranStatus, message: impl Display) -> AsuranStatus {
+    // Interior NULs would truncate the message, so replace them rather than dropping it
+    let message = message.to_string().replace('\0', "\u{FFFD}");
+    let message = CString::new(message).expect("NULs were just removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    status
+}
+
+/// Runs the body of an exported function, clearing the last error first and converting panics
+/// into `AsuranStatus::Panic`
+fn guard(body: impl FnOnce() -> AsuranStatus) -> AsuranStatus {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(status) => status,
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| (*s).to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Unknown panic".to_string());
+            fail(AsuranStatus::Panic, format!("asuran panicked: {}", message))
+        }
+    }
+}
+
+/// Converts a C string argument into a `&str`
+unsafe fn string_arg<'a>(pointer: *const c_char, name: &str) -> Result<&'a str, AsuranStatus> {
+    if pointer.is_null() {
+        return Err(fail(
+            AsuranStatus::NullArgument,
+            format!("{} must not be null", name),
+        ));
+    }
+    CStr::from_ptr(pointer)
+        .to_str()
+        .map_err(|_| fail(AsuranStatus::InvalidUtf8, format!("{} is not utf-8", name)))
+}
+
+/// Converts a handle argument into a reference
+unsafe fn handle_arg<'a, T>(pointer: *mut T, name: &str) -> Result<&'a mut T, AsuranStatus> {
+    pointer.as_mut().ok_or_else(|| {
+        fail(
+            AsuranStatus::NullArgument,
+            format!("{} must not be null", name),
+        )
+    })
+}
+
+/// Unwraps the `Result`, returning its error status from the enclosing function
+macro_rules! try_status {
+    ($e:expr) => {
+        match $e {
+            Ok(value) => value,
+            Err(status) => return status,
+        }
+    };
+}
+
+/// An open repository
+pub struct AsuranRepository {
+    repo: Repository<BackendObject>,
+    manifest: Manifest<BackendObject>,
+    /// Dropping this stops the executor threads
+    shutdown: piper::Sender<()>,
+    threads: Vec<JoinHandle<Option<()>>>,
+}
+
+/// An archive, either being built up for storage or loaded from a repository
+pub struct AsuranArchive {
+    archive: ActiveArchive,
+}
+
+/// A snapshot of the archives in a repository
+pub struct AsuranArchiveList {
+    names: Vec<CString>,
+    timestamps: Vec<i64>,
+}
+
+/// Returns a description of the last error on the calling thread, or null if the last call
+/// succeeded
+///
+/// The string is owned by the library and remains valid until the next call into the library
+/// on the same thread.
+#[no_mangle]
+pub extern "C" fn asuran_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Opens the repository at `path`, storing a handle to it in `out`
+///
+/// Directories are opened as MultiFile repositories, and files as FlatFile repositories.
+#[no_mangle]
+pub unsafe extern "C" fn asuran_repository_open(
+    path: *const c_char,
+    password: *const c_char,
+    out: *mut *mut AsuranRepository,
+) -> AsuranStatus {
+    guard(|| {
+        let path = Path::new(try_status!(string_arg(path, "path")));
+        let password = try_status!(string_arg(password, "password"));
+        let out = try_status!(handle_arg(out, "out"));
+        if !path.exists() {
+            return fail(
+                AsuranStatus::NotFound,
+                format!("No repository at {:?}", path),
+            );
+        }
+
+        let (shutdown, receiver) = piper::chan::<()>(0);
+        let threads: Vec<_> = (0..num_cpus::get_physical())
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || smol::run(receiver.recv()))
+            })
+            .collect();
+        match smol::block_on(open(path, password)) {
+            Ok((repo, manifest)) => {
+                *out = Box::into_raw(Box::new(AsuranRepository {
+                    repo,
+                    manifest,
+                    shutdown,
+                    threads,
+                }));
+                AsuranStatus::Ok
+            }
+            Err(status) => {
+                drop(shutdown);
+                for thread in threads {
+                    let _ = thread.join();
+                }
+                status
+            }
+        }
+    })
+}
+
+/// Opens the backend at `path`, and builds a repository around it with its stored settings
+async fn open(
+    path: &Path,
+    password: &str,
+) -> Result<(Repository<BackendObject>, Manifest<BackendObject>), AsuranStatus> {
+    let queue_depth = num_cpus::get() * 2;
+    let (backend, key) = if path.is_dir() {
+        let encrypted_key =
+            MultiFile::read_key(path).map_err(|e| fail(AsuranStatus::Repository, e))?;
+        let key = encrypted_key
+            .decrypt(password.as_bytes())
+            .map_err(|e| fail(AsuranStatus::BadPassword, e))?;
+        let backend = MultiFile::open_defaults(path, None, &key, queue_depth)
+            .await
+            .map_err(|e| fail(AsuranStatus::Repository, e))?;
+        (backend.get_object_handle(), key)
+    } else {
+        let encrypted_key =
+            FlatFile::load_encrypted_key(path).map_err(|e| fail(AsuranStatus::Repository, e))?;
+        let key = encrypted_key
+            .decrypt(password.as_bytes())
+            .map_err(|e| fail(AsuranStatus::BadPassword, e))?;
+        let backend = FlatFile::new(path, None, None, key.clone(), queue_depth)
+            .map_err(|e| fail(AsuranStatus::Repository, e))?;
+        (backend.get_object_handle(), key)
+    };
+    let settings = backend.get_manifest().chunk_settings().await;
+    let repo = Repository::with(backend, settings, key, num_cpus::get())
+        .map_err(|e| fail(AsuranStatus::Repository, e))?;
+    let manifest = Manifest::load(&repo);
+    Ok((repo, manifest))
+}
+
+/// Closes a repository, flushing any pending writes, and frees its handle
+///
+/// Passing null is a no-op.
+#[no_mangle]
+pub unsafe extern "C" fn asuran_repository_close(repo: *mut AsuranRepository) {
+    if repo.is_null() {
+        return;
+    }
+    let repo = Box::from_raw(repo);
+    // Nothing can be reported from here, so a panic is only kept from crossing the boundary
+    let _ = catch_unwind(AssertUnwindSafe(move || {
+        let AsuranRepository {
+            repo,
+            shutdown,
+            threads,
+            ..
+        } = *repo;
+        smol::block_on(repo.close());
+        drop(shutdown);
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }));
+}
+
+/// Lists the archives in a repository, oldest first, storing the list in `out`
+#[no_mangle]
+pub unsafe extern "C" fn asuran_repository_archives(
+    repo: *mut AsuranRepository,
+    out: *mut *mut AsuranArchiveList,
+) -> AsuranStatus {
+    guard(|| {
+        let repo = try_status!(handle_arg(repo, "repo"));
+        let out = try_status!(handle_arg(out, "out"));
+        let mut archives = smol::block_on(repo.manifest.archives());
+        archives.sort_by_key(|archive| archive.timestamp());
+        let timestamps = archives
+            .iter()
+            .map(|archive| archive.timestamp().unix_seconds())
+            .collect();
+        let names = archives
+            .iter()
+            .map(|archive| CString::new(archive.name().replace('\0', "\u{FFFD}")))
+            .collect::<Result<_, _>>()
+            .expect("NULs were just removed");
+        *out = Box::into_raw(Box::new(AsuranArchiveList { names, timestamps }));
+        AsuranStatus::Ok
+    })
+}
+
+/// Returns the number of archives in a list, or zero if `list` is null
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_list_len(list: *const AsuranArchiveList) -> usize {
+    list.as_ref().map_or(0, |list| list.names.len())
+}
+
+/// Returns the name of the archive at `index`, or null if `index` is out of range
+///
+/// The string is owned by the list, and remains valid until the list is freed.
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_list_name(
+    list: *const AsuranArchiveList,
+    index: usize,
+) -> *const c_char {
+    list.as_ref()
+        .and_then(|list| list.names.get(index))
+        .map_or(ptr::null(), |name| name.as_ptr())
+}
+
+/// Returns the time the archive at `index` was taken, in seconds since the unix epoch, or zero
+/// if `index` is out of range
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_list_timestamp(
+    list: *const AsuranArchiveList,
+    index: usize,
+) -> i64 {
+    list.as_ref()
+        .and_then(|list| list.timestamps.get(index))
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Frees an archive list
+///
+/// Passing null is a no-op.
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_list_free(list: *mut AsuranArchiveList) {
+    if !list.is_null() {
+        drop(Box::from_raw(list));
+    }
+}
+
+/// Starts a new, empty archive called `name`, storing a handle to it in `out`
+///
+/// The archive is not written to any repository until it is passed to `asuran_archive_commit`.
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_new(
+    name: *const c_char,
+    out: *mut *mut AsuranArchive,
+) -> AsuranStatus {
+    guard(|| {
+        let name = try_status!(string_arg(name, "name"));
+        let out = try_status!(handle_arg(out, "out"));
+        *out = Box::into_raw(Box::new(AsuranArchive {
+            archive: ActiveArchive::new(name),
+        }));
+        AsuranStatus::Ok
+    })
+}
+
+/// Loads the most recent archive called `name` from a repository, storing a handle to it in
+/// `out`
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_open(
+    repo: *mut AsuranRepository,
+    name: *const c_char,
+    out: *mut *mut AsuranArchive,
+) -> AsuranStatus {
+    guard(|| {
+        let repo = try_status!(handle_arg(repo, "repo"));
+        let name = try_status!(string_arg(name, "name"));
+        let out = try_status!(handle_arg(out, "out"));
+        let result = smol::block_on(async {
+            let stored = repo
+                .manifest
+                .archives()
+                .await
+                .into_iter()
+                .filter(|archive| archive.name() == name)
+                .max_by_key(|archive| archive.timestamp());
+            match stored {
+                Some(stored) => stored
+                    .load(&mut repo.repo)
+                    .await
+                    .map_err(|e| fail(AsuranStatus::Repository, e)),
+                None => Err(fail(
+                    AsuranStatus::NotFound,
+                    format!("No archive named {:?}", name),
+                )),
+            }
+        });
+        let archive = try_status!(result);
+        *out = Box::into_raw(Box::new(AsuranArchive { archive }));
+        AsuranStatus::Ok
+    })
+}
+
+/// Frees an archive without committing it
+///
+/// Passing null is a no-op.
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_free(archive: *mut AsuranArchive) {
+    if !archive.is_null() {
+        drop(Box::from_raw(archive));
+    }
+}
+
+/// Adapts a read callback into a `Read`
+struct CallbackReader {
+    read: AsuranReadFn,
+    user_data: *mut c_void,
+    failed: Arc<AtomicBool>,
+}
+
+// The store functions do not return until every read has completed, and the header documents
+// that the callback may be called from a thread other than the caller's
+unsafe impl Send for CallbackReader {}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = unsafe { (self.read)(self.user_data, buf.as_mut_ptr(), buf.len()) };
+        match usize::try_from(read) {
+            Ok(read) if read <= buf.len() => Ok(read),
+            Ok(_) => {
+                self.failed.store(true, Ordering::SeqCst);
+                Err(io::Error::other(
+                    "Read callback reported more bytes than requested",
+                ))
+            }
+            Err(_) => {
+                self.failed.store(true, Ordering::SeqCst);
+                Err(io::Error::other("Read callback failed"))
+            }
+        }
+    }
+}
+
+/// Adapts a write callback into a `Write`
+struct CallbackWriter {
+    write: AsuranWriteFn,
+    user_data: *mut c_void,
+    failed: Arc<AtomicBool>,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if unsafe { (self.write)(self.user_data, buf.as_ptr(), buf.len()) } == 0 {
+            Ok(buf.len())
+        } else {
+            self.failed.store(true, Ordering::SeqCst);
+            Err(io::Error::other("Write callback failed"))
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stores an object at `path` in an archive, reading its contents from `read`
+///
+/// `read` may be called from a thread other than the caller's, but never concurrently, and is
+/// not called again after this function returns.
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_store(
+    repo: *mut AsuranRepository,
+    archive: *mut AsuranArchive,
+    path: *const c_char,
+    read: Option<AsuranReadFn>,
+    user_data: *mut c_void,
+) -> AsuranStatus {
+    guard(|| {
+        let repo = try_status!(handle_arg(repo, "repo"));
+        let archive = try_status!(handle_arg(archive, "archive"));
+        let path = try_status!(string_arg(path, "path"));
+        let read = try_status!(
+            read.ok_or_else(|| fail(AsuranStatus::NullArgument, "read must not be null"))
+        );
+        let failed = Arc::new(AtomicBool::new(false));
+        let reader = CallbackReader {
+            read,
+            user_data,
+            failed: Arc::clone(&failed),
+        };
+        match store(repo, archive, path, reader) {
+            Err(e) if failed.load(Ordering::SeqCst) => fail(AsuranStatus::Callback, e),
+            result => status(result),
+        }
+    })
+}
+
+/// Stores an object at `path` in an archive, with the `length` bytes at `data` as its contents
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_store_buffer(
+    repo: *mut AsuranRepository,
+    archive: *mut AsuranArchive,
+    path: *const c_char,
+    data: *const u8,
+    length: usize,
+) -> AsuranStatus {
+    guard(|| {
+        let repo = try_status!(handle_arg(repo, "repo"));
+        let archive = try_status!(handle_arg(archive, "archive"));
+        let path = try_status!(string_arg(path, "path"));
+        if data.is_null() && length > 0 {
+            return fail(AsuranStatus::NullArgument, "data must not be null");
+        }
+        let data = if length == 0 {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(data, length).to_vec()
+        };
+        status(store(repo, archive, path, io::Cursor::new(data)))
+    })
+}
+
+fn store(
+    repo: &mut AsuranRepository,
+    archive: &mut AsuranArchive,
+    path: &str,
+    reader: impl Read + Send + 'static,
+) -> Result<(), ArchiveError> {
+    // TODO: Allow chunker configuration
+    let chunker = FastCDC::default();
+    smol::block_on(
+        archive
+            .archive
+            .put_object(&chunker, &mut repo.repo, path, reader),
+    )
+    .map(|_| ())
+}
+
+/// Converts the result of a repository operation into a status
+fn status<E: Display>(result: Result<(), E>) -> AsuranStatus {
+    match result {
+        Ok(()) => AsuranStatus::Ok,
+        Err(e) => fail(AsuranStatus::Repository, e),
+    }
+}
+
+/// Writes an archive to the repository, and frees its handle
+///
+/// The handle is freed even if committing fails.
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_commit(
+    repo: *mut AsuranRepository,
+    archive: *mut AsuranArchive,
+) -> AsuranStatus {
+    guard(|| {
+        let repo = try_status!(handle_arg(repo, "repo"));
+        if archive.is_null() {
+            return fail(AsuranStatus::NullArgument, "archive must not be null");
+        }
+        let archive = Box::from_raw(archive).archive;
+        status(smol::block_on(
+            repo.manifest.commit_archive(&mut repo.repo, archive),
+        ))
+    })
+}
+
+/// Extracts the object at `path` in an archive, passing its contents to `write`
+#[no_mangle]
+pub unsafe extern "C" fn asuran_archive_extract(
+    repo: *mut AsuranRepository,
+    archive: *mut AsuranArchive,
+    path: *const c_char,
+    write: Option<AsuranWriteFn>,
+    user_data: *mut c_void,
+) -> AsuranStatus {
+    guard(|| {
+        let repo = try_status!(handle_arg(repo, "repo"));
+        let archive = try_status!(handle_arg(archive, "archive"));
+        let path = try_status!(string_arg(path, "path"));
+        let write = try_status!(
+            write.ok_or_else(|| fail(AsuranStatus::NullArgument, "write must not be null"))
+        );
+        match archive.archive.chunk_locations(path) {
+            None => {
+                return fail(
+                    AsuranStatus::NotFound,
+                    format!("No object at {:?} in archive", path),
+                )
+            }
+            // Empty objects have nothing to extract
+            Some(locations) if locations.is_empty() => return AsuranStatus::Ok,
+            Some(_) => (),
+        }
+        let failed = Arc::new(AtomicBool::new(false));
+        let writer = CallbackWriter {
+            write,
+            user_data,
+            failed: Arc::clone(&failed),
+        };
+        match smol::block_on(archive.archive.get_object(&mut repo.repo, path, writer)) {
+            Err(e) if failed.load(Ordering::SeqCst) => fail(AsuranStatus::Callback, e),
+            result => status(result),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asuran::repository::{ChunkSettings, EncryptedKey, Encryption, Key};
+    use std::slice;
+    use tempfile::tempdir;
+
+    const PASSWORD: &str = "password";
+
+    fn create(path: &Path) {
+        let mut settings = ChunkSettings::lightweight();
+        settings.encryption = Encryption::new_aes256ctr();
+        let key = Key::random(32);
+        let encrypted_key =
+            EncryptedKey::encrypt_defaults(&key, settings.encryption, PASSWORD.as_bytes());
+        std::fs::create_dir_all(path).unwrap();
+        smol::run(async {
+            let mut multifile = MultiFile::open_defaults(path, Some(settings), &key, 4)
+                .await
+                .unwrap();
+            multifile.write_key(&encrypted_key).await.unwrap();
+            multifile.close().await;
+        });
+    }
+
+    fn c(string: &str) -> CString {
+        CString::new(string).unwrap()
+    }
+
+    unsafe extern "C" fn read_cursor(
+        user_data: *mut c_void,
+        buffer: *mut u8,
+        length: usize,
+    ) -> isize {
+        let cursor = &mut *(user_data as *mut io::Cursor<Vec<u8>>);
+        let buffer = slice::from_raw_parts_mut(buffer, length);
+        cursor.read(buffer).map_or(-1, |read| read as isize)
+    }
+
+    unsafe extern "C" fn write_vec(
+        user_data: *mut c_void,
+        buffer: *const u8,
+        length: usize,
+    ) -> c_int {
+        let output = &mut *(user_data as *mut Vec<u8>);
+        output.extend_from_slice(slice::from_raw_parts(buffer, length));
+        0
+    }
+
+    unsafe extern "C" fn read_fail(_: *mut c_void, _: *mut u8, _: usize) -> isize {
+        -1
+    }
+
+    unsafe extern "C" fn write_fail(_: *mut c_void, _: *const u8, _: usize) -> c_int {
+        1
+    }
+
+    unsafe fn extract(
+        repo: *mut AsuranRepository,
+        archive: *mut AsuranArchive,
+        path: &str,
+    ) -> (AsuranStatus, Vec<u8>) {
+        let mut output = Vec::new();
+        let status = asuran_archive_extract(
+            repo,
+            archive,
+            c(path).as_ptr(),
+            Some(write_vec),
+            &mut output as *mut Vec<u8> as *mut c_void,
+        );
+        (status, output)
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("repo");
+        create(&path);
+        let path = c(path.to_str().unwrap());
+        let large: Vec<u8> = (0..200_000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        unsafe {
+            let mut repo = ptr::null_mut();
+            let status = asuran_repository_open(path.as_ptr(), c(PASSWORD).as_ptr(), &mut repo);
+            assert_eq!(status, AsuranStatus::Ok);
+            assert!(asuran_last_error().is_null());
+
+            let mut archive = ptr::null_mut();
+            assert_eq!(
+                asuran_archive_new(c("first").as_ptr(), &mut archive),
+                AsuranStatus::Ok
+            );
+            let status = asuran_archive_store_buffer(
+                repo,
+                archive,
+                c("small").as_ptr(),
+                b"hello".as_ptr(),
+                5,
+            );
+            assert_eq!(status, AsuranStatus::Ok);
+            let mut cursor = io::Cursor::new(large.clone());
+            let status = asuran_archive_store(
+                repo,
+                archive,
+                c("large").as_ptr(),
+                Some(read_cursor),
+                &mut cursor as *mut io::Cursor<Vec<u8>> as *mut c_void,
+            );
+            assert_eq!(status, AsuranStatus::Ok);
+            let status =
+                asuran_archive_store_buffer(repo, archive, c("empty").as_ptr(), ptr::null(), 0);
+            assert_eq!(status, AsuranStatus::Ok);
+            let status = asuran_archive_store(
+                repo,
+                archive,
+                c("broken").as_ptr(),
+                Some(read_fail),
+                ptr::null_mut(),
+            );
+            assert_eq!(status, AsuranStatus::Callback);
+            assert!(!asuran_last_error().is_null());
+            assert_eq!(asuran_archive_commit(repo, archive), AsuranStatus::Ok);
+
+            let mut list = ptr::null_mut();
+            assert_eq!(
+                asuran_repository_archives(repo, &mut list),
+                AsuranStatus::Ok
+            );
+            assert_eq!(asuran_archive_list_len(list), 1);
+            let name = CStr::from_ptr(asuran_archive_list_name(list, 0));
+            assert_eq!(name.to_str().unwrap(), "first");
+            assert!(asuran_archive_list_timestamp(list, 0) > 0);
+            assert!(asuran_archive_list_name(list, 1).is_null());
+            asuran_archive_list_free(list);
+
+            let mut archive = ptr::null_mut();
+            let status = asuran_archive_open(repo, c("first").as_ptr(), &mut archive);
+            assert_eq!(status, AsuranStatus::Ok);
+            assert_eq!(
+                extract(repo, archive, "small"),
+                (AsuranStatus::Ok, b"hello".to_vec())
+            );
+            assert_eq!(extract(repo, archive, "large"), (AsuranStatus::Ok, large));
+            assert_eq!(
+                extract(repo, archive, "empty"),
+                (AsuranStatus::Ok, Vec::new())
+            );
+            assert_eq!(extract(repo, archive, "missing").0, AsuranStatus::NotFound);
+            let status = asuran_archive_extract(
+                repo,
+                archive,
+                c("small").as_ptr(),
+                Some(write_fail),
+                ptr::null_mut(),
+            );
+            assert_eq!(status, AsuranStatus::Callback);
+            asuran_archive_free(archive);
+
+            let mut archive = ptr::null_mut();
+            let status = asuran_archive_open(repo, c("second").as_ptr(), &mut archive);
+            assert_eq!(status, AsuranStatus::NotFound);
+            assert!(archive.is_null());
+
+            asuran_repository_close(repo);
+        }
+    }
+
+    #[test]
+    fn open_errors() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("repo");
+        unsafe {
+            let mut repo = ptr::null_mut();
+            let missing = c(path.to_str().unwrap());
+            let status = asuran_repository_open(missing.as_ptr(), c(PASSWORD).as_ptr(), &mut repo);
+            assert_eq!(status, AsuranStatus::NotFound);
+
+            create(&path);
+            let status = asuran_repository_open(missing.as_ptr(), c("wrong").as_ptr(), &mut repo);
+            assert_eq!(status, AsuranStatus::BadPassword);
+            assert!(!asuran_last_error().is_null());
+            assert!(repo.is_null());
+
+            let status = asuran_repository_open(ptr::null(), c(PASSWORD).as_ptr(), &mut repo);
+            assert_eq!(status, AsuranStatus::NullArgument);
+            let status =
+                asuran_repository_open(missing.as_ptr(), c(PASSWORD).as_ptr(), ptr::null_mut());
+            assert_eq!(status, AsuranStatus::NullArgument);
+        }
+    }
+}
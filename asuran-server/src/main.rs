@@ -0,0 +1,151 @@
+/*!
+The `asuran-server` binary serves a local repository to remote `asuran` clients over HTTP,
+enforcing append only and quota policies on their behalf.
+ */
+use asuran::repository::backend::multifile::{MultiFile, MultiFileSettings};
+use asuran::repository::backend::remote::server::{Server, ServerPolicy};
+
+use anyhow::{anyhow, Context, Result};
+use structopt::StructOpt;
+use walkdir::WalkDir;
+
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+/// Serves an asuran repository to remote clients
+#[derive(Debug, StructOpt)]
+#[structopt(name = "asuran-server")]
+struct Opt {
+    /// Location of the MultiFile repository to serve
+    #[structopt(parse(from_os_str))]
+    repo: PathBuf,
+    /// Password of the repository
+    ///
+    /// The server needs the key to maintain the repository's encrypted index and manifest, so
+    /// whoever runs it can decrypt everything stored in the repository.
+    #[structopt(short, long, env = "ASURAN_PASSWORD", hide_env_values = true)]
+    password: String,
+    /// Address to listen on
+    ///
+    /// Only loopback addresses are accepted, unless --insecure-listen is passed.
+    #[structopt(short, long, default_value = "127.0.0.1:8420")]
+    listen: String,
+    /// Allow listening on addresses other than loopback ones
+    ///
+    /// The protocol is plain HTTP, so the token and every chunk are sent in the clear. Only
+    /// use this on a trusted network, and otherwise put a TLS terminating reverse proxy in
+    /// front of a server listening on the loopback interface.
+    #[structopt(long)]
+    insecure_listen: bool,
+    /// Bearer token clients must present
+    #[structopt(long, env = "ASURAN_SERVER_TOKEN", hide_env_values = true)]
+    token: String,
+    /// Refuse any request that would replace or rewrite existing data
+    #[structopt(long)]
+    append_only: bool,
+    /// Maximum size, in bytes, the repository may grow to
+    #[structopt(long)]
+    quota: Option<u64>,
+}
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let options = Opt::from_args();
+    if options.token.is_empty() {
+        return Err(anyhow!(
+            "Refusing to serve a repository with an empty token"
+        ));
+    }
+    let num_threads = num_cpus::get_physical();
+    let (s, r) = piper::chan::<()>(0);
+    let mut threads = Vec::new();
+    for _ in 0..num_threads {
+        let r = r.clone();
+        threads.push(thread::spawn(move || smol::run(r.recv())));
+    }
+    let result = serve(&options);
+    drop(s);
+
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    result
+}
+
+fn serve(options: &Opt) -> Result<()> {
+    if !options.insecure_listen {
+        check_loopback(&options.listen)?;
+    }
+    let key = MultiFile::read_key(&options.repo)
+        .with_context(|| format!("Unable to read key material from {:?}", options.repo))?
+        .decrypt(options.password.as_bytes())
+        .context("Unable to decrypt key material, possibly due to an invalid password")?;
+    let backend = smol::block_on(MultiFile::open_with_settings(
+        &options.repo,
+        None,
+        &key,
+        num_cpus::get() * 2,
+        MultiFileSettings::default(),
+    ))
+    .context("Unable to open repository")?;
+    let used_bytes = repository_size(&options.repo)?;
+    let policy = ServerPolicy {
+        append_only: options.append_only,
+        quota: options.quota,
+    };
+    let server = Arc::new(Server::new(
+        backend,
+        options.token.clone(),
+        policy,
+        used_bytes,
+    ));
+
+    let listener = TcpListener::bind(&options.listen)
+        .with_context(|| format!("Unable to listen on {}", options.listen))?;
+    println!(
+        "Serving {:?} on {} ({} bytes in use)",
+        options.repo,
+        listener.local_addr()?,
+        used_bytes
+    );
+    server
+        .serve(&listener)
+        .context("Failed to accept connection")?;
+    Ok(())
+}
+
+/// Refuses to listen on an address that is reachable from other machines
+fn check_loopback(listen: &str) -> Result<()> {
+    let addresses = listen
+        .to_socket_addrs()
+        .with_context(|| format!("Unable to resolve {}", listen))?;
+    for address in addresses {
+        if !address.ip().is_loopback() {
+            return Err(anyhow!(
+                "Refusing to listen on {}, which is not a loopback address, as the protocol is \
+                 plain HTTP. Put a TLS terminating reverse proxy in front of the server, or pass \
+                 --insecure-listen if the network is trusted",
+                address
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Totals the size of every file in the repository
+fn repository_size(path: &Path) -> Result<u64> {
+    let mut total = 0;
+    for entry in WalkDir::new(path) {
+        let entry = entry.context("Unable to measure repository size")?;
+        if entry.file_type().is_file() {
+            total += entry
+                .metadata()
+                .context("Unable to measure repository size")?
+                .len();
+        }
+    }
+    Ok(total)
+}
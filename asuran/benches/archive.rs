@@ -52,9 +52,11 @@ fn get_repo(key: Key) -> Repository<impl BackendClone> {
         compression: Compression::ZStd { level: 1 },
         encryption: Encryption::new_aes256ctr(),
         hmac: HMAC::Blake3,
+        id: ChunkIDSettings::default(),
+        chunker: None,
     };
     let backend = Mem::new(settings, key.clone(), num_cpus::get() * 2);
-    Repository::with(backend, settings, key, num_cpus::get())
+    Repository::with(backend, settings, key, num_cpus::get()).unwrap()
 }
 
 fn get_repo_bare(key: Key) -> Repository<impl BackendClone> {
@@ -62,9 +64,11 @@ fn get_repo_bare(key: Key) -> Repository<impl BackendClone> {
         compression: Compression::NoCompression,
         encryption: Encryption::NoEncryption,
         hmac: HMAC::Blake3,
+        id: ChunkIDSettings::default(),
+        chunker: None,
     };
     let backend = Mem::new(settings, key.clone(), num_cpus::get() * 2);
-    Repository::with(backend, settings, key, num_cpus::get())
+    Repository::with(backend, settings, key, num_cpus::get()).unwrap()
 }
 
 fn bench(c: &mut Criterion) {
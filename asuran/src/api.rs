@@ -0,0 +1,526 @@
+/*!
+A simplified interface to asuran repositories, for applications that just want backups
+
+The rest of this crate exposes every moving part of a repository: backends, indexes,
+manifests, chunkers, and targets, each generic over the others. That is what makes
+asuran adaptable, but an application that just wants to back up and restore some
+directories, such as a GUI or a scheduling daemon, should not have to understand all of
+it.
+
+`Repo` wraps all of that behind a handful of methods for the common operations:
+creating and opening repositories, listing, backing up, restoring, and pruning
+archives, and reading the repository's audit log, which backups and prunes are recorded
+in. It only deals in owned types, without any generic backend parameters, and is
+`Send` and `Sync`, so it can be moved between threads and kept around in application
+state. Every method reports failures as the categorized `asuran::Error`.
+
+```no_run
+use asuran::api::{Location, Repo};
+
+# smol::run(async {
+let location = Location::MultiFile("/srv/backups".into());
+let mut repo = Repo::open(&location, b"password").await?;
+repo.backup("documents", "/home/user/documents", &[]).await?;
+for archive in repo.archives().await? {
+    println!("{} taken at {}", archive.name, archive.timestamp);
+}
+repo.restore("latest", "/tmp/restored", &[]).await?;
+repo.close().await;
+# Ok::<(), asuran::Error>(())
+# });
+```
+
+Anything more involved, such as scanning files as they are stored, checkpointing
+long backups, or signing archives, still needs the lower level API.
+*/
+use crate::chunker::AnyChunker;
+use crate::error::{Error, Result};
+use crate::manifest::driver::{BackupDriver, RestoreDriver};
+use crate::manifest::retention::RetentionPolicy;
+use crate::manifest::target::filesystem::FileSystemTarget;
+use crate::manifest::target::{BackupTarget, RestoreTarget, SkippedEntry};
+use crate::manifest::{ActiveArchive, Manifest, PruneStats, StoreReport, StoredArchive};
+use crate::repository::backend::flatfile::FlatFile;
+use crate::repository::backend::multifile::MultiFile;
+use crate::repository::audit::{self, AuditEntry, Operation};
+use crate::repository::backend::{Backend, BackendObject};
+use crate::repository::{ChunkSettings, EncryptedKey, Key, Repository};
+use crate::time::Timestamp;
+
+use asuran_core::manifest::listing::Node;
+use futures::future::select_all;
+use smol::Task;
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The number of objects stored at once during a backup
+const MAX_IN_FLIGHT: usize = 30;
+
+/// Where a repository lives, and what kind of repository it is
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Location {
+    /// A `MultiFile` repository, in the given directory
+    MultiFile(PathBuf),
+    /// A `FlatFile` repository, in the given file
+    FlatFile(PathBuf),
+}
+
+impl Location {
+    /// Returns the path of the repository
+    pub fn path(&self) -> &Path {
+        match self {
+            Location::MultiFile(path) | Location::FlatFile(path) => path,
+        }
+    }
+}
+
+/// An archive in a repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveInfo {
+    pub name: String,
+    /// The ID of the archive, as a hexadecimal string
+    pub id: String,
+    /// The time the archive was started
+    pub timestamp: Timestamp,
+    /// The tags the archive was stored with
+    pub tags: Vec<String>,
+}
+
+impl ArchiveInfo {
+    /// Describes an archive, taking its name and tags from the loaded archive, as not
+    /// every backend records them in its manifest
+    fn new(stored_archive: &StoredArchive, archive: &ActiveArchive) -> ArchiveInfo {
+        ArchiveInfo {
+            name: archive.name().to_string(),
+            id: stored_archive.id().to_hex(),
+            timestamp: stored_archive.timestamp(),
+            tags: archive.metadata().tags.iter().cloned().collect(),
+        }
+    }
+}
+
+/// The outcome of `Repo::backup`
+#[derive(Debug, Clone)]
+pub struct BackupSummary {
+    /// The archive that was committed
+    pub archive: ArchiveInfo,
+    /// The chunks and bytes written, along with the files that could not be read, which
+    /// were left out of the archive
+    pub report: StoreReport,
+    /// The entries that were left out of the archive without being read, such as sockets
+    pub skipped: Vec<SkippedEntry>,
+}
+
+/// The outcome of `Repo::restore`
+#[derive(Debug, Clone)]
+pub struct RestoreSummary {
+    /// The archive that was restored from
+    pub archive: ArchiveInfo,
+    /// The number of entries restored
+    pub restored: usize,
+    /// The entries that were not restored, as they would have been written outside of the
+    /// target directory
+    pub refused: Vec<SkippedEntry>,
+}
+
+/// The outcome of `Repo::prune`
+#[derive(Debug, Clone)]
+pub struct PruneSummary {
+    /// The archives that were removed
+    pub removed: Vec<ArchiveInfo>,
+    pub stats: PruneStats,
+}
+
+/// An open connection to a repository
+///
+/// Writes are only guaranteed to have reached the repository once `close` has been called.
+pub struct Repo {
+    repo: Repository<BackendObject>,
+    manifest: Manifest<BackendObject>,
+}
+
+impl Repo {
+    /// Creates a new repository at `location`, protected by `password`, writing chunks
+    /// with `settings` unless told otherwise
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if something already exists at the location, if `settings` use an
+    /// algorithm this build does not support, or if the repository can not be created
+    /// there.
+    pub async fn create(
+        location: &Location,
+        password: &[u8],
+        mut settings: ChunkSettings,
+    ) -> Result<Repo> {
+        let path = location.path();
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            )
+            .into());
+        }
+        // Refuse settings this build can not write before leaving anything behind
+        settings.check_supported()?;
+        // Record the chunker, so later backups split data the same way
+        settings.chunker = Some(settings.chunker());
+        let key = Key::random(settings.encryption.key_length());
+        let encrypted_key = EncryptedKey::encrypt_defaults(&key, settings.encryption, password);
+        let backend = match location {
+            Location::MultiFile(path) => {
+                fs::create_dir_all(path)?;
+                let multifile =
+                    MultiFile::open_defaults(path, Some(settings), &key, queue_depth()).await?;
+                multifile.write_key(&encrypted_key).await?;
+                multifile.get_object_handle()
+            }
+            Location::FlatFile(path) => FlatFile::new(
+                path,
+                Some(settings),
+                Some(encrypted_key),
+                key.clone(),
+                queue_depth(),
+            )?
+            .get_object_handle(),
+        };
+        Repo::with(backend, settings, key)
+    }
+
+    /// Opens the existing repository at `location` with `password`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there is no repository at the location, if the password is
+    /// wrong, or if this build of asuran can not work with the repository.
+    pub async fn open(location: &Location, password: &[u8]) -> Result<Repo> {
+        let path = location.path();
+        if !path.exists() {
+            return Err(Error::RepositoryNotFound(path.display().to_string()));
+        }
+        let encrypted_key = match location {
+            Location::MultiFile(path) => MultiFile::read_key(path)?,
+            Location::FlatFile(path) => FlatFile::load_encrypted_key(path)?,
+        };
+        let key = encrypted_key
+            .decrypt(password)
+            .map_err(|_| Error::WrongPassword)?;
+        let backend = match location {
+            Location::MultiFile(path) => {
+                MultiFile::open_defaults(path, None, &key, queue_depth())
+                    .await?
+                    .get_object_handle()
+            }
+            Location::FlatFile(path) => {
+                FlatFile::new(path, None, None, key.clone(), queue_depth())?.get_object_handle()
+            }
+        };
+        // Chunks are written with the settings recorded in the repository
+        let settings = backend.get_manifest().chunk_settings().await;
+        let mut repo = Repo::with(backend, settings, key)?;
+        if let Err(error) = repo.repo.self_test().await {
+            repo.close().await;
+            return Err(error.into());
+        }
+        Ok(repo)
+    }
+
+    fn with(backend: BackendObject, settings: ChunkSettings, key: Key) -> Result<Repo> {
+        let repo = Repository::with(backend, settings, key, num_cpus::get())?;
+        let manifest = Manifest::load(&repo);
+        Ok(Repo { repo, manifest })
+    }
+
+    /// Lists the archives in the repository
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the archives fail to load.
+    pub async fn archives(&mut self) -> Result<Vec<ArchiveInfo>> {
+        let stored_archives = self.manifest.archives().await;
+        let archives = self.manifest.load_archives(&mut self.repo).await?;
+        Ok(stored_archives
+            .iter()
+            .zip(&archives)
+            .map(|(stored_archive, archive)| ArchiveInfo::new(stored_archive, archive))
+            .collect())
+    }
+
+    /// Finds the archive `spec` refers to, by name, index, `latest`, or prefix, see
+    /// `manifest::resolve`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `spec` does not refer to exactly one archive.
+    pub async fn archive(&mut self, spec: &str) -> Result<ArchiveInfo> {
+        let stored_archive = self.manifest.resolve_archive(&mut self.repo, spec).await?;
+        let archive = stored_archive.load(&mut self.repo).await?;
+        Ok(ArchiveInfo::new(&stored_archive, &archive))
+    }
+
+    /// Backs up the directory `root` as a new archive called `name`
+    ///
+    /// If `paths` is not empty, only those paths, relative to `root`, and everything
+    /// inside of them are backed up. They keep their place relative to `root` in the
+    /// archive.
+    ///
+    /// Files that can not be read are left out of the archive and recorded in the summary,
+    /// rather than failing the whole backup.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of `paths` do not exist, or if the archive can not be
+    /// written to the repository.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the committed archive can not be found in the manifest
+    pub async fn backup(
+        &mut self,
+        name: &str,
+        root: impl AsRef<Path>,
+        paths: &[String],
+    ) -> Result<BackupSummary> {
+        let target = FileSystemTarget::new(utf8(root.as_ref())?);
+        let listing = target.backup_paths().await;
+        let paths = paths.iter().map(|x| trim(x)).collect::<Vec<_>>();
+        if let Some(missing) = paths.iter().find(|x| listing.get(x).is_none()) {
+            return Err(not_found(missing, root.as_ref()));
+        }
+        let chunker = AnyChunker::from_settings(
+            self.manifest.chunk_settings().await.chunker(),
+            self.repo.key(),
+        );
+        let archive = ActiveArchive::new(name);
+        let mut report = StoreReport::default();
+        let mut queue = Vec::new();
+        let nodes = listing.into_iter().filter(|node| selected(node, &paths));
+        for node in nodes {
+            let mut repo = self.repo.clone();
+            let archive = archive.clone();
+            let target = target.clone();
+            queue.push(Task::spawn(async move {
+                target.store_object(&mut repo, chunker, &archive, node).await
+            }));
+            if queue.len() > MAX_IN_FLIGHT {
+                let (stored, _, rest) = select_all(queue).await;
+                report.merge(&stored?);
+                queue = rest;
+            }
+        }
+        for stored in queue {
+            report.merge(&stored.await?);
+        }
+        let mut listing = target.backup_listing().await;
+        for entry in &report.failed {
+            listing.remove(&entry.path);
+        }
+        archive.set_listing(listing).await;
+        self.manifest
+            .commit_archive(&mut self.repo, archive.clone())
+            .await?;
+        // The ID is only known once the archive has been committed
+        let stored_archive = self
+            .manifest
+            .archives()
+            .await
+            .into_iter()
+            .find(|x| {
+                x.timestamp() == archive.timestamp()
+                    && (x.name().is_empty() || x.name() == archive.name())
+            })
+            .expect("Committed archive is missing from the manifest");
+        let archive = ArchiveInfo::new(&stored_archive, &archive);
+        let entry = AuditEntry::new(Operation::Store, Some(archive.name.clone()));
+        audit::append(&mut self.repo, &entry).await?;
+        let skipped = target.skipped_paths().await;
+        Ok(BackupSummary {
+            archive,
+            report,
+            skipped,
+        })
+    }
+
+    /// Restores the archive `spec` refers to into the directory `target`
+    ///
+    /// If `paths` is not empty, only those paths and everything inside of them are
+    /// restored, in the same place under `target` a full restore would put them.
+    ///
+    /// Entries whose paths in the archive would have them written outside of `target`
+    /// are refused, and recorded in the summary.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `spec` does not refer to exactly one archive, if any of
+    /// `paths` are not in the archive, or if restoring any entry fails.
+    pub async fn restore(
+        &mut self,
+        spec: &str,
+        target: impl AsRef<Path>,
+        paths: &[String],
+    ) -> Result<RestoreSummary> {
+        let stored_archive = self.manifest.resolve_archive(&mut self.repo, spec).await?;
+        let archive = stored_archive.load(&mut self.repo).await?;
+        let listing = archive.listing().await;
+        // Find everything to restore before touching the target, so a typo does not leave
+        // a partial restore behind
+        let nodes: Vec<Node> = if paths.is_empty() {
+            listing.iter().cloned().collect()
+        } else {
+            let mut seen = HashSet::new();
+            let mut nodes = Vec::new();
+            for path in paths.iter().map(|x| trim(x)) {
+                let subtree = listing.subtree(path);
+                if subtree.is_empty() {
+                    return Err(not_found(path, Path::new(archive.name())));
+                }
+                for node in subtree {
+                    if seen.insert(node.path.clone()) {
+                        nodes.push(node.clone());
+                    }
+                }
+            }
+            nodes
+        };
+        let target = FileSystemTarget::load_listing(utf8(target.as_ref())?, listing).await;
+        let restored = nodes.len();
+        target
+            .retrieve_objects(&self.repo, &archive, nodes, num_cpus::get(), |_| ())
+            .await?;
+        target.finish_restore().await;
+        Ok(RestoreSummary {
+            archive: ArchiveInfo::new(&stored_archive, &archive),
+            restored,
+            refused: target.refused_paths().await,
+        })
+    }
+
+    /// Removes the archives `policy` does not keep, along with the chunks only they
+    /// referred to
+    ///
+    /// A policy without any rules keeps nothing, so is refused.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `policy` has no rules, or if the repository can not remove
+    /// the archives or chunks, see `Manifest::prune`.
+    pub async fn prune(&mut self, policy: &RetentionPolicy) -> Result<PruneSummary> {
+        if !policy.has_rules() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Refusing to prune with a policy that keeps no archives",
+            )
+            .into());
+        }
+        let archives = self.archives().await?;
+        let selection = policy.select(&self.manifest.archives().await, Timestamp::now());
+        let stats = self
+            .manifest
+            .prune(&mut self.repo, &selection.remove)
+            .await?;
+        let removed = selection
+            .remove
+            .iter()
+            .map(|x| x.id().to_hex())
+            .collect::<HashSet<_>>();
+        audit::append(&mut self.repo, &AuditEntry::new(Operation::Prune, None)).await?;
+        Ok(PruneSummary {
+            removed: archives
+                .into_iter()
+                .filter(|x| removed.contains(&x.id))
+                .collect(),
+            stats,
+        })
+    }
+
+    /// Reads the repository's audit log, oldest entry first
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the entries can not be read, see `audit::read_log`.
+    pub async fn log(&mut self) -> Result<Vec<AuditEntry>> {
+        Ok(audit::read_log(&mut self.repo).await?)
+    }
+
+    /// Flushes everything written to the repository, and closes the connection to it
+    pub async fn close(self) {
+        self.repo.close().await;
+    }
+}
+
+/// The depth of the queues used for communicating with the backend, as the CLI picks it
+fn queue_depth() -> usize {
+    num_cpus::get() * 8
+}
+
+/// Returns true if `node` is one of `paths`, inside of one, or a directory containing one
+fn selected(node: &Node, paths: &[&str]) -> bool {
+    let within = |inner: &str, outer: &str| {
+        inner == outer || (inner.starts_with(outer) && inner[outer.len()..].starts_with('/'))
+    };
+    paths.is_empty()
+        || paths
+            .iter()
+            .any(|path| within(&node.path, path) || within(path, &node.path))
+}
+
+/// Puts a user provided path in the form paths take in listings
+fn trim(path: &str) -> &str {
+    path.trim_start_matches("./").trim_end_matches('/')
+}
+
+fn utf8(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not valid UTF-8", path.display()),
+        )
+        .into()
+    })
+}
+
+fn not_found(path: &str, within: &Path) -> Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found in {}", path, within.display()),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asuran_core::manifest::listing::{NodeMetadata, NodeType};
+
+    #[test]
+    fn is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Repo>();
+        assert_send_sync::<BackupSummary>();
+        assert_send_sync::<RestoreSummary>();
+        assert_send_sync::<PruneSummary>();
+    }
+
+    #[test]
+    fn selects_paths() {
+        let node = |path: &str| Node {
+            path: path.to_string(),
+            total_length: 0,
+            total_size: 0,
+            extents: None,
+            node_type: NodeType::File,
+            metadata: NodeMetadata::default(),
+            hash: None,
+        };
+        let paths = ["docs/notes"];
+        assert!(selected(&node("docs"), &paths));
+        assert!(selected(&node("docs/notes"), &paths));
+        assert!(selected(&node("docs/notes/todo.txt"), &paths));
+        assert!(!selected(&node("docs/notes.txt"), &paths));
+        assert!(!selected(&node("pictures"), &paths));
+        assert!(selected(&node("pictures"), &[]));
+    }
+}
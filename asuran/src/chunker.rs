@@ -1 +1,209 @@
+//! Chunkers for splitting objects into chunks
+//!
+//! This reexports the `asuran-chunker` crate, along with `AnyChunker`, which selects one of
+//! its chunkers at runtime from a repository's `ChunkerSettings`.
 pub use asuran_chunker::*;
+
+use crate::repository::{ChunkerSettings, Key};
+
+use zeroize::Zeroizing;
+
+use std::convert::TryInto;
+use std::io::Read;
+
+/// A `Chunker` selected at runtime, as described by a `ChunkerSettings`
+///
+/// Chunkers are copied into every task storing an object, so this is not boxed to keep it
+/// `Copy`, despite the size of `BuzHash`'s lookup table.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy)]
+pub enum AnyChunker {
+    FastCDC(FastCDC),
+    FastCDC2020(FastCDC2020),
+    BuzHash(BuzHash),
+}
+
+impl AnyChunker {
+    /// Creates the chunker described by `settings`
+    ///
+    /// `BuzHash` derives its lookup table from the key's chunker nonce, and `KeyedBuzHash`
+    /// from its secret key material, so the same key must be used every time to get the same
+    /// chunks.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the sizes in `settings` do not fit in a `usize`
+    pub fn from_settings(settings: ChunkerSettings, key: &Key) -> AnyChunker {
+        let size = |x: u64| -> usize { x.try_into().expect("Chunk size too large") };
+        match settings {
+            ChunkerSettings::FastCDC {
+                min_size,
+                avg_size,
+                max_size,
+            } => AnyChunker::FastCDC(FastCDC {
+                min_size: size(min_size),
+                avg_size: size(avg_size),
+                max_size: size(max_size),
+            }),
+            ChunkerSettings::FastCDC2020 {
+                min_size,
+                avg_size,
+                max_size,
+                normalization,
+            } => AnyChunker::FastCDC2020(FastCDC2020 {
+                min_size: size(min_size),
+                avg_size: size(avg_size),
+                max_size: size(max_size),
+                normalization: match normalization {
+                    0 | 1 => Normalization::Level1,
+                    2 => Normalization::Level2,
+                    _ => Normalization::Level3,
+                },
+            }),
+            ChunkerSettings::BuzHash {
+                window_size,
+                mask_bits,
+            } => AnyChunker::BuzHash(BuzHash::new(key.chunker_nonce(), window_size, mask_bits)),
+            ChunkerSettings::KeyedBuzHash {
+                window_size,
+                mask_bits,
+            } => AnyChunker::BuzHash(BuzHash::with_seed(table_seed(key), window_size, mask_bits)),
+        }
+    }
+}
+
+/// Derives the seed for a keyed `BuzHash` lookup table from the key's ID key and chunker
+/// nonce
+///
+/// The derivation is domain separated, so the seed reveals nothing about the ID key.
+fn table_seed(key: &Key) -> [u8; 32] {
+    let mut material = Zeroizing::new(Vec::with_capacity(key.id_key().len() + 8));
+    material.extend_from_slice(key.id_key());
+    material.extend_from_slice(&key.chunker_nonce().to_le_bytes());
+    let mut seed = [0_u8; 32];
+    blake3::derive_key("asuran 2020-06-01 BuzHash table seed", &material, &mut seed);
+    seed
+}
+
+impl Default for AnyChunker {
+    /// The chunker described by the default `ChunkerSettings`
+    fn default() -> AnyChunker {
+        AnyChunker::FastCDC(FastCDC::default())
+    }
+}
+
+impl Chunker for AnyChunker {
+    type Chunks = AnyChunks;
+    fn chunk_from_boxed(
+        &self,
+        read: Box<dyn Read + Send + 'static>,
+        state: ChunkerState,
+    ) -> Self::Chunks {
+        match self {
+            AnyChunker::FastCDC(chunker) => {
+                AnyChunks::FastCDC(chunker.chunk_from_boxed(read, state))
+            }
+            AnyChunker::FastCDC2020(chunker) => {
+                AnyChunks::FastCDC2020(chunker.chunk_from_boxed(read, state))
+            }
+            AnyChunker::BuzHash(chunker) => {
+                AnyChunks::BuzHash(chunker.chunk_from_boxed(read, state))
+            }
+        }
+    }
+}
+
+/// The chunks produced by an `AnyChunker`
+#[allow(clippy::large_enum_variant)]
+pub enum AnyChunks {
+    FastCDC(<FastCDC as Chunker>::Chunks),
+    FastCDC2020(<FastCDC2020 as Chunker>::Chunks),
+    BuzHash(<BuzHash as Chunker>::Chunks),
+}
+
+impl Iterator for AnyChunks {
+    type Item = Result<Vec<u8>, ChunkerError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            AnyChunks::FastCDC(chunks) => chunks.next(),
+            AnyChunks::FastCDC2020(chunks) => chunks.next(),
+            AnyChunks::BuzHash(chunks) => chunks.next(),
+        }
+    }
+}
+
+impl ChunkIterator for AnyChunks {
+    fn state(&self) -> ChunkerState {
+        match self {
+            AnyChunks::FastCDC(chunks) => chunks.state(),
+            AnyChunks::FastCDC2020(chunks) => chunks.state(),
+            AnyChunks::BuzHash(chunks) => chunks.state(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // An `AnyChunker` should split data exactly as the chunker it wraps does
+    #[test]
+    fn matches_wrapped_chunker() {
+        let key = Key::random(32);
+        let data = (0..1_000_000_u32)
+            .map(|x| x.wrapping_mul(2_654_435_761).to_le_bytes()[2])
+            .collect::<Vec<_>>();
+        let settings = ChunkerSettings::BuzHash {
+            window_size: 4095,
+            mask_bits: 14,
+        };
+        let expected = BuzHash::new(key.chunker_nonce(), 4095, 14)
+            .chunk(Cursor::new(data.clone()))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        let chunks = AnyChunker::from_settings(settings, &key)
+            .chunk(Cursor::new(data.clone()))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, expected);
+        let expected = FastCDC::default()
+            .chunk(Cursor::new(data.clone()))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        let chunks = AnyChunker::from_settings(ChunkerSettings::default(), &key)
+            .chunk(Cursor::new(data))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, expected);
+    }
+
+    // Keyed tables should follow the key, and not match the nonce derived table
+    #[test]
+    fn keyed_buzhash() {
+        let key = Key::random(32);
+        let data = (0..1_000_000_u32)
+            .map(|x| x.wrapping_mul(2_654_435_761).to_le_bytes()[2])
+            .collect::<Vec<_>>();
+        let chunk = |settings, key: &Key| {
+            AnyChunker::from_settings(settings, key)
+                .chunk(Cursor::new(data.clone()))
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        };
+        let keyed = ChunkerSettings::KeyedBuzHash {
+            window_size: 4095,
+            mask_bits: 14,
+        };
+        let plain = ChunkerSettings::BuzHash {
+            window_size: 4095,
+            mask_bits: 14,
+        };
+        let chunks = chunk(keyed, &key);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), data);
+        assert_eq!(chunks, chunk(keyed, &key.clone()));
+        assert_ne!(chunks, chunk(keyed, &Key::random(32)));
+        assert_ne!(chunks, chunk(plain, &key));
+    }
+}
@@ -0,0 +1,367 @@
+//! The public error type of the asuran API
+//!
+//! Each module of asuran has its own error type, describing exactly what went wrong
+//! inside of it. These are precise, but awkward to act on from outside of the library,
+//! as the same underlying failure, such as a chunk failing its HMAC, can surface
+//! wrapped in any number of them.
+//!
+//! `Error` sorts failures into the handful of categories a consumer of the library is
+//! actually interested in, such as the password being wrong or the repository being
+//! locked, keeping the original error around as its source. Every module error
+//! converts into it, and `ErrorKind::of` can categorize any error chain that contains
+//! one, so errors that have already been wrapped, such as in an `anyhow::Error`, can
+//! still be mapped to an exit code.
+#![allow(clippy::used_underscore_binding)] // TODO: Fix this after clippy and thiserror start
+                                           // playing nice
+use crate::interop::restic::ResticError;
+use crate::interop::tar::TarError;
+use crate::manifest::archive::ArchiveError;
+use crate::manifest::driver::DriverError;
+use crate::manifest::resolve::ResolveError;
+use crate::manifest::signing::SignatureError;
+use crate::repository::backend::remote::RemoteError;
+use crate::repository::backend::BackendError;
+use crate::repository::bundle::BundleError;
+use crate::repository::RepositoryError;
+
+use asuran_core::repository::backend::flatfile::FlatFileError;
+use asuran_core::repository::chunk::ChunkError;
+
+use thiserror::Error;
+
+use std::error::Error as StdError;
+use std::io;
+
+/// The underlying error an `Error` was categorized from
+pub type Source = Box<dyn StdError + Send + Sync + 'static>;
+
+/// Categorized error for everything that can go wrong while using asuran
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("No repository found at {0}")]
+    RepositoryNotFound(String),
+    #[error("Unable to decrypt the key material, the password is likely wrong")]
+    WrongPassword,
+    #[error("No archive named {0} in the repository")]
+    ArchiveNotFound(String),
+    #[error("Repository data failed verification, it may be corrupt or have been tampered with")]
+    CorruptChunk(#[source] Source),
+    #[error("Unable to communicate with the backend")]
+    BackendUnavailable(#[source] Source),
+    #[error("Repository is locked by another process")]
+    Locked(#[source] Source),
+    #[error("Operation not permitted on this repository")]
+    NotPermitted(#[source] Source),
+    #[error("Operation not supported")]
+    Unsupported(#[source] Source),
+    #[error("I/O Error")]
+    Io(#[source] Source),
+    #[error("")]
+    Other(#[source] Source),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The category of an `Error`, without any of its details
+///
+/// Each kind has a stable name and process exit code, for reporting failures to
+/// other programs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    RepositoryNotFound,
+    WrongPassword,
+    ArchiveNotFound,
+    CorruptChunk,
+    BackendUnavailable,
+    Locked,
+    NotPermitted,
+    Unsupported,
+    Io,
+    Other,
+}
+
+impl ErrorKind {
+    /// Finds the most specific kind of error in an error and its chain of sources
+    ///
+    /// Errors from asuran are recognized anywhere in the chain, along with I/O errors.
+    /// Returns `ErrorKind::Other` if none of the errors could be categorized.
+    pub fn of(error: &(dyn StdError + 'static)) -> ErrorKind {
+        let mut current = Some(error);
+        while let Some(error) = current {
+            if let Some(kind) = classify(error) {
+                return kind;
+            }
+            current = error.source();
+        }
+        ErrorKind::Other
+    }
+
+    /// Returns the stable, machine readable, name of this kind of error
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::RepositoryNotFound => "repository_not_found",
+            ErrorKind::WrongPassword => "wrong_password",
+            ErrorKind::ArchiveNotFound => "archive_not_found",
+            ErrorKind::CorruptChunk => "corrupt_chunk",
+            ErrorKind::BackendUnavailable => "backend_unavailable",
+            ErrorKind::Locked => "locked",
+            ErrorKind::NotPermitted => "not_permitted",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::Io => "io",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    /// Returns the process exit code that should be used to report this kind of error
+    ///
+    /// Uncategorized errors exit with 1, and every other kind of error has its own code
+    /// starting at 10, staying clear of the codes used for usage errors.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::RepositoryNotFound => 10,
+            ErrorKind::WrongPassword => 11,
+            ErrorKind::ArchiveNotFound => 12,
+            ErrorKind::CorruptChunk => 13,
+            ErrorKind::BackendUnavailable => 14,
+            ErrorKind::Locked => 15,
+            ErrorKind::NotPermitted => 16,
+            ErrorKind::Unsupported => 17,
+            ErrorKind::Io => 18,
+        }
+    }
+}
+
+impl Error {
+    /// Categorizes an arbitrary error, keeping it as the source of the result
+    pub fn new(error: impl Into<Source>) -> Error {
+        let error = error.into();
+        match ErrorKind::of(error.as_ref()) {
+            ErrorKind::RepositoryNotFound => Error::RepositoryNotFound(error.to_string()),
+            ErrorKind::WrongPassword => Error::WrongPassword,
+            ErrorKind::ArchiveNotFound => match error.downcast_ref::<ResolveError>() {
+                Some(ResolveError::NotFound(spec)) => Error::ArchiveNotFound(spec.clone()),
+                _ => Error::ArchiveNotFound(error.to_string()),
+            },
+            ErrorKind::CorruptChunk => Error::CorruptChunk(error),
+            ErrorKind::BackendUnavailable => Error::BackendUnavailable(error),
+            ErrorKind::Locked => Error::Locked(error),
+            ErrorKind::NotPermitted => Error::NotPermitted(error),
+            ErrorKind::Unsupported => Error::Unsupported(error),
+            ErrorKind::Io => Error::Io(error),
+            ErrorKind::Other => Error::Other(error),
+        }
+    }
+
+    /// Returns the kind of this error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::RepositoryNotFound(_) => ErrorKind::RepositoryNotFound,
+            Error::WrongPassword => ErrorKind::WrongPassword,
+            Error::ArchiveNotFound(_) => ErrorKind::ArchiveNotFound,
+            Error::CorruptChunk(_) => ErrorKind::CorruptChunk,
+            Error::BackendUnavailable(_) => ErrorKind::BackendUnavailable,
+            Error::Locked(_) => ErrorKind::Locked,
+            Error::NotPermitted(_) => ErrorKind::NotPermitted,
+            Error::Unsupported(_) => ErrorKind::Unsupported,
+            Error::Io(_) => ErrorKind::Io,
+            Error::Other(_) => ErrorKind::Other,
+        }
+    }
+}
+
+/// Describes an error along with everything that caused it
+///
+/// Several of our errors only make sense alongside their source, so the whole chain is
+/// included, skipping any links without a message of their own.
+pub fn describe(error: &(dyn StdError + 'static)) -> String {
+    let mut messages = Vec::new();
+    let mut current = Some(error);
+    while let Some(error) = current {
+        let message = error.to_string();
+        if !message.is_empty() {
+            messages.push(message);
+        }
+        current = error.source();
+    }
+    messages.join(": ")
+}
+
+/// Categorizes a single error, without looking at its source
+///
+/// Returns `None` for errors that are only wrappers, or that do not say anything
+/// about the kind of failure on their own, so the search continues down the chain.
+fn classify(error: &(dyn StdError + 'static)) -> Option<ErrorKind> {
+    if let Some(error) = error.downcast_ref::<Error>() {
+        // Errors without a category of their own might be wrapping one that has one
+        return Some(error.kind()).filter(|x| *x != ErrorKind::Other);
+    }
+    if let Some(error) = error.downcast_ref::<BackendError>() {
+        return match error {
+            BackendError::IOError(_) => Some(ErrorKind::Io),
+            BackendError::FileLockError | BackendError::RepositoryGloballyLocked(_) => {
+                Some(ErrorKind::Locked)
+            }
+            BackendError::CancelledOneshotError(_)
+            | BackendError::ChannelDroppedSend(_)
+            | BackendError::ConnectionError(_) => Some(ErrorKind::BackendUnavailable),
+            BackendError::AppendOnly(_) | BackendError::QuotaExceeded(_) => {
+                Some(ErrorKind::NotPermitted)
+            }
+            BackendError::Unsupported(_) => Some(ErrorKind::Unsupported),
+            BackendError::ChunkUnpackError(_) | BackendError::FlatFile(_) => None,
+            _ => Some(ErrorKind::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<RemoteError>() {
+        return match error {
+            RemoteError::Unauthorized
+            | RemoteError::AppendOnly(_)
+            | RemoteError::QuotaExceeded(_) => Some(ErrorKind::NotPermitted),
+            _ => Some(ErrorKind::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<ChunkError>() {
+        return match error {
+            ChunkError::CompressionError(_)
+            | ChunkError::EncryptionError(_)
+            | ChunkError::KeyError(_)
+            | ChunkError::HMACValidationFailed => Some(ErrorKind::CorruptChunk),
+            ChunkError::UnsupportedHMAC(_) => Some(ErrorKind::Unsupported),
+            _ => Some(ErrorKind::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<FlatFileError>() {
+        return match error {
+            FlatFileError::IOError(_) => Some(ErrorKind::Io),
+            FlatFileError::ChunkError(_) => None,
+            FlatFileError::InvalidMagicNumber => Some(ErrorKind::RepositoryNotFound),
+            _ => Some(ErrorKind::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<RepositoryError>() {
+        return match error {
+            RepositoryError::ChunkerError(_) | RepositoryError::BackendError(_) => None,
+            RepositoryError::CompressionError(_) => Some(ErrorKind::CorruptChunk),
+            RepositoryError::SelfTestFailed(_) => Some(ErrorKind::Unsupported),
+            RepositoryError::ChunkNotFound => Some(ErrorKind::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<ResticError>() {
+        return match error {
+            ResticError::IO(_) => Some(ErrorKind::Io),
+            ResticError::Archive(_) => None,
+            ResticError::WrongPassword => Some(ErrorKind::WrongPassword),
+            ResticError::Authentication(_) | ResticError::Corrupt(_) => {
+                Some(ErrorKind::CorruptChunk)
+            }
+            ResticError::UnsupportedVersion(_) => Some(ErrorKind::Unsupported),
+            _ => Some(ErrorKind::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<BundleError>() {
+        return match error {
+            BundleError::IOError(_) => Some(ErrorKind::Io),
+            BundleError::RepositoryError(_)
+            | BundleError::BackendError(_)
+            | BundleError::ChunkError(_) => None,
+            BundleError::IntegrityFailure(_) => Some(ErrorKind::CorruptChunk),
+            BundleError::UnsupportedVersion(_) => Some(ErrorKind::Unsupported),
+            _ => Some(ErrorKind::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<SignatureError>() {
+        return match error {
+            SignatureError::Invalid(_) => Some(ErrorKind::CorruptChunk),
+            SignatureError::Unsigned(_) | SignatureError::Untrusted(_, _) => {
+                Some(ErrorKind::NotPermitted)
+            }
+            SignatureError::InvalidKey => Some(ErrorKind::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<ResolveError>() {
+        return match error {
+            ResolveError::NotFound(_) => Some(ErrorKind::ArchiveNotFound),
+            ResolveError::Ambiguous { .. } => Some(ErrorKind::Other),
+            ResolveError::Archive(_) => None,
+        };
+    }
+    if error.is::<io::Error>() {
+        return Some(ErrorKind::Io);
+    }
+    // Everything else either wraps one of the above, or says nothing about the kind of
+    // failure, such as `ArchiveError` and `DriverError`
+    None
+}
+
+macro_rules! impl_from {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for Error {
+                fn from(error: $error) -> Self {
+                    Error::new(error)
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    ArchiveError,
+    BackendError,
+    BundleError,
+    ChunkError,
+    DriverError,
+    FlatFileError,
+    RemoteError,
+    RepositoryError,
+    ResolveError,
+    ResticError,
+    SignatureError,
+    TarError,
+    io::Error
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn categorizes_wrapped_errors() {
+        // A failed HMAC, as it would come out of the repository during a restore
+        let error = ArchiveError::Repository(RepositoryError::BackendError(
+            BackendError::ChunkUnpackError(ChunkError::HMACValidationFailed),
+        ));
+        let error = Error::from(error);
+        assert_eq!(error.kind(), ErrorKind::CorruptChunk);
+        assert_eq!(
+            describe(&error),
+            "Repository data failed verification, it may be corrupt or have been tampered \
+             with: Backend Error: Chunk Unpacking Error: HMAC Vailidation Failed: HMAC \
+             Vailidation Failed"
+        );
+
+        let error = Error::from(BackendError::RepositoryGloballyLocked("pid 1".to_string()));
+        assert_eq!(error.kind(), ErrorKind::Locked);
+        assert_eq!(error.kind().exit_code(), 15);
+
+        // Errors without a category leave it to their source
+        let error = DriverError::ArchiveError(ArchiveError::IO(io::Error::other("disk on fire")));
+        assert_eq!(ErrorKind::of(&error), ErrorKind::Io);
+        let error = Error::Other(Box::new(Error::WrongPassword));
+        assert_eq!(ErrorKind::of(&error), ErrorKind::WrongPassword);
+        assert_eq!(
+            ErrorKind::of(&RepositoryError::ChunkNotFound),
+            ErrorKind::Other
+        );
+
+        let error = Error::from(ResolveError::NotFound("latest~3".to_string()));
+        assert_eq!(error.kind().exit_code(), 12);
+        assert_eq!(
+            error.to_string(),
+            "No archive named latest~3 in the repository"
+        );
+    }
+}
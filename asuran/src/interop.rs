@@ -0,0 +1,4 @@
+//! This module provides facilities for moving data between asuran archives and other, more
+//! widely supported, formats.
+pub mod restic;
+pub mod tar;
@@ -0,0 +1,706 @@
+//! Imports snapshots from restic repositories
+//!
+//! A restic repository is opened from a local path with its password, after which its indexes
+//! are loaded into memory, and each of its snapshots can be re-stored as an asuran archive.
+//!
+//! Every file and blob read from the restic repository has its MAC checked, and its ID
+//! compared against the SHA-256 of its contents, so a damaged restic repository is reported as
+//! such, rather than silently producing a damaged archive. Both version 1 and version 2
+//! (compressed) repositories are supported.
+//!
+//! Regular files, directories, and symlinks are imported, other node types, such as devices and
+//! fifos, are skipped. As with the rest of asuran, links are recorded in the listing without
+//! their targets.
+use crate::chunker::AsyncChunker;
+use crate::manifest::archive::{ActiveArchive, ArchiveError};
+use crate::repository::{BackendClone, Repository};
+use crate::time::Timestamp;
+
+use asuran_core::manifest::listing::{Listing, Node, NodeMetadata, NodeType};
+
+use serde::{de, Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+mod crypto;
+
+use crypto::{MasterKey, StoredKey};
+
+/// Plaintext marker for compressed unpacked files in version 2 repositories
+const COMPRESSED_FILE: u8 = 2;
+
+/// Error for all the things that can go wrong while importing from restic
+#[derive(Error, Debug)]
+pub enum ResticError {
+    #[error("I/O Error")]
+    IO(#[from] io::Error),
+    #[error("Failed to store object in archive")]
+    Archive(#[from] ArchiveError),
+    #[error("Malformed JSON in {0}")]
+    JSON(String, #[source] serde_json::Error),
+    #[error(
+        "None of the keys in the restic repository could be opened with the provided password"
+    )]
+    WrongPassword,
+    #[error("{0} failed authentication, it may be corrupt or have been tampered with")]
+    Authentication(String),
+    #[error("The contents of {0} do not match its ID")]
+    Corrupt(String),
+    #[error("Unsupported restic repository version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Blob {0} is not in any index")]
+    MissingBlob(ResticID),
+}
+
+type Result<T> = std::result::Result<T, ResticError>;
+
+/// The ID of a file or blob in a restic repository, the SHA-256 of its contents
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct ResticID([u8; 32]);
+
+impl ResticID {
+    /// Parses an ID from its hex representation
+    pub fn parse(hex: &str) -> Option<ResticID> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut id = [0_u8; 32];
+        for (i, byte) in id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(ResticID(id))
+    }
+
+    /// Computes the ID of some data
+    pub fn of(data: &[u8]) -> ResticID {
+        let mut id = [0_u8; 32];
+        id.copy_from_slice(&Sha256::digest(data));
+        ResticID(id)
+    }
+
+    /// Returns the first eight hex digits of the ID, which restic uses for display
+    pub fn short(&self) -> String {
+        self.to_string()[..8].to_string()
+    }
+}
+
+impl fmt::Display for ResticID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ResticID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ResticID({self})")
+    }
+}
+
+impl<'de> Deserialize<'de> for ResticID {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        ResticID::parse(&hex).ok_or_else(|| de::Error::custom(format!("invalid ID {hex}")))
+    }
+}
+
+/// A key file, which holds the master key sealed with a key derived from the password
+#[derive(Deserialize)]
+struct KeyFile {
+    kdf: String,
+    #[serde(rename = "N")]
+    n: u64,
+    r: u32,
+    p: u32,
+    #[serde(with = "crypto::base64_bytes")]
+    salt: Vec<u8>,
+    #[serde(with = "crypto::base64_bytes")]
+    data: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct Config {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct IndexFile {
+    packs: Vec<IndexPack>,
+}
+
+#[derive(Deserialize)]
+struct IndexPack {
+    id: ResticID,
+    blobs: Vec<IndexBlob>,
+}
+
+#[derive(Deserialize)]
+struct IndexBlob {
+    id: ResticID,
+    offset: u64,
+    length: u64,
+    #[serde(default)]
+    uncompressed_length: Option<u64>,
+}
+
+/// Where a blob lives inside of the pack files
+#[derive(Clone, Copy, Debug)]
+struct BlobLocation {
+    pack: ResticID,
+    offset: u64,
+    length: u64,
+    compressed: bool,
+}
+
+#[derive(Deserialize)]
+struct Tree {
+    #[serde(default)]
+    nodes: Option<Vec<TreeNode>>,
+}
+
+#[derive(Deserialize)]
+struct TreeNode {
+    name: String,
+    #[serde(rename = "type")]
+    node_type: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    content: Option<Vec<ResticID>>,
+    #[serde(default)]
+    subtree: Option<ResticID>,
+    #[serde(default)]
+    linktarget: Option<String>,
+}
+
+/// A snapshot in a restic repository
+#[derive(Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    /// The ID of the snapshot
+    #[serde(skip)]
+    pub id: ResticID,
+    /// The time the snapshot was taken
+    pub time: Timestamp,
+    /// The root tree of the snapshot
+    pub tree: ResticID,
+    /// The paths that were backed up
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// The host the snapshot was taken on
+    #[serde(default)]
+    pub hostname: String,
+    /// Tags attached to the snapshot
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// A restic repository on the local filesystem, opened for reading
+pub struct ResticRepository {
+    path: PathBuf,
+    key: MasterKey,
+    blobs: HashMap<ResticID, BlobLocation>,
+}
+
+impl fmt::Debug for ResticRepository {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResticRepository")
+            .field("path", &self.path)
+            .field("blobs", &self.blobs.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResticRepository {
+    /// Opens the restic repository at the given path, and loads its indexes
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if none of the repository's keys can be opened with the password, if
+    /// the repository is of an unsupported version, or if its config or indexes can not be
+    /// read.
+    pub fn open(path: impl AsRef<Path>, password: &[u8]) -> Result<ResticRepository> {
+        let path = path.as_ref().to_path_buf();
+        let key = open_key(&path, password)?;
+        let mut repository = ResticRepository {
+            path,
+            key,
+            blobs: HashMap::new(),
+        };
+
+        let config: Config =
+            repository.decode("config", &fs::read(repository.path.join("config"))?)?;
+        if config.version < 1 || config.version > 2 {
+            return Err(ResticError::UnsupportedVersion(config.version));
+        }
+
+        for id in repository.list("index")? {
+            let index: IndexFile = repository.read_file("index", id)?;
+            for pack in index.packs {
+                for blob in pack.blobs {
+                    repository.blobs.insert(
+                        blob.id,
+                        BlobLocation {
+                            pack: pack.id,
+                            offset: blob.offset,
+                            length: blob.length,
+                            compressed: blob.uncompressed_length.is_some(),
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(repository)
+    }
+
+    /// Returns all of the snapshots in the repository, oldest first
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the snapshots can not be read.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for id in self.list("snapshots")? {
+            let mut snapshot: Snapshot = self.read_file("snapshots", id)?;
+            snapshot.id = id;
+            snapshots.push(snapshot);
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+        Ok(snapshots)
+    }
+
+    /// Reads, authenticates, and decompresses a blob
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the blob is not in the index, or its pack can not be read, or if
+    /// the blob is damaged.
+    pub fn read_blob(&self, id: ResticID) -> Result<Vec<u8>> {
+        let location = self.blobs.get(&id).ok_or(ResticError::MissingBlob(id))?;
+        let pack = location.pack.to_string();
+        let mut file = File::open(self.path.join("data").join(&pack[..2]).join(&pack))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut sealed = Vec::new();
+        file.take(location.length).read_to_end(&mut sealed)?;
+        let name = format!("blob {id}");
+        let mut data = self
+            .key
+            .open(&sealed)
+            .ok_or_else(|| ResticError::Authentication(name.clone()))?;
+        if location.compressed {
+            data = zstd::stream::decode_all(&data[..])?;
+        }
+        if ResticID::of(&data) == id {
+            Ok(data)
+        } else {
+            Err(ResticError::Corrupt(name))
+        }
+    }
+
+    /// Lists the IDs of the files in one of the repository's directories
+    fn list(&self, directory: &str) -> Result<Vec<ResticID>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(self.path.join(directory))? {
+            // Anything that is not named by an ID, such as a temporary file, is not ours
+            if let Some(id) = entry?.file_name().to_str().and_then(ResticID::parse) {
+                ids.push(id);
+            }
+        }
+        ids.sort_by_key(|id| id.0);
+        Ok(ids)
+    }
+
+    /// Reads and decodes one of the files in the repository's directories
+    fn read_file<T: de::DeserializeOwned>(&self, directory: &str, id: ResticID) -> Result<T> {
+        let name = format!("{directory}/{id}");
+        let sealed = fs::read(self.path.join(directory).join(id.to_string()))?;
+        if ResticID::of(&sealed) != id {
+            return Err(ResticError::Corrupt(name));
+        }
+        self.decode(&name, &sealed)
+    }
+
+    /// Opens a sealed file, and parses its contents
+    fn decode<T: de::DeserializeOwned>(&self, name: &str, sealed: &[u8]) -> Result<T> {
+        let plaintext = self
+            .key
+            .open(sealed)
+            .ok_or_else(|| ResticError::Authentication(name.to_string()))?;
+        let plaintext = if plaintext.first() == Some(&COMPRESSED_FILE) {
+            zstd::stream::decode_all(&plaintext[1..])?
+        } else {
+            plaintext
+        };
+        serde_json::from_slice(&plaintext).map_err(|e| ResticError::JSON(name.to_string(), e))
+    }
+
+    /// Reads and parses a tree blob
+    fn read_tree(&self, id: ResticID) -> Result<Vec<TreeNode>> {
+        let data = self.read_blob(id)?;
+        let tree: Tree = serde_json::from_slice(&data)
+            .map_err(|e| ResticError::JSON(format!("tree {id}"), e))?;
+        Ok(tree.nodes.unwrap_or_default())
+    }
+}
+
+/// Finds the key file that the password opens, and returns the master key inside it
+fn open_key(path: &Path, password: &[u8]) -> Result<MasterKey> {
+    let mut entries = fs::read_dir(path.join("keys"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        let name = entry.to_string_lossy().to_string();
+        let key_file: KeyFile = serde_json::from_slice(&fs::read(&entry)?)
+            .map_err(|e| ResticError::JSON(name.clone(), e))?;
+        if key_file.kdf != "scrypt" {
+            continue;
+        }
+        let user_key =
+            MasterKey::derive(password, &key_file.salt, key_file.n, key_file.r, key_file.p);
+        if let Some(data) = user_key.and_then(|user_key| user_key.open(&key_file.data)) {
+            let stored: StoredKey =
+                serde_json::from_slice(&data).map_err(|e| ResticError::JSON(name.clone(), e))?;
+            if let Some(key) = stored.into_key() {
+                return Ok(key);
+            }
+        }
+    }
+    Err(ResticError::WrongPassword)
+}
+
+/// Reads the contents of a file out of a restic repository, one blob at a time
+struct BlobReader {
+    repository: Arc<ResticRepository>,
+    blobs: std::vec::IntoIter<ResticID>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.blobs.next() {
+                Some(id) => {
+                    let data = self
+                        .repository
+                        .read_blob(id)
+                        .map_err(|e| io::Error::other(e.to_string()))?;
+                    self.current = Cursor::new(data);
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Stores the contents of a restic snapshot into an archive
+///
+/// Paths in the archive are relative to the root of the snapshot, so a snapshot of
+/// `/home/user` will contain `home`, `home/user`, and so on. The listing of the archive is
+/// replaced with the one built from the snapshot.
+///
+/// The archive is not committed to the manifest, that is left to the caller.
+///
+/// # Errors
+///
+/// Will return `Err` if any of the snapshot's trees or blobs can not be read, or if storing
+/// an object fails.
+pub async fn import_snapshot(
+    restic: &Arc<ResticRepository>,
+    snapshot: &Snapshot,
+    repository: &mut Repository<impl BackendClone>,
+    chunker: &impl AsyncChunker,
+    archive: &mut ActiveArchive,
+) -> Result<()> {
+    let mut data_archive = archive.namespace_append("");
+    let mut listing = Listing::default();
+    let mut trees = vec![(snapshot.tree, String::new())];
+    while let Some((tree, parent)) = trees.pop() {
+        for node in restic.read_tree(tree)? {
+            let path = if parent.is_empty() {
+                node.name.clone()
+            } else {
+                format!("{}/{}", parent, node.name)
+            };
+            let node_type = match node.node_type.as_str() {
+                "file" => {
+                    let blobs = node.content.unwrap_or_default();
+                    if blobs.is_empty() {
+                        data_archive.put_empty(&path).await;
+                    } else {
+                        let reader = BlobReader {
+                            repository: restic.clone(),
+                            blobs: blobs.into_iter(),
+                            current: Cursor::new(Vec::new()),
+                        };
+                        data_archive
+                            .put_object(chunker, repository, &path, reader)
+                            .await?;
+                    }
+                    NodeType::File
+                }
+                "dir" => {
+                    if let Some(subtree) = node.subtree {
+                        trees.push((subtree, path.clone()));
+                    }
+                    NodeType::Directory {
+                        children: Vec::new(),
+                    }
+                }
+                "symlink" => NodeType::Link,
+                _ => continue,
+            };
+            let size = if let NodeType::File = node_type {
+                node.size
+            } else {
+                0
+            };
+            listing.add_child(
+                &parent,
+                Node {
+                    path,
+                    total_length: size,
+                    total_size: size,
+                    extents: None,
+                    metadata: NodeMetadata {
+                        link_target: node.linktarget,
+                        ..NodeMetadata::default()
+                    },
+                    node_type,
+                    hash: None,
+                },
+            );
+        }
+    }
+    archive.set_listing(listing).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FastCDC;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::{ChunkSettings, Key};
+
+    use tempfile::tempdir;
+
+    /// Builds restic repositories on disk, for testing
+    struct Fixture {
+        path: PathBuf,
+        key: MasterKey,
+        counter: u8,
+        pack: Vec<u8>,
+        index: Vec<String>,
+    }
+
+    impl Fixture {
+        fn new(path: &Path, password: &[u8], version: u32) -> Fixture {
+            for directory in &["keys", "index", "snapshots", "data"] {
+                fs::create_dir_all(path.join(directory)).unwrap();
+            }
+            let key = MasterKey::new([7; 32], [8; 16], [9; 16]);
+            let mut fixture = Fixture {
+                path: path.to_path_buf(),
+                key,
+                counter: 0,
+                pack: Vec::new(),
+                index: Vec::new(),
+            };
+            // Seal the master key with the password
+            let salt = [10_u8; 16];
+            let user_key = MasterKey::derive(password, &salt, 16, 1, 1).unwrap();
+            let stored = format!(
+                r#"{{"mac":{{"k":"{}","r":"{}"}},"encrypt":"{}"}}"#,
+                base64::encode([8; 16]),
+                base64::encode([9; 16]),
+                base64::encode([7; 32]),
+            );
+            let data = user_key.seal([0; 16], stored.as_bytes());
+            let key_file = format!(
+                r#"{{"kdf":"scrypt","N":16,"r":1,"p":1,"salt":"{}","data":"{}"}}"#,
+                base64::encode(salt),
+                base64::encode(&data),
+            );
+            fs::write(path.join("keys").join("a"), key_file).unwrap();
+            let config = format!(r#"{{"version":{version},"id":"test"}}"#);
+            let config = fixture.seal(config.as_bytes());
+            fs::write(path.join("config"), config).unwrap();
+            fixture
+        }
+
+        fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+            self.counter += 1;
+            self.key.seal([self.counter; 16], plaintext)
+        }
+
+        fn write_file(&mut self, directory: &str, plaintext: &[u8]) -> ResticID {
+            let sealed = self.seal(plaintext);
+            let id = ResticID::of(&sealed);
+            fs::write(self.path.join(directory).join(id.to_string()), sealed).unwrap();
+            id
+        }
+
+        /// Adds a blob to the pending pack, optionally compressing it
+        fn add_blob(&mut self, blob_type: &str, data: &[u8], compress: bool) -> ResticID {
+            let id = ResticID::of(data);
+            let (sealed, uncompressed) = if compress {
+                let compressed = zstd::stream::encode_all(data, 0).unwrap();
+                (
+                    self.seal(&compressed),
+                    format!(r#","uncompressed_length":{}"#, data.len()),
+                )
+            } else {
+                (self.seal(data), String::new())
+            };
+            self.index.push(format!(
+                r#"{{"id":"{}","type":"{}","offset":{},"length":{}{}}}"#,
+                id,
+                blob_type,
+                self.pack.len(),
+                sealed.len(),
+                uncompressed
+            ));
+            self.pack.extend_from_slice(&sealed);
+            id
+        }
+
+        /// Writes out the pending pack, and an index pointing into it
+        fn finish(&mut self) {
+            // Real packs have a header at the end, which importing never needs to read
+            let pack = std::mem::take(&mut self.pack);
+            let id = ResticID::of(&pack);
+            let directory = self.path.join("data").join(&id.to_string()[..2]);
+            fs::create_dir_all(&directory).unwrap();
+            fs::write(directory.join(id.to_string()), pack).unwrap();
+            let index = format!(
+                r#"{{"packs":[{{"id":"{}","blobs":[{}]}}]}}"#,
+                id,
+                self.index.join(",")
+            );
+            self.write_file("index", index.as_bytes());
+        }
+    }
+
+    #[test]
+    fn import() {
+        let tempdir = tempdir().unwrap();
+        let mut fixture = Fixture::new(tempdir.path(), b"restic", 2);
+        let first: Vec<u8> = (0..20_000_u32).map(|x| (x % 251) as u8).collect();
+        let second = b"The second half of the file".to_vec();
+        let first_id = fixture.add_blob("data", &first, false);
+        let second_id = fixture.add_blob("data", &second, true);
+        let inner = format!(
+            r#"{{"nodes":[
+                {{"name":"file","type":"file","size":{},"content":["{}","{}"]}},
+                {{"name":"empty","type":"file","size":0,"content":[]}},
+                {{"name":"link","type":"symlink","linktarget":"file"}},
+                {{"name":"fifo","type":"fifo"}}
+            ]}}"#,
+            first.len() + second.len(),
+            first_id,
+            second_id
+        );
+        let inner_id = fixture.add_blob("tree", inner.as_bytes(), true);
+        let root =
+            format!(r#"{{"nodes":[{{"name":"home","type":"dir","subtree":"{inner_id}"}}]}}"#);
+        let root_id = fixture.add_blob("tree", root.as_bytes(), false);
+        fixture.finish();
+        let snapshot = format!(
+            r#"{{"time":"2020-05-01T12:00:00.5+02:00","tree":"{root_id}","paths":["/home"],"hostname":"host"}}"#
+        );
+        // Version 2 repositories compress their unpacked files
+        let mut compressed = vec![COMPRESSED_FILE];
+        compressed.extend(zstd::stream::encode_all(snapshot.as_bytes(), 0).unwrap());
+        let snapshot_id = fixture.write_file("snapshots", &compressed);
+
+        assert!(matches!(
+            ResticRepository::open(tempdir.path(), b"wrong"),
+            Err(ResticError::WrongPassword)
+        ));
+        let restic = Arc::new(ResticRepository::open(tempdir.path(), b"restic").unwrap());
+        let snapshots = restic.snapshots().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].id, snapshot_id);
+        assert_eq!(snapshots[0].hostname, "host");
+        assert_eq!(snapshots[0].paths, vec!["/home".to_string()]);
+
+        smol::run(async {
+            let key = Key::random(32);
+            let backend = Mem::new(ChunkSettings::lightweight(), key.clone(), 4);
+            let mut repo = Repository::with(backend, ChunkSettings::lightweight(), key, 2).unwrap();
+            let mut archive = ActiveArchive::new("restic");
+            import_snapshot(
+                &restic,
+                &snapshots[0],
+                &mut repo,
+                &FastCDC::default(),
+                &mut archive,
+            )
+            .await
+            .unwrap();
+
+            let listing = archive.listing().await;
+            let mut paths: Vec<String> = listing.into_iter().map(|node| node.path).collect();
+            paths.sort();
+            assert_eq!(paths, ["home", "home/empty", "home/file", "home/link"]);
+
+            let data_archive = archive.namespace_append("");
+            let mut output = Vec::new();
+            data_archive
+                .get_object(&mut repo, "home/file", &mut output)
+                .await
+                .unwrap();
+            let mut expected = first.clone();
+            expected.extend_from_slice(&second);
+            assert_eq!(output, expected);
+        });
+    }
+
+    #[test]
+    fn corrupt_blob() {
+        let tempdir = tempdir().unwrap();
+        let mut fixture = Fixture::new(tempdir.path(), b"restic", 1);
+        let id = fixture.add_blob("data", b"Some data", false);
+        fixture.finish();
+        // Flip a bit in the middle of the pack
+        let pack_dir = fs::read_dir(tempdir.path().join("data"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let pack = fs::read_dir(pack_dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let mut data = fs::read(&pack).unwrap();
+        data[20] ^= 1;
+        fs::write(&pack, data).unwrap();
+
+        let restic = ResticRepository::open(tempdir.path(), b"restic").unwrap();
+        assert!(matches!(
+            restic.read_blob(id),
+            Err(ResticError::Authentication(_))
+        ));
+        assert!(matches!(
+            restic.read_blob(ResticID::default()),
+            Err(ResticError::MissingBlob(_))
+        ));
+    }
+}
@@ -0,0 +1,485 @@
+//! The cryptographic primitives used by restic repositories
+//!
+//! Restic seals everything it writes as `IV || AES-256-CTR(ciphertext) || MAC`, where the MAC
+//! is Poly1305-AES over the ciphertext, using the IV as the nonce. The master key that does all
+//! of this is stored in the key files, sealed with a key derived from the password with scrypt.
+//!
+//! Neither scrypt nor Poly1305 are used anywhere else in asuran, so they are implemented here
+//! directly on top of the primitives asuran already depends on.
+use aes::block_cipher_trait::generic_array::GenericArray;
+use aes::block_cipher_trait::BlockCipher;
+use aes::{Aes128, Aes256};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use std::convert::TryInto;
+
+/// Size of the IV at the start of every sealed message
+const IV_SIZE: usize = 16;
+/// Size of the MAC at the end of every sealed message
+const MAC_SIZE: usize = 16;
+/// Largest amount of memory scrypt is allowed to use, restic's defaults use 32MiB
+const MAX_SCRYPT_MEMORY: u64 = 1 << 30;
+
+/// The pair of keys restic uses to seal its data
+#[derive(Clone, Zeroize)]
+#[zeroize(drop)]
+pub struct MasterKey {
+    encrypt: [u8; 32],
+    mac_k: [u8; 16],
+    mac_r: [u8; 16],
+}
+
+impl MasterKey {
+    /// Creates a key from its raw parts
+    pub fn new(encrypt: [u8; 32], mac_k: [u8; 16], mac_r: [u8; 16]) -> MasterKey {
+        MasterKey {
+            encrypt,
+            mac_k,
+            mac_r,
+        }
+    }
+
+    /// Splits the output of the key derivation function into a key
+    fn from_derived(bytes: &[u8; 64]) -> MasterKey {
+        let mut key = MasterKey::new([0; 32], [0; 16], [0; 16]);
+        key.encrypt.copy_from_slice(&bytes[..32]);
+        key.mac_k.copy_from_slice(&bytes[32..48]);
+        key.mac_r.copy_from_slice(&bytes[48..]);
+        key
+    }
+
+    /// Derives the key that seals a key file from the user's password
+    ///
+    /// Returns `None` if the parameters are invalid, or would use an unreasonable amount of
+    /// memory.
+    pub fn derive(password: &[u8], salt: &[u8], n: u64, r: u32, p: u32) -> Option<MasterKey> {
+        let mut bytes = [0_u8; 64];
+        scrypt(password, salt, n, r, p, &mut bytes)?;
+        let key = MasterKey::from_derived(&bytes);
+        bytes.zeroize();
+        Some(key)
+    }
+
+    /// Checks the MAC of a sealed message, and returns its plaintext
+    ///
+    /// Returns `None` if the message is too short or has been tampered with.
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < IV_SIZE + MAC_SIZE {
+            return None;
+        }
+        let (iv, rest) = sealed.split_at(IV_SIZE);
+        let (ciphertext, mac) = rest.split_at(rest.len() - MAC_SIZE);
+        let expected = self.mac(iv, ciphertext);
+        // Compare in constant time
+        let difference = expected
+            .iter()
+            .zip(mac)
+            .fold(0_u8, |acc, (x, y)| acc | (x ^ y));
+        if difference != 0 {
+            return None;
+        }
+        let mut plaintext = ciphertext.to_vec();
+        self.apply_keystream(iv, &mut plaintext);
+        Some(plaintext)
+    }
+
+    /// Seals a message with the provided IV
+    ///
+    /// Asuran never writes to restic repositories, this only exists to build test fixtures.
+    #[cfg(test)]
+    pub fn seal(&self, iv: [u8; 16], plaintext: &[u8]) -> Vec<u8> {
+        let mut ciphertext = plaintext.to_vec();
+        self.apply_keystream(&iv, &mut ciphertext);
+        let mac = self.mac(&iv, &ciphertext);
+        let mut sealed = iv.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed.extend_from_slice(&mac);
+        sealed
+    }
+
+    /// Applies AES-256-CTR
+    ///
+    /// The counter is the whole 128 bit IV, incremented as a big endian integer, which is
+    /// what restic does, but not what the `aes-ctr` crate does, so CTR is done by hand here.
+    fn apply_keystream(&self, iv: &[u8], data: &mut [u8]) {
+        let cipher = Aes256::new(GenericArray::from_slice(&self.encrypt));
+        let mut iv_bytes = [0_u8; 16];
+        iv_bytes.copy_from_slice(iv);
+        let mut counter = u128::from_be_bytes(iv_bytes);
+        for block in data.chunks_mut(16) {
+            let mut keystream = GenericArray::clone_from_slice(&counter.to_be_bytes());
+            cipher.encrypt_block(&mut keystream);
+            for (byte, key) in block.iter_mut().zip(keystream.iter()) {
+                *byte ^= key;
+            }
+            counter = counter.wrapping_add(1);
+        }
+    }
+
+    /// Computes the Poly1305-AES MAC of a ciphertext
+    fn mac(&self, nonce: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+        let mut key = [0_u8; 32];
+        key[..16].copy_from_slice(&self.mac_r);
+        let mut s = GenericArray::clone_from_slice(nonce);
+        Aes128::new(GenericArray::from_slice(&self.mac_k)).encrypt_block(&mut s);
+        key[16..].copy_from_slice(&s);
+        let tag = poly1305(&key, ciphertext);
+        key.zeroize();
+        tag
+    }
+}
+
+/// The master key, as stored inside a key file
+#[derive(Deserialize)]
+pub struct StoredKey {
+    mac: StoredMacKey,
+    #[serde(with = "base64_bytes")]
+    encrypt: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct StoredMacKey {
+    #[serde(with = "base64_bytes")]
+    k: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    r: Vec<u8>,
+}
+
+impl StoredKey {
+    /// Converts the stored key into a usable one
+    ///
+    /// Returns `None` if any of the parts have the wrong length.
+    pub fn into_key(mut self) -> Option<MasterKey> {
+        let key = MasterKey::new(
+            self.encrypt.as_slice().try_into().ok()?,
+            self.mac.k.as_slice().try_into().ok()?,
+            self.mac.r.as_slice().try_into().ok()?,
+        );
+        self.encrypt.zeroize();
+        self.mac.k.zeroize();
+        self.mac.r.zeroize();
+        Some(key)
+    }
+}
+
+/// Serde support for the base64 encoded byte strings restic uses in its JSON
+pub mod base64_bytes {
+    use serde::{de, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        base64::decode(&string).map_err(de::Error::custom)
+    }
+}
+
+/// Reads a little endian word out of the first four bytes of a slice
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Computes the Poly1305 tag of a message, with a key made up of `r` followed by `s`
+///
+/// This follows the 26-bit limb implementation from poly1305-donna. `r` is clamped here.
+// Limbs are masked to 26 bits before being narrowed
+#[allow(clippy::cast_possible_truncation)]
+fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; 16] {
+    const MASK: u32 = 0x03ff_ffff;
+    let r0 = le32(&key[0..]) & 0x03ff_ffff;
+    let r1 = (le32(&key[3..]) >> 2) & 0x03ff_ff03;
+    let r2 = (le32(&key[6..]) >> 4) & 0x03ff_c0ff;
+    let r3 = (le32(&key[9..]) >> 6) & 0x03f0_3fff;
+    let r4 = (le32(&key[12..]) >> 8) & 0x000f_ffff;
+    let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+    let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0_u32, 0_u32, 0_u32, 0_u32, 0_u32);
+
+    for block in message.chunks(16) {
+        let mut padded = [0_u8; 16];
+        padded[..block.len()].copy_from_slice(block);
+        // Full blocks get their high bit set past the end of the block, partial blocks get a
+        // one byte appended instead
+        let hibit = if block.len() == 16 {
+            1 << 24
+        } else {
+            padded[block.len()] = 1;
+            0
+        };
+        h0 += le32(&padded[0..]) & MASK;
+        h1 += (le32(&padded[3..]) >> 2) & MASK;
+        h2 += (le32(&padded[6..]) >> 4) & MASK;
+        h3 += (le32(&padded[9..]) >> 6) & MASK;
+        h4 += (le32(&padded[12..]) >> 8) | hibit;
+
+        let m = |a: u32, b: u32| u64::from(a) * u64::from(b);
+        let d0 = m(h0, r0) + m(h1, s4) + m(h2, s3) + m(h3, s2) + m(h4, s1);
+        let mut d1 = m(h0, r1) + m(h1, r0) + m(h2, s4) + m(h3, s3) + m(h4, s2);
+        let mut d2 = m(h0, r2) + m(h1, r1) + m(h2, r0) + m(h3, s4) + m(h4, s3);
+        let mut d3 = m(h0, r3) + m(h1, r2) + m(h2, r1) + m(h3, r0) + m(h4, s4);
+        let mut d4 = m(h0, r4) + m(h1, r3) + m(h2, r2) + m(h3, r1) + m(h4, r0);
+
+        d1 += d0 >> 26;
+        h0 = d0 as u32 & MASK;
+        d2 += d1 >> 26;
+        h1 = d1 as u32 & MASK;
+        d3 += d2 >> 26;
+        h2 = d2 as u32 & MASK;
+        d4 += d3 >> 26;
+        h3 = d3 as u32 & MASK;
+        h0 += (d4 >> 26) as u32 * 5;
+        h4 = d4 as u32 & MASK;
+        h1 += h0 >> 26;
+        h0 &= MASK;
+    }
+
+    // Fully carry h
+    let mut c = h1 >> 26;
+    h1 &= MASK;
+    h2 += c;
+    c = h2 >> 26;
+    h2 &= MASK;
+    h3 += c;
+    c = h3 >> 26;
+    h3 &= MASK;
+    h4 += c;
+    c = h4 >> 26;
+    h4 &= MASK;
+    h0 += c * 5;
+    c = h0 >> 26;
+    h0 &= MASK;
+    h1 += c;
+
+    // Compute h - p, and select it if it did not underflow
+    let mut g0 = h0.wrapping_add(5);
+    c = g0 >> 26;
+    g0 &= MASK;
+    let mut g1 = h1.wrapping_add(c);
+    c = g1 >> 26;
+    g1 &= MASK;
+    let mut g2 = h2.wrapping_add(c);
+    c = g2 >> 26;
+    g2 &= MASK;
+    let mut g3 = h3.wrapping_add(c);
+    c = g3 >> 26;
+    g3 &= MASK;
+    let g4 = h4.wrapping_add(c).wrapping_sub(1 << 26);
+    let select = (g4 >> 31).wrapping_sub(1);
+    h0 = (h0 & !select) | (g0 & select);
+    h1 = (h1 & !select) | (g1 & select);
+    h2 = (h2 & !select) | (g2 & select);
+    h3 = (h3 & !select) | (g3 & select);
+    h4 = (h4 & !select) | (g4 & select);
+
+    // Pack h into 128 bits and add s
+    let words = [
+        h0 | (h1 << 26),
+        (h1 >> 6) | (h2 << 20),
+        (h2 >> 12) | (h3 << 14),
+        (h3 >> 18) | (h4 << 8),
+    ];
+    let mut tag = [0_u8; 16];
+    let mut carry = 0_u64;
+    for (i, word) in words.iter().enumerate() {
+        let f = u64::from(*word) + u64::from(le32(&key[16 + 4 * i..])) + carry;
+        tag[4 * i..4 * i + 4].copy_from_slice(&(f as u32).to_le_bytes());
+        carry = f >> 32;
+    }
+    tag
+}
+
+/// PBKDF2 with HMAC-SHA256, as used by scrypt
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], rounds: u32, output: &mut [u8]) {
+    let prf = Hmac::<Sha256>::new_varkey(password).expect("HMAC accepts keys of any length");
+    for (index, block) in (1_u32..).zip(output.chunks_mut(32)) {
+        let mut mac = prf.clone();
+        mac.input(salt);
+        mac.input(&index.to_be_bytes());
+        let mut u = mac.result().code();
+        let mut t = u;
+        for _ in 1..rounds {
+            let mut mac = prf.clone();
+            mac.input(&u);
+            u = mac.result().code();
+            for (t, u) in t.iter_mut().zip(u.iter()) {
+                *t ^= u;
+            }
+        }
+        block.copy_from_slice(&t[..block.len()]);
+    }
+}
+
+/// The Salsa20/8 core, applied in place
+fn salsa20_8(block: &mut [u32; 16]) {
+    let mut x = *block;
+    macro_rules! quarter {
+        ($a:expr, $b:expr, $c:expr, $d:expr) => {
+            x[$b] ^= x[$a].wrapping_add(x[$d]).rotate_left(7);
+            x[$c] ^= x[$b].wrapping_add(x[$a]).rotate_left(9);
+            x[$d] ^= x[$c].wrapping_add(x[$b]).rotate_left(13);
+            x[$a] ^= x[$d].wrapping_add(x[$c]).rotate_left(18);
+        };
+    }
+    for _ in 0..4 {
+        // Columns
+        quarter!(0, 4, 8, 12);
+        quarter!(5, 9, 13, 1);
+        quarter!(10, 14, 2, 6);
+        quarter!(15, 3, 7, 11);
+        // Rows
+        quarter!(0, 1, 2, 3);
+        quarter!(5, 6, 7, 4);
+        quarter!(10, 11, 8, 9);
+        quarter!(15, 12, 13, 14);
+    }
+    for (b, x) in block.iter_mut().zip(x.iter()) {
+        *b = b.wrapping_add(*x);
+    }
+}
+
+/// scrypt's `BlockMix`, reading `2r` 64 byte blocks from `input` into `output`
+fn block_mix(input: &[u32], output: &mut [u32], r: usize) {
+    let mut x = [0_u32; 16];
+    x.copy_from_slice(&input[(2 * r - 1) * 16..]);
+    for i in 0..2 * r {
+        for (x, b) in x.iter_mut().zip(&input[i * 16..(i + 1) * 16]) {
+            *x ^= b;
+        }
+        salsa20_8(&mut x);
+        // Even blocks go in the first half of the output, odd ones in the second
+        let position = (i / 2 + (i % 2) * r) * 16;
+        output[position..position + 16].copy_from_slice(&x);
+    }
+}
+
+/// scrypt's `ROMix`, mixing a `128r` byte block in place
+// The index is reduced modulo n, which fits in memory, and the names follow RFC 7914
+#[allow(clippy::cast_possible_truncation, clippy::many_single_char_names)]
+fn ro_mix(block: &mut [u8], n: usize, r: usize) {
+    let words = 32 * r;
+    let mut x: Vec<u32> = block.chunks(4).map(le32).collect();
+    let mut y = vec![0_u32; words];
+    let mut v = vec![0_u32; words * n];
+    for i in 0..n {
+        v[i * words..(i + 1) * words].copy_from_slice(&x);
+        block_mix(&x, &mut y, r);
+        std::mem::swap(&mut x, &mut y);
+    }
+    for _ in 0..n {
+        let j = (u64::from(x[(2 * r - 1) * 16]) | (u64::from(x[(2 * r - 1) * 16 + 1]) << 32))
+            % n as u64;
+        let j = j as usize;
+        for (x, v) in x.iter_mut().zip(&v[j * words..(j + 1) * words]) {
+            *x ^= v;
+        }
+        block_mix(&x, &mut y, r);
+        std::mem::swap(&mut x, &mut y);
+    }
+    for (bytes, word) in block.chunks_mut(4).zip(x.iter()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    v.zeroize();
+}
+
+/// The scrypt key derivation function
+///
+/// Returns `None` if `n` is not a power of two greater than one, if `r` or `p` are zero, or if
+/// the parameters would require more than `MAX_SCRYPT_MEMORY` bytes.
+// Memory use is bounded before anything is converted to a usize
+#[allow(clippy::cast_possible_truncation)]
+fn scrypt(password: &[u8], salt: &[u8], n: u64, r: u32, p: u32, output: &mut [u8]) -> Option<()> {
+    if n < 2 || !n.is_power_of_two() || r == 0 || p == 0 {
+        return None;
+    }
+    let block_size = 128 * u64::from(r);
+    if block_size.checked_mul(n)? > MAX_SCRYPT_MEMORY
+        || block_size.checked_mul(u64::from(p))? > MAX_SCRYPT_MEMORY
+    {
+        return None;
+    }
+    let block_size = block_size as usize;
+    let mut blocks = vec![0_u8; block_size * p as usize];
+    pbkdf2_sha256(password, salt, 1, &mut blocks);
+    for block in blocks.chunks_mut(block_size) {
+        ro_mix(block, n as usize, r as usize);
+    }
+    pbkdf2_sha256(password, &blocks, 1, output);
+    blocks.zeroize();
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(string: &str) -> Vec<u8> {
+        (0..string.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&string[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn scrypt_vectors() {
+        // Test vectors from RFC 7914
+        let mut output = [0_u8; 64];
+        scrypt(b"", b"", 16, 1, 1, &mut output).unwrap();
+        assert_eq!(
+            output.to_vec(),
+            unhex(
+                "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442\
+                 fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"
+            )
+        );
+        scrypt(b"password", b"NaCl", 1024, 8, 16, &mut output).unwrap();
+        assert_eq!(
+            output.to_vec(),
+            unhex(
+                "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162\
+                 2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+            )
+        );
+        assert!(scrypt(b"", b"", 15, 1, 1, &mut output).is_none());
+        assert!(scrypt(b"", b"", 1 << 40, 8, 1, &mut output).is_none());
+    }
+
+    #[test]
+    fn poly1305_vector() {
+        // Test vector from RFC 8439
+        let mut key = [0_u8; 32];
+        key.copy_from_slice(&unhex(
+            "85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b",
+        ));
+        assert_eq!(
+            poly1305(&key, b"Cryptographic Forum Research Group").to_vec(),
+            unhex("a8061dc1305136c6c22b8baf0c0127a9")
+        );
+    }
+
+    #[test]
+    fn seal_open() {
+        let key = MasterKey::new([1; 32], [2; 16], [3; 16]);
+        let sealed = key.seal([4; 16], b"Hello, restic");
+        assert_eq!(sealed.len(), 13 + IV_SIZE + MAC_SIZE);
+        assert_eq!(key.open(&sealed).unwrap(), b"Hello, restic");
+        let mut tampered = sealed.clone();
+        tampered[IV_SIZE] ^= 1;
+        assert!(key.open(&tampered).is_none());
+        assert!(key.open(&sealed[..IV_SIZE]).is_none());
+    }
+
+    #[test]
+    fn open_known_message() {
+        // Sealed independently, with an IV that makes the counter carry across all 128 bits
+        let key = MasterKey::new([1; 32], [2; 16], [3; 16]);
+        let sealed = unhex(
+            "fffffffffffffffffffffffffffffffe874b580e789d6291527b448950e6fcf2\
+             623cf8026175f3a869b519dd633d1a9705f7eac60a7670d9a3bc03b052c90013\
+             ddd37739785876bf4f15269a1e641d7dcf",
+        );
+        assert_eq!(
+            key.open(&sealed).unwrap(),
+            b"A message long enough to cross two counter blocks".to_vec()
+        );
+    }
+}
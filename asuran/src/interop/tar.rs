@@ -0,0 +1,256 @@
+//! Exports the contents of an archive as a tar stream
+//!
+//! The archive is walked in listing order, and each object's data is pulled from the repository
+//! chunk by chunk and written directly into the output, so an archive can be exported without
+//! first being extracted to disk.
+//!
+//! Entries are written as ustar headers, falling back to a pax extended header for paths and
+//! link targets that do not fit in the ustar name fields.
+use crate::manifest::archive::{ActiveArchive, ArchiveError};
+use crate::repository::{BackendClone, Repository};
+
+use asuran_core::manifest::listing::{Node, NodeType, SpecialFile};
+
+use tar::{EntryType, Header};
+use thiserror::Error;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+/// The size of a tar block, all entries are padded to a multiple of this
+const BLOCK_SIZE: u64 = 512;
+
+/// Error for all the things that can go wrong while exporting a tar
+#[derive(Error, Debug)]
+pub enum TarError {
+    #[error("I/O Error")]
+    IO(#[from] io::Error),
+    #[error("Failed to retrieve object from archive")]
+    Archive(#[from] ArchiveError),
+    #[error("Object at {path} was {actual} bytes long, but the listing claims {expected}")]
+    LengthMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+type Result<T> = std::result::Result<T, TarError>;
+
+/// Writes the contents of an archive to the provided writer as a tar stream.
+///
+/// Returns the writer after the end of archive marker has been written, so that any compression
+/// stream wrapping it can be properly finished.
+///
+/// Links are exported as symbolic links, except for those stored before the listing recorded
+/// their targets, which are skipped.
+pub async fn export_archive<W: Write>(
+    repository: &mut Repository<impl BackendClone>,
+    archive: &ActiveArchive,
+    mut writer: W,
+) -> Result<W> {
+    let mtime = u64::try_from(archive.timestamp().unix_seconds()).unwrap_or(0);
+    let listing = archive.listing().await;
+    for node in listing {
+        export_node(repository, archive, &node, mtime, &mut writer).await?;
+    }
+    // A tar archive is terminated by two empty blocks
+    write_zeros(&mut writer, 2 * BLOCK_SIZE)?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Writes a single node, and its data if it has any, to the tar stream
+async fn export_node<W: Write>(
+    repository: &mut Repository<impl BackendClone>,
+    archive: &ActiveArchive,
+    node: &Node,
+    mtime: u64,
+    writer: &mut W,
+) -> Result<()> {
+    let mut header = Header::new_ustar();
+    header.set_mtime(mtime);
+    header.set_uid(0);
+    header.set_gid(0);
+    match node.node_type {
+        NodeType::Directory { .. } => {
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            write_header(writer, header, &format!("{}/", node.path), None)?;
+        }
+        NodeType::File => {
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(node.total_length);
+            write_header(writer, header, &node.path, None)?;
+            if node.total_length > 0 {
+                let mut counter = CountingWriter {
+                    inner: &mut *writer,
+                    count: 0,
+                };
+                // Objects from a `BackupDriver` are stored in the sub namespace ""
+                archive
+                    .namespace_append("")
+                    .get_object(repository, &node.path, &mut counter)
+                    .await?;
+                let written = counter.count;
+                // Trailing holes are not written by `get_object`, so fill them in
+                if written < node.total_length {
+                    write_zeros(writer, node.total_length - written)?;
+                } else if written > node.total_length {
+                    return Err(TarError::LengthMismatch {
+                        path: node.path.clone(),
+                        expected: node.total_length,
+                        actual: written,
+                    });
+                }
+                pad_block(writer, node.total_length)?;
+            }
+        }
+        NodeType::Link => {
+            if let Some(target) = &node.metadata.link_target {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_mode(0o777);
+                header.set_size(0);
+                write_header(writer, header, &node.path, Some(target))?;
+            }
+        }
+        NodeType::Special { kind } => {
+            let (entry_type, mode, device) = match kind {
+                SpecialFile::Fifo => (EntryType::Fifo, 0o644, None),
+                SpecialFile::CharDevice { major, minor } => {
+                    (EntryType::Char, 0o600, Some((major, minor)))
+                }
+                SpecialFile::BlockDevice { major, minor } => {
+                    (EntryType::Block, 0o600, Some((major, minor)))
+                }
+            };
+            header.set_entry_type(entry_type);
+            header.set_mode(mode);
+            header.set_size(0);
+            if let Some((major, minor)) = device {
+                header.set_device_major(major)?;
+                header.set_device_minor(minor)?;
+            }
+            write_header(writer, header, &node.path, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the path, and the link target if there is one, on the given header, and writes it
+/// out, preceding it with a pax extended header if either is too long to fit in the ustar
+/// header.
+fn write_header<W: Write>(
+    writer: &mut W,
+    mut header: Header,
+    path: &str,
+    link_name: Option<&str>,
+) -> Result<()> {
+    let mut records = Vec::new();
+    match header.set_path(path) {
+        Ok(()) => {}
+        Err(_) if path.len() > 100 => {
+            records.extend(pax_record("path", path));
+            // Readers that do not understand pax headers will get a truncated path
+            let name = &mut header.as_old_mut().name;
+            let length = name.len();
+            name.copy_from_slice(&path.as_bytes()[..length]);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    if let Some(link_name) = link_name {
+        match header.set_link_name(link_name) {
+            Ok(()) => {}
+            Err(_) if link_name.len() > 100 => {
+                records.extend(pax_record("linkpath", link_name));
+                let name = &mut header.as_old_mut().linkname;
+                let length = name.len();
+                name.copy_from_slice(&link_name.as_bytes()[..length]);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if !records.is_empty() {
+        let mut pax_header = Header::new_ustar();
+        pax_header.set_entry_type(EntryType::XHeader);
+        pax_header.set_path("PaxHeader")?;
+        pax_header.set_mode(0o644);
+        pax_header.set_size(records.len() as u64);
+        pax_header.set_cksum();
+        writer.write_all(pax_header.as_bytes())?;
+        writer.write_all(&records)?;
+        pad_block(writer, records.len() as u64)?;
+    }
+    header.set_cksum();
+    writer.write_all(header.as_bytes())?;
+    Ok(())
+}
+
+/// Formats a pax extended header record.
+///
+/// Records are of the form "<length> <key>=<value>\n", where the length includes its own digits.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let rest = format!(" {key}={value}\n");
+    let mut length = rest.len();
+    while length != rest.len() + length.to_string().len() {
+        length = rest.len() + length.to_string().len();
+    }
+    format!("{length}{rest}").into_bytes()
+}
+
+/// Pads the stream out to the end of the current block, given the length of the entry
+fn pad_block<W: Write>(writer: &mut W, length: u64) -> io::Result<()> {
+    let remainder = length % BLOCK_SIZE;
+    if remainder == 0 {
+        Ok(())
+    } else {
+        write_zeros(writer, BLOCK_SIZE - remainder)
+    }
+}
+
+/// Writes the given number of zeros to the stream
+fn write_zeros<W: Write>(writer: &mut W, count: u64) -> io::Result<()> {
+    io::copy(&mut io::repeat(0).take(count), writer)?;
+    Ok(())
+}
+
+/// Wraps a writer and keeps track of how many bytes have been written through it
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pax_record_length() {
+        let record = pax_record("path", "a");
+        assert_eq!(record, b"9 path=a\n".to_vec());
+        // Crossing a digit boundary must account for the extra length digit
+        let value = "a".repeat(90);
+        let record = pax_record("path", &value);
+        let length: usize = std::str::from_utf8(&record)
+            .unwrap()
+            .split(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(length, record.len());
+    }
+}
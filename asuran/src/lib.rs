@@ -17,10 +17,19 @@ use uuid::Uuid;
 
 use std::convert::TryInto;
 
+pub mod api;
 pub mod chunker;
+pub mod error;
+pub mod interop;
 pub mod manifest;
+pub mod metrics;
 pub mod prelude;
 pub mod repository;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
+pub use crate::error::{Error, ErrorKind};
+pub use asuran_core::time;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -7,16 +7,53 @@
 //! The repository is not encapsulated in the manifest because the manifest needs
 //! to be triviallly serializeable and deserilazeable.
 pub mod archive;
+pub mod compare;
+pub mod copy;
 pub mod driver;
+pub mod hash;
+pub mod integrity;
+pub mod listing;
+pub mod resolve;
+pub mod retention;
+pub mod scan;
+pub mod signing;
 pub mod target;
+pub mod verify;
 
-pub use self::archive::{ActiveArchive, StoredArchive};
+use self::archive::ArchiveError;
+pub use self::archive::{ActiveArchive, ArchiveMetadata, StoreReport, StoredArchive};
+use self::integrity::ChunkIntegrity;
+use self::resolve::ResolveError;
+use self::signing::SigningKey;
 use crate::repository::backend::Manifest as BackendManifest;
-use crate::repository::backend::Result;
-use crate::repository::{Backend, BackendClone, ChunkSettings, Repository};
+use crate::repository::backend::{BackendError, Result};
+use crate::repository::{
+    Backend, BackendClone, ChunkID, ChunkSettings, Repository, RepositoryError,
+};
+use crate::time::Timestamp;
 
-use chrono::prelude::*;
+use futures::future::join_all;
 use serde::{Deserialize, Serialize};
+use smol::Task;
+use tracing::warn;
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Summary of the work performed by `Manifest::prune`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct PruneStats {
+    /// The number of archives removed from the manifest
+    pub archives_removed: usize,
+    /// The number of archives left in the manifest
+    pub archives_kept: usize,
+    /// The number of chunks, no longer referenced by any archive, removed from the index
+    pub chunks_removed: usize,
+    /// True if every remaining archive was loaded to find the chunks still referenced, rather
+    /// than using the repository's chunk reference counts
+    pub full_collection: bool,
+}
 
 /// Repository manifest
 ///
@@ -25,6 +62,9 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Manifest<T: Backend> {
     internal_manifest: T::Manifest,
+    /// The key archives committed through this manifest are signed with, if any
+    #[serde(skip)]
+    signing_key: Option<Arc<SigningKey>>,
 }
 
 impl<T: BackendClone> Manifest<T> {
@@ -38,7 +78,18 @@ impl<T: BackendClone> Manifest<T> {
         T: Backend,
     {
         let internal_manifest = repo.backend_manifest();
-        Manifest { internal_manifest }
+        Manifest {
+            internal_manifest,
+            signing_key: None,
+        }
+    }
+
+    /// Signs every archive and checkpoint committed through this manifest from now on with
+    /// `key`
+    ///
+    /// See `manifest::signing` for details.
+    pub fn sign_with(&mut self, key: SigningKey) {
+        self.signing_key = Some(Arc::new(key));
     }
 
     /// Set the Chunk Settings used by the repository
@@ -53,6 +104,9 @@ impl<T: BackendClone> Manifest<T> {
 
     /// Commits an archive to the manifest, then the manifest to the repository
     ///
+    /// The index is committed before the archive is written, so other connections never see the
+    /// archive before its chunks.
+    ///
     /// Consumes the repository while commiting it.
     ///
     /// # Panics
@@ -63,12 +117,102 @@ impl<T: BackendClone> Manifest<T> {
         repo: &mut Repository<impl BackendClone>,
         archive: ActiveArchive,
     ) -> Result<()> {
-        let stored_archive = archive.store(repo).await;
+        let mut stored_archive = archive.store(repo).await;
+        stored_archive.integrity = ChunkIntegrity::record(repo, stored_archive.id()).await?;
+        self.sign(&mut stored_archive);
+        count_references(repo, &stored_archive).await?;
+        // The chunks have to be committed before the archive referring to them, or another
+        // connection could see the archive without being able to find its chunks
+        repo.commit_index().await;
         self.internal_manifest.write_archive(stored_archive).await?;
         repo.commit_index().await;
         Ok(())
     }
 
+    /// Commits a checkpoint of an archive that is still being written to
+    ///
+    /// The checkpoint is a complete archive in its own right, tagged with
+    /// `ArchiveMetadata::CHECKPOINT_TAG`, containing whatever objects and listing have
+    /// been added to `archive` so far. Committing it also commits the index, so if the
+    /// backup is interrupted, the chunks it has stored stay known to the repository and
+    /// will be deduplicated against when the backup is run again.
+    ///
+    /// Callers are expected to pass in a clone of the archive they are writing to, and
+    /// should make sure its listing only refers to objects that have been completely
+    /// stored.
+    pub async fn commit_checkpoint(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        mut archive: ActiveArchive,
+    ) -> Result<StoredArchive> {
+        archive.add_tag(ArchiveMetadata::CHECKPOINT_TAG);
+        let mut stored_archive = archive.store(repo).await;
+        stored_archive.integrity = ChunkIntegrity::record(repo, stored_archive.id()).await?;
+        self.sign(&mut stored_archive);
+        count_references(repo, &stored_archive).await?;
+        repo.commit_index().await;
+        self.internal_manifest
+            .write_archive(stored_archive.clone())
+            .await?;
+        repo.commit_index().await;
+        Ok(stored_archive)
+    }
+
+    /// Signs an archive about to be committed, if a signing key has been set
+    fn sign(&self, archive: &mut StoredArchive) {
+        if let Some(key) = &self.signing_key {
+            archive.signature = Some(key.sign(archive));
+        }
+    }
+
+    /// Returns the checkpoints of unfinished backups in this repository, newest first
+    pub async fn checkpoints(&mut self) -> Vec<StoredArchive> {
+        let mut checkpoints = self
+            .archives()
+            .await
+            .into_iter()
+            .filter(|archive| archive.metadata().is_checkpoint())
+            .collect::<Vec<_>>();
+        checkpoints.sort_by_key(|archive| Reverse(archive.timestamp()));
+        checkpoints
+    }
+
+    /// Removes every checkpoint with the given archive name from the manifest, returning
+    /// how many were removed
+    ///
+    /// This should be called once the archive the checkpoints were taken of has been
+    /// committed. If the repository keeps chunk reference counts covering every archive in
+    /// it, the chunks only the checkpoints referred to are removed along with them.
+    /// Otherwise, the chunks are left alone, and anything the finished archive does not
+    /// refer to is collected by `prune`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the backend can not remove archives, such as when other
+    /// connections to the repository are open.
+    pub async fn remove_checkpoints(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        name: &str,
+    ) -> std::result::Result<usize, ArchiveError> {
+        let checkpoints = self
+            .checkpoints()
+            .await
+            .into_iter()
+            .filter(|archive| archive.name() == name)
+            .collect::<Vec<_>>();
+        if !checkpoints.is_empty() {
+            let all = self.archives().await;
+            let references = load_references(repo, &all, &checkpoints).await?;
+            let ids = checkpoints.iter().map(StoredArchive::id).collect();
+            repo.remove_archives(ids).await?;
+            if let Some(references) = references {
+                repo.release_references(references).await?;
+            }
+        }
+        Ok(checkpoints.len())
+    }
+
     /// Returns a copy of the list of archives in this repository
     ///
     /// Theses can be converted into full archives with `StoredArchive::load`
@@ -76,12 +220,279 @@ impl<T: BackendClone> Manifest<T> {
         self.internal_manifest.archive_iterator().await.collect()
     }
 
+    /// Finds the archive `spec` refers to, which may be its name, its index in `archives`,
+    /// `latest`, or a prefix, see `resolve::resolve_archive`
+    ///
+    /// Backends that do not record the names of archives in their manifests, such as
+    /// `FlatFile`, have their archives loaded to match against the names inside of them.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if no archive matches, if a prefix matches more than one, or if
+    /// an archive that has to be loaded fails to load
+    pub async fn resolve_archive(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        spec: &str,
+    ) -> std::result::Result<StoredArchive, ResolveError> {
+        let archives = self.archives().await;
+        // The names are only filled in to match against, as they are covered by the
+        // signatures of the archives
+        let mut named = archives.clone();
+        for archive in named.iter_mut().filter(|x| x.name.is_empty()) {
+            archive.name = archive.load(repo).await?.name().to_string();
+        }
+        let found = resolve::resolve_archive(&named, spec)?;
+        // Hand back the archive as it is stored, without the name filled in for matching
+        Ok(archives
+            .into_iter()
+            .find(|x| x.id() == found.id())
+            .unwrap_or(found))
+    }
+
+    /// Loads every archive in this repository, in the same order as `archives`
+    ///
+    /// The archive metadata chunks are all requested up front and fetched concurrently,
+    /// rather than one after another, so that backends with high latency only pay for
+    /// roughly one round trip instead of one per archive.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the archives fail to load
+    pub async fn load_archives(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+    ) -> std::result::Result<Vec<ActiveArchive>, ArchiveError> {
+        let fetches = self
+            .archives()
+            .await
+            .into_iter()
+            .map(|stored_archive| {
+                let mut repo = repo.clone();
+                Task::spawn(async move { stored_archive.load(&mut repo).await })
+            })
+            .collect::<Vec<_>>();
+        join_all(fetches).await.into_iter().collect()
+    }
+
+    /// Removes the given archives from the repository, along with every chunk that is no
+    /// longer referenced by any of the remaining archives
+    ///
+    /// If the repository keeps chunk reference counts covering every archive in it, only the
+    /// archives being removed are loaded, and the chunks whose counts drop to zero are
+    /// removed. Otherwise this falls back to `prune_full`, which also establishes the
+    /// reference counts on backends that can keep them. The chunks are only removed from the
+    /// index, run `Repository::compact` afterwards to reclaim the space they took up.
+    ///
+    /// Chunks that were already unreferenced, such as those left behind by interrupted
+    /// stores, are only found by `prune_full`, which should be run every so often as a
+    /// backstop.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the archives that have to be loaded fail to load, or if
+    /// the backend can not remove archives or chunks, such as when other connections to the
+    /// repository are open.
+    pub async fn prune(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        archives: &[StoredArchive],
+    ) -> std::result::Result<PruneStats, ArchiveError> {
+        let all = self.archives().await;
+        let removed = archives
+            .iter()
+            .map(StoredArchive::id)
+            .collect::<HashSet<ChunkID>>();
+        let (to_remove, to_keep): (Vec<_>, Vec<_>) = all
+            .iter()
+            .cloned()
+            .partition(|archive| removed.contains(&archive.id()));
+        let Some(references) = load_references(repo, &all, &to_remove).await? else {
+            return self.prune_full(repo, archives).await;
+        };
+        if !to_remove.is_empty() {
+            repo.remove_archives(removed).await?;
+        }
+        let chunks_removed = repo.release_references(references).await?;
+        Ok(PruneStats {
+            archives_removed: to_remove.len(),
+            archives_kept: to_keep.len(),
+            chunks_removed,
+            full_collection: false,
+        })
+    }
+
+    /// Removes the given archives from the repository, along with every chunk that is not
+    /// referenced by any of the remaining archives, found by loading all of them
+    ///
+    /// Unreferenced chunks are collected even if `archives` is empty, cleaning up after
+    /// interrupted prunes and stores. The chunks are only removed from the index, run
+    /// `Repository::compact` afterwards to reclaim the space they took up. The repository's
+    /// chunk reference counts are then replaced with the ones found along the way, if its
+    /// backend can keep them.
+    ///
+    /// The chunks referenced by the remaining archives are found before anything is
+    /// removed, so an interrupted prune can leave behind unreferenced chunks, but never
+    /// an archive referring to a removed chunk.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the remaining archives fail to load, or if the backend
+    /// can not remove archives or chunks, such as when other connections to the
+    /// repository are open.
+    pub async fn prune_full(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        archives: &[StoredArchive],
+    ) -> std::result::Result<PruneStats, ArchiveError> {
+        let removed = archives
+            .iter()
+            .map(StoredArchive::id)
+            .collect::<HashSet<ChunkID>>();
+        let (to_remove, to_keep): (Vec<_>, Vec<_>) = self
+            .archives()
+            .await
+            .into_iter()
+            .partition(|archive| removed.contains(&archive.id()));
+        // Find everything the remaining archives refer to, and how many of them do
+        let fetches = to_keep
+            .iter()
+            .cloned()
+            .map(|stored_archive| {
+                let mut repo = repo.clone();
+                Task::spawn(async move { referenced_chunks(&mut repo, &stored_archive).await })
+            })
+            .collect::<Vec<_>>();
+        let mut counts: HashMap<ChunkID, u64> = HashMap::new();
+        for ids in join_all(fetches).await {
+            for id in ids? {
+                *counts.entry(id).or_insert(0) += 1;
+            }
+        }
+        if !to_remove.is_empty() {
+            repo.remove_archives(removed).await?;
+        }
+        let live = counts.keys().copied().collect::<HashSet<_>>();
+        let chunks_removed = repo.collect_garbage(&live).await?;
+        let counted = to_keep.iter().map(StoredArchive::id).collect();
+        match repo.reset_references(counted, counts).await {
+            Err(RepositoryError::BackendError(BackendError::Unsupported(_))) | Ok(()) => (),
+            Err(error) => return Err(error.into()),
+        }
+        Ok(PruneStats {
+            archives_removed: to_remove.len(),
+            archives_kept: to_keep.len(),
+            chunks_removed,
+            full_collection: true,
+        })
+    }
+
     /// Provides the timestamp of the manifest's last modification
-    pub async fn timestamp(&mut self) -> Result<DateTime<FixedOffset>> {
+    pub async fn timestamp(&mut self) -> Result<Timestamp> {
         self.internal_manifest.last_modification().await
     }
 }
 
+/// Returns every chunk `archive` keeps alive: its own pointer, the chunks its objects and
+/// listing are made up of, and the chunks covered by its integrity record
+async fn referenced_chunks(
+    repo: &mut Repository<impl BackendClone>,
+    archive: &StoredArchive,
+) -> std::result::Result<HashSet<ChunkID>, ArchiveError> {
+    let mut chunks = archive.load(repo).await?.chunk_ids();
+    chunks.insert(archive.id());
+    // Keep the chunks covered by the integrity record, so collecting them does not look
+    // like tampering to `verify_integrity`
+    if let Some(integrity) = archive.integrity() {
+        chunks.insert(integrity.listing);
+        if let Ok(Some(listed)) = integrity.load_listing(repo, archive.id()).await {
+            chunks.extend(listed.into_iter().map(|(id, _)| id));
+        }
+    }
+    Ok(chunks)
+}
+
+/// Adds the references of an archive about to be written to the manifest to the repository's
+/// chunk reference counts, if it keeps them, and commits the index
+///
+/// An archive that can not be loaded back is left out of the counts, which only means the next
+/// prune has to fall back to a full garbage collection.
+async fn count_references(
+    repo: &mut Repository<impl BackendClone>,
+    archive: &StoredArchive,
+) -> Result<()> {
+    if repo
+        .counted_archives()
+        .await
+        .map_err(backend_error)?
+        .is_none()
+    {
+        return Ok(());
+    }
+    match referenced_chunks(repo, archive).await {
+        Ok(chunks) => {
+            repo.add_references(archive.id(), chunks)
+                .await
+                .map_err(backend_error)?;
+            repo.commit_index().await;
+        }
+        Err(error) => warn!(
+            "Leaving archive {} out of the reference counts, as it could not be loaded: {:?}",
+            archive.id().to_hex(),
+            error
+        ),
+    }
+    Ok(())
+}
+
+/// Loads the chunks each of `removed` refers to, for releasing their references, if the
+/// repository's chunk reference counts can be used
+///
+/// Returns `None` if the repository does not keep reference counts, if any of `all`, the
+/// archives in the manifest, is not counted, or if any of `removed` fails to load.
+async fn load_references(
+    repo: &mut Repository<impl BackendClone>,
+    all: &[StoredArchive],
+    removed: &[StoredArchive],
+) -> std::result::Result<Option<HashMap<ChunkID, HashSet<ChunkID>>>, ArchiveError> {
+    let Some(counted) = repo.counted_archives().await? else {
+        return Ok(None);
+    };
+    if all.iter().any(|archive| !counted.contains(&archive.id())) {
+        return Ok(None);
+    }
+    let fetches = removed
+        .iter()
+        .cloned()
+        .map(|stored_archive| {
+            let mut repo = repo.clone();
+            Task::spawn(async move {
+                referenced_chunks(&mut repo, &stored_archive)
+                    .await
+                    .map(|chunks| (stored_archive.id(), chunks))
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut references = HashMap::new();
+    for result in join_all(fetches).await {
+        match result {
+            Ok((archive, chunks)) => {
+                references.insert(archive, chunks);
+            }
+            Err(_) => return Ok(None),
+        }
+    }
+    Ok(Some(references))
+}
+
+/// Unwraps the backend error out of a repository error
+fn backend_error(error: RepositoryError) -> BackendError {
+    match error {
+        RepositoryError::BackendError(error) => error,
+        error => BackendError::Unknown(error.to_string()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -94,11 +505,13 @@ mod tests {
                 encryption: Encryption::NoEncryption,
                 compression: Compression::NoCompression,
                 hmac: HMAC::Blake2b,
+                id: ChunkIDSettings::default(),
+                chunker: None,
             };
 
             let key = Key::random(32);
             let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
-            let repo = Repository::with(backend, settings, key, 2);
+            let repo = Repository::with(backend, settings, key, 2).unwrap();
             let mut manifest = Manifest::load(&repo);
 
             manifest.set_chunk_settings(settings).await.unwrap();
@@ -114,7 +527,7 @@ mod tests {
             let settings = ChunkSettings::lightweight();
             let key = Key::random(32);
             let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
-            let repo = Repository::with(backend.clone(), settings, key, 2);
+            let repo = Repository::with(backend.clone(), settings, key, 2).unwrap();
 
             let mut manifest = Manifest::load(&repo);
 
@@ -129,4 +542,27 @@ mod tests {
             assert!(time2 > time1);
         });
     }
+
+    #[test]
+    fn load_archives_in_order() {
+        smol::run(async {
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let mut manifest = Manifest::load(&repo);
+
+            for name in &["first", "second", "third"] {
+                let archive = ActiveArchive::new(name);
+                manifest.commit_archive(&mut repo, archive).await.unwrap();
+            }
+
+            let stored = manifest.archives().await;
+            let loaded = manifest.load_archives(&mut repo).await.unwrap();
+            assert_eq!(stored.len(), 3);
+            let stored_names = stored.iter().map(StoredArchive::name).collect::<Vec<_>>();
+            let loaded_names = loaded.iter().map(ActiveArchive::name).collect::<Vec<_>>();
+            assert_eq!(stored_names, loaded_names);
+        });
+    }
 }
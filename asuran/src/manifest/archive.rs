@@ -1,23 +1,38 @@
 use crate::chunker::AsyncChunker;
+use crate::manifest::hash::{ObjectHash, ObjectHashAlgorithm};
+use crate::manifest::integrity::ChunkIntegrity;
+use crate::manifest::listing::ListingTree;
+use crate::manifest::signing::ArchiveSignature;
+use crate::manifest::target::SkippedEntry;
 use crate::repository::backend::common::manifest::ManifestTransaction;
-use crate::repository::{BackendClone, ChunkID, Repository};
+use crate::repository::backend::BackendError;
+use crate::repository::{
+    BackendClone, ChunkID, ChunkSettings, ChunkWrite, ReadChunk, Repository, RepositoryError,
+};
+use crate::time::Timestamp;
 
-pub use asuran_core::manifest::archive::{Archive, ChunkLocation, Extent};
+pub use asuran_core::manifest::archive::{Archive, ArchiveMetadata, ChunkLocation, Extent};
 pub use asuran_core::manifest::listing::{Listing, Node, NodeType};
 
-use chrono::prelude::*;
 use dashmap::DashMap;
-use futures::future::join_all;
+use futures::future::{join_all, Either};
+use futures::io::{AsyncRead, AsyncWriteExt};
 use futures::stream::StreamExt;
 use piper::Lock;
 use rmp_serde::{Deserializer, Serializer};
+use serde::de::IgnoredAny;
+use serde::ser::SerializeStruct;
 use serde::{Deserialize, Serialize};
-use smol::Task;
+use smol::{blocking, Task};
 use thiserror::Error;
 
-use std::collections::VecDeque;
-use std::io::{Read, Write};
-use std::sync::Arc;
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 /// Error for all the things that can go wrong with handling Archives
 #[derive(Error, Debug)]
@@ -32,6 +47,77 @@ pub enum ArchiveError {
 
 type Result<T> = std::result::Result<T, ArchiveError>;
 
+/// Summary of the chunks written while putting objects into an archive
+///
+/// Returned by `ActiveArchive::put_object` and `ActiveArchive::put_sparse_object`, and
+/// can be summed across objects with `merge`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StoreReport {
+    /// The number of chunks that were new to the repository, and were written
+    pub chunks_written: u64,
+    /// The number of chunks that were already in the repository
+    pub chunks_deduplicated: u64,
+    /// The number of bytes read from the objects
+    pub bytes_read: u64,
+    /// The number of bytes read that were part of chunks already in the repository
+    pub bytes_deduplicated: u64,
+    /// The number of bytes handed to the backend, after compression and encryption
+    pub bytes_written: u64,
+    /// Objects that could not be read while storing them, and were left out of the
+    /// archive, see `BackupDriver::store_object`
+    pub failed: Vec<SkippedEntry>,
+}
+
+impl StoreReport {
+    /// Accounts for a single chunk
+    pub fn record(&mut self, chunk: &ChunkWrite) {
+        self.bytes_read += chunk.plaintext_length;
+        if chunk.already_present {
+            self.chunks_deduplicated += 1;
+            self.bytes_deduplicated += chunk.plaintext_length;
+        } else {
+            self.chunks_written += 1;
+            self.bytes_written += chunk.stored_length;
+        }
+    }
+
+    /// Adds the counts from another report to this one
+    pub fn merge(&mut self, other: &StoreReport) {
+        self.chunks_written += other.chunks_written;
+        self.chunks_deduplicated += other.chunks_deduplicated;
+        self.bytes_read += other.bytes_read;
+        self.bytes_deduplicated += other.bytes_deduplicated;
+        self.bytes_written += other.bytes_written;
+        self.failed.extend(other.failed.iter().cloned());
+    }
+
+    /// The ratio of the bytes of new chunks to the bytes written for them, the savings
+    /// from compression alone
+    ///
+    /// Returns None if nothing was written.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.bytes_written == 0 {
+            None
+        } else {
+            Some((self.bytes_read - self.bytes_deduplicated) as f64 / self.bytes_written as f64)
+        }
+    }
+
+    /// The ratio of the bytes read to the bytes written, the savings from deduplication
+    /// and compression combined
+    ///
+    /// Returns None if nothing was written.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn effective_ratio(&self) -> Option<f64> {
+        if self.bytes_written == 0 {
+            None
+        } else {
+            Some(self.bytes_read as f64 / self.bytes_written as f64)
+        }
+    }
+}
+
 /// A 'heavy' pointer to a an `Archive` in a repository.
 ///
 /// Contains the `ChunkID` of the chunk the `Archive` is serialized in, as well as
@@ -39,7 +125,10 @@ type Result<T> = std::result::Result<T, ArchiveError>;
 ///
 /// Currently also contains the name of the `Archive`, but adding this was a mistake
 /// as it leaks information that should not be leaked, so it will be removed soon.
-#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+///
+/// `Serialize` is implemented by hand, as the trailing optional fields must be left out of
+/// the encoding when unset, without shifting the position of the fields before them.
+#[derive(Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct StoredArchive {
     /// The name of the archive
     pub name: String,
@@ -48,7 +137,72 @@ pub struct StoredArchive {
     /// Time the archive was started it
     ///
     /// Used to prevent replay attackts
-    pub timestamp: DateTime<FixedOffset>,
+    pub timestamp: Timestamp,
+    /// The tags and metadata of the archive
+    ///
+    /// Left out of the encoding when empty, so archives recorded in checkpoints written
+    /// before archives had metadata still verify.
+    #[serde(default)]
+    pub metadata: ArchiveMetadata,
+    /// The record of the chunks committed along with the archive, if one was kept
+    ///
+    /// Left out of the encoding when not set, like `metadata`.
+    #[serde(default)]
+    pub integrity: Option<ChunkIntegrity>,
+    /// The signature of the client that made the archive, if it was signed
+    ///
+    /// Left out of the encoding when not set, like `metadata`.
+    #[serde(default)]
+    pub signature: Option<ArchiveSignature>,
+    /// How long the archive took to store, if it was recorded
+    ///
+    /// Left out of the encoding when not set, like `metadata`.
+    #[serde(default)]
+    pub duration: Option<Duration>,
+}
+
+impl Serialize for StoredArchive {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        // Optional fields have to be written, even if unset, to hold their position whenever
+        // a later field follows them
+        let write_duration = self.duration.is_some();
+        let write_signature = self.signature.is_some() || write_duration;
+        let write_integrity = self.integrity.is_some() || write_signature;
+        let write_metadata = !self.metadata.is_empty() || write_integrity;
+        let len = 3
+            + usize::from(write_metadata)
+            + usize::from(write_integrity)
+            + usize::from(write_signature)
+            + usize::from(write_duration);
+        let mut state = serializer.serialize_struct("StoredArchive", len)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("timestamp", &self.timestamp)?;
+        if write_metadata {
+            state.serialize_field("metadata", &self.metadata)?;
+        } else {
+            state.skip_field("metadata")?;
+        }
+        if write_integrity {
+            state.serialize_field("integrity", &self.integrity)?;
+        } else {
+            state.skip_field("integrity")?;
+        }
+        if write_signature {
+            state.serialize_field("signature", &self.signature)?;
+        } else {
+            state.skip_field("signature")?;
+        }
+        if write_duration {
+            state.serialize_field("duration", &self.duration)?;
+        } else {
+            state.skip_field("duration")?;
+        }
+        state.end()
+    }
 }
 
 impl StoredArchive {
@@ -56,27 +210,60 @@ impl StoredArchive {
     pub async fn load(&self, repo: &mut Repository<impl BackendClone>) -> Result<ActiveArchive> {
         let bytes = repo.read_chunk(self.id).await?;
         let mut de = Deserializer::new(&bytes[..]);
-        let dumb_archive: Archive =
+        let mut dumb_archive: Archive =
             Deserialize::deserialize(&mut de).expect("Unable to deserialize archive");
-        let archive = ActiveArchive::from_archive(dumb_archive);
+        let mut listing_chunks = Vec::new();
+        if let Some(root) = dumb_archive.listing_root {
+            let (listing, ids) = ListingTree::new(root).load(repo).await?;
+            dumb_archive.listing = listing;
+            listing_chunks = ids;
+        }
+        let mut archive = ActiveArchive::from_archive(dumb_archive);
+        archive.listing_chunks = Arc::new(listing_chunks);
         Ok(archive)
     }
 
+    /// Reads the archive's listing tree, without loading the rest of the archive
+    ///
+    /// Returns `None` for archives stored before listings were split into a tree of
+    /// chunks, which have to be `load`ed to get at their listing.
+    pub async fn listing_tree(
+        &self,
+        repo: &mut Repository<impl BackendClone>,
+    ) -> Result<Option<ListingTree>> {
+        let bytes = repo.read_chunk(self.id).await?;
+        let header: ArchiveHeader = rmp_serde::from_slice(&bytes)
+            .map_err(|e| RepositoryError::from(BackendError::from(e)))?;
+        Ok(header.listing_root.map(ListingTree::new))
+    }
+
     /// Constructs a dummy archive object used for testing
     #[cfg(test)]
     pub fn dummy_archive() -> StoredArchive {
         StoredArchive {
             name: "Test".to_string(),
             id: ChunkID::random_id(),
-            timestamp: Local::now().with_timezone(Local::now().offset()),
+            timestamp: Timestamp::now(),
+            metadata: ArchiveMetadata::default(),
+            integrity: None,
+            signature: None,
+            duration: None,
         }
     }
 
-    /// Returns the timestamp of the archive
-    pub fn timestamp(&self) -> DateTime<FixedOffset> {
+    /// Returns the time the archive was started
+    pub fn timestamp(&self) -> Timestamp {
         self.timestamp
     }
 
+    /// Returns how long the archive took to store, if it was recorded
+    ///
+    /// Archives stored before durations were recorded, and those in backends that do not
+    /// record them in their manifests, such as `FlatFile`, return `None`.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
     /// Returns the pointer to the archive
     pub fn id(&self) -> ChunkID {
         self.id
@@ -86,6 +273,30 @@ impl StoredArchive {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Returns the tags and metadata of the archive
+    ///
+    /// Backends that do not record metadata in their manifests, such as `FlatFile`, will
+    /// always return empty metadata here. The metadata is always available from the loaded
+    /// archive.
+    pub fn metadata(&self) -> &ArchiveMetadata {
+        &self.metadata
+    }
+
+    /// Returns the record of the chunks committed along with the archive, if one was kept
+    ///
+    /// See `manifest::integrity` for details.
+    pub fn integrity(&self) -> Option<&ChunkIntegrity> {
+        self.integrity.as_ref()
+    }
+
+    /// Returns the signature of the archive, if it was signed
+    ///
+    /// Use `SignatureStatus::of` or a `SignaturePolicy` to verify it. See
+    /// `manifest::signing` for details.
+    pub fn signature(&self) -> Option<&ArchiveSignature> {
+        self.signature.as_ref()
+    }
 }
 
 impl From<ManifestTransaction> for StoredArchive {
@@ -94,8 +305,151 @@ impl From<ManifestTransaction> for StoredArchive {
             name: item.name().to_string(),
             id: item.pointer(),
             timestamp: item.timestamp(),
+            metadata: item.metadata().clone(),
+            integrity: item.integrity().cloned(),
+            signature: item.signature().cloned(),
+            duration: item.duration(),
+        }
+    }
+}
+
+/// Number of bytes of plaintext an `ObjectReader` buffers ahead of its reader
+const OBJECT_STREAM_BUFFER: usize = 256 * 1024;
+
+/// A reader over the contents of an object, returned by `ActiveArchive::get_object_stream`
+#[derive(Debug)]
+pub struct ObjectReader {
+    pipe: piper::Reader,
+    /// Set by the task filling the pipe if it hits an error, before it closes the pipe
+    error: Arc<Mutex<Option<ArchiveError>>>,
+}
+
+impl AsyncRead for ObjectReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.pipe).poll_read(cx, buf) {
+            // The pipe has been closed, which may have been because of an error
+            Poll::Ready(Ok(0)) if !buf.is_empty() => {
+                let error = self
+                    .error
+                    .lock()
+                    .expect("Object stream lock poisoned")
+                    .take();
+                Poll::Ready(match error {
+                    Some(error) => Err(io::Error::other(error)),
+                    None => Ok(0),
+                })
+            }
+            poll => poll,
+        }
+    }
+}
+
+/// Adapts the writing end of a pipe into a blocking writer
+///
+/// Must only be used from a blocking thread.
+struct BlockingWriter(piper::Writer);
+
+impl Write for BlockingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        smol::block_on(self.0.write(buf))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        smol::block_on(self.0.flush())
+    }
+}
+
+/// Writes the plaintext of an object into a pipe, filling holes with zeros
+///
+/// Chunks are decoded on a blocking thread, as the pipe applies backpressure, while the
+/// repository's read ahead window of chunks is fetched in the meantime.
+async fn stream_object(
+    mut repository: Repository<impl BackendClone>,
+    locations: Vec<ChunkLocation>,
+    mut writer: piper::Writer,
+) -> Result<()> {
+    let zeros = [0_u8; 4096];
+    let mut position = locations.first().map_or(0, |x| x.start);
+    let chunks = repository.read_ahead(locations.iter().map(|x| x.id).collect::<Vec<_>>());
+    let pieces = futures::stream::iter(locations).zip(chunks);
+    futures::pin_mut!(pieces);
+    while let Some((location, chunk)) = pieces.next().await {
+        // Fill the space between this chunk and the last with zeros
+        while position < location.start {
+            let length = zeros
+                .len()
+                .min(usize::try_from(location.start - position).unwrap_or(usize::MAX));
+            writer.write_all(&zeros[..length]).await?;
+            position += length as u64;
+        }
+        let chunk = match chunk? {
+            ReadChunk::Cached(plaintext) => Some(plaintext),
+            ReadChunk::Raw(chunk) if repository.read_cache().is_some() => {
+                Some(repository.unpack_cached(&chunk).await?)
+            }
+            ReadChunk::Raw(chunk) => {
+                let dictionary = repository.chunk_dictionary(&chunk).await?;
+                let key = repository.shared_key();
+                let (returned, result) = blocking!({
+                    let mut output = BlockingWriter(writer);
+                    let result = chunk.unpack_into(&key, dictionary.as_deref(), &mut output);
+                    (output.0, result)
+                });
+                writer = returned;
+                result.map_err(RepositoryError::from)?;
+                None
+            }
+        };
+        if let Some(plaintext) = chunk {
+            writer.write_all(&plaintext[..]).await?;
         }
+        // Chunk locations include their end point, so the data ends one byte short of that
+        position = location.start + location.length - 1;
     }
+    Ok(())
+}
+
+/// Moves the chunk locations of an object stored before `Archive::exact_locations` was
+/// recorded to where their data actually starts
+///
+/// Those archives placed each chunk one byte past the end of the chunk before it, so a
+/// chunk starting exactly there is taken to directly follow it. Anything further along
+/// starts a new extent, whose first chunk was always placed correctly.
+fn realign_locations(locations: &mut [ChunkLocation]) {
+    locations.sort_unstable();
+    let mut drift = 0;
+    let mut previous: Option<ChunkLocation> = None;
+    for location in locations {
+        match previous {
+            Some(last) if location.start == last.start + last.length => drift += 1,
+            _ => drift = 0,
+        }
+        previous = Some(*location);
+        location.start -= drift;
+    }
+}
+
+/// The fields of an `Archive` in the same order, skipping over everything but the pointer
+/// to its listing tree
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct ArchiveHeader {
+    name: IgnoredAny,
+    objects: IgnoredAny,
+    namespace: IgnoredAny,
+    timestamp: IgnoredAny,
+    listing: IgnoredAny,
+    #[serde(default)]
+    chunk_settings: Option<IgnoredAny>,
+    #[serde(default)]
+    metadata: Option<IgnoredAny>,
+    #[serde(default)]
+    listing_root: Option<ChunkID>,
+    #[serde(default)]
+    exact_locations: Option<IgnoredAny>,
 }
 
 #[derive(Clone, Debug)]
@@ -123,9 +477,26 @@ pub struct ActiveArchive {
     namespace: Vec<String>,
     /// Time stamp is set at archive creation, this is different than the one
     /// set in stored archive
-    timestamp: DateTime<FixedOffset>,
+    timestamp: Timestamp,
     /// The object listing of the archive
     listing: Arc<Lock<Listing>>,
+    /// Chunk settings to store objects with, instead of the repository's defaults
+    chunk_settings: Option<ChunkSettings>,
+    /// User supplied tags and metadata
+    metadata: ArchiveMetadata,
+    /// The algorithm drivers hash the contents of objects with
+    object_hash: ObjectHashAlgorithm,
+    /// Hashes of the contents of objects, by the path of their node in the listing
+    ///
+    /// These are stamped onto the nodes when the listing is read out of the archive, as
+    /// targets may not add a node to the listing until after its object has been stored.
+    object_hashes: Arc<DashMap<String, ObjectHash>>,
+    /// When this archive was created, for recording how long it took to store
+    started: Option<Instant>,
+    /// How long the archive took to store, if it was set rather than measured
+    recorded_duration: Option<Duration>,
+    /// The chunks the listing was read from, if it was loaded from a listing tree
+    listing_chunks: Arc<Vec<ChunkID>>,
 }
 
 impl ActiveArchive {
@@ -135,8 +506,92 @@ impl ActiveArchive {
             name: name.to_string(),
             objects: Arc::new(DashMap::new()),
             namespace: Vec::new(),
-            timestamp: Local::now().with_timezone(Local::now().offset()),
+            timestamp: Timestamp::now(),
             listing: Arc::new(Lock::new(Listing::default())),
+            chunk_settings: None,
+            metadata: ArchiveMetadata::default(),
+            object_hash: ObjectHashAlgorithm::default(),
+            object_hashes: Arc::new(DashMap::new()),
+            started: Some(Instant::now()),
+            recorded_duration: None,
+            listing_chunks: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Returns how long the archive has taken to store so far
+    ///
+    /// Archives loaded from a repository have no duration, unless one is set with
+    /// `set_duration`.
+    pub fn duration(&self) -> Option<Duration> {
+        self.started
+            .map(|started| started.elapsed())
+            .or(self.recorded_duration)
+    }
+
+    /// Sets how long the archive took to store, instead of measuring it, such as when
+    /// copying an archive that has already been stored
+    pub fn set_duration(&mut self, duration: Option<Duration>) {
+        self.started = None;
+        self.recorded_duration = duration;
+    }
+
+    /// Adds a tag to the archive
+    pub fn add_tag(&mut self, tag: &str) {
+        self.metadata.tags.insert(tag.to_string());
+    }
+
+    /// Sets a metadata value on the archive, replacing any previous value for `key`
+    pub fn set_metadata(&mut self, key: &str, value: &str) {
+        self.metadata
+            .values
+            .insert(key.to_string(), value.to_string());
+    }
+
+    /// Returns the tags and metadata of the archive
+    pub fn metadata(&self) -> &ArchiveMetadata {
+        &self.metadata
+    }
+
+    /// Overrides the chunk settings used for objects put into this archive from now on
+    ///
+    /// The override is recorded in the archive when it is stored. Only the compression
+    /// and encryption are overridden, chunk IDs and HMACs always follow the repository,
+    /// as changing them would defeat deduplication.
+    pub fn set_chunk_settings(&mut self, settings: ChunkSettings) {
+        self.chunk_settings = Some(settings);
+    }
+
+    /// Provides the chunk settings this archive overrides the repository's defaults
+    /// with, if any
+    pub fn chunk_settings(&self) -> Option<ChunkSettings> {
+        self.chunk_settings
+    }
+
+    /// Sets the algorithm drivers hash the contents of objects with, from now on
+    ///
+    /// See `manifest::hash` for details.
+    pub fn set_object_hash_algorithm(&mut self, algorithm: ObjectHashAlgorithm) {
+        self.object_hash = algorithm;
+    }
+
+    /// Provides the algorithm drivers hash the contents of objects with
+    pub fn object_hash_algorithm(&self) -> ObjectHashAlgorithm {
+        self.object_hash
+    }
+
+    /// Records the hash of the contents of the object belonging to the node at `path`
+    ///
+    /// The hash is attached to the node when the listing is next read out of the archive.
+    pub fn record_object_hash(&self, path: &str, hash: ObjectHash) {
+        self.object_hashes.insert(path.to_string(), hash);
+    }
+
+    /// Attaches the recorded object hashes to their nodes in `listing`
+    fn stamp_object_hashes(&self, listing: &mut Listing) {
+        for entry in self.object_hashes.iter() {
+            if let Some(node) = listing.get_mut(entry.key()) {
+                node.hash = Some(entry.value().clone());
+            }
         }
     }
 
@@ -151,7 +606,7 @@ impl ActiveArchive {
         repository: &mut Repository<impl BackendClone>,
         path: &str,
         from_reader: R,
-    ) -> Result<()> {
+    ) -> Result<StoreReport> {
         // We take advantage of put_sparse_object's behavior of reading past the given end if the
         // given reader is actually longer
         let extent = Extent { start: 0, end: 0 };
@@ -163,52 +618,76 @@ impl ActiveArchive {
     /// Inserts a sparse object into the archive
     ///
     /// Requires that the object be pre-split into extents
+    ///
+    /// Returns a summary of the chunks the object was split into, and how many of them
+    /// had to be written.
     pub async fn put_sparse_object<R: Read + Send + 'static>(
         &mut self,
         chunker: &impl AsyncChunker,
         repository: &mut Repository<impl BackendClone>,
         path: &str,
         from_readers: Vec<(Extent, R)>,
-    ) -> Result<()> {
+    ) -> Result<StoreReport> {
         let mut locations: Vec<ChunkLocation> = Vec::new();
+        let mut report = StoreReport::default();
         let path = self.canonical_namespace() + path.trim();
+        let repository = &mut match self.chunk_settings {
+            Some(settings) => repository.with_chunk_settings(settings),
+            None => repository.clone(),
+        };
 
         for (extent, read) in from_readers {
             let max_futs = 100;
             let mut futs = VecDeque::new();
-            let mut slices = chunker.async_chunk(read, repository.queue_depth);
-            let mut start = extent.start;
+            let depth = repository.queue_depth;
+            let mut slices = if repository.adaptive_compression {
+                let slices = chunker.async_chunk_estimated(read, depth);
+                Either::Left(slices.map(|x| x.map(|(offset, data, c)| (offset, data, Some(c)))))
+            } else {
+                let slices = chunker.async_chunk_with_offsets(read, depth);
+                Either::Right(slices.map(|x| x.map(|(offset, data)| (offset, data, None))))
+            };
             while let Some(result) = slices.next().await {
-                let data = result?;
-                let end = start + (data.len() as u64);
+                let (offset, data, compressibility) = result?;
+                let start = extent.start + offset;
+                // Chunk locations include their end point
+                let length = data.len() as u64 + 1;
+                // Held until the chunk has been handed to the backend
+                let reservation = repository.reserve_memory(data.len() as u64).await;
 
                 let mut repository = repository.clone();
                 futs.push_back(Task::spawn(async move {
-                    let id = repository.write_chunk(data).await?.0;
-                    let result: Result<ChunkLocation> = Ok(ChunkLocation {
-                        id,
+                    let written = match compressibility {
+                        Some(estimate) => repository.write_chunk_estimated(data, estimate).await?,
+                        None => repository.write_chunk_measured(data).await?,
+                    };
+                    drop(reservation);
+                    let location = ChunkLocation {
+                        id: written.id,
                         start,
-                        length: end - start + 1,
-                    });
+                        length,
+                    };
+                    let result: Result<(ChunkLocation, ChunkWrite)> = Ok((location, written));
                     result
                 }));
                 while futs.len() >= max_futs {
                     // This unwrap is sound, since we can only be here if futs has elements in it
-                    let loc = futs.pop_front().unwrap().await?;
+                    let (loc, written) = futs.pop_front().unwrap().await?;
+                    report.record(&written);
                     locations.push(loc);
                 }
-                start = end + 1;
             }
             let locs = join_all(futs).await;
             for loc in locs {
-                let loc = loc?;
+                let (loc, written) = loc?;
+                report.record(&written);
                 locations.push(loc);
             }
         }
 
         self.objects.insert(path.to_string(), locations);
 
-        Ok(())
+        Ok(report)
     }
 
     /// Inserts an object into the archive without writing any bytes
@@ -217,6 +696,35 @@ impl ActiveArchive {
         self.objects.insert(path.to_string(), locations);
     }
 
+    /// Removes an object from the archive, if it exists
+    ///
+    /// The chunks making up the object are not removed from the repository.
+    pub fn remove_object(&mut self, path: &str) {
+        let path = self.canonical_namespace() + path.trim();
+        self.objects.remove(&path);
+    }
+
+    /// Returns the locations of the chunks making up an object, sorted by their
+    /// position within the object
+    ///
+    /// Returns None if the object does not exist in the archive.
+    pub fn chunk_locations(&self, path: &str) -> Option<Vec<ChunkLocation>> {
+        let path = self.canonical_namespace() + path.trim();
+        let mut locations = self.objects.get(&path).map(|x| x.clone())?;
+        locations.sort_unstable();
+        Some(locations)
+    }
+
+    /// Returns the IDs of every chunk the objects in this archive are made up of, along
+    /// with the chunks its listing was read from
+    pub fn chunk_ids(&self) -> HashSet<ChunkID> {
+        let mut ids = self.listing_chunks.iter().copied().collect::<HashSet<_>>();
+        for entry in self.objects.iter() {
+            ids.extend(entry.value().iter().map(|location| location.id));
+        }
+        ids
+    }
+
     /// Retreives an object from the archive, without regard to sparsity.
     ///
     /// Will fill in holes with zeros.
@@ -236,26 +744,64 @@ impl ActiveArchive {
             return Ok(());
         };
         locations.sort_unstable();
-        let mut last_index = locations[0].start;
-        for location in &locations {
-            let id = location.id;
+        let chunks = repository.read_ahead(locations.iter().map(|x| x.id).collect::<Vec<_>>());
+        let pieces = futures::stream::iter(&locations).zip(chunks);
+        futures::pin_mut!(pieces);
+        let mut position = locations[0].start;
+        while let Some((location, chunk)) = pieces.next().await {
             // If a chunk is not included, fill the space inbween it and the last with zeros
             let start = location.start;
-            if start > last_index + 1 {
+            if start > position {
                 let zero = [0_u8];
-                for _ in last_index + 1..start {
+                for _ in position..start {
                     restore_to.write_all(&zero)?;
                 }
             }
-            let bytes = repository.read_chunk(id).await?;
-
-            restore_to.write_all(&bytes)?;
-            last_index = start + location.length - 1;
+            repository
+                .unpack_read_into(&chunk?, &mut restore_to)
+                .await?;
+            // Chunk locations include their end point, so the data ends one byte short of that
+            position = start + location.length - 1;
         }
 
         Ok(())
     }
 
+    /// Returns a reader over the contents of an object, with holes filled in with zeros
+    ///
+    /// Chunks are fetched and decoded in a background task, through a pipe holding at most
+    /// `OBJECT_STREAM_BUFFER` bytes of plaintext, so objects of any size can be read with
+    /// constant memory. The reader will be empty if the object does not exist.
+    ///
+    /// Any error encountered while retrieving the object is returned from the read that
+    /// would have hit the missing data.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the task filling the stream panicked while recording an error.
+    pub fn get_object_stream<T: BackendClone>(
+        &self,
+        repository: &Repository<T>,
+        path: &str,
+    ) -> ObjectReader {
+        let (reader, writer) = piper::pipe(OBJECT_STREAM_BUFFER);
+        let error = Arc::new(Mutex::new(None));
+        if let Some(locations) = self.chunk_locations(path) {
+            let repository = repository.clone();
+            let task_error = error.clone();
+            Task::spawn(async move {
+                if let Err(e) = stream_object(repository, locations, writer).await {
+                    *task_error.lock().expect("Object stream lock poisoned") = Some(e);
+                }
+            })
+            .detach();
+        }
+        ObjectReader {
+            pipe: reader,
+            error,
+        }
+    }
+
     /// Retrieve a single extent of an object from the repository
     ///
     /// Will write past the end of the last chunk ends after the extent
@@ -277,23 +823,28 @@ impl ActiveArchive {
         };
         locations.sort_unstable();
         let locations = locations
-            .iter()
-            .filter(|x| x.start >= extent.start && x.start <= extent.end);
+            .into_iter()
+            .filter(|x| x.start >= extent.start && x.start <= extent.end)
+            .collect::<Vec<_>>();
+        let chunks = repository.read_ahead(locations.iter().map(|x| x.id).collect::<Vec<_>>());
+        let pieces = futures::stream::iter(&locations).zip(chunks);
+        futures::pin_mut!(pieces);
         // If there are any holes in the extent, fill them in with zeros
-        let mut last_index = extent.start;
-        for location in locations {
-            let id = location.id;
+        let mut position = extent.start;
+        while let Some((location, chunk)) = pieces.next().await {
             // Perform filling if needed
             let start = location.start;
-            if start > last_index + 1 {
+            if start > position {
                 let zero = [0_u8];
-                for _ in last_index + 1..start {
+                for _ in position..start {
                     restore_to.write_all(&zero)?;
                 }
             }
-            let bytes = repository.read_chunk(id).await?;
-            restore_to.write_all(&bytes)?;
-            last_index = start + location.length - 1;
+            repository
+                .unpack_read_into(&chunk?, &mut restore_to)
+                .await?;
+            // Chunk locations include their end point, so the data ends one byte short of that
+            position = start + location.length - 1;
         }
 
         Ok(())
@@ -338,7 +889,14 @@ impl ActiveArchive {
     ///
     /// Returns the key of the serialized archive in the repository
     pub async fn store(self, repo: &mut Repository<impl BackendClone>) -> StoredArchive {
-        let dumb_archive = self.into_archive().await;
+        let (started, recorded_duration) = (self.started, self.recorded_duration);
+        let mut dumb_archive = self.into_archive().await;
+        // Only the root of the listing is kept in the archive itself
+        let listing = std::mem::take(&mut dumb_archive.listing);
+        let tree = ListingTree::store(repo, &listing)
+            .await
+            .expect("Unable to write archive listing to repository.");
+        dumb_archive.listing_root = Some(tree.root());
         let mut bytes = Vec::<u8>::new();
         dumb_archive
             .serialize(&mut Serializer::new(&mut bytes))
@@ -356,6 +914,12 @@ impl ActiveArchive {
             id,
             name: dumb_archive.name,
             timestamp: dumb_archive.timestamp,
+            metadata: dumb_archive.metadata,
+            integrity: None,
+            signature: None,
+            duration: started
+                .map(|started| started.elapsed())
+                .or(recorded_duration),
         }
     }
 
@@ -366,36 +930,61 @@ impl ActiveArchive {
     }
 
     #[cfg_attr(tarpaulin, skip)]
-    /// Provides the timestamp of the archive
-    pub fn timestamp(&self) -> &DateTime<FixedOffset> {
-        &self.timestamp
+    /// Provides the time the archive was started
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
     }
 
     /// Converts an Archive into an `ActiveArchive`
+    ///
+    /// Chunk locations in archives stored before `Archive::exact_locations` was recorded
+    /// are moved to where their data actually starts.
     pub fn from_archive(archive: Archive) -> ActiveArchive {
+        let exact = archive.exact_locations;
+        let objects = archive.objects.into_iter().map(|(path, mut locations)| {
+            if !exact {
+                realign_locations(&mut locations);
+            }
+            (path, locations)
+        });
         ActiveArchive {
             name: archive.name,
-            objects: Arc::new(archive.objects.into_iter().collect()),
+            objects: Arc::new(objects.collect()),
             namespace: archive.namespace,
             timestamp: archive.timestamp,
             listing: Arc::new(Lock::new(archive.listing)),
+            chunk_settings: archive.chunk_settings,
+            metadata: archive.metadata,
+            object_hash: ObjectHashAlgorithm::default(),
+            object_hashes: Arc::new(DashMap::new()),
+            started: None,
+            recorded_duration: None,
+            listing_chunks: Arc::new(Vec::new()),
         }
     }
 
     /// Converts self into an Archive
     pub async fn into_archive(self) -> Archive {
+        let listing = self.listing().await;
         Archive {
             name: self.name,
             objects: DashMap::clone(&self.objects).into_iter().collect(),
             namespace: self.namespace,
             timestamp: self.timestamp,
-            listing: self.listing.lock().await.clone(),
+            listing,
+            chunk_settings: self.chunk_settings,
+            metadata: self.metadata,
+            listing_root: None,
+            exact_locations: true,
         }
     }
 
-    /// Gets a copy of the listing from the archive
+    /// Gets a copy of the listing from the archive, with any recorded object hashes
+    /// attached to their nodes
     pub async fn listing(&self) -> Listing {
-        self.listing.lock().await.clone()
+        let mut listing = self.listing.lock().await.clone();
+        self.stamp_object_hashes(&mut listing);
+        listing
     }
 
     /// Replaces the listing with the provided value
@@ -410,8 +999,9 @@ mod tests {
     use super::*;
     use crate::chunker::*;
     use crate::repository::backend::mem::Mem;
-    use crate::repository::ChunkSettings;
     use crate::repository::Key;
+    use crate::repository::{ChunkSettings, Compression, Encryption};
+    use futures::io::AsyncReadExt;
     use rand::prelude::*;
     use std::fs;
     use std::io::{BufReader, Cursor, Seek, SeekFrom};
@@ -421,7 +1011,7 @@ mod tests {
     fn get_repo_mem(key: Key) -> Repository<impl BackendClone> {
         let settings = ChunkSettings::lightweight();
         let backend = Mem::new(settings, key.clone(), 4);
-        Repository::with(backend, settings, key, 2)
+        Repository::with(backend, settings, key, 2).unwrap()
     }
 
     #[test]
@@ -566,6 +1156,307 @@ mod tests {
         });
     }
 
+    #[test]
+    fn stream_get() {
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key);
+            let mut archive = ActiveArchive::new("test");
+
+            // Several times the size of the stream's buffer
+            let mut data = vec![0_u8; 4 * OBJECT_STREAM_BUFFER];
+            SmallRng::seed_from_u64(0).fill_bytes(&mut data);
+            archive
+                .put_object(&chunker, &mut repo, "large", Cursor::new(data.clone()))
+                .await
+                .unwrap();
+            // A sparse object, whose holes must come back as zeros
+            let extents = vec![
+                (
+                    Extent {
+                        start: 0,
+                        end: 1000,
+                    },
+                    Cursor::new(vec![1_u8; 1000]),
+                ),
+                (
+                    Extent {
+                        start: 20_000,
+                        end: 21_000,
+                    },
+                    Cursor::new(vec![2_u8; 1000]),
+                ),
+            ];
+            archive
+                .put_sparse_object(&chunker, &mut repo, "sparse", extents)
+                .await
+                .unwrap();
+
+            let mut output = Vec::new();
+            archive
+                .get_object_stream(&repo, "large")
+                .read_to_end(&mut output)
+                .await
+                .unwrap();
+            assert_eq!(output, data);
+
+            let mut expected = Vec::new();
+            archive
+                .get_object(&mut repo, "sparse", &mut expected)
+                .await
+                .unwrap();
+            let mut output = Vec::new();
+            archive
+                .get_object_stream(&repo, "sparse")
+                .read_to_end(&mut output)
+                .await
+                .unwrap();
+            assert_eq!(output, expected);
+            let end = output.len() - 1000;
+            assert!(output[..1000].iter().all(|x| *x == 1));
+            assert!(output[1000..end].iter().all(|x| *x == 0));
+            assert!(output[end..].iter().all(|x| *x == 2));
+
+            let mut output = Vec::new();
+            archive
+                .get_object_stream(&repo, "missing")
+                .read_to_end(&mut output)
+                .await
+                .unwrap();
+            assert!(output.is_empty());
+        });
+    }
+
+    // Output must come back in order, no matter how many chunks are in flight
+    #[test]
+    fn read_ahead_get() {
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key);
+            let mut archive = ActiveArchive::new("test");
+
+            let mut data = vec![0_u8; 64 * 2_usize.pow(14)];
+            SmallRng::seed_from_u64(0).fill_bytes(&mut data);
+            archive
+                .put_object(&chunker, &mut repo, "object", Cursor::new(data.clone()))
+                .await
+                .unwrap();
+            assert!(archive.chunk_ids().len() > 8);
+
+            for read_ahead in &[0, 1, 8, 1000] {
+                repo.read_ahead = *read_ahead;
+                let mut output = Vec::new();
+                archive
+                    .get_object(&mut repo, "object", &mut output)
+                    .await
+                    .unwrap();
+                assert_eq!(output, data);
+                let mut output = Vec::new();
+                archive
+                    .get_object_stream(&repo, "object")
+                    .read_to_end(&mut output)
+                    .await
+                    .unwrap();
+                assert_eq!(output, data);
+            }
+        });
+    }
+
+    #[test]
+    fn memory_limited_put() {
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let mut repo = get_repo_mem(Key::random(32));
+            // Smaller than any chunk, so chunks are stored one at a time
+            repo.set_memory_limit(1024);
+            let mut archive = ActiveArchive::new("test");
+
+            let mut data = vec![0_u8; 16 * 2_usize.pow(14)];
+            SmallRng::seed_from_u64(0).fill_bytes(&mut data);
+            archive
+                .put_object(&chunker, &mut repo, "object", Cursor::new(data.clone()))
+                .await
+                .unwrap();
+            let mut output = Vec::new();
+            archive
+                .get_object(&mut repo, "object", &mut output)
+                .await
+                .unwrap();
+            assert_eq!(output, data);
+        });
+    }
+
+    // Chunks past the first in an extent should be located where their data starts
+    #[test]
+    fn sparse_chunk_locations() {
+        smol::run(async {
+            let chunker = StaticSize { len: 1000 };
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut archive = ActiveArchive::new("test");
+            let mut data = vec![0_u8; 10_000];
+            SmallRng::seed_from_u64(0).fill_bytes(&mut data);
+            let extents = vec![
+                (
+                    Extent {
+                        start: 0,
+                        end: 2499,
+                    },
+                    Cursor::new(data[..2500].to_vec()),
+                ),
+                (
+                    Extent {
+                        start: 5000,
+                        end: 7499,
+                    },
+                    Cursor::new(data[5000..7500].to_vec()),
+                ),
+            ];
+            archive
+                .put_sparse_object(&chunker, &mut repo, "object", extents)
+                .await
+                .unwrap();
+            let locations = archive.chunk_locations("object").unwrap();
+            let starts = locations.iter().map(|x| x.start).collect::<Vec<_>>();
+            assert_eq!(starts, [0, 1000, 2000, 5000, 6000, 7000]);
+            let lengths = locations.iter().map(|x| x.length).collect::<Vec<_>>();
+            assert_eq!(lengths, [1001, 1001, 501, 1001, 1001, 501]);
+
+            let extent = Extent {
+                start: 5000,
+                end: 7499,
+            };
+            let mut output = Vec::new();
+            archive
+                .get_extent(&mut repo, "object", extent, &mut output)
+                .await
+                .unwrap();
+            assert_eq!(output, &data[5000..7500]);
+
+            // The whole object, with the hole between the extents filled in
+            let mut expected = data[..7500].to_vec();
+            for byte in &mut expected[2500..5000] {
+                *byte = 0;
+            }
+            let mut output = Vec::new();
+            archive
+                .get_object(&mut repo, "object", &mut output)
+                .await
+                .unwrap();
+            assert_eq!(output, expected);
+            let mut output = Vec::new();
+            archive
+                .get_object_stream(&repo, "object")
+                .read_to_end(&mut output)
+                .await
+                .unwrap();
+            assert_eq!(output, expected);
+        });
+    }
+
+    // Archives stored before chunk locations were exact should still read back correctly
+    #[test]
+    fn legacy_chunk_locations() {
+        smol::run(async {
+            let chunker = StaticSize { len: 1000 };
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut archive = ActiveArchive::new("test");
+            let mut data = vec![0_u8; 10_000];
+            SmallRng::seed_from_u64(0).fill_bytes(&mut data);
+            let extents = vec![
+                (
+                    Extent {
+                        start: 0,
+                        end: 2499,
+                    },
+                    Cursor::new(data[..2500].to_vec()),
+                ),
+                (
+                    Extent {
+                        start: 5000,
+                        end: 7499,
+                    },
+                    Cursor::new(data[5000..7500].to_vec()),
+                ),
+            ];
+            archive
+                .put_sparse_object(&chunker, &mut repo, "object", extents)
+                .await
+                .unwrap();
+            let mut expected = Vec::new();
+            archive
+                .get_object(&mut repo, "object", &mut expected)
+                .await
+                .unwrap();
+
+            // Lay the chunks out the way older versions did
+            let mut stored = archive.into_archive().await;
+            stored.exact_locations = false;
+            let locations = stored.objects.values_mut().next().unwrap();
+            locations.sort_unstable();
+            for (location, start) in locations.iter_mut().zip(&[0, 1001, 2002, 5000, 6001, 7002]) {
+                location.start = *start;
+            }
+
+            let archive = ActiveArchive::from_archive(stored);
+            let starts = archive
+                .chunk_locations("object")
+                .unwrap()
+                .iter()
+                .map(|x| x.start)
+                .collect::<Vec<_>>();
+            assert_eq!(starts, [0, 1000, 2000, 5000, 6000, 7000]);
+            let mut output = Vec::new();
+            archive
+                .get_object(&mut repo, "object", &mut output)
+                .await
+                .unwrap();
+            assert_eq!(output, expected);
+        });
+    }
+
+    #[test]
+    fn adaptive_compression_put() {
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let key = Key::random(32);
+            let settings = ChunkSettings {
+                compression: Compression::ZStd { level: 3 },
+                ..ChunkSettings::lightweight()
+            };
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            repo.adaptive_compression = true;
+
+            let text = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. "
+                .repeat(5000)
+                .into_bytes();
+            let mut random = vec![0_u8; 16 * 2_usize.pow(14)];
+            SmallRng::seed_from_u64(0).fill_bytes(&mut random);
+            for (data, expected) in [
+                (text, Compression::ZStd { level: 12 }),
+                (random, Compression::NoCompression),
+            ] {
+                let mut archive = ActiveArchive::new("test");
+                archive
+                    .put_object(&chunker, &mut repo, "object", Cursor::new(data.clone()))
+                    .await
+                    .unwrap();
+                for id in archive.chunk_ids() {
+                    let chunk = repo.read_raw(id).await.unwrap();
+                    assert_eq!(chunk.compression(), expected);
+                }
+                let mut output = Vec::new();
+                archive
+                    .get_object(&mut repo, "object", &mut output)
+                    .await
+                    .unwrap();
+                assert_eq!(output, data);
+            }
+        });
+    }
+
     #[test]
     fn default_namespace() {
         let archive = ActiveArchive::new("test");
@@ -629,6 +1520,91 @@ mod tests {
         });
     }
 
+    // Storing the same object twice should write its chunks once, and deduplicate them
+    // the second time
+    #[test]
+    fn store_report_counts_deduplication() {
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let key = Key::random(32);
+            let mut data = vec![0_u8; 4 * 2_usize.pow(14)];
+            SmallRng::seed_from_u64(0).fill_bytes(&mut data);
+            let mut repo = get_repo_mem(key);
+            let mut archive = ActiveArchive::new("test");
+
+            let first = archive
+                .put_object(&chunker, &mut repo, "first", Cursor::new(data.clone()))
+                .await
+                .unwrap();
+            assert!(first.chunks_written > 0);
+            assert_eq!(first.chunks_deduplicated, 0);
+            assert_eq!(first.bytes_read, data.len() as u64);
+            assert_eq!(first.bytes_deduplicated, 0);
+            assert!(first.bytes_written > 0);
+            assert!(first.compression_ratio().is_some());
+
+            let second = archive
+                .put_object(&chunker, &mut repo, "second", Cursor::new(data.clone()))
+                .await
+                .unwrap();
+            assert_eq!(second.chunks_written, 0);
+            assert_eq!(second.chunks_deduplicated, first.chunks_written);
+            assert_eq!(second.bytes_read, data.len() as u64);
+            assert_eq!(second.bytes_deduplicated, data.len() as u64);
+            assert_eq!(second.bytes_written, 0);
+            assert_eq!(second.effective_ratio(), None);
+
+            let mut total = first.clone();
+            total.merge(&second);
+            assert_eq!(total.bytes_read, 2 * data.len() as u64);
+            assert_eq!(total.bytes_written, first.bytes_written);
+            repo.close().await;
+        });
+    }
+
+    #[test]
+    fn chunk_settings_override() {
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let key = Key::random(32);
+
+            let mut repo = get_repo_mem(key);
+            let mut obj = vec![0_u8; 8192];
+            thread_rng().fill_bytes(&mut obj);
+
+            let settings = ChunkSettings {
+                compression: Compression::LZ4 { level: 1 },
+                encryption: Encryption::new_chacha20(),
+                ..repo.chunk_settings()
+            };
+            let mut archive = ActiveArchive::new("test");
+            archive.set_chunk_settings(settings);
+            archive
+                .put_object(&chunker, &mut repo, "1", Cursor::new(obj.clone()))
+                .await
+                .expect("Unable to put object in archive");
+            // The chunks should have been packed with the override, not the defaults
+            for location in archive.chunk_locations("1").unwrap() {
+                let chunk = repo.read_raw(location.id).await.unwrap();
+                assert!(matches!(chunk.encryption(), Encryption::ChaCha20 { .. }));
+            }
+
+            let stored_archive = archive.store(&mut repo).await;
+            let archive = stored_archive
+                .load(&mut repo)
+                .await
+                .expect("Unable to load archive from repository");
+            assert_eq!(archive.chunk_settings(), Some(settings));
+
+            let mut obj_restore = Cursor::new(Vec::new());
+            archive
+                .get_object(&mut repo, "1", &mut obj_restore)
+                .await
+                .expect("Unable to restore object from archive");
+            assert_eq!(obj, obj_restore.into_inner());
+        });
+    }
+
     #[test]
     fn commit_and_load() {
         smol::run(async {
@@ -665,4 +1641,26 @@ mod tests {
             assert_eq!(&obj1.into_inner()[..], &obj_restore.into_inner()[..]);
         });
     }
+
+    // Archives record how long they took to store, and stored archives without a duration
+    // still encode the way they did before durations were recorded
+    #[test]
+    fn stored_duration() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let stored_archive = ActiveArchive::new("test").store(&mut repo).await;
+            assert!(stored_archive.duration().is_some());
+
+            let mut archive = StoredArchive::dummy_archive();
+            let legacy = rmp_serde::encode::to_vec(&archive).unwrap();
+            let decoded: StoredArchive = rmp_serde::decode::from_slice(&legacy).unwrap();
+            assert_eq!(decoded.duration(), None);
+            assert_eq!(rmp_serde::encode::to_vec(&decoded).unwrap(), legacy);
+
+            archive.duration = Some(Duration::from_millis(1500));
+            let bytes = rmp_serde::encode::to_vec(&archive).unwrap();
+            let decoded: StoredArchive = rmp_serde::decode::from_slice(&bytes).unwrap();
+            assert_eq!(decoded.duration(), Some(Duration::from_millis(1500)));
+        });
+    }
 }
@@ -0,0 +1,271 @@
+//! Compares the contents of an archive against a live `BackupTarget`
+//!
+//! The listing of the target and the listing of the archive are walked side by side,
+//! reporting any objects that have been added, removed, or have changed type or size.
+//!
+//! Objects whose size has not changed have their content compared without fetching
+//! anything from the repository. The live object is run through the chunker, and the
+//! IDs of the resulting chunks are computed with the repository's key and compared to
+//! the IDs recorded in the archive. Only chunks whose boundaries do not line up with a
+//! chunk of the live object, such as when the archive was created with different
+//! chunker settings, are fetched from the repository and compared byte for byte.
+use crate::chunker::AsyncChunker;
+use crate::manifest::archive::{ActiveArchive, ArchiveError, ChunkLocation};
+use crate::manifest::target::{BackupObject, BackupTarget};
+use crate::repository::{BackendClone, ChunkID, Repository};
+
+use asuran_core::manifest::listing::{Node, NodeType};
+
+use futures::stream::StreamExt;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read};
+
+type Result<T> = std::result::Result<T, ArchiveError>;
+
+/// A single difference between an archive and the live target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Drift {
+    /// The object exists in the target, but not in the archive
+    Added(Node),
+    /// The object exists in the archive, but not in the target
+    Removed(Node),
+    /// The object is a different kind of node in the target than in the archive
+    TypeChanged {
+        path: String,
+        archived: NodeType,
+        live: NodeType,
+    },
+    /// The length of the object differs between the archive and the target
+    SizeChanged {
+        path: String,
+        archived: u64,
+        live: u64,
+    },
+    /// The object has the same length in both, but its content differs
+    ContentChanged(String),
+}
+
+impl Drift {
+    /// Returns the path of the object this difference applies to
+    pub fn path(&self) -> &str {
+        match self {
+            Drift::Added(node) | Drift::Removed(node) => &node.path,
+            Drift::TypeChanged { path, .. }
+            | Drift::SizeChanged { path, .. }
+            | Drift::ContentChanged(path) => path,
+        }
+    }
+}
+
+/// A chunk at a known byte offset within an object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    id: ChunkID,
+    offset: u64,
+    length: u64,
+}
+
+/// Walks the listing of the archive and the target side by side, returning every
+/// difference found between them, in path order.
+///
+/// `chunker` should be configured the same way as the chunker the archive was
+/// created with, otherwise every chunk of every object of unchanged size will need to
+/// be fetched from the repository.
+///
+/// Note that this requests objects from the target through `backup_object`, so the
+/// target should not be used for a backup afterwards.
+pub async fn compare_archive<R: Read + Send + 'static>(
+    repository: &mut Repository<impl BackendClone>,
+    chunker: &impl AsyncChunker,
+    archive: &ActiveArchive,
+    target: &impl BackupTarget<R>,
+) -> Result<Vec<Drift>> {
+    let archived: BTreeMap<String, Node> = archive
+        .listing()
+        .await
+        .into_iter()
+        .map(|x| (x.path.clone(), x))
+        .collect();
+    let mut live: BTreeMap<String, Node> = target
+        .backup_paths()
+        .await
+        .into_iter()
+        .map(|x| (x.path.clone(), x))
+        .collect();
+
+    let mut drift = Vec::new();
+    for (path, archived_node) in archived {
+        match live.remove(&path) {
+            None => drift.push(Drift::Removed(archived_node)),
+            // Directories differ in their children, but those are compared on their own
+            Some(live_node)
+                if archived_node.drain_children().node_type
+                    != live_node.drain_children().node_type =>
+            {
+                drift.push(Drift::TypeChanged {
+                    path,
+                    archived: archived_node.node_type,
+                    live: live_node.node_type,
+                });
+            }
+            Some(live_node) if archived_node.total_length != live_node.total_length => {
+                drift.push(Drift::SizeChanged {
+                    path,
+                    archived: archived_node.total_length,
+                    live: live_node.total_length,
+                });
+            }
+            Some(live_node) => {
+                if live_node.is_file()
+                    && !content_matches(repository, chunker, archive, target, live_node).await?
+                {
+                    drift.push(Drift::ContentChanged(path));
+                }
+            }
+        }
+    }
+    drift.extend(live.into_values().map(Drift::Added));
+    drift.sort_by(|a, b| a.path().cmp(b.path()));
+
+    Ok(drift)
+}
+
+/// Determines if the content of a live object matches its copy in the archive
+async fn content_matches<R: Read + Send + 'static>(
+    repository: &mut Repository<impl BackendClone>,
+    chunker: &impl AsyncChunker,
+    archive: &ActiveArchive,
+    target: &impl BackupTarget<R>,
+    node: Node,
+) -> Result<bool> {
+    let archived = archived_spans(archive.chunk_locations(&node.path).unwrap_or_default());
+    // Hash the live object, keeping track of where each chunk starts
+    let mut live = HashMap::new();
+    let mut offset = 0;
+    let reader = live_reader(target, node.clone()).await?;
+    let mut slices = chunker.async_chunk(reader, repository.queue_depth);
+    while let Some(result) = slices.next().await {
+        let data = result?;
+        let length = data.len() as u64;
+        let id = repository.chunk_id(&data);
+        live.insert((offset, length), id);
+        offset += length;
+    }
+    let archived_length = archived.last().map_or(0, |x| x.offset + x.length);
+    if offset != archived_length {
+        return Ok(false);
+    }
+
+    // Chunks that line up with a chunk of the live object can be compared by ID alone
+    let mut unaligned = Vec::new();
+    for span in archived {
+        match live.get(&(span.offset, span.length)) {
+            Some(id) if *id == span.id => (),
+            Some(_) => return Ok(false),
+            None => unaligned.push(span),
+        }
+    }
+    if unaligned.is_empty() {
+        return Ok(true);
+    }
+
+    // Anything else has to be fetched and compared byte for byte
+    let mut reader = live_reader(target, node).await?;
+    let mut position = 0;
+    let mut buffer = Vec::new();
+    for span in unaligned {
+        io::copy(
+            &mut (&mut reader).take(span.offset - position),
+            &mut io::sink(),
+        )?;
+        buffer.clear();
+        (&mut reader).take(span.length).read_to_end(&mut buffer)?;
+        position = span.offset + span.length;
+        if buffer != repository.read_chunk(span.id).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Converts the locations of an archived object's chunks into the byte offsets of
+/// its data
+///
+/// Holes in sparse objects are not counted, matching the data produced by
+/// `live_reader`.
+fn archived_spans(locations: Vec<ChunkLocation>) -> Vec<Span> {
+    let mut offset = 0;
+    locations
+        .into_iter()
+        .map(|location| {
+            // Chunk locations include their end point, so the length is one larger than
+            // the data contained in the chunk
+            let length = location.length.saturating_sub(1);
+            let span = Span {
+                id: location.id,
+                offset,
+                length,
+            };
+            offset += length;
+            span
+        })
+        .collect()
+}
+
+/// Opens a reader over all of the data in a live object, with any holes skipped
+///
+/// Objects the target does not provide any data for are treated as empty.
+async fn live_reader<R: Read + Send + 'static>(
+    target: &impl BackupTarget<R>,
+    node: Node,
+) -> io::Result<Box<dyn Read + Send>> {
+    let mut reader: Box<dyn Read + Send> = Box::new(io::empty());
+    let object: Option<BackupObject<R>> = target.backup_object(node).await?.remove("");
+    if let Some(object) = object {
+        let mut ranges = object.ranges();
+        ranges.sort_by_key(|x| x.start);
+        for range in ranges {
+            reader = Box::new(reader.chain(range.object));
+        }
+    }
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archived_spans_offsets() {
+        let id = ChunkID::manifest_id();
+        let locations = vec![
+            ChunkLocation {
+                id,
+                start: 0,
+                length: 11,
+            },
+            ChunkLocation {
+                id,
+                start: 11,
+                length: 6,
+            },
+        ];
+        let spans = archived_spans(locations);
+        assert_eq!(
+            spans,
+            vec![
+                Span {
+                    id,
+                    offset: 0,
+                    length: 10
+                },
+                Span {
+                    id,
+                    offset: 10,
+                    length: 5
+                }
+            ]
+        );
+    }
+}
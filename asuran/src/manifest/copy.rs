@@ -0,0 +1,295 @@
+//! Copies archives from one repository to another
+//!
+//! Chunk IDs are derived with a repository's key, so the same plaintext usually has a
+//! different ID in every repository. Each chunk an archive refers to is read from the
+//! source repository, and the ID it would have in the destination is derived from its
+//! plaintext. Only chunks the destination does not already contain are packed with the
+//! destination's settings and key, and written to it. The archive is then rewritten to
+//! refer to the destination's IDs, and committed to the destination's manifest.
+//!
+//! When both repositories derive IDs the same way with the same key, such as a repository
+//! and a copy of it made with `bundle`, chunks the destination already contains are
+//! skipped without reading them from the source at all.
+use crate::manifest::archive::{ActiveArchive, ArchiveError, StoredArchive};
+use crate::manifest::Manifest;
+use crate::repository::{BackendClone, ChunkID, Repository, RepositoryError};
+
+use futures::future::join_all;
+use smol::Task;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+type Result<T> = std::result::Result<T, ArchiveError>;
+
+/// Summary of the work performed by `copy_archive`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct CopyStats {
+    /// The number of chunks written to the destination
+    pub chunks_copied: usize,
+    /// The number of chunks the destination already contained
+    pub chunks_skipped: usize,
+    /// The number of bytes of plaintext written to the destination
+    pub bytes_copied: u64,
+}
+
+impl CopyStats {
+    /// Records a chunk, `copied` being the length of its plaintext if it was written
+    fn record(&mut self, copied: Option<u64>) {
+        match copied {
+            Some(length) => {
+                self.chunks_copied += 1;
+                self.bytes_copied += length;
+            }
+            None => self.chunks_skipped += 1,
+        }
+    }
+}
+
+impl std::ops::AddAssign for CopyStats {
+    fn add_assign(&mut self, other: CopyStats) {
+        self.chunks_copied += other.chunks_copied;
+        self.chunks_skipped += other.chunks_skipped;
+        self.bytes_copied += other.bytes_copied;
+    }
+}
+
+/// Returns true if both repositories derive the same ID from the same plaintext
+pub fn shares_ids(
+    source: &Repository<impl BackendClone + 'static>,
+    destination: &Repository<impl BackendClone + 'static>,
+) -> bool {
+    let source_settings = source.chunk_settings();
+    let destination_settings = destination.chunk_settings();
+    source_settings.id == destination_settings.id
+        && source_settings.hmac == destination_settings.hmac
+        && source.key().id_key() == destination.key().id_key()
+}
+
+/// Copies a single chunk, returning its ID in the destination, along with the length of its
+/// plaintext if it had to be written
+async fn copy_chunk(
+    mut source: Repository<impl BackendClone + 'static>,
+    mut destination: Repository<impl BackendClone + 'static>,
+    id: ChunkID,
+    shared_ids: bool,
+) -> Result<(ChunkID, Option<u64>)> {
+    if shared_ids && destination.has_chunk(id).await {
+        return Ok((id, None));
+    }
+    let data = source.read_chunk(id).await?;
+    let new_id = destination.chunk_id(&data);
+    if destination.has_chunk(new_id).await {
+        return Ok((new_id, None));
+    }
+    let length = data.len() as u64;
+    let (new_id, _) = destination.write_chunk(data).await?;
+    Ok((new_id, Some(length)))
+}
+
+/// Copies an archive, along with every chunk it refers to that the destination does not
+/// already contain, from `source` to `destination`
+///
+/// The copy keeps the name, timestamp, and metadata of the original, and is committed to
+/// `manifest`, which must be the destination's manifest. Objects keep the chunk boundaries
+/// they were stored with, so if the repositories use different chunkers, data later stored
+/// in the destination will not deduplicate against the copy.
+///
+/// # Panics
+///
+/// Will panic if a task copying a chunk panics
+pub async fn copy_archive<S, D>(
+    source: &mut Repository<S>,
+    archive: &StoredArchive,
+    destination: &mut Repository<D>,
+    manifest: &mut Manifest<D>,
+) -> Result<CopyStats>
+where
+    S: BackendClone + 'static,
+    D: BackendClone + 'static,
+{
+    let duration = archive.duration();
+    let mut archive = archive.load(source).await?.into_archive().await;
+    let shared_ids = shares_ids(source, destination);
+    let ids = archive
+        .objects
+        .values()
+        .flatten()
+        .map(|location| location.id)
+        .collect::<HashSet<_>>();
+
+    let mut stats = CopyStats::default();
+    let mut new_ids = HashMap::new();
+    let max_tasks = destination.queue_depth.max(1);
+    let mut tasks = VecDeque::new();
+    for id in ids {
+        let source = source.clone();
+        let destination = destination.clone();
+        tasks.push_back(Task::spawn(async move {
+            copy_chunk(source, destination, id, shared_ids)
+                .await
+                .map(|copied| (id, copied))
+        }));
+        while tasks.len() >= max_tasks {
+            // This unwrap is sound, since we can only be here if tasks has elements in it
+            let (id, (new_id, copied)) = tasks.pop_front().unwrap().await?;
+            new_ids.insert(id, new_id);
+            stats.record(copied);
+        }
+    }
+    for result in join_all(tasks).await {
+        let (id, (new_id, copied)) = result?;
+        new_ids.insert(id, new_id);
+        stats.record(copied);
+    }
+
+    for locations in archive.objects.values_mut() {
+        for location in locations {
+            location.id = new_ids[&location.id];
+        }
+    }
+    // Every chunk is now packed with the destination's settings
+    archive.chunk_settings = None;
+    let mut archive = ActiveArchive::from_archive(archive);
+    archive.set_duration(duration);
+    manifest
+        .commit_archive(destination, archive)
+        .await
+        .map_err(RepositoryError::from)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FastCDC;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::{ChunkSettings, Compression, Key};
+    use rand::prelude::*;
+    use std::io::Cursor;
+
+    fn get_repo_mem(key: Key, settings: ChunkSettings) -> Repository<impl BackendClone> {
+        let backend = Mem::new(settings, key.clone(), 4);
+        Repository::with(backend, settings, key, 2).unwrap()
+    }
+
+    async fn object(
+        repo: &mut Repository<impl BackendClone + 'static>,
+        archive: &ActiveArchive,
+    ) -> Vec<u8> {
+        let mut output = Vec::new();
+        archive.get_object(repo, "data", &mut output).await.unwrap();
+        output
+    }
+
+    // Copying an archive between repositories with different keys should reproduce its
+    // contents, and copying it again should not write any chunks
+    #[test]
+    fn copy_between_keys() {
+        smol::run(async {
+            let mut data = vec![0_u8; 1_000_000];
+            rand::thread_rng().fill_bytes(&mut data);
+            let mut source = get_repo_mem(Key::random(32), ChunkSettings::lightweight());
+            let mut source_manifest = Manifest::load(&source);
+            let mut archive = ActiveArchive::new("test");
+            archive.set_metadata("host", "source");
+            archive
+                .put_object(
+                    &FastCDC::default(),
+                    &mut source,
+                    "data",
+                    Cursor::new(data.clone()),
+                )
+                .await
+                .unwrap();
+            let chunks = archive.chunk_ids().len();
+            source_manifest
+                .commit_archive(&mut source, archive)
+                .await
+                .unwrap();
+            let stored = source_manifest.archives().await.remove(0);
+
+            let destination_settings = ChunkSettings {
+                compression: Compression::ZStd { level: 1 },
+                ..ChunkSettings::lightweight()
+            };
+            let mut destination = get_repo_mem(Key::random(32), destination_settings);
+            let mut destination_manifest = Manifest::load(&destination);
+            assert!(!shares_ids(&source, &destination));
+            let stats = copy_archive(
+                &mut source,
+                &stored,
+                &mut destination,
+                &mut destination_manifest,
+            )
+            .await
+            .unwrap();
+            assert_eq!(stats.chunks_copied, chunks);
+            assert_eq!(stats.chunks_skipped, 0);
+            assert_eq!(stats.bytes_copied, data.len() as u64);
+
+            let copies = destination_manifest.archives().await;
+            assert_eq!(copies.len(), 1);
+            assert_eq!(copies[0].name(), stored.name());
+            assert_eq!(copies[0].timestamp(), stored.timestamp());
+            let copy = copies[0].load(&mut destination).await.unwrap();
+            assert_eq!(copy.metadata().values["host"], "source");
+            assert_eq!(object(&mut destination, &copy).await, data);
+
+            let stats = copy_archive(
+                &mut source,
+                &stored,
+                &mut destination,
+                &mut destination_manifest,
+            )
+            .await
+            .unwrap();
+            assert_eq!(stats.chunks_copied, 0);
+            assert_eq!(stats.chunks_skipped, chunks);
+        });
+    }
+
+    // Repositories sharing a key should keep chunk IDs, and skip chunks without reading them
+    #[test]
+    fn copy_shared_key() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut data = vec![0_u8; 500_000];
+            rand::thread_rng().fill_bytes(&mut data);
+            let mut source = get_repo_mem(key.clone(), ChunkSettings::lightweight());
+            let mut source_manifest = Manifest::load(&source);
+            let mut archive = ActiveArchive::new("test");
+            archive
+                .put_object(
+                    &FastCDC::default(),
+                    &mut source,
+                    "data",
+                    Cursor::new(data.clone()),
+                )
+                .await
+                .unwrap();
+            source_manifest
+                .commit_archive(&mut source, archive)
+                .await
+                .unwrap();
+            let stored = source_manifest.archives().await.remove(0);
+            // Includes the chunks of the archive's listing tree
+            let ids = stored.load(&mut source).await.unwrap().chunk_ids();
+
+            let mut destination = get_repo_mem(key, ChunkSettings::lightweight());
+            let mut destination_manifest = Manifest::load(&destination);
+            assert!(shares_ids(&source, &destination));
+            copy_archive(
+                &mut source,
+                &stored,
+                &mut destination,
+                &mut destination_manifest,
+            )
+            .await
+            .unwrap();
+            let copy = destination_manifest.archives().await.remove(0);
+            let copy = copy.load(&mut destination).await.unwrap();
+            assert_eq!(copy.chunk_ids(), ids);
+            assert_eq!(object(&mut destination, &copy).await, data);
+        });
+    }
+}
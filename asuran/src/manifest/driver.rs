@@ -1,25 +1,106 @@
-use crate::chunker::AsyncChunker;
-use crate::manifest::archive::{ActiveArchive, Extent};
-use crate::manifest::target::{BackupObject, BackupTarget, RestoreObject, RestoreTarget};
+#![allow(clippy::used_underscore_binding)] // TODO: Fix this after clippy and thiserror start
+                                           // playing nice
+use crate::chunker::{AsyncChunker, ChunkerError};
+use crate::manifest::archive::{ActiveArchive, ArchiveError, Extent, StoreReport};
+use crate::manifest::hash::{HashingReader, HashingWriter, ObjectHasher};
+use crate::manifest::scan::{self, ScanHook, ScanVerdict, ScanningReader};
+use crate::manifest::target::{
+    BackupObject, BackupTarget, RestoreObject, RestoreTarget, SkipReason, SkippedEntry,
+};
 use crate::repository::{BackendClone, Repository};
 
-use asuran_core::manifest::listing::Node;
+use asuran_core::manifest::listing::{Node, ObjectHash};
 
 use async_trait::async_trait;
+use futures::future::select_all;
+use smol::Task;
 use thiserror::Error;
 
 use std::collections::HashMap;
+use std::fmt::Display;
 use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
 
 /// An error for things that can go wrong with drivers
 #[derive(Error, Debug)]
 pub enum DriverError {
     #[error("")]
     ArchiveError(#[from] crate::manifest::archive::ArchiveError),
+    #[error("Restored contents of {0} do not match the hash stored with them")]
+    HashMismatch(String),
 }
 
 type Result<T> = std::result::Result<T, DriverError>;
 
+/// Returns a report of a single object that could not be read
+fn failed(node: &Node, error: impl Display) -> StoreReport {
+    StoreReport {
+        failed: vec![SkippedEntry {
+            path: node.path.clone(),
+            reason: SkipReason::Failed(error.to_string()),
+        }],
+        ..StoreReport::default()
+    }
+}
+
+/// Returns the error an object could not be read with, or `None` if the error has nothing
+/// to do with reading the object, such as the repository failing
+fn read_error(error: &DriverError) -> Option<&std::io::Error> {
+    match error {
+        DriverError::ArchiveError(
+            ArchiveError::IO(error) | ArchiveError::Chunker(ChunkerError::IOError(error)),
+        ) => Some(error),
+        _ => None,
+    }
+}
+
+/// Loads a single `BackupObject` into the repository, under the given path in the
+/// given archive
+///
+/// Returns the hash of the contents of the object, with the extents of sparse objects
+/// hashed in order of their starting position, along with a summary of the chunks
+/// written.
+async fn store_backup_object<R: Read + Send + 'static>(
+    repo: &mut Repository<impl BackendClone>,
+    chunker: &impl AsyncChunker,
+    archive: &mut ActiveArchive,
+    path: &str,
+    backup_object: BackupObject<R>,
+) -> Result<(ObjectHash, StoreReport)> {
+    // TODO (#45): Store total size in archive
+    // let total_size = backup_object.total_size();
+    let hasher = Arc::new(Mutex::new(ObjectHasher::new(
+        archive.object_hash_algorithm(),
+    )));
+    // Pull ranges out of object and determine sparsity
+    let mut ranges = backup_object.ranges();
+    ranges.sort_by_key(|x| x.start);
+    // Determine sparsity and load object into repository
+    let range_count = ranges.len();
+    let report = if range_count == 0 {
+        archive.put_empty(path).await;
+        StoreReport::default()
+    } else if range_count == 1 {
+        let object = HashingReader::new(ranges.remove(0).object, hasher.clone());
+        archive.put_object(chunker, repo, path, object).await?
+    } else {
+        let mut readers: Vec<(Extent, HashingReader<R>)> = Vec::new();
+        for object in ranges {
+            let extent = Extent {
+                start: object.start,
+                end: object.end,
+            };
+            let object = HashingReader::new(object.object, hasher.clone());
+            readers.push((extent, object));
+        }
+        archive
+            .put_sparse_object(chunker, repo, path, readers)
+            .await?
+    };
+    let hash = hasher.lock().expect("Hasher lock poisoned").finish();
+    Ok((hash, report))
+}
+
 /// Defines a type that can, semi-automatically, drive the storage of objects from
 /// an associated `BackupTarget` into a repository.
 ///
@@ -35,6 +116,17 @@ pub trait BackupDriver<T: Read + Send + 'static>: BackupTarget<T> {
     /// route, otherwise use `store_object`.
     ///
     /// Stores objects in sub-namespaces of the namespace of the archive object provided
+    ///
+    /// The hash of the raw data of the object (the root namespace) is recorded in the
+    /// archive, see `manifest::hash`.
+    ///
+    /// If reading any of the namespaces fails, such as when the file is removed or
+    /// truncated while it is being read, the object is removed from every namespace, and
+    /// recorded in the `failed` entries of the report rather than returned as an error, so
+    /// that one bad object does not abort a whole backup. As with vetoed objects, callers
+    /// are responsible for removing failed nodes from the listing.
+    ///
+    /// Returns a summary of the chunks written across all of the namespaces.
     async fn raw_store_object<B: BackendClone, C: AsyncChunker + Send + 'static>(
         &self,
         repo: &mut Repository<B>,
@@ -42,55 +134,148 @@ pub trait BackupDriver<T: Read + Send + 'static>: BackupTarget<T> {
         archive: &ActiveArchive,
         node: Node,
         objects: HashMap<String, BackupObject<T>>,
-    ) -> Result<()> {
+    ) -> Result<StoreReport> {
+        let mut report = StoreReport::default();
         if node.is_file() {
+            let namespaces: Vec<String> = objects.keys().cloned().collect();
             for (namespace, backup_object) in objects {
-                let path = &node.path;
-                // TODO (#45): Store total size in archive
-                // let total_size = backup_object.total_size();
                 // Get a new archive with the specified namespace
                 let mut archive = archive.namespace_append(&namespace);
-                // Pull ranges out of object and determine sparsity
-                let mut ranges = backup_object.ranges();
-                // Determine sparsity and load object into repository
-                let range_count = ranges.len();
-                if range_count == 0 {
-                    archive.put_empty(path).await;
-                } else if range_count == 1 {
-                    let object = ranges.remove(0).object;
-                    archive.put_object(&chunker, repo, path, object).await?;
-                } else {
-                    let mut readers: Vec<(Extent, T)> = Vec::new();
-                    for object in ranges {
-                        let extent = Extent {
-                            start: object.start,
-                            end: object.end,
-                        };
-                        let object = object.object;
-                        readers.push((extent, object));
-                    }
+                let result =
+                    store_backup_object(repo, &chunker, &mut archive, &node.path, backup_object)
+                        .await;
+                let (hash, object_report) = match result {
+                    Ok(stored) => stored,
+                    Err(error) => match read_error(&error) {
+                        Some(read_error) => {
+                            report.merge(&failed(&node, read_error));
+                            break;
+                        }
+                        None => return Err(error),
+                    },
+                };
+                if namespace.is_empty() {
+                    archive.record_object_hash(&node.path, hash);
+                }
+                report.merge(&object_report);
+            }
+            if !report.failed.is_empty() {
+                for namespace in &namespaces {
                     archive
-                        .put_sparse_object(&chunker, repo, path, readers)
-                        .await?;
+                        .namespace_append(namespace)
+                        .remove_object(&node.path);
                 }
             }
         }
-        Ok(())
+        Ok(report)
     }
 
     /// Convenience method that performs a call to `self.backup_object` for you and
     /// routes the results into `self.raw_store_object`
+    ///
+    /// Objects the target can not open are recorded in the `failed` entries of the report.
     async fn store_object<B: BackendClone, C: AsyncChunker + Send + 'static>(
         &self,
         repo: &mut Repository<B>,
         chunker: C,
         archive: &ActiveArchive,
         node: Node,
-    ) -> Result<()> {
-        let objects = self.backup_object(node.clone()).await;
+    ) -> Result<StoreReport> {
+        let objects = match self.backup_object(node.clone()).await {
+            Ok(objects) => objects,
+            Err(error) => return Ok(failed(&node, error)),
+        };
         self.raw_store_object(repo, chunker, archive, node, objects)
             .await
     }
+
+    /// Performs the same operation as `store_object`, but passes the raw data of the
+    /// object (the root namespace) through a `FileScanner` provided by `hook` as it is
+    /// read, before it is chunked.
+    ///
+    /// If the scanner vetoes the object, it is removed from the archive, and its other
+    /// namespaces are not stored. Any chunks that were written before the veto are left
+    /// in the repository unreferenced. If the scanner tags the object, the tags are
+    /// stored alongside it, and can be retrieved with `scan::read_tags`.
+    ///
+    /// Targets may record nodes in their listing during `backup_object`, so callers are
+    /// responsible for removing vetoed nodes from the listing before storing it in the
+    /// archive, see `Listing::remove`.
+    ///
+    /// Objects that can not be read are handled like they are by `store_object`, their
+    /// scanners are never finished.
+    ///
+    /// Returns the verdict of the scanner, or `ScanVerdict::Accept` if the object was
+    /// not scanned or could not be read, along with a summary of the chunks written,
+    /// including those written before a veto.
+    async fn store_object_scanned<B: BackendClone, C: AsyncChunker + Send + 'static>(
+        &self,
+        repo: &mut Repository<B>,
+        chunker: C,
+        archive: &ActiveArchive,
+        node: Node,
+        hook: &dyn ScanHook,
+    ) -> Result<(ScanVerdict, StoreReport)> {
+        let mut objects = match self.backup_object(node.clone()).await {
+            Ok(objects) => objects,
+            Err(error) => return Ok((ScanVerdict::Accept, failed(&node, error))),
+        };
+        let scanner = if node.is_file() {
+            hook.begin(&node)
+        } else {
+            None
+        };
+        let scanner = if let Some(scanner) = scanner {
+            Arc::new(Mutex::new(scanner))
+        } else {
+            let report = self
+                .raw_store_object(repo, chunker, archive, node, objects)
+                .await?;
+            return Ok((ScanVerdict::Accept, report));
+        };
+        // Store the raw data first, so the scanner sees it before we commit to anything else
+        let mut data_archive = archive.namespace_append("");
+        let mut data_hash = None;
+        let mut report = StoreReport::default();
+        if let Some(data) = objects.remove("") {
+            let data = data.map_readers(|read| ScanningReader::new(read, scanner.clone()));
+            let result =
+                store_backup_object(repo, &chunker, &mut data_archive, &node.path, data).await;
+            let (hash, data_report) = match result {
+                Ok(stored) => stored,
+                Err(error) => match read_error(&error) {
+                    Some(read_error) => {
+                        data_archive.remove_object(&node.path);
+                        return Ok((ScanVerdict::Accept, failed(&node, read_error)));
+                    }
+                    None => return Err(error),
+                },
+            };
+            data_hash = Some(hash);
+            report = data_report;
+        }
+        let verdict = scanner.lock().expect("Scanner lock poisoned").finish();
+        if let (Some(hash), false) = (data_hash, matches!(verdict, ScanVerdict::Veto(_))) {
+            archive.record_object_hash(&node.path, hash);
+        }
+        match &verdict {
+            ScanVerdict::Veto(_) => {
+                data_archive.remove_object(&node.path);
+                return Ok((verdict, report));
+            }
+            ScanVerdict::Tag(tags) => {
+                let tag_report =
+                    scan::store_tags(&chunker, repo, archive, &node.path, tags).await?;
+                report.merge(&tag_report);
+            }
+            ScanVerdict::Accept => (),
+        }
+        let rest = self
+            .raw_store_object(repo, chunker, archive, node, objects)
+            .await?;
+        report.merge(&rest);
+        Ok((verdict, report))
+    }
 }
 
 /// Defines a type that can, semi-automatically, drive the retrieval of objects from
@@ -107,6 +292,10 @@ pub trait RestoreDriver<T: Write + Send + 'static>: RestoreTarget<T> {
     /// otherwise use retrive_object.
     ///
     /// Retrives objects from the stub-namespaces of the namespace of the object provided
+    ///
+    /// If the node carries a hash of the object's contents, the raw data of the object (the
+    /// root namespace) is hashed as it is written, and `DriverError::HashMismatch` is
+    /// returned if it does not match.
     async fn raw_retrieve_object<B: BackendClone>(
         &self,
         repo: &mut Repository<B>,
@@ -120,35 +309,47 @@ pub trait RestoreDriver<T: Write + Send + 'static>: RestoreTarget<T> {
                 // TODO (#45): get total size and do something with it
                 // Get a new archive with the specified namespace
                 let archive = archive.namespace_append(&namespace);
+                let expected = node.hash.as_ref().filter(|_| namespace.is_empty());
+                // Without an expected hash, the hasher is simply never checked
+                let hasher = Arc::new(Mutex::new(ObjectHasher::new(
+                    expected.map(|x| x.algorithm).unwrap_or_default(),
+                )));
                 // Pull ranges out of object and determine sparsity
                 let mut ranges = restore_object.ranges();
+                ranges.sort_by_key(|x| x.start);
                 // determin sparsity and retrieve object from repository
                 let range_count = ranges.len();
                 // This does not have a case for zero, as the target method should have already created
                 // an empty object
                 if range_count == 1 {
-                    let object = ranges.remove(0).object;
+                    let object = HashingWriter::new(ranges.remove(0).object, hasher.clone());
                     archive.get_object(repo, &path, object).await?;
                 // This used to be a if range count > 1, this may cause issues
                 } else {
-                    let mut writers: Vec<(Extent, T)> = Vec::new();
+                    let mut writers: Vec<(Extent, HashingWriter<T>)> = Vec::new();
                     for object in ranges {
                         let extent = Extent {
                             start: object.start,
                             end: object.end,
                         };
-                        let object = object.object;
+                        let object = HashingWriter::new(object.object, hasher.clone());
                         writers.push((extent, object));
                     }
                     archive.get_sparse_object(repo, &path, writers).await?;
                 }
+                if let Some(expected) = expected {
+                    let actual = hasher.lock().expect("Hasher lock poisoned").finish();
+                    if &actual != expected {
+                        return Err(DriverError::HashMismatch(path.clone()));
+                    }
+                }
             }
         }
         Ok(())
     }
 
     /// Retrieves an object, performing the call to BackupTarget::restore_object and raw_retrive_object
-    /// for you.
+    /// for you, then lets the target finish the object with `RestoreTarget::finish_object`.
     async fn retrieve_object<B: BackendClone>(
         &self,
         repo: &mut Repository<B>,
@@ -156,6 +357,56 @@ pub trait RestoreDriver<T: Write + Send + 'static>: RestoreTarget<T> {
         node: Node,
     ) -> Result<()> {
         let objects = self.restore_object(node.clone()).await;
-        self.raw_retrieve_object(repo, archive, node, objects).await
+        self.raw_retrieve_object(repo, archive, node.clone(), objects)
+            .await?;
+        self.finish_object(node).await;
+        Ok(())
+    }
+
+    /// Retrieves every object in `nodes`, up to `concurrency` of them at once, calling
+    /// `restored` with each one as it is finished
+    ///
+    /// Restoring objects one after another spends most of its time waiting on the backend
+    /// when there are many small ones, so each object is retrieved in its own task, with
+    /// its own writers. Objects can finish in any order, so the target has to be able to
+    /// restore an object before the directory it is in, as `FileSystemTarget` can.
+    ///
+    /// Stops at the first object that fails to be retrieved, dropping any that are still
+    /// in flight. `RestoreTarget::finish_restore` still has to be called afterwards.
+    #[allow(clippy::multiple_bound_locations)]
+    async fn retrieve_objects<B: BackendClone, F: FnMut(&Node) + Send>(
+        &self,
+        repo: &Repository<B>,
+        archive: &ActiveArchive,
+        nodes: Vec<Node>,
+        concurrency: usize,
+        mut restored: F,
+    ) -> Result<()>
+    where
+        Self: 'static,
+    {
+        let mut queue = Vec::new();
+        for node in nodes {
+            let target = self.clone();
+            let mut repo = repo.clone();
+            let archive = archive.clone();
+            queue.push(Task::spawn(async move {
+                target
+                    .retrieve_object(&mut repo, &archive, node.clone())
+                    .await
+                    .map(|()| node)
+            }));
+            if queue.len() >= concurrency.max(1) {
+                let (node, _, rest) = select_all(queue).await;
+                restored(&node?);
+                queue = rest;
+            }
+        }
+        while !queue.is_empty() {
+            let (node, _, rest) = select_all(queue).await;
+            restored(&node?);
+            queue = rest;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,174 @@
+//! Hashing of the full contents of objects
+//!
+//! Every chunk carries its own HMAC, which proves the chunk came out of the repository as
+//! it went in, but says nothing about whether the chunks of an object were put back
+//! together correctly. When objects are stored through a `BackupDriver`, their contents are
+//! also hashed as a whole, as they are read, and the hash is recorded on the object's node
+//! in the listing. `RestoreDriver` hashes the contents again as they are written back out,
+//! and fails the restore of any object whose hash does not match.
+pub use asuran_core::manifest::listing::{ObjectHash, ObjectHashAlgorithm};
+
+use sha2::{Digest, Sha256};
+
+use std::io::{Read, Result, Write};
+use std::sync::{Arc, Mutex};
+
+/// An in progress hash of the contents of an object
+#[derive(Clone)]
+pub enum ObjectHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+}
+
+impl ObjectHasher {
+    /// Creates a new hasher using the given algorithm
+    pub fn new(algorithm: ObjectHashAlgorithm) -> ObjectHasher {
+        match algorithm {
+            ObjectHashAlgorithm::Blake3 => ObjectHasher::Blake3(Box::new(blake3::Hasher::new())),
+            ObjectHashAlgorithm::Sha256 => ObjectHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    /// Feeds more of the object's contents into the hash
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            ObjectHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            ObjectHasher::Sha256(hasher) => hasher.input(data),
+        }
+    }
+
+    /// Returns the hash of everything fed in so far
+    pub fn finish(&self) -> ObjectHash {
+        match self {
+            ObjectHasher::Blake3(hasher) => ObjectHash {
+                algorithm: ObjectHashAlgorithm::Blake3,
+                digest: hasher.finalize().as_bytes().to_vec(),
+            },
+            ObjectHasher::Sha256(hasher) => ObjectHash {
+                algorithm: ObjectHashAlgorithm::Sha256,
+                digest: hasher.clone().result().to_vec(),
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for ObjectHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectHasher::Blake3(_) => write!(f, "ObjectHasher::Blake3"),
+            ObjectHasher::Sha256(_) => write!(f, "ObjectHasher::Sha256"),
+        }
+    }
+}
+
+/// A hasher shared between the readers or writers of each extent of an object
+pub type SharedHasher = Arc<Mutex<ObjectHasher>>;
+
+/// Wraps a `Read`, feeding everything read through it into a hasher
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: SharedHasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    /// Wraps the provided `Read`, feeding its contents to `hasher`
+    pub fn new(inner: R, hasher: SharedHasher) -> HashingReader<R> {
+        HashingReader { inner, hasher }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count > 0 {
+            self.hasher
+                .lock()
+                .expect("Hasher lock poisoned")
+                .update(&buf[..count]);
+        }
+        Ok(count)
+    }
+}
+
+/// Wraps a `Write`, feeding everything written through it into a hasher
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: SharedHasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    /// Wraps the provided `Write`, feeding everything written to it to `hasher`
+    pub fn new(inner: W, hasher: SharedHasher) -> HashingWriter<W> {
+        HashingWriter { inner, hasher }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.hasher
+            .lock()
+            .expect("Hasher lock poisoned")
+            .update(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes
+            .iter()
+            .map(|x| format!("{x:02x}"))
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
+    fn hash(algorithm: ObjectHashAlgorithm, data: &[u8]) -> ObjectHash {
+        let mut hasher = ObjectHasher::new(algorithm);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    #[test]
+    fn known_digests() {
+        let sha = hash(ObjectHashAlgorithm::Sha256, b"abc");
+        assert_eq!(
+            hex(&sha.digest),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let blake = hash(ObjectHashAlgorithm::Blake3, b"abc");
+        assert_eq!(
+            hex(&blake.digest),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    // Data passed through a reader and a writer, in pieces, should hash the same as all
+    // at once
+    #[test]
+    fn reader_writer_agree() {
+        let data = (0..=255_u8).cycle().take(10_000).collect::<Vec<_>>();
+        let expected = hash(ObjectHashAlgorithm::Blake3, &data);
+        let read_hasher = Arc::new(Mutex::new(ObjectHasher::new(ObjectHashAlgorithm::Blake3)));
+        let write_hasher = Arc::new(Mutex::new(ObjectHasher::new(ObjectHashAlgorithm::Blake3)));
+        let mut output = Vec::new();
+        for piece in data.chunks(3000) {
+            let mut reader = HashingReader::new(Cursor::new(piece), read_hasher.clone());
+            let mut writer = HashingWriter::new(&mut output, write_hasher.clone());
+            std::io::copy(&mut reader, &mut writer).unwrap();
+        }
+        assert_eq!(output, data);
+        assert_eq!(read_hasher.lock().unwrap().finish(), expected);
+        assert_eq!(write_hasher.lock().unwrap().finish(), expected);
+    }
+}
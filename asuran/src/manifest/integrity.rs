@@ -0,0 +1,262 @@
+//! Tamper evidence for the chunks committed along with each archive
+//!
+//! The HMAC on each chunk shows that the chunk has not been altered, but only when it is
+//! read, and only that it is *some* chunk written with the repository's key. Someone with
+//! write access to the backend can still delete chunks, or swap a chunk for another valid
+//! one, and this will go unnoticed until a restore needs the chunk.
+//!
+//! When a repository has been told to `Repository::record_integrity`, every archive
+//! committed through `Manifest` carries a `ChunkIntegrity` record. The record points to a
+//! listing of the ID and MAC tag of every chunk written since the previous commit, ordered
+//! by ID and stored as a chunk of its own, and holds a MAC over the archive's pointer and
+//! that listing. As the record is part of the archive's entry in the manifest, it is in turn
+//! covered by the manifest's own HMACs.
+//!
+//! `verify_integrity` walks the records of a set of archives, and reports chunks that are
+//! missing from the repository, or that are no longer the chunk that was committed.
+//!
+//! Only the chunks written while recording was enabled are covered. `FlatFile` repositories
+//! do not keep the records in their manifest.
+use crate::manifest::archive::{ArchiveError, StoredArchive};
+use crate::repository::backend::BackendError;
+use crate::repository::{BackendClone, Chunk, ChunkID, Repository, RepositoryError, HMAC};
+
+use futures::stream::StreamExt;
+use piper::Lock;
+use serde::{Deserialize, Serialize};
+
+use std::cmp::min;
+use std::sync::Arc;
+
+/// The first 32 bytes of a chunk's MAC tag, as recorded in a listing
+pub type ChunkTag = [u8; 32];
+
+/// The chunks written to a repository since the last archive was committed
+pub(crate) type WrittenChunks = Arc<Lock<Vec<(ChunkID, ChunkTag)>>>;
+
+/// Returns the part of `chunk`'s MAC tag recorded in a listing
+pub(crate) fn chunk_tag(chunk: &Chunk) -> ChunkTag {
+    let mac = chunk.mac();
+    let mut tag = [0_u8; 32];
+    let length = min(mac.len(), tag.len());
+    tag[..length].copy_from_slice(&mac[..length]);
+    tag
+}
+
+/// A record of the chunks committed along with an archive
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkIntegrity {
+    /// Pointer to the listing of the chunks, a list of `(ChunkID, ChunkTag)` pairs ordered
+    /// by ID
+    pub listing: ChunkID,
+    /// The number of chunks in the listing
+    pub count: u64,
+    /// The algorithm `mac` was produced with
+    pub hmac: HMAC,
+    /// MAC over the pointer of the archive the record belongs to, followed by the encoded
+    /// listing
+    #[serde(with = "serde_bytes")]
+    pub mac: Vec<u8>,
+}
+
+impl ChunkIntegrity {
+    /// Stores a listing of the chunks recorded by `repo` since the last commit, and returns
+    /// the record for the archive at `archive`
+    ///
+    /// Returns `None` if the repository is not recording integrity information. The index is
+    /// committed once the listing has been written.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing the listing fails
+    pub(crate) async fn record(
+        repo: &mut Repository<impl BackendClone>,
+        archive: ChunkID,
+    ) -> Result<Option<ChunkIntegrity>, BackendError> {
+        let Some(mut chunks) = repo.take_written().await else {
+            return Ok(None);
+        };
+        chunks.sort_unstable_by(|a, b| a.0.get_id().cmp(b.0.get_id()));
+        chunks.dedup_by_key(|chunk| chunk.0);
+        let bytes = rmp_serde::to_vec(&chunks)?;
+        let hmac = repo.chunk_settings().hmac;
+        let mac = hmac.mac(&signed_bytes(archive, &bytes), repo.key());
+        let (listing, _) = repo.write_chunk(bytes).await.map_err(|e| match e {
+            RepositoryError::BackendError(e) => e,
+            e => BackendError::Unknown(format!("Unable to write chunk listing: {e}")),
+        })?;
+        // The listing is covered by the record itself, and must not end up in the next one
+        repo.forget_written(listing).await;
+        repo.commit_index().await;
+        Ok(Some(ChunkIntegrity {
+            listing,
+            count: chunks.len() as u64,
+            hmac,
+            mac,
+        }))
+    }
+
+    /// Loads the listing of this record, belonging to the archive at `archive`, and checks
+    /// it against the record's MAC
+    ///
+    /// Returns `None` if the listing has been tampered with, or does not belong to this
+    /// record.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the listing can not be read
+    pub async fn load_listing(
+        &self,
+        repo: &mut Repository<impl BackendClone>,
+        archive: ChunkID,
+    ) -> Result<Option<Vec<(ChunkID, ChunkTag)>>, RepositoryError> {
+        let bytes = repo.read_chunk(self.listing).await?;
+        if !self
+            .hmac
+            .verify_hmac(&self.mac, &signed_bytes(archive, &bytes), repo.key())
+        {
+            return Ok(None);
+        }
+        let chunks: Vec<(ChunkID, ChunkTag)> = match rmp_serde::from_slice(&bytes) {
+            Ok(chunks) => chunks,
+            Err(_) => return Ok(None),
+        };
+        if chunks.len() as u64 == self.count {
+            Ok(Some(chunks))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// The bytes a record's MAC is calculated over
+fn signed_bytes(archive: ChunkID, listing: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(archive.get_id().len() + listing.len());
+    bytes.extend_from_slice(archive.get_id());
+    bytes.extend_from_slice(listing);
+    bytes
+}
+
+/// Summary of the work performed by `verify_integrity`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IntegrityReport {
+    /// The number of archives with a record that were checked
+    pub archives_checked: usize,
+    /// The number of chunks in their listings that were checked
+    pub chunks_checked: usize,
+    /// Archives whose listing is missing, or does not match their record
+    pub tampered_records: Vec<StoredArchive>,
+    /// Chunks that were committed, but are no longer in the repository
+    pub missing: Vec<ChunkID>,
+    /// Chunks that are in the repository, but are not the chunk that was committed
+    pub replaced: Vec<ChunkID>,
+}
+
+impl IntegrityReport {
+    /// Returns true if no sign of tampering was found
+    pub fn is_clean(&self) -> bool {
+        self.tampered_records.is_empty() && self.missing.is_empty() && self.replaced.is_empty()
+    }
+}
+
+/// Checks the chunks recorded by the `ChunkIntegrity` records of `archives` against the
+/// repository
+///
+/// Archives without a record are skipped. Chunks are only compared against their recorded
+/// ID and tag, use `Repository::check` to verify their contents.
+///
+/// # Errors
+///
+/// Will return `Err` if reading a chunk fails for any reason other than it missing from the
+/// index
+///
+/// # Panics
+///
+/// Will panic if the repository returns fewer chunks than were requested
+pub async fn verify_integrity(
+    repo: &mut Repository<impl BackendClone>,
+    archives: &[StoredArchive],
+) -> Result<IntegrityReport, ArchiveError> {
+    let mut report = IntegrityReport::default();
+    for archive in archives {
+        let Some(integrity) = &archive.integrity else {
+            continue;
+        };
+        report.archives_checked += 1;
+        let chunks = match integrity.load_listing(repo, archive.id()).await {
+            Ok(Some(chunks)) => chunks,
+            Ok(None) | Err(RepositoryError::ChunkNotFound) => {
+                report.tampered_records.push(archive.clone());
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut read = repo.read_raw_ahead(chunks.iter().map(|(id, _)| *id));
+        for (id, tag) in &chunks {
+            report.chunks_checked += 1;
+            match read.next().await.expect("Stream ended early") {
+                Ok(chunk) => {
+                    if chunk.get_id() != *id || chunk_tag(&chunk) != *tag {
+                        report.replaced.push(*id);
+                    }
+                }
+                Err(RepositoryError::ChunkNotFound) => report.missing.push(*id),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{ActiveArchive, Manifest};
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::{ChunkSettings, Key};
+
+    async fn committed_repo() -> (Repository<impl BackendClone>, Vec<StoredArchive>) {
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let backend = Mem::new(settings, key.clone(), 4);
+        let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+        repo.record_integrity();
+        let mut manifest = Manifest::load(&repo);
+        for i in 0..2_u8 {
+            repo.write_chunk(vec![i; 1024]).await.unwrap();
+            repo.write_chunk(vec![i + 10; 1024]).await.unwrap();
+            let archive = ActiveArchive::new(&format!("test {i}"));
+            manifest.commit_archive(&mut repo, archive).await.unwrap();
+        }
+        let archives = manifest.archives().await;
+        (repo, archives)
+    }
+
+    // Every chunk should be recorded exactly once, and check out
+    #[test]
+    fn records_verify() {
+        smol::run(async {
+            let (mut repo, archives) = committed_repo().await;
+            assert!(archives.iter().all(|archive| archive.integrity.is_some()));
+            let report = verify_integrity(&mut repo, &archives).await.unwrap();
+            assert!(report.is_clean());
+            assert_eq!(report.archives_checked, 2);
+            // Two chunks and an archive in each, along with the chunk of their (identical)
+            // listing tree, written only once. The record listings are covered by the
+            // records themselves.
+            assert_eq!(report.chunks_checked, 7);
+        });
+    }
+
+    // A record whose listing does not belong to it should be reported
+    #[test]
+    fn detects_swapped_listing() {
+        smol::run(async {
+            let (mut repo, mut archives) = committed_repo().await;
+            let other = archives[1].integrity.clone();
+            archives[0].integrity = other;
+            let report = verify_integrity(&mut repo, &archives).await.unwrap();
+            assert_eq!(report.tampered_records, vec![archives[0].clone()]);
+        });
+    }
+}
@@ -0,0 +1,381 @@
+//! Stores archive listings as a tree of chunks, so they can be read a directory at a time
+//!
+//! Each directory's entries are stored in chunks of their own, with every entry that is a
+//! directory pointing to the chunks holding its own entries. Directories with more than
+//! `ENTRIES_PER_CHUNK` entries are split across a chain of chunks. Only the pointer to the
+//! root of the tree is kept in the archive itself.
+//!
+//! This keeps the archive small, even for archives with millions of files, and lets
+//! consumers that only need part of the listing, such as listing the contents of an
+//! archive, or restoring a single directory, read only the chunks they need. Directories
+//! that did not change between backups produce the same chunks, and are deduplicated.
+//!
+//! Directory nodes are stored without their list of children, as their entries are found
+//! through the tree instead. `ListingTree::load` rebuilds the complete `Listing`.
+use crate::manifest::archive::ArchiveError;
+use crate::repository::backend::BackendError;
+use crate::repository::{BackendClone, ChunkID, Repository, RepositoryError};
+
+use asuran_core::manifest::listing::{Listing, Node};
+use futures::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, VecDeque};
+
+type Result<T> = std::result::Result<T, ArchiveError>;
+
+/// The largest number of entries stored in a single chunk of a directory's listing
+pub const ENTRIES_PER_CHUNK: usize = 4096;
+
+/// A node in a directory's listing, along with where to find its own entries
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TreeEntry {
+    /// The node, without its list of children
+    pub node: Node,
+    /// The first chunk of the node's own listing, if it is a directory
+    pub children: Option<ChunkID>,
+}
+
+/// The contents of one chunk of a directory's listing
+#[derive(Serialize, Deserialize)]
+struct ListingChunk {
+    entries: Vec<TreeEntry>,
+    /// The next chunk of the same directory, if it did not fit into this one
+    next: Option<ChunkID>,
+}
+
+/// A listing stored as a tree of chunks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ListingTree {
+    root: ChunkID,
+}
+
+impl ListingTree {
+    /// Refers to the tree whose root directory starts at the chunk `root`
+    pub fn new(root: ChunkID) -> ListingTree {
+        ListingTree { root }
+    }
+
+    /// The first chunk of the root directory's listing
+    pub fn root(&self) -> ChunkID {
+        self.root
+    }
+
+    /// Writes `listing` to the repository as a tree
+    ///
+    /// Directories are written before the directories containing them, so every chunk is
+    /// already in the repository by the time anything points to it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing any of the chunks fails
+    pub async fn store(
+        repo: &mut Repository<impl BackendClone>,
+        listing: &Listing,
+    ) -> Result<ListingTree> {
+        // Find every directory, parents first
+        let mut directories = vec![String::new()];
+        let mut index = 0;
+        while index < directories.len() {
+            let children = listing.children(&directories[index]);
+            directories.extend(
+                children
+                    .into_iter()
+                    .filter(|x| x.is_directory())
+                    .map(|x| x.path.clone()),
+            );
+            index += 1;
+        }
+        let mut written: HashMap<String, ChunkID> = HashMap::new();
+        for directory in directories.into_iter().rev() {
+            let entries = listing
+                .children(&directory)
+                .into_iter()
+                .map(|node| TreeEntry {
+                    node: node.drain_children(),
+                    children: written.remove(&node.path),
+                })
+                .collect::<Vec<_>>();
+            let id = write_directory(repo, entries).await?;
+            written.insert(directory, id);
+        }
+        Ok(ListingTree::new(written[""]))
+    }
+
+    /// Reads the entries of the directory whose listing starts at the chunk `id`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the directory's chunks can not be read or decoded
+    pub async fn read_directory(
+        repo: &mut Repository<impl BackendClone>,
+        id: ChunkID,
+    ) -> Result<Vec<TreeEntry>> {
+        let mut entries = Vec::new();
+        let mut next = Some(id);
+        while let Some(id) = next {
+            let chunk = read_chunk(repo, id).await?;
+            entries.extend(chunk.entries);
+            next = chunk.next;
+        }
+        Ok(entries)
+    }
+
+    /// Reads the entries of the directory at `path`, or of the root directory if `path`
+    /// is empty
+    ///
+    /// Only the directories leading up to `path` are read. Returns `None` if there is no
+    /// directory at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the chunks read can not be read or decoded
+    pub async fn entries(
+        &self,
+        repo: &mut Repository<impl BackendClone>,
+        path: &str,
+    ) -> Result<Option<Vec<TreeEntry>>> {
+        let path = path.trim_matches('/');
+        let mut entries = ListingTree::read_directory(repo, self.root).await?;
+        while !path.is_empty() {
+            let parent = entries.into_iter().find(|entry| {
+                let node = &entry.node.path;
+                path == node || path.starts_with(&format!("{node}/"))
+            });
+            let parent = match parent {
+                Some(TreeEntry {
+                    children: Some(id),
+                    node,
+                }) => {
+                    entries = ListingTree::read_directory(repo, id).await?;
+                    node
+                }
+                _ => return Ok(None),
+            };
+            if parent.path == path {
+                break;
+            }
+        }
+        Ok(Some(entries))
+    }
+
+    /// Reads the node at `path`, along with all of its descendants, in breadth-first
+    /// order
+    ///
+    /// Only the directories leading up to `path`, and those inside of it, are read.
+    /// Returns an empty `Vec` if there is no node at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the chunks read can not be read or decoded
+    pub async fn subtree(
+        &self,
+        repo: &mut Repository<impl BackendClone>,
+        path: &str,
+    ) -> Result<Vec<Node>> {
+        let path = path.trim_matches('/');
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        let entry = match self.entries(repo, parent).await? {
+            Some(entries) => entries.into_iter().find(|x| x.node.path == path),
+            None => None,
+        };
+        let mut output = Vec::new();
+        let mut queue = entry.into_iter().collect::<VecDeque<_>>();
+        while let Some(entry) = queue.pop_front() {
+            if let Some(id) = entry.children {
+                queue.extend(ListingTree::read_directory(repo, id).await?);
+            }
+            output.push(entry.node);
+        }
+        Ok(output)
+    }
+
+    /// Walks every node in the tree in breadth-first order, reading each directory only
+    /// once the walk reaches it
+    ///
+    /// Only the directories still waiting to be read, and the entries of the one being
+    /// walked, are held in memory.
+    pub fn walk<T: BackendClone>(&self, repo: &Repository<T>) -> impl Stream<Item = Result<Node>> {
+        let state = (
+            repo.clone(),
+            VecDeque::from(vec![self.root]),
+            VecDeque::<TreeEntry>::new(),
+        );
+        stream::unfold(Some(state), |state| async move {
+            let (mut repo, mut directories, mut entries) = state?;
+            while entries.is_empty() {
+                let id = directories.pop_front()?;
+                match read_chunk(&mut repo, id).await {
+                    Ok(chunk) => {
+                        entries.extend(chunk.entries);
+                        // Keep the rest of the directory next in line
+                        if let Some(next) = chunk.next {
+                            directories.push_front(next);
+                        }
+                    }
+                    // Stop at the first error
+                    Err(error) => return Some((Err(error), None)),
+                }
+            }
+            let entry = entries.pop_front()?;
+            if let Some(id) = entry.children {
+                directories.push_back(id);
+            }
+            Some((Ok(entry.node), Some((repo, directories, entries))))
+        })
+    }
+
+    /// Reads the whole tree back into a `Listing`, along with the IDs of every chunk the
+    /// tree is made up of
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the chunks can not be read or decoded
+    pub async fn load(
+        &self,
+        repo: &mut Repository<impl BackendClone>,
+    ) -> Result<(Listing, Vec<ChunkID>)> {
+        let mut listing = Listing::default();
+        let mut ids = Vec::new();
+        let mut queue = VecDeque::from(vec![(String::new(), self.root)]);
+        while let Some((parent, id)) = queue.pop_front() {
+            let chunk = read_chunk(repo, id).await?;
+            ids.push(id);
+            if let Some(next) = chunk.next {
+                queue.push_front((parent.clone(), next));
+            }
+            for entry in chunk.entries {
+                if let Some(id) = entry.children {
+                    queue.push_back((entry.node.path.clone(), id));
+                }
+                listing.add_child(&parent, entry.node);
+            }
+        }
+        Ok((listing, ids))
+    }
+}
+
+/// Writes the entries of a directory, returning the first chunk of its listing
+async fn write_directory(
+    repo: &mut Repository<impl BackendClone>,
+    entries: Vec<TreeEntry>,
+) -> Result<ChunkID> {
+    let mut chunks = entries
+        .chunks(ENTRIES_PER_CHUNK)
+        .map(<[TreeEntry]>::to_vec)
+        .collect::<Vec<_>>();
+    if chunks.is_empty() {
+        chunks.push(Vec::new());
+    }
+    // Write the chain back to front, so each chunk can point to the one after it
+    let mut next = None;
+    for entries in chunks.into_iter().rev() {
+        let bytes = rmp_serde::to_vec(&ListingChunk { entries, next })
+            .map_err(|e| RepositoryError::from(BackendError::from(e)))?;
+        next = Some(repo.write_chunk(bytes).await?.0);
+    }
+    Ok(next.expect("Directory listings always have at least one chunk"))
+}
+
+/// Reads and decodes one chunk of a directory's listing
+async fn read_chunk(repo: &mut Repository<impl BackendClone>, id: ChunkID) -> Result<ListingChunk> {
+    let bytes = repo.read_chunk(id).await?;
+    Ok(rmp_serde::from_slice(&bytes).map_err(|e| RepositoryError::from(BackendError::from(e)))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::{ChunkSettings, Key};
+    use asuran_core::manifest::listing::{NodeMetadata, NodeType};
+    use futures::stream::TryStreamExt;
+
+    fn node(path: &str, node_type: NodeType) -> Node {
+        Node {
+            path: path.to_string(),
+            total_length: 0,
+            total_size: 0,
+            extents: None,
+            node_type,
+            metadata: NodeMetadata::default(),
+            hash: None,
+        }
+    }
+
+    fn directory(path: &str) -> Node {
+        node(
+            path,
+            NodeType::Directory {
+                children: Vec::new(),
+            },
+        )
+    }
+
+    /// A listing with a directory too large to fit into a single chunk
+    fn get_listing() -> Listing {
+        let mut listing = Listing::default();
+        listing.add_child("", directory("a"));
+        listing.add_child("", node("d", NodeType::File));
+        listing.add_child("a", directory("a/b"));
+        listing.add_child("a/b", node("a/b/c", NodeType::File));
+        for i in 0..ENTRIES_PER_CHUNK + 10 {
+            listing.add_child("a", node(&format!("a/{i}"), NodeType::File));
+        }
+        listing
+    }
+
+    #[test]
+    fn store_load_round_trip() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let listing = get_listing();
+
+            let tree = ListingTree::store(&mut repo, &listing).await.unwrap();
+            let (loaded, ids) = tree.load(&mut repo).await.unwrap();
+            assert_eq!(loaded, listing);
+            // The root, "a" split across two chunks, and "a/b"
+            assert_eq!(ids.len(), 4);
+            // Unchanged listings produce the same tree
+            let again = ListingTree::store(&mut repo, &listing).await.unwrap();
+            assert_eq!(again, tree);
+
+            let walked = tree.walk(&repo).try_collect::<Vec<_>>().await.unwrap();
+            assert_eq!(walked.len(), ENTRIES_PER_CHUNK + 14);
+            assert_eq!(walked[0], directory("a"));
+            assert_eq!(walked[1], node("d", NodeType::File));
+            assert_eq!(walked.last().unwrap().path, "a/b/c");
+        });
+    }
+
+    #[test]
+    fn partial_reads() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let tree = ListingTree::store(&mut repo, &get_listing()).await.unwrap();
+
+            let root = tree.entries(&mut repo, "").await.unwrap().unwrap();
+            assert_eq!(root.len(), 2);
+            assert!(root[0].children.is_some());
+            assert!(root[1].children.is_none());
+            let a = tree.entries(&mut repo, "/a/").await.unwrap().unwrap();
+            assert_eq!(a.len(), ENTRIES_PER_CHUNK + 11);
+            let b = tree.entries(&mut repo, "a/b").await.unwrap().unwrap();
+            assert_eq!(b[0].node, node("a/b/c", NodeType::File));
+            assert!(tree.entries(&mut repo, "d").await.unwrap().is_none());
+            assert!(tree.entries(&mut repo, "a/x").await.unwrap().is_none());
+
+            let subtree = tree.subtree(&mut repo, "a/b").await.unwrap();
+            let paths = subtree.iter().map(|x| x.path.as_str()).collect::<Vec<_>>();
+            assert_eq!(paths, ["a/b", "a/b/c"]);
+            assert_eq!(tree.subtree(&mut repo, "d").await.unwrap().len(), 1);
+            assert!(tree.subtree(&mut repo, "nothing").await.unwrap().is_empty());
+        });
+    }
+}
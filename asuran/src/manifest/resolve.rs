@@ -0,0 +1,135 @@
+//! Resolves the ways a user can refer to an archive into the archive itself
+//!
+//! Besides by its full name, an archive can be referred to by:
+//!
+//! * Its index in the list of archives, as returned by `Manifest::archives`
+//! * `latest` for the most recently stored archive, or `latest~N` for the archive stored
+//!   N archives before it
+//! * A prefix of its name, or of the hexadecimal form of its ID, as long as no other
+//!   archive shares the prefix
+//!
+//! These are tried in that order, so an archive that happens to be named `latest` or `3`
+//! can always be found by its name.
+#![allow(clippy::used_underscore_binding)] // TODO: Fix this after clippy and thiserror start
+                                           // playing nice
+use crate::manifest::archive::ArchiveError;
+use crate::manifest::StoredArchive;
+
+use thiserror::Error;
+
+use std::cmp::Reverse;
+
+/// An error for archive references that do not lead to exactly one archive
+#[derive(Error, Debug)]
+pub enum ResolveError {
+    #[error("No archive matches {0}")]
+    NotFound(String),
+    #[error("{spec} is ambiguous, it matches the archives {}", .matches.join(", "))]
+    Ambiguous { spec: String, matches: Vec<String> },
+    #[error("Unable to load archive to match against its name")]
+    Archive(#[from] ArchiveError),
+}
+
+/// Finds the one archive in `archives` that `spec` refers to
+///
+/// # Errors
+///
+/// Will return `Err` if no archive matches, or if a prefix matches more than one archive
+pub fn resolve_archive(
+    archives: &[StoredArchive],
+    spec: &str,
+) -> Result<StoredArchive, ResolveError> {
+    let not_found = || ResolveError::NotFound(spec.to_string());
+    if let Some(archive) = archives.iter().find(|x| x.name() == spec) {
+        return Ok(archive.clone());
+    }
+    if let Some(archive) = archives
+        .iter()
+        .enumerate()
+        .find(|(index, _)| index.to_string() == spec)
+        .map(|(_, archive)| archive)
+    {
+        return Ok(archive.clone());
+    }
+    if let Some(back) = latest_offset(spec) {
+        let mut newest_first = archives.iter().collect::<Vec<_>>();
+        newest_first.sort_by_key(|x| Reverse(x.timestamp()));
+        return newest_first
+            .get(back)
+            .map(|archive| (*archive).clone())
+            .ok_or_else(not_found);
+    }
+    if spec.is_empty() {
+        return Err(not_found());
+    }
+    let id_prefix = spec.to_ascii_lowercase();
+    let matches = archives
+        .iter()
+        .filter(|x| x.name().starts_with(spec) || x.id().to_hex().starts_with(&id_prefix))
+        .collect::<Vec<_>>();
+    match matches.as_slice() {
+        [] => Err(not_found()),
+        [archive] => Ok((*archive).clone()),
+        _ => Err(ResolveError::Ambiguous {
+            spec: spec.to_string(),
+            matches: matches.iter().map(|x| x.name().to_string()).collect(),
+        }),
+    }
+}
+
+/// Returns how many archives before the latest one `spec` refers to, if it is of the
+/// form `latest` or `latest~N`
+fn latest_offset(spec: &str) -> Option<usize> {
+    match spec.strip_prefix("latest")? {
+        "" => Some(0),
+        rest => rest.strip_prefix('~')?.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::ChunkID;
+    use crate::time::Timestamp;
+
+    fn archive(name: &str, id: u8, seconds: i64) -> StoredArchive {
+        StoredArchive {
+            name: name.to_string(),
+            id: ChunkID::new(&[id; 32]),
+            timestamp: Timestamp::from_unix(seconds, 0).unwrap(),
+            ..StoredArchive::dummy_archive()
+        }
+    }
+
+    #[test]
+    fn resolves_references() {
+        // Not in timestamp order, as not every backend lists archives newest first
+        let archives = vec![
+            archive("home-2020-03-02", 0xab, 200),
+            archive("home-2020-03-01", 0xac, 100),
+            archive("etc-2020-03-03", 0x12, 300),
+            archive("1", 0xcd, 50),
+        ];
+        let resolve = |spec| resolve_archive(&archives, spec).map(|x| x.name().to_string());
+
+        assert_eq!(resolve("home-2020-03-01").unwrap(), "home-2020-03-01");
+        // Names win over indexes
+        assert_eq!(resolve("1").unwrap(), "1");
+        assert_eq!(resolve("2").unwrap(), "etc-2020-03-03");
+        assert_eq!(resolve("latest").unwrap(), "etc-2020-03-03");
+        assert_eq!(resolve("latest~2").unwrap(), "home-2020-03-01");
+        assert!(matches!(
+            resolve("latest~4"),
+            Err(ResolveError::NotFound(_))
+        ));
+        assert_eq!(resolve("etc").unwrap(), "etc-2020-03-03");
+        assert_eq!(resolve("ABAB").unwrap(), "home-2020-03-02");
+        assert!(matches!(
+            resolve("home"),
+            Err(ResolveError::Ambiguous { matches, .. }) if matches.len() == 2
+        ));
+        assert!(matches!(resolve("a"), Err(ResolveError::Ambiguous { .. })));
+        assert!(matches!(resolve("var"), Err(ResolveError::NotFound(_))));
+        assert!(matches!(resolve(""), Err(ResolveError::NotFound(_))));
+    }
+}
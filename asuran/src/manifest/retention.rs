@@ -0,0 +1,383 @@
+//! Retention policies, deciding which archives to keep when pruning a repository
+//!
+//! A policy is made up of a number of rules, each of which keeps some of the archives,
+//! with an archive being kept if any rule keeps it. The rules are applied independently
+//! of each other, so an archive kept by one rule still counts towards the others.
+//!
+//! The periodic rules (daily, weekly, monthly, and yearly) keep the newest archive in
+//! each of the most recent periods that have an archive in them. Periods are computed
+//! in UTC, so archives taken on machines in different timezones are grouped the same
+//! way, with weeks being ISO weeks.
+//!
+//! Policies can be restricted to archives carrying a set of tags, or whose names start
+//! with a prefix, allowing archives from different machines or jobs to be pruned
+//! separately. Archives outside a policy's filter are neither kept nor removed by it.
+//!
+//! Checkpoints of unfinished backups do not count towards any rule. They are removed,
+//! except for the newest one if it is newer than every finished archive, as it may still
+//! be needed to resume the backup it was taken of.
+use crate::manifest::StoredArchive;
+use crate::time::Timestamp;
+
+use chrono::prelude::*;
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::time::Duration;
+
+/// The rule that caused an archive to be kept
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum KeepReason {
+    /// The archive is one of the most recent `keep_last`
+    Last,
+    /// The archive is younger than `keep_within`
+    Within,
+    /// The archive is the newest of its day
+    Daily,
+    /// The archive is the newest of its week
+    Weekly,
+    /// The archive is the newest of its month
+    Monthly,
+    /// The archive is the newest of its year
+    Yearly,
+    /// The archive is the checkpoint of a backup that has not finished yet
+    Checkpoint,
+}
+
+impl fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KeepReason::Last => "last",
+            KeepReason::Within => "within",
+            KeepReason::Daily => "daily",
+            KeepReason::Weekly => "weekly",
+            KeepReason::Monthly => "monthly",
+            KeepReason::Yearly => "yearly",
+            KeepReason::Checkpoint => "checkpoint",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl KeepReason {
+    /// Returns the period an archive falls into, for the periodic rules
+    fn period(self, timestamp: Timestamp) -> (i32, u32) {
+        let timestamp = timestamp.to_chrono();
+        match self {
+            KeepReason::Last | KeepReason::Within | KeepReason::Checkpoint => {
+                unreachable!("{} is not a periodic rule", self)
+            }
+            KeepReason::Daily => (timestamp.year(), timestamp.ordinal()),
+            KeepReason::Weekly => {
+                let week = timestamp.iso_week();
+                (week.year(), week.week())
+            }
+            KeepReason::Monthly => (timestamp.year(), timestamp.month()),
+            KeepReason::Yearly => (timestamp.year(), 0),
+        }
+    }
+}
+
+/// A set of rules deciding which archives to keep
+///
+/// Every count defaults to zero, disabling the corresponding rule.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RetentionPolicy {
+    /// Keep this many of the most recent archives
+    pub keep_last: usize,
+    /// Keep the newest archive of this many of the most recent days
+    pub keep_daily: usize,
+    /// Keep the newest archive of this many of the most recent weeks
+    pub keep_weekly: usize,
+    /// Keep the newest archive of this many of the most recent months
+    pub keep_monthly: usize,
+    /// Keep the newest archive of this many of the most recent years
+    pub keep_yearly: usize,
+    /// Keep every archive taken within this long of the time the policy is applied
+    pub keep_within: Option<Duration>,
+    /// Only apply the policy to archives carrying all of these tags
+    pub tags: Vec<String>,
+    /// Only apply the policy to archives whose names start with this prefix
+    pub prefix: Option<String>,
+}
+
+/// The archives a `RetentionPolicy` keeps and removes
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Selection {
+    /// The archives to keep, newest first, along with the rules keeping them
+    pub keep: Vec<(StoredArchive, Vec<KeepReason>)>,
+    /// The archives to remove, newest first
+    pub remove: Vec<StoredArchive>,
+}
+
+impl RetentionPolicy {
+    /// Returns true if the policy has at least one rule keeping archives
+    pub fn has_rules(&self) -> bool {
+        self.keep_last > 0
+            || self.keep_daily > 0
+            || self.keep_weekly > 0
+            || self.keep_monthly > 0
+            || self.keep_yearly > 0
+            || self.keep_within.is_some()
+    }
+
+    /// Returns true if the policy applies to the given archive
+    pub fn matches(&self, archive: &StoredArchive) -> bool {
+        self.prefix
+            .as_ref()
+            .is_none_or(|prefix| archive.name().starts_with(prefix.as_str()))
+            && archive
+                .metadata()
+                .has_tags(self.tags.iter().map(String::as_str))
+    }
+
+    /// Decides which of the given archives to keep and which to remove, as of `now`
+    ///
+    /// Archives the policy does not apply to are left out of the selection entirely. A
+    /// policy without any rules keeps every archive.
+    pub fn select(&self, archives: &[StoredArchive], now: Timestamp) -> Selection {
+        let mut matching = archives
+            .iter()
+            .filter(|archive| self.matches(archive))
+            .cloned()
+            .collect::<Vec<_>>();
+        matching.sort_by_key(|archive| Reverse(archive.timestamp()));
+        if !self.has_rules() {
+            return Selection {
+                keep: matching.into_iter().map(|x| (x, Vec::new())).collect(),
+                remove: Vec::new(),
+            };
+        }
+        let (checkpoints, matching): (Vec<_>, Vec<_>) = matching
+            .into_iter()
+            .partition(|archive| archive.metadata().is_checkpoint());
+
+        let mut reasons = vec![Vec::new(); matching.len()];
+        for reason in reasons.iter_mut().take(self.keep_last) {
+            reason.push(KeepReason::Last);
+        }
+        if let Some(within) = self.keep_within {
+            for (archive, reason) in matching.iter().zip(reasons.iter_mut()) {
+                // Archives from the future are as young as they get
+                let age = now.duration_since(archive.timestamp()).unwrap_or_default();
+                if age <= within {
+                    reason.push(KeepReason::Within);
+                }
+            }
+        }
+        let periodic = [
+            (KeepReason::Daily, self.keep_daily),
+            (KeepReason::Weekly, self.keep_weekly),
+            (KeepReason::Monthly, self.keep_monthly),
+            (KeepReason::Yearly, self.keep_yearly),
+        ];
+        for (rule, count) in periodic.iter().copied() {
+            let mut last_period = None;
+            let mut kept = 0;
+            for (archive, reason) in matching.iter().zip(reasons.iter_mut()) {
+                if kept == count {
+                    break;
+                }
+                // Archives are sorted newest first, so the first archive seen in each
+                // period is the newest one in it
+                let period = rule.period(archive.timestamp());
+                if last_period != Some(period) {
+                    reason.push(rule);
+                    last_period = Some(period);
+                    kept += 1;
+                }
+            }
+        }
+
+        // Only the newest checkpoint can still be resumed from, and only if no backup has
+        // finished since it was taken
+        let newest = matching.first().map(StoredArchive::timestamp);
+        for (index, checkpoint) in checkpoints.iter().enumerate() {
+            if index == 0 && newest.is_none_or(|newest| checkpoint.timestamp() > newest) {
+                reasons.push(vec![KeepReason::Checkpoint]);
+            } else {
+                reasons.push(Vec::new());
+            }
+        }
+
+        let mut selection = Selection::default();
+        for (archive, reason) in matching.into_iter().chain(checkpoints).zip(reasons) {
+            if reason.is_empty() {
+                selection.remove.push(archive);
+            } else {
+                selection.keep.push((archive, reason));
+            }
+        }
+        selection
+            .keep
+            .sort_by_key(|(archive, _)| Reverse(archive.timestamp()));
+        selection
+            .remove
+            .sort_by_key(|archive| Reverse(archive.timestamp()));
+        selection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ArchiveMetadata;
+    use crate::repository::ChunkID;
+
+    fn archive(name: &str, timestamp: &str, tags: &[&str]) -> StoredArchive {
+        let metadata = ArchiveMetadata {
+            tags: tags.iter().map(|x| (*x).to_string()).collect(),
+            ..ArchiveMetadata::default()
+        };
+        StoredArchive {
+            name: name.to_string(),
+            id: ChunkID::random_id(),
+            timestamp: Timestamp::parse_rfc3339(timestamp).unwrap(),
+            metadata,
+            integrity: None,
+            signature: None,
+            duration: None,
+        }
+    }
+
+    fn names(archives: &[StoredArchive]) -> Vec<&str> {
+        archives.iter().map(StoredArchive::name).collect()
+    }
+
+    fn kept(selection: &Selection) -> Vec<&str> {
+        selection.keep.iter().map(|(x, _)| x.name()).collect()
+    }
+
+    fn now() -> Timestamp {
+        Timestamp::parse_rfc3339("2020-03-01T12:00:00+00:00").unwrap()
+    }
+
+    // Two archives a day for the last twenty days of February 2020
+    fn history() -> Vec<StoredArchive> {
+        let mut archives = Vec::new();
+        for day in 10..=29 {
+            for hour in &[3, 15] {
+                let timestamp = format!("2020-02-{day:02}T{hour:02}:00:00+00:00");
+                archives.push(archive(&format!("{day}-{hour}"), &timestamp, &[]));
+            }
+        }
+        archives
+    }
+
+    #[test]
+    fn keep_last() {
+        let policy = RetentionPolicy {
+            keep_last: 3,
+            ..RetentionPolicy::default()
+        };
+        let selection = policy.select(&history(), now());
+        assert_eq!(kept(&selection), vec!["29-15", "29-3", "28-15"]);
+        assert_eq!(selection.remove.len(), 37);
+        assert_eq!(names(&selection.remove)[0], "28-3");
+    }
+
+    #[test]
+    fn keep_daily_and_weekly() {
+        let policy = RetentionPolicy {
+            keep_daily: 3,
+            keep_weekly: 2,
+            ..RetentionPolicy::default()
+        };
+        let selection = policy.select(&history(), now());
+        // The 23rd is the last archive of the week before the one containing the 29th
+        assert_eq!(kept(&selection), vec!["29-15", "28-15", "27-15", "23-15"]);
+        assert_eq!(
+            selection.keep[0].1,
+            vec![KeepReason::Daily, KeepReason::Weekly]
+        );
+        assert_eq!(selection.keep[3].1, vec![KeepReason::Weekly]);
+    }
+
+    #[test]
+    fn keep_monthly_and_yearly() {
+        let mut archives = history();
+        archives.push(archive("january", "2020-01-15T00:00:00+00:00", &[]));
+        archives.push(archive("old", "2018-06-01T00:00:00+00:00", &[]));
+        archives.push(archive("older", "2018-05-01T00:00:00+00:00", &[]));
+        let policy = RetentionPolicy {
+            keep_monthly: 2,
+            keep_yearly: 5,
+            ..RetentionPolicy::default()
+        };
+        let selection = policy.select(&archives, now());
+        assert_eq!(kept(&selection), vec!["29-15", "january", "old"]);
+    }
+
+    #[test]
+    fn keep_within() {
+        let policy = RetentionPolicy {
+            keep_within: Some(Duration::from_hours(48)),
+            ..RetentionPolicy::default()
+        };
+        let selection = policy.select(&history(), now());
+        assert_eq!(kept(&selection), vec!["29-15", "29-3", "28-15"]);
+        assert!(selection
+            .keep
+            .iter()
+            .all(|(_, reasons)| reasons == &[KeepReason::Within]));
+    }
+
+    #[test]
+    fn filters() {
+        let archives = vec![
+            archive("laptop-1", "2020-02-01T00:00:00+00:00", &["nightly"]),
+            archive("laptop-2", "2020-02-02T00:00:00+00:00", &["nightly"]),
+            archive("laptop-3", "2020-02-03T00:00:00+00:00", &[]),
+            archive("server-1", "2020-02-01T00:00:00+00:00", &["nightly"]),
+            archive("server-2", "2020-02-02T00:00:00+00:00", &["nightly"]),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            tags: vec!["nightly".to_string()],
+            prefix: Some("laptop-".to_string()),
+            ..RetentionPolicy::default()
+        };
+        let selection = policy.select(&archives, now());
+        assert_eq!(kept(&selection), vec!["laptop-2"]);
+        assert_eq!(names(&selection.remove), vec!["laptop-1"]);
+    }
+
+    #[test]
+    fn checkpoints() {
+        let checkpoint = ArchiveMetadata::CHECKPOINT_TAG;
+        let mut archives = history();
+        archives.push(archive("stale", "2020-02-20T09:00:00+00:00", &[checkpoint]));
+        archives.push(archive("older", "2020-02-29T18:00:00+00:00", &[checkpoint]));
+        archives.push(archive(
+            "newest",
+            "2020-02-29T21:00:00+00:00",
+            &[checkpoint],
+        ));
+        let policy = RetentionPolicy {
+            keep_last: 2,
+            ..RetentionPolicy::default()
+        };
+        let selection = policy.select(&archives, now());
+        // Checkpoints do not count towards the rules
+        assert_eq!(kept(&selection), vec!["newest", "29-15", "29-3"]);
+        assert_eq!(selection.keep[0].1, vec![KeepReason::Checkpoint]);
+        let removed = names(&selection.remove);
+        assert_eq!(removed[0], "older");
+        assert!(removed.contains(&"stale"));
+
+        // Once a backup finishes, the checkpoints before it are no longer needed
+        archives.push(archive("finished", "2020-02-29T22:00:00+00:00", &[]));
+        let selection = policy.select(&archives, now());
+        assert_eq!(kept(&selection), vec!["finished", "29-15"]);
+        assert_eq!(names(&selection.remove)[..2], ["newest", "older"]);
+    }
+
+    #[test]
+    fn no_rules() {
+        let policy = RetentionPolicy::default();
+        assert!(!policy.has_rules());
+        let selection = policy.select(&history(), now());
+        assert_eq!(selection.keep.len(), 40);
+        assert!(selection.remove.is_empty());
+    }
+}
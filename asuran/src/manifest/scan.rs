@@ -0,0 +1,113 @@
+//! Hooks for inspecting the content of files as they are stored.
+//!
+//! A `ScanHook` is handed the content of each file as it is read during a store,
+//! before it reaches the chunker, allowing virus scanners, DLP checks, and other
+//! policy engines to be integrated into the backup path without requiring a
+//! separate full read of the dataset.
+//!
+//! Once the content of a file has been read, its scanner decides whether the file
+//! should be stored as normal, stored with a set of tags, or vetoed entirely. See
+//! `BackupDriver::store_object_scanned` for details.
+use crate::chunker::AsyncChunker;
+use crate::manifest::archive::{ActiveArchive, ArchiveError, StoreReport};
+use crate::repository::{BackendClone, Repository};
+
+use asuran_core::manifest::listing::Node;
+
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+
+/// The namespace, relative to the root of an archive, that scan tags are stored in
+pub const TAG_NAMESPACE: &str = "scan:tags";
+
+/// The decision a `FileScanner` has made about a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    /// Store the file as normal
+    Accept,
+    /// Store the file, recording the provided tags alongside it
+    Tag(Vec<String>),
+    /// Do not store the file, for the provided reason
+    Veto(String),
+}
+
+/// Inspects the content of a single file
+pub trait FileScanner: Send {
+    /// Called with each block of the file's content, in order, as it is read
+    fn update(&mut self, data: &[u8]);
+    /// Called once all of the file's content has been read
+    fn finish(&mut self) -> ScanVerdict;
+}
+
+/// Provides a `FileScanner` for each file being stored
+pub trait ScanHook: Send + Sync {
+    /// Called before the content of a file is read
+    ///
+    /// Returns None if the file does not need to be scanned.
+    fn begin(&self, node: &Node) -> Option<Box<dyn FileScanner>>;
+}
+
+/// A `Read` that passes everything read through it to a shared `FileScanner`
+pub struct ScanningReader<R: Read> {
+    inner: R,
+    scanner: Arc<Mutex<Box<dyn FileScanner>>>,
+}
+
+impl<R: Read> ScanningReader<R> {
+    /// Wraps the provided `Read`, feeding its contents to `scanner`
+    pub fn new(inner: R, scanner: Arc<Mutex<Box<dyn FileScanner>>>) -> ScanningReader<R> {
+        ScanningReader { inner, scanner }
+    }
+}
+
+impl<R: Read> Read for ScanningReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count > 0 {
+            self.scanner
+                .lock()
+                .expect("Scanner lock poisoned")
+                .update(&buf[..count]);
+        }
+        Ok(count)
+    }
+}
+
+/// Stores the tags a `FileScanner` applied to the object at the given path
+pub async fn store_tags(
+    chunker: &impl AsyncChunker,
+    repository: &mut Repository<impl BackendClone>,
+    archive: &ActiveArchive,
+    path: &str,
+    tags: &[String],
+) -> Result<StoreReport, ArchiveError> {
+    let bytes = rmp_serde::to_vec(tags)
+        .map_err(|e| ArchiveError::IO(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+    archive
+        .namespace_append(TAG_NAMESPACE)
+        .put_object(chunker, repository, path, Cursor::new(bytes))
+        .await
+}
+
+/// Reads the tags a `FileScanner` applied to the object at the given path
+///
+/// Returns None if the object was not tagged.
+pub async fn read_tags(
+    repository: &mut Repository<impl BackendClone>,
+    archive: &ActiveArchive,
+    path: &str,
+) -> Result<Option<Vec<String>>, ArchiveError> {
+    let mut bytes = Vec::new();
+    archive
+        .namespace_append(TAG_NAMESPACE)
+        .get_object(repository, path, &mut bytes)
+        .await?;
+    if bytes.is_empty() {
+        Ok(None)
+    } else {
+        let tags = rmp_serde::from_slice(&bytes).map_err(|e| {
+            ArchiveError::IO(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        })?;
+        Ok(Some(tags))
+    }
+}
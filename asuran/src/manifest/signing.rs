@@ -0,0 +1,324 @@
+//! Signing of archives with Ed25519 keys
+//!
+//! Anyone holding a repository's key can write archives to it, and the HMACs on the
+//! manifest can not tell them apart. Archives can additionally be signed with a
+//! `SigningKey`, which is kept separate from the repository key, and only handed to the
+//! clients that are allowed to make backups. Hosts restoring from the repository then
+//! only need the matching `VerifyingKey` to prove an archive was produced by one of them.
+//!
+//! The signature covers the archive's entry in the manifest: its name, timestamp,
+//! metadata, integrity record, and pointer. As the pointer is derived from the contents
+//! of the archive, this covers the archive's listing and every object in it as well.
+//!
+//! `FlatFile` repositories do not keep signatures in their manifest.
+#![allow(clippy::used_underscore_binding)] // TODO: Fix this after clippy and thiserror start
+                                           // playing nice
+use crate::manifest::archive::StoredArchive;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, SECRET_KEY_LENGTH};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Prefix of the bytes an archive signature is calculated over, keeping signatures made
+/// for archives from being valid for anything else
+const DOMAIN: &[u8] = b"asuran archive signature v1\0";
+
+/// Error describing things that can go wrong with signing archives
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    #[error("Invalid signing or verifying key")]
+    InvalidKey,
+    #[error("Archive {0} is not signed")]
+    Unsigned(String),
+    #[error("Archive {0} has an invalid signature")]
+    Invalid(String),
+    #[error("Archive {0} is signed by the untrusted key {1}")]
+    Untrusted(String, VerifyingKey),
+}
+
+/// An Ed25519 key used to sign archives
+///
+/// The key material is wiped from memory when the key is dropped, and is left out of its
+/// `Debug` output.
+pub struct SigningKey(Keypair);
+
+impl SigningKey {
+    /// Securely generates a new random signing key
+    pub fn generate() -> SigningKey {
+        SigningKey(Keypair::generate(&mut OsRng))
+    }
+
+    /// Loads a signing key from its 32 secret bytes
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `bytes` is not 32 bytes long
+    pub fn from_bytes(bytes: &[u8]) -> Result<SigningKey, SignatureError> {
+        let secret = SecretKey::from_bytes(bytes).map_err(|_| SignatureError::InvalidKey)?;
+        let public = PublicKey::from(&secret);
+        Ok(SigningKey(Keypair { secret, public }))
+    }
+
+    /// Returns the secret bytes of this key, in a buffer that is wiped when dropped
+    pub fn to_bytes(&self) -> Zeroizing<[u8; SECRET_KEY_LENGTH]> {
+        Zeroizing::new(self.0.secret.to_bytes())
+    }
+
+    /// Loads a signing key from its base64 encoding, as produced by `encode`
+    ///
+    /// Surrounding whitespace is ignored.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `encoded` is not a valid encoded key
+    pub fn decode(encoded: &str) -> Result<SigningKey, SignatureError> {
+        let bytes =
+            Zeroizing::new(base64::decode(encoded.trim()).map_err(|_| SignatureError::InvalidKey)?);
+        SigningKey::from_bytes(&bytes)
+    }
+
+    /// Encodes the secret bytes of this key as base64, in a buffer that is wiped when
+    /// dropped
+    pub fn encode(&self) -> Zeroizing<String> {
+        Zeroizing::new(base64::encode(&self.to_bytes()[..]))
+    }
+
+    /// Returns the key archives signed with this key are verified with
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.0.public.to_bytes())
+    }
+
+    /// Signs the entry of an archive in the manifest
+    ///
+    /// Any signature the archive already carries is ignored.
+    pub fn sign(&self, archive: &StoredArchive) -> ArchiveSignature {
+        let signature = self.0.sign(&signed_bytes(archive));
+        ArchiveSignature {
+            key: self.verifying_key(),
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("verifying_key", &self.verifying_key())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The public half of a `SigningKey`, used to verify the archives it signed
+///
+/// Displayed and parsed as base64.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VerifyingKey([u8; 32]);
+
+impl VerifyingKey {
+    /// Returns the bytes of this key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", base64::encode(self.0))
+    }
+}
+
+impl FromStr for VerifyingKey {
+    type Err = SignatureError;
+    fn from_str(input: &str) -> Result<VerifyingKey, SignatureError> {
+        let bytes = base64::decode(input.trim()).map_err(|_| SignatureError::InvalidKey)?;
+        // Make sure the bytes are actually a point on the curve
+        let key = PublicKey::from_bytes(&bytes).map_err(|_| SignatureError::InvalidKey)?;
+        Ok(VerifyingKey(key.to_bytes()))
+    }
+}
+
+/// A signature over an archive's entry in the manifest, along with the key that made it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ArchiveSignature {
+    /// The key the signature can be verified with
+    pub key: VerifyingKey,
+    /// The Ed25519 signature
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+/// The outcome of checking the signature of an archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SignatureStatus {
+    /// The archive does not carry a signature
+    Unsigned,
+    /// The archive carries a signature that does not match its contents
+    Invalid,
+    /// The archive was signed by a key that is not trusted
+    Untrusted(VerifyingKey),
+    /// The archive was signed by a trusted key
+    Trusted(VerifyingKey),
+}
+
+impl SignatureStatus {
+    /// Checks the signature of `archive`, trusting the keys in `trusted`
+    pub fn of(archive: &StoredArchive, trusted: &[VerifyingKey]) -> SignatureStatus {
+        let Some(signature) = archive.signature() else {
+            return SignatureStatus::Unsigned;
+        };
+        let valid = PublicKey::from_bytes(signature.key.as_bytes())
+            .ok()
+            .zip(Signature::try_from(&signature.signature[..]).ok())
+            .is_some_and(|(key, bytes)| key.verify_strict(&signed_bytes(archive), &bytes).is_ok());
+        if !valid {
+            SignatureStatus::Invalid
+        } else if trusted.contains(&signature.key) {
+            SignatureStatus::Trusted(signature.key)
+        } else {
+            SignatureStatus::Untrusted(signature.key)
+        }
+    }
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureStatus::Unsigned => write!(f, "Unsigned"),
+            SignatureStatus::Invalid => write!(f, "Invalid"),
+            SignatureStatus::Untrusted(key) => write!(f, "Untrusted ({key})"),
+            SignatureStatus::Trusted(key) => write!(f, "Trusted ({key})"),
+        }
+    }
+}
+
+/// Which archives are accepted, based on their signatures
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SignaturePolicy {
+    /// The keys archives may be signed by
+    pub trusted: Vec<VerifyingKey>,
+    /// Reject archives that are not signed by a trusted key
+    ///
+    /// Archives with an invalid signature are always rejected.
+    pub require_signed: bool,
+}
+
+impl SignaturePolicy {
+    /// Checks the signature of `archive` against this policy
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the archive's signature is invalid, or if signatures are
+    /// required, and the archive is not signed by a trusted key
+    pub fn check(&self, archive: &StoredArchive) -> Result<SignatureStatus, SignatureError> {
+        let status = SignatureStatus::of(archive, &self.trusted);
+        match status {
+            SignatureStatus::Invalid => Err(SignatureError::Invalid(archive.name().to_string())),
+            SignatureStatus::Unsigned if self.require_signed => {
+                Err(SignatureError::Unsigned(archive.name().to_string()))
+            }
+            SignatureStatus::Untrusted(key) if self.require_signed => {
+                Err(SignatureError::Untrusted(archive.name().to_string(), key))
+            }
+            status => Ok(status),
+        }
+    }
+}
+
+/// The bytes the signature of an archive is calculated over
+fn signed_bytes(archive: &StoredArchive) -> Vec<u8> {
+    let mut unsigned = archive.clone();
+    unsigned.signature = None;
+    let mut bytes = DOMAIN.to_vec();
+    // StoredArchive only contains types that can not fail to serialize
+    rmp_serde::encode::write(&mut bytes, &unsigned).expect("Unable to serialize archive");
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed() -> (SigningKey, StoredArchive) {
+        let key = SigningKey::generate();
+        let mut archive = StoredArchive::dummy_archive();
+        archive.metadata.tags.insert("daily".to_string());
+        archive.signature = Some(key.sign(&archive));
+        (key, archive)
+    }
+
+    #[test]
+    fn sign_verify() {
+        let (key, archive) = signed();
+        let trusted = [key.verifying_key()];
+        assert_eq!(
+            SignatureStatus::of(&archive, &trusted),
+            SignatureStatus::Trusted(key.verifying_key())
+        );
+        assert_eq!(
+            SignatureStatus::of(&archive, &[]),
+            SignatureStatus::Untrusted(key.verifying_key())
+        );
+    }
+
+    // Changing any part of the archive's entry should invalidate the signature
+    #[test]
+    fn tamper() {
+        let (key, archive) = signed();
+        let trusted = [key.verifying_key()];
+        let mut renamed = archive.clone();
+        renamed.name = "Other".to_string();
+        assert_eq!(
+            SignatureStatus::of(&renamed, &trusted),
+            SignatureStatus::Invalid
+        );
+        let mut retagged = archive;
+        retagged.metadata.tags.clear();
+        assert_eq!(
+            SignatureStatus::of(&retagged, &trusted),
+            SignatureStatus::Invalid
+        );
+    }
+
+    #[test]
+    fn policy() {
+        let (key, archive) = signed();
+        let unsigned = StoredArchive::dummy_archive();
+        let lax = SignaturePolicy::default();
+        assert!(lax.check(&unsigned).is_ok());
+        assert!(lax.check(&archive).is_ok());
+        let strict = SignaturePolicy {
+            trusted: vec![key.verifying_key()],
+            require_signed: true,
+        };
+        assert!(strict.check(&archive).is_ok());
+        assert!(matches!(
+            strict.check(&unsigned),
+            Err(SignatureError::Unsigned(_))
+        ));
+        let other = SignaturePolicy {
+            trusted: vec![SigningKey::generate().verifying_key()],
+            require_signed: true,
+        };
+        assert!(matches!(
+            other.check(&archive),
+            Err(SignatureError::Untrusted(_, _))
+        ));
+    }
+
+    #[test]
+    fn encode_decode() {
+        let key = SigningKey::generate();
+        let decoded = SigningKey::decode(&key.encode()).unwrap();
+        assert_eq!(key.verifying_key(), decoded.verifying_key());
+        let verifying = key.verifying_key().to_string().parse::<VerifyingKey>();
+        assert_eq!(verifying.unwrap(), key.verifying_key());
+        assert!(SigningKey::decode("not a key").is_err());
+        assert!(format!("{key:?}").find(&*key.encode()).is_none());
+    }
+}
@@ -1,4 +1,7 @@
 pub mod filesystem;
+pub mod walk;
+#[cfg(windows)]
+pub mod windows;
 
 pub use filesystem::FileSystemTarget;
 
@@ -7,7 +10,101 @@ pub use asuran_core::manifest::listing::*;
 use async_trait::async_trait;
 
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// The reason an entry found while walking a target was left out of its listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SkipReason {
+    /// The path matched the contained exclusion pattern
+    Excluded(String),
+    /// The entry could not be read, for the contained reason
+    Unreadable(String),
+    /// The entry is a special file that can not be stored, such as a socket
+    SpecialFile,
+    /// The entry is a directory on a different filesystem than the root of the target
+    OtherFilesystem,
+    /// The entry is a directory containing the contained marker file, such as a
+    /// `CACHEDIR.TAG`
+    Marked(String),
+    /// A scan hook refused to let the file be stored, for the contained reason
+    Vetoed(String),
+    /// Restoring the entry would have written outside of the target, for the contained
+    /// reason
+    Unsafe(String),
+    /// The entry was listed, but could not be opened or read while it was being stored,
+    /// for the contained reason
+    Failed(String),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Excluded(pattern) => write!(f, "excluded by pattern {pattern}"),
+            SkipReason::Unreadable(error) => write!(f, "unreadable: {error}"),
+            SkipReason::SpecialFile => write!(f, "special file"),
+            SkipReason::OtherFilesystem => write!(f, "on another filesystem"),
+            SkipReason::Marked(marker) => write!(f, "marked by {marker}"),
+            SkipReason::Vetoed(reason) => write!(f, "vetoed: {reason}"),
+            SkipReason::Unsafe(reason) => write!(f, "unsafe: {reason}"),
+            SkipReason::Failed(error) => write!(f, "failed: {error}"),
+        }
+    }
+}
+
+/// An entry that was not stored or restored, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedEntry {
+    /// The path of the entry, relative to the root of the target
+    pub path: String,
+    pub reason: SkipReason,
+}
+
+/// The number of entries skipped for each kind of reason
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SkipCounts {
+    pub excluded: usize,
+    pub unreadable: usize,
+    pub special_file: usize,
+    pub other_filesystem: usize,
+    pub marked: usize,
+    pub vetoed: usize,
+    pub unsafe_path: usize,
+    pub failed: usize,
+}
+
+impl SkipCounts {
+    /// Counts up the reasons for a list of skipped entries
+    pub fn tally<'a>(entries: impl IntoIterator<Item = &'a SkippedEntry>) -> SkipCounts {
+        let mut counts = SkipCounts::default();
+        for entry in entries {
+            match entry.reason {
+                SkipReason::Excluded(_) => counts.excluded += 1,
+                SkipReason::Unreadable(_) => counts.unreadable += 1,
+                SkipReason::SpecialFile => counts.special_file += 1,
+                SkipReason::OtherFilesystem => counts.other_filesystem += 1,
+                SkipReason::Marked(_) => counts.marked += 1,
+                SkipReason::Vetoed(_) => counts.vetoed += 1,
+                SkipReason::Unsafe(_) => counts.unsafe_path += 1,
+                SkipReason::Failed(_) => counts.failed += 1,
+            }
+        }
+        counts
+    }
+
+    /// Returns the total number of skipped entries
+    pub fn total(&self) -> usize {
+        self.excluded
+            + self.unreadable
+            + self.special_file
+            + self.other_filesystem
+            + self.marked
+            + self.vetoed
+            + self.unsafe_path
+            + self.failed
+    }
+}
 
 /// Representation of a `Read`/`Write` for an object, and the range of bytes within
 /// that object it is responsible for
@@ -68,6 +165,24 @@ impl<T: Read> BackupObject<T> {
             object: read,
         });
     }
+
+    /// Wraps each of the `Read`s in this object with the provided function, keeping
+    /// their ranges intact
+    pub fn map_readers<U: Read>(self, mut f: impl FnMut(T) -> U) -> BackupObject<U> {
+        let ranges = self
+            .ranges
+            .into_iter()
+            .map(|range| ByteRange {
+                start: range.start,
+                end: range.end,
+                object: f(range.object),
+            })
+            .collect();
+        BackupObject {
+            ranges,
+            total_size: self.total_size,
+        }
+    }
 }
 
 /// A collection of `Write`s and their associated byte ranges with in an object to
@@ -147,11 +262,30 @@ pub trait BackupTarget<T: Read>: Clone + Send + Sync {
     /// Additional pieces of metatdata, such as filesystem permissions
     /// should be stored in a namespace roughly matching the path of the
     /// datastructure that represents them, e.g. filesystem:permissions:
-    async fn backup_object(&self, node: Node) -> HashMap<String, BackupObject<T>>;
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the object can not be opened, such as if it was removed or
+    /// made unreadable after it was listed. The object is then not recorded in the
+    /// target's listing.
+    async fn backup_object(&self, node: Node) -> io::Result<HashMap<String, BackupObject<T>>>;
 
     /// Returns a serialized listing that should be stored in an archive at
     /// archive:listing
     async fn backup_listing(&self) -> Listing;
+
+    /// Returns the entries that were left out of the listing produced by the last call
+    /// to `backup_paths`, along with the reason each one was left out
+    ///
+    /// The default implementation, for targets that never leave anything out, returns
+    /// nothing.
+    #[allow(clippy::unused_async)]
+    async fn skipped_paths(&self) -> Vec<SkippedEntry>
+    where
+        T: 'async_trait,
+    {
+        Vec::new()
+    }
 }
 
 /// Collection of methods that a restore target has to implement in order for a
@@ -174,4 +308,30 @@ pub trait RestoreTarget<T: Write>: Clone + Send + Sync {
     ///
     /// Returns a hashmap, keyed by namespace, of the various parts of this object
     async fn restore_object(&self, path: Node) -> HashMap<String, RestoreObject<T>>;
+
+    /// Called once the contents of every namespace of an object have been written
+    ///
+    /// Metadata that writing the contents would disturb, such as modification times,
+    /// should be restored here. The default implementation does nothing.
+    #[allow(
+        unused_variables,
+        clippy::unused_async,
+        clippy::multiple_bound_locations
+    )]
+    async fn finish_object(&self, node: Node)
+    where
+        T: 'async_trait,
+    {
+    }
+
+    /// Called once every object in the listing has been restored and finished
+    ///
+    /// Metadata of directories that restoring their contents would disturb should be
+    /// restored here. The default implementation does nothing.
+    #[allow(clippy::unused_async, clippy::multiple_bound_locations)]
+    async fn finish_restore(&self)
+    where
+        T: 'async_trait,
+    {
+    }
 }
@@ -0,0 +1,1389 @@
+#![allow(unused_variables)]
+use super::walk::{walk_parallel, WalkEvent};
+#[cfg(windows)]
+use super::windows;
+use super::{
+    BackupObject, BackupTarget, Listing, Node, NodeMetadata, NodeType, RestoreObject,
+    RestoreTarget, SkipReason, SkippedEntry, SpecialFile,
+};
+use crate::manifest::archive::Extent;
+use crate::manifest::driver::{BackupDriver, RestoreDriver};
+
+use async_trait::async_trait;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use piper::Lock;
+use smol::{blocking, Task};
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{self, create_dir_all, File, Metadata};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// The name of the file that marks a directory as a cache, see
+/// <https://bford.info/cachedir/>
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+/// The contents every valid `CACHEDIR.TAG` starts with
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+#[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)]
+/// A type that handles the complexities of dealing with a file system for you.
+pub struct FileSystemTarget {
+    root_directory: String,
+    listing: Arc<Lock<Listing>>,
+    /// The exclusion patterns, kept around so a skipped path can name what excluded it
+    exclude_patterns: Vec<String>,
+    excludes: GlobSet,
+    one_file_system: bool,
+    /// Whether directories marked with a valid `CACHEDIR.TAG` are left out
+    exclude_caches: bool,
+    /// Names of files that cause the directory containing them to be left out
+    exclude_if_present: Vec<String>,
+    /// Whether symbolic links are followed, rather than stored as links
+    dereference: bool,
+    skipped: Arc<Lock<Vec<SkippedEntry>>>,
+    /// Objects that restoring would have written outside of the root directory
+    refused: Arc<Lock<Vec<SkippedEntry>>>,
+    /// Whether every restored path is resolved against the root directory before writing
+    hardened: bool,
+    /// Number of threads used to walk the directory tree
+    walk_threads: usize,
+    /// Whether the alternate data streams of files are stored, only used on Windows
+    alternate_streams: bool,
+    /// Whether the contents of block devices are stored, rather than just the device node
+    read_special: bool,
+    /// Restored directories whose metadata is applied once everything in them is restored
+    unfinished_directories: Arc<Lock<Vec<Node>>>,
+    /// Stored objects waiting on the directory they are in to be added to the listing,
+    /// keyed by the path of that directory
+    orphans: Arc<Lock<HashMap<String, Vec<Node>>>>,
+}
+
+/// What walking a single entry produced
+enum Walked {
+    Node(Node),
+    Skipped(SkippedEntry),
+}
+
+impl FileSystemTarget {
+    /// Creates a new `FileSystemTarget` with the given path as its top level directory.
+    ///
+    /// The `FileSystemTarget` will consider all paths below this directory for backup.
+    pub fn new(root_directory: &str) -> FileSystemTarget {
+        FileSystemTarget {
+            root_directory: root_directory.to_string(),
+            listing: Arc::new(Lock::new(Listing::default())),
+            exclude_patterns: Vec::new(),
+            excludes: GlobSet::empty(),
+            one_file_system: false,
+            exclude_caches: false,
+            exclude_if_present: Vec::new(),
+            dereference: false,
+            skipped: Arc::new(Lock::new(Vec::new())),
+            refused: Arc::new(Lock::new(Vec::new())),
+            hardened: false,
+            walk_threads: num_cpus::get(),
+            alternate_streams: false,
+            read_special: false,
+            unfinished_directories: Arc::new(Lock::new(Vec::new())),
+            orphans: Arc::new(Lock::new(HashMap::new())),
+        }
+    }
+
+    pub fn set_root_directory(&mut self, new_root: &str) {
+        self.root_directory = new_root.to_string();
+    }
+
+    /// Adds a stored object to the backup listing
+    ///
+    /// Objects are stored concurrently, so one can finish before the directory it is in
+    /// has been added. Those are held back until their directory is added, rather than
+    /// being left out of the listing.
+    async fn add_to_listing(&self, parent_path: &str, node: Node) {
+        let mut listing = self.listing.lock().await;
+        let mut orphans = self.orphans.lock().await;
+        if !parent_path.is_empty() && listing.get(parent_path).is_none() {
+            orphans
+                .entry(parent_path.to_string())
+                .or_default()
+                .push(node);
+            return;
+        }
+        let mut ready = vec![(parent_path.to_string(), node)];
+        while let Some((parent_path, node)) = ready.pop() {
+            for child in orphans.remove(&node.path).into_iter().flatten() {
+                ready.push((node.path.clone(), child));
+            }
+            listing.add_child(&parent_path, node);
+        }
+    }
+
+    /// Leaves any path, relative to the root directory, that matches one of the provided
+    /// globs out of the backup. Directories that match are not descended into.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the patterns is not a valid glob
+    pub fn set_excludes(&mut self, patterns: &[String]) -> Result<(), globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern)?);
+        }
+        self.excludes = builder.build()?;
+        self.exclude_patterns = patterns.to_vec();
+        Ok(())
+    }
+
+    /// Sets whether directories on a different filesystem than the root directory are left
+    /// out of the backup
+    ///
+    /// Only has an effect on unix-like platforms.
+    pub fn set_one_file_system(&mut self, one_file_system: bool) {
+        self.one_file_system = one_file_system;
+    }
+
+    /// Sets whether directories containing a `CACHEDIR.TAG` file are left out of the
+    /// backup
+    ///
+    /// Only tags starting with the signature required by the Cache Directory Tagging
+    /// Specification count, so a file that just happens to have the same name does not
+    /// cause anything to be left out.
+    pub fn set_exclude_caches(&mut self, exclude_caches: bool) {
+        self.exclude_caches = exclude_caches;
+    }
+
+    /// Leaves any directory containing a file, or other entry, with one of the provided
+    /// names out of the backup
+    pub fn set_exclude_if_present(&mut self, names: &[String]) {
+        self.exclude_if_present = names.to_vec();
+    }
+
+    /// Returns the name of the marker that causes the directory to be left out, if any
+    fn marked_by(&self, directory: &Path) -> Option<String> {
+        if let Some(name) = self
+            .exclude_if_present
+            .iter()
+            .find(|name| directory.join(name).symlink_metadata().is_ok())
+        {
+            return Some(name.clone());
+        }
+        if self.exclude_caches && is_cache_tag(&directory.join(CACHEDIR_TAG)) {
+            return Some(CACHEDIR_TAG.to_string());
+        }
+        None
+    }
+
+    /// Sets whether symbolic links are followed, storing what they point to, rather than
+    /// being stored as links
+    ///
+    /// Links that lead back to a directory they are in are always stored as links, as
+    /// following them would never end.
+    pub fn set_dereference(&mut self, dereference: bool) {
+        self.dereference = dereference;
+    }
+
+    /// Sets the number of threads used to walk the directory tree, defaulting to the number
+    /// of CPUs
+    pub fn set_walk_threads(&mut self, walk_threads: usize) {
+        self.walk_threads = walk_threads.max(1);
+    }
+
+    /// Sets whether the alternate data streams of files are stored alongside their contents
+    ///
+    /// Only has an effect on Windows. Streams are always restored if they were stored.
+    pub fn set_alternate_streams(&mut self, alternate_streams: bool) {
+        self.alternate_streams = alternate_streams;
+    }
+
+    /// Sets whether the contents of block devices, such as the snapshot of a logical volume,
+    /// are read and stored as if they were regular files
+    ///
+    /// Otherwise, named pipes and device nodes are stored as special files, recording only
+    /// what they are, and recreated as such when restored. Named pipes and character
+    /// devices are never read, as there is no telling how much, if anything, they will
+    /// produce. Sockets are always left out.
+    pub fn set_read_special(&mut self, read_special: bool) {
+        self.read_special = read_special;
+    }
+
+    /// Sets whether restoring is hardened against archives crafted to write outside of the
+    /// root directory
+    ///
+    /// When hardened, the path every object is restored to is canonicalized, and refused if
+    /// it does not resolve to somewhere below the canonical root directory. On Linux, files
+    /// are also opened with `openat2`, relative to the root directory, so that the kernel
+    /// refuses to resolve them through a symbolic link or outside of the root directory,
+    /// even if the directory tree is changed by someone else during the restore.
+    pub fn set_hardened(&mut self, hardened: bool) {
+        self.hardened = hardened;
+    }
+
+    /// Returns the objects `restore_object` refused to restore, and why
+    ///
+    /// An object is always refused if its path is absolute or contains `..`, or if any
+    /// directory it is in, below the root directory, is a symbolic link, as writing through
+    /// the link could overwrite files outside of the root directory. Hardened restores
+    /// refuse more, see `set_hardened`.
+    pub async fn refused_paths(&self) -> Vec<SkippedEntry> {
+        self.refused.lock().await.clone()
+    }
+
+    /// Returns the first exclusion pattern matching the path, if any
+    fn excluded_by(&self, path: &str) -> Option<String> {
+        self.excludes
+            .matches(path)
+            .first()
+            .map(|&index| self.exclude_patterns[index].clone())
+    }
+
+    /// Converts a path on the filesystem into one relative to the root directory
+    fn relative_path(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root_directory)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Decides if a walked entry should be left out of the listing, returning its
+    /// metadata if it should be kept
+    ///
+    /// `root` is the metadata of the root directory, only provided if directories on other
+    /// filesystems should be left out.
+    fn check_entry(
+        &self,
+        path: &str,
+        metadata: io::Result<Metadata>,
+        root: Option<&Metadata>,
+    ) -> Result<Metadata, SkipReason> {
+        if let Some(pattern) = self.excluded_by(path) {
+            return Err(SkipReason::Excluded(pattern));
+        }
+        let metadata = metadata.map_err(|e| SkipReason::Unreadable(e.to_string()))?;
+        if !metadata.is_file() && !metadata.is_dir() && special_file(&metadata).is_none() {
+            return Err(SkipReason::SpecialFile);
+        }
+        if let Some(root) = root {
+            if metadata.is_dir() && !same_filesystem(&metadata, root) {
+                return Err(SkipReason::OtherFilesystem);
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// Turns something the walker found into a node or a skipped entry, returning whether
+    /// it should be descended into if it is a directory
+    ///
+    /// This is called from the walker's threads.
+    fn visit(&self, event: WalkEvent, root: Option<&Metadata>) -> (Option<Walked>, bool) {
+        let (full_path, metadata) = match event {
+            WalkEvent::Entry { path, .. } => {
+                let metadata = path.symlink_metadata();
+                (path, metadata)
+            }
+            WalkEvent::Error { path, error } => {
+                let skipped = SkippedEntry {
+                    path: self.relative_path(&path),
+                    reason: SkipReason::Unreadable(error.to_string()),
+                };
+                return (Some(Walked::Skipped(skipped)), false);
+            }
+        };
+        let path = full_path
+            .strip_prefix(&self.root_directory)
+            .expect("Failed getting realtive path in file system target")
+            .to_str()
+            .expect("Path contained non-utf8")
+            .to_string();
+        let metadata = match metadata {
+            Ok(link) if link.file_type().is_symlink() => {
+                if !self.dereference || self.link_loops(&full_path) {
+                    return (Some(self.visit_link(&full_path, path, &link)), false);
+                }
+                full_path.metadata()
+            }
+            metadata => metadata,
+        };
+        let metadata = match self.check_entry(&path, metadata, root) {
+            Ok(metadata) => metadata,
+            // Nothing below a skipped directory is considered
+            Err(reason) => return (Some(Walked::Skipped(SkippedEntry { path, reason })), false),
+        };
+        if metadata.is_dir() {
+            if let Some(marker) = self.marked_by(&full_path) {
+                let reason = SkipReason::Marked(marker);
+                return (Some(Walked::Skipped(SkippedEntry { path, reason })), false);
+            }
+        }
+        let node_metadata = match capture_metadata(&full_path, &metadata, self.alternate_streams) {
+            Ok(node_metadata) => node_metadata,
+            Err(error) => {
+                let reason = SkipReason::Unreadable(error.to_string());
+                return (Some(Walked::Skipped(SkippedEntry { path, reason })), false);
+            }
+        };
+
+        let (node_type, length) = match special_file(&metadata) {
+            Some(SpecialFile::BlockDevice { .. }) if self.read_special => {
+                match device_length(&full_path) {
+                    Ok(length) => (NodeType::File, length),
+                    Err(error) => {
+                        let reason = SkipReason::Unreadable(error.to_string());
+                        return (Some(Walked::Skipped(SkippedEntry { path, reason })), false);
+                    }
+                }
+            }
+            Some(kind) => (NodeType::Special { kind }, 0),
+            None if metadata.is_file() => (NodeType::File, metadata.len()),
+            None => (
+                NodeType::Directory {
+                    children: Vec::new(),
+                },
+                metadata.len(),
+            ),
+        };
+
+        let extents = if node_type == NodeType::File && length > 0 {
+            Some(vec![Extent {
+                start: 0,
+                end: length - 1,
+            }])
+        } else {
+            None
+        };
+
+        let node = Node {
+            path,
+            total_length: length,
+            total_size: length,
+            extents,
+            node_type,
+            metadata: node_metadata,
+            hash: None,
+        };
+        (Some(Walked::Node(node)), metadata.is_dir())
+    }
+
+    /// Turns a symbolic link, or on Windows a junction, into a link node
+    fn visit_link(&self, full_path: &Path, path: String, metadata: &Metadata) -> Walked {
+        if let Some(pattern) = self.excluded_by(&path) {
+            let reason = SkipReason::Excluded(pattern);
+            return Walked::Skipped(SkippedEntry { path, reason });
+        }
+        match capture_link(full_path, metadata) {
+            Ok(metadata) => Walked::Node(Node {
+                path,
+                total_length: 0,
+                total_size: 0,
+                extents: None,
+                node_type: NodeType::Link,
+                metadata,
+                hash: None,
+            }),
+            Err(error) => {
+                let reason = SkipReason::Unreadable(error.to_string());
+                Walked::Skipped(SkippedEntry { path, reason })
+            }
+        }
+    }
+
+    /// Returns true if following the symbolic link at `path` would lead back to a directory
+    /// it is in
+    fn link_loops(&self, path: &Path) -> bool {
+        let root = Path::new(&self.root_directory);
+        fs::canonicalize(path).is_ok_and(|target| {
+            path.ancestors()
+                .skip(1)
+                .take_while(|ancestor| ancestor.starts_with(root))
+                .filter_map(|ancestor| fs::canonicalize(ancestor).ok())
+                .any(|ancestor| ancestor.starts_with(&target))
+        })
+    }
+
+    /// Returns the path on the filesystem a node is restored to, or why it can not be
+    fn restore_path(&self, node: &Node) -> Result<PathBuf, SkipReason> {
+        let root_path = Path::new(&self.root_directory);
+        // Joining an absolute path would replace the root directory entirely
+        let relative = Path::new(&node.path);
+        let escapes = relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+        if escapes {
+            let reason = "path is absolute or contains ..".to_string();
+            return Err(SkipReason::Unsafe(reason));
+        }
+        let path = root_path.join(relative);
+        let linked = path
+            .ancestors()
+            .skip(1)
+            .take_while(|ancestor| *ancestor != root_path && ancestor.starts_with(root_path))
+            .find(|ancestor| is_symlink(ancestor));
+        match linked {
+            Some(link) => {
+                let link = link.strip_prefix(root_path).unwrap_or(link);
+                let reason = format!("{} is a symbolic link", link.display());
+                Err(SkipReason::Unsafe(reason))
+            }
+            None if self.hardened => self.check_resolved(path),
+            None => Ok(path),
+        }
+    }
+
+    /// Makes sure as much of `path` as exists resolves to somewhere below the root directory
+    fn check_resolved(&self, path: PathBuf) -> Result<PathBuf, SkipReason> {
+        let unsafe_reason = |reason: &str| SkipReason::Unsafe(reason.to_string());
+        // The root directory is only created along with the first object otherwise
+        let _ = create_dir_all(&self.root_directory);
+        let root = fs::canonicalize(&self.root_directory)
+            .map_err(|_| unsafe_reason("target directory can not be resolved"))?;
+        let existing = path
+            .ancestors()
+            .find(|ancestor| ancestor.symlink_metadata().is_ok())
+            .ok_or_else(|| unsafe_reason("path can not be resolved"))?;
+        let resolved =
+            fs::canonicalize(existing).map_err(|_| unsafe_reason("path can not be resolved"))?;
+        if resolved.starts_with(&root) {
+            Ok(path)
+        } else {
+            Err(unsafe_reason("path resolves outside of the target"))
+        }
+    }
+
+    /// Creates, or truncates, a file to restore into
+    ///
+    /// Hardened restores on Linux open the file relative to the root directory with
+    /// `openat2`, falling back to a regular open on kernels too old to support it.
+    fn create_file(&self, path: &Path) -> io::Result<File> {
+        #[cfg(target_os = "linux")]
+        {
+            if self.hardened {
+                let relative = path
+                    .strip_prefix(&self.root_directory)
+                    .expect("Restore path outside of the root directory");
+                match open_beneath(Path::new(&self.root_directory), relative) {
+                    Err(error) if error.raw_os_error() == Some(libc::ENOSYS) => {}
+                    result => return result,
+                }
+            }
+        }
+        File::create(path)
+    }
+}
+
+/// Returns true if there is a symbolic link at `path`
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+/// Removes the symbolic link at `path`, if there is one, so that restoring an object
+/// replaces it, rather than writing through it
+fn remove_symlink(path: &Path) {
+    if is_symlink(path) {
+        // Links to directories are directories themselves on Windows
+        let _ = fs::remove_file(path).or_else(|_| fs::remove_dir(path));
+    }
+}
+
+/// Captures the target of a symbolic link, along with any platform specific metadata of the
+/// link itself
+fn capture_link(path: &Path, metadata: &Metadata) -> io::Result<NodeMetadata> {
+    let target = fs::read_link(path)?;
+    let target = target
+        .to_str()
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Link target contained non-utf8")
+        })?
+        .to_string();
+    #[cfg(windows)]
+    let windows = Some(windows::capture(path, metadata, false)?);
+    #[cfg(not(windows))]
+    let windows = None;
+    Ok(NodeMetadata {
+        windows,
+        link_target: Some(target),
+    })
+}
+
+/// Creates, or truncates, the file at `relative` for writing, letting the kernel refuse to
+/// resolve it through any symbolic link, or to anywhere outside of `root`
+#[cfg(target_os = "linux")]
+#[allow(clippy::cast_sign_loss)]
+fn open_beneath(root: &Path, relative: &Path) -> io::Result<File> {
+    use std::convert::TryFrom;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let root = File::open(root)?;
+    let relative = CString::new(relative.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path contained a null"))?;
+    // open_how is non-exhaustive, so it has to start out zeroed
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC) as u64;
+    how.mode = 0o666;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS | libc::RESOLVE_NO_MAGICLINKS;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.as_raw_fd(),
+            relative.as_ptr(),
+            std::ptr::addr_of!(how),
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = i32::try_from(fd).expect("File descriptor out of range");
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Returns what kind of special file the metadata describes, if it is a named pipe or a
+/// device node
+#[cfg(target_os = "linux")]
+fn special_file(metadata: &Metadata) -> Option<SpecialFile> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let file_type = metadata.file_type();
+    let (major, minor) = (libc::major(metadata.rdev()), libc::minor(metadata.rdev()));
+    if file_type.is_fifo() {
+        Some(SpecialFile::Fifo)
+    } else if file_type.is_char_device() {
+        Some(SpecialFile::CharDevice { major, minor })
+    } else if file_type.is_block_device() {
+        Some(SpecialFile::BlockDevice { major, minor })
+    } else {
+        None
+    }
+}
+
+/// Device numbers are encoded differently on every platform, so only named pipes are
+/// recognized outside of Linux
+#[cfg(not(target_os = "linux"))]
+fn special_file(metadata: &Metadata) -> Option<SpecialFile> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if metadata.file_type().is_fifo() {
+            return Some(SpecialFile::Fifo);
+        }
+    }
+    None
+}
+
+/// Returns the size of the device at `path`, which its metadata does not report
+fn device_length(path: &Path) -> io::Result<u64> {
+    use std::io::{Seek, SeekFrom};
+    File::open(path)?.seek(SeekFrom::End(0))
+}
+
+/// Creates a named pipe or device node at `path`
+///
+/// Creating device nodes usually requires root.
+#[cfg(target_os = "linux")]
+fn create_special(path: &Path, kind: SpecialFile) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path contained a null"))?;
+    let (mode, device) = match kind {
+        SpecialFile::Fifo => (libc::S_IFIFO | 0o666, 0),
+        SpecialFile::CharDevice { major, minor } => {
+            (libc::S_IFCHR | 0o600, libc::makedev(major, minor))
+        }
+        SpecialFile::BlockDevice { major, minor } => {
+            (libc::S_IFBLK | 0o600, libc::makedev(major, minor))
+        }
+    };
+    if unsafe { libc::mknod(path.as_ptr(), mode, device) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create_special(path: &Path, kind: SpecialFile) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Special files can only be restored on Linux",
+    ))
+}
+
+/// Creates a symbolic link to `target` at `path`
+#[cfg(unix)]
+fn create_symlink(path: &Path, target: &str) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+/// Captures the platform specific metadata of an object, `metadata` being its metadata as
+/// found by the walker
+#[cfg(windows)]
+fn capture_metadata(path: &Path, metadata: &Metadata, streams: bool) -> io::Result<NodeMetadata> {
+    Ok(NodeMetadata {
+        windows: Some(windows::capture(path, metadata, streams)?),
+        ..NodeMetadata::default()
+    })
+}
+
+#[cfg(not(windows))]
+#[allow(clippy::unnecessary_wraps)]
+fn capture_metadata(path: &Path, metadata: &Metadata, streams: bool) -> io::Result<NodeMetadata> {
+    Ok(NodeMetadata::default())
+}
+
+/// Restores the platform specific metadata of an object, once its contents are restored
+#[cfg(windows)]
+fn restore_metadata(path: &Path, metadata: &NodeMetadata) {
+    if let Some(windows) = &metadata.windows {
+        if let Err(error) = windows::apply(path, windows) {
+            tracing::warn!(
+                "Unable to restore metadata of {}: {}",
+                path.display(),
+                error
+            );
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn restore_metadata(path: &Path, metadata: &NodeMetadata) {}
+
+/// Adds a writer for each alternate data stream of a restored file, creating the empty
+/// streams outright
+#[cfg(windows)]
+fn restore_streams(path: &Path, node: &Node, output: &mut HashMap<String, RestoreObject<File>>) {
+    for stream in node.metadata.windows.iter().flat_map(|x| &x.streams) {
+        let file = File::create(windows::stream_path(path, stream)).expect("Unable to open stream");
+        if stream.length > 0 {
+            let mut stream_object = RestoreObject::new(stream.length);
+            stream_object.direct_add_range(0, stream.length - 1, file);
+            output.insert(stream.namespace(), stream_object);
+        }
+    }
+}
+
+/// Checks if the file at `path` is a `CACHEDIR.TAG` with a valid signature
+fn is_cache_tag(path: &Path) -> bool {
+    use std::io::Read;
+    let mut signature = [0_u8; CACHEDIR_SIGNATURE.len()];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut signature))
+        .is_ok()
+        && signature[..] == *CACHEDIR_SIGNATURE
+}
+
+/// Checks if the files described by the two pieces of metadata live on the same device
+#[cfg(unix)]
+fn same_filesystem(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev()
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(a: &Metadata, b: &Metadata) -> bool {
+    true
+}
+
+#[async_trait]
+impl BackupTarget<File> for FileSystemTarget {
+    async fn backup_paths(&self) -> Listing {
+        let mut listing = Listing::default();
+        let mut skipped = Vec::new();
+        let root_metadata = if self.one_file_system {
+            let root = self.root_directory.clone();
+            blocking!(Path::new(&root).metadata()).ok()
+        } else {
+            None
+        };
+        // Walk the tree in parallel, then sort what was found, so the listing comes out
+        // the same no matter which thread got to what first. Sorting by path components
+        // also puts every directory ahead of its contents.
+        let target = self.clone();
+        let root = Path::new(&self.root_directory).to_owned();
+        let threads = self.walk_threads;
+        let walked = blocking!(walk_parallel(&root, threads, move |event| {
+            target.visit(event, root_metadata.as_ref())
+        }));
+        let mut nodes = Vec::new();
+        for walked in walked {
+            match walked {
+                Walked::Node(node) => nodes.push(node),
+                Walked::Skipped(entry) => skipped.push(entry),
+            }
+        }
+        nodes.sort_by(|a, b| Path::new(&a.path).cmp(Path::new(&b.path)));
+        skipped.sort_by(|a, b| Path::new(&a.path).cmp(Path::new(&b.path)));
+        for node in nodes {
+            let parent_path = Path::new(&node.path)
+                .parent()
+                .expect("Failed getting parent path in filesystem target")
+                .to_str()
+                .expect("Path contained non-utf8")
+                .to_string();
+            listing.add_child(&parent_path, node);
+        }
+        *self.skipped.lock().await = skipped;
+        listing
+    }
+    async fn backup_object(&self, node: Node) -> io::Result<HashMap<String, BackupObject<File>>> {
+        let mut output = HashMap::new();
+        // FIXME: Store directory metatdata
+        if node.is_file() {
+            // Get the actual path on the filesystem this referes to
+            let root_path = Path::new(&self.root_directory);
+            let path = root_path.join(&node.path);
+            // Construct the file_object based on the information in the node
+            let mut file_object = BackupObject::new(node.total_length);
+            // add each extent from the node to the object
+            if let Some(extents) = node.extents.as_ref() {
+                for extent in extents {
+                    let file = {
+                        let path = path.clone();
+
+                        blocking!(File::open(&path))?
+                    };
+                    file_object.direct_add_range(extent.start, extent.end, file);
+                }
+            }
+            output.insert(String::new(), file_object);
+            // Empty streams are recreated from the listing alone
+            #[cfg(windows)]
+            for stream in node.metadata.windows.iter().flat_map(|x| &x.streams) {
+                if stream.length > 0 {
+                    let path = windows::stream_path(&path, stream);
+                    let mut stream_object = BackupObject::new(stream.length);
+                    let file = blocking!(File::open(&path))?;
+                    stream_object.direct_add_range(0, stream.length - 1, file);
+                    output.insert(stream.namespace(), stream_object);
+                }
+            }
+        }
+        let path = node.path.clone();
+        let parent_path = Path::new(&path)
+            .parent()
+            .expect("Unable to get parent path")
+            .to_str()
+            .expect("Invalid utf-8 in path");
+        self.add_to_listing(parent_path, node).await;
+        Ok(output)
+    }
+    async fn backup_listing(&self) -> Listing {
+        self.listing.lock().await.clone()
+    }
+    async fn skipped_paths(&self) -> Vec<SkippedEntry> {
+        self.skipped.lock().await.clone()
+    }
+}
+
+#[async_trait]
+impl RestoreTarget<File> for FileSystemTarget {
+    async fn load_listing(root_path: &str, listing: Listing) -> Self {
+        FileSystemTarget {
+            listing: Arc::new(Lock::new(listing)),
+            ..FileSystemTarget::new(root_path)
+        }
+    }
+    async fn restore_object(&self, node: Node) -> HashMap<String, RestoreObject<File>> {
+        let mut output = HashMap::new();
+        // Get the actual path on the filesystem this refers to, never writing through a
+        // symbolic link, be it one restored from the archive or one already in the target
+        let path = match self.restore_path(&node) {
+            Ok(path) => path,
+            Err(reason) => {
+                let path = node.path.clone();
+                self.refused
+                    .lock()
+                    .await
+                    .push(SkippedEntry { path, reason });
+                return output;
+            }
+        };
+        remove_symlink(&path);
+        #[cfg(windows)]
+        {
+            let windows = node.metadata.windows.as_ref();
+            if let Some(reparse_point) = windows.and_then(|x| x.reparse_point.clone()) {
+                blocking!({
+                    let parent = path.parent().expect("Unable to get parent(restore_object)");
+                    create_dir_all(parent).expect("Unable to create parent (restore_object)");
+                    if let Err(error) = windows::create_link(&path, &reparse_point) {
+                        tracing::warn!("Unable to create link {}: {}", path.display(), error);
+                    }
+                });
+                return output;
+            }
+        }
+        #[cfg(unix)]
+        {
+            if let Some(target) = node.metadata.link_target.clone() {
+                blocking!({
+                    let parent = path.parent().expect("Unable to get parent(restore_object)");
+                    create_dir_all(parent).expect("Unable to create parent (restore_object)");
+                    // Links replace files in their way, just like restored files do
+                    if path.symlink_metadata().is_ok_and(|x| x.is_file()) {
+                        let _ = fs::remove_file(&path);
+                    }
+                    if let Err(error) = create_symlink(&path, &target) {
+                        tracing::warn!("Unable to create link {}: {}", path.display(), error);
+                    }
+                });
+                return output;
+            }
+        }
+        if let NodeType::Special { kind } = node.node_type {
+            blocking!({
+                let parent = path.parent().expect("Unable to get parent(restore_object)");
+                create_dir_all(parent).expect("Unable to create parent (restore_object)");
+                // Special files replace files in their way, just like restored files do
+                if path.symlink_metadata().is_ok_and(|x| !x.is_dir()) {
+                    let _ = fs::remove_file(&path);
+                }
+                if let Err(error) = create_special(&path, kind) {
+                    tracing::warn!(
+                        "Unable to create special file {}: {}",
+                        path.display(),
+                        error
+                    );
+                }
+            });
+            return output;
+        }
+        if node.is_directory() {
+            // If the node is a directory, just create it
+            let path = path.to_owned();
+            Task::blocking(async move {
+                create_dir_all(path).expect("Unable to create directory (restore_object)")
+            })
+            .await;
+            output
+        } else {
+            // Get the parent directory, and create it if it does not exist
+            let parent_path = path
+                .parent()
+                .expect("Unable to get parent(restore_object)")
+                .to_owned();
+            Task::blocking(async move {
+                create_dir_all(parent_path).expect("Unable to create parent (restore_object)")
+            })
+            .await;
+            // Open the file up front, so that a hardened restore can refuse it cleanly, this
+            // also takes care of creating files without any extents
+            let file = match self.create_file(&path) {
+                Ok(file) => file,
+                Err(error) if self.hardened => {
+                    let reason = SkipReason::Unsafe(format!("unable to open safely: {error}"));
+                    let path = node.path.clone();
+                    self.refused
+                        .lock()
+                        .await
+                        .push(SkippedEntry { path, reason });
+                    return output;
+                }
+                Err(error) => panic!("Unable to open file: {}", error),
+            };
+            if let Some(extents) = node.extents.as_ref().filter(|x| !x.is_empty()) {
+                // Each extent gets its own handle, so they can seek independently
+                let mut files = std::iter::once(file).chain(std::iter::repeat_with(|| {
+                    self.create_file(&path).expect("Unable to open file")
+                }));
+                let mut file_object = RestoreObject::new(node.total_length);
+                for extent in extents {
+                    // This unwrap is sound, as the iterator never ends
+                    file_object.direct_add_range(extent.start, extent.end, files.next().unwrap());
+                }
+                output.insert(String::new(), file_object);
+            }
+            #[cfg(windows)]
+            restore_streams(&path, &node, &mut output);
+            output
+        }
+    }
+    async fn finish_object(&self, node: Node) {
+        if node.metadata == NodeMetadata::default() {
+            return;
+        }
+        if node.is_directory() {
+            // Restoring the directory's contents would disturb its metadata
+            self.unfinished_directories.lock().await.push(node);
+        } else if let Ok(path) = self.restore_path(&node) {
+            blocking!(restore_metadata(&path, &node.metadata));
+        }
+    }
+    async fn finish_restore(&self) {
+        let mut directories = std::mem::take(&mut *self.unfinished_directories.lock().await);
+        // Deepest first, so finishing a directory does not disturb its parent
+        directories.sort_by_key(|node| Reverse(Path::new(&node.path).components().count()));
+        let directories = directories
+            .into_iter()
+            .filter_map(|node| Some((self.restore_path(&node).ok()?, node.metadata)))
+            .collect::<Vec<_>>();
+        blocking!({
+            for (path, metadata) in directories {
+                restore_metadata(&path, &metadata);
+            }
+        });
+    }
+    async fn restore_listing(&self) -> Listing {
+        self.listing.lock().await.clone()
+    }
+}
+
+impl BackupDriver<File> for FileSystemTarget {}
+impl RestoreDriver<File> for FileSystemTarget {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dir_diff;
+    use std::fs::{create_dir, File};
+    use tempfile::{tempdir, TempDir};
+
+    fn make_test_directory() -> TempDir {
+        let root = tempdir().unwrap();
+        let root_path = root.path();
+
+        create_dir(root_path.join("A")).unwrap();
+        create_dir(root_path.join("B")).unwrap();
+        create_dir(root_path.join("B").join("C")).unwrap();
+
+        File::create(root_path.join("1")).unwrap();
+        File::create(root_path.join("2")).unwrap();
+        File::create(root_path.join("3")).unwrap();
+        File::create(root_path.join("A").join("4")).unwrap();
+        File::create(root_path.join("B").join("5")).unwrap();
+        File::create(root_path.join("B").join("C").join("6")).unwrap();
+
+        root
+    }
+
+    #[test]
+    fn backup_restore_structure() {
+        smol::run(async {
+            let input_dir = make_test_directory();
+            let root_path = input_dir.path().to_owned();
+
+            let input_target = FileSystemTarget::new(&root_path.display().to_string());
+
+            let listing = input_target.backup_paths().await;
+            for node in listing {
+                println!("Backing up: {}", node.path);
+                input_target.backup_object(node).await.unwrap();
+            }
+
+            let listing = input_target.backup_listing().await;
+            println!("{:?}", listing);
+
+            let output_dir = tempdir().unwrap();
+
+            let output_target =
+                FileSystemTarget::load_listing(&output_dir.path().display().to_string(), listing)
+                    .await;
+
+            let output_listing = output_target.restore_listing().await;
+            for entry in output_listing {
+                println!("Restore listing:");
+                println!(" - {}", entry.path);
+                output_target.restore_object(entry).await;
+            }
+
+            let _input_path = input_dir.path().display().to_string();
+            let _output_path = output_dir.path().display().to_string();
+
+            assert!(!dir_diff::is_different(&input_dir.path(), &output_dir.path()).unwrap());
+        });
+    }
+
+    // Objects stored before the directory they are in must still end up in the listing
+    #[test]
+    fn backup_out_of_order() {
+        smol::run(async {
+            let input_dir = make_test_directory();
+            let root_path = input_dir.path().display().to_string();
+            let input_target = FileSystemTarget::new(&root_path);
+            let mut nodes = input_target
+                .backup_paths()
+                .await
+                .into_iter()
+                .collect::<Vec<_>>();
+            nodes.reverse();
+            for node in nodes {
+                input_target.backup_object(node).await.unwrap();
+            }
+            let listing = input_target.backup_listing().await;
+            let mut paths = listing.iter().map(|x| x.path.clone()).collect::<Vec<_>>();
+            paths.sort();
+            assert_eq!(
+                paths,
+                ["1", "2", "3", "A", "A/4", "B", "B/5", "B/C", "B/C/6"]
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn skipped_entries() {
+        smol::run(async {
+            let input_dir = make_test_directory();
+            let root_path = input_dir.path();
+            // A socket is a special file, and a dangling symlink can not be followed
+            let _socket = std::os::unix::net::UnixListener::bind(root_path.join("socket")).unwrap();
+            std::os::unix::fs::symlink(root_path.join("missing"), root_path.join("dangling"))
+                .unwrap();
+
+            let mut input_target = FileSystemTarget::new(&root_path.display().to_string());
+            input_target
+                .set_excludes(&["B".to_string(), "*3".to_string()])
+                .unwrap();
+            input_target.set_dereference(true);
+            let paths: Vec<String> = input_target
+                .backup_paths()
+                .await
+                .into_iter()
+                .map(|x| x.path)
+                .collect();
+            for path in &["B", "B/5", "B/C", "3", "socket", "dangling"] {
+                assert!(!paths.contains(&path.to_string()), "{} was listed", path);
+            }
+            assert!(paths.contains(&"A/4".to_string()));
+
+            let mut skipped = input_target.skipped_paths().await;
+            skipped.sort_by(|a, b| a.path.cmp(&b.path));
+            let summary: Vec<(&str, SkipReason)> = skipped
+                .iter()
+                .map(|x| {
+                    let reason = match &x.reason {
+                        SkipReason::Unreadable(_) => SkipReason::Unreadable(String::new()),
+                        reason => reason.clone(),
+                    };
+                    (x.path.as_str(), reason)
+                })
+                .collect();
+            assert_eq!(
+                summary,
+                vec![
+                    ("3", SkipReason::Excluded("*3".to_string())),
+                    ("B", SkipReason::Excluded("B".to_string())),
+                    ("dangling", SkipReason::Unreadable(String::new())),
+                    ("socket", SkipReason::SpecialFile),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn special_files() {
+        use std::ffi::CString;
+        use std::os::unix::fs::FileTypeExt;
+        smol::run(async {
+            let input_dir = make_test_directory();
+            let root_path = input_dir.path();
+            let fifo = CString::new(root_path.join("fifo").to_str().unwrap()).unwrap();
+            assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+            // Reading the pipe would block forever, as nothing ever writes to it
+            let mut input_target = FileSystemTarget::new(&root_path.display().to_string());
+            input_target.set_read_special(true);
+            let listing = input_target.backup_paths().await;
+            let node = listing.iter().find(|x| x.path == "fifo").unwrap().clone();
+            assert_eq!(
+                node.node_type,
+                NodeType::Special {
+                    kind: SpecialFile::Fifo
+                }
+            );
+            for node in listing {
+                let objects = input_target.backup_object(node.clone()).await.unwrap();
+                assert_eq!(objects.is_empty(), !node.is_file());
+            }
+
+            let output_dir = tempdir().unwrap();
+            let output_target = FileSystemTarget::load_listing(
+                &output_dir.path().display().to_string(),
+                input_target.backup_listing().await,
+            )
+            .await;
+            for node in output_target.restore_listing().await {
+                output_target.restore_object(node).await;
+            }
+            let restored = output_dir.path().join("fifo").symlink_metadata().unwrap();
+            assert!(restored.file_type().is_fifo());
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn marked_directories() {
+        smol::run(async {
+            let input_dir = make_test_directory();
+            let root_path = input_dir.path();
+            std::fs::write(
+                root_path.join("A").join(CACHEDIR_TAG),
+                b"Signature: 8a477f597d28d172789f06886806bc55\n# A cache\n",
+            )
+            .unwrap();
+            // Tags without the signature do not count
+            std::fs::write(root_path.join("B").join(CACHEDIR_TAG), b"Not a cache").unwrap();
+            std::fs::write(root_path.join("B").join("C").join(".nobackup"), b"").unwrap();
+
+            let mut input_target = FileSystemTarget::new(&root_path.display().to_string());
+            input_target.set_exclude_caches(true);
+            input_target.set_exclude_if_present(&[".nobackup".to_string()]);
+            let paths: Vec<String> = input_target
+                .backup_paths()
+                .await
+                .into_iter()
+                .map(|x| x.path)
+                .collect();
+            for path in &["A", "A/4", "B/C", "B/C/6"] {
+                assert!(!paths.contains(&path.to_string()), "{} was listed", path);
+            }
+            assert!(paths.contains(&"B/5".to_string()));
+
+            let mut skipped = input_target.skipped_paths().await;
+            skipped.sort_by(|a, b| a.path.cmp(&b.path));
+            assert_eq!(
+                skipped,
+                vec![
+                    SkippedEntry {
+                        path: "A".to_string(),
+                        reason: SkipReason::Marked(CACHEDIR_TAG.to_string()),
+                    },
+                    SkippedEntry {
+                        path: "B/C".to_string(),
+                        reason: SkipReason::Marked(".nobackup".to_string()),
+                    },
+                ]
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_metadata() {
+        use super::super::AlternateStream;
+        use std::io::{Read, Write};
+        use std::os::windows::fs::MetadataExt;
+        smol::run(async {
+            let input_dir = make_test_directory();
+            let root_path = input_dir.path();
+            std::fs::write(root_path.join("1:stream"), b"stream contents").unwrap();
+            let mut permissions = root_path.join("1").metadata().unwrap().permissions();
+            permissions.set_readonly(true);
+            std::fs::set_permissions(root_path.join("1"), permissions).unwrap();
+
+            let mut input_target = FileSystemTarget::new(&root_path.display().to_string());
+            input_target.set_alternate_streams(true);
+            let listing = input_target.backup_paths().await;
+            let node = listing.clone().into_iter().find(|x| x.path == "1").unwrap();
+            let windows = node.metadata.windows.clone().unwrap();
+            let stream = AlternateStream {
+                name: "stream".to_string(),
+                length: 15,
+            };
+            assert_eq!(windows.streams, vec![stream.clone()]);
+            assert_eq!(windows.attributes & 0x1, 0x1);
+            let mut objects = input_target.backup_object(node.clone()).await.unwrap();
+            let mut reader = objects
+                .remove(&stream.namespace())
+                .unwrap()
+                .ranges()
+                .remove(0)
+                .object;
+            let mut contents = Vec::new();
+            reader.read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, b"stream contents");
+
+            let output_dir = tempdir().unwrap();
+            let output_target =
+                FileSystemTarget::load_listing(&output_dir.path().display().to_string(), listing)
+                    .await;
+            let mut objects = output_target.restore_object(node.clone()).await;
+            let mut writer = objects
+                .remove(&stream.namespace())
+                .unwrap()
+                .ranges()
+                .remove(0)
+                .object;
+            writer.write_all(&contents).unwrap();
+            drop(writer);
+            output_target.finish_object(node).await;
+
+            let restored = output_dir.path().join("1");
+            let contents = std::fs::read(output_dir.path().join("1:stream")).unwrap();
+            assert_eq!(contents, b"stream contents");
+            let metadata = restored.metadata().unwrap();
+            assert_eq!(metadata.file_attributes() & 0x1, 0x1);
+            assert_eq!(metadata.last_write_time(), windows.last_write_time);
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinks() {
+        use std::os::unix::fs::symlink;
+        smol::run(async {
+            let input_dir = make_test_directory();
+            let root_path = input_dir.path();
+            symlink("B/C", root_path.join("link")).unwrap();
+            symlink(root_path.join("missing"), root_path.join("dangling")).unwrap();
+            // Following this one would lead back to B, forever
+            symlink("..", root_path.join("B").join("C").join("up")).unwrap();
+
+            // By default, links are stored as links, dangling or not
+            let target = FileSystemTarget::new(&root_path.display().to_string());
+            let listing = target.backup_paths().await;
+            let link = listing.iter().find(|x| x.path == "link").unwrap();
+            assert_eq!(link.node_type, NodeType::Link);
+            assert_eq!(link.metadata.link_target, Some("B/C".to_string()));
+            let dangling = listing.iter().find(|x| x.path == "dangling").unwrap();
+            assert_eq!(dangling.node_type, NodeType::Link);
+            assert!(!listing.iter().any(|x| x.path == "link/6"));
+
+            // Dereferencing follows links, but not ones that loop
+            let mut target = FileSystemTarget::new(&root_path.display().to_string());
+            target.set_dereference(true);
+            let listing = target.backup_paths().await;
+            assert!(listing.iter().any(|x| x.path == "link/6" && x.is_file()));
+            let up = listing.iter().find(|x| x.path == "B/C/up").unwrap();
+            assert_eq!(up.node_type, NodeType::Link);
+            let loops = listing
+                .iter()
+                .find(|x| x.path == "link/up")
+                .expect("Links below a followed link should still be listed");
+            assert_eq!(loops.node_type, NodeType::Link);
+            let skipped = target.skipped_paths().await;
+            assert_eq!(skipped.len(), 1);
+            assert_eq!(skipped[0].path, "dangling");
+        });
+    }
+
+    // Restoring must never write through a symbolic link into the world outside the target
+    #[test]
+    #[cfg(unix)]
+    fn restore_refuses_symlink_escape() {
+        smol::run(async {
+            let outside = tempdir().unwrap();
+            let link_target = outside.path().display().to_string();
+            let node = |path: &str, node_type: NodeType, link_target: Option<String>| Node {
+                path: path.to_string(),
+                total_length: 0,
+                total_size: 0,
+                extents: None,
+                node_type,
+                metadata: NodeMetadata {
+                    link_target,
+                    ..NodeMetadata::default()
+                },
+                hash: None,
+            };
+            let link = node("escape", NodeType::Link, Some(link_target));
+            let file = node("escape/planted", NodeType::File, None);
+            let mut listing = Listing::default();
+            listing.add_child("", link.clone());
+            listing.add_child("", file.clone());
+
+            let output_dir = tempdir().unwrap();
+            let output_path = output_dir.path().display().to_string();
+            let target = FileSystemTarget::load_listing(&output_path, listing).await;
+            target.restore_object(link).await;
+            target.restore_object(file).await;
+
+            let link = output_dir.path().join("escape");
+            assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+            assert!(!outside.path().join("planted").exists());
+            let refused = target.refused_paths().await;
+            assert_eq!(refused.len(), 1);
+            assert_eq!(refused[0].path, "escape/planted");
+            assert!(matches!(refused[0].reason, SkipReason::Unsafe(_)));
+        });
+    }
+
+    // Listings naming paths outside of the target must be refused, hardened or not
+    #[test]
+    fn restore_refuses_traversal() {
+        smol::run(async {
+            let outside = tempdir().unwrap();
+            let output_dir = tempdir().unwrap();
+            let output_path = output_dir.path().join("output");
+            create_dir(&output_path).unwrap();
+            let absolute = outside.path().join("absolute").display().to_string();
+            let target = FileSystemTarget::load_listing(
+                &output_path.display().to_string(),
+                Listing::default(),
+            )
+            .await;
+            for path in &["../escaped", "A/../../escaped", absolute.as_str()] {
+                let node = Node {
+                    path: path.to_string(),
+                    total_length: 0,
+                    total_size: 0,
+                    extents: None,
+                    node_type: NodeType::File,
+                    metadata: NodeMetadata::default(),
+                    hash: None,
+                };
+                target.restore_object(node).await;
+            }
+            assert!(!output_dir.path().join("escaped").exists());
+            assert!(!outside.path().join("absolute").exists());
+            let refused = target.refused_paths().await;
+            assert_eq!(refused.len(), 3);
+            assert!(refused
+                .iter()
+                .all(|x| matches!(x.reason, SkipReason::Unsafe(_))));
+        });
+    }
+
+    // Hardened restores still restore normal files, but the kernel refuses to open anything
+    // through a symbolic link, even one that appears after the path was checked
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn hardened_restore() {
+        smol::run(async {
+            let outside = tempdir().unwrap();
+            let output_dir = tempdir().unwrap();
+            let output_path = output_dir.path().display().to_string();
+            let mut target = FileSystemTarget::new(&output_path);
+            target.set_hardened(true);
+            let node = Node {
+                path: "A/file".to_string(),
+                total_length: 0,
+                total_size: 0,
+                extents: None,
+                node_type: NodeType::File,
+                metadata: NodeMetadata::default(),
+                hash: None,
+            };
+            target.restore_object(node).await;
+            assert!(output_dir.path().join("A").join("file").is_file());
+            assert!(target.refused_paths().await.is_empty());
+
+            std::os::unix::fs::symlink(outside.path(), output_dir.path().join("B")).unwrap();
+            let result = target.create_file(&output_dir.path().join("B").join("file"));
+            assert!(result.is_err());
+            assert!(!outside.path().join("file").exists());
+        });
+    }
+
+    #[test]
+    fn listing_is_deterministic() {
+        smol::run(async {
+            let input_dir = make_test_directory();
+            let root_path = input_dir.path().display().to_string();
+
+            let mut serial = FileSystemTarget::new(&root_path);
+            serial.set_walk_threads(1);
+            let mut parallel = FileSystemTarget::new(&root_path);
+            parallel.set_walk_threads(8);
+
+            let serial = serial.backup_paths().await;
+            let parallel = parallel.backup_paths().await;
+            assert_eq!(serial, parallel);
+            assert!(serial.iter().any(|x| x.path == "B/C/6"));
+        });
+    }
+}
@@ -0,0 +1,230 @@
+//! A parallel, work-stealing directory walker
+//!
+//! Each worker thread reads directories off of its own queue, pushing any subdirectories
+//! it finds back onto it, and steals queued directories from the other workers once its
+//! own queue runs dry. This keeps every thread busy on wide trees, which is what it takes
+//! to saturate fast storage when a tree contains millions of small files.
+//!
+//! Entries are visited in no particular order, callers that care about ordering need to
+//! sort the results themselves.
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Something the walker came across
+#[derive(Debug)]
+pub enum WalkEvent {
+    /// An entry in a directory
+    Entry {
+        /// The full path of the entry
+        path: PathBuf,
+        /// True if the entry is a directory, not following symbolic links
+        is_dir: bool,
+        /// True if the entry is a symbolic link
+        is_symlink: bool,
+    },
+    /// A directory, or an entry in one, that could not be read
+    Error { path: PathBuf, error: io::Error },
+}
+
+/// Shared state of a walk
+struct Walk<F> {
+    /// Directories that have not been picked up by any worker yet
+    injector: Injector<PathBuf>,
+    /// Handles for stealing from each worker's queue
+    stealers: Vec<Stealer<PathBuf>>,
+    /// Number of directories that have been queued, but not yet completely read
+    pending: AtomicUsize,
+    /// Set if a worker panicked, as the directory it was reading will never be finished
+    poisoned: AtomicBool,
+    visit: F,
+}
+
+/// Poisons a walk if the worker holding it panics
+struct PoisonGuard<'a>(&'a AtomicBool);
+
+impl Drop for PoisonGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<T, F> Walk<F>
+where
+    F: Fn(WalkEvent) -> (Option<T>, bool),
+{
+    /// Finds the next directory to read, preferring the local queue
+    fn next_directory(&self, local: &Worker<PathBuf>) -> Option<PathBuf> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|s| !s.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    /// Reads a single directory, visiting each of its entries and queueing up the
+    /// subdirectories that should be descended into
+    fn read_directory(&self, directory: &Path, local: &Worker<PathBuf>, output: &mut Vec<T>) {
+        let mut emit = |event| {
+            let (value, descend) = (self.visit)(event);
+            if let Some(value) = value {
+                output.push(value);
+            }
+            descend
+        };
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(error) => {
+                emit(WalkEvent::Error {
+                    path: directory.to_owned(),
+                    error,
+                });
+                return;
+            }
+        };
+        for entry in entries {
+            let event = match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))) {
+                Ok((path, file_type)) => WalkEvent::Entry {
+                    path,
+                    is_dir: file_type.is_dir(),
+                    is_symlink: file_type.is_symlink(),
+                },
+                Err(error) => WalkEvent::Error {
+                    path: directory.to_owned(),
+                    error,
+                },
+            };
+            let subdirectory = match &event {
+                WalkEvent::Entry {
+                    path,
+                    is_dir,
+                    is_symlink,
+                } if *is_dir || *is_symlink => Some(path.clone()),
+                _ => None,
+            };
+            if emit(event) {
+                if let Some(subdirectory) = subdirectory {
+                    self.pending.fetch_add(1, Ordering::SeqCst);
+                    local.push(subdirectory);
+                }
+            }
+        }
+    }
+
+    /// Runs a single worker until every queued directory has been read
+    fn run(&self, local: &Worker<PathBuf>) -> Vec<T> {
+        let _guard = PoisonGuard(&self.poisoned);
+        let mut output = Vec::new();
+        loop {
+            if self.poisoned.load(Ordering::SeqCst) {
+                return output;
+            }
+            if let Some(directory) = self.next_directory(local) {
+                self.read_directory(&directory, local, &mut output);
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+            } else if self.pending.load(Ordering::SeqCst) == 0 {
+                return output;
+            } else {
+                // Another worker is still reading a directory, and may queue up more
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+/// Walks every entry below `root` on `threads` threads, collecting the values `visit`
+/// produces
+///
+/// `visit` is called once for every entry below `root`, but not `root` itself, as well as
+/// for every directory or entry that could not be read. Along with a value to collect, it
+/// returns whether a directory entry should be descended into. Symbolic links are
+/// followed if `visit` asks for them to be descended into, so it must only do so for links
+/// to directories, and must itself guard against links leading back up the tree.
+///
+/// # Panics
+///
+/// Will panic if `visit` panics on any of the worker threads.
+pub fn walk_parallel<T, F>(root: &Path, threads: usize, visit: F) -> Vec<T>
+where
+    T: Send + 'static,
+    F: Fn(WalkEvent) -> (Option<T>, bool) + Send + Sync + 'static,
+{
+    let workers: Vec<Worker<PathBuf>> = (0..threads.max(1)).map(|_| Worker::new_lifo()).collect();
+    let walk = Arc::new(Walk {
+        injector: Injector::new(),
+        stealers: workers.iter().map(Worker::stealer).collect(),
+        pending: AtomicUsize::new(1),
+        poisoned: AtomicBool::new(false),
+        visit,
+    });
+    walk.injector.push(root.to_owned());
+    let handles: Vec<_> = workers
+        .into_iter()
+        .map(|local| {
+            let walk = walk.clone();
+            thread::spawn(move || walk.run(&local))
+        })
+        .collect();
+    handles
+        .into_iter()
+        .flat_map(|handle| handle.join().expect("Directory walker thread panicked"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::fs::{create_dir_all, File};
+    use tempfile::tempdir;
+
+    #[test]
+    fn visits_everything_once() {
+        let root = tempdir().unwrap();
+        let mut expected = HashSet::new();
+        for a in 0..4 {
+            for b in 0..8 {
+                let directory = root.path().join(format!("{a}/{b}"));
+                create_dir_all(&directory).unwrap();
+                expected.insert(root.path().join(a.to_string()));
+                expected.insert(directory.clone());
+                for c in 0..8 {
+                    let file = directory.join(format!("{c}.txt"));
+                    File::create(&file).unwrap();
+                    expected.insert(file);
+                }
+            }
+        }
+        // Leave out everything below "3/"
+        let excluded = root.path().join("3");
+        let expected_excluded = excluded.clone();
+        let visited = walk_parallel(root.path(), 4, move |event| match event {
+            WalkEvent::Entry { path, .. } => {
+                let descend = path != excluded;
+                (Some(path), descend)
+            }
+            WalkEvent::Error { path, error } => panic!("{:?}: {}", path, error),
+        });
+
+        let count = visited.len();
+        let visited: HashSet<PathBuf> = visited.into_iter().collect();
+        assert_eq!(count, visited.len());
+        let expected: HashSet<PathBuf> = expected
+            .into_iter()
+            .filter(|x| !x.starts_with(&expected_excluded) || *x == expected_excluded)
+            .collect();
+        assert_eq!(visited, expected);
+    }
+}
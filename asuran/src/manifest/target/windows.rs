@@ -0,0 +1,324 @@
+//! Capturing and restoring the metadata of objects on Windows filesystems
+//!
+//! Symbolic links and junctions are both reparse points, which std only tells apart from
+//! regular files by their being symlinks, so the reparse tag is looked up separately to
+//! find out which of the two a link is.
+use super::{AlternateStream, ReparsePoint, WindowsMetadata};
+
+use winapi::shared::minwindef::{DWORD, LPVOID, MAX_PATH};
+use winapi::shared::winerror::ERROR_HANDLE_EOF;
+use winapi::um::fileapi::{
+    CreateFileW, FindClose, FindFirstFileW, FindFirstStreamW, FindNextStreamW,
+    FindStreamInfoStandard, SetFileAttributesW, OPEN_EXISTING,
+};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::minwinbase::WIN32_FIND_DATAW;
+use winapi::um::winbase::{FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OPEN_REPARSE_POINT};
+use winapi::um::winioctl::FSCTL_SET_REPARSE_POINT;
+use winapi::um::winnt::{
+    FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_NORMAL,
+    FILE_ATTRIBUTE_NOT_CONTENT_INDEXED, FILE_ATTRIBUTE_OFFLINE, FILE_ATTRIBUTE_READONLY,
+    FILE_ATTRIBUTE_SYSTEM, FILE_ATTRIBUTE_TEMPORARY, GENERIC_WRITE, IO_REPARSE_TAG_MOUNT_POINT,
+};
+
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs::{self, FileTimes, Metadata, OpenOptions};
+use std::io;
+use std::iter;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::{symlink_dir, symlink_file, FileTimesExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The attributes `SetFileAttributesW` accepts, the rest are managed by the filesystem
+const SETTABLE_ATTRIBUTES: u32 = FILE_ATTRIBUTE_READONLY
+    | FILE_ATTRIBUTE_HIDDEN
+    | FILE_ATTRIBUTE_SYSTEM
+    | FILE_ATTRIBUTE_ARCHIVE
+    | FILE_ATTRIBUTE_TEMPORARY
+    | FILE_ATTRIBUTE_OFFLINE
+    | FILE_ATTRIBUTE_NOT_CONTENT_INDEXED;
+
+/// The unix epoch, as a `FILETIME`
+const UNIX_EPOCH_FILETIME: u64 = 116_444_736_000_000_000;
+
+/// `FILETIME`s are counted in 100 nanosecond intervals
+const INTERVALS_PER_SECOND: u64 = 10_000_000;
+
+/// `WIN32_FIND_STREAM_DATA`, which winapi does not provide
+#[repr(C)]
+struct FindStreamData {
+    stream_size: i64,
+    stream_name: [u16; MAX_PATH + 36],
+}
+
+/// Converts a path into a null terminated wide string
+fn wide(path: &OsStr) -> Vec<u16> {
+    path.encode_wide().chain(iter::once(0)).collect()
+}
+
+/// Returns true if `metadata`, gathered without following links, is that of a symbolic
+/// link or junction
+pub fn is_link(metadata: &Metadata) -> bool {
+    metadata.file_type().is_symlink()
+}
+
+/// Captures the metadata of the object at `path`
+///
+/// `metadata` must have been gathered without following links. The alternate data streams
+/// of files are only listed if `streams` is set.
+pub fn capture(path: &Path, metadata: &Metadata, streams: bool) -> io::Result<WindowsMetadata> {
+    let reparse_point = if is_link(metadata) {
+        Some(read_reparse_point(path, metadata)?)
+    } else {
+        None
+    };
+    let streams = if streams && metadata.is_file() {
+        alternate_streams(path)?
+    } else {
+        Vec::new()
+    };
+    Ok(WindowsMetadata {
+        attributes: metadata.file_attributes(),
+        creation_time: metadata.creation_time(),
+        last_access_time: metadata.last_access_time(),
+        last_write_time: metadata.last_write_time(),
+        reparse_point,
+        streams,
+    })
+}
+
+/// Looks up the reparse tag of the object at `path`, which must be a reparse point
+fn reparse_tag(path: &Path) -> io::Result<u32> {
+    let name = wide(path.as_os_str());
+    unsafe {
+        let mut data: WIN32_FIND_DATAW = mem::zeroed();
+        let handle = FindFirstFileW(name.as_ptr(), &mut data);
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        FindClose(handle);
+        // For reparse points, dwReserved0 holds the reparse tag
+        Ok(data.dwReserved0)
+    }
+}
+
+/// Removes the `\\?\` or `\??\` prefix from a path, if it has one
+fn strip_verbatim(path: &str) -> &str {
+    path.strip_prefix(r"\\?\")
+        .or_else(|| path.strip_prefix(r"\??\"))
+        .unwrap_or(path)
+}
+
+fn read_reparse_point(path: &Path, metadata: &Metadata) -> io::Result<ReparsePoint> {
+    let target = fs::read_link(path)?
+        .into_os_string()
+        .into_string()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Link target is not unicode"))?;
+    if reparse_tag(path)? == IO_REPARSE_TAG_MOUNT_POINT {
+        Ok(ReparsePoint::Junction {
+            target: strip_verbatim(&target).to_string(),
+        })
+    } else {
+        Ok(ReparsePoint::Symlink {
+            target,
+            directory: metadata.file_attributes() & FILE_ATTRIBUTE_DIRECTORY != 0,
+        })
+    }
+}
+
+/// Lists the named data streams of the file at `path`
+fn alternate_streams(path: &Path) -> io::Result<Vec<AlternateStream>> {
+    let name = wide(path.as_os_str());
+    let mut streams = Vec::new();
+    unsafe {
+        let mut data: FindStreamData = mem::zeroed();
+        let data_ptr = &mut data as *mut FindStreamData as LPVOID;
+        let handle = FindFirstStreamW(name.as_ptr(), FindStreamInfoStandard, data_ptr, 0);
+        if handle == INVALID_HANDLE_VALUE {
+            let error = io::Error::last_os_error();
+            // Filesystems without streams, such as FAT, report there being none
+            return if error.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) {
+                Ok(streams)
+            } else {
+                Err(error)
+            };
+        }
+        loop {
+            let length = data
+                .stream_name
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(data.stream_name.len());
+            let full_name = String::from_utf16_lossy(&data.stream_name[..length]);
+            // Names look like :NAME:$DATA, with the unnamed stream being the file itself
+            let name = full_name
+                .strip_prefix(':')
+                .and_then(|x| x.strip_suffix(":$DATA"));
+            if let Some(name) = name.filter(|x| !x.is_empty()) {
+                streams.push(AlternateStream {
+                    name: name.to_string(),
+                    length: u64::try_from(data.stream_size).unwrap_or(0),
+                });
+            }
+            if FindNextStreamW(handle, data_ptr) == 0 {
+                break;
+            }
+        }
+        FindClose(handle);
+    }
+    Ok(streams)
+}
+
+/// Returns the path used to open a stream of the file at `path`
+pub fn stream_path(path: &Path, stream: &AlternateStream) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(":");
+    path.push(&stream.name);
+    PathBuf::from(path)
+}
+
+/// Creates the link described by `reparse_point` at `path`
+pub fn create_link(path: &Path, reparse_point: &ReparsePoint) -> io::Result<()> {
+    match reparse_point {
+        ReparsePoint::Symlink {
+            target,
+            directory: true,
+        } => symlink_dir(target, path),
+        ReparsePoint::Symlink {
+            target,
+            directory: false,
+        } => symlink_file(target, path),
+        ReparsePoint::Junction { target } => create_junction(path, target),
+    }
+}
+
+fn u16_length(length: usize) -> io::Result<u16> {
+    u16::try_from(length)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Junction target too long"))
+}
+
+/// Creates a junction at `path`, by turning a new, empty directory into a mount point
+/// reparse point
+fn create_junction(path: &Path, target: &str) -> io::Result<()> {
+    let substitute_name = OsStr::new(&format!(r"\??\{}", strip_verbatim(target)))
+        .encode_wide()
+        .collect::<Vec<_>>();
+    let print_name = OsStr::new(target).encode_wide().collect::<Vec<_>>();
+    // Both names are followed by a null in the path buffer
+    let substitute_length = u16_length(substitute_name.len() * 2)?;
+    let print_length = u16_length(print_name.len() * 2)?;
+    let path_buffer_length = usize::from(substitute_length) + usize::from(print_length) + 4;
+    // REPARSE_DATA_BUFFER, with its MountPointReparseBuffer
+    let mut buffer = Vec::with_capacity(16 + path_buffer_length);
+    buffer.extend_from_slice(&IO_REPARSE_TAG_MOUNT_POINT.to_le_bytes());
+    buffer.extend_from_slice(&u16_length(8 + path_buffer_length)?.to_le_bytes());
+    buffer.extend_from_slice(&0_u16.to_le_bytes());
+    buffer.extend_from_slice(&0_u16.to_le_bytes());
+    buffer.extend_from_slice(&substitute_length.to_le_bytes());
+    buffer.extend_from_slice(&(substitute_length + 2).to_le_bytes());
+    buffer.extend_from_slice(&print_length.to_le_bytes());
+    let names = substitute_name
+        .iter()
+        .chain(iter::once(&0))
+        .chain(print_name.iter())
+        .chain(iter::once(&0));
+    for c in names {
+        buffer.extend_from_slice(&c.to_le_bytes());
+    }
+
+    fs::create_dir(path)?;
+    let name = wide(path.as_os_str());
+    unsafe {
+        let handle = CreateFileW(
+            name.as_ptr(),
+            GENERIC_WRITE,
+            0,
+            ptr::null_mut(),
+            OPEN_EXISTING,
+            FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_BACKUP_SEMANTICS,
+            ptr::null_mut(),
+        );
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        let mut returned: DWORD = 0;
+        let result = DeviceIoControl(
+            handle,
+            FSCTL_SET_REPARSE_POINT,
+            buffer.as_mut_ptr() as LPVOID,
+            buffer.len() as DWORD,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+        );
+        let error = io::Error::last_os_error();
+        CloseHandle(handle);
+        if result == 0 {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Converts a `FILETIME` into a `SystemTime`, returning `None` for unset times
+fn system_time(filetime: u64) -> Option<SystemTime> {
+    let duration = |intervals: u64| {
+        Duration::new(
+            intervals / INTERVALS_PER_SECOND,
+            // The remainder is less than 10^7, so this can not overflow
+            (intervals % INTERVALS_PER_SECOND) as u32 * 100,
+        )
+    };
+    if filetime == 0 {
+        None
+    } else if filetime >= UNIX_EPOCH_FILETIME {
+        UNIX_EPOCH.checked_add(duration(filetime - UNIX_EPOCH_FILETIME))
+    } else {
+        UNIX_EPOCH.checked_sub(duration(UNIX_EPOCH_FILETIME - filetime))
+    }
+}
+
+/// Applies the times and attributes in `metadata` to the object at `path`
+///
+/// This must be done after the object's contents, and for directories, everything inside
+/// them, have been restored, as writing to them would update the times, and the read only
+/// attribute would prevent the writes. Links are left alone, as their times and
+/// attributes would be set on their targets.
+pub fn apply(path: &Path, metadata: &WindowsMetadata) -> io::Result<()> {
+    if metadata.reparse_point.is_some() {
+        return Ok(());
+    }
+    let mut times = FileTimes::new();
+    if let Some(time) = system_time(metadata.creation_time) {
+        times = times.set_created(time);
+    }
+    if let Some(time) = system_time(metadata.last_access_time) {
+        times = times.set_accessed(time);
+    }
+    if let Some(time) = system_time(metadata.last_write_time) {
+        times = times.set_modified(time);
+    }
+    // Directories can only be opened with backup semantics
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)?;
+    file.set_times(times)?;
+    drop(file);
+
+    let attributes = match metadata.attributes & SETTABLE_ATTRIBUTES {
+        0 => FILE_ATTRIBUTE_NORMAL,
+        attributes => attributes,
+    };
+    let name = wide(path.as_os_str());
+    if unsafe { SetFileAttributesW(name.as_ptr(), attributes) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
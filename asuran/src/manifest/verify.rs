@@ -0,0 +1,170 @@
+//! Verifies that the objects in an archive can actually be restored
+//!
+//! Where `Repository::check` makes sure every chunk in the repository is intact, this
+//! walks the listing of an archive and performs a restore of every object into a writer
+//! that throws the data away. Every chunk an object refers to is fetched, has its HMAC
+//! validated, and is decrypted and decompressed, exactly as it would be during a real
+//! restore, and the number of bytes that come out is compared against the length the
+//! listing records for the object. If the listing records a hash of the object's contents,
+//! the restored contents are checked against that as well.
+use crate::error::describe;
+use crate::manifest::archive::{ActiveArchive, Extent};
+use crate::manifest::hash::{HashingWriter, ObjectHasher};
+use crate::repository::{BackendClone, Repository};
+
+use asuran_core::manifest::listing::Node;
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// An object that could not be restored, or did not restore to the expected length
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unreadable {
+    /// The path of the object in the listing
+    pub path: String,
+    /// The namespace of the object's contents, empty for an object's data
+    pub namespace: String,
+    /// Why the object could not be restored
+    pub reason: String,
+}
+
+/// Summary of the work performed by `verify_archive`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VerifyReport {
+    /// The number of objects with contents that were restored
+    pub objects_verified: u64,
+    /// The number of bytes those objects restored to
+    pub bytes_verified: u64,
+    /// The objects that could not be restored
+    pub unreadable: Vec<Unreadable>,
+}
+
+impl VerifyReport {
+    /// Returns true if every object in the archive could be restored
+    pub fn is_clean(&self) -> bool {
+        self.unreadable.is_empty()
+    }
+}
+
+/// A writer that discards everything written to it, keeping count of how much was
+struct NullWriter {
+    count: u64,
+}
+
+impl Write for NullWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Performs a restore of every object in the archive without writing the results
+/// anywhere, reporting every object that could not be restored.
+///
+/// Objects are restored from the namespaces a `RestoreDriver` would restore them from,
+/// the object's data, along with any Windows alternate data streams recorded in its
+/// metadata. Failing to restore an object does not stop the rest of the archive from
+/// being verified.
+pub async fn verify_archive(
+    repository: &mut Repository<impl BackendClone>,
+    archive: &ActiveArchive,
+) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    for node in archive.listing().await {
+        if !node.is_file() {
+            continue;
+        }
+        // Objects from a `BackupDriver` are stored in the sub namespace ""
+        let mut objects = vec![(String::new(), node.total_length)];
+        if let Some(windows) = &node.metadata.windows {
+            objects.extend(windows.streams.iter().map(|x| (x.namespace(), x.length)));
+        }
+        for (namespace, length) in objects {
+            report.objects_verified += 1;
+            match verify_object(repository, archive, &node, &namespace, length).await {
+                Ok(bytes) => report.bytes_verified += bytes,
+                Err(reason) => report.unreadable.push(Unreadable {
+                    path: node.path.clone(),
+                    namespace,
+                    reason,
+                }),
+            }
+        }
+    }
+    report
+}
+
+/// Restores a single object into a `NullWriter`, returning the number of bytes it
+/// restored to, or a description of what went wrong
+async fn verify_object(
+    repository: &mut Repository<impl BackendClone>,
+    archive: &ActiveArchive,
+    node: &Node,
+    namespace: &str,
+    length: u64,
+) -> Result<u64, String> {
+    let archive = archive.namespace_append(namespace);
+    // Empty objects do not get any chunks
+    if archive.chunk_locations(&node.path).is_none() {
+        return if length == 0 {
+            Ok(0)
+        } else {
+            Err(format!("object is {length} bytes long, but has no data"))
+        };
+    }
+    // Only the object's data is hashed
+    let expected = node.hash.as_ref().filter(|_| namespace.is_empty());
+    let hasher = Arc::new(Mutex::new(ObjectHasher::new(
+        expected.map(|x| x.algorithm).unwrap_or_default(),
+    )));
+    // Sparse objects are restored extent by extent, and trailing holes are never
+    // written, so they only need to fit
+    let written = if let Some(extents) = node.extents.as_ref().filter(|x| x.len() > 1) {
+        let mut writers: Vec<(Extent, NullWriter)> = extents
+            .iter()
+            .map(|extent| (*extent, NullWriter { count: 0 }))
+            .collect();
+        writers.sort_by_key(|(extent, _)| extent.start);
+        for (extent, writer) in &mut writers {
+            let writer = HashingWriter::new(writer, hasher.clone());
+            archive
+                .get_extent(repository, &node.path, *extent, writer)
+                .await
+                .map_err(|e| describe(&e))?;
+        }
+        let written = writers.iter().map(|(_, writer)| writer.count).sum();
+        if written > length {
+            return Err(format!(
+                "restored to {written} bytes, but the object is {length} bytes long"
+            ));
+        }
+        written
+    } else {
+        let mut writer = NullWriter { count: 0 };
+        archive
+            .get_object(
+                repository,
+                &node.path,
+                HashingWriter::new(&mut writer, hasher.clone()),
+            )
+            .await
+            .map_err(|e| describe(&e))?;
+        if writer.count != length {
+            return Err(format!(
+                "restored to {} bytes, but the object is {} bytes long",
+                writer.count, length
+            ));
+        }
+        writer.count
+    };
+    if let Some(expected) = expected {
+        if &hasher.lock().expect("Hasher lock poisoned").finish() != expected {
+            return Err("restored contents do not match the hash stored with them".to_string());
+        }
+    }
+    Ok(written)
+}
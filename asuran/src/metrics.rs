@@ -0,0 +1,168 @@
+//! Counters and histograms describing the work asuran does, for monitoring backups
+//!
+//! Metrics are reported as `TRACE` level `tracing` events with the target [`TARGET`], using the
+//! field naming convention of `tracing-opentelemetry`:
+//!
+//! * A field named `monotonic_counter.<name>` adds its value to the counter `<name>`
+//! * A field named `histogram.<name>` records its value in the histogram `<name>`
+//! * Any other field on the event is a label for the metrics recorded by it
+//!
+//! Values are `u64`s, except for histograms of durations, which record seconds as an `f64`. As
+//! `tracing` can not record floats directly, those are recorded with their `Debug` format.
+//!
+//! Nothing is collected unless a subscriber interested in these events is installed, so
+//! reporting metrics costs next to nothing when they are not wanted. `asuran-cli` ships a
+//! subscriber layer that aggregates them and exports them in the Prometheus text format or as
+//! OTLP.
+//!
+//! [`TARGET`]: constant.TARGET.html
+use tracing::{event, Level};
+
+use std::time::Duration;
+
+/// The target of every event carrying metrics
+pub const TARGET: &str = "asuran::metrics";
+
+/// Chunks written to the backend
+pub const CHUNKS_WRITTEN: &str = "asuran_chunks_written";
+/// Chunks that were not written, as the repository already contained them
+pub const CHUNKS_DEDUPLICATED: &str = "asuran_chunks_deduplicated";
+/// Bytes of packed chunks written to the backend
+pub const BYTES_STORED: &str = "asuran_bytes_stored";
+/// Bytes of plaintext that were not written, as the repository already contained them
+pub const BYTES_DEDUPLICATED: &str = "asuran_bytes_deduplicated";
+/// Chunks read from the backend
+pub const CHUNKS_READ: &str = "asuran_chunks_read";
+/// Bytes of packed chunks read from the backend
+pub const BYTES_READ: &str = "asuran_bytes_read";
+/// Chunks compressed and encrypted by the pipeline
+pub const PIPELINE_CHUNKS: &str = "asuran_pipeline_chunks";
+/// Bytes of plaintext handed to the pipeline
+pub const PIPELINE_BYTES_IN: &str = "asuran_pipeline_bytes_in";
+/// Bytes of packed chunks produced by the pipeline
+pub const PIPELINE_BYTES_OUT: &str = "asuran_pipeline_bytes_out";
+/// Reads served by an already open segment, labeled with the `backend`
+pub const SEGMENT_CACHE_HITS: &str = "asuran_segment_cache_hits";
+/// Reads that had to open their segment, labeled with the `backend`
+pub const SEGMENT_CACHE_MISSES: &str = "asuran_segment_cache_misses";
+/// Chunk reads served from the repository's read cache
+pub const CHUNK_CACHE_HITS: &str = "asuran_chunk_cache_hits";
+/// Chunk reads that missed the repository's read cache
+pub const CHUNK_CACHE_MISSES: &str = "asuran_chunk_cache_misses";
+/// Time taken by backend operations in seconds, labeled with the `operation`
+pub const BACKEND_LATENCY: &str = "asuran_backend_latency_seconds";
+
+/// Records a chunk of `bytes` packed bytes being written to the backend
+pub(crate) fn chunk_written(bytes: u64) {
+    event!(
+        target: TARGET,
+        Level::TRACE,
+        {
+            monotonic_counter.asuran_chunks_written = 1_u64,
+            monotonic_counter.asuran_bytes_stored = bytes
+        }
+    );
+}
+
+/// Records a chunk being skipped, as the repository already contained it
+pub(crate) fn chunk_deduplicated() {
+    event!(
+        target: TARGET,
+        Level::TRACE,
+        {
+            monotonic_counter.asuran_chunks_deduplicated = 1_u64
+        }
+    );
+}
+
+/// Records `bytes` bytes of plaintext being skipped, as the repository already contained them
+pub(crate) fn bytes_deduplicated(bytes: u64) {
+    event!(
+        target: TARGET,
+        Level::TRACE,
+        {
+            monotonic_counter.asuran_bytes_deduplicated = bytes
+        }
+    );
+}
+
+/// Records a chunk of `bytes` packed bytes being read from the backend
+pub(crate) fn chunk_read(bytes: u64) {
+    event!(
+        target: TARGET,
+        Level::TRACE,
+        {
+            monotonic_counter.asuran_chunks_read = 1_u64,
+            monotonic_counter.asuran_bytes_read = bytes
+        }
+    );
+}
+
+/// Records the pipeline packing `bytes_in` bytes of plaintext into `bytes_out` bytes
+pub(crate) fn chunk_packed(bytes_in: u64, bytes_out: u64) {
+    event!(
+        target: TARGET,
+        Level::TRACE,
+        {
+            monotonic_counter.asuran_pipeline_chunks = 1_u64,
+            monotonic_counter.asuran_pipeline_bytes_in = bytes_in,
+            monotonic_counter.asuran_pipeline_bytes_out = bytes_out
+        }
+    );
+}
+
+/// Records whether a read from `backend` found its segment already open
+pub(crate) fn segment_cache(backend: &'static str, hit: bool) {
+    if hit {
+        event!(
+            target: TARGET,
+            Level::TRACE,
+            {
+                monotonic_counter.asuran_segment_cache_hits = 1_u64,
+                backend
+            }
+        );
+    } else {
+        event!(
+            target: TARGET,
+            Level::TRACE,
+            {
+                monotonic_counter.asuran_segment_cache_misses = 1_u64,
+                backend
+            }
+        );
+    }
+}
+
+/// Records whether a chunk read found its plaintext in the read cache
+pub(crate) fn chunk_cache(hit: bool) {
+    if hit {
+        event!(
+            target: TARGET,
+            Level::TRACE,
+            {
+                monotonic_counter.asuran_chunk_cache_hits = 1_u64
+            }
+        );
+    } else {
+        event!(
+            target: TARGET,
+            Level::TRACE,
+            {
+                monotonic_counter.asuran_chunk_cache_misses = 1_u64
+            }
+        );
+    }
+}
+
+/// Records a backend `operation` taking `elapsed`
+pub(crate) fn backend_latency(operation: &'static str, elapsed: Duration) {
+    event!(
+        target: TARGET,
+        Level::TRACE,
+        {
+            histogram.asuran_backend_latency_seconds = ?elapsed.as_secs_f64(),
+            operation
+        }
+    );
+}
@@ -7,3 +7,4 @@ pub use crate::repository::backend::flatfile::FlatFile;
 pub use crate::repository::backend::multifile::MultiFile;
 pub use crate::repository::backend::*;
 pub use crate::repository::*;
+pub use crate::time::Timestamp;
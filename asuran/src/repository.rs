@@ -40,19 +40,45 @@
 //!
 //! Asuran will not write a chunk whose key already exists in the repository,
 //! effectivly preventing the storage of duplicate chunks.
-pub use crate::repository::backend::{Backend, BackendClone, Index, SegmentDescriptor};
+use crate::chunker::{AnyChunker, Compressibility};
+use crate::manifest::integrity::{chunk_tag, ChunkTag, WrittenChunks};
+use crate::metrics;
+pub use crate::repository::backend::{
+    common::ManifestID, Backend, BackendClone, CheckReport, CheckpointStats, CompactionStats,
+    Index, ManifestHead, SegmentDescriptor,
+};
+use crate::repository::budget::{MemoryBudget, Reservation};
+use crate::repository::cache::ChunkCache;
 use crate::repository::pipeline::Pipeline;
 
-pub use asuran_core::repository::chunk::{Chunk, ChunkID, ChunkSettings};
-pub use asuran_core::repository::compression::Compression;
-pub use asuran_core::repository::encryption::Encryption;
+pub use asuran_core::repository::chunk::{
+    Chunk, ChunkID, ChunkIDAlgorithm, ChunkIDSettings, ChunkSettings, ChunkerSettings,
+};
+pub use asuran_core::repository::compression::{Compression, CompressionError, ZStdDictionary};
+pub use asuran_core::repository::encryption::{CpuFeatures, Encryption};
 pub use asuran_core::repository::hmac::HMAC;
 pub use asuran_core::repository::key::{EncryptedKey, Key};
+pub use asuran_core::repository::padding::Padding;
 
+use futures::future::Either;
+use futures::stream::{self, Stream, StreamExt};
+use piper::Lock;
+use rand::seq::IteratorRandom;
 use thiserror::Error;
 use tracing::{debug, info, instrument, span, trace, Level};
 
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::mem::discriminant;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Instant;
+
+pub mod audit;
 pub mod backend;
+pub mod budget;
+pub mod bundle;
+pub mod cache;
 pub mod pipeline;
 
 /// An error for all the various things that can go wrong with handling chunks
@@ -64,10 +90,58 @@ pub enum RepositoryError {
     ChunkerError(#[from] asuran_core::repository::chunk::ChunkError),
     #[error("Backend Error")]
     BackendError(#[from] backend::BackendError),
+    #[error("Repository self test failed: {0}")]
+    SelfTestFailed(String),
+    #[error("Compression Error")]
+    CompressionError(#[from] CompressionError),
 }
 
 type Result<T> = std::result::Result<T, RepositoryError>;
 
+/// The default number of chunks read ahead of the one being restored
+pub const DEFAULT_READ_AHEAD: usize = 8;
+
+/// The default number of consecutive chunks whose reads are put in storage order
+pub const DEFAULT_REORDER_WINDOW: usize = 64;
+
+/// The plaintext of the canary chunk used by `Repository::self_test`
+const CANARY: &[u8] = b"asuran repository canary: if this round trips, the chunk pipeline works";
+
+/// The outcome of writing a chunk with `Repository::write_chunk_measured`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChunkWrite {
+    /// The ID of the chunk
+    pub id: ChunkID,
+    /// True if the chunk was already in the repository, and nothing was written
+    pub already_present: bool,
+    /// The length of the chunk's plaintext
+    pub plaintext_length: u64,
+    /// The number of bytes of chunk data handed to the backend, after compression and
+    /// encryption, zero if the chunk was already present
+    pub stored_length: u64,
+}
+
+/// A chunk handed back by `Repository::read_ahead`
+#[derive(Debug)]
+pub enum ReadChunk {
+    /// The chunk's plaintext, taken from the repository's read cache
+    Cached(Arc<Vec<u8>>),
+    /// The chunk as it was read from the backend, still to be unpacked
+    Raw(Chunk),
+}
+
+/// Summary of the work performed by `Repository::migrate`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct MigrationStats {
+    /// The number of chunks rewritten with the new settings
+    pub chunks_migrated: usize,
+    /// The number of chunks that were already stored with the new settings
+    pub chunks_skipped: usize,
+}
+
+/// The number of chunks `Repository::migrate` rewrites between commits of the index
+const MIGRATION_COMMIT_INTERVAL: usize = 1000;
+
 /// Provides an interface to the storage-backed key value store
 ///
 /// File access is abstracted behind a swappable backend, all backends should
@@ -82,16 +156,62 @@ pub struct Repository<T> {
     hmac: HMAC,
     /// Default encryption algorthim for new chunks
     encryption: Encryption,
+    /// How IDs are derived for new chunks
+    id: ChunkIDSettings,
+    /// The chunker objects are split with, if known
+    chunker: Option<ChunkerSettings>,
     /// Encryption key for this repo
-    key: Key,
+    ///
+    /// Shared with the pipeline, rather than copied for every chunk, so only one copy of
+    /// the key material has to be wiped when the repository is dropped
+    key: Arc<Key>,
     /// Pipeline used for chunking
     pipeline: Pipeline,
     /// Depth of queues to build
     pub queue_depth: usize,
+    /// Number of chunks read ahead of the one being restored
+    ///
+    /// Setting this to 0 reads chunks strictly one after the other.
+    pub read_ahead: usize,
+    /// Number of consecutive chunks whose reads are sorted into the order they are stored
+    /// in, before being handed back in the order they were asked for
+    ///
+    /// Reading in storage order keeps spinning disks from seeking back and forth. Up to
+    /// twice this many chunks may be held in memory at once. Setting this to 0 or 1 reads
+    /// chunks in the order they were asked for.
+    pub reorder_window: usize,
+    /// Whether objects are chunked with an estimate of each chunk's compressibility, which
+    /// is used to pick the level each chunk is compressed at
+    ///
+    /// Redundant data, such as text, is then compressed at a high level, while dense data
+    /// is compressed at a fast level, or not at all. See `Compression::for_entropy`.
+    pub adaptive_compression: bool,
+    /// Padding applied to new chunks before they are encrypted, to obscure their sizes
+    ///
+    /// The padding is recorded in each chunk, so chunks written with any padding can be
+    /// read regardless of this setting.
+    pub padding: Padding,
+    /// Zstd dictionaries that have been loaded from the repository, by id
+    dictionaries: Arc<Lock<HashMap<u32, Arc<ZStdDictionary>>>>,
+    /// The ID and MAC tag of every chunk written since the last archive was committed, if
+    /// integrity records are being kept
+    written: Option<WrittenChunks>,
+    /// The IDs of the chunks that would have been written so far, if writes are only being
+    /// simulated
+    simulated: Option<Arc<Lock<HashSet<ChunkID>>>>,
+    /// The limit on the bytes of chunk data being stored at once, if any
+    memory_budget: Option<MemoryBudget>,
+    /// The plaintext of recently read chunks, if reads are being cached
+    read_cache: Option<ChunkCache>,
 }
 
 impl<T: BackendClone + 'static> Repository<T> {
     /// Creates a new repository with the specificed backend and defaults
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(UnsupportedHMAC)` if support for `hmac` was not compiled in, see
+    /// `Repository::with`.
     #[instrument(skip(key))]
     pub fn new(
         backend: T,
@@ -100,56 +220,85 @@ impl<T: BackendClone + 'static> Repository<T> {
         encryption: Encryption,
         key: Key,
         pipeline_tasks: usize,
-    ) -> Repository<T> {
-        info!("Creating a repository with backend {:?}", backend);
-        let pipeline = Pipeline::new(pipeline_tasks);
-        Repository {
-            backend,
+    ) -> Result<Repository<T>> {
+        let settings = ChunkSettings {
             compression,
-            hmac,
             encryption,
-            key,
-            pipeline,
-            queue_depth: pipeline_tasks,
-        }
+            hmac,
+            id: ChunkIDSettings::default(),
+            chunker: None,
+        };
+        Repository::with(backend, settings, key, pipeline_tasks)
     }
 
     /// Creates a new repository, accepting a ChunkSettings and a ThreadPool
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(UnsupportedHMAC)` if this build can not produce the HMACs or chunk IDs
+    /// of chunks packed with `settings`, such as for a repository created by a build with
+    /// different features, or a newer version of asuran. Every chunk written through the
+    /// repository is packed with its HMAC algorithm, so this is checked up front, rather than
+    /// failing on the first write.
     #[instrument(skip(key))]
     pub fn with(
         backend: T,
         settings: ChunkSettings,
         key: Key,
         pipeline_tasks: usize,
-    ) -> Repository<T> {
+    ) -> Result<Repository<T>> {
         info!(
             "Creating a repository with backend {:?} and chunk settings {:?}",
             backend, settings
         );
+        settings.check_supported()?;
         let pipeline = Pipeline::new(pipeline_tasks);
-        Repository {
+        Ok(Repository {
             backend,
-            key,
+            key: Arc::new(key),
             pipeline,
             compression: settings.compression,
             hmac: settings.hmac,
             encryption: settings.encryption,
+            id: settings.id,
+            chunker: settings.chunker,
             queue_depth: pipeline_tasks,
-        }
+            read_ahead: DEFAULT_READ_AHEAD,
+            reorder_window: DEFAULT_REORDER_WINDOW,
+            adaptive_compression: false,
+            padding: Padding::NoPadding,
+            dictionaries: Arc::new(Lock::new(HashMap::new())),
+            written: None,
+            simulated: None,
+            memory_budget: None,
+            read_cache: None,
+        })
     }
 
     /// Commits the index to storage
     ///
     /// This should be called every time an archive or manifest is written, at
     /// the very least
+    ///
+    /// The backend is flushed first, so the committed index never refers to a chunk
+    /// that can not be read back after the process is killed.
     #[instrument(skip(self))]
     pub async fn commit_index(&self) {
         debug!("Commiting Index");
+        let start = Instant::now();
+        self.backend
+            .clone()
+            .flush()
+            .await
+            .expect("Unable to flush backend");
+        metrics::backend_latency("flush", start.elapsed());
+        let start = Instant::now();
         self.backend
             .get_index()
             .commit_index()
             .await
             .expect("Unable to commit index");
+        metrics::backend_latency("commit_index", start.elapsed());
     }
 
     /// Writes a chunk directly to the repository
@@ -164,18 +313,35 @@ impl<T: BackendClone + 'static> Repository<T> {
         let _guard = span.enter();
         debug!("Writing chunk with id {:?}", id);
 
+        if let Some(simulated) = &self.simulated {
+            trace!("Simulating write, not writing chunk");
+            let already_present = self.has_chunk(id).await || !simulated.lock().await.insert(id);
+            return Ok((id, already_present));
+        }
+
         // Check if chunk exists
         if self.has_chunk(id).await && id != ChunkID::manifest_id() {
             trace!("Chunk already existed, doing nothing.");
+            metrics::chunk_deduplicated();
             Ok((id, true))
         } else {
             trace!("Chunk did not exist, continuning");
 
             // Get highest segment and check to see if has enough space
+            let length = chunk.len() as u64;
+            let tag = chunk_tag(&chunk);
             let backend = &mut self.backend;
+            let start = Instant::now();
             let location = backend.write_chunk(chunk).await?;
+            metrics::backend_latency("write_chunk", start.elapsed());
 
             self.backend.get_index().set_chunk(id, location).await?;
+            metrics::chunk_written(length);
+            if let Some(written) = &self.written {
+                if id != ChunkID::manifest_id() {
+                    written.lock().await.push((id, tag));
+                }
+            }
 
             Ok((id, false))
         }
@@ -192,6 +358,36 @@ impl<T: BackendClone + 'static> Repository<T> {
     /// Repository, and false otherwise
     #[instrument(skip(self, data))]
     pub async fn write_chunk(&mut self, data: Vec<u8>) -> Result<(ChunkID, bool)> {
+        let written = self.write_chunk_measured(data).await?;
+        Ok((written.id, written.already_present))
+    }
+
+    /// Writes a chunk to the repo, like `write_chunk`, additionally reporting how much
+    /// data went in, and how much was handed to the backend
+    #[instrument(skip(self, data))]
+    pub async fn write_chunk_measured(&mut self, data: Vec<u8>) -> Result<ChunkWrite> {
+        self.write_measured(data, None).await
+    }
+
+    /// Writes a chunk to the repo, like `write_chunk_measured`, picking the level it is
+    /// compressed at to suit its estimated compressibility
+    #[instrument(skip(self, data))]
+    pub async fn write_chunk_estimated(
+        &mut self,
+        data: Vec<u8>,
+        compressibility: Compressibility,
+    ) -> Result<ChunkWrite> {
+        self.write_measured(data, Some(compressibility)).await
+    }
+
+    async fn write_measured(
+        &mut self,
+        data: Vec<u8>,
+        compressibility: Option<Compressibility>,
+    ) -> Result<ChunkWrite> {
+        let length = data.len() as u64;
+        let dictionary = self.compression_dictionary().await?;
+        let admission = self.pipeline.admit().await;
         let chunk = self
             .pipeline
             .process(
@@ -199,10 +395,29 @@ impl<T: BackendClone + 'static> Repository<T> {
                 self.compression,
                 self.encryption,
                 self.hmac,
-                self.key.clone(),
+                self.id,
+                Arc::clone(&self.key),
+                dictionary,
+                compressibility,
+                self.padding,
             )
             .await;
-        self.write_raw(chunk).await
+        let stored_length = chunk.len() as u64;
+        let start = Instant::now();
+        let (id, already_present) = self.write_raw(chunk).await?;
+        // Deduplicated chunks never reach the backend, so say nothing about its speed
+        if !already_present {
+            admission.complete(start.elapsed());
+        }
+        if already_present {
+            metrics::bytes_deduplicated(length);
+        }
+        Ok(ChunkWrite {
+            id,
+            already_present,
+            plaintext_length: length,
+            stored_length: if already_present { 0 } else { stored_length },
+        })
     }
 
     /// Writes a chunk to the repo
@@ -222,6 +437,8 @@ impl<T: BackendClone + 'static> Repository<T> {
         data: Vec<u8>,
         id: ChunkID,
     ) -> Result<(ChunkID, bool)> {
+        let length = data.len() as u64;
+        let dictionary = self.compression_dictionary().await?;
         let mut chunk = self
             .pipeline
             .process(
@@ -229,20 +446,38 @@ impl<T: BackendClone + 'static> Repository<T> {
                 self.compression,
                 self.encryption,
                 self.hmac,
-                self.key.clone(),
+                self.id,
+                Arc::clone(&self.key),
+                dictionary,
+                None,
+                self.padding,
             )
             .await;
         let mac = chunk.mac();
         let encryption = chunk.encryption();
+        let compression = chunk.compression();
+        let padding = chunk.padding();
         let data = (chunk.split().1).0;
-        chunk = Chunk::from_parts(data, self.compression, encryption, self.hmac, mac, id);
-        self.write_raw(chunk).await
+        chunk = Chunk::from_parts(data, compression, encryption, self.hmac, mac, id, padding);
+        let (id, already_present) = self.write_raw(chunk).await?;
+        if already_present {
+            metrics::bytes_deduplicated(length);
+        }
+        Ok((id, already_present))
+    }
+
+    /// Derives the ID a chunk with the given plaintext is written with
+    pub fn chunk_id(&self, data: &[u8]) -> ChunkID {
+        self.id.derive(data, self.hmac, &self.key)
     }
 
     /// Determines if a chunk exists in the index
+    ///
+    /// Chunks that are definitely not in the repository are filtered out by the index's
+    /// `ChunkFilter`, without waiting on a full lookup.
     #[instrument(skip(self))]
     pub async fn has_chunk(&self, id: ChunkID) -> bool {
-        self.backend.get_index().lookup_chunk(id).await.is_some()
+        self.backend.get_index().contains_chunk(id).await
     }
 
     /// Reads a chunk from the repo
@@ -250,19 +485,275 @@ impl<T: BackendClone + 'static> Repository<T> {
     /// Returns none if reading the chunk fails
     #[instrument(skip(self))]
     pub async fn read_chunk(&mut self, id: ChunkID) -> Result<Vec<u8>> {
-        // First, check if the chunk exists
-        if self.has_chunk(id).await {
-            let mut index = self.backend.get_index();
-            let location = index.lookup_chunk(id).await.unwrap_or_else(|| {
-                panic!("Index lied to us about having the chunk with ID {:?}", id)
-            });
-            let chunk = self.backend.read_chunk(location).await?;
+        if let Some(plaintext) = self.cached_chunk(id) {
+            return Ok(plaintext.to_vec());
+        }
+        let chunk = self.read_raw(id).await?;
+        if self.read_cache.is_some() {
+            let plaintext = self.unpack_cached(&chunk).await?;
+            return Ok(plaintext.to_vec());
+        }
+
+        let dictionary = self.chunk_dictionary(&chunk).await?;
+        let data = chunk.unpack_with_dictionary(&self.key, dictionary.as_deref())?;
+
+        Ok(data)
+    }
+
+    /// Reads a chunk from the repo, decoding it straight into `output`
+    ///
+    /// Unlike `read_chunk`, the chunk is decrypted and decompressed through bounded buffers
+    /// as it is written out, rather than into a buffer holding its entire plaintext.
+    ///
+    /// Returns the length of the chunk's plaintext.
+    #[instrument(skip(self, output))]
+    pub async fn read_chunk_into<W: Write + ?Sized>(
+        &mut self,
+        id: ChunkID,
+        output: &mut W,
+    ) -> Result<u64> {
+        if let Some(plaintext) = self.cached_chunk(id) {
+            return write_plaintext(&plaintext, output);
+        }
+        let chunk = self.read_raw(id).await?;
+        self.unpack_into(&chunk, output).await
+    }
+
+    /// Decodes a chunk read with `read_raw`, writing its plaintext into `output`
+    ///
+    /// If reads are being cached, the chunk is decoded in full and added to the cache before
+    /// it is written out.
+    ///
+    /// Returns the length of the chunk's plaintext.
+    pub async fn unpack_into<W: Write + ?Sized>(
+        &mut self,
+        chunk: &Chunk,
+        output: &mut W,
+    ) -> Result<u64> {
+        if self.read_cache.is_some() {
+            let plaintext = self.unpack_cached(chunk).await?;
+            return write_plaintext(&plaintext, output);
+        }
+        let dictionary = self.chunk_dictionary(chunk).await?;
+        Ok(chunk.unpack_into(&self.key, dictionary.as_deref(), output)?)
+    }
+
+    /// Writes the plaintext of a chunk handed back by `read_ahead` into `output`
+    ///
+    /// Returns the length of the chunk's plaintext.
+    pub async fn unpack_read_into<W: Write + ?Sized>(
+        &mut self,
+        chunk: &ReadChunk,
+        output: &mut W,
+    ) -> Result<u64> {
+        match chunk {
+            ReadChunk::Cached(plaintext) => write_plaintext(plaintext, output),
+            ReadChunk::Raw(chunk) => self.unpack_into(chunk, output).await,
+        }
+    }
+
+    /// Decodes a chunk read with `read_raw` in full, adding its plaintext to the read cache,
+    /// if there is one
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the chunk can not be verified or decoded
+    pub async fn unpack_cached(&mut self, chunk: &Chunk) -> Result<Arc<Vec<u8>>> {
+        let dictionary = self.chunk_dictionary(chunk).await?;
+        let plaintext = Arc::new(chunk.unpack_with_dictionary(&self.key, dictionary.as_deref())?);
+        if let Some(cache) = &self.read_cache {
+            cache.insert(chunk.get_id(), Arc::clone(&plaintext));
+        }
+        Ok(plaintext)
+    }
+
+    /// Looks up the plaintext of a chunk in the read cache, if there is one
+    fn cached_chunk(&self, id: ChunkID) -> Option<Arc<Vec<u8>>> {
+        self.read_cache.as_ref().and_then(|cache| cache.get(id))
+    }
+
+    /// Loads the dictionary needed to decompress `chunk`, if it needs one
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the chunk needs a dictionary that can not be loaded
+    pub async fn chunk_dictionary(&mut self, chunk: &Chunk) -> Result<Option<Arc<ZStdDictionary>>> {
+        match chunk.compression().dictionary_id() {
+            Some(dict_id) => Ok(Some(self.dictionary(dict_id).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads the zstd dictionary with the given id from the repository
+    ///
+    /// Dictionaries are cached after the first time they are loaded.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ChunkNotFound)` if the repository does not contain the
+    /// dictionary, or an error if the dictionary chunk can not be read.
+    #[instrument(skip(self))]
+    pub async fn dictionary(&mut self, dict_id: u32) -> Result<Arc<ZStdDictionary>> {
+        if let Some(dictionary) = self.dictionaries.lock().await.get(&dict_id) {
+            return Ok(dictionary.clone());
+        }
+        let chunk = self.read_raw(ChunkID::dictionary_id(dict_id)).await?;
+        let dictionary = ZStdDictionary::from_bytes(chunk.unpack(&self.key)?)?;
+        if dictionary.id() != dict_id {
+            return Err(CompressionError::MissingDictionary(dict_id).into());
+        }
+        let dictionary = Arc::new(dictionary);
+        self.dictionaries
+            .lock()
+            .await
+            .insert(dict_id, dictionary.clone());
+        Ok(dictionary)
+    }
 
-            let data = chunk.unpack(&self.key)?;
+    /// Loads the dictionary needed by this repository's default compression, if any
+    async fn compression_dictionary(&mut self) -> Result<Option<Arc<ZStdDictionary>>> {
+        match self.compression.dictionary_id() {
+            Some(dict_id) => Ok(Some(self.dictionary(dict_id).await?)),
+            None => Ok(None),
+        }
+    }
 
-            Ok(data)
+    /// Trains a zstd dictionary of at most `max_size` bytes on up to `samples` chunks,
+    /// chosen at random from the repository
+    ///
+    /// The dictionary is not stored; use `add_dictionary` to do so.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if reading a sampled chunk fails, or if training fails,
+    /// most commonly because the repository does not have enough chunks to train on.
+    #[instrument(skip(self))]
+    pub async fn train_dictionary(
+        &mut self,
+        samples: usize,
+        max_size: usize,
+    ) -> Result<ZStdDictionary> {
+        let ids = self
+            .known_chunks()
+            .await
+            .into_iter()
+            .filter(|id| *id != ChunkID::manifest_id())
+            .choose_multiple(&mut rand::thread_rng(), samples);
+        let mut data = Vec::with_capacity(ids.len());
+        for id in ids {
+            let chunk = self.read_chunk(id).await?;
+            // Data that would not be compressed anyway only dilutes the samples
+            if !Compression::is_incompressible(&chunk) {
+                data.push(chunk);
+            }
+        }
+        Ok(ZStdDictionary::train(&data, max_size)?)
+    }
+
+    /// Stores a zstd dictionary in the repository, under its special `ChunkID`
+    ///
+    /// Returns the `ChunkID` the dictionary was stored under. The index must be
+    /// committed afterwards for the dictionary to persist.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if writing the dictionary fails.
+    #[instrument(skip(self, dictionary))]
+    pub async fn add_dictionary(&mut self, dictionary: &ZStdDictionary) -> Result<ChunkID> {
+        let id = ChunkID::dictionary_id(dictionary.id());
+        let chunk = Chunk::pack_with_id(
+            dictionary.as_bytes().to_vec(),
+            Compression::NoCompression,
+            self.encryption,
+            self.hmac,
+            &self.key,
+            id,
+        );
+        self.write_raw(chunk).await?;
+        self.dictionaries
+            .lock()
+            .await
+            .insert(dictionary.id(), Arc::new(dictionary.clone()));
+        Ok(id)
+    }
+
+    /// Reads a chunk from the repo, without verifying or unpacking it
+    #[instrument(skip(self))]
+    pub async fn read_raw(&mut self, id: ChunkID) -> Result<Chunk> {
+        read_raw_from(&mut self.backend, id).await
+    }
+
+    /// Reads the chunks with the given IDs from the repo, without verifying or unpacking them,
+    /// returning them in the same order
+    ///
+    /// Up to `read_ahead` chunks past the one most recently taken from the stream are read at
+    /// the same time, so backends with high latency can have several reads in flight. The
+    /// IDs are read in windows of `reorder_window` chunks, each sorted by where its chunks
+    /// are stored, with the next window being read while the current one is taken from the
+    /// stream.
+    pub fn read_raw_ahead<I>(&self, ids: I) -> impl Stream<Item = Result<Chunk>>
+    where
+        I: IntoIterator<Item = ChunkID>,
+    {
+        self.read_ahead_with(ids, None).map(|chunk| match chunk? {
+            ReadChunk::Raw(chunk) => Ok(chunk),
+            ReadChunk::Cached(_) => unreachable!("Chunk was cached without a read cache"),
+        })
+    }
+
+    /// Reads the chunks with the given IDs from the repo, returning them in the same order,
+    /// in the same manner as `read_raw_ahead`
+    ///
+    /// Chunks found in the read cache, if there is one, are handed back as their plaintext
+    /// without touching the backend. The cache is checked as each chunk is about to be read,
+    /// so chunks that appear more than once are only read the first time, as long as they
+    /// were not evicted in the meantime. Use `unpack_read_into` to write out the chunks.
+    pub fn read_ahead<I>(&self, ids: I) -> impl Stream<Item = Result<ReadChunk>>
+    where
+        I: IntoIterator<Item = ChunkID>,
+    {
+        self.read_ahead_with(ids, self.read_cache.clone())
+    }
+
+    fn read_ahead_with<I>(
+        &self,
+        ids: I,
+        cache: Option<ChunkCache>,
+    ) -> impl Stream<Item = Result<ReadChunk>>
+    where
+        I: IntoIterator<Item = ChunkID>,
+    {
+        let backend = self.backend.clone();
+        let read_ahead = self.read_ahead;
+        if self.reorder_window <= 1 {
+            Either::Left(
+                stream::iter(ids)
+                    .map(move |id| {
+                        let mut backend = backend.clone();
+                        let cache = cache.clone();
+                        async move {
+                            if let Some(plaintext) = cache.and_then(|cache| cache.get(id)) {
+                                return Ok(ReadChunk::Cached(plaintext));
+                            }
+                            read_raw_from(&mut backend, id).await.map(ReadChunk::Raw)
+                        }
+                    })
+                    .buffered(read_ahead + 1),
+            )
         } else {
-            Err(RepositoryError::ChunkNotFound)
+            let ids = ids.into_iter().collect::<Vec<_>>();
+            let windows = ids
+                .chunks(self.reorder_window)
+                .map(<[ChunkID]>::to_vec)
+                .collect::<Vec<_>>();
+            Either::Right(
+                stream::iter(windows)
+                    .map(move |window| {
+                        read_window(backend.clone(), window, read_ahead, cache.clone())
+                    })
+                    .buffered(2)
+                    .flat_map(stream::iter),
+            )
         }
     }
 
@@ -272,6 +763,12 @@ impl<T: BackendClone + 'static> Repository<T> {
         self.backend.get_index().count_chunk().await
     }
 
+    /// Returns the IDs of every chunk in the repository
+    #[instrument(skip(self))]
+    pub async fn known_chunks(&self) -> HashSet<ChunkID> {
+        self.backend.get_index().known_chunks().await
+    }
+
     /// Returns the current default chunk settings for this repository
     #[instrument(skip(self))]
     pub fn chunk_settings(&self) -> ChunkSettings {
@@ -279,15 +776,430 @@ impl<T: BackendClone + 'static> Repository<T> {
             encryption: self.encryption,
             compression: self.compression,
             hmac: self.hmac,
+            id: self.id,
+            chunker: self.chunker,
         }
     }
 
+    /// Returns the chunker objects in this repository are split with
+    ///
+    /// Repositories that have not recorded their chunker use the default one.
+    pub fn chunker(&self) -> AnyChunker {
+        AnyChunker::from_settings(self.chunk_settings().chunker(), &self.key)
+    }
+
+    /// Returns a handle to this repository that packs new chunks with the compression
+    /// and encryption from `settings`, instead of the repository's defaults
+    ///
+    /// The HMAC, chunk ID, and chunker settings are left as is, as chunks written with
+    /// different ones would never be deduplicated against the rest of the repository.
+    #[must_use]
+    pub fn with_chunk_settings(&self, settings: ChunkSettings) -> Repository<T> {
+        let mut repository = self.clone();
+        repository.compression = settings.compression;
+        repository.encryption = settings.encryption;
+        repository
+    }
+
     /// Gets a refrence to the repository's key
     #[instrument(skip(self))]
     pub fn key(&self) -> &Key {
         &self.key
     }
 
+    /// Gets a shared handle to the repository's key, for handing it to other threads
+    /// without copying the key material
+    pub(crate) fn shared_key(&self) -> Arc<Key> {
+        Arc::clone(&self.key)
+    }
+
+    /// Starts recording the chunks written to this repository, so archives committed through
+    /// a `Manifest` carry a `ChunkIntegrity` record of them
+    ///
+    /// Clones of the repository made afterwards share the same record. See
+    /// `manifest::integrity` for details.
+    pub fn record_integrity(&mut self) {
+        if self.written.is_none() {
+            self.written = Some(Arc::new(Lock::new(Vec::new())));
+        }
+    }
+
+    /// Takes the chunks recorded since the last call, or `None` if chunks are not being
+    /// recorded
+    pub(crate) async fn take_written(&self) -> Option<Vec<(ChunkID, ChunkTag)>> {
+        match &self.written {
+            Some(written) => Some(std::mem::take(&mut *written.lock().await)),
+            None => None,
+        }
+    }
+
+    /// Stops chunks from being written to the backend, only working out which chunks would
+    /// have been, for dry runs
+    ///
+    /// Chunks are still compressed and encrypted, so `write_chunk_measured` reports exactly
+    /// how much would have been written. Chunks that are in the repository, or that would
+    /// already have been written through this repository or its clones, are reported as
+    /// already present. Anything else that writes to the backend, such as committing the
+    /// index or the manifest, is not affected.
+    pub fn simulate_writes(&mut self) {
+        if self.simulated.is_none() {
+            self.simulated = Some(Arc::new(Lock::new(HashSet::new())));
+        }
+    }
+
+    /// Limits the chunk data being stored through this repository and its clones to
+    /// `bytes` bytes at a time
+    ///
+    /// Objects being stored reserve the length of each chunk from the budget before handing
+    /// it off to be packed and written, and return it once the backend has taken the chunk,
+    /// so chunking pauses while the budget is used up. See `budget::MemoryBudget`.
+    pub fn set_memory_limit(&mut self, bytes: u64) {
+        self.memory_budget = Some(MemoryBudget::new(bytes));
+    }
+
+    /// Caches the plaintext of up to `bytes` bytes of the most recently read chunks, shared
+    /// between this repository and its clones made afterwards
+    ///
+    /// Reads of a cached chunk skip the backend, as well as verifying and decoding the chunk,
+    /// which greatly speeds up restoring archives that refer to the same chunks many times.
+    /// A limit of zero turns the cache off. See `cache::ChunkCache`.
+    pub fn set_read_cache(&mut self, bytes: u64) {
+        self.read_cache = if bytes == 0 {
+            None
+        } else {
+            Some(ChunkCache::new(bytes))
+        };
+    }
+
+    /// Returns the read cache, if reads are being cached
+    pub fn read_cache(&self) -> Option<&ChunkCache> {
+        self.read_cache.as_ref()
+    }
+
+    /// Reserves `bytes` bytes from the memory budget, if there is one, waiting for them to
+    /// be available
+    pub(crate) async fn reserve_memory(&self, bytes: u64) -> Option<Reservation> {
+        match &self.memory_budget {
+            Some(budget) => Some(budget.reserve(bytes).await),
+            None => None,
+        }
+    }
+
+    /// Drops a chunk from the chunks recorded since the last call to `take_written`
+    pub(crate) async fn forget_written(&self, id: ChunkID) {
+        if let Some(written) = &self.written {
+            written.lock().await.retain(|(x, _)| *x != id);
+        }
+    }
+
+    /// Performs a quick self test of this repository, intended to be run right after opening it,
+    /// before starting any long running operations.
+    ///
+    /// This first round trips a canary chunk through compression, encryption, and HMAC with this
+    /// repository's default chunk settings, catching settings whose support was not compiled in
+    /// or is otherwise broken. It then verifies the canary chunk stored in the repository,
+    /// catching repositories written by an incompatible build or platform. If the repository does
+    /// not yet contain a canary chunk, one is written and the index is committed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `RepositoryError::SelfTestFailed` if either of the canary checks fail, or any
+    /// other error that occurs while reading or writing the stored canary.
+    #[instrument(skip(self))]
+    pub async fn self_test(&mut self) -> Result<()> {
+        self.run_self_test(true).await
+    }
+
+    /// Performs the same self test as `self_test`, without ever writing to the repository, for
+    /// repositories opened read only
+    ///
+    /// If the repository does not yet contain a canary chunk, only the round trip through this
+    /// repository's default chunk settings is checked.
+    ///
+    /// # Errors
+    ///
+    /// Will return `RepositoryError::SelfTestFailed` if either of the canary checks fail, or any
+    /// other error that occurs while reading the stored canary.
+    #[instrument(skip(self))]
+    pub async fn self_test_read_only(&mut self) -> Result<()> {
+        self.run_self_test(false).await
+    }
+
+    async fn run_self_test(&mut self, store_canary: bool) -> Result<()> {
+        // Round trip the canary in this thread, as the pipeline does not survive a panic
+        let settings = self.chunk_settings();
+        let key = &self.key;
+        let round_trip = catch_unwind(AssertUnwindSafe(|| {
+            let id = settings.id.derive(CANARY, settings.hmac, key);
+            let chunk = Chunk::pack_with_id(
+                CANARY.to_vec(),
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                key,
+                id,
+            );
+            let id = chunk.get_id();
+            chunk.unpack(key).map(|data| (id, data))
+        }));
+        let id = match round_trip {
+            Ok(Ok((id, data))) if data == CANARY => id,
+            Ok(Ok(_)) => {
+                return Err(RepositoryError::SelfTestFailed(
+                    "Canary chunk did not survive a round trip".to_string(),
+                ))
+            }
+            Ok(Err(e)) => {
+                return Err(RepositoryError::SelfTestFailed(format!(
+                    "Unable to unpack canary chunk: {e}"
+                )))
+            }
+            Err(_) => {
+                return Err(RepositoryError::SelfTestFailed(format!(
+                    "Packing a chunk with settings {settings:?} panicked, support for them may not have been compiled in"
+                )))
+            }
+        };
+        // Verify the stored canary, or store it if it does not exist
+        if self.has_chunk(id).await {
+            debug!("Verifying stored canary chunk");
+            match self.read_chunk(id).await {
+                Ok(data) if data == CANARY => Ok(()),
+                Ok(_) => Err(RepositoryError::SelfTestFailed(
+                    "Stored canary chunk did not match".to_string(),
+                )),
+                Err(RepositoryError::ChunkerError(e)) => Err(RepositoryError::SelfTestFailed(
+                    format!("Unable to unpack stored canary chunk: {e}"),
+                )),
+                Err(e) => Err(e),
+            }
+        } else if store_canary {
+            debug!("Writing canary chunk");
+            self.write_chunk(CANARY.to_vec()).await?;
+            self.commit_index().await;
+            Ok(())
+        } else {
+            debug!("No canary chunk stored, skipping its verification");
+            Ok(())
+        }
+    }
+    /// Rewrites every chunk in the repository with the given compression and encryption,
+    /// and makes them the defaults for new chunks
+    ///
+    /// Each chunk is unpacked and packed again under its existing ID, with the repository's
+    /// HMAC, and the repository's current `padding`, so everything referring to it stays
+    /// valid. Chunks already stored with the new settings and padding are left alone, so an
+    /// interrupted migration picks up where it left off when run again. Zstd dictionaries and audit log entries are only re-encrypted, as they have to
+    /// be readable without a dictionary. The index is committed every
+    /// `MIGRATION_COMMIT_INTERVAL` chunks, and once the migration is complete.
+    ///
+    /// The old copies of the chunks are left behind as dead space, for `compact` to
+    /// reclaim. As the chunks' MAC tags change, any `ChunkIntegrity` records will report
+    /// them as replaced.
+    ///
+    /// The new defaults only apply to this handle, they still have to be recorded in the
+    /// manifest.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a chunk can not be read or unpacked, if `compression` needs a
+    /// dictionary the repository does not have, or if writing a chunk fails.
+    #[instrument(skip(self))]
+    pub async fn migrate(
+        &mut self,
+        compression: Compression,
+        encryption: Encryption,
+    ) -> Result<MigrationStats> {
+        let dictionary = match compression.dictionary_id() {
+            Some(dict_id) => Some(self.dictionary(dict_id).await?),
+            None => None,
+        };
+        let ids = self.known_chunks().await;
+        let mut chunks = self.read_raw_ahead(ids);
+        let mut stats = MigrationStats::default();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            let id = chunk.get_id();
+            let compression = if id.is_dictionary() || id.audit_log_sequence().is_some() {
+                chunk.compression()
+            } else {
+                compression
+            };
+            if chunk.compression() == compression
+                && discriminant(&chunk.encryption()) == discriminant(&encryption)
+                && chunk.padding() == self.padding
+            {
+                stats.chunks_skipped += 1;
+                continue;
+            }
+            let data = {
+                let old_dictionary = self.chunk_dictionary(&chunk).await?;
+                chunk.unpack_with_dictionary(&self.key, old_dictionary.as_deref())?
+            };
+            let chunk = Chunk::pack_padded(
+                data,
+                compression,
+                encryption,
+                self.hmac,
+                &self.key,
+                id,
+                dictionary.as_deref(),
+                self.padding,
+            );
+            let location = self.backend.write_chunk(chunk).await?;
+            self.backend.get_index().set_chunk(id, location).await?;
+            stats.chunks_migrated += 1;
+            if stats.chunks_migrated % MIGRATION_COMMIT_INTERVAL == 0 {
+                self.commit_index().await;
+            }
+        }
+        self.commit_index().await;
+        self.compression = compression;
+        self.encryption = encryption;
+        Ok(stats)
+    }
+
+    /// Reclaims the space taken up by chunks that are no longer in the index, by rewriting any
+    /// segment where the proportion of live chunk data is below `threshold` (between 0 and 1)
+    ///
+    /// See `Backend::compact` for details.
+    #[instrument(skip(self))]
+    pub async fn compact(&mut self, threshold: f64) -> Result<CompactionStats> {
+        Ok(self.backend.compact(threshold).await?)
+    }
+
+    /// Verifies every chunk in the backend, optionally repairing any damage that can be
+    /// rebuilt from parity
+    ///
+    /// See `Backend::check` for details.
+    #[instrument(skip(self))]
+    pub async fn check(&mut self, repair: bool) -> Result<CheckReport> {
+        Ok(self.backend.check(repair).await?)
+    }
+
+    /// Squashes the manifest's transactions into a single checkpoint, optionally keeping
+    /// the replaced transactions as an audit trail
+    ///
+    /// See `Backend::checkpoint` for details.
+    #[instrument(skip(self))]
+    pub async fn checkpoint(&mut self, keep_squashed: bool) -> Result<CheckpointStats> {
+        Ok(self.backend.checkpoint(keep_squashed).await?)
+    }
+
+    /// Lists the heads of the manifest's branches, newest first
+    ///
+    /// See `Backend::heads` for details.
+    #[instrument(skip(self))]
+    pub async fn heads(&mut self) -> Result<Vec<ManifestHead>> {
+        Ok(self.backend.heads().await?)
+    }
+
+    /// Joins the manifest's branches into one, returning the tag of the merge, or `None` if
+    /// there was nothing to merge
+    ///
+    /// See `Backend::merge_heads` for details.
+    #[instrument(skip(self))]
+    pub async fn merge_heads(&mut self) -> Result<Option<ManifestID>> {
+        Ok(self.backend.merge_heads().await?)
+    }
+
+    /// Removes the archives with the given pointers from the manifest, leaving the chunks
+    /// they refer to in place
+    ///
+    /// See `Backend::remove_archives` for details.
+    #[instrument(skip(self))]
+    pub async fn remove_archives(&mut self, archives: HashSet<ChunkID>) -> Result<CheckpointStats> {
+        Ok(self.backend.remove_archives(archives).await?)
+    }
+
+    /// Removes every chunk that is not in `live` from the index, returning the number of
+    /// chunks removed
+    ///
+    /// The repository's own chunks, its zstd dictionaries, its audit log, and its self test
+    /// canary, are always kept. The space taken up by the removed chunks is reclaimed by `compact`.
+    ///
+    /// See `Backend::remove_chunks` for details.
+    #[instrument(skip(self, live))]
+    pub async fn collect_garbage(&mut self, live: &HashSet<ChunkID>) -> Result<usize> {
+        let garbage = self
+            .known_chunks()
+            .await
+            .into_iter()
+            .filter(|id| !live.contains(id))
+            .collect::<HashSet<_>>();
+        self.remove_garbage(garbage).await
+    }
+
+    /// Removes the given chunks from the index, other than the chunks `collect_garbage` always
+    /// keeps, returning the number of chunks removed
+    async fn remove_garbage(&mut self, mut garbage: HashSet<ChunkID>) -> Result<usize> {
+        let settings = self.chunk_settings();
+        let canary = settings.id.derive(CANARY, settings.hmac, &self.key);
+        garbage.retain(|id| {
+            *id != canary
+                && *id != ChunkID::manifest_id()
+                && !id.is_dictionary()
+                && id.audit_log_sequence().is_none()
+        });
+        if garbage.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.backend.remove_chunks(garbage).await?)
+    }
+
+    /// Returns the pointers of the archives whose references are included in the backend's
+    /// chunk reference counts, or `None` if it is not keeping reference counts
+    ///
+    /// See `Backend::counted_archives` for details.
+    #[instrument(skip(self))]
+    pub async fn counted_archives(&mut self) -> Result<Option<HashSet<ChunkID>>> {
+        Ok(self.backend.counted_archives().await?)
+    }
+
+    /// Records that the archive with the pointer `archive` refers to each of `chunks`
+    ///
+    /// The index must be committed before the archive is written to the manifest.
+    ///
+    /// See `Backend::add_references` for details.
+    #[instrument(skip(self, chunks))]
+    pub async fn add_references(
+        &mut self,
+        archive: ChunkID,
+        chunks: HashSet<ChunkID>,
+    ) -> Result<()> {
+        Ok(self.backend.add_references(archive, chunks).await?)
+    }
+
+    /// Releases the references the given archives hold, and removes every chunk that is no
+    /// longer referenced from the index, returning the number of chunks removed
+    ///
+    /// The same chunks `collect_garbage` always keeps are kept here as well. If the chunks can
+    /// not be removed, they are left unreferenced in the index until the next full garbage
+    /// collection.
+    ///
+    /// See `Backend::release_references` for details.
+    #[instrument(skip(self, archives))]
+    pub async fn release_references(
+        &mut self,
+        archives: HashMap<ChunkID, HashSet<ChunkID>>,
+    ) -> Result<usize> {
+        let garbage = self.backend.release_references(archives).await?;
+        self.remove_garbage(garbage).await
+    }
+
+    /// Replaces the backend's chunk reference counts with `counts`, covering the references of
+    /// exactly `archives`
+    ///
+    /// See `Backend::reset_references` for details.
+    #[instrument(skip(self, archives, counts))]
+    pub async fn reset_references(
+        &mut self,
+        archives: HashSet<ChunkID>,
+        counts: HashMap<ChunkID, u64>,
+    ) -> Result<()> {
+        Ok(self.backend.reset_references(archives, counts).await?)
+    }
+
     /// Provides a handle to the backend manifest
     #[instrument(skip(self))]
     pub fn backend_manifest(&self) -> T::Manifest {
@@ -304,11 +1216,88 @@ impl<T: BackendClone + 'static> Repository<T> {
     }
 }
 
+/// Looks up a chunk in the backend's index and reads it, without verifying or unpacking it
+async fn read_raw_from<T: Backend>(backend: &mut T, id: ChunkID) -> Result<Chunk> {
+    let location = lookup_from(backend, id).await;
+    read_located(backend, location).await
+}
+
+/// Looks up where a chunk is stored
+async fn lookup_from<T: Backend>(backend: &mut T, id: ChunkID) -> Option<SegmentDescriptor> {
+    let start = Instant::now();
+    let location = backend.get_index().lookup_chunk(id).await;
+    metrics::backend_latency("lookup_chunk", start.elapsed());
+    location
+}
+
+/// Reads the chunk at a location found by `lookup_from`
+async fn read_located<T: Backend>(
+    backend: &mut T,
+    location: Option<SegmentDescriptor>,
+) -> Result<Chunk> {
+    if let Some(location) = location {
+        let start = Instant::now();
+        let chunk = backend.read_chunk(location).await?;
+        metrics::backend_latency("read_chunk", start.elapsed());
+        metrics::chunk_read(chunk.len() as u64);
+        Ok(chunk)
+    } else {
+        Err(RepositoryError::ChunkNotFound)
+    }
+}
+
+/// Writes a chunk's plaintext out in full, returning its length
+fn write_plaintext<W: Write + ?Sized>(plaintext: &[u8], output: &mut W) -> Result<u64> {
+    output
+        .write_all(plaintext)
+        .map_err(CompressionError::from)?;
+    Ok(plaintext.len() as u64)
+}
+
+/// Reads a window of chunks in the order they are stored in, returning them in the order
+/// their IDs were given in
+///
+/// Chunks found in `cache` are handed back without being read. Up to `read_ahead` reads
+/// past the one that is being waited on are kept in flight.
+async fn read_window<T: BackendClone>(
+    mut backend: T,
+    ids: Vec<ChunkID>,
+    read_ahead: usize,
+    cache: Option<ChunkCache>,
+) -> Vec<Result<ReadChunk>> {
+    let mut chunks = ids.iter().map(|_| None).collect::<Vec<_>>();
+    let mut locations = Vec::with_capacity(ids.len());
+    for (index, id) in ids.into_iter().enumerate() {
+        if let Some(plaintext) = cache.as_ref().and_then(|cache| cache.get(id)) {
+            chunks[index] = Some(Ok(ReadChunk::Cached(plaintext)));
+        } else {
+            locations.push((lookup_from(&mut backend, id).await, index));
+        }
+    }
+    // Chunks missing from the index sort first, and fail without touching the backend
+    locations.sort_unstable_by_key(|(location, _)| location.map(|x| (x.segment_id, x.start)));
+    let reads = stream::iter(locations)
+        .map(|(location, index)| {
+            let mut backend = backend.clone();
+            async move { (index, read_located(&mut backend, location).await) }
+        })
+        .buffered(read_ahead + 1);
+    futures::pin_mut!(reads);
+    while let Some((index, chunk)) = reads.next().await {
+        chunks[index] = Some(chunk.map(ReadChunk::Raw));
+    }
+    chunks
+        .into_iter()
+        .map(|chunk| chunk.expect("Chunk in window was not read"))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::repository::backend::common::sync_backend::BackendHandle;
     use crate::repository::backend::mem::*;
+    use asuran_core::repository::chunk::ChunkError;
     use rand::prelude::*;
 
     fn get_repo_mem(key: Key) -> Repository<BackendHandle<Mem>> {
@@ -316,9 +1305,11 @@ mod tests {
             compression: Compression::ZStd { level: 1 },
             hmac: HMAC::Blake2b,
             encryption: Encryption::new_aes256ctr(),
+            id: ChunkIDSettings::default(),
+            chunker: None,
         };
         let backend = Mem::new(settings, key.clone(), 4);
-        Repository::with(backend, settings, key, 2)
+        Repository::with(backend, settings, key, 2).unwrap()
     }
 
     #[test]
@@ -351,6 +1342,87 @@ mod tests {
         });
     }
 
+    #[test]
+    fn reordered_reads() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut ids = Vec::new();
+            for i in 0..20_u8 {
+                ids.push(repo.write_chunk(vec![i; 1000]).await.unwrap().0);
+            }
+            // Ask for the chunks out of storage order, along with one that does not exist
+            ids.shuffle(&mut SmallRng::seed_from_u64(0));
+            ids.insert(7, ChunkID::manifest_id());
+            for window in &[0, 1, 3, 64] {
+                repo.reorder_window = *window;
+                let chunks: Vec<_> = repo.read_raw_ahead(ids.clone()).collect().await;
+                assert_eq!(chunks.len(), ids.len());
+                for (id, chunk) in ids.iter().zip(chunks) {
+                    match chunk {
+                        Ok(chunk) => assert_eq!(chunk.get_id(), *id),
+                        Err(RepositoryError::ChunkNotFound) => {
+                            assert_eq!(*id, ChunkID::manifest_id());
+                        }
+                        Err(e) => panic!("Unexpected error: {}", e),
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn cached_reads() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            repo.set_read_cache(2500);
+            let mut ids = Vec::new();
+            for i in 0..3_u8 {
+                ids.push(repo.write_chunk(vec![i; 1000]).await.unwrap().0);
+            }
+            assert_eq!(repo.read_chunk(ids[0]).await.unwrap(), vec![0; 1000]);
+            let mut output = Vec::new();
+            repo.read_chunk_into(ids[1], &mut output).await.unwrap();
+            assert_eq!(output, vec![1; 1000]);
+            let cache = repo.read_cache().unwrap().clone();
+            assert_eq!(cache.len(), 2);
+            // The first chunk is the least recently used, and makes room for the third
+            assert_eq!(repo.read_chunk(ids[2]).await.unwrap(), vec![2; 1000]);
+            assert_eq!(cache.size(), 2000);
+            assert!(cache.get(ids[0]).is_none());
+            // Cached chunks, and repeats of a chunk after its first read, are handed back from
+            // the cache
+            repo.set_read_cache(10_000);
+            repo.read_chunk(ids[1]).await.unwrap();
+            repo.read_chunk(ids[2]).await.unwrap();
+            for window in &[0, 64] {
+                repo.reorder_window = *window;
+                let order = [0_u8, 1, 0, 2];
+                let wanted = order.iter().map(|i| ids[usize::from(*i)]);
+                let chunks: Vec<_> = repo.read_ahead(wanted).collect().await;
+                let mut output = Vec::new();
+                for chunk in &chunks {
+                    repo.unpack_read_into(chunk.as_ref().unwrap(), &mut output)
+                        .await
+                        .unwrap();
+                }
+                let expected = order
+                    .iter()
+                    .flat_map(|i| vec![*i; 1000])
+                    .collect::<Vec<_>>();
+                assert_eq!(output, expected);
+                assert!(matches!(chunks[1], Ok(ReadChunk::Cached(_))));
+                assert!(matches!(chunks[3], Ok(ReadChunk::Cached(_))));
+            }
+            // Turning the cache off reads everything from the backend
+            repo.set_read_cache(0);
+            assert!(repo.read_cache().is_none());
+            let chunks: Vec<_> = repo.read_ahead(ids.clone()).collect().await;
+            assert!(chunks
+                .iter()
+                .all(|chunk| matches!(chunk, Ok(ReadChunk::Raw(_)))));
+        });
+    }
+
     #[test]
     fn double_add() {
         smol::run(async {
@@ -371,6 +1443,136 @@ mod tests {
         });
     }
 
+    #[test]
+    fn simulated_writes() {
+        smol::run(async {
+            // Simulated writes should never reach the backend, but should still be
+            // deduplicated against the repository and each other
+            let mut repo = get_repo_mem(Key::random(32));
+            let stored = [1_u8; 8192];
+            repo.write_chunk(stored.to_vec()).await.unwrap();
+            assert_eq!(repo.count_chunk().await, 1);
+
+            repo.simulate_writes();
+            let existing = repo.write_chunk_measured(stored.to_vec()).await.unwrap();
+            assert!(existing.already_present);
+            assert_eq!(existing.stored_length, 0);
+            let new = [2_u8; 8192];
+            let first = repo.write_chunk_measured(new.to_vec()).await.unwrap();
+            assert!(!first.already_present);
+            assert_eq!(first.plaintext_length, 8192);
+            assert!(first.stored_length > 0);
+            let second = repo
+                .clone()
+                .write_chunk_measured(new.to_vec())
+                .await
+                .unwrap();
+            assert!(second.already_present);
+            assert_eq!(repo.count_chunk().await, 1);
+            assert!(!repo.has_chunk(first.id).await);
+            std::mem::drop(repo);
+        });
+    }
+
+    #[test]
+    fn truncated_blake3_ids() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings {
+                compression: Compression::ZStd { level: 1 },
+                hmac: HMAC::Blake2b,
+                encryption: Encryption::new_aes256ctr(),
+                id: ChunkIDSettings::new(ChunkIDAlgorithm::Blake3, 20).unwrap(),
+                chunker: None,
+            };
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            repo.self_test().await.unwrap();
+
+            let data = vec![7_u8; 8192];
+            let (id, _) = repo.write_chunk(data.clone()).await.unwrap();
+            assert_eq!(&id.get_id()[20..], &[0_u8; 12][..]);
+            let (id_2, duplicate) = repo.write_chunk(data.clone()).await.unwrap();
+            assert!(duplicate);
+            assert_eq!(id, id_2);
+            assert_eq!(repo.read_chunk(id).await.unwrap(), data);
+        });
+    }
+
+    // Repositories should split objects with their recorded chunker, or the default one if
+    // they have not recorded one
+    #[test]
+    fn recorded_chunker() {
+        let key = Key::random(32);
+        let repo = get_repo_mem(key.clone());
+        assert!(matches!(repo.chunker(), AnyChunker::FastCDC(_)));
+        let settings = ChunkSettings {
+            chunker: Some(ChunkerSettings::BuzHash {
+                window_size: 4095,
+                mask_bits: 21,
+            }),
+            ..ChunkSettings::lightweight()
+        };
+        let backend = Mem::new(settings, key.clone(), 4);
+        let repo = Repository::with(backend, settings, key, 2).unwrap();
+        assert!(matches!(repo.chunker(), AnyChunker::BuzHash(_)));
+    }
+
+    // Chunks are packed with the repository's HMAC algorithm, so one this build can not
+    // compute has to be refused before anything is written
+    #[test]
+    fn unsupported_hmac() {
+        let key = Key::random(32);
+        let settings = ChunkSettings {
+            hmac: HMAC::Unknown(99),
+            ..ChunkSettings::lightweight()
+        };
+        let backend = Mem::new(ChunkSettings::lightweight(), key.clone(), 4);
+        assert!(matches!(
+            Repository::with(backend, settings, key, 2),
+            Err(RepositoryError::ChunkerError(ChunkError::UnsupportedHMAC(
+                HMAC::Unknown(99)
+            )))
+        ));
+    }
+
+    #[test]
+    fn dictionary_round_trip() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key.clone());
+            for i in 0..500 {
+                let record = format!(
+                    "{{\"id\": {}, \"name\": \"user{}\", \"email\": \"user{}@example.com\"}}",
+                    i,
+                    i * 7,
+                    i * 13
+                );
+                repo.write_chunk(record.into_bytes()).await.unwrap();
+            }
+            let dictionary = repo.train_dictionary(500, 4096).await.unwrap();
+            repo.add_dictionary(&dictionary).await.unwrap();
+
+            let settings = ChunkSettings {
+                compression: Compression::ZStdDict {
+                    level: 1,
+                    dict_id: dictionary.id(),
+                },
+                ..repo.chunk_settings()
+            };
+            let mut dict_repo = repo.with_chunk_settings(settings);
+            let data =
+                b"{\"id\": 9000, \"name\": \"user63000\", \"email\": \"user117000@example.com\"}"
+                    .to_vec();
+            let (id, _) = dict_repo.write_chunk(data.clone()).await.unwrap();
+            let chunk = repo.read_raw(id).await.unwrap();
+            assert_eq!(chunk.compression(), settings.compression);
+            // Clear the cache, so the dictionary has to be loaded from the repository
+            repo.dictionaries.lock().await.clear();
+            assert_eq!(repo.read_chunk(id).await.unwrap(), data);
+        });
+    }
+
     // Ensure writing a chunk with an ID works
     #[test]
     fn chunk_with_id() {
@@ -393,4 +1595,100 @@ mod tests {
             assert_eq!(data, data_restore);
         });
     }
+
+    // Ensure the self test passes on a fresh repository, and again once the canary is stored
+    #[test]
+    fn self_test() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            repo.self_test()
+                .await
+                .expect("Self test failed on fresh repository");
+            assert_eq!(repo.count_chunk().await, 1);
+            repo.self_test()
+                .await
+                .expect("Self test failed on stored canary");
+            assert_eq!(repo.count_chunk().await, 1);
+        });
+    }
+
+    // Ensure the self test fails when the stored canary has been corrupted
+    #[test]
+    fn self_test_corrupt_canary() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let settings = repo.chunk_settings();
+            let canary = Chunk::pack(
+                CANARY.to_vec(),
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &repo.key,
+            );
+            // Store garbage under the canary's id
+            repo.write_chunk_with_id(vec![0_u8; 64], canary.get_id())
+                .await
+                .unwrap();
+            assert!(repo.self_test().await.is_err());
+        });
+    }
+
+    // Ensure migrated chunks keep their IDs and contents, and are not migrated twice
+    #[test]
+    fn migrate_settings() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut ids = Vec::new();
+            for i in 0..10_u8 {
+                ids.push(repo.write_chunk(vec![i; 1000]).await.unwrap().0);
+            }
+            let compression = Compression::LZ4 { level: 1 };
+            let encryption = Encryption::new_chacha20();
+            let stats = repo.migrate(compression, encryption).await.unwrap();
+            assert_eq!(stats.chunks_migrated, 10);
+            assert_eq!(repo.chunk_settings().compression, compression);
+            for (i, id) in (0..10_u8).zip(ids) {
+                let chunk = repo.read_raw(id).await.unwrap();
+                assert_eq!(chunk.compression(), compression);
+                assert!(matches!(chunk.encryption(), Encryption::ChaCha20 { .. }));
+                assert_eq!(repo.read_chunk(id).await.unwrap(), vec![i; 1000]);
+            }
+            let stats = repo.migrate(compression, encryption).await.unwrap();
+            assert_eq!(stats.chunks_migrated, 0);
+            assert_eq!(stats.chunks_skipped, 10);
+        });
+    }
+
+    #[test]
+    fn padded_writes() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut data = vec![0_u8; 1000];
+            thread_rng().fill_bytes(&mut data);
+            let plain = repo.write_chunk(data.clone()).await.unwrap().0;
+            let plain_length = repo.read_raw(plain).await.unwrap().len();
+            assert!(!plain_length.is_power_of_two());
+            repo.padding = Padding::PowerOfTwo;
+            data[0] = data[0].wrapping_add(1);
+            let write = repo.write_chunk_measured(data.clone()).await.unwrap();
+            assert_eq!(
+                write.stored_length,
+                Padding::PowerOfTwo.padded_length(plain_length) as u64
+            );
+            let chunk = repo.read_raw(write.id).await.unwrap();
+            assert_eq!(chunk.padding(), Padding::PowerOfTwo);
+            assert_eq!(repo.read_chunk(write.id).await.unwrap(), data);
+            // Migrating pads the chunks written before padding was turned on
+            let settings = repo.chunk_settings();
+            let stats = repo
+                .migrate(settings.compression, settings.encryption)
+                .await
+                .unwrap();
+            assert_eq!(stats.chunks_migrated, 1);
+            assert_eq!(
+                repo.read_raw(plain).await.unwrap().len(),
+                Padding::PowerOfTwo.padded_length(plain_length)
+            );
+        });
+    }
 }
@@ -0,0 +1,218 @@
+//! An append only log of the operations performed on a repository, for reviewing who did
+//! what to a shared repository after the fact.
+//!
+//! Each entry records the operation, the archive it concerned, if any, who performed it
+//! and from which host, when, and with which client. Entries are stored as chunks of their
+//! own, under the special `ChunkID::audit_log_id` of their sequence number. As chunks are
+//! never written over, an entry can not be replaced once written, and as they are packed
+//! like any other chunk, they are encrypted and covered by the repository's HMAC. Garbage
+//! collection always keeps them.
+//!
+//! The log is only as complete as the clients writing to the repository make it. The
+//! lower level parts of the library never write entries on their own, `api::Repo` records
+//! the backups and prunes it performs.
+use crate::repository::backend::BackendError;
+use crate::repository::{BackendClone, Chunk, ChunkID, Compression, Repository, RepositoryError};
+use crate::time::Timestamp;
+
+use serde::{Deserialize, Serialize};
+
+use std::env;
+use std::fmt;
+
+type Result<T> = std::result::Result<T, RepositoryError>;
+
+/// An operation recorded in the audit log
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// An archive was stored
+    Store,
+    /// Archives were removed by a retention policy
+    Prune,
+    /// The repository's key, or the password protecting it, was changed
+    KeyChange,
+    /// The repository was checked for damage
+    Check,
+    /// The repository's chunks were rewritten with new compression or encryption
+    Migrate,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Operation::Store => "store",
+            Operation::Prune => "prune",
+            Operation::KeyChange => "key-change",
+            Operation::Check => "check",
+            Operation::Migrate => "migrate",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// A single entry in the audit log
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Position of the entry in the log, assigned when it is written
+    #[serde(skip)]
+    pub sequence: u64,
+    /// The operation that was performed
+    pub operation: Operation,
+    /// The archive the operation concerned, if any
+    pub archive: Option<String>,
+    /// The user that performed the operation
+    pub user: String,
+    /// The host the operation was performed from
+    pub hostname: String,
+    /// When the operation was performed
+    pub timestamp: Timestamp,
+    /// The name and version of the client that performed the operation
+    pub client_version: String,
+}
+
+impl AuditEntry {
+    /// Describes `operation`, performed right now, by the current user, on this host,
+    /// through this version of the library
+    ///
+    /// Clients built on top of asuran should replace `client_version` with their own.
+    pub fn new(operation: Operation, archive: Option<String>) -> AuditEntry {
+        let user = env::var("USER")
+            .or_else(|_| env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".to_string());
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+        AuditEntry {
+            sequence: 0,
+            operation,
+            archive,
+            user,
+            hostname,
+            timestamp: Timestamp::now(),
+            client_version: format!("asuran {}", crate::VERSION),
+        }
+    }
+}
+
+/// Appends `entry` to the end of the repository's audit log, returning the sequence number
+/// it was written with
+///
+/// The index is committed once the entry has been written.
+///
+/// # Errors
+///
+/// Will return `Err` if encoding or writing the entry fails
+pub async fn append(repo: &mut Repository<impl BackendClone>, entry: &AuditEntry) -> Result<u64> {
+    let bytes = rmp_serde::to_vec(entry).map_err(BackendError::from)?;
+    let settings = repo.chunk_settings();
+    let mut sequence = next_sequence(repo).await;
+    loop {
+        let chunk = Chunk::pack_with_id(
+            bytes.clone(),
+            Compression::NoCompression,
+            settings.encryption,
+            settings.hmac,
+            repo.key(),
+            ChunkID::audit_log_id(sequence),
+        );
+        // Someone else got to this sequence number first, try the next one
+        let (_, already_present) = repo.write_raw(chunk).await?;
+        if !already_present {
+            break;
+        }
+        sequence += 1;
+    }
+    repo.commit_index().await;
+    Ok(sequence)
+}
+
+/// Reads every entry in the repository's audit log, oldest first
+///
+/// # Errors
+///
+/// Will return `Err` if an entry can not be read, fails verification, or can not be
+/// decoded
+pub async fn read_log(repo: &mut Repository<impl BackendClone>) -> Result<Vec<AuditEntry>> {
+    let mut entries = Vec::new();
+    for sequence in sequences(repo).await {
+        let bytes = repo.read_chunk(ChunkID::audit_log_id(sequence)).await?;
+        let mut entry: AuditEntry = rmp_serde::from_slice(&bytes).map_err(BackendError::from)?;
+        entry.sequence = sequence;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Returns the sequence number following the last entry in the log
+///
+/// Entries are always written at the first free sequence number, and never removed, so the
+/// log has no gaps. This lets the end be found by probing for entries, rather than listing
+/// every chunk in the repository, which a paged index would have to read in full.
+async fn next_sequence(repo: &Repository<impl BackendClone>) -> u64 {
+    let exists = |sequence| repo.has_chunk(ChunkID::audit_log_id(sequence));
+    if !exists(0).await {
+        return 0;
+    }
+    // Double the range until it runs past the end, then narrow it back down
+    let (mut present, mut missing) = (0, 1);
+    while exists(missing).await {
+        present = missing;
+        missing = missing.saturating_mul(2);
+    }
+    while missing - present > 1 {
+        let middle = present + (missing - present) / 2;
+        if exists(middle).await {
+            present = middle;
+        } else {
+            missing = middle;
+        }
+    }
+    missing
+}
+
+/// Returns the sequence numbers of the entries in the log, in order
+async fn sequences(repo: &Repository<impl BackendClone>) -> Vec<u64> {
+    let mut sequences = repo
+        .known_chunks()
+        .await
+        .into_iter()
+        .filter_map(|id| id.audit_log_sequence())
+        .collect::<Vec<_>>();
+    sequences.sort_unstable();
+    sequences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::{ChunkSettings, Key};
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn append_read_collect() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            assert!(read_log(&mut repo).await.unwrap().is_empty());
+
+            let store = AuditEntry::new(Operation::Store, Some("nightly".to_string()));
+            let check = AuditEntry::new(Operation::Check, None);
+            assert_eq!(append(&mut repo, &store).await.unwrap(), 0);
+            assert_eq!(append(&mut repo, &check).await.unwrap(), 1);
+            for sequence in 2..7 {
+                assert_eq!(append(&mut repo, &check).await.unwrap(), sequence);
+            }
+
+            // Entries are never garbage
+            repo.collect_garbage(&HashSet::new()).await.unwrap();
+            let log = read_log(&mut repo).await.unwrap();
+            assert_eq!(log.len(), 7);
+            assert_eq!(log[0], store);
+            assert_eq!(log[1].operation, Operation::Check);
+            assert_eq!(log[1].sequence, 1);
+            assert!(log[1].client_version.starts_with("asuran "));
+        });
+    }
+}
@@ -4,20 +4,28 @@
                                            // playing nice
 use crate::manifest::StoredArchive;
 use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey};
+use crate::time::Timestamp;
 
 use async_trait::async_trait;
-use chrono::prelude::*;
+use futures::stream::BoxStream;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 pub mod common;
+#[cfg(any(test, feature = "faulty"))]
+pub mod faulty;
 pub mod flatfile;
 pub mod mem;
+pub mod mirror;
 pub mod multifile;
+pub mod remote;
 #[cfg(feature = "sftp")]
 pub mod sftp;
+pub mod sharded;
+#[cfg(feature = "webdav")]
+pub mod webdav;
 
 #[cfg_attr(tarpaulin, skip)]
 pub mod object_wrappers;
@@ -54,6 +62,16 @@ pub enum BackendError {
     ConnectionError(String),
     #[error("FlatFile Format Error: {0}")]
     FlatFile(#[from] asuran_core::repository::backend::flatfile::FlatFileError),
+    #[error("Operation not permitted on an append only repository: {0}")]
+    AppendOnly(String),
+    #[error("Operation not permitted on a repository opened read only: {0}")]
+    ReadOnly(String),
+    #[error("Repository requires a newer client: {0}")]
+    ClientTooOld(String),
+    #[error("Storage quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Operation not supported by this backend: {0}")]
+    Unsupported(String),
     #[error("Unknown Error: {0}")]
     Unknown(String),
 }
@@ -69,6 +87,92 @@ pub struct SegmentDescriptor {
     pub start: u64,
 }
 
+/// Summary of the work performed by `Backend::compact`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct CompactionStats {
+    /// The number of underutilized segments that were rewritten and removed
+    pub segments_removed: usize,
+    /// The number of live chunks that were moved into new segments
+    pub chunks_moved: usize,
+    /// The number of bytes of dead chunk data that were freed
+    pub bytes_reclaimed: u64,
+}
+
+/// Summary of the work performed by `Backend::checkpoint`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct CheckpointStats {
+    /// The number of transactions replaced by the checkpoint
+    pub transactions_squashed: usize,
+    /// The number of archives recorded in the checkpoint
+    pub archives: usize,
+    /// The number of transaction files moved out of the manifest
+    pub files_removed: usize,
+}
+
+/// A branch head of a manifest, as returned by `Backend::heads`
+///
+/// Connections committing at the same time each start their own branch, leaving the
+/// manifest with more than one head until a later commit or merge joins them back up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestHead {
+    /// The tag of the transaction at the head of the branch
+    pub id: common::ManifestID,
+    /// When the transaction at the head of the branch was written
+    pub timestamp: Timestamp,
+    /// The name of the archive the head records, if it records one
+    pub name: Option<String>,
+    /// The number of transactions that can only be reached from this head
+    pub diverged: usize,
+}
+
+/// Summary of the damage found by `Backend::check`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CheckReport {
+    /// The number of chunks that were verified
+    pub chunks_checked: usize,
+    /// Damaged chunks that can be rebuilt from their parity, but were not repaired
+    pub repairable: Vec<SegmentDescriptor>,
+    /// The number of damaged chunks that were rebuilt from their parity
+    pub repaired: usize,
+    /// Damaged chunks that can not be rebuilt
+    pub unrecoverable: Vec<SegmentDescriptor>,
+}
+
+impl CheckReport {
+    /// Returns true if no damage remains in the repository
+    pub fn is_clean(&self) -> bool {
+        self.repairable.is_empty() && self.unrecoverable.is_empty()
+    }
+}
+
+/// How hard a local backend works to get written data onto stable storage
+///
+/// Syncing is by far the most expensive part of writing lots of small chunks, so targets
+/// that do not need to survive a power loss, such as throwaway repositories or disks behind
+/// a battery backed cache, can trade some of this safety away for throughput.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Sync every write before acknowledging it
+    Always,
+    /// Sync everything written so far whenever the index or manifest is committed
+    #[default]
+    OnCommit,
+    /// Never sync, leaving it up to the operating system when data reaches the disk
+    Never,
+}
+
+impl Durability {
+    /// Returns true if data should be synced when the index or manifest is committed
+    pub fn sync_commits(self) -> bool {
+        self != Durability::Never
+    }
+
+    /// Returns true if data should be synced after every write
+    pub fn sync_writes(self) -> bool {
+        self == Durability::Always
+    }
+}
+
 /// Manifest trait
 ///
 /// Keeps track of which archives are in the repository.
@@ -78,7 +182,7 @@ pub struct SegmentDescriptor {
 pub trait Manifest: Send + Sync + std::fmt::Debug + 'static {
     type Iterator: Iterator<Item = StoredArchive> + 'static;
     /// Timestamp of the last modification
-    async fn last_modification(&mut self) -> Result<DateTime<FixedOffset>>;
+    async fn last_modification(&mut self) -> Result<Timestamp>;
     /// Returns the default settings for new chunks in this repository
     async fn chunk_settings(&mut self) -> ChunkSettings;
     /// Returns an iterator over the list of archives in this repository, in reverse chronological
@@ -102,6 +206,14 @@ pub trait Index: Send + Sync + std::fmt::Debug + 'static {
     async fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor>;
     /// Sets the location of a chunk in the repository
     async fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()>;
+    /// Returns true if the chunk is in the index
+    ///
+    /// This is called for every chunk written to the repository, so implementations are
+    /// encouraged to answer it without a full lookup where they can, such as by consulting
+    /// a `ChunkFilter`.
+    async fn contains_chunk(&mut self, id: ChunkID) -> bool {
+        self.lookup_chunk(id).await.is_some()
+    }
     /// Returns the set of all `ChunkID`s known to exist in the Asuran repository.
     async fn known_chunks(&mut self) -> HashSet<ChunkID>;
     /// Commits the index
@@ -145,6 +257,28 @@ pub trait Backend: 'static + Send + Sync + std::fmt::Debug + 'static {
     /// This must be passed owned data because it will be sent into a task, so the caller has no
     /// control over drop time
     async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor>;
+    /// Writes several chunks to the backend, returning their locations in the same order
+    ///
+    /// Backends should override this to write the whole batch with as few round trips and
+    /// appends as they can, the default simply writes the chunks one at a time.
+    async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let mut locations = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            locations.push(self.write_chunk(chunk).await?);
+        }
+        Ok(locations)
+    }
+    /// Writes out any state the backend is buffering, such as segment headers, so that
+    /// every chunk written so far can be read back even if the process is killed before
+    /// `close` is called
+    ///
+    /// This must be called before committing an index that refers to chunks written
+    /// through this handle, if the index is to survive a crash. The default does
+    /// nothing, for backends that do not buffer.
+    #[allow(clippy::unused_async)]
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
     /// Consumes the current backend handle, and does any work necessary to
     /// close out the backend properly
     ///
@@ -154,12 +288,183 @@ pub trait Backend: 'static + Send + Sync + std::fmt::Debug + 'static {
     /// It is not correct to call any methods on a Backend after close has
     /// returned
     async fn close(&mut self);
+    /// Rewrites any segments where the proportion of their space taken up by chunks in
+    /// the index has fallen below `threshold`, moving the live chunks into new segments,
+    /// updating the index, and then removing the old segments.
+    ///
+    /// The index must be committed with the new locations before any segment is
+    /// removed, so interrupting a compaction can leave behind dead space, but never a
+    /// dangling index entry.
+    ///
+    /// Backends that do not store chunks in segments return `Err(Unsupported)`, which
+    /// is the default.
+    #[allow(clippy::unused_async)]
+    async fn compact(&mut self, _threshold: f64) -> Result<CompactionStats> {
+        Err(BackendError::Unsupported("Compaction".to_string()))
+    }
+    /// Verifies every chunk stored in the backend against its HMAC, and against its
+    /// parity if it was written with any.
+    ///
+    /// If `repair` is set, damaged chunks that can be rebuilt from their parity are
+    /// rewritten in place.
+    ///
+    /// Backends that do not store chunks in segments return `Err(Unsupported)`, which
+    /// is the default.
+    #[allow(clippy::unused_async)]
+    async fn check(&mut self, _repair: bool) -> Result<CheckReport> {
+        Err(BackendError::Unsupported("Checking".to_string()))
+    }
+    /// Writes a checkpoint transaction to the manifest, summarizing the current set of
+    /// archives, and then moves every transaction it replaces out of the manifest, so
+    /// that opening and verifying the manifest no longer has to process them.
+    ///
+    /// If `keep_squashed` is set, the replaced transactions are archived alongside the
+    /// manifest as an audit trail, otherwise they are deleted. Either way, the
+    /// checkpoint records the tags of every transaction it replaces.
+    ///
+    /// Backends that do not store their manifest as a log of transactions return
+    /// `Err(Unsupported)`, which is the default.
+    #[allow(clippy::unused_async)]
+    async fn checkpoint(&mut self, _keep_squashed: bool) -> Result<CheckpointStats> {
+        Err(BackendError::Unsupported("Checkpointing".to_string()))
+    }
+    /// Lists the heads of the manifest's branches, newest first
+    ///
+    /// A manifest that has never been written to has no heads, and one that has not
+    /// diverged has exactly one.
+    ///
+    /// Backends that do not store their manifest as a log of transactions return
+    /// `Err(Unsupported)`, which is the default.
+    #[allow(clippy::unused_async)]
+    async fn heads(&mut self) -> Result<Vec<ManifestHead>> {
+        Err(BackendError::Unsupported(
+            "Listing manifest heads".to_string(),
+        ))
+    }
+    /// Joins every head of the manifest into one, by writing a merge transaction that
+    /// follows all of them, returning its tag
+    ///
+    /// Committing an archive also follows every head, so this is only needed to join the
+    /// branches back up without committing anything. Nothing is written, and `None` is
+    /// returned, if the manifest has not diverged.
+    ///
+    /// Backends that do not store their manifest as a log of transactions return
+    /// `Err(Unsupported)`, which is the default.
+    #[allow(clippy::unused_async)]
+    async fn merge_heads(&mut self) -> Result<Option<common::ManifestID>> {
+        Err(BackendError::Unsupported(
+            "Merging manifest heads".to_string(),
+        ))
+    }
+    /// Removes the archives with the given pointers from the manifest, by writing a
+    /// checkpoint that leaves them out, and deleting the transactions it replaces
+    ///
+    /// The chunks the archives refer to are left alone; see `remove_chunks`.
+    ///
+    /// Backends that do not store their manifest as a log of transactions return
+    /// `Err(Unsupported)`, which is the default.
+    #[allow(clippy::unused_async)]
+    async fn remove_archives(&mut self, _archives: HashSet<ChunkID>) -> Result<CheckpointStats> {
+        Err(BackendError::Unsupported("Removing archives".to_string()))
+    }
+    /// Removes the given chunks from the index, returning how many of them it contained
+    ///
+    /// The space taken up by the chunks is not reclaimed until the segments holding them
+    /// are compacted.
+    ///
+    /// Backends that can not remove chunks from their index return `Err(Unsupported)`,
+    /// which is the default.
+    #[allow(clippy::unused_async)]
+    async fn remove_chunks(&mut self, _chunks: HashSet<ChunkID>) -> Result<usize> {
+        Err(BackendError::Unsupported("Removing chunks".to_string()))
+    }
+    /// Returns the pointers of the archives whose references to chunks are included in the
+    /// backend's chunk reference counts, or `None` if it is not keeping reference counts
+    ///
+    /// Reference counts let the chunks only referred to by removed archives be found without
+    /// loading every remaining archive. They are only kept once established by
+    /// `reset_references`, and only cover the archives whose references were added with
+    /// `add_references` since. Any archive in the manifest that is not counted, such as one
+    /// committed by a client that does not keep reference counts, makes them unusable until
+    /// they are established again.
+    ///
+    /// Backends that do not keep reference counts return `Ok(None)`, which is the default.
+    #[allow(clippy::unused_async)]
+    async fn counted_archives(&mut self) -> Result<Option<HashSet<ChunkID>>> {
+        Ok(None)
+    }
+    /// Records that the archive with the pointer `archive` refers to each of `chunks`, adding
+    /// one to each of their reference counts
+    ///
+    /// This is committed along with the index, which must happen before the archive is
+    /// written to the manifest, so a chunk is never referenced by more archives than it is
+    /// counted for. Adding the references of an archive that is already counted does nothing.
+    ///
+    /// Backends that do not keep reference counts, or have not established them, return
+    /// `Err(Unsupported)`, which is the default.
+    #[allow(clippy::unused_async)]
+    async fn add_references(&mut self, _archive: ChunkID, _chunks: HashSet<ChunkID>) -> Result<()> {
+        Err(BackendError::Unsupported("Reference counting".to_string()))
+    }
+    /// Releases the references each of the given archives holds to its chunks, returning the
+    /// chunks that are no longer referenced by any counted archive
+    ///
+    /// `archives` maps the pointer of each archive to the same chunks its references were
+    /// added with. Archives that are not counted are skipped. The change is committed right
+    /// away, the returned chunks are left in the index; see `remove_chunks`.
+    ///
+    /// Backends that do not keep reference counts, or have not established them, return
+    /// `Err(Unsupported)`, which is the default.
+    #[allow(clippy::unused_async)]
+    async fn release_references(
+        &mut self,
+        _archives: HashMap<ChunkID, HashSet<ChunkID>>,
+    ) -> Result<HashSet<ChunkID>> {
+        Err(BackendError::Unsupported("Reference counting".to_string()))
+    }
+    /// Replaces the chunk reference counts with `counts`, which must cover the references of
+    /// exactly `archives`, establishing them if they were not already
+    ///
+    /// This is meant to be called after a full garbage collection, which has to find every
+    /// chunk referenced by the remaining archives anyway.
+    ///
+    /// Backends that can not keep reference counts return `Err(Unsupported)`, which is the
+    /// default.
+    #[allow(clippy::unused_async)]
+    async fn reset_references(
+        &mut self,
+        _archives: HashSet<ChunkID>,
+        _counts: HashMap<ChunkID, u64>,
+    ) -> Result<()> {
+        Err(BackendError::Unsupported("Reference counting".to_string()))
+    }
+    /// Returns a stream of the ID and location of every chunk stored in the backend, read
+    /// directly from the segments rather than from the index
+    ///
+    /// As such, this includes chunks that were written but never added to the index, as
+    /// well as chunks that have been removed from it, but whose space has not been
+    /// reclaimed yet. This is intended for checking, rebuilding, and garbage collecting
+    /// the index.
+    ///
+    /// Chunks are read out one segment at a time, as the stream is polled, so other
+    /// operations may be performed on the backend while it is being consumed.
+    ///
+    /// Backends that do not store chunks in segments return `Err(Unsupported)`, which is
+    /// the default.
+    #[allow(clippy::unused_async)]
+    async fn chunk_descriptors(&mut self) -> Result<ChunkDescriptors> {
+        Err(BackendError::Unsupported("Enumerating chunks".to_string()))
+    }
     /// Creates a new trait-object based BackendHandle
     ///
     /// This is required to implement clone for
     fn get_object_handle(&self) -> BackendObject;
 }
 
+/// A stream of the ID and location of every chunk stored in a backend, as returned by
+/// `Backend::chunk_descriptors`
+pub type ChunkDescriptors = BoxStream<'static, Result<(ChunkID, SegmentDescriptor)>>;
+
 pub trait BackendClone: Backend + Clone {}
 
 impl<T: ?Sized> BackendClone for T where T: Backend + Clone {}
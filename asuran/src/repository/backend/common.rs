@@ -1,11 +1,16 @@
 pub mod files;
+pub mod filter;
 pub mod generic_flatfile;
 pub mod index;
 pub mod manifest;
+pub mod parity;
 pub mod segment;
 pub mod sync_backend;
+pub mod volumes;
 
 pub use files::*;
+pub use filter::*;
 pub use index::*;
 pub use manifest::*;
+pub use parity::*;
 pub use segment::*;
@@ -1,6 +1,8 @@
 use std::fs::{remove_file, File, OpenOptions};
-use std::io::{Read, Result, Seek, Write};
+use std::io::{ErrorKind, Read, Result, Seek, Write};
 use std::ops::{Deref, DerefMut, Drop};
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, BorrowedFd};
 use std::path::{Path, PathBuf};
 
 /// Wraps a file with its paired lock file.
@@ -30,28 +32,44 @@ impl LockedFile {
             "lock".to_string()
         };
         let lock_file_path = path.with_extension(extension);
-        // Check to see if the lock file exists
-        if Path::exists(&lock_file_path) {
+        // Create the lock file, failing if it already exists. This is a single atomic operation,
+        // so two connections can never both believe they hold the lock.
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_file_path)
+        {
+            Ok(_) => (),
             // Unable to return the lock, failing
-            Ok(None)
-        } else {
-            // First, create the lock file
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(&lock_file_path)?;
-            // Second, open the real file
-            let file = OpenOptions::new()
-                .create(true)
-                .read(true)
-                .write(true)
-                .open(&path)?;
-            Ok(Some(LockedFile {
-                file,
-                path,
-                lock_file_path,
-            }))
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(None),
+            Err(e) => return Err(e),
         }
+        // Second, open the real file, giving the lock back up if we can not
+        let file = match OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = remove_file(&lock_file_path);
+                return Err(e);
+            }
+        };
+        Ok(Some(LockedFile {
+            file,
+            path,
+            lock_file_path,
+        }))
+    }
+}
+
+impl LockedFile {
+    /// Returns the path of the locked file
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 }
 
@@ -68,6 +86,13 @@ impl DerefMut for LockedFile {
     }
 }
 
+#[cfg(unix)]
+impl AsFd for LockedFile {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
 impl Drop for LockedFile {
     fn drop(&mut self) {
         // Check to see if the lock file exists before doing anything, if it is already gone (i.e.
@@ -0,0 +1,208 @@
+//! A probabilistic filter over `ChunkID`s
+//!
+//! Checking if a chunk is already in the repository is done for every single chunk that
+//! gets written, and usually requires a round trip to the task that owns the index. The
+//! filter lets index handles answer the common case of a new chunk on the spot, and only
+//! consult the index itself if the chunk might already be present.
+use crate::repository::ChunkID;
+
+use std::sync::{Arc, RwLock};
+
+/// Number of bits set aside per chunk, which with `HASHES` hash functions gives a false
+/// positive rate of roughly 1%
+const BITS_PER_CHUNK: usize = 10;
+/// Number of bits set for each chunk
+const HASHES: u64 = 7;
+/// Capacity of the first layer of an empty filter
+const INITIAL_CAPACITY: usize = 1 << 16;
+
+/// A single, fixed size, bloom filter
+#[derive(Debug, Clone)]
+struct Bloom {
+    bits: Vec<u64>,
+    capacity: usize,
+    len: usize,
+}
+
+impl Bloom {
+    fn new(capacity: usize) -> Bloom {
+        let words = (capacity * BITS_PER_CHUNK).div_ceil(64);
+        Bloom {
+            bits: vec![0; words],
+            capacity,
+            len: 0,
+        }
+    }
+
+    /// Returns the positions of the bits for a chunk
+    ///
+    /// Chunk IDs are already the output of a keyed hash, so their bytes are used directly
+    /// for double hashing.
+    // Positions are reduced modulo the size of the filter, so they always fit in a usize
+    #[allow(clippy::cast_possible_truncation)]
+    fn positions(&self, id: ChunkID) -> impl Iterator<Item = usize> {
+        let bytes = id.get_id();
+        let mut a = [0_u8; 8];
+        let mut b = [0_u8; 8];
+        a.copy_from_slice(&bytes[0..8]);
+        b.copy_from_slice(&bytes[8..16]);
+        let a = u64::from_le_bytes(a);
+        let b = u64::from_le_bytes(b) | 1;
+        let size = (self.bits.len() * 64) as u64;
+        (0..HASHES).map(move |i| (a.wrapping_add(i.wrapping_mul(b)) % size) as usize)
+    }
+
+    fn insert(&mut self, id: ChunkID) {
+        for position in self.positions(id).collect::<Vec<_>>() {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+        self.len += 1;
+    }
+
+    fn may_contain(&self, id: ChunkID) -> bool {
+        self.positions(id)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
+
+/// A scalable bloom filter over `ChunkID`s
+///
+/// Whenever the newest layer of the filter fills up, a new one with twice the capacity is
+/// added, so the filter never has to be rebuilt from the full set of chunks.
+///
+/// The filter never reports a chunk that was inserted as missing, but may report a chunk
+/// that was never inserted as present.
+#[derive(Debug, Clone)]
+pub struct ChunkFilter {
+    layers: Vec<Bloom>,
+}
+
+impl ChunkFilter {
+    /// Creates an empty filter, with room for at least `capacity` chunks before it has to
+    /// grow
+    pub fn with_capacity(capacity: usize) -> ChunkFilter {
+        ChunkFilter {
+            layers: vec![Bloom::new(capacity.max(INITIAL_CAPACITY))],
+        }
+    }
+
+    /// Inserts a chunk into the filter
+    pub fn insert(&mut self, id: ChunkID) {
+        let capacity = match self.layers.last() {
+            Some(last) if last.len < last.capacity => None,
+            Some(last) => Some(last.capacity * 2),
+            None => Some(INITIAL_CAPACITY),
+        };
+        if let Some(capacity) = capacity {
+            self.layers.push(Bloom::new(capacity));
+        }
+        if let Some(layer) = self.layers.last_mut() {
+            layer.insert(id);
+        }
+    }
+
+    /// Returns false if the chunk was definitely never inserted into the filter
+    pub fn may_contain(&self, id: ChunkID) -> bool {
+        self.layers.iter().any(|layer| layer.may_contain(id))
+    }
+}
+
+impl Default for ChunkFilter {
+    fn default() -> ChunkFilter {
+        ChunkFilter::with_capacity(INITIAL_CAPACITY)
+    }
+}
+
+/// A `ChunkFilter` shared between the handles to an index, and the task that owns it
+///
+/// Until the filter has been populated, every chunk is reported as possibly present.
+#[derive(Debug, Clone, Default)]
+pub struct SharedChunkFilter(Arc<RwLock<Option<ChunkFilter>>>);
+
+impl SharedChunkFilter {
+    /// Fills the filter with the chunks already in the index
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a thread panicked while holding the filter's lock.
+    pub fn populate(&self, ids: impl ExactSizeIterator<Item = ChunkID>) {
+        let mut filter = ChunkFilter::with_capacity(ids.len() * 2);
+        for id in ids {
+            filter.insert(id);
+        }
+        self.replace(Some(filter));
+    }
+
+    /// Replaces the filter with one that was filled elsewhere, or with nothing, in which case
+    /// every chunk is reported as possibly present again
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a thread panicked while holding the filter's lock.
+    pub fn replace(&self, filter: Option<ChunkFilter>) {
+        *self.0.write().expect("Chunk filter lock poisoned") = filter;
+    }
+
+    /// Records a chunk that was added to the index
+    ///
+    /// This must be done before the addition is reported to whoever made it.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a thread panicked while holding the filter's lock.
+    pub fn insert(&self, id: ChunkID) {
+        if let Some(filter) = self.0.write().expect("Chunk filter lock poisoned").as_mut() {
+            filter.insert(id);
+        }
+    }
+
+    /// Returns false if the chunk is definitely not in the index
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a thread panicked while holding the filter's lock.
+    pub fn may_contain(&self, id: ChunkID) -> bool {
+        self.0
+            .read()
+            .expect("Chunk filter lock poisoned")
+            .as_ref()
+            .is_none_or(|filter| filter.may_contain(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut filter = ChunkFilter::with_capacity(0);
+        let ids: Vec<ChunkID> = (0..200_000).map(|_| ChunkID::random_id()).collect();
+        for id in &ids {
+            filter.insert(*id);
+        }
+        // The filter had to grow past its initial capacity
+        assert!(filter.layers.len() > 1);
+        assert!(ids.iter().all(|id| filter.may_contain(*id)));
+
+        let false_positives = (0..100_000)
+            .filter(|_| filter.may_contain(ChunkID::random_id()))
+            .count();
+        assert!(
+            false_positives < 5_000,
+            "{} false positives",
+            false_positives
+        );
+    }
+
+    #[test]
+    fn unpopulated_shared_filter() {
+        let filter = SharedChunkFilter::default();
+        let id = ChunkID::random_id();
+        assert!(filter.may_contain(id));
+        filter.populate(Vec::new().into_iter());
+        assert!(!filter.may_contain(id));
+        filter.insert(id);
+        assert!(filter.may_contain(id));
+    }
+}
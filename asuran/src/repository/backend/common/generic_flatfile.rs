@@ -45,36 +45,62 @@
 //!     following `Chunk`, then the serialized `EntryFooterData` struct, wrapped and
 //!     encrypted/compressed in a `Chunk`
 //!
+//! A `FlatFile` repository is in append only mode if the `EntryFooterData` of any of its
+//! entries has the `append_only` flag set.
+//!
+//! A `FlatFile` opened with `new_read_only` never writes to its file, and refuses every
+//! operation that would.
+//!
 //! `FlatFile` repositories are always terminated with an `EntryHeader` with the
 //! `footer_offset` and `next_header_offset` set to 0. This is intended to be
 //! overridden during the next writing session.
+//!
+//! # Streamed `FlatFile`s
+//!
+//! A `FlatFile` opened with `new_streaming` is written strictly sequentially, so it can
+//! be written to a pipe or tape device, which can not be seeked back to update the header
+//! of the entry. Instead, the header of its single entry has both offsets set to
+//! `EntryHeader::STREAMED`, and the terminating `EntryHeader` is followed by a
+//! `StreamTail`, which records the real offsets.
+//!
+//! When reading, the offsets are taken from the `StreamTail`. The first time a streamed
+//! `FlatFile` is written to after that, the header of the entry is patched with the real
+//! offsets, after which the file is an ordinary `FlatFile`.
 use super::sync_backend::{SyncBackend, SyncIndex, SyncManifest};
+use crate::manifest::ArchiveMetadata;
 use crate::repository::backend::{
     BackendError, Chunk, ChunkID, ChunkSettings, EncryptedKey, Result, SegmentDescriptor,
     StoredArchive,
 };
 use crate::repository::Key;
+use crate::time::Timestamp;
 use asuran_core::repository::backend::flatfile::{
-    EntryFooter, EntryFooterData, EntryHeader, FlatFileHeader,
+    EntryFooter, EntryFooterData, EntryHeader, FlatFileError, FlatFileHeader, StreamTail,
 };
 use asuran_core::repository::chunk::{ChunkBody, ChunkHeader};
 
-use chrono::{DateTime, FixedOffset};
-
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fmt::Debug;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
 pub use asuran_core::repository::backend::flatfile::MAGIC_NUMBER;
 
+/// Written after chunks with empty bodies, so that each chunk starts at a distinct offset
+///
+/// Chunks are located by their offset alone, so without this an empty chunk would share
+/// its location with the chunk after it. The padding is not part of the recorded length.
+const EMPTY_BODY_PADDING: [u8; 1] = [0];
+
 /// A view over a generic `FlatFile` backend.
 ///
 /// This generic backend can accept any (owned) `Read + Write + Seek`, and will
 /// implement the same binary format on top of it.
 ///
 /// See module level documentation for details.
+#[allow(clippy::struct_excessive_bools)]
 pub struct GenericFlatFile<F: Read + Write + Seek + 'static> {
     file: F,
     path: PathBuf,
@@ -88,6 +114,12 @@ pub struct GenericFlatFile<F: Read + Write + Seek + 'static> {
     key: Key,
     chunk_headers: HashMap<SegmentDescriptor, ChunkHeader>,
     header_offset: u64,
+    append_only: bool,
+    read_only: bool,
+    /// True while writing a streamed entry, whose footer is deferred until `finish_stream`
+    streaming: bool,
+    /// The offset of a streamed entry header that still needs its real offsets written in
+    streamed_header: Option<(u64, EntryHeader)>,
 }
 
 impl<F: Read + Write + Seek + 'static> Debug for GenericFlatFile<F> {
@@ -164,6 +196,10 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
                 key,
                 chunk_headers: HashMap::new(),
                 header_offset: header_location,
+                append_only: false,
+                read_only: false,
+                streaming: false,
+                streamed_header: None,
             };
             Ok(flat_file)
         } else {
@@ -178,61 +214,21 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
                 ));
             }
             let enc_key = global_header.key()?;
-            // Extract the first entry header
-            let mut header_offset = file.seek(SeekFrom::Current(0))?;
-            let mut entry_header = EntryHeader::from_read(&mut file)?;
-            // Create a place to put the chunk settings
-            let mut chunk_settings: Option<ChunkSettings> = None;
-            // Places to put our stuff
-            let mut index = HashMap::new();
-            let mut length_map = HashMap::new();
-            let mut manifest = Vec::new();
-            let mut chunk_headers = HashMap::new();
-            // Parse all the headers and footers
-            while entry_header.footer_offset != 0 && entry_header.next_header_offset != 0 {
-                // Read the associated footer
-                file.seek(SeekFrom::Start(entry_header.footer_offset))?;
-                let footer = EntryFooter::from_read(&mut file)?.into_data(&key)?;
-                // Update the chunk settings
-                chunk_settings = Some(footer.chunk_settings);
-                // Parse the chunk locations into segment descriptors
-                for (id, start, length) in footer.chunk_locations {
-                    let descriptor = SegmentDescriptor {
-                        segment_id: 0,
-                        start,
-                    };
-                    // load that into our index
-                    index.insert(id, descriptor);
-                    // load it into our length map
-                    length_map.insert(descriptor, length);
-                    // load the header and put it into the map
-                    // TODO: move out of the map instead of clone
-                    let header = footer
-                        .chunk_headers
-                        .get(&id)
-                        .ok_or_else(|| {
-                            BackendError::IndexError(format!(
-                                "Chunk with id {:?} did not have an associated header.",
-                                id
-                            ))
-                        })?
-                        .clone();
-                    chunk_headers.insert(descriptor, header);
-                }
-
-                // Load any archives
-                for (id, timestamp) in footer.archives {
-                    // Temporary hack, the name field is pending removal
-                    manifest.push(StoredArchive {
-                        id,
-                        name: "".to_string(),
-                        timestamp,
-                    });
-                }
-
-                // Load up the next header
-                header_offset = file.seek(SeekFrom::Start(entry_header.next_header_offset))?;
-                entry_header = EntryHeader::from_read(&mut file)?;
+            // Parse all the headers and footers, starting with the first entry header
+            let header_offset = file.stream_position()?;
+            let Entries {
+                chunk_settings,
+                index,
+                length_map,
+                manifest,
+                chunk_headers,
+                append_only,
+                header_offset,
+                streamed_header,
+                damage,
+            } = Self::read_entries(&mut file, &key, header_offset)?;
+            if let Some(damage) = damage {
+                return Err(damage);
             }
             // If we haven't set chunk settings yet, we have an invalid repository
             let chunk_settings = chunk_settings.ok_or_else(|| {
@@ -255,12 +251,405 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
                 key,
                 chunk_headers,
                 header_offset,
+                append_only,
+                read_only: false,
+                streaming: false,
+                streamed_header,
             };
 
             Ok(flat_file)
         }
     }
 
+    /// Opens up an existing repository over the provided `Read + Write + Seek`, without ever
+    /// writing to it
+    ///
+    /// Every operation that would modify the repository is refused with `Err(ReadOnly)`, so
+    /// the underlying file may be opened read only.
+    ///
+    /// # Errors
+    ///
+    /// - If the `file` is empty, as there is no repository to read, `Err(ManifestError)`
+    /// - Any of the errors `new_raw` returns for an already initialized repository
+    pub fn new_read_only(
+        mut file: F,
+        path: impl AsRef<Path>,
+        key: Key,
+    ) -> Result<GenericFlatFile<F>> {
+        if file.seek(SeekFrom::End(0))? == 0 {
+            return Err(BackendError::ManifestError(format!(
+                "Attempted to open the empty FlatFile at {} read only",
+                path.as_ref().display()
+            )));
+        }
+        let mut flat_file = Self::new_raw(file, path, None, key, None)?;
+        flat_file.read_only = true;
+        Ok(flat_file)
+    }
+
+    /// Starts a new repository, written strictly sequentially to the provided `file`
+    ///
+    /// This is intended for use with a `SequentialWriter`, over a writer that can not be
+    /// seeked, such as a pipe. Nothing is ever read back from, or seeked backwards in,
+    /// the `file`, so chunks can not be read back from the repository while it is being
+    /// written. The footer of the entry is only written out by `finish_stream`, which is
+    /// also called on drop, until then, committing the index does nothing.
+    ///
+    /// See the module level documentation for the layout of the resulting file.
+    ///
+    /// # Errors
+    ///
+    /// - If an underlying I/O error occurs
+    /// - If the `file` is not empty, `Err(ManifestError)`
+    /// - If encoding the encrypted key fails
+    pub fn new_streaming(
+        mut file: F,
+        path: impl AsRef<Path>,
+        settings: ChunkSettings,
+        key: Key,
+        enc_key: EncryptedKey,
+    ) -> Result<GenericFlatFile<F>> {
+        if file.seek(SeekFrom::End(0))? != 0 {
+            return Err(BackendError::ManifestError(format!(
+                "Attempted to stream a FlatFile into the non-empty file at {}",
+                path.as_ref().display()
+            )));
+        }
+        FlatFileHeader::new(&enc_key)?.to_write(&mut file)?;
+        let header_location = file.seek(SeekFrom::End(0))?;
+        EntryHeader::new(
+            &crate::VERSION_STRUCT,
+            EntryHeader::STREAMED,
+            EntryHeader::STREAMED,
+            *crate::IMPLEMENTATION_UUID,
+        )?
+        .to_write(&mut file)?;
+
+        Ok(GenericFlatFile {
+            file,
+            path: path.as_ref().to_owned(),
+            chunk_settings: settings,
+            index: HashMap::new(),
+            length_map: HashMap::new(),
+            manifest: Vec::new(),
+            entry_footer_data: EntryFooterData::new(settings),
+            chunk_settings_modified: true,
+            enc_key,
+            key,
+            chunk_headers: HashMap::new(),
+            header_offset: header_location,
+            append_only: false,
+            read_only: false,
+            streaming: true,
+            streamed_header: None,
+        })
+    }
+
+    /// Writes out the footer of a streamed entry, followed by the terminating header and the
+    /// `StreamTail`, and flushes the `file`
+    ///
+    /// Nothing more can be written to the repository afterwards. This does nothing if the
+    /// repository was not opened with `new_streaming`, or the stream was already finished.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an underlying I/O error occurs
+    pub fn finish_stream(&mut self) -> Result<()> {
+        if !self.streaming {
+            return Ok(());
+        }
+        self.streaming = false;
+        // The stream can not be appended to once its tail is written
+        self.read_only = true;
+        let (footer_offset, next_header_offset) = self.write_footer()?;
+        StreamTail {
+            footer_offset,
+            next_header_offset,
+        }
+        .to_write(Write::by_ref(&mut self.file))?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Returns true if this repository is in append only mode
+    pub fn append_only(&self) -> bool {
+        self.append_only
+    }
+
+    /// Returns true if this repository was opened read only
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns `Err(ReadOnly)`, describing the refused `operation`, if this repository was
+    /// opened read only
+    fn check_writable(&self, operation: &str) -> Result<()> {
+        if self.read_only {
+            Err(BackendError::ReadOnly(operation.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes the real offsets into the header of a streamed entry, if this repository was
+    /// streamed and that has not happened yet
+    ///
+    /// This must happen before anything is appended to the file, as that moves the
+    /// `StreamTail` away from the end of the file, where readers look for it.
+    fn patch_streamed_header(&mut self) -> Result<()> {
+        if let Some((offset, header)) = self.streamed_header.take() {
+            self.file.seek(SeekFrom::Start(offset))?;
+            header.to_write(Write::by_ref(&mut self.file))?;
+        }
+        Ok(())
+    }
+
+    /// Swaps out the `EntryFooterData`, and writes it to the end of the file, followed by
+    /// a new, blank header
+    ///
+    /// Returns the offsets of the footer and of the new header.
+    fn write_footer(&mut self) -> Result<(u64, u64)> {
+        // Reset the chunk_settings_modified flag
+        self.chunk_settings_modified = false;
+        // Make a new footer and swap it out
+        let mut footer = EntryFooterData::new(self.chunk_settings);
+        std::mem::swap(&mut self.entry_footer_data, &mut footer);
+        footer.append_only = self.append_only;
+        // Pack the footer up
+        let footer = EntryFooter::from_data(&footer, &self.key, self.chunk_settings);
+        // seek to the end of the file
+        let file = &mut self.file;
+        let footer_location = file.seek(SeekFrom::End(0))?;
+        // Write the footer
+        footer.to_write(Write::by_ref(file))?;
+        // Write a new, blank header
+        let header_location = file.seek(SeekFrom::End(0))?;
+        EntryHeader::new(&crate::VERSION_STRUCT, 0, 0, *crate::IMPLEMENTATION_UUID)?
+            .to_write(Write::by_ref(file))?;
+        Ok((footer_location, header_location))
+    }
+
+    /// Returns a reference to the underlying `Read + Write + Seek`
+    pub fn get_ref(&self) -> &F {
+        &self.file
+    }
+
+    /// Puts this repository in append only mode
+    ///
+    /// With this set, the chunk settings can not be changed, and chunks already in the index can
+    /// not be moved. The change will be persisted on the next commit, and can not be undone.
+    pub fn set_append_only(&mut self) {
+        if !self.append_only {
+            self.append_only = true;
+            // The flag lives in the entry footer alongside the chunk settings, so piggyback on
+            // their dirty flag to ensure it gets written
+            self.chunk_settings_modified = true;
+        }
+    }
+
+    /// Walks the entries of a repository file, starting with the entry header at
+    /// `header_offset`, and collects everything described by their footers
+    ///
+    /// An entry is only taken into account once its footer, and the header following
+    /// it, have been read and checked. If an entry turns out to be damaged, the walk
+    /// stops there, and the error is returned in `damage`, along with everything from
+    /// the intact entries before it.
+    ///
+    /// The offsets of a streamed entry are taken from the `StreamTail` at the end of the
+    /// file, and its header is returned in `streamed_header`, to be patched later.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an underlying I/O error occurs
+    fn read_entries(file: &mut F, key: &Key, mut header_offset: u64) -> Result<Entries> {
+        let file_length = file.seek(SeekFrom::End(0))?;
+        let mut entries = Entries::default();
+        loop {
+            file.seek(SeekFrom::Start(header_offset))?;
+            let mut entry_header = match EntryHeader::from_read(Read::by_ref(file)) {
+                Ok(entry_header) => entry_header,
+                Err(e) => {
+                    entries.damage = Some(e.into());
+                    break;
+                }
+            };
+            if entry_header.is_streamed() {
+                let tail = file_length
+                    .checked_sub(StreamTail::LENGTH)
+                    .ok_or(BackendError::FlatFile(FlatFileError::MissingStreamTail))
+                    .and_then(|offset| {
+                        file.seek(SeekFrom::Start(offset))?;
+                        Ok(StreamTail::from_read(Read::by_ref(file))?)
+                    });
+                match tail {
+                    Ok(tail) => {
+                        entry_header.footer_offset = tail.footer_offset;
+                        entry_header.next_header_offset = tail.next_header_offset;
+                        entries.streamed_header = Some((header_offset, entry_header));
+                    }
+                    Err(e) => {
+                        entries.damage = Some(e);
+                        break;
+                    }
+                }
+            }
+            // The last entry header is left blank
+            if entry_header.footer_offset == 0 || entry_header.next_header_offset == 0 {
+                break;
+            }
+            let footer = match Self::read_footer(file, key, &entry_header, file_length) {
+                Ok(footer) => footer,
+                Err(e) => {
+                    entries.damage = Some(e);
+                    break;
+                }
+            };
+            // Update the chunk settings
+            entries.chunk_settings = Some(footer.chunk_settings);
+            // Append only mode, once set, is never unset
+            entries.append_only |= footer.append_only;
+            // Parse the chunk locations into segment descriptors, and load them into the
+            // index, the length map, and the header map
+            for (id, start, length) in footer.chunk_locations {
+                let descriptor = SegmentDescriptor {
+                    segment_id: 0,
+                    start,
+                };
+                entries.index.insert(id, descriptor);
+                entries.length_map.insert(descriptor, length);
+                if let Some(header) = footer.chunk_headers.get(&id) {
+                    entries.chunk_headers.insert(descriptor, header.clone());
+                }
+            }
+            // Load any archives
+            for (id, timestamp) in footer.archives {
+                // Temporary hack, the name field is pending removal
+                entries.manifest.push(StoredArchive {
+                    id,
+                    name: String::new(),
+                    timestamp,
+                    metadata: ArchiveMetadata::default(),
+                    integrity: None,
+                    signature: None,
+                    duration: None,
+                });
+            }
+            header_offset = entry_header.next_header_offset;
+        }
+        entries.header_offset = header_offset;
+        Ok(entries)
+    }
+
+    /// Reads and decodes the footer of the entry with the given header, making sure it,
+    /// and every chunk it describes, actually fits in the file
+    ///
+    /// # Errors
+    ///
+    /// - If the footer, or any of its chunks, runs past the end of its entry
+    /// - If decoding the footer fails
+    /// - If any of the chunks described by the footer do not have an associated
+    ///   `ChunkHeader`
+    fn read_footer(
+        file: &mut F,
+        key: &Key,
+        entry_header: &EntryHeader,
+        file_length: u64,
+    ) -> Result<EntryFooterData> {
+        let footer_offset = entry_header.footer_offset;
+        let next_header_offset = entry_header.next_header_offset;
+        if footer_offset >= next_header_offset || next_header_offset > file_length {
+            return Err(BackendError::IndexError(format!(
+                "Entry footer at {footer_offset} and next header at {next_header_offset} do not fit in a file of {file_length} bytes"
+            )));
+        }
+        // Check the length of the footer before reading it, so a garbage length does not
+        // get allocated
+        file.seek(SeekFrom::Start(footer_offset))?;
+        let mut length = [0_u8; 8];
+        file.read_exact(&mut length)?;
+        let length = u64::from_be_bytes(length);
+        if length
+            .checked_add(8)
+            .is_none_or(|end| end > next_header_offset - footer_offset)
+        {
+            return Err(BackendError::IndexError(format!(
+                "Entry footer at {footer_offset} runs past the next header"
+            )));
+        }
+        file.seek(SeekFrom::Start(footer_offset))?;
+        let footer = EntryFooter::from_read(Read::by_ref(file))?.into_data(key)?;
+        for (id, start, length) in &footer.chunk_locations {
+            if !footer.chunk_headers.contains_key(id) {
+                return Err(BackendError::IndexError(format!(
+                    "Chunk with id {id:?} did not have an associated header."
+                )));
+            }
+            if start
+                .checked_add(*length)
+                .is_none_or(|end| end > footer_offset)
+            {
+                return Err(BackendError::IndexError(format!(
+                    "Chunk with id {id:?} runs past the footer of its entry"
+                )));
+            }
+        }
+        Ok(footer)
+    }
+
+    /// Looks for a torn tail in the provided repository file, as left behind by a crash
+    /// part of the way through writing an entry
+    ///
+    /// Returns the offset of the header of the torn entry, which is the end of the last
+    /// intact entry, or `None` if every entry is intact. Truncating the file to this
+    /// offset, and terminating it with `terminate_at`, leaves a repository containing
+    /// everything in the intact entries.
+    ///
+    /// # Errors
+    ///
+    /// - If an underlying I/O error occurs
+    /// - If the initial header of the file is damaged
+    /// - If a damaged entry is followed by another complete entry. A crash can only tear
+    ///   the last entry, so this is not a torn tail, and truncating the file would throw
+    ///   away intact data.
+    pub fn find_torn_tail(file: &mut F, key: &Key) -> Result<Option<u64>> {
+        file.seek(SeekFrom::Start(0))?;
+        FlatFileHeader::from_read(Read::by_ref(file))?;
+        let header_offset = file.stream_position()?;
+        let entries = Self::read_entries(file, key, header_offset)?;
+        let Some(damage) = entries.damage else {
+            return Ok(None);
+        };
+        // Look for a complete entry after the damaged one
+        file.seek(SeekFrom::Start(entries.header_offset))?;
+        if let Ok(entry_header) = EntryHeader::from_read(Read::by_ref(file)) {
+            if entry_header.next_header_offset > entries.header_offset {
+                file.seek(SeekFrom::Start(entry_header.next_header_offset))?;
+                if let Ok(next_header) = EntryHeader::from_read(Read::by_ref(file)) {
+                    if next_header.footer_offset != 0 && next_header.next_header_offset != 0 {
+                        return Err(BackendError::IndexError(format!(
+                            "Entry at {} is damaged, but is followed by further entries: {}",
+                            entries.header_offset, damage
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(Some(entries.header_offset))
+    }
+
+    /// Writes a blank entry header, which terminates a repository file, at the given
+    /// offset
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an underlying I/O error occurs
+    pub fn terminate_at(file: &mut F, offset: u64) -> Result<()> {
+        file.seek(SeekFrom::Start(offset))?;
+        EntryHeader::new(&crate::VERSION_STRUCT, 0, 0, *crate::IMPLEMENTATION_UUID)?
+            .to_write(Write::by_ref(file))?;
+        Ok(())
+    }
+
     /// Attempts to read an `EncryptedKey` from the header of the provided repository
     /// file
     ///
@@ -275,6 +664,78 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
     }
 }
 
+/// Adapts a `Write` that can not be seeked, such as a pipe, for use with
+/// `GenericFlatFile::new_streaming`
+///
+/// This only keeps track of how many bytes have been written. Seeks that do not move away
+/// from the current position are answered with it, and every other seek, as well as every
+/// read, is refused with an error.
+#[derive(Debug)]
+pub struct SequentialWriter<W: Write> {
+    inner: W,
+    position: u64,
+}
+
+impl<W: Write> SequentialWriter<W> {
+    /// Wraps the provided `Write`, treating the first byte written to it as offset 0
+    pub fn new(inner: W) -> SequentialWriter<W> {
+        SequentialWriter { inner, position: 0 }
+    }
+
+    /// Returns a reference to the underlying `Write`
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+}
+
+impl<W: Write> Write for SequentialWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Read for SequentialWriter<W> {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::other(
+            "Reading is not supported by a sequential writer",
+        ))
+    }
+}
+
+impl<W: Write> Seek for SequentialWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Start(offset) if offset == self.position => Ok(self.position),
+            SeekFrom::End(0) | SeekFrom::Current(0) => Ok(self.position),
+            _ => Err(io::Error::other(
+                "Seeking is not supported by a sequential writer",
+            )),
+        }
+    }
+}
+
+/// Everything collected from the intact entries of a repository file
+#[derive(Default)]
+struct Entries {
+    chunk_settings: Option<ChunkSettings>,
+    index: HashMap<ChunkID, SegmentDescriptor>,
+    length_map: HashMap<SegmentDescriptor, u64>,
+    manifest: Vec<StoredArchive>,
+    chunk_headers: HashMap<SegmentDescriptor, ChunkHeader>,
+    append_only: bool,
+    /// The offset of the entry header following the last intact entry
+    header_offset: u64,
+    /// The offset of a streamed entry header, along with its real offsets
+    streamed_header: Option<(u64, EntryHeader)>,
+    /// The error encountered reading the entry at `header_offset`, if it was damaged
+    damage: Option<BackendError>,
+}
+
 impl<F: Read + Write + Seek + 'static> SyncManifest for GenericFlatFile<F> {
     type Iterator = std::vec::IntoIter<StoredArchive>;
     /// Assumes archives were written in chronological order, and returns the timestamp
@@ -283,7 +744,7 @@ impl<F: Read + Write + Seek + 'static> SyncManifest for GenericFlatFile<F> {
     /// # Errors
     ///
     /// Will return `Err` if there are no archives in this repository
-    fn last_modification(&mut self) -> Result<DateTime<FixedOffset>> {
+    fn last_modification(&mut self) -> Result<Timestamp> {
         if self.manifest.is_empty() {
             Err(BackendError::ManifestError(
                 "No archives/timestamps present".to_string(),
@@ -301,18 +762,32 @@ impl<F: Read + Write + Seek + 'static> SyncManifest for GenericFlatFile<F> {
     /// `EntryFooterData`. Additionally sets the dirty flag on the chunk settings, so if
     /// only the chunk settings were modified, this change will still get persisted to
     /// the repository.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(AppendOnly)` if this repository is append only, and `Err(ReadOnly)` if it
+    /// was opened read only
     fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
+        self.check_writable("Attempted to rewrite the chunk settings")?;
+        if self.append_only {
+            return Err(BackendError::AppendOnly(
+                "Attempted to rewrite the chunk settings".to_string(),
+            ));
+        }
         self.chunk_settings = settings;
         self.entry_footer_data.chunk_settings = settings;
         self.chunk_settings_modified = true;
         Ok(())
     }
-    /// Clones the cached `manifest` `Vec` and turns it into an iterator
+    /// Clones the cached `manifest` `Vec`, newest archive first, and turns it into an iterator
     fn archive_iterator(&mut self) -> Self::Iterator {
-        self.manifest.clone().into_iter()
+        let mut archives = self.manifest.iter().rev().cloned().collect::<Vec<_>>();
+        archives.sort_by_key(|archive| Reverse(archive.timestamp()));
+        archives.into_iter()
     }
     /// Adds the archive to the cached `manifest` `Vec`, as well as to the `EntryFooterData`
     fn write_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        self.check_writable("Attempted to add an archive to the manifest")?;
         self.entry_footer_data
             .add_archive(archive.id, archive.timestamp);
         self.manifest.push(archive);
@@ -336,7 +811,24 @@ impl<F: Read + Write + Seek + 'static> SyncIndex for GenericFlatFile<F> {
     ///
     /// Will return `Err` if the `Chunk` had not been previously written with
     /// `write_chunk`, and thus has an unknown length.
+    ///
+    /// Will return `Err(AppendOnly)` if this repository is append only, and the
+    /// chunk is already known to be at a different location.
+    ///
+    /// Will return `Err(ReadOnly)` if this repository was opened read only.
     fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
+        self.check_writable("Attempted to set the location of a chunk")?;
+        if self.append_only {
+            match self.index.get(&id) {
+                Some(existing) if *existing == location => return Ok(()),
+                Some(existing) => {
+                    return Err(BackendError::AppendOnly(format!(
+                        "Attempted to move chunk {id:?} from {existing:?} to {location:?}"
+                    )))
+                }
+                None => (),
+            }
+        }
         let length = self.length_map.get(&location).ok_or_else(|| {
             BackendError::IndexError(format!(
                 "Attempted to add chunk with id {:?} to the index, whose length was not known",
@@ -353,26 +845,19 @@ impl<F: Read + Write + Seek + 'static> SyncIndex for GenericFlatFile<F> {
         self.index.keys().copied().collect()
     }
     /// Flush the `EntryFooterDisk` to disk and make a new one
+    ///
+    /// While streaming, this does nothing, as the footer can only be written once, by
+    /// `finish_stream`.
     fn commit_index(&mut self) -> Result<()> {
+        if self.streaming {
+            return Ok(());
+        }
         // First check and see if we need to do anything
         if self.chunk_settings_modified || self.entry_footer_data.dirty() {
-            // Reset the chunk_settings_modified flag
-            self.chunk_settings_modified = false;
-            // Make a new footer and swap it out
-            let mut footer = EntryFooterData::new(self.chunk_settings);
-            std::mem::swap(&mut self.entry_footer_data, &mut footer);
-            // Pack the footer up
-            let footer = EntryFooter::from_data(&footer, &self.key, self.chunk_settings);
-            // seek to the end of the file
-            let file = &mut self.file;
-            let footer_location = file.seek(SeekFrom::End(0))?;
-            // Write the footer
-            footer.to_write(Write::by_ref(file))?;
-            // Write a new, blank header
-            let header_location = file.seek(SeekFrom::End(0))?;
-            EntryHeader::new(&*crate::VERSION_STRUCT, 0, 0, *crate::IMPLEMENTATION_UUID)?
-                .to_write(Write::by_ref(file))?;
+            self.patch_streamed_header()?;
+            let (footer_location, header_location) = self.write_footer()?;
             // Go back and update the previous header
+            let file = &mut self.file;
             file.seek(SeekFrom::Start(self.header_offset))?;
             EntryHeader::new(
                 &*crate::VERSION_STRUCT,
@@ -409,8 +894,8 @@ impl<F: Read + Write + Seek + 'static> SyncBackend for GenericFlatFile<F> {
     /// This operation is not currently supported for `FlatFile` repositories, so we just return an
     /// error
     fn write_key(&mut self, _key: EncryptedKey) -> Result<()> {
-        Err(BackendError::Unknown(
-            "Changing the key of a FlatFile repository is not supported at this time.".to_string(),
+        Err(BackendError::Unsupported(
+            "Changing the key of a FlatFile repository".to_string(),
         ))
     }
     /// Return the cached `EncryptedKey`
@@ -427,6 +912,11 @@ impl<F: Read + Write + Seek + 'static> SyncBackend for GenericFlatFile<F> {
     ///   before, or it has not been written with `write_chunk`
     /// - If the header is not present in the `chunk_headers` map
     fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        if self.streaming {
+            return Err(BackendError::Unsupported(
+                "Reading chunks back while streaming a FlatFile".to_string(),
+            ));
+        }
         // Find the start of its chunk, and lookup its length
         let start = location.start;
         let length = *self.length_map.get(&location).ok_or_else(|| {
@@ -462,6 +952,8 @@ impl<F: Read + Write + Seek + 'static> SyncBackend for GenericFlatFile<F> {
         Ok(chunk)
     }
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        self.check_writable("Attempted to write a chunk")?;
+        self.patch_streamed_header()?;
         let id = chunk.get_id();
         // Seek to the end of the file and record that location
         let file = &mut self.file;
@@ -484,15 +976,76 @@ impl<F: Read + Write + Seek + 'static> SyncBackend for GenericFlatFile<F> {
         self.chunk_headers.insert(descriptor, header);
         // Write the chunk to the file
         file.write_all(&body.0[..])?;
+        if length == 0 {
+            file.write_all(&EMPTY_BODY_PADDING)?;
+        }
 
         Ok(descriptor)
     }
+    fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        self.check_writable("Attempted to write chunks")?;
+        self.patch_streamed_header()?;
+        // Lay the chunks out back to back, and write them all in one go
+        let end = self.file.seek(SeekFrom::End(0))?;
+        let mut buffer = Vec::new();
+        let mut descriptors = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            let id = chunk.get_id();
+            let location = end + buffer.len() as u64;
+            let (header, body) = chunk.split();
+            let length = body.0.len() as u64;
+            let descriptor = SegmentDescriptor {
+                segment_id: 0,
+                start: location,
+            };
+            self.length_map.insert(descriptor, length);
+            self.entry_footer_data.add_chunk(id, location, length);
+            self.entry_footer_data.add_header(id, header.clone());
+            self.chunk_headers.insert(descriptor, header);
+            buffer.extend_from_slice(&body.0[..]);
+            if length == 0 {
+                buffer.extend_from_slice(&EMPTY_BODY_PADDING);
+            }
+            descriptors.push(descriptor);
+        }
+        self.file.write_all(&buffer[..])?;
+
+        Ok(descriptors)
+    }
+    /// The whole file is treated as a single segment, with id 0
+    fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        Ok(vec![0])
+    }
+    /// Lists every chunk with a header in the entry footers read so far, or written since,
+    /// in the order they appear in the file
+    fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        if segment_id != 0 {
+            return Err(BackendError::SegmentError(format!(
+                "FlatFile repositories only have segment 0, not {segment_id}"
+            )));
+        }
+        let mut descriptors: Vec<_> = self
+            .chunk_headers
+            .iter()
+            .map(|(location, header)| (header.id(), *location))
+            .collect();
+        descriptors.sort_unstable_by_key(|(_, location)| location.start);
+        Ok(descriptors)
+    }
 }
 
 impl<T: Read + Write + Seek + 'static> Drop for GenericFlatFile<T> {
     fn drop(&mut self) {
-        // Attempt to commit the index before dropping, if committing fails, panic
-        let res = self.commit_index();
+        // Attempt to commit the index, or finish the stream, before dropping, if that fails,
+        // panic
+        let res = if self.streaming {
+            self.finish_stream()
+        } else {
+            self.commit_index()
+        };
         if res.is_err() && !std::thread::panicking() {
             panic!(
                 "Failed to commit index during drop. Path was {:?}",
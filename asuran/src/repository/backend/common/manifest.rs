@@ -1,23 +1,45 @@
+use crate::manifest::integrity::ChunkIntegrity;
+use crate::manifest::signing::ArchiveSignature;
+use crate::manifest::{ArchiveMetadata, StoredArchive};
 use crate::repository::{ChunkID, Key, HMAC};
+use crate::time::Timestamp;
 
-use chrono::prelude::*;
 use rand::prelude::*;
 use rmp_serde as rmps;
-use serde::{Deserialize, Serialize};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::time::Duration;
 
 /// Wrapper around [u8; 32] used for transaction hashes
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize, Hash)]
 pub struct ManifestID([u8; 32]);
 
+impl ManifestID {
+    /// Returns the ID as a lowercase hexadecimal string
+    pub fn to_hex(&self) -> String {
+        let mut hex = String::with_capacity(64);
+        for byte in &self.0 {
+            write!(hex, "{byte:02x}").expect("Writing to a String can not fail");
+        }
+        hex
+    }
+}
+
 /// Describes a transaction in a manifest
-#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+///
+/// `Serialize` is implemented by hand, as the trailing optional fields must be left out of
+/// the encoding when unset, without shifting the position of the fields before them.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
 pub struct ManifestTransaction {
     /// The HMACs of all previous branch heads in the repository that this transaction references
     previous_heads: Vec<ManifestID>,
     /// The location of the archive this trasnaction refrences within the archive
     pointer: ChunkID,
     /// The timestamp of this Transactions Creation
-    timestamp: DateTime<FixedOffset>,
+    timestamp: Timestamp,
     /// The human readable name of the archive
     name: String,
     /// A 128-bit random nonce
@@ -36,18 +58,112 @@ pub struct ManifestTransaction {
     /// This is calculated based off the compact (array form) messagepacked encoding of
     /// this struct with this value set to all zeros
     tag: ManifestID,
+    /// If this transaction is a checkpoint, the summary of the manifest it replaces
+    ///
+    /// This is left out of the encoding entirely when not set, so that the tags of
+    /// transactions written before checkpoints existed still verify.
+    #[serde(default)]
+    checkpoint: Option<Checkpoint>,
+    /// The tags and metadata of the archive
+    ///
+    /// Like `checkpoint`, this is left out of the encoding entirely when empty.
+    #[serde(default)]
+    metadata: ArchiveMetadata,
+    /// The record of the chunks committed along with the archive, if one was kept
+    ///
+    /// Like `checkpoint`, this is left out of the encoding entirely when not set.
+    #[serde(default)]
+    integrity: Option<ChunkIntegrity>,
+    /// The signature of the client that made the archive, if it was signed
+    ///
+    /// Like `checkpoint`, this is left out of the encoding entirely when not set.
+    #[serde(default)]
+    signature: Option<ArchiveSignature>,
+    /// How long the archive took to store, if it was recorded
+    ///
+    /// Like `checkpoint`, this is left out of the encoding entirely when not set.
+    #[serde(default)]
+    duration: Option<Duration>,
+}
+
+impl Serialize for ManifestTransaction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        // Optional fields have to be written, even if unset, to hold their position whenever
+        // a later field follows them
+        let write_duration = self.duration.is_some();
+        let write_signature = self.signature.is_some() || write_duration;
+        let write_integrity = self.integrity.is_some() || write_signature;
+        let write_metadata = !self.metadata.is_empty() || write_integrity;
+        let write_checkpoint = self.checkpoint.is_some() || write_metadata;
+        let len = 7
+            + usize::from(write_checkpoint)
+            + usize::from(write_metadata)
+            + usize::from(write_integrity)
+            + usize::from(write_signature)
+            + usize::from(write_duration);
+        let mut state = serializer.serialize_struct("ManifestTransaction", len)?;
+        state.serialize_field("previous_heads", &self.previous_heads)?;
+        state.serialize_field("pointer", &self.pointer)?;
+        state.serialize_field("timestamp", &self.timestamp)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("nonce", &self.nonce)?;
+        state.serialize_field("hmac", &self.hmac)?;
+        state.serialize_field("tag", &self.tag)?;
+        if write_checkpoint {
+            state.serialize_field("checkpoint", &self.checkpoint)?;
+        } else {
+            state.skip_field("checkpoint")?;
+        }
+        if write_metadata {
+            state.serialize_field("metadata", &self.metadata)?;
+        } else {
+            state.skip_field("metadata")?;
+        }
+        if write_integrity {
+            state.serialize_field("integrity", &self.integrity)?;
+        } else {
+            state.skip_field("integrity")?;
+        }
+        if write_signature {
+            state.serialize_field("signature", &self.signature)?;
+        } else {
+            state.skip_field("signature")?;
+        }
+        if write_duration {
+            state.serialize_field("duration", &self.duration)?;
+        } else {
+            state.skip_field("duration")?;
+        }
+        state.end()
+    }
+}
+
+/// A summary of the state of the manifest, allowing the transactions it replaces to be
+/// removed from the manifest without losing track of any archives
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The archives in the repository at the time the checkpoint was written
+    archives: Vec<StoredArchive>,
+    /// The tags of every transaction this checkpoint replaces
+    squashed: Vec<ManifestID>,
 }
 
 impl ManifestTransaction {
     /// Constructs a new `ManifestTransaction` from the given list of previous heads, a
-    /// pointer, a name, a timestamp, and an HMAC method to use
+    /// pointer, a name, a timestamp, the archive's metadata, integrity record, signature, and
+    /// duration, and an HMAC method to use
     ///
     /// Will automatically produce the random nonce, and update the tag
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         previous_heads: &[ManifestID],
         pointer: ChunkID,
-        timestamp: DateTime<FixedOffset>,
+        timestamp: Timestamp,
         name: &str,
+        metadata: ArchiveMetadata,
+        integrity: Option<ChunkIntegrity>,
+        signature: Option<ArchiveSignature>,
+        duration: Option<Duration>,
         hmac: HMAC,
         key: &Key,
     ) -> ManifestTransaction {
@@ -61,6 +177,69 @@ impl ManifestTransaction {
             nonce,
             hmac,
             tag: ManifestID([0_u8; 32]),
+            checkpoint: None,
+            metadata,
+            integrity,
+            signature,
+            duration,
+        };
+        tx.update_tag(key);
+        tx
+    }
+
+    /// Constructs a new checkpoint transaction, recording the given set of archives as
+    /// the contents of the manifest, and replacing the given transactions
+    ///
+    /// The checkpoint points at the given previous heads like any other transaction, so
+    /// the transactions it replaces can be kept around as an audit trail, or removed.
+    pub fn new_checkpoint(
+        previous_heads: &[ManifestID],
+        archives: Vec<StoredArchive>,
+        squashed: Vec<ManifestID>,
+        hmac: HMAC,
+        key: &Key,
+    ) -> ManifestTransaction {
+        let mut nonce = [0_u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut tx = ManifestTransaction {
+            previous_heads: previous_heads.to_vec(),
+            pointer: ChunkID::manifest_id(),
+            timestamp: Timestamp::now(),
+            name: String::new(),
+            nonce,
+            hmac,
+            tag: ManifestID([0_u8; 32]),
+            checkpoint: Some(Checkpoint { archives, squashed }),
+            metadata: ArchiveMetadata::default(),
+            integrity: None,
+            signature: None,
+            duration: None,
+        };
+        tx.update_tag(key);
+        tx
+    }
+
+    /// Constructs a new merge transaction, which joins the given heads back into a single
+    /// branch without recording an archive
+    ///
+    /// Merges are otherwise ordinary transactions, pointing at the manifest's own chunk ID
+    /// in place of an archive.
+    pub fn new_merge(previous_heads: &[ManifestID], hmac: HMAC, key: &Key) -> ManifestTransaction {
+        let mut nonce = [0_u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut tx = ManifestTransaction {
+            previous_heads: previous_heads.to_vec(),
+            pointer: ChunkID::manifest_id(),
+            timestamp: Timestamp::now(),
+            name: String::new(),
+            nonce,
+            hmac,
+            tag: ManifestID([0_u8; 32]),
+            checkpoint: None,
+            metadata: ArchiveMetadata::default(),
+            integrity: None,
+            signature: None,
+            duration: None,
         };
         tx.update_tag(key);
         tx
@@ -92,15 +271,45 @@ impl ManifestTransaction {
     }
 
     /// Returns the timestamp of the archive
-    pub fn timestamp(&self) -> DateTime<FixedOffset> {
+    pub fn timestamp(&self) -> Timestamp {
         self.timestamp
     }
 
+    /// Returns the tags and metadata of the archive
+    pub fn metadata(&self) -> &ArchiveMetadata {
+        &self.metadata
+    }
+
+    /// Returns the record of the chunks committed along with the archive, if one was kept
+    pub fn integrity(&self) -> Option<&ChunkIntegrity> {
+        self.integrity.as_ref()
+    }
+
+    /// Returns the signature of the archive, if it was signed
+    pub fn signature(&self) -> Option<&ArchiveSignature> {
+        self.signature.as_ref()
+    }
+
+    /// Returns how long the archive took to store, if it was recorded
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
     /// Returns the HMAC value tag of this transaction
     pub fn tag(&self) -> ManifestID {
         self.tag
     }
 
+    /// Returns the checkpoint summary, if this transaction is a checkpoint
+    pub fn checkpoint(&self) -> Option<&Checkpoint> {
+        self.checkpoint.as_ref()
+    }
+
+    /// Returns true if this transaction is a merge, and does not record an archive
+    pub fn is_merge(&self) -> bool {
+        self.checkpoint.is_none() && self.pointer == ChunkID::manifest_id()
+    }
+
     /// Verifies the hmac of the transaction
     ///
     /// This does not descend down the DAG, will only verfiy thistransaction.
@@ -112,6 +321,53 @@ impl ManifestTransaction {
     }
 }
 
+impl Checkpoint {
+    /// Returns the archives recorded in this checkpoint
+    pub fn archives(&self) -> &[StoredArchive] {
+        &self.archives[..]
+    }
+
+    /// Returns the tags of the transactions this checkpoint replaces
+    pub fn squashed(&self) -> &[ManifestID] {
+        &self.squashed[..]
+    }
+}
+
+/// Returns true if the parent of a transaction is allowed to be missing from the manifest
+///
+/// Only checkpoints may refer to transactions that no longer exist, and only ones they
+/// explicitly replace.
+pub fn may_be_missing(tx: &ManifestTransaction, parent: ManifestID) -> bool {
+    tx.checkpoint()
+        .is_some_and(|checkpoint| checkpoint.squashed.contains(&parent))
+}
+
+/// Collects the archives described by a set of transactions, newest first
+///
+/// Archives are read out of checkpoints, and out of any transactions that have not been
+/// replaced by a checkpoint. Merges do not record an archive, and are skipped.
+pub fn archives_from_transactions<'a>(
+    transactions: impl Iterator<Item = &'a ManifestTransaction> + Clone,
+) -> Vec<StoredArchive> {
+    let squashed = transactions
+        .clone()
+        .filter_map(ManifestTransaction::checkpoint)
+        .flat_map(|checkpoint| checkpoint.squashed.iter().copied())
+        .collect::<HashSet<_>>();
+    let mut archives = HashSet::new();
+    for tx in transactions.filter(|tx| !squashed.contains(&tx.tag())) {
+        if let Some(checkpoint) = tx.checkpoint() {
+            archives.extend(checkpoint.archives.iter().cloned());
+        } else if !tx.is_merge() {
+            archives.insert(StoredArchive::from(tx.clone()));
+        }
+    }
+    let mut archives = archives.into_iter().collect::<Vec<_>>();
+    archives.sort_by_key(StoredArchive::timestamp);
+    archives.reverse();
+    archives
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,8 +375,19 @@ mod tests {
     fn create_tx(name: &str, key: &Key) -> ManifestTransaction {
         let hmac = HMAC::Blake2b;
         let pointer = ChunkID::new(&[1_u8; 32]);
-        let timestamp = Local::now().with_timezone(Local::now().offset());
-        ManifestTransaction::new(&[], pointer, timestamp, name, hmac, key)
+        let timestamp = Timestamp::now();
+        ManifestTransaction::new(
+            &[],
+            pointer,
+            timestamp,
+            name,
+            ArchiveMetadata::default(),
+            None,
+            None,
+            None,
+            hmac,
+            key,
+        )
     }
 
     // Creating a manifest and verifying it should result in success
@@ -160,4 +427,143 @@ mod tests {
         let output_tx: ManifestTransaction = rmps::decode::from_slice(&bytes[..]).unwrap();
         assert!(output_tx.verify(&key));
     }
+
+    // Checkpoints should survive a round trip, and their contents should be covered by the tag
+    #[test]
+    fn checkpoint_verify() {
+        let key = Key::random(32);
+        let txs = [create_tx("one", &key), create_tx("two", &key)];
+        let archives = archives_from_transactions(txs.iter());
+        let squashed = txs.iter().map(ManifestTransaction::tag).collect::<Vec<_>>();
+        let checkpoint =
+            ManifestTransaction::new_checkpoint(&[], archives, squashed, HMAC::Blake2b, &key);
+        let bytes = rmps::encode::to_vec(&checkpoint).unwrap();
+        let mut output_tx: ManifestTransaction = rmps::decode::from_slice(&bytes[..]).unwrap();
+        assert!(output_tx.verify(&key));
+        assert_eq!(output_tx.checkpoint().unwrap().archives().len(), 2);
+        output_tx.checkpoint.as_mut().unwrap().archives.pop();
+        assert!(!output_tx.verify(&key));
+    }
+
+    // Metadata should survive a round trip, and be covered by the tag
+    #[test]
+    fn metadata_verify() {
+        let key = Key::random(32);
+        let mut metadata = ArchiveMetadata::default();
+        metadata.tags.insert("daily".to_string());
+        metadata
+            .values
+            .insert("host".to_string(), "example".to_string());
+        let tx = ManifestTransaction::new(
+            &[],
+            ChunkID::new(&[1_u8; 32]),
+            Timestamp::now(),
+            "test",
+            metadata.clone(),
+            None,
+            None,
+            None,
+            HMAC::Blake2b,
+            &key,
+        );
+        let bytes = rmps::encode::to_vec(&tx).unwrap();
+        let mut output_tx: ManifestTransaction = rmps::decode::from_slice(&bytes[..]).unwrap();
+        assert!(output_tx.verify(&key));
+        assert_eq!(output_tx.metadata(), &metadata);
+        assert_eq!(StoredArchive::from(output_tx.clone()).metadata(), &metadata);
+        output_tx.metadata.tags.insert("weekly".to_string());
+        assert!(!output_tx.verify(&key));
+    }
+
+    // Transactions without metadata should encode exactly as they did before metadata existed
+    #[test]
+    fn metadata_absent() {
+        #[derive(Serialize)]
+        struct OldTransaction<'a> {
+            previous_heads: &'a [ManifestID],
+            pointer: ChunkID,
+            timestamp: Timestamp,
+            name: &'a str,
+            nonce: [u8; 16],
+            hmac: HMAC,
+            tag: ManifestID,
+        }
+        let key = Key::random(32);
+        let tx = create_tx("test", &key);
+        let old = OldTransaction {
+            previous_heads: tx.previous_heads(),
+            pointer: tx.pointer,
+            timestamp: tx.timestamp,
+            name: &tx.name,
+            nonce: tx.nonce,
+            hmac: tx.hmac,
+            tag: tx.tag,
+        };
+        let bytes = rmps::encode::to_vec(&tx).unwrap();
+        assert_eq!(bytes, rmps::encode::to_vec(&old).unwrap());
+    }
+
+    // Archives should be read out of checkpoints instead of the transactions they replace
+    #[test]
+    fn checkpoint_archives() {
+        let key = Key::random(32);
+        let mut txs = vec![create_tx("one", &key), create_tx("two", &key)];
+        let archives = archives_from_transactions(txs.iter());
+        let squashed = vec![txs[0].tag(), txs[1].tag()];
+        let heads = [txs[1].tag()];
+        txs.push(ManifestTransaction::new_checkpoint(
+            &heads,
+            archives.clone(),
+            squashed,
+            HMAC::Blake2b,
+            &key,
+        ));
+        txs.push(create_tx("three", &key));
+        assert!(may_be_missing(&txs[2], txs[0].tag()));
+        assert!(!may_be_missing(&txs[3], txs[0].tag()));
+        // With the squashed transactions still present
+        assert_eq!(archives_from_transactions(txs.iter()).len(), 3);
+        // And with them removed
+        assert_eq!(archives_from_transactions(txs[2..].iter()).len(), 3);
+    }
+
+    // Merges should verify, and not show up as archives
+    #[test]
+    fn merge_archives() {
+        let key = Key::random(32);
+        let mut txs = vec![create_tx("one", &key), create_tx("two", &key)];
+        let heads = [txs[0].tag(), txs[1].tag()];
+        let merge = ManifestTransaction::new_merge(&heads, HMAC::Blake2b, &key);
+        assert!(merge.verify(&key));
+        assert!(merge.is_merge());
+        assert!(!txs[0].is_merge());
+        assert_eq!(merge.previous_heads(), &heads);
+        txs.push(merge);
+        assert_eq!(archives_from_transactions(txs.iter()).len(), 2);
+    }
+
+    // Archives carrying an integrity record, but no metadata, should survive a round trip
+    // through a checkpoint
+    #[test]
+    fn checkpoint_integrity() {
+        let key = Key::random(32);
+        let mut archive = StoredArchive::from(create_tx("one", &key));
+        archive.integrity = Some(ChunkIntegrity {
+            listing: ChunkID::new(&[2_u8; 32]),
+            count: 1,
+            hmac: HMAC::Blake2b,
+            mac: vec![3_u8; 32],
+        });
+        let checkpoint = ManifestTransaction::new_checkpoint(
+            &[],
+            vec![archive.clone()],
+            Vec::new(),
+            HMAC::Blake2b,
+            &key,
+        );
+        let bytes = rmps::encode::to_vec(&checkpoint).unwrap();
+        let output_tx: ManifestTransaction = rmps::decode::from_slice(&bytes[..]).unwrap();
+        assert!(output_tx.verify(&key));
+        assert_eq!(output_tx.checkpoint().unwrap().archives(), &[archive]);
+    }
 }
@@ -0,0 +1,242 @@
+//! Optional Reed-Solomon parity for chunks stored in segments
+//!
+//! When parity is enabled, the body of each chunk written to a segment is split into
+//! `data_shards` equally sized shards (the last one padded with zeros), and
+//! `parity_shards` parity shards are computed over them and written to the segment
+//! directly after the chunk body.
+//!
+//! Damage to a chunk is detected by its HMAC failing to verify. In order to know which
+//! shards to throw away and rebuild, a short BLAKE3 hash of every shard is stored in the
+//! segment header alongside the chunk's header, which is itself protected by the HMAC of
+//! the header file. A repaired chunk is only ever trusted once it passes its HMAC.
+//!
+//! As long as no more than `parity_shards` shards, counting both data and parity shards,
+//! are damaged, the chunk can be recovered.
+use crate::repository::backend::{BackendError, Result};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryInto;
+
+/// The number of data and parity shards each chunk is split into
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParitySettings {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+}
+
+impl ParitySettings {
+    /// Creates a new set of parity settings
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if either shard count is zero, or if there are more than 256
+    /// shards in total
+    pub fn new(data_shards: usize, parity_shards: usize) -> Result<ParitySettings> {
+        let settings = ParitySettings {
+            data_shards,
+            parity_shards,
+        };
+        settings.codec()?;
+        Ok(settings)
+    }
+
+    /// Returns the total number of shards per chunk
+    pub fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    fn codec(&self) -> Result<ReedSolomon> {
+        ReedSolomon::new(self.data_shards, self.parity_shards).map_err(|e| {
+            BackendError::SegmentError(format!(
+                "Invalid parity settings ({} data, {} parity shards): {}",
+                self.data_shards, self.parity_shards, e
+            ))
+        })
+    }
+
+    /// Computes the parity shards for a chunk body
+    ///
+    /// Returns the description of the parity to be stored in the segment header, as well
+    /// as the parity shards themselves, concatenated in order. The `parity_offset` of the
+    /// returned `ChunkParity` is left at zero, as it is up to the caller to decide where
+    /// the parity gets written.
+    pub fn encode(&self, body: &[u8]) -> Result<(ChunkParity, Vec<u8>)> {
+        let shard_size = shard_size(body.len(), self.data_shards);
+        let mut shards = split_shards(body, shard_size, self.data_shards);
+        shards.resize(self.total_shards(), vec![0_u8; shard_size]);
+        self.codec()?
+            .encode(&mut shards)
+            .map_err(|e| BackendError::SegmentError(format!("Parity encoding failed: {e}")))?;
+        let parity = ChunkParity {
+            settings: *self,
+            shard_size: shard_size as u64,
+            parity_offset: 0,
+            shard_hashes: shards.iter().map(|x| shard_hash(x)).collect(),
+        };
+        Ok((parity, shards.split_off(self.data_shards).concat()))
+    }
+}
+
+/// Describes the parity stored for a single chunk in a segment
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkParity {
+    /// The settings the parity was computed with
+    pub settings: ParitySettings,
+    /// The size, in bytes, of each shard
+    pub shard_size: u64,
+    /// The offset of the parity shards within the segment's data file
+    pub parity_offset: u64,
+    /// A truncated hash of every shard, data shards first, used to locate damage
+    pub shard_hashes: Vec<u64>,
+}
+
+impl ChunkParity {
+    /// Returns the number of bytes of parity stored for the chunk
+    pub fn parity_length(&self) -> u64 {
+        self.shard_size * self.settings.parity_shards as u64
+    }
+
+    /// Attempts to rebuild any damaged shards of the chunk body and its parity
+    ///
+    /// Returns `Ok(None)` if every shard is intact, otherwise the repaired body and
+    /// parity are returned. `parity` may be shorter than `parity_length`, in which case
+    /// the missing bytes are treated as damaged.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if too many shards have been damaged to rebuild them
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the shard size is too large to fit in memory
+    pub fn repair(&self, body: &[u8], parity: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let shard_size: usize = self
+            .shard_size
+            .try_into()
+            .expect("Shard size too big to fit in memory");
+        let data_shards = self.settings.data_shards;
+        let mut shards = split_shards(body, shard_size, data_shards);
+        shards.extend(split_shards(
+            parity,
+            shard_size,
+            self.settings.parity_shards,
+        ));
+        let mut shards: Vec<Option<Vec<u8>>> = shards
+            .into_iter()
+            .zip(self.shard_hashes.iter())
+            .map(|(shard, hash)| Some(shard).filter(|x| shard_hash(x) == *hash))
+            .collect();
+        if shards.len() != self.settings.total_shards() {
+            return Err(BackendError::SegmentError(
+                "Chunk parity is missing shard hashes".to_string(),
+            ));
+        }
+        if shards.iter().all(Option::is_some) {
+            return Ok(None);
+        }
+        self.settings
+            .codec()?
+            .reconstruct(&mut shards)
+            .map_err(|e| BackendError::SegmentError(format!("Unable to repair chunk: {e}")))?;
+        let mut shards: Vec<Vec<u8>> = shards.into_iter().map(Option::unwrap).collect();
+        let parity = shards.split_off(data_shards).concat();
+        let mut body_out = shards.concat();
+        body_out.truncate(body.len());
+        Ok(Some((body_out, parity)))
+    }
+}
+
+/// Picks the smallest shard size that fits `length` bytes into `data_shards` shards
+///
+/// Shards must be non-empty, so this is never less than one.
+fn shard_size(length: usize, data_shards: usize) -> usize {
+    length.div_ceil(data_shards).max(1)
+}
+
+/// Splits `data` into `count` shards of `shard_size` bytes, padding with zeros as needed
+fn split_shards(data: &[u8], shard_size: usize, count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| {
+            let start = (i * shard_size).min(data.len());
+            let end = ((i + 1) * shard_size).min(data.len());
+            let mut shard = data[start..end].to_vec();
+            shard.resize(shard_size, 0);
+            shard
+        })
+        .collect()
+}
+
+/// Hashes a shard, keeping the first eight bytes of its BLAKE3 hash
+fn shard_hash(shard: &[u8]) -> u64 {
+    let hash = blake3::hash(shard);
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    fn random_body(len: usize) -> Vec<u8> {
+        let mut body = vec![0_u8; len];
+        thread_rng().fill_bytes(&mut body);
+        body
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert!(ParitySettings::new(0, 2).is_err());
+        assert!(ParitySettings::new(4, 0).is_err());
+        assert!(ParitySettings::new(200, 100).is_err());
+        assert!(ParitySettings::new(10, 4).is_ok());
+    }
+
+    #[test]
+    fn intact_chunk() {
+        let settings = ParitySettings::new(10, 4).unwrap();
+        for len in &[0, 1, 9, 10, 11, 4096] {
+            let body = random_body(*len);
+            let (info, parity) = settings.encode(&body).unwrap();
+            assert_eq!(parity.len() as u64, info.parity_length());
+            assert!(info.repair(&body, &parity).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn repair_damage() {
+        let settings = ParitySettings::new(4, 2).unwrap();
+        let body = random_body(1001);
+        let (info, parity) = settings.encode(&body).unwrap();
+        // Damage one data shard and one parity shard
+        let mut damaged_body = body.clone();
+        damaged_body[10] ^= 0xFF;
+        let mut damaged_parity = parity.clone();
+        damaged_parity[0] ^= 0xFF;
+        let (repaired_body, repaired_parity) = info
+            .repair(&damaged_body, &damaged_parity)
+            .unwrap()
+            .unwrap();
+        assert_eq!(repaired_body, body);
+        assert_eq!(repaired_parity, parity);
+        // Truncated parity counts as damage too
+        let (repaired_body, repaired_parity) = info.repair(&body, &parity[..10]).unwrap().unwrap();
+        assert_eq!(repaired_body, body);
+        assert_eq!(repaired_parity, parity);
+    }
+
+    #[test]
+    fn too_much_damage() {
+        let settings = ParitySettings::new(4, 2).unwrap();
+        let body = random_body(1000);
+        let (info, parity) = settings.encode(&body).unwrap();
+        let mut damaged_body = body.clone();
+        for shard in 0..3 {
+            damaged_body[shard * 250] ^= 0xFF;
+        }
+        assert!(info.repair(&damaged_body, &parity).is_err());
+    }
+}
@@ -1,14 +1,20 @@
-use crate::repository::backend::{BackendError, Result};
-use crate::repository::{Chunk, ChunkSettings, Key};
+use crate::repository::backend::common::files::LockedFile;
+use crate::repository::backend::common::parity::{ChunkParity, ParitySettings};
+use crate::repository::backend::{BackendError, ChunkDescriptors, Result, SegmentDescriptor};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, Key};
 
 use asuran_core::repository::chunk::{ChunkBody, ChunkHeader};
 
+use futures::future::Future;
+use futures::stream::{self, StreamExt};
 use rmp_serde as rmps;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use std::convert::TryInto;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, BorrowedFd};
 
 /// Magic number used for asuran segment files
 ///
@@ -71,6 +77,44 @@ pub struct SegmentHeaderEntry {
     pub header: ChunkHeader,
     pub start_offset: u64,
     pub end_offset: u64,
+    /// The Reed-Solomon parity stored for the chunk, if the segment was written with
+    /// parity enabled
+    #[serde(default)]
+    pub parity: Option<ChunkParity>,
+}
+
+/// Builds the stream returned by `Backend::chunk_descriptors` for a backend that stores its
+/// chunks in the segments with the given ids
+///
+/// `read_segment` is called with each segment id in turn, as the stream is polled, and must
+/// produce the id and location of every chunk in that segment.
+pub fn descriptors_by_segment<F, Fut>(segment_ids: Vec<u64>, read_segment: F) -> ChunkDescriptors
+where
+    F: FnMut(u64) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Vec<(ChunkID, SegmentDescriptor)>>> + Send + 'static,
+{
+    stream::iter(segment_ids)
+        .then(read_segment)
+        .flat_map(|segment| {
+            stream::iter(match segment {
+                Ok(descriptors) => descriptors.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            })
+        })
+        .boxed()
+}
+
+/// The condition of a single chunk in a segment, as determined by `Segment::check_chunk`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChunkHealth {
+    /// The chunk and its parity, if any, are undamaged
+    Intact,
+    /// The chunk or its parity are damaged, but can be rebuilt from the parity
+    Repairable,
+    /// The chunk or its parity were damaged, and have been rebuilt in place
+    Repaired,
+    /// The chunk is damaged, and there is not enough parity to rebuild it
+    Unrecoverable,
 }
 
 /// A view over the header portion of a segment
@@ -156,6 +200,19 @@ impl<T: Read + Write + Seek> SegmentHeaderPart<T> {
         self.entries.get(index).cloned()
     }
 
+    /// Returns the size, in bytes, of each chunk in the segment, in index order
+    pub fn chunk_sizes(&self) -> Vec<u64> {
+        self.entries
+            .iter()
+            .map(|x| x.end_offset - x.start_offset)
+            .collect()
+    }
+
+    /// Returns the `ChunkID` of each chunk in the segment, in index order
+    pub fn chunk_ids(&self) -> Vec<ChunkID> {
+        self.entries.iter().map(|x| x.header.id()).collect()
+    }
+
     /// Will insert the chunk header information and provide its index
     pub fn insert_header(&mut self, header: SegmentHeaderEntry) -> usize {
         let index = self.entries.len();
@@ -262,6 +319,42 @@ impl<T: Read + Write + Seek> SegmentDataPart<T> {
         Ok(self.size_limit - len)
     }
 
+    /// Reads `length` bytes starting at `offset`
+    ///
+    /// Any bytes past the end of the segment file are returned as zeros, so truncated
+    /// data can still be handed to the parity for repair.
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `length` does not fit in memory on this platform
+    pub fn read_range(&mut self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let length: usize = length
+            .try_into()
+            .expect("Chunk size too big to fit in memory");
+        let mut buffer = Vec::with_capacity(length);
+        self.handle.seek(SeekFrom::Start(offset))?;
+        (&mut self.handle)
+            .take(length as u64)
+            .read_to_end(&mut buffer)?;
+        buffer.resize(length, 0);
+        Ok(buffer)
+    }
+
+    /// Overwrites the bytes starting at `offset`
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<()> {
+        self.handle.seek(SeekFrom::Start(offset))?;
+        self.handle.write_all(bytes)?;
+        Ok(())
+    }
+
     pub fn read_chunk(&mut self, header: SegmentHeaderEntry) -> Result<Chunk> {
         let length: usize = (header.end_offset - header.start_offset)
             .try_into()
@@ -274,22 +367,107 @@ impl<T: Read + Write + Seek> SegmentDataPart<T> {
     }
 
     pub fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentHeaderEntry> {
+        self.write_chunk_with_parity(chunk, None)
+    }
+
+    /// Writes a chunk, followed by its parity shards if `parity` is provided
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn write_chunk_with_parity(
+        &mut self,
+        chunk: Chunk,
+        parity: Option<ParitySettings>,
+    ) -> Result<SegmentHeaderEntry> {
         let start_offset: u64 = self.handle.seek(SeekFrom::End(1))?;
         let end_offset: u64 = start_offset + chunk.get_bytes().len() as u64;
         let (header, body) = chunk.split();
         self.handle.write_all(&body.0[..])?;
+        let parity = if let Some(settings) = parity {
+            let (mut info, bytes) = settings.encode(&body.0[..])?;
+            info.parity_offset = self.handle.seek(SeekFrom::End(0))?;
+            self.handle.write_all(&bytes[..])?;
+            Some(info)
+        } else {
+            None
+        };
         Ok(SegmentHeaderEntry {
             header,
             start_offset,
             end_offset,
+            parity,
         })
     }
+
+    /// Writes several chunks, and their parity shards if `parity` is provided, with a
+    /// single append
+    ///
+    /// The chunks are laid out exactly as repeated calls to `write_chunk_with_parity`
+    /// would lay them out.
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn write_chunks_with_parity(
+        &mut self,
+        chunks: Vec<Chunk>,
+        parity: Option<ParitySettings>,
+    ) -> Result<Vec<SegmentHeaderEntry>> {
+        let (end, buffer, entries) = self.layout_chunks(chunks, parity)?;
+        self.write_at(end, &buffer[..])?;
+        Ok(entries)
+    }
+
+    /// Lays out several chunks, and their parity shards if `parity` is provided, for a
+    /// single append, without writing them
+    ///
+    /// Returns the offset the append must be made at, the bytes to append, and the
+    /// entries describing the chunks once they are written.
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn layout_chunks(
+        &mut self,
+        chunks: Vec<Chunk>,
+        parity: Option<ParitySettings>,
+    ) -> Result<(u64, Vec<u8>, Vec<SegmentHeaderEntry>)> {
+        let end = self.handle.seek(SeekFrom::End(0))?;
+        let mut buffer = Vec::new();
+        let mut entries = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            // Each chunk starts one byte past the end of the previous one
+            buffer.push(0);
+            let start_offset = end + buffer.len() as u64;
+            let (header, body) = chunk.split();
+            buffer.extend_from_slice(&body.0[..]);
+            let end_offset = end + buffer.len() as u64;
+            let parity = if let Some(settings) = parity {
+                let (mut info, bytes) = settings.encode(&body.0[..])?;
+                info.parity_offset = end_offset;
+                buffer.extend_from_slice(&bytes[..]);
+                Some(info)
+            } else {
+                None
+            };
+            entries.push(SegmentHeaderEntry {
+                header,
+                start_offset,
+                end_offset,
+                parity,
+            });
+        }
+        Ok((end, buffer, entries))
+    }
 }
 
 /// Generic segment implementation wrapping any Read + Write + Seek
 pub struct Segment<T: Read + Write + Seek> {
     data_handle: SegmentDataPart<T>,
     header_handle: SegmentHeaderPart<T>,
+    key: Key,
+    parity: Option<ParitySettings>,
 }
 
 impl<T: Read + Write + Seek> Segment<T> {
@@ -302,13 +480,22 @@ impl<T: Read + Write + Seek> Segment<T> {
         key: Key,
     ) -> Result<Segment<T>> {
         let data_handle = SegmentDataPart::new(data_handle, size_limit)?;
-        let header_handle = SegmentHeaderPart::open(header_handle, key, chunk_settings)?;
+        let header_handle = SegmentHeaderPart::open(header_handle, key.clone(), chunk_settings)?;
         Ok(Segment {
             data_handle,
             header_handle,
+            key,
+            parity: None,
         })
     }
 
+    /// Sets the parity that will be written along with any new chunks
+    ///
+    /// Chunks already in the segment keep whatever parity they were written with.
+    pub fn set_parity(&mut self, parity: Option<ParitySettings>) {
+        self.parity = parity;
+    }
+
     /// Returns the size in bytes of the segment
     pub fn size(&mut self) -> u64 {
         self.data_handle
@@ -323,23 +510,170 @@ impl<T: Read + Write + Seek> Segment<T> {
             .expect("Unable to read size from data handle. Please check file permissions.")
     }
 
-    /// Reads the chunk with the specified index from the segment
-    pub fn read_chunk(&mut self, index: u64) -> Result<Chunk> {
+    /// Looks up the header entry for the chunk with the specified index
+    /// Returns the header entry describing the chunk with the specified index
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(SegmentError)` if there is no chunk with that index
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the index does not fit in a `usize`
+    pub fn get_entry(&self, index: u64) -> Result<SegmentHeaderEntry> {
         let index: usize = index
             .try_into()
             .expect("Index provided to read_chunk larger than could possibly fit into memory");
-        let entry = self.header_handle.get_header(index).ok_or_else(|| {
+        self.header_handle.get_header(index).ok_or_else(|| {
             BackendError::SegmentError(format!("Invalid index {} provided to read_chunk", index))
-        })?;
-        self.data_handle.read_chunk(entry)
+        })
+    }
+
+    /// Reads the chunk with the specified index from the segment
+    ///
+    /// If the chunk was written with parity and fails its HMAC, the chunk will be
+    /// rebuilt from its parity in memory. The segment itself is left untouched, use
+    /// `check_chunk` to repair it on disk. If the chunk can not be rebuilt, it is returned
+    /// as read, and will fail to unpack.
+    pub fn read_chunk(&mut self, index: u64) -> Result<Chunk> {
+        let entry = self.get_entry(index)?;
+        let chunk = self.data_handle.read_chunk(entry.clone())?;
+        match entry.parity {
+            Some(ref parity) if !chunk.verify_mac(&self.key) => {
+                let body = chunk.get_bytes();
+                let parity_bytes = self
+                    .data_handle
+                    .read_range(parity.parity_offset, parity.parity_length())?;
+                match parity.repair(body, &parity_bytes) {
+                    Ok(Some((body, _))) => {
+                        let repaired = Chunk::unsplit(entry.header, ChunkBody(body));
+                        if repaired.verify_mac(&self.key) {
+                            Ok(repaired)
+                        } else {
+                            Ok(chunk)
+                        }
+                    }
+                    _ => Ok(chunk),
+                }
+            }
+            _ => Ok(chunk),
+        }
+    }
+
+    /// Verifies the chunk with the specified index against its HMAC and parity
+    ///
+    /// If `repair` is set, any damage that can be repaired will be overwritten in place
+    /// with the rebuilt data.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the index is invalid, or if an I/O error occurs. Damage to
+    /// the chunk itself is reported through the returned `ChunkHealth`.
+    pub fn check_chunk(&mut self, index: u64, repair: bool) -> Result<ChunkHealth> {
+        let entry = self.get_entry(index)?;
+        let body = self
+            .data_handle
+            .read_range(entry.start_offset, entry.end_offset - entry.start_offset)?;
+        // Rebuild any damaged shards, keeping track of where the parity lives so it can be
+        // written back
+        let rebuilt = if let Some(parity) = &entry.parity {
+            let parity_bytes = self
+                .data_handle
+                .read_range(parity.parity_offset, parity.parity_length())?;
+            match parity.repair(&body, &parity_bytes) {
+                Ok(Some((body, parity_bytes))) => Some((body, parity_bytes, parity.parity_offset)),
+                Ok(None) => None,
+                Err(_) => return Ok(ChunkHealth::Unrecoverable),
+            }
+        } else {
+            None
+        };
+        if let Some((repaired_body, repaired_parity, parity_offset)) = rebuilt {
+            let chunk = Chunk::unsplit(entry.header, ChunkBody(repaired_body));
+            if !chunk.verify_mac(&self.key) {
+                Ok(ChunkHealth::Unrecoverable)
+            } else if repair {
+                self.data_handle
+                    .write_at(entry.start_offset, chunk.get_bytes())?;
+                self.data_handle.write_at(parity_offset, &repaired_parity)?;
+                Ok(ChunkHealth::Repaired)
+            } else {
+                Ok(ChunkHealth::Repairable)
+            }
+        } else {
+            let chunk = Chunk::unsplit(entry.header, ChunkBody(body));
+            Ok(if chunk.verify_mac(&self.key) {
+                ChunkHealth::Intact
+            } else {
+                ChunkHealth::Unrecoverable
+            })
+        }
+    }
+
+    /// Returns the number of chunks in the segment
+    pub fn chunk_count(&self) -> u64 {
+        self.header_handle.chunk_sizes().len() as u64
+    }
+
+    /// Returns the size, in bytes, of each chunk in the segment, in index order
+    pub fn chunk_sizes(&self) -> Vec<u64> {
+        self.header_handle.chunk_sizes()
+    }
+
+    /// Returns the `ChunkID` and location of each chunk in the segment, in index order,
+    /// given the id of this segment
+    pub fn chunk_descriptors(&self, segment_id: u64) -> Vec<(ChunkID, SegmentDescriptor)> {
+        (0_u64..)
+            .zip(self.header_handle.chunk_ids())
+            .map(|(start, id)| (id, SegmentDescriptor { segment_id, start }))
+            .collect()
     }
 
     pub fn write_chunk(&mut self, chunk: Chunk) -> Result<u64> {
-        let entry = self.data_handle.write_chunk(chunk)?;
+        let entry = self
+            .data_handle
+            .write_chunk_with_parity(chunk, self.parity)?;
         let index = self.header_handle.insert_header(entry);
         Ok(index as u64)
     }
 
+    /// Writes several chunks with a single append to the segment, returning their
+    /// indexes in order
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<u64>> {
+        let entries = self
+            .data_handle
+            .write_chunks_with_parity(chunks, self.parity)?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| self.header_handle.insert_header(entry) as u64)
+            .collect())
+    }
+
+    /// Lays out several chunks for a single append, but hands the bytes to `append`, along with
+    /// the offset they must be written at, rather than writing them itself
+    ///
+    /// The chunks are only recorded in the header once `append` succeeds.
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur, and any error returned by `append`
+    pub fn write_chunks_with(
+        &mut self,
+        chunks: Vec<Chunk>,
+        append: impl FnOnce(u64, &[u8]) -> Result<()>,
+    ) -> Result<Vec<u64>> {
+        let (offset, buffer, entries) = self.data_handle.layout_chunks(chunks, self.parity)?;
+        append(offset, &buffer[..])?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| self.header_handle.insert_header(entry) as u64)
+            .collect())
+    }
+
     pub fn read_header(&mut self) -> Result<Header> {
         self.data_handle.read_header()
     }
@@ -349,10 +683,57 @@ impl<T: Read + Write + Seek> Segment<T> {
     }
 }
 
+#[cfg(unix)]
+impl<T: Read + Write + Seek + AsFd> Segment<T> {
+    /// Returns the file descriptor of the data portion of the segment
+    pub fn data_fd(&self) -> BorrowedFd<'_> {
+        self.data_handle.handle.as_fd()
+    }
+}
+
+impl Segment<Cursor<Vec<u8>>> {
+    /// Flushes the header, and then returns copies of the contents of the data and header
+    /// parts, from which an identical segment can be opened with `new`
+    ///
+    /// # Errors
+    ///
+    /// Will error if the header can not be written
+    pub fn contents(&mut self) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.flush()?;
+        Ok((
+            self.data_handle.handle.get_ref().clone(),
+            self.header_handle.handle.get_ref().clone(),
+        ))
+    }
+}
+
+impl Segment<LockedFile> {
+    /// Flushes the header, and then syncs both the data and header files to disk
+    ///
+    /// # Errors
+    ///
+    /// Will error if an I/O error occurs during writing or syncing.
+    pub fn sync(&mut self) -> Result<()> {
+        self.sync_header()?;
+        self.data_handle.handle.sync_data()?;
+        Ok(())
+    }
+
+    /// Flushes the header, and then syncs only the header file to disk
+    ///
+    /// # Errors
+    ///
+    /// Will error if an I/O error occurs during writing or syncing.
+    pub fn sync_header(&mut self) -> Result<()> {
+        self.flush()?;
+        self.header_handle.handle.sync_data()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Cursor;
     #[test]
     fn header_sanity() {
         let input = Header::new();
@@ -392,4 +773,59 @@ mod tests {
 
         assert!(segment.read_header().unwrap().validate())
     }
+
+    #[test]
+    fn batch_write_matches_single_writes() {
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let chunks: Vec<Chunk> = (0..8_u8)
+            .map(|i| {
+                Chunk::pack(
+                    vec![i; 100 * (i as usize + 1)],
+                    settings.compression,
+                    settings.encryption,
+                    settings.hmac,
+                    &key,
+                )
+            })
+            .collect();
+        let open = || {
+            let mut segment = Segment::new(
+                Cursor::new(Vec::<u8>::new()),
+                Cursor::new(Vec::<u8>::new()),
+                1_000_000,
+                settings,
+                key.clone(),
+            )
+            .unwrap();
+            segment.set_parity(Some(ParitySettings::new(4, 2).unwrap()));
+            segment
+        };
+
+        let mut single = open();
+        let single_indexes: Vec<u64> = chunks
+            .iter()
+            .map(|chunk| single.write_chunk(chunk.clone()).unwrap())
+            .collect();
+        let mut batch = open();
+        let batch_indexes = batch.write_chunks(chunks.clone()).unwrap();
+
+        assert_eq!(single_indexes, batch_indexes);
+        assert_eq!(single.size(), batch.size());
+        for (index, chunk) in batch_indexes.into_iter().zip(chunks) {
+            let (single_entry, batch_entry) = (
+                single.get_entry(index).unwrap(),
+                batch.get_entry(index).unwrap(),
+            );
+            assert_eq!(single_entry.start_offset, batch_entry.start_offset);
+            assert_eq!(single_entry.end_offset, batch_entry.end_offset);
+            assert_eq!(
+                single_entry.parity.map(|x| x.parity_offset),
+                batch_entry.parity.map(|x| x.parity_offset)
+            );
+            let read = batch.read_chunk(index).unwrap();
+            assert_eq!(read.get_id(), chunk.get_id());
+            assert_eq!(read.get_bytes(), chunk.get_bytes());
+        }
+    }
 }
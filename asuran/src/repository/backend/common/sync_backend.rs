@@ -12,13 +12,16 @@
 //! Methods in this module are intentionally left undocumented, as they are indented to be syncronus
 //! versions of their async equivlants in the main Backend traits.
 use crate::manifest::StoredArchive;
+use crate::repository::backend::common::segment::descriptors_by_segment;
+use crate::repository::backend::common::SharedChunkFilter;
 use crate::repository::backend::{
-    backend_to_object, Backend, BackendObject, Index, Manifest, Result, SegmentDescriptor,
+    backend_to_object, Backend, BackendError, BackendObject, ChunkDescriptors, Index, Manifest,
+    Result, SegmentDescriptor,
 };
 use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey};
+use crate::time::Timestamp;
 
 use async_trait::async_trait;
-use chrono::prelude::*;
 use futures::channel::mpsc;
 use futures::channel::oneshot;
 use futures::sink::SinkExt;
@@ -30,7 +33,7 @@ use std::thread;
 
 pub trait SyncManifest: std::fmt::Debug {
     type Iterator: Iterator<Item = StoredArchive> + std::fmt::Debug + Send + 'static;
-    fn last_modification(&mut self) -> Result<DateTime<FixedOffset>>;
+    fn last_modification(&mut self) -> Result<Timestamp>;
     fn chunk_settings(&mut self) -> ChunkSettings;
     fn archive_iterator(&mut self) -> Self::Iterator;
     fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()>;
@@ -61,6 +64,27 @@ pub trait SyncBackend: 'static + std::fmt::Debug {
     fn read_key(&mut self) -> Result<EncryptedKey>;
     fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk>;
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor>;
+    fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        chunks
+            .into_iter()
+            .map(|chunk| self.write_chunk(chunk))
+            .collect()
+    }
+    /// Writes out any buffered state, see `Backend::flush`
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Lists the segments chunks are stored in, see `Backend::chunk_descriptors`
+    fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        Err(BackendError::Unsupported("Enumerating chunks".to_string()))
+    }
+    /// Lists the id and location of every chunk in a segment, see `Backend::chunk_descriptors`
+    fn segment_descriptors(
+        &mut self,
+        _segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        Err(BackendError::Unsupported("Enumerating chunks".to_string()))
+    }
 }
 
 enum SyncIndexCommand {
@@ -72,7 +96,7 @@ enum SyncIndexCommand {
 }
 
 enum SyncManifestCommand<I> {
-    LastMod(oneshot::Sender<Result<DateTime<FixedOffset>>>),
+    LastMod(oneshot::Sender<Result<Timestamp>>),
     ChunkSettings(oneshot::Sender<ChunkSettings>),
     ArchiveIterator(oneshot::Sender<I>),
     WriteChunkSettings(ChunkSettings, oneshot::Sender<Result<()>>),
@@ -83,15 +107,24 @@ enum SyncManifestCommand<I> {
 enum SyncBackendCommand {
     ReadChunk(SegmentDescriptor, oneshot::Sender<Result<Chunk>>),
     WriteChunk(Chunk, oneshot::Sender<Result<SegmentDescriptor>>),
+    WriteChunks(Vec<Chunk>, oneshot::Sender<Result<Vec<SegmentDescriptor>>>),
+    Flush(oneshot::Sender<Result<()>>),
+    SegmentIds(oneshot::Sender<Result<Vec<u64>>>),
+    SegmentDescriptors(
+        u64,
+        oneshot::Sender<Result<Vec<(ChunkID, SegmentDescriptor)>>>,
+    ),
     ReadKey(oneshot::Sender<Result<EncryptedKey>>),
     WriteKey(EncryptedKey, oneshot::Sender<Result<()>>),
     Close(oneshot::Sender<()>),
 }
 
-enum SyncCommand<I> {
+enum SyncCommand<I, B> {
     Index(SyncIndexCommand),
     Manifest(SyncManifestCommand<I>),
     Backend(SyncBackendCommand),
+    /// Runs a closure against the backend itself, see `BackendHandle::with_backend`
+    With(Box<dyn FnOnce(&mut B) + Send>),
 }
 
 /// Wrapper Type for sync backends that converts them into async backends
@@ -100,7 +133,8 @@ enum SyncCommand<I> {
 /// to instruct that task on what to do.
 pub struct BackendHandle<B: SyncBackend> {
     channel:
-        mpsc::Sender<SyncCommand<<<B as SyncBackend>::SyncManifest as SyncManifest>::Iterator>>,
+        mpsc::Sender<SyncCommand<<<B as SyncBackend>::SyncManifest as SyncManifest>::Iterator, B>>,
+    filter: SharedChunkFilter,
 }
 
 impl<B> BackendHandle<B>
@@ -116,10 +150,15 @@ where
     ///
     /// `queue_depth` should be a positive (greater than 0) integer, that represents the
     /// number of requests to hold in the processing queue at any given time.
+    #[allow(clippy::too_many_lines)]
     pub fn new(queue_depth: usize, backend: impl FnOnce() -> B + Send + 'static) -> Self {
         let (input, mut output) = mpsc::channel(queue_depth);
+        let filter = SharedChunkFilter::default();
+        let task_filter = filter.clone();
         thread::spawn(move || {
             let mut backend = backend();
+            // Handles fall back to asking the index until the filter is populated
+            task_filter.populate(backend.get_index().known_chunks().into_iter());
             let mut final_ret: Option<oneshot::Sender<()>> = None;
             while let Some(command) = block_on(output.next()) {
                 match command {
@@ -130,7 +169,11 @@ where
                                 ret.send(index.lookup_chunk(id)).unwrap();
                             }
                             SyncIndexCommand::Set(id, location, ret) => {
-                                ret.send(index.set_chunk(id, location)).unwrap();
+                                let result = index.set_chunk(id, location);
+                                if result.is_ok() {
+                                    task_filter.insert(id);
+                                }
+                                ret.send(result).unwrap();
                             }
                             SyncIndexCommand::KnownChunks(ret) => {
                                 ret.send(index.known_chunks()).unwrap();
@@ -173,6 +216,18 @@ where
                         SyncBackendCommand::WriteChunk(chunk, ret) => {
                             ret.send(backend.write_chunk(chunk)).unwrap();
                         }
+                        SyncBackendCommand::WriteChunks(chunks, ret) => {
+                            ret.send(backend.write_chunks(chunks)).unwrap();
+                        }
+                        SyncBackendCommand::Flush(ret) => {
+                            ret.send(backend.flush()).unwrap();
+                        }
+                        SyncBackendCommand::SegmentIds(ret) => {
+                            ret.send(backend.segment_ids()).unwrap();
+                        }
+                        SyncBackendCommand::SegmentDescriptors(segment_id, ret) => {
+                            ret.send(backend.segment_descriptors(segment_id)).unwrap();
+                        }
                         SyncBackendCommand::WriteKey(key, ret) => {
                             ret.send(backend.write_key(key)).unwrap();
                         }
@@ -183,6 +238,7 @@ where
                             final_ret = Some(ret);
                         }
                     },
+                    SyncCommand::With(f) => f(&mut backend),
                 };
                 if final_ret.is_some() {
                     break;
@@ -195,7 +251,36 @@ where
             }
         });
 
-        BackendHandle { channel: input }
+        BackendHandle {
+            channel: input,
+            filter,
+        }
+    }
+
+    /// Runs `f` against the backend on its runner thread, returning its result
+    ///
+    /// `f` runs in order with every other command sent through this handle, and its clones,
+    /// so it sees the backend exactly as those commands left it.
+    ///
+    /// Chunks `f` adds to or removes from the index directly are not reflected in the handle's
+    /// chunk filter.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the backend has already been closed
+    pub async fn with_backend<R: Send + 'static>(
+        &mut self,
+        f: impl FnOnce(&mut B) -> R + Send + 'static,
+    ) -> R {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::With(Box::new(move |backend| {
+                // The caller going away is no concern of the runner
+                let _ = i.send(f(backend));
+            })))
+            .await
+            .unwrap();
+        o.await.unwrap()
     }
 }
 
@@ -209,6 +294,7 @@ impl<B: SyncBackend> Clone for BackendHandle<B> {
     fn clone(&self) -> Self {
         BackendHandle {
             channel: self.channel.clone(),
+            filter: self.filter.clone(),
         }
     }
 }
@@ -216,7 +302,7 @@ impl<B: SyncBackend> Clone for BackendHandle<B> {
 #[async_trait]
 impl<B: SyncBackend> Manifest for BackendHandle<B> {
     type Iterator = <<B as SyncBackend>::SyncManifest as SyncManifest>::Iterator;
-    async fn last_modification(&mut self) -> Result<DateTime<FixedOffset>> {
+    async fn last_modification(&mut self) -> Result<Timestamp> {
         let (i, o) = oneshot::channel();
         self.channel
             .send(SyncCommand::Manifest(SyncManifestCommand::LastMod(i)))
@@ -290,6 +376,9 @@ impl<B: SyncBackend> Index for BackendHandle<B> {
             .unwrap();
         o.await?
     }
+    async fn contains_chunk(&mut self, id: ChunkID) -> bool {
+        self.filter.may_contain(id) && self.lookup_chunk(id).await.is_some()
+    }
     async fn known_chunks(&mut self) -> HashSet<ChunkID> {
         let (i, o) = oneshot::channel();
         self.channel
@@ -375,6 +464,47 @@ impl<B: SyncBackend> Backend for BackendHandle<B> {
             .unwrap();
         o.await?
     }
+    async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Backend(SyncBackendCommand::WriteChunks(
+                chunks, i,
+            )))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn flush(&mut self) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Backend(SyncBackendCommand::Flush(i)))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn chunk_descriptors(&mut self) -> Result<ChunkDescriptors> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Backend(SyncBackendCommand::SegmentIds(i)))
+            .await
+            .unwrap();
+        let segment_ids = o.await??;
+        let handle = self.clone();
+        Ok(descriptors_by_segment(segment_ids, move |segment_id| {
+            let mut handle = handle.clone();
+            async move {
+                let (i, o) = oneshot::channel();
+                handle
+                    .channel
+                    .send(SyncCommand::Backend(
+                        SyncBackendCommand::SegmentDescriptors(segment_id, i),
+                    ))
+                    .await
+                    .unwrap();
+                o.await?
+            }
+        }))
+    }
     async fn close(&mut self) {
         let (i, o) = oneshot::channel();
         self.channel
@@ -0,0 +1,384 @@
+//! Splits a `FlatFile` across several files of a fixed size, called volumes, such as to
+//! fit it on FAT32 formatted or optical media.
+//!
+//! The first volume lives at the path of the repository, and each further volume at the
+//! same path, with its index appended as a three digit extension, so the volumes of
+//! `backup.asuran` are `backup.asuran`, `backup.asuran.001`, `backup.asuran.002`, and so
+//! on.
+//!
+//! Each volume starts with a `VolumeHeader`, linking it to the rest of its set, followed by
+//! the next part of the `FlatFile`. `Volumes` reassembles these parts, presenting the set
+//! as one file. A `FlatFile` that was not split is presented as it is, so the same path
+//! opens either kind.
+use crate::repository::backend::{BackendError, Result};
+use asuran_core::repository::backend::flatfile::{VolumeHeader, VOLUME_MAGIC_NUMBER};
+
+use uuid::Uuid;
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A `FlatFile`, which may be split across several volumes, presented as a single
+/// `Read + Write + Seek`
+///
+/// Writing past the end of the last volume of a set starts a new volume.
+#[derive(Debug)]
+pub struct Volumes {
+    path: PathBuf,
+    files: Vec<File>,
+    /// The UUID and volume size of the set, or `None` if the file was not split
+    set: Option<(Uuid, u64)>,
+    position: u64,
+    read_only: bool,
+}
+
+impl Volumes {
+    /// Opens the `FlatFile` at the given path, along with the rest of its volumes if it was
+    /// split
+    ///
+    /// Unless opening read only, the file is created, without being split, if it does not
+    /// exist.
+    ///
+    /// # Errors
+    ///
+    /// - If an underlying I/O error occurs
+    /// - If any volume of the set is missing, incomplete, or belongs to a different set
+    pub fn open(path: impl AsRef<Path>, read_only: bool) -> Result<Volumes> {
+        let path = path.as_ref().to_owned();
+        let mut first = Self::open_file(&path, read_only)?;
+        let mut magic_number = [0_u8; 8];
+        let split = match first.read_exact(&mut magic_number) {
+            Ok(()) => magic_number == VOLUME_MAGIC_NUMBER,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e.into()),
+        };
+        if !split {
+            return Ok(Volumes {
+                path,
+                files: vec![first],
+                set: None,
+                position: 0,
+                read_only,
+            });
+        }
+        first.seek(SeekFrom::Start(0))?;
+        let header = VolumeHeader::from_read(&mut first)?;
+        if header.index != 0 || header.volume_size <= VolumeHeader::LENGTH {
+            return Err(BackendError::SegmentError(format!(
+                "{} is not the first volume of a FlatFile",
+                path.display()
+            )));
+        }
+        let set = (header.set_uuid(), header.volume_size);
+        let mut files = vec![first];
+        loop {
+            let index = files.len() as u64;
+            let volume_path = Self::volume_path(&path, index);
+            if !volume_path.exists() {
+                // Make sure the set was not cut short in the middle
+                if Self::volume_path(&path, index + 1).exists() {
+                    return Err(BackendError::SegmentError(format!(
+                        "Volume {} of the FlatFile at {} is missing",
+                        volume_path.display(),
+                        path.display()
+                    )));
+                }
+                break;
+            }
+            let mut file = Self::open_file(&volume_path, read_only)?;
+            let header = VolumeHeader::from_read(&mut file)?;
+            if (header.set_uuid(), header.volume_size) != set || header.index != index {
+                return Err(BackendError::SegmentError(format!(
+                    "Volume {} does not belong to the FlatFile at {}",
+                    volume_path.display(),
+                    path.display()
+                )));
+            }
+            files.push(file);
+        }
+        // Only the last volume may have room left in it
+        for (index, file) in files.iter().enumerate().rev().skip(1) {
+            if file.metadata()?.len() != set.1 {
+                return Err(BackendError::SegmentError(format!(
+                    "Volume {} of the FlatFile at {} is incomplete",
+                    Self::volume_path(&path, index as u64).display(),
+                    path.display()
+                )));
+            }
+        }
+        Ok(Volumes {
+            path,
+            files,
+            set: Some(set),
+            position: 0,
+            read_only,
+        })
+    }
+
+    /// Creates a new, empty, `FlatFile` at the given path, which will be split into volumes
+    /// of `volume_size` bytes, headers included
+    ///
+    /// # Errors
+    ///
+    /// - If an underlying I/O error occurs
+    /// - If a file already exists at the path
+    /// - If the volumes would not have room for anything past their headers
+    pub fn create(path: impl AsRef<Path>, volume_size: u64) -> Result<Volumes> {
+        let path = path.as_ref().to_owned();
+        if volume_size <= VolumeHeader::LENGTH {
+            return Err(BackendError::SegmentError(format!(
+                "Volumes of {} bytes do not have room for anything past their {} byte header",
+                volume_size,
+                VolumeHeader::LENGTH
+            )));
+        }
+        let mut volumes = Volumes {
+            path,
+            files: Vec::new(),
+            set: Some((Uuid::new_v4(), volume_size)),
+            position: 0,
+            read_only: false,
+        };
+        volumes.add_volume()?;
+        Ok(volumes)
+    }
+
+    /// Returns the path of the volume with the given index, for a `FlatFile` at `path`
+    pub fn volume_path(path: impl AsRef<Path>, index: u64) -> PathBuf {
+        if index == 0 {
+            path.as_ref().to_owned()
+        } else {
+            let mut name = OsString::from(path.as_ref().as_os_str());
+            name.push(format!(".{index:03}"));
+            PathBuf::from(name)
+        }
+    }
+
+    /// Returns the size of each volume, or `None` if the file was not split
+    pub fn volume_size(&self) -> Option<u64> {
+        self.set.map(|(_, volume_size)| volume_size)
+    }
+
+    /// Returns the number of volumes the file is currently split into
+    pub fn volume_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns the length of the reassembled file, without any volume headers
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an underlying I/O error occurs
+    pub fn length(&self) -> io::Result<u64> {
+        let last = self.files.len() - 1;
+        let last_length = self.files[last].metadata()?.len();
+        match self.set {
+            Some((_, volume_size)) => Ok(last as u64 * (volume_size - VolumeHeader::LENGTH)
+                + last_length.saturating_sub(VolumeHeader::LENGTH)),
+            None => Ok(last_length),
+        }
+    }
+
+    /// Truncates the reassembled file to the given length, removing any volumes that are
+    /// no longer needed
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an underlying I/O error occurs
+    pub fn set_len(&mut self, length: u64) -> io::Result<()> {
+        if let Some((_, volume_size)) = self.set {
+            let capacity = volume_size - VolumeHeader::LENGTH;
+            // Keep a volume that is filled exactly to the brim as the last one
+            let keep = usize::try_from(length.div_ceil(capacity))
+                .unwrap_or(usize::MAX)
+                .max(1);
+            while self.files.len() > keep {
+                self.files.pop();
+                remove_file(Self::volume_path(&self.path, self.files.len() as u64))?;
+            }
+            let last = self.files.len() - 1;
+            self.files[last].set_len(VolumeHeader::LENGTH + length - last as u64 * capacity)?;
+        } else {
+            self.files[0].set_len(length)?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the contents of every volume to disk
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an underlying I/O error occurs
+    pub fn sync_data(&self) -> io::Result<()> {
+        for file in &self.files {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn open_file(path: &Path, read_only: bool) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .open(path)
+    }
+
+    /// Starts the next volume of the set
+    fn add_volume(&mut self) -> io::Result<()> {
+        let (set_uuid, volume_size) = self.set.expect("Only sets of volumes can grow");
+        let index = self.files.len() as u64;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(Self::volume_path(&self.path, index))?;
+        VolumeHeader::new(set_uuid, index, volume_size)
+            .to_write(&mut file)
+            .map_err(io::Error::other)?;
+        self.files.push(file);
+        Ok(())
+    }
+
+    /// Finds the volume holding the current position, returning its index, the offset of
+    /// the position within it, and the number of bytes after the position it has room for
+    fn locate(&self) -> (usize, u64, u64) {
+        match self.set {
+            Some((_, volume_size)) => {
+                let capacity = volume_size - VolumeHeader::LENGTH;
+                let index = usize::try_from(self.position / capacity).unwrap_or(usize::MAX);
+                let offset = self.position % capacity;
+                (index, VolumeHeader::LENGTH + offset, capacity - offset)
+            }
+            None => (0, self.position, u64::MAX),
+        }
+    }
+}
+
+/// Limits a buffer length to the room left in a volume
+fn clamp(length: usize, room: u64) -> usize {
+    usize::try_from(room).map_or(length, |room| length.min(room))
+}
+
+impl Read for Volumes {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (index, offset, room) = self.locate();
+        let Some(file) = self.files.get_mut(index) else {
+            return Ok(0);
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let length = clamp(buf.len(), room);
+        let read = file.read(&mut buf[..length])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Volumes {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (index, offset, room) = self.locate();
+        if index == self.files.len() && !self.read_only {
+            self.add_volume()?;
+        }
+        let Some(file) = self.files.get_mut(index) else {
+            return Err(io::Error::other(
+                "Attempted to write past the end of a FlatFile split into volumes",
+            ));
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let length = clamp(buf.len(), room);
+        let written = file.write(&buf[..length])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        for file in &mut self.files {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for Volumes {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.length()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before the start of a FlatFile",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // Write a file across several volumes, and make sure it reads back the same after
+    // reopening, and can be truncated again
+    #[test]
+    fn volumes_round_trip() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("temp.asuran");
+        let data: Vec<u8> = (0..1000_u32).map(|i| (i % 251) as u8).collect();
+        let mut volumes = Volumes::create(&path, VolumeHeader::LENGTH + 100).unwrap();
+        volumes.write_all(&data).unwrap();
+        assert_eq!(volumes.volume_count(), 10);
+        // Rewrite some data straddling a volume boundary
+        volumes.seek(SeekFrom::Start(195)).unwrap();
+        volumes.write_all(&[0_u8; 10]).unwrap();
+        assert_eq!(volumes.length().unwrap(), 1000);
+        std::mem::drop(volumes);
+        for index in 0..10 {
+            let length = std::fs::metadata(Volumes::volume_path(&path, index))
+                .unwrap()
+                .len();
+            assert_eq!(length, VolumeHeader::LENGTH + 100);
+        }
+
+        let mut expected = data.clone();
+        expected[195..205].copy_from_slice(&[0_u8; 10]);
+        let mut volumes = Volumes::open(&path, true).unwrap();
+        assert_eq!(volumes.volume_size(), Some(VolumeHeader::LENGTH + 100));
+        let mut read = Vec::new();
+        volumes.read_to_end(&mut read).unwrap();
+        assert_eq!(read, expected);
+        std::mem::drop(volumes);
+
+        let mut volumes = Volumes::open(&path, false).unwrap();
+        volumes.set_len(250).unwrap();
+        assert_eq!(volumes.volume_count(), 3);
+        assert_eq!(volumes.length().unwrap(), 250);
+        assert!(!Volumes::volume_path(&path, 3).exists());
+        std::mem::drop(volumes);
+
+        // A set missing a volume in the middle is refused
+        std::fs::remove_file(Volumes::volume_path(&path, 1)).unwrap();
+        assert!(Volumes::open(&path, true).is_err());
+    }
+
+    // A file that was not split is passed through as it is
+    #[test]
+    fn unsplit_passthrough() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("temp.asuran");
+        let mut volumes = Volumes::open(&path, false).unwrap();
+        assert_eq!(volumes.volume_size(), None);
+        volumes.write_all(b"ASURAN_F and then some").unwrap();
+        std::mem::drop(volumes);
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"ASURAN_F and then some".to_vec()
+        );
+    }
+}
@@ -0,0 +1,439 @@
+//! A backend wrapper that injects faults into the operations on an underlying backend,
+//! for testing how the layers above it cope with failing storage.
+//!
+//! Four kinds of fault can be injected, each with its own `Trigger`:
+//!
+//! - I/O errors, returned from chunk writes in place of writing the chunk
+//! - Torn writes, where only the first half of a chunk's body is written, but the write
+//!   is reported as successful, like storage that acknowledged a write it then lost part of
+//! - Latency spikes, delaying a chunk read, chunk write, or flush
+//! - Bit flips, where a single random bit of a chunk's body is flipped as it is read
+//!
+//! Chunk writes and chunk reads are counted separately, each from 1, across the wrapper and
+//! all of its clones. Every chunk in a batch write counts as a write of its own. If a chunk
+//! in a batch is to fail, the whole batch fails without any of it being written. Latency
+//! spikes count chunk reads, chunk writes, and flushes together, with a batch counting once.
+//!
+//! Every other operation, on the index and manifest included, is passed through untouched.
+//!
+//! This module is only available in tests, or with the `faulty` feature enabled.
+use crate::repository::backend::{
+    backend_to_object, common, Backend, BackendClone, BackendError, BackendObject, CheckReport,
+    CheckpointStats, Chunk, ChunkDescriptors, ChunkID, CompactionStats, EncryptedKey, HashMap,
+    HashSet, ManifestHead, Result, SegmentDescriptor,
+};
+
+use asuran_core::repository::chunk::ChunkBody;
+
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use smol::Timer;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Decides which operations a fault is injected into
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Trigger {
+    /// Never injects the fault
+    #[default]
+    Never,
+    /// Injects the fault into only the nth operation
+    Nth(u64),
+    /// Injects the fault into every nth operation
+    Every(u64),
+    /// Injects the fault into each operation with the given probability
+    Chance(f64),
+}
+
+impl Trigger {
+    /// Decides whether the fault is injected into the operation with the given count
+    fn fires(self, count: u64, rng: &mut StdRng) -> bool {
+        match self {
+            Trigger::Never => false,
+            Trigger::Nth(n) => count == n,
+            Trigger::Every(n) => count.is_multiple_of(n),
+            Trigger::Chance(p) => rng.gen::<f64>() < p,
+        }
+    }
+}
+
+/// The faults a `Faulty` backend injects
+///
+/// The default injects nothing.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Faults {
+    /// Chunk writes that fail with an I/O error
+    pub write_error: Trigger,
+    /// Chunk writes that are torn
+    pub torn_write: Trigger,
+    /// Operations that are delayed by `latency_spike`
+    pub latency: Trigger,
+    /// How long each latency spike lasts
+    pub latency_spike: Duration,
+    /// Chunk reads that have a bit flipped
+    pub bit_flip: Trigger,
+    /// Seed for the random choices, `Trigger::Chance` and which bit gets flipped, so that
+    /// failing runs can be reproduced
+    pub seed: u64,
+}
+
+/// The counters shared between a `Faulty` backend and its clones
+#[derive(Debug)]
+struct FaultState {
+    reads: u64,
+    writes: u64,
+    operations: u64,
+    injected: u64,
+    rng: StdRng,
+}
+
+/// Injects faults into the operations on an underlying backend
+///
+/// See module level documentation for details.
+#[derive(Clone, Debug)]
+pub struct Faulty<B: BackendClone> {
+    inner: B,
+    faults: Faults,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl<B: BackendClone> Faulty<B> {
+    /// Wraps `inner`, injecting `faults` into the operations on it
+    pub fn new(inner: B, faults: Faults) -> Faulty<B> {
+        let state = FaultState {
+            reads: 0,
+            writes: 0,
+            operations: 0,
+            injected: 0,
+            rng: StdRng::seed_from_u64(faults.seed),
+        };
+        Faulty {
+            inner,
+            faults,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Returns the number of faults injected so far, by this backend and its clones
+    ///
+    /// # Panics
+    ///
+    /// Will panic if another thread panicked while injecting a fault
+    pub fn injected(&self) -> u64 {
+        self.state.lock().expect("Fault state poisoned").injected
+    }
+
+    /// Counts an operation, and delays it if a latency spike is due
+    async fn operation(&self) {
+        let delay = {
+            let mut state = self.state.lock().expect("Fault state poisoned");
+            state.operations += 1;
+            let count = state.operations;
+            let fires = self.faults.latency.fires(count, &mut state.rng);
+            if fires {
+                state.injected += 1;
+            }
+            fires
+        };
+        if delay {
+            Timer::after(self.faults.latency_spike).await;
+        }
+    }
+
+    /// Counts the writes of `chunks`, failing if any of them is due an I/O error, and tearing
+    /// those due a torn write
+    fn write_faults(&self, chunks: Vec<Chunk>) -> Result<Vec<Chunk>> {
+        let mut state = self.state.lock().expect("Fault state poisoned");
+        let mut output = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            state.writes += 1;
+            let count = state.writes;
+            if self.faults.write_error.fires(count, &mut state.rng) {
+                state.injected += 1;
+                return Err(BackendError::IOError(std::io::Error::other(format!(
+                    "Injected I/O error on chunk write {count}"
+                ))));
+            }
+            if self.faults.torn_write.fires(count, &mut state.rng) {
+                state.injected += 1;
+                let (header, ChunkBody(mut body)) = chunk.split();
+                body.truncate(body.len() / 2);
+                output.push(Chunk::unsplit(header, ChunkBody(body)));
+            } else {
+                output.push(chunk);
+            }
+        }
+        Ok(output)
+    }
+
+    /// Counts the read of `chunk`, flipping one of its bits if it is due a bit flip
+    fn read_faults(&self, chunk: Chunk) -> Chunk {
+        let mut state = self.state.lock().expect("Fault state poisoned");
+        state.reads += 1;
+        let count = state.reads;
+        if !self.faults.bit_flip.fires(count, &mut state.rng) {
+            return chunk;
+        }
+        let (header, ChunkBody(mut body)) = chunk.split();
+        if !body.is_empty() {
+            state.injected += 1;
+            let bit = state.rng.gen_range(0, body.len() * 8);
+            body[bit / 8] ^= 1 << (bit % 8);
+        }
+        Chunk::unsplit(header, ChunkBody(body))
+    }
+}
+
+#[async_trait]
+impl<B: BackendClone> Backend for Faulty<B> {
+    type Manifest = B::Manifest;
+    type Index = B::Index;
+    fn get_index(&self) -> Self::Index {
+        self.inner.get_index()
+    }
+    async fn write_key(&self, key: &EncryptedKey) -> Result<()> {
+        self.inner.write_key(key).await
+    }
+    async fn read_key(&self) -> Result<EncryptedKey> {
+        self.inner.read_key().await
+    }
+    fn get_manifest(&self) -> Self::Manifest {
+        self.inner.get_manifest()
+    }
+    /// Reads the chunk, possibly delayed, and possibly with a bit flipped
+    async fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        self.operation().await;
+        let chunk = self.inner.read_chunk(location).await?;
+        Ok(self.read_faults(chunk))
+    }
+    /// Writes the chunk, unless it is due an I/O error, possibly delayed, and possibly torn
+    async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        self.operation().await;
+        let chunk = self
+            .write_faults(vec![chunk])?
+            .pop()
+            .expect("Fault injection lost a chunk");
+        self.inner.write_chunk(chunk).await
+    }
+    /// Writes the chunks, unless any of them is due an I/O error, possibly delayed, and with
+    /// any chunks due a torn write torn
+    async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        self.operation().await;
+        let chunks = self.write_faults(chunks)?;
+        self.inner.write_chunks(chunks).await
+    }
+    /// Flushes the underlying backend, possibly delayed
+    async fn flush(&mut self) -> Result<()> {
+        self.operation().await;
+        self.inner.flush().await
+    }
+    async fn close(&mut self) {
+        self.inner.close().await;
+    }
+    async fn compact(&mut self, threshold: f64) -> Result<CompactionStats> {
+        self.inner.compact(threshold).await
+    }
+    async fn check(&mut self, repair: bool) -> Result<CheckReport> {
+        self.inner.check(repair).await
+    }
+    async fn chunk_descriptors(&mut self) -> Result<ChunkDescriptors> {
+        self.inner.chunk_descriptors().await
+    }
+    async fn checkpoint(&mut self, keep_squashed: bool) -> Result<CheckpointStats> {
+        self.inner.checkpoint(keep_squashed).await
+    }
+    async fn heads(&mut self) -> Result<Vec<ManifestHead>> {
+        self.inner.heads().await
+    }
+    async fn merge_heads(&mut self) -> Result<Option<common::ManifestID>> {
+        self.inner.merge_heads().await
+    }
+    async fn remove_archives(&mut self, archives: HashSet<ChunkID>) -> Result<CheckpointStats> {
+        self.inner.remove_archives(archives).await
+    }
+    async fn remove_chunks(&mut self, chunks: HashSet<ChunkID>) -> Result<usize> {
+        self.inner.remove_chunks(chunks).await
+    }
+    async fn counted_archives(&mut self) -> Result<Option<HashSet<ChunkID>>> {
+        self.inner.counted_archives().await
+    }
+    async fn add_references(&mut self, archive: ChunkID, chunks: HashSet<ChunkID>) -> Result<()> {
+        self.inner.add_references(archive, chunks).await
+    }
+    async fn release_references(
+        &mut self,
+        archives: HashMap<ChunkID, HashSet<ChunkID>>,
+    ) -> Result<HashSet<ChunkID>> {
+        self.inner.release_references(archives).await
+    }
+    async fn reset_references(
+        &mut self,
+        archives: HashSet<ChunkID>,
+        counts: HashMap<ChunkID, u64>,
+    ) -> Result<()> {
+        self.inner.reset_references(archives, counts).await
+    }
+    fn get_object_handle(&self) -> BackendObject {
+        backend_to_object(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::common::sync_backend::BackendHandle;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::backend::mirror::Mirror;
+    use crate::repository::*;
+
+    use std::time::Instant;
+
+    fn setup(key: &Key, faults: Faults) -> Faulty<BackendHandle<Mem>> {
+        Faulty::new(
+            Mem::new(ChunkSettings::lightweight(), key.clone(), 8),
+            faults,
+        )
+    }
+
+    fn open<B: BackendClone>(backend: B, key: &Key) -> Repository<B> {
+        Repository::with(backend, ChunkSettings::lightweight(), key.clone(), 2).unwrap()
+    }
+
+    #[test]
+    fn nth_write_fails() {
+        smol::run(async {
+            let key = Key::random(32);
+            let faults = Faults {
+                write_error: Trigger::Nth(3),
+                ..Faults::default()
+            };
+            let backend = setup(&key, faults);
+            let mut repo = open(backend.clone(), &key);
+            let mut results = Vec::new();
+            for i in 0..5_u8 {
+                results.push(repo.write_chunk(vec![i; 1024]).await);
+            }
+            assert_eq!(
+                results
+                    .iter()
+                    .map(std::result::Result::is_ok)
+                    .collect::<Vec<_>>(),
+                vec![true, true, false, true, true]
+            );
+            assert_eq!(backend.injected(), 1);
+            // The failed chunk never made it into the repository
+            let id = repo.chunk_id(&[2; 1024]);
+            assert!(!repo.has_chunk(id).await);
+            for (i, result) in (0..5_u8).zip(results) {
+                if let Ok((id, _)) = result {
+                    assert_eq!(repo.read_chunk(id).await.unwrap(), vec![i; 1024]);
+                }
+            }
+            repo.close().await;
+        });
+    }
+
+    #[test]
+    fn torn_write_fails_to_read() {
+        smol::run(async {
+            let key = Key::random(32);
+            let faults = Faults {
+                torn_write: Trigger::Nth(1),
+                ..Faults::default()
+            };
+            let mut repo = open(setup(&key, faults), &key);
+            let (torn, _) = repo.write_chunk(vec![1; 1024]).await.unwrap();
+            let (intact, _) = repo.write_chunk(vec![2; 1024]).await.unwrap();
+            assert!(repo.read_chunk(torn).await.is_err());
+            assert_eq!(repo.read_chunk(intact).await.unwrap(), vec![2; 1024]);
+            repo.close().await;
+        });
+    }
+
+    #[test]
+    fn latency_spikes() {
+        smol::run(async {
+            let key = Key::random(32);
+            let spike = Duration::from_millis(50);
+            let faults = Faults {
+                latency: Trigger::Every(2),
+                latency_spike: spike,
+                ..Faults::default()
+            };
+            let mut backend = setup(&key, faults);
+            let start = Instant::now();
+            backend.flush().await.unwrap();
+            let unaffected = start.elapsed();
+            let start = Instant::now();
+            backend.flush().await.unwrap();
+            assert!(start.elapsed() >= spike);
+            assert!(unaffected < spike);
+            assert_eq!(backend.injected(), 1);
+        });
+    }
+
+    // A flipped bit must be caught by the chunk's HMAC, and repaired by a mirror with an
+    // intact copy
+    #[test]
+    fn bit_flips_are_caught() {
+        smol::run(async {
+            let key = Key::random(32);
+            let faults = Faults {
+                bit_flip: Trigger::Every(1),
+                seed: 42,
+                ..Faults::default()
+            };
+            let mut repo = open(setup(&key, faults), &key);
+            let (id, _) = repo.write_chunk(vec![3; 1024]).await.unwrap();
+            assert!(repo.read_chunk(id).await.is_err());
+            repo.close().await;
+
+            let faulty = Faulty::new(
+                Mem::new(ChunkSettings::lightweight(), key.clone(), 8).get_object_handle(),
+                faults,
+            );
+            let intact = Mem::new(ChunkSettings::lightweight(), key.clone(), 8);
+            let mirror = Mirror::new(
+                vec![faulty.get_object_handle(), intact.get_object_handle()],
+                key.clone(),
+            )
+            .unwrap();
+            let mut repo = open(mirror, &key);
+            let (id, _) = repo.write_chunk(vec![4; 1024]).await.unwrap();
+            assert_eq!(repo.read_chunk(id).await.unwrap(), vec![4; 1024]);
+            assert!(faulty.injected() > 0);
+            repo.close().await;
+        });
+    }
+
+    #[test]
+    fn chance_is_reproducible() {
+        let faults = Faults {
+            write_error: Trigger::Chance(0.5),
+            seed: 7,
+            ..Faults::default()
+        };
+        let run = || {
+            smol::run(async {
+                let key = Key::random(32);
+                let mut repo = open(setup(&key, faults), &key);
+                let mut outcomes = Vec::new();
+                for i in 0..32_u8 {
+                    outcomes.push(repo.write_chunk(vec![i; 64]).await.is_ok());
+                }
+                repo.close().await;
+                outcomes
+            })
+        };
+        let outcomes = run();
+        assert!(outcomes.contains(&true) && outcomes.contains(&false));
+        assert_eq!(outcomes, run());
+    }
+
+    // Without any faults configured, the wrapper should behave exactly like what it wraps
+    crate::backend_conformance_tests!(volatile conformance, |setup| async move {
+        Faulty::new(Mem::new(setup.settings, setup.key, 4), Faults::default())
+    });
+}
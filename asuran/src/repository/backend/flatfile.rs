@@ -4,23 +4,26 @@ use crate::repository::backend::common::sync_backend::{
     BackendHandle, SyncBackend, SyncIndex, SyncManifest,
 };
 use crate::repository::backend::{
-    Chunk, ChunkID, ChunkSettings, DateTime, EncryptedKey, FixedOffset, SegmentDescriptor,
-    StoredArchive,
+    Chunk, ChunkID, ChunkSettings, Durability, EncryptedKey, SegmentDescriptor, StoredArchive,
+    Timestamp,
 };
 use crate::repository::Key;
 
 use std::collections::HashSet;
-use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::path::Path;
 
-pub use super::common::generic_flatfile::GenericFlatFile;
+pub use super::common::generic_flatfile::{GenericFlatFile, SequentialWriter};
+pub use super::common::volumes::Volumes;
 
-#[repr(transparent)]
+/// A flatfile repository on disk, which may be split into several volumes
+///
+/// See `Volumes` for how a repository is split.
 #[derive(Debug)]
-pub struct FlatFile(GenericFlatFile<File>);
+pub struct FlatFile(GenericFlatFile<Volumes>, Durability);
 
 impl FlatFile {
-    /// Constructs a flatfile and wraps it
+    /// Constructs a flatfile and wraps it, with the default `Durability`
     ///
     /// See the documentation for `GenericFlatFile::new_raw` for further details
     pub fn new(
@@ -29,28 +32,152 @@ impl FlatFile {
         enc_key: Option<EncryptedKey>,
         key: Key,
         queue_depth: usize,
+    ) -> Result<BackendHandle<FlatFile>> {
+        Self::new_with_durability(
+            repository_path,
+            settings,
+            enc_key,
+            key,
+            queue_depth,
+            Durability::default(),
+        )
+    }
+
+    /// Constructs a flatfile and wraps it, syncing the repository file to disk as often as
+    /// `durability` asks for
+    ///
+    /// See the documentation for `GenericFlatFile::new_raw` for further details
+    pub fn new_with_durability(
+        repository_path: impl AsRef<Path>,
+        settings: Option<ChunkSettings>,
+        enc_key: Option<EncryptedKey>,
+        key: Key,
+        queue_depth: usize,
+        durability: Durability,
     ) -> Result<BackendHandle<FlatFile>> {
         let path = repository_path.as_ref().to_owned();
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)?;
+        let file = Volumes::open(&path, false)?;
         let flat_file = GenericFlatFile::new_raw(file, path, settings, key, enc_key)?;
-        Ok(BackendHandle::new(queue_depth, move || FlatFile(flat_file)))
+        Ok(BackendHandle::new(queue_depth, move || {
+            FlatFile(flat_file, durability)
+        }))
+    }
+
+    /// Creates a new flatfile repo at the given path, split into volumes of `volume_size`
+    /// bytes, and wraps it
+    ///
+    /// New volumes are started as the repository grows. Once created, the repository is
+    /// opened like any other, through `new` or `open_read_only`, which find the rest of
+    /// its volumes.
+    ///
+    /// # Errors
+    ///
+    /// - If a file already exists at the given path
+    /// - If `volume_size` is too small to hold anything past the header of each volume
+    /// - Any of the errors `GenericFlatFile::new_raw` returns when initializing a repository
+    pub fn new_with_volumes(
+        repository_path: impl AsRef<Path>,
+        settings: ChunkSettings,
+        enc_key: EncryptedKey,
+        key: Key,
+        queue_depth: usize,
+        durability: Durability,
+        volume_size: u64,
+    ) -> Result<BackendHandle<FlatFile>> {
+        let path = repository_path.as_ref().to_owned();
+        let file = Volumes::create(&path, volume_size)?;
+        let flat_file = GenericFlatFile::new_raw(file, path, Some(settings), key, Some(enc_key))?;
+        Ok(BackendHandle::new(queue_depth, move || {
+            FlatFile(flat_file, durability)
+        }))
+    }
+
+    /// Opens the existing flatfile repo at the given path without ever writing to it, so it
+    /// may live on a read only mount
+    ///
+    /// See the documentation for `GenericFlatFile::new_read_only` for further details
+    pub fn open_read_only(
+        repository_path: impl AsRef<Path>,
+        key: Key,
+        queue_depth: usize,
+    ) -> Result<BackendHandle<FlatFile>> {
+        let path = repository_path.as_ref().to_owned();
+        let file = Volumes::open(&path, true)?;
+        let flat_file = GenericFlatFile::new_read_only(file, path, key)?;
+        Ok(BackendHandle::new(queue_depth, move || {
+            FlatFile(flat_file, Durability::default())
+        }))
+    }
+
+    /// Puts the flatfile repo at the given path in append only mode
+    ///
+    /// See `GenericFlatFile::set_append_only` for details. The repository must not be open
+    /// elsewhere while this is called.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if opening the repository, or writing the new entry, fails
+    pub fn set_append_only(repository_path: impl AsRef<Path>, key: Key) -> Result<()> {
+        let path = repository_path.as_ref().to_owned();
+        let file = Volumes::open(&path, false)?;
+        let mut flat_file = GenericFlatFile::new_raw(file, path, None, key, None)?;
+        flat_file.set_append_only();
+        flat_file.commit_index()
+    }
+
+    /// Checks the flatfile repo at the given path for a torn tail, left behind by a crash
+    /// while an entry was being written, without modifying it
+    ///
+    /// Returns the offset the file would be truncated to by `repair_tail`, or `None` if
+    /// the file is intact. See `GenericFlatFile::find_torn_tail` for details.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file can not be read, or is damaged somewhere other than
+    /// its tail
+    pub fn check_tail(repository_path: impl AsRef<Path>, key: &Key) -> Result<Option<u64>> {
+        let mut file = Volumes::open(repository_path, true)?;
+        GenericFlatFile::find_torn_tail(&mut file, key)
+    }
+
+    /// Repairs a torn tail in the flatfile repo at the given path, by truncating it to the
+    /// end of the last intact entry
+    ///
+    /// Everything in the intact entries is kept, and will be loaded into the index the next
+    /// time the repository is opened. The chunks and archives of the torn entry were never
+    /// committed, and are lost. The repository must not be open elsewhere while this is
+    /// called.
+    ///
+    /// Returns the length of the torn tail that was cut off, which is 0 if the file was
+    /// intact.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file can not be read or written, or is damaged somewhere
+    /// other than its tail
+    pub fn repair_tail(repository_path: impl AsRef<Path>, key: &Key) -> Result<u64> {
+        let mut file = Volumes::open(repository_path, false)?;
+        let length = file.length()?;
+        if let Some(offset) = GenericFlatFile::find_torn_tail(&mut file, key)? {
+            file.set_len(offset)?;
+            GenericFlatFile::terminate_at(&mut file, offset)?;
+            file.sync_data()?;
+            Ok(length - offset)
+        } else {
+            Ok(0)
+        }
     }
 
     /// Attempts to read the key from the flatfile repo at a given path
     pub fn load_encrypted_key(repository_path: impl AsRef<Path>) -> Result<EncryptedKey> {
-        let path = repository_path.as_ref().to_owned();
-        let file = OpenOptions::new().read(true).open(&path)?;
+        let file = Volumes::open(repository_path, true)?;
         GenericFlatFile::load_encrypted_key(file)
     }
 }
 
 impl SyncManifest for FlatFile {
     type Iterator = std::vec::IntoIter<StoredArchive>;
-    fn last_modification(&mut self) -> Result<DateTime<FixedOffset>> {
+    fn last_modification(&mut self) -> Result<Timestamp> {
         self.0.last_modification()
     }
     fn chunk_settings(&mut self) -> ChunkSettings {
@@ -81,7 +208,11 @@ impl SyncIndex for FlatFile {
         self.0.known_chunks()
     }
     fn commit_index(&mut self) -> Result<()> {
-        self.0.commit_index()
+        self.0.commit_index()?;
+        if self.1.sync_commits() {
+            self.0.get_ref().sync_data()?;
+        }
+        Ok(())
     }
     fn chunk_count(&mut self) -> usize {
         self.0.chunk_count()
@@ -89,6 +220,147 @@ impl SyncIndex for FlatFile {
 }
 
 impl SyncBackend for FlatFile {
+    type SyncManifest = Self;
+    type SyncIndex = Self;
+    fn get_index(&mut self) -> &mut Self::SyncIndex {
+        self
+    }
+    fn get_manifest(&mut self) -> &mut Self::SyncManifest {
+        self
+    }
+    fn write_key(&mut self, key: EncryptedKey) -> Result<()> {
+        self.0.write_key(key)
+    }
+    fn read_key(&mut self) -> Result<EncryptedKey> {
+        self.0.read_key()
+    }
+    fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        self.0.read_chunk(location)
+    }
+    fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        let descriptor = self.0.write_chunk(chunk)?;
+        if self.1.sync_writes() {
+            self.0.get_ref().sync_data()?;
+        }
+        Ok(descriptor)
+    }
+    fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let descriptors = self.0.write_chunks(chunks)?;
+        if self.1.sync_writes() {
+            self.0.get_ref().sync_data()?;
+        }
+        Ok(descriptors)
+    }
+    fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        self.0.segment_ids()
+    }
+    fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        self.0.segment_descriptors(segment_id)
+    }
+}
+
+impl Drop for FlatFile {
+    fn drop(&mut self) {
+        // Commit here, rather than leaving it to the `GenericFlatFile`, so the final commit gets
+        // synced as well. If this fails, the `GenericFlatFile` will retry, and panic.
+        let _ = SyncIndex::commit_index(self);
+    }
+}
+
+/// A new flatfile repository, streamed out to a `Write` that can not be seeked, such as
+/// standard output
+///
+/// Chunks can not be read back while the repository is being streamed. The index of the
+/// repository is written at the end of the stream, when `finish` is called, or the backend
+/// is closed. The result can be opened as an ordinary `FlatFile`.
+///
+/// See `GenericFlatFile::new_streaming` for details.
+#[derive(Debug)]
+pub struct FlatFileStream(GenericFlatFile<SequentialWriter<Box<dyn Write + Send>>>);
+
+impl FlatFileStream {
+    /// Starts streaming a new flatfile repository into `writer`, and wraps it
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing the initial headers fails
+    pub fn new(
+        writer: impl Write + Send + 'static,
+        settings: ChunkSettings,
+        enc_key: EncryptedKey,
+        key: Key,
+        queue_depth: usize,
+    ) -> Result<BackendHandle<FlatFileStream>> {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let flat_file = GenericFlatFile::new_streaming(
+            SequentialWriter::new(writer),
+            "<stream>",
+            settings,
+            key,
+            enc_key,
+        )?;
+        Ok(BackendHandle::new(queue_depth, move || {
+            FlatFileStream(flat_file)
+        }))
+    }
+
+    /// Writes out the index of the repository, and ends the stream
+    ///
+    /// This happens automatically when the backend is closed, but errors are only reported
+    /// when it is called directly, such as through `BackendHandle::with_backend`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing to, or flushing, the stream fails
+    pub fn finish(&mut self) -> Result<()> {
+        self.0.finish_stream()
+    }
+}
+
+impl SyncManifest for FlatFileStream {
+    type Iterator = std::vec::IntoIter<StoredArchive>;
+    fn last_modification(&mut self) -> Result<Timestamp> {
+        self.0.last_modification()
+    }
+    fn chunk_settings(&mut self) -> ChunkSettings {
+        self.0.chunk_settings()
+    }
+    fn archive_iterator(&mut self) -> Self::Iterator {
+        self.0.archive_iterator()
+    }
+    fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
+        self.0.write_chunk_settings(settings)
+    }
+    fn write_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        self.0.write_archive(archive)
+    }
+    fn touch(&mut self) -> Result<()> {
+        self.0.touch()
+    }
+}
+
+impl SyncIndex for FlatFileStream {
+    fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+        self.0.lookup_chunk(id)
+    }
+    fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
+        self.0.set_chunk(id, location)
+    }
+    fn known_chunks(&mut self) -> HashSet<ChunkID> {
+        self.0.known_chunks()
+    }
+    fn commit_index(&mut self) -> Result<()> {
+        self.0.commit_index()
+    }
+    fn chunk_count(&mut self) -> usize {
+        self.0.chunk_count()
+    }
+}
+
+impl SyncBackend for FlatFileStream {
     type SyncManifest = Self;
     type SyncIndex = Self;
     fn get_index(&mut self) -> &mut Self::SyncIndex {
@@ -109,13 +381,28 @@ impl SyncBackend for FlatFile {
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         self.0.write_chunk(chunk)
     }
+    fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        self.0.write_chunks(chunks)
+    }
+    fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        self.0.segment_ids()
+    }
+    fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        self.0.segment_descriptors(segment_id)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::repository::backend::Backend;
+    use crate::repository::backend::{Backend, BackendError, Index, Manifest};
     use crate::repository::{Encryption, Key};
+    use asuran_core::repository::backend::flatfile::{EntryHeader, FlatFileHeader};
+    use futures::stream::StreamExt;
+    use std::convert::TryFrom;
     use tempfile::tempdir;
 
     fn setup() -> (Key, EncryptedKey, ChunkSettings) {
@@ -150,4 +437,497 @@ mod tests {
             assert_eq!(key, new_key);
         });
     }
+
+    // Write a batch of chunks, and make sure they can be read back after reopening
+    #[test]
+    fn batch_write() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut flatfile =
+                FlatFile::new(&file, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+            let data: Vec<Vec<u8>> = (0..16_u8).map(|i| vec![i; 256]).collect();
+            let chunks: Vec<Chunk> = data
+                .iter()
+                .map(|data| {
+                    Chunk::pack(
+                        data.clone(),
+                        settings.compression,
+                        settings.encryption,
+                        settings.hmac,
+                        &key,
+                    )
+                })
+                .collect();
+            let ids: Vec<_> = chunks.iter().map(Chunk::get_id).collect();
+            let locations = flatfile.write_chunks(chunks).await.unwrap();
+            for (id, location) in ids.iter().zip(&locations) {
+                flatfile
+                    .get_index()
+                    .set_chunk(*id, *location)
+                    .await
+                    .unwrap();
+            }
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+
+            let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+            for (id, data) in ids.into_iter().zip(data) {
+                let location = flatfile.get_index().lookup_chunk(id).await.unwrap();
+                let chunk = flatfile.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), data);
+            }
+            flatfile.close().await;
+        });
+    }
+
+    // Make sure every durability setting leaves behind a readable repository
+    #[test]
+    fn durability_round_trip() {
+        smol::run(async {
+            for durability in [Durability::Always, Durability::OnCommit, Durability::Never] {
+                let (key, enc_key, settings) = setup();
+                let directory = tempdir().unwrap();
+                let file = directory.path().join("temp.asuran");
+                let mut flatfile = FlatFile::new_with_durability(
+                    &file,
+                    Some(settings),
+                    Some(enc_key),
+                    key.clone(),
+                    4,
+                    durability,
+                )
+                .unwrap();
+                let data = vec![7_u8; 256];
+                let chunk = Chunk::pack(
+                    data.clone(),
+                    settings.compression,
+                    settings.encryption,
+                    settings.hmac,
+                    &key,
+                );
+                let id = chunk.get_id();
+                let location = flatfile.write_chunk(chunk).await.unwrap();
+                flatfile.get_index().set_chunk(id, location).await.unwrap();
+                flatfile.get_index().commit_index().await.unwrap();
+                flatfile.close().await;
+                std::mem::drop(flatfile);
+
+                let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+                let location = flatfile.get_index().lookup_chunk(id).await.unwrap();
+                let chunk = flatfile.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), data);
+                flatfile.close().await;
+            }
+        });
+    }
+
+    // Writes a chunk with the given contents and commits it, returning its id
+    async fn write_entry(
+        flatfile: &mut impl Backend,
+        key: &Key,
+        settings: ChunkSettings,
+        data: Vec<u8>,
+    ) -> ChunkID {
+        let chunk = Chunk::pack(
+            data,
+            settings.compression,
+            settings.encryption,
+            settings.hmac,
+            key,
+        );
+        let id = chunk.get_id();
+        let location = flatfile.write_chunk(chunk).await.unwrap();
+        flatfile.get_index().set_chunk(id, location).await.unwrap();
+        flatfile.get_index().commit_index().await.unwrap();
+        id
+    }
+
+    // Cut the second of two entries short at a few different points, and make sure the repair
+    // brings back a repository with just the first
+    #[test]
+    fn torn_tail_repair() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut flatfile =
+                FlatFile::new(&file, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+            let first = write_entry(&mut flatfile, &key, settings, vec![1_u8; 1024]).await;
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+            let first_length = std::fs::metadata(&file).unwrap().len();
+            let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+            let second = write_entry(&mut flatfile, &key, settings, vec![2_u8; 1024]).await;
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+            let intact = std::fs::read(&file).unwrap();
+            assert_eq!(FlatFile::check_tail(&file, &key).unwrap(), None);
+            assert_eq!(FlatFile::repair_tail(&file, &key).unwrap(), 0);
+
+            // In the middle of the chunk and in the middle of the footer, the second entry is
+            // lost, but if only the final header is cut short, it is still intact
+            let cuts = [
+                (first_length + 10, false),
+                (intact.len() as u64 - 100, false),
+                (intact.len() as u64 - 5, true),
+            ];
+            for (cut, survives) in &cuts {
+                let cut = usize::try_from(*cut).unwrap();
+                std::fs::write(&file, &intact[..cut]).unwrap();
+                assert!(FlatFile::new(&file, None, None, key.clone(), 4).is_err());
+                assert!(FlatFile::check_tail(&file, &key).unwrap().is_some());
+                assert!(FlatFile::repair_tail(&file, &key).unwrap() > 0);
+                assert_eq!(FlatFile::check_tail(&file, &key).unwrap(), None);
+
+                let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+                let location = flatfile.get_index().lookup_chunk(first).await.unwrap();
+                let chunk = flatfile.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), vec![1_u8; 1024]);
+                assert_eq!(
+                    flatfile.get_index().lookup_chunk(second).await.is_some(),
+                    *survives
+                );
+                // And the repaired repository can be written to again
+                let third = write_entry(&mut flatfile, &key, settings, vec![3_u8; 1024]).await;
+                flatfile.close().await;
+                std::mem::drop(flatfile);
+                let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+                assert!(flatfile.get_index().lookup_chunk(third).await.is_some());
+                flatfile.close().await;
+            }
+
+            // Damage to an entry followed by intact entries is not a torn tail, and must be
+            // left alone. The damage goes in the encrypted data of the first footer, as the
+            // tail end of a footer holds its chunk ID, which is not covered by the MAC
+            let mut reader = std::io::Cursor::new(&intact);
+            FlatFileHeader::from_read(&mut reader).unwrap();
+            let entry_header = EntryHeader::from_read(&mut reader).unwrap();
+            let footer_data = usize::try_from(entry_header.footer_offset).unwrap() + 8 + 16;
+            let mut damaged = intact.clone();
+            damaged[footer_data] ^= 0xFF;
+            std::fs::write(&file, &damaged).unwrap();
+            assert!(FlatFile::check_tail(&file, &key).is_err());
+            assert!(FlatFile::repair_tail(&file, &key).is_err());
+            assert_eq!(std::fs::read(&file).unwrap(), damaged);
+        });
+    }
+
+    // Chunks from previous sessions and the current one should all be listed, in file order
+    #[test]
+    fn chunk_descriptors() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut flatfile =
+                FlatFile::new(&file, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+            let first = write_entry(&mut flatfile, &key, settings, vec![1_u8; 1024]).await;
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+            let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+            let second = write_entry(&mut flatfile, &key, settings, vec![2_u8; 1024]).await;
+            let listed: Vec<_> = flatfile
+                .chunk_descriptors()
+                .await
+                .unwrap()
+                .map(std::result::Result::unwrap)
+                .collect()
+                .await;
+            let mut index = flatfile.get_index();
+            let expected = vec![
+                (first, index.lookup_chunk(first).await.unwrap()),
+                (second, index.lookup_chunk(second).await.unwrap()),
+            ];
+            assert_eq!(listed, expected);
+            flatfile.close().await;
+        });
+    }
+
+    // Put a flatfile in append only mode, and make sure it persists and refuses to rewrite data
+    #[test]
+    fn append_only() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut flatfile =
+                FlatFile::new(&file, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+            let chunk = Chunk::pack(
+                vec![1_u8; 1024],
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &key,
+            );
+            let id = chunk.get_id();
+            let location = flatfile.write_chunk(chunk.clone()).await.unwrap();
+            flatfile.get_index().set_chunk(id, location).await.unwrap();
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+            // Turn on append only mode and reopen
+            FlatFile::set_append_only(&file, key.clone()).unwrap();
+            let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+            // Writing new data is still allowed
+            let new_location = flatfile.write_chunk(chunk).await.unwrap();
+            // Setting a chunk to its existing location is a no-op
+            flatfile.get_index().set_chunk(id, location).await.unwrap();
+            // But rewriting any existing data is not
+            assert!(matches!(
+                flatfile.get_index().set_chunk(id, new_location).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            assert!(matches!(
+                flatfile.get_manifest().write_chunk_settings(settings).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            flatfile.close().await;
+        });
+    }
+
+    // Open a repository read only, and make sure it can be read from, refuses to be written to,
+    // and is left untouched
+    #[test]
+    fn read_only() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut flatfile =
+                FlatFile::new(&file, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+            let data = vec![1_u8; 1024];
+            let chunk = Chunk::pack(
+                data.clone(),
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &key,
+            );
+            let id = chunk.get_id();
+            let location = flatfile.write_chunk(chunk.clone()).await.unwrap();
+            flatfile.get_index().set_chunk(id, location).await.unwrap();
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+            let before = std::fs::read(&file).unwrap();
+
+            let mut flatfile = FlatFile::open_read_only(&file, key.clone(), 4).unwrap();
+            let location = flatfile.get_index().lookup_chunk(id).await.unwrap();
+            let read = flatfile.read_chunk(location).await.unwrap();
+            assert_eq!(read.unpack(&key).unwrap(), data);
+            assert!(matches!(
+                flatfile.write_chunk(chunk).await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            assert!(matches!(
+                flatfile.get_index().set_chunk(id, location).await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            assert!(matches!(
+                flatfile.get_manifest().write_chunk_settings(settings).await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+            assert_eq!(std::fs::read(&file).unwrap(), before);
+            // There is nothing to read in an empty file
+            let empty = directory.path().join("empty.asuran");
+            std::fs::File::create(&empty).unwrap();
+            assert!(FlatFile::open_read_only(&empty, key, 4).is_err());
+        });
+    }
+
+    // Stream a repository into a file, then make sure it opens as an ordinary flatfile, which
+    // can be appended to
+    #[test]
+    fn stream_then_append() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut stream = FlatFileStream::new(
+                std::fs::File::create(&file).unwrap(),
+                settings,
+                enc_key,
+                key.clone(),
+                4,
+            )
+            .unwrap();
+            let first = write_entry(&mut stream, &key, settings, vec![1_u8; 1024]).await;
+            let location = stream.get_index().lookup_chunk(first).await.unwrap();
+            assert!(matches!(
+                stream.read_chunk(location).await,
+                Err(BackendError::Unsupported(_))
+            ));
+            let archive = StoredArchive {
+                name: "streamed".to_string(),
+                id: ChunkID::new(&[7_u8; 32]),
+                timestamp: Timestamp::now(),
+                metadata: crate::manifest::ArchiveMetadata::default(),
+                integrity: None,
+                signature: None,
+                duration: None,
+            };
+            stream
+                .get_manifest()
+                .write_archive(archive.clone())
+                .await
+                .unwrap();
+            stream.with_backend(FlatFileStream::finish).await.unwrap();
+            // Nothing more can be written once the stream is finished
+            assert!(stream
+                .write_chunk(Chunk::pack(
+                    vec![],
+                    settings.compression,
+                    settings.encryption,
+                    settings.hmac,
+                    &key,
+                ))
+                .await
+                .is_err());
+            stream.close().await;
+            std::mem::drop(stream);
+            let streamed = std::fs::read(&file).unwrap();
+
+            // Reading the stream does not touch it
+            let mut flatfile = FlatFile::open_read_only(&file, key.clone(), 4).unwrap();
+            let location = flatfile.get_index().lookup_chunk(first).await.unwrap();
+            let chunk = flatfile.read_chunk(location).await.unwrap();
+            assert_eq!(chunk.unpack(&key).unwrap(), vec![1_u8; 1024]);
+            let archives: Vec<_> = flatfile.get_manifest().archive_iterator().await.collect();
+            assert_eq!(archives.len(), 1);
+            assert_eq!(archives[0].id, archive.id);
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+            assert_eq!(std::fs::read(&file).unwrap(), streamed);
+
+            // Appending turns it into an ordinary flatfile
+            let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+            let second = write_entry(&mut flatfile, &key, settings, vec![2_u8; 1024]).await;
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+            let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+            for (id, data) in &[(first, 1_u8), (second, 2_u8)] {
+                let location = flatfile.get_index().lookup_chunk(*id).await.unwrap();
+                let chunk = flatfile.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), vec![*data; 1024]);
+            }
+            assert_eq!(flatfile.get_manifest().archive_iterator().await.count(), 1);
+            flatfile.close().await;
+
+            // A stream cut short has no index, and is refused
+            std::fs::write(&file, &streamed[..streamed.len() - 10]).unwrap();
+            assert!(FlatFile::new(&file, None, None, key.clone(), 4).is_err());
+        });
+    }
+
+    // Write enough to fill several volumes, across a few entries, and make sure it all reads
+    // back through the first volume, even after repairing a torn tail
+    #[test]
+    fn volumes() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut flatfile = FlatFile::new_with_volumes(
+                &file,
+                settings,
+                enc_key.clone(),
+                key.clone(),
+                4,
+                Durability::default(),
+                4096,
+            )
+            .unwrap();
+            let mut ids = Vec::new();
+            for i in 0..8_u8 {
+                ids.push((
+                    write_entry(&mut flatfile, &key, settings, vec![i; 3000]).await,
+                    i,
+                ));
+            }
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+            assert!(Volumes::volume_path(&file, 5).exists());
+            for index in 0..5 {
+                let length = std::fs::metadata(Volumes::volume_path(&file, index))
+                    .unwrap()
+                    .len();
+                assert_eq!(length, 4096);
+            }
+            // The key is read from the first volume
+            assert_eq!(
+                FlatFile::load_encrypted_key(&file)
+                    .unwrap()
+                    .decrypt(b"A Very strong password")
+                    .unwrap(),
+                key
+            );
+            assert!(FlatFile::new_with_volumes(
+                &file,
+                settings,
+                enc_key,
+                key.clone(),
+                4,
+                Durability::default(),
+                4096
+            )
+            .is_err());
+
+            let mut flatfile = FlatFile::open_read_only(&file, key.clone(), 4).unwrap();
+            for (id, data) in &ids {
+                let location = flatfile.get_index().lookup_chunk(*id).await.unwrap();
+                let chunk = flatfile.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), vec![*data; 3000]);
+            }
+            flatfile.close().await;
+            std::mem::drop(flatfile);
+
+            // Tear the last entry, in the middle of its chunk
+            let mut volumes = Volumes::open(&file, false).unwrap();
+            let length = volumes.length().unwrap();
+            volumes.set_len(length - 2000).unwrap();
+            std::mem::drop(volumes);
+            assert!(FlatFile::check_tail(&file, &key).unwrap().is_some());
+            assert!(FlatFile::repair_tail(&file, &key).unwrap() > 0);
+            let mut flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+            let (last, _) = ids.pop().unwrap();
+            assert!(flatfile.get_index().lookup_chunk(last).await.is_none());
+            for (id, data) in &ids {
+                let location = flatfile.get_index().lookup_chunk(*id).await.unwrap();
+                let chunk = flatfile.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), vec![*data; 3000]);
+            }
+            flatfile.close().await;
+        });
+    }
+
+    crate::backend_conformance_tests!(conformance, |setup| async move {
+        let path = setup.path.join("repository.asuran");
+        // The key can only be given to a flatfile when it is created
+        let enc_key = if path.exists() {
+            None
+        } else {
+            Some(setup.encrypted_key)
+        };
+        FlatFile::new(path, Some(setup.settings), enc_key, setup.key, 4).unwrap()
+    });
+
+    crate::backend_conformance_tests!(conformance_volumes, |setup| async move {
+        let path = setup.path.join("repository.asuran");
+        if path.exists() {
+            FlatFile::new(path, None, None, setup.key, 4).unwrap()
+        } else {
+            FlatFile::new_with_volumes(
+                path,
+                setup.settings,
+                setup.encrypted_key,
+                setup.key,
+                4,
+                Durability::default(),
+                64 * 1024,
+            )
+            .unwrap()
+        }
+    });
 }
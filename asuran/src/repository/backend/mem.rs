@@ -4,15 +4,32 @@ use crate::repository::backend::common::sync_backend::{
     BackendHandle, SyncBackend, SyncIndex, SyncManifest,
 };
 use crate::repository::backend::{
-    BackendError, ChunkID, ChunkSettings, DateTime, FixedOffset, HashSet, SegmentDescriptor,
-    StoredArchive,
+    BackendError, ChunkID, ChunkSettings, HashSet, SegmentDescriptor, StoredArchive, Timestamp,
 };
 use crate::repository::{Chunk, EncryptedKey, Key};
 
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::Cursor;
 
+/// The full state of a `Mem` backend, from which an identical backend can be restored
+///
+/// Taking a snapshot and restoring it later simulates a crash and restart of the process
+/// holding the backend, without touching disk. Snapshots are serializable, so they can be
+/// stored alongside a test case or fuzzer input.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemSnapshot {
+    segment: Vec<u8>,
+    segment_header: Vec<u8>,
+    index: HashMap<ChunkID, SegmentDescriptor>,
+    manifest: Vec<StoredArchive>,
+    chunk_settings: ChunkSettings,
+    key: Option<EncryptedKey>,
+}
+
 pub struct Mem {
     data: common::Segment<Cursor<Vec<u8>>>,
     index: HashMap<ChunkID, SegmentDescriptor>,
@@ -45,11 +62,82 @@ impl Mem {
     pub fn new(chunk_settings: ChunkSettings, key: Key, queue_depth: usize) -> BackendHandle<Mem> {
         BackendHandle::new(queue_depth, move || Self::new_raw(chunk_settings, key))
     }
+
+    /// Recreates a backend from a snapshot, see `BackendHandle::<Mem>::snapshot`
+    ///
+    /// `key` must be the key the backend the snapshot was taken of was created with.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the snapshot's segment is malformed, or can not be decrypted with
+    /// `key`
+    pub fn restore_raw(snapshot: MemSnapshot, key: Key) -> Result<Mem> {
+        let data = common::Segment::new(
+            Cursor::new(snapshot.segment),
+            Cursor::new(snapshot.segment_header),
+            u64::MAX,
+            snapshot.chunk_settings,
+            key,
+        )?;
+        Ok(Mem {
+            data,
+            index: snapshot.index,
+            manifest: snapshot.manifest,
+            chunk_settings: snapshot.chunk_settings,
+            key: snapshot.key,
+        })
+    }
+
+    /// Recreates a backend from a snapshot, and wraps it in a `BackendHandle`
+    ///
+    /// # Errors
+    ///
+    /// See `restore_raw`
+    pub fn restore(
+        snapshot: MemSnapshot,
+        key: Key,
+        queue_depth: usize,
+    ) -> Result<BackendHandle<Mem>> {
+        let mem = Self::restore_raw(snapshot, key)?;
+        Ok(BackendHandle::new(queue_depth, move || mem))
+    }
+
+    /// Captures the full state of the backend
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the segment's header can not be written out
+    pub fn snapshot_raw(&mut self) -> Result<MemSnapshot> {
+        let (segment, segment_header) = self.data.contents()?;
+        Ok(MemSnapshot {
+            segment,
+            segment_header,
+            index: self.index.clone(),
+            manifest: self.manifest.clone(),
+            chunk_settings: self.chunk_settings,
+            key: self.key.clone(),
+        })
+    }
+}
+
+impl BackendHandle<Mem> {
+    /// Captures the full state of the backend, see `MemSnapshot`
+    ///
+    /// The snapshot is taken in order with the other operations sent through this handle and
+    /// its clones, so it never contains half of an operation, even while other tasks are using
+    /// the backend.
+    ///
+    /// # Errors
+    ///
+    /// See `Mem::snapshot_raw`
+    pub async fn snapshot(&mut self) -> Result<MemSnapshot> {
+        self.with_backend(Mem::snapshot_raw).await
+    }
 }
 
 impl SyncManifest for Mem {
     type Iterator = std::vec::IntoIter<StoredArchive>;
-    fn last_modification(&mut self) -> Result<DateTime<FixedOffset>> {
+    fn last_modification(&mut self) -> Result<Timestamp> {
         if self.manifest.is_empty() {
             Err(BackendError::ManifestError(
                 "No archives/timestamps present".to_string(),
@@ -63,7 +151,10 @@ impl SyncManifest for Mem {
         self.chunk_settings
     }
     fn archive_iterator(&mut self) -> Self::Iterator {
-        self.manifest.clone().into_iter()
+        // Archives are kept in the order they were written, but listed newest first
+        let mut archives = self.manifest.iter().rev().cloned().collect::<Vec<_>>();
+        archives.sort_by_key(|archive| Reverse(archive.timestamp()));
+        archives.into_iter()
     }
     fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
         self.chunk_settings = settings;
@@ -131,6 +222,31 @@ impl SyncBackend for Mem {
             start,
         })
     }
+    fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let starts = self.data.write_chunks(chunks)?;
+        Ok(starts
+            .into_iter()
+            .map(|start| SegmentDescriptor {
+                segment_id: 0,
+                start,
+            })
+            .collect())
+    }
+    fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        Ok(vec![0])
+    }
+    fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        if segment_id == 0 {
+            Ok(self.data.chunk_descriptors(0))
+        } else {
+            Err(BackendError::SegmentError(format!(
+                "Mem backend only has segment 0, not {segment_id}"
+            )))
+        }
+    }
 }
 
 impl std::fmt::Debug for Mem {
@@ -142,7 +258,9 @@ impl std::fmt::Debug for Mem {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::repository::backend::Manifest as BackendManifest;
     use crate::repository::*;
+    use futures::stream::StreamExt;
 
     /// Makes sure accessing an unset key panics
     #[test]
@@ -169,4 +287,114 @@ mod tests {
             assert_eq!(key, output);
         });
     }
+
+    /// Checks that every chunk written gets listed, indexed or not
+    #[test]
+    fn chunk_descriptors() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let mut backend = Mem::new(settings, key.clone(), 8);
+            let mut written = Vec::new();
+            for i in 0..8_u8 {
+                let chunk = Chunk::pack(
+                    vec![i; 256],
+                    settings.compression,
+                    settings.encryption,
+                    settings.hmac,
+                    &key,
+                );
+                let id = chunk.get_id();
+                written.push((id, backend.write_chunk(chunk).await.unwrap()));
+            }
+            let listed: Vec<_> = backend
+                .chunk_descriptors()
+                .await
+                .unwrap()
+                .map(std::result::Result::unwrap)
+                .collect()
+                .await;
+            assert_eq!(listed, written);
+        });
+    }
+
+    /// Checks that a restored snapshot has everything written before it was taken, and nothing
+    /// written after, and can keep being written to
+    #[test]
+    fn snapshot_restore() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let mut backend = Mem::new(settings, key.clone(), 8);
+            let key_key = [0_u8; 128];
+            let encrypted_key =
+                EncryptedKey::encrypt(&key, 1024, 1, Encryption::new_aes256ctr(), &key_key);
+            backend.write_key(&encrypted_key).await.unwrap();
+            let pack = |i: u8| {
+                Chunk::pack(
+                    vec![i; 256],
+                    settings.compression,
+                    settings.encryption,
+                    settings.hmac,
+                    &key,
+                )
+            };
+            let mut written = Vec::new();
+            for i in 0..8_u8 {
+                let chunk = pack(i);
+                let id = chunk.get_id();
+                let location = backend.write_chunk(chunk).await.unwrap();
+                backend.get_index().set_chunk(id, location).await.unwrap();
+                written.push((i, id, location));
+            }
+            let archive = StoredArchive::dummy_archive();
+            backend
+                .get_manifest()
+                .write_archive(archive.clone())
+                .await
+                .unwrap();
+
+            let snapshot = backend.snapshot().await.unwrap();
+            // Nothing written after the snapshot may show up in it
+            let late = pack(100);
+            let late_id = late.get_id();
+            let location = backend.write_chunk(late).await.unwrap();
+            backend
+                .get_index()
+                .set_chunk(late_id, location)
+                .await
+                .unwrap();
+            backend.close().await;
+
+            let snapshot: MemSnapshot =
+                rmp_serde::from_slice(&rmp_serde::to_vec(&snapshot).unwrap()).unwrap();
+            let mut restored = Mem::restore(snapshot, key.clone(), 8).unwrap();
+            let output = restored
+                .read_key()
+                .await
+                .unwrap()
+                .decrypt(&key_key)
+                .unwrap();
+            assert_eq!(key, output);
+            let archives: Vec<_> = restored.get_manifest().archive_iterator().await.collect();
+            assert_eq!(archives, vec![archive]);
+            let mut index = restored.get_index();
+            assert_eq!(index.count_chunk().await, written.len());
+            assert!(!index.contains_chunk(late_id).await);
+            for (i, id, location) in written {
+                assert_eq!(index.lookup_chunk(id).await, Some(location));
+                let chunk = restored.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), vec![i; 256]);
+            }
+            // The restored segment must be appended to, not overwritten
+            let location = restored.write_chunk(pack(200)).await.unwrap();
+            let chunk = restored.read_chunk(location).await.unwrap();
+            assert_eq!(chunk.unpack(&key).unwrap(), vec![200; 256]);
+            restored.close().await;
+        });
+    }
+
+    crate::backend_conformance_tests!(volatile conformance, |setup| async move {
+        Mem::new(setup.settings, setup.key, 4)
+    });
 }
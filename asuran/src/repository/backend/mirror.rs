@@ -0,0 +1,516 @@
+//! A backend wrapper that keeps a full copy of a repository on each of several
+//! underlying backends, and heals damaged chunks from the healthy copies.
+//!
+//! Every write, whether of a chunk, an archive, the chunk settings, or the key, goes to
+//! every replica, and each replica keeps its own index, so every replica remains a
+//! complete repository that can be opened on its own.
+//!
+//! Reads are served from the first replica that has the requested chunk. If reading the
+//! chunk from that replica fails, or the chunk it returns fails to verify against its
+//! HMAC, the chunk is fetched from the other replicas in order, and the first copy that
+//! verifies is returned and written back to the replica that failed.
+//!
+//! # Segment Descriptors
+//!
+//! The index of the replica a chunk was located on is packed into the upper 16 bits
+//! of the `segment_id` of the `SegmentDescriptor` handed out by the `MirrorIndex`. These
+//! descriptors are never persisted, each replica only ever stores its own descriptors.
+use crate::repository::backend::{
+    backend_to_object, Backend, BackendClone, BackendError, BackendObject, Chunk, ChunkDescriptors,
+    ChunkID, ChunkSettings, EncryptedKey, HashSet, Index, Manifest, Result, SegmentDescriptor,
+    StoredArchive, Timestamp,
+};
+use crate::repository::Key;
+
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use tracing::warn;
+
+use std::convert::TryInto;
+
+/// The number of bits the replica index is shifted into the `segment_id`
+const REPLICA_SHIFT: u32 = 48;
+
+/// The maximum number of replicas a `Mirror` backend can have
+pub const MAX_REPLICAS: usize = 1 << (64 - REPLICA_SHIFT);
+
+/// Packs the index of a replica into a descriptor from that replica
+fn encode_location(replica: usize, location: SegmentDescriptor) -> Result<SegmentDescriptor> {
+    if location.segment_id >> REPLICA_SHIFT != 0 {
+        return Err(BackendError::SegmentError(format!(
+            "Segment id {} of replica {} is too large to be mirrored",
+            location.segment_id, replica
+        )));
+    }
+    // The constructor ensures that every replica index fits in the upper bits
+    let replica: u64 = replica
+        .try_into()
+        .expect("Replica index did not fit in a u64");
+    Ok(SegmentDescriptor {
+        segment_id: (replica << REPLICA_SHIFT) | location.segment_id,
+        start: location.start,
+    })
+}
+
+/// Splits a descriptor handed out by the `MirrorIndex` into the index of its replica,
+/// and the descriptor within that replica
+fn decode_location(location: SegmentDescriptor) -> (usize, SegmentDescriptor) {
+    let replica = (location.segment_id >> REPLICA_SHIFT)
+        .try_into()
+        .expect("Replica index did not fit in a usize");
+    let inner = SegmentDescriptor {
+        segment_id: location.segment_id & ((1 << REPLICA_SHIFT) - 1),
+        start: location.start,
+    };
+    (replica, inner)
+}
+
+/// Mirrors a repository across a number of backends
+///
+/// See module level documentation for details.
+#[derive(Clone)]
+pub struct Mirror<B: BackendClone> {
+    replicas: Vec<B>,
+    /// Used to verify the HMACs of chunks read from the replicas
+    key: Key,
+}
+
+impl<B: BackendClone> Mirror<B> {
+    /// Creates a new `Mirror` backend over `replicas`, using `key` to detect damaged chunks
+    ///
+    /// Reads are tried against the replicas in the order provided, so the fastest or most
+    /// reliable replica should come first.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if fewer than 2, or more than `MAX_REPLICAS`, replicas are
+    /// provided
+    pub fn new(replicas: Vec<B>, key: Key) -> Result<Mirror<B>> {
+        if replicas.len() < 2 || replicas.len() > MAX_REPLICAS {
+            return Err(BackendError::SegmentError(format!(
+                "A mirrored backend requires between 2 and {} replicas, {} were provided",
+                MAX_REPLICAS,
+                replicas.len()
+            )));
+        }
+        Ok(Mirror { replicas, key })
+    }
+
+    /// Returns the number of replicas the repository is mirrored across
+    pub fn replica_count(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// Reads a chunk from a single replica, only returning it if it verifies
+    ///
+    /// If the chunk could be read, but failed to verify, it is returned as the error.
+    async fn read_verified(
+        &mut self,
+        replica: usize,
+        location: SegmentDescriptor,
+    ) -> std::result::Result<Chunk, (BackendError, Option<Chunk>)> {
+        match self.replicas[replica].read_chunk(location).await {
+            Ok(chunk) if chunk.verify_mac(&self.key) => Ok(chunk),
+            Ok(chunk) => Err((
+                BackendError::SegmentError(format!(
+                    "Chunk at {location:?} on replica {replica} failed to verify"
+                )),
+                Some(chunk),
+            )),
+            Err(e) => Err((e, None)),
+        }
+    }
+
+    /// Works out which chunk is stored at a location on a replica
+    ///
+    /// The ID recorded in the damaged chunk is tried first, but as it may itself be
+    /// damaged, it is only trusted if the index of the replica agrees with it. Otherwise,
+    /// the whole index of the replica is searched.
+    async fn find_id(
+        &self,
+        replica: usize,
+        location: SegmentDescriptor,
+        damaged: Option<&Chunk>,
+    ) -> Option<ChunkID> {
+        let mut index = self.replicas[replica].get_index();
+        if let Some(chunk) = damaged {
+            let id = chunk.get_id();
+            if index.lookup_chunk(id).await == Some(location) {
+                return Some(id);
+            }
+        }
+        for id in index.known_chunks().await {
+            if index.lookup_chunk(id).await == Some(location) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// Fetches a chunk from any replica other than `failed` that has a copy which
+    /// verifies, and writes it back to `failed`
+    async fn heal(&mut self, failed: usize, id: ChunkID) -> Option<Chunk> {
+        for replica in (0..self.replicas.len()).filter(|x| *x != failed) {
+            let location = self.replicas[replica].get_index().lookup_chunk(id).await;
+            if let Some(location) = location {
+                if let Ok(chunk) = self.read_verified(replica, location).await {
+                    if let Err(e) = self.rewrite(failed, id, chunk.clone()).await {
+                        warn!(
+                            "Unable to rewrite chunk {:?} to replica {}: {}",
+                            id, failed, e
+                        );
+                    }
+                    return Some(chunk);
+                }
+            }
+        }
+        None
+    }
+
+    /// Writes a chunk to a single replica, and points its index at the new copy
+    async fn rewrite(&mut self, replica: usize, id: ChunkID, chunk: Chunk) -> Result<()> {
+        let location = self.replicas[replica].write_chunk(chunk).await?;
+        self.replicas[replica]
+            .get_index()
+            .set_chunk(id, location)
+            .await
+    }
+}
+
+#[async_trait]
+impl<B: BackendClone> Backend for Mirror<B> {
+    type Manifest = MirrorManifest<B::Manifest>;
+    type Index = MirrorIndex<B::Index>;
+    /// Returns a view over the indexes of all the replicas
+    fn get_index(&self) -> Self::Index {
+        MirrorIndex {
+            indexes: self.replicas.iter().map(Backend::get_index).collect(),
+        }
+    }
+    /// Writes the key to every replica
+    async fn write_key(&self, key: &EncryptedKey) -> Result<()> {
+        for replica in &self.replicas {
+            replica.write_key(key).await?;
+        }
+        Ok(())
+    }
+    /// Reads the key from the first replica that has a readable copy
+    async fn read_key(&self) -> Result<EncryptedKey> {
+        let mut error = None;
+        for replica in &self.replicas {
+            match replica.read_key().await {
+                Ok(key) => return Ok(key),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.expect("Mirror had no replicas"))
+    }
+    /// Returns a view over the manifests of all the replicas
+    fn get_manifest(&self) -> Self::Manifest {
+        MirrorManifest {
+            manifests: self.replicas.iter().map(Backend::get_manifest).collect(),
+        }
+    }
+    /// Reads the chunk from the replica encoded in its location, falling back to the
+    /// other replicas, and healing the original copy, if it is damaged
+    async fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        let (replica, location) = decode_location(location);
+        if replica >= self.replicas.len() {
+            return Err(BackendError::SegmentError(format!(
+                "Chunk was located on replica {}, but only {} replicas are present",
+                replica,
+                self.replicas.len()
+            )));
+        }
+        let (error, damaged) = match self.read_verified(replica, location).await {
+            Ok(chunk) => return Ok(chunk),
+            Err(failure) => failure,
+        };
+        warn!("Failed to read chunk from replica {}: {}", replica, error);
+        if let Some(id) = self.find_id(replica, location, damaged.as_ref()).await {
+            if let Some(chunk) = self.heal(replica, id).await {
+                return Ok(chunk);
+            }
+        }
+        Err(error)
+    }
+    /// Writes the chunk to every replica, returning its location on the first
+    ///
+    /// The indexes of the other replicas are updated immediately, the first replica's
+    /// is updated when the returned location is set in the `MirrorIndex`.
+    async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        let id = chunk.get_id();
+        for replica in 1..self.replicas.len() {
+            self.rewrite(replica, id, chunk.clone()).await?;
+        }
+        let location = self.replicas[0].write_chunk(chunk).await?;
+        encode_location(0, location)
+    }
+    /// Writes the chunks to every replica as a single batch, returning their locations on
+    /// the first
+    async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let ids: Vec<ChunkID> = chunks.iter().map(Chunk::get_id).collect();
+        for replica in 1..self.replicas.len() {
+            let locations = self.replicas[replica].write_chunks(chunks.clone()).await?;
+            let mut index = self.replicas[replica].get_index();
+            for (id, location) in ids.iter().zip(locations) {
+                index.set_chunk(*id, location).await?;
+            }
+        }
+        let locations = self.replicas[0].write_chunks(chunks).await?;
+        locations
+            .into_iter()
+            .map(|location| encode_location(0, location))
+            .collect()
+    }
+    /// Flushes every replica
+    async fn flush(&mut self) -> Result<()> {
+        for replica in &mut self.replicas {
+            replica.flush().await?;
+        }
+        Ok(())
+    }
+    /// Lists the chunks stored on the first replica
+    async fn chunk_descriptors(&mut self) -> Result<ChunkDescriptors> {
+        let descriptors = self.replicas[0].chunk_descriptors().await?;
+        Ok(descriptors
+            .map(|descriptor| {
+                let (id, location) = descriptor?;
+                Ok((id, encode_location(0, location)?))
+            })
+            .boxed())
+    }
+    /// Closes every replica
+    async fn close(&mut self) {
+        for replica in &mut self.replicas {
+            replica.close().await;
+        }
+    }
+    fn get_object_handle(&self) -> BackendObject {
+        backend_to_object(self.clone())
+    }
+}
+
+impl<B: BackendClone> std::fmt::Debug for Mirror<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mirror")
+            .field("replicas", &self.replicas)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A view over the indexes of every replica of a `Mirror`
+#[derive(Debug)]
+pub struct MirrorIndex<I: Index> {
+    indexes: Vec<I>,
+}
+
+#[async_trait]
+impl<I: Index> Index for MirrorIndex<I> {
+    /// Returns the location of the chunk on the first replica that has it
+    async fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+        for (replica, index) in self.indexes.iter_mut().enumerate() {
+            if let Some(location) = index.lookup_chunk(id).await {
+                return encode_location(replica, location).ok();
+            }
+        }
+        None
+    }
+    /// Sets the location of the chunk in the index of the replica encoded in the location
+    async fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
+        let (replica, location) = decode_location(location);
+        let replica_count = self.indexes.len();
+        let index = self.indexes.get_mut(replica).ok_or_else(|| {
+            BackendError::IndexError(format!(
+                "Chunk was located on replica {replica}, but only {replica_count} replicas are present"
+            ))
+        })?;
+        index.set_chunk(id, location).await
+    }
+    /// Returns true if at least one replica has the chunk
+    async fn contains_chunk(&mut self, id: ChunkID) -> bool {
+        for index in &mut self.indexes {
+            if index.contains_chunk(id).await {
+                return true;
+            }
+        }
+        false
+    }
+    /// Returns every chunk known to at least one replica
+    async fn known_chunks(&mut self) -> HashSet<ChunkID> {
+        let mut chunks = HashSet::new();
+        for index in &mut self.indexes {
+            chunks.extend(index.known_chunks().await);
+        }
+        chunks
+    }
+    /// Commits the index of every replica
+    async fn commit_index(&mut self) -> Result<()> {
+        for index in &mut self.indexes {
+            index.commit_index().await?;
+        }
+        Ok(())
+    }
+    async fn count_chunk(&mut self) -> usize {
+        self.known_chunks().await.len()
+    }
+}
+
+/// A view over the manifests of every replica of a `Mirror`
+///
+/// Reads are served by the first replica that can answer them, and writes go to every
+/// replica.
+#[derive(Debug)]
+pub struct MirrorManifest<M: Manifest> {
+    manifests: Vec<M>,
+}
+
+#[async_trait]
+impl<M: Manifest> Manifest for MirrorManifest<M> {
+    type Iterator = M::Iterator;
+    async fn last_modification(&mut self) -> Result<Timestamp> {
+        let mut error = None;
+        for manifest in &mut self.manifests {
+            match manifest.last_modification().await {
+                Ok(timestamp) => return Ok(timestamp),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.expect("Mirror had no replicas"))
+    }
+    async fn chunk_settings(&mut self) -> ChunkSettings {
+        self.manifests[0].chunk_settings().await
+    }
+    async fn archive_iterator(&mut self) -> Self::Iterator {
+        self.manifests[0].archive_iterator().await
+    }
+    async fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
+        for manifest in &mut self.manifests {
+            manifest.write_chunk_settings(settings).await?;
+        }
+        Ok(())
+    }
+    async fn write_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        for manifest in &mut self.manifests {
+            manifest.write_archive(archive.clone()).await?;
+        }
+        Ok(())
+    }
+    async fn touch(&mut self) -> Result<()> {
+        for manifest in &mut self.manifests {
+            manifest.touch().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::common::sync_backend::BackendHandle;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::*;
+
+    fn setup(key: &Key, replicas: usize) -> Mirror<BackendHandle<Mem>> {
+        let settings = ChunkSettings::lightweight();
+        let replicas = (0..replicas)
+            .map(|_| Mem::new(settings, key.clone(), 8))
+            .collect();
+        Mirror::new(replicas, key.clone()).unwrap()
+    }
+
+    #[test]
+    fn location_round_trip() {
+        let location = SegmentDescriptor {
+            segment_id: 12,
+            start: 345,
+        };
+        let encoded = encode_location(3, location).unwrap();
+        assert_eq!(decode_location(encoded), (3, location));
+        let too_large = SegmentDescriptor {
+            segment_id: 1 << REPLICA_SHIFT,
+            start: 0,
+        };
+        assert!(encode_location(0, too_large).is_err());
+    }
+
+    #[test]
+    fn too_few_replicas() {
+        let key = Key::random(32);
+        let replica = Mem::new(ChunkSettings::lightweight(), key.clone(), 8);
+        assert!(Mirror::new(vec![replica], key).is_err());
+    }
+
+    // Chunks written through the mirror should be readable from every replica on its own
+    #[test]
+    fn writes_every_replica() {
+        smol::run(async {
+            let key = Key::random(32);
+            let backend = setup(&key, 3);
+            let mut repo = Repository::with(
+                backend.clone(),
+                ChunkSettings::lightweight(),
+                key.clone(),
+                2,
+            )
+            .unwrap();
+            let (id, _) = repo.write_chunk(vec![7_u8; 1024]).await.unwrap();
+            for replica in &backend.replicas {
+                let mut repo = Repository::with(
+                    replica.clone(),
+                    ChunkSettings::lightweight(),
+                    key.clone(),
+                    2,
+                )
+                .unwrap();
+                assert_eq!(repo.read_chunk(id).await.unwrap(), vec![7_u8; 1024]);
+            }
+            repo.close().await;
+        });
+    }
+
+    // Point the first replica's index at a garbage chunk, and make sure the read falls
+    // back to the second replica and heals the first
+    #[test]
+    fn heals_damaged_replica() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut backend = setup(&key, 2);
+            let mut repo = Repository::with(
+                backend.clone(),
+                ChunkSettings::lightweight(),
+                key.clone(),
+                2,
+            )
+            .unwrap();
+            let data = vec![42_u8; 4096];
+            let (id, _) = repo.write_chunk(data.clone()).await.unwrap();
+
+            let settings = ChunkSettings::lightweight();
+            let garbage = Chunk::pack(
+                vec![0_u8; 4096],
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &Key::random(32),
+            );
+            let garbage_location = backend.replicas[0].write_chunk(garbage).await.unwrap();
+            backend.replicas[0]
+                .get_index()
+                .set_chunk(id, garbage_location)
+                .await
+                .unwrap();
+
+            assert_eq!(repo.read_chunk(id).await.unwrap(), data);
+            let healed = backend.replicas[0]
+                .get_index()
+                .lookup_chunk(id)
+                .await
+                .unwrap();
+            assert_ne!(healed, garbage_location);
+            let chunk = backend.replicas[0].read_chunk(healed).await.unwrap();
+            assert_eq!(chunk.unpack(&key).unwrap(), data);
+            repo.close().await;
+        });
+    }
+}
@@ -1,22 +1,51 @@
+//! A backend that stores a repository as a directory of files on a local filesystem
+//!
+//! Any number of connections may store into the same repository at once. Each connection takes
+//! the lock on its own segment, index, and manifest transaction files, creating new ones when all
+//! the existing ones are taken, and only ever appends to those. The index and manifest read in what
+//! other connections have committed when they need it: the index when a chunk is not found, and the
+//! manifest whenever archives are listed or committed. An archive committed after such a merge
+//! records every head the manifest has seen as a parent, so concurrent histories are joined back
+//! into one. Nothing a connection has not yet committed is visible to the others.
+//!
+//! Operations that remove or rewrite data require every other connection to be closed.
+//!
+//! A repository can also be opened read only, such as from a read only mount, with
+//! `MultiFileSettings::read_only`. Read only connections create, lock, and write nothing, not
+//! even their shared lock, so they are invisible to the other connections, and an operation
+//! that removes data may pull it out from under them. Every operation that would modify the
+//! repository is refused with `Err(ReadOnly)`.
+//!
+//! Connections opened with `MultiFileSettings::snapshot` never merge in what the others commit,
+//! and keep serving the archives and chunks that were committed when they were opened.
 #![allow(unused_variables)]
 use super::{BackendError, Result};
 use crate::repository::backend::common::files::LockedFile;
+use crate::repository::backend::common::parity::ParitySettings;
+use crate::repository::backend::common::segment::descriptors_by_segment;
 use crate::repository::backend::{
-    backend_to_object, Backend, BackendObject, Chunk, EncryptedKey, Manifest, SegmentDescriptor,
+    backend_to_object, common::ManifestID, Backend, BackendObject, CheckReport, CheckpointStats,
+    Chunk, ChunkDescriptors, CompactionStats, Durability, EncryptedKey, Index, Manifest,
+    ManifestHead, SegmentDescriptor,
 };
-use crate::repository::{ChunkSettings, Key};
+use crate::repository::{ChunkID, ChunkSettings, Key};
 
 use async_trait::async_trait;
 use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use std::fs::{create_dir_all, remove_file, File, OpenOptions};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{create_dir_all, remove_file, File};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 pub mod index;
+pub mod lock;
 pub mod manifest;
 pub mod segment;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
 
 #[derive(Debug, Clone)]
 pub struct MultiFile {
@@ -27,7 +56,211 @@ pub struct MultiFile {
     /// Connection uuid, used for read locks.
     uuid: Uuid,
     /// Path to readlock for this connection, must be deleted on close
-    read_lock_path: Arc<PathBuf>,
+    ///
+    /// Read only connections do not take a readlock.
+    read_lock_path: Option<Arc<PathBuf>>,
+    /// The persistent configuration of this repository
+    config: MultiFileConfig,
+}
+
+/// The newest version of the `MultiFile` on disk format this client understands
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The format feature recorded once a repository keeps chunk reference counts
+///
+/// Clients that do not keep the counts up to date when removing archives and chunks would leave
+/// them claiming chunks are still referenced, or worse, that they are not.
+pub const REFERENCE_COUNTS: &str = "chunk-refcounts";
+
+/// The optional format features this client understands
+///
+/// Changes to the on disk format that older clients can not safely read or write are rolled out
+/// as a named feature, recorded in the repository's configuration once it is in use, and listed
+/// here by every client that supports it.
+pub const SUPPORTED_FEATURES: &[&str] = &[REFERENCE_COUNTS];
+
+/// Persistent configuration of a `MultiFile` repository
+///
+/// This is stored in the `config` file in the root of the repository. Repositories without a
+/// `config` file use the default configuration.
+///
+/// New fields are only ever added to the end, so older clients can still read the format version
+/// and features of a newer repository, and refuse to open it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultiFileConfig {
+    /// Refuse any operation that would delete or rewrite existing data in the repository
+    ///
+    /// With this set, the key and default chunk settings can not be changed, and chunks already
+    /// in the index can not be moved. This can not be turned back off through the backend, it
+    /// must be done by someone with direct access to the repository.
+    #[serde(default)]
+    pub append_only: bool,
+    /// Write Reed-Solomon parity along with every new chunk, allowing chunks damaged by bit
+    /// rot to be repaired
+    ///
+    /// Changing this only affects chunks written afterwards.
+    #[serde(default)]
+    pub parity: Option<ParitySettings>,
+    /// The version of the on disk format of this repository
+    ///
+    /// Repositories written before the format was versioned are version 1.
+    #[serde(default = "default_format_version")]
+    pub format_version: u32,
+    /// The optional format features in use in this repository
+    ///
+    /// Clients that do not support every one of these must refuse to open the repository.
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+}
+
+fn default_format_version() -> u32 {
+    FORMAT_VERSION
+}
+
+impl Default for MultiFileConfig {
+    fn default() -> Self {
+        MultiFileConfig {
+            append_only: false,
+            parity: None,
+            format_version: FORMAT_VERSION,
+            features: BTreeSet::new(),
+        }
+    }
+}
+
+impl MultiFileConfig {
+    /// Reads the configuration of the repository at the given path
+    ///
+    /// Note: this path is the repository root path, not the config path
+    ///
+    /// # Errors
+    ///
+    /// Will error if the config file exists but can not be read or deserialized
+    pub fn load(path: impl AsRef<Path>) -> Result<MultiFileConfig> {
+        let config_path = path.as_ref().join("config");
+        if config_path.exists() {
+            let file = File::open(&config_path)?;
+            Ok(rmps::decode::from_read(&file)?)
+        } else {
+            Ok(MultiFileConfig::default())
+        }
+    }
+
+    /// Writes this configuration to the repository at the given path
+    ///
+    /// Note: this path is the repository root path, not the config path
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(AppendOnly)` if this would turn off append only mode on a repository
+    /// that has it enabled.
+    ///
+    /// Will return `Err(ClientTooOld)` if either the existing configuration or this one uses a
+    /// format this client does not understand, as rewriting it would drop settings this client
+    /// does not know about.
+    ///
+    /// Will also error if the config file can not be locked or written to.
+    pub fn store(&self, path: impl AsRef<Path>) -> Result<()> {
+        let existing = MultiFileConfig::load(&path)?;
+        existing.check_format()?;
+        self.check_format()?;
+        if existing.append_only && !self.append_only {
+            return Err(BackendError::AppendOnly(
+                "Attempted to disable append only mode".to_string(),
+            ));
+        }
+        let config_path = path.as_ref().join("config");
+        let mut file =
+            LockedFile::open_read_write(&config_path)?.ok_or(BackendError::FileLockError)?;
+        file.set_len(0)?;
+        Ok(rmps::encode::write(&mut file, self)?)
+    }
+
+    /// Checks that this client understands the on disk format described by this configuration
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ClientTooOld)` if the format version is newer than `FORMAT_VERSION`, or if
+    /// any of the features is not in `SUPPORTED_FEATURES`.
+    pub fn check_format(&self) -> Result<()> {
+        if self.format_version > FORMAT_VERSION {
+            return Err(BackendError::ClientTooOld(format!(
+                "repository format version is {}, but this client only supports up to version {}",
+                self.format_version, FORMAT_VERSION
+            )));
+        }
+        let unsupported: Vec<&str> = self
+            .features
+            .iter()
+            .map(String::as_str)
+            .filter(|feature| !SUPPORTED_FEATURES.contains(feature))
+            .collect();
+        if unsupported.is_empty() {
+            Ok(())
+        } else {
+            Err(BackendError::ClientTooOld(format!(
+                "repository uses format features this client does not support: {}",
+                unsupported.join(", ")
+            )))
+        }
+    }
+}
+
+/// Tunables controlling the resource usage of a `MultiFile` backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultiFileSettings {
+    /// The soft size limit of each segment, in bytes
+    pub size_limit: u64,
+    /// The number of segments stored in each data directory
+    pub segments_per_directory: u64,
+    /// The number of read only segment file handles kept open at any one time
+    pub segment_cache_size: usize,
+    /// When segments, index files, and manifest transactions are synced to disk
+    pub durability: Durability,
+    /// Open the repository without creating, locking, or writing to any files, refusing every
+    /// operation that would modify it
+    pub read_only: bool,
+    /// Serve the archives and chunks as they were when the repository was opened, ignoring
+    /// anything other connections commit afterwards
+    ///
+    /// Intended for connections that only read archives, so that a listing or extraction never
+    /// sees an archive committed partway through.
+    pub snapshot: bool,
+    /// Look the locations of chunks up from sorted pages written to the system's temporary
+    /// directory when the repository is opened, rather than holding the whole index in memory
+    ///
+    /// See `index::Index::open` for details.
+    pub paged_index: bool,
+}
+
+impl MultiFileSettings {
+    /// Settings intended for memory constrained devices, such as SBCs and NAS boxes with 512MiB of
+    /// RAM or less.
+    ///
+    /// Keeps only a handful of segment handles open for reading, and pages the index rather than
+    /// reading it fully into memory. The on disk format is identical to that produced by the
+    /// default settings, so a repository may be freely opened in either mode.
+    pub fn low_memory() -> MultiFileSettings {
+        MultiFileSettings {
+            segment_cache_size: 4,
+            paged_index: true,
+            ..MultiFileSettings::default()
+        }
+    }
+}
+
+impl Default for MultiFileSettings {
+    fn default() -> Self {
+        MultiFileSettings {
+            size_limit: 2_000_000_000,
+            segments_per_directory: 100,
+            segment_cache_size: 100,
+            durability: Durability::default(),
+            read_only: false,
+            snapshot: false,
+            paged_index: false,
+        }
+    }
 }
 
 impl MultiFile {
@@ -45,50 +278,107 @@ impl MultiFile {
         chunk_settings: Option<ChunkSettings>,
         key: &Key,
         queue_depth: usize,
+    ) -> Result<MultiFile> {
+        Self::open_with_settings(
+            path,
+            chunk_settings,
+            key,
+            queue_depth,
+            MultiFileSettings::default(),
+        )
+        .await
+    }
+
+    /// Opens a new `MultiFile` backend with the provided `MultiFileSettings`
+    ///
+    /// # Errors
+    ///
+    /// Will error if creating or locking any of the index or manifest files
+    /// fails (such as if the user does not have permissions for that
+    /// directory), or if any other I/O error occurs
+    pub async fn open_with_settings(
+        path: impl AsRef<Path>,
+        chunk_settings: Option<ChunkSettings>,
+        key: &Key,
+        queue_depth: usize,
+        settings: MultiFileSettings,
     ) -> Result<MultiFile> {
         // First, check to see if the global lock exists, and return an error early if it does
-        let global_lock_path = path.as_ref().join("lock");
-        if Path::exists(&global_lock_path) {
-            return Err(BackendError::RepositoryGloballyLocked(format!(
-                "Global lock for this repository already exists at: {:?}",
-                global_lock_path
-            )));
-        }
+        lock::check_exclusive(path.as_ref())?;
+        // Read the repository's configuration, and make sure we understand its format
+        let config = MultiFileConfig::load(&path)?;
+        config.check_format()?;
         // Generate a uuid
         let uuid = Uuid::new_v4();
-        let size_limit = 2_000_000_000;
-        let segments_per_directory = 100;
+        if settings.read_only {
+            return Self::open_read_only(path, key, queue_depth, settings, config, uuid).await;
+        }
+        // Open up a manifest connection. This is read before the index, and archives are only
+        // committed to the manifest after the index entries for their chunks, so every archive we
+        // see has its chunks in the index we read.
+        let mut manifest_handle = manifest::Manifest::open(
+            &path,
+            chunk_settings,
+            key,
+            queue_depth,
+            config.append_only,
+            settings.durability,
+        )?;
         // Open up an index connection
-        let index_handle = index::Index::open(&path, queue_depth)?;
-        // Open up a manifest connection
-        let mut manifest_handle =
-            manifest::Manifest::open(&path, chunk_settings, key, queue_depth)?;
+        let index_handle = index::Index::open(
+            &path,
+            queue_depth,
+            config.append_only,
+            settings.paged_index,
+            settings.durability,
+        );
+        let mut index_handle = match index_handle {
+            Ok(index_handle) => index_handle,
+            Err(error) => {
+                manifest_handle.close().await;
+                return Err(error);
+            }
+        };
+        if settings.snapshot {
+            manifest_handle.snapshot().await;
+            index_handle.snapshot().await;
+        }
+        // Append only repositories may have ignored the provided chunk settings
         let chunk_settings = if let Some(chunk_settings) = chunk_settings {
-            chunk_settings
+            if config.append_only {
+                manifest_handle.chunk_settings().await
+            } else {
+                chunk_settings
+            }
         } else {
             manifest_handle.chunk_settings().await
         };
         // Open up a segment handler connection
-        let segment_handle = segment::SegmentHandler::open(
+        let mut segment_handle = segment::SegmentHandler::open(
             &path,
-            size_limit,
-            segments_per_directory,
+            settings.size_limit,
+            settings.segments_per_directory,
             chunk_settings,
             key.clone(),
             queue_depth,
+            settings.segment_cache_size,
+            config.parity,
+            settings.durability,
         )?;
-        // Make sure the readlocks directory exists
-        create_dir_all(path.as_ref().join("readlocks"))?;
-        // generate a path to our readlock
-        let read_lock_path = path
-            .as_ref()
-            .join("readlocks")
-            .join(uuid.to_simple().to_string());
-        // Create the read_lock file
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&read_lock_path)?;
+        // Make sure the readlocks directory exists, and take our readlock, which will fail if
+        // the global lock was taken while we were opening everything else
+        let read_lock_path = create_dir_all(path.as_ref().join("readlocks"))
+            .map_err(BackendError::from)
+            .and_then(|()| lock::acquire_shared(path.as_ref(), &uuid.to_simple().to_string()));
+        let read_lock_path = match read_lock_path {
+            Ok(read_lock_path) => read_lock_path,
+            Err(error) => {
+                index_handle.close().await;
+                manifest_handle.close().await;
+                segment_handle.close().await;
+                return Err(error);
+            }
+        };
 
         let path = path.as_ref().to_path_buf();
         Ok(MultiFile {
@@ -97,10 +387,73 @@ impl MultiFile {
             segment_handle,
             path,
             uuid,
-            read_lock_path: Arc::new(read_lock_path),
+            read_lock_path: Some(Arc::new(read_lock_path)),
+            config,
         })
     }
 
+    /// Opens the connections for a read only `MultiFile`, without taking any locks
+    ///
+    /// The stored chunk settings are always used.
+    async fn open_read_only(
+        path: impl AsRef<Path>,
+        key: &Key,
+        queue_depth: usize,
+        settings: MultiFileSettings,
+        config: MultiFileConfig,
+        uuid: Uuid,
+    ) -> Result<MultiFile> {
+        let mut manifest_handle = manifest::Manifest::open_read_only(&path, key, queue_depth)?;
+        let index_handle = index::Index::open_read_only(&path, queue_depth, settings.paged_index);
+        let mut index_handle = match index_handle {
+            Ok(index_handle) => index_handle,
+            Err(error) => {
+                manifest_handle.close().await;
+                return Err(error);
+            }
+        };
+        if settings.snapshot {
+            manifest_handle.snapshot().await;
+            index_handle.snapshot().await;
+        }
+        let chunk_settings = manifest_handle.chunk_settings().await;
+        let segment_handle = segment::SegmentHandler::open_read_only(
+            &path,
+            settings.segments_per_directory,
+            chunk_settings,
+            key.clone(),
+            queue_depth,
+            settings.segment_cache_size,
+        );
+        let segment_handle = match segment_handle {
+            Ok(segment_handle) => segment_handle,
+            Err(error) => {
+                index_handle.close().await;
+                manifest_handle.close().await;
+                return Err(error);
+            }
+        };
+        Ok(MultiFile {
+            index_handle,
+            manifest_handle,
+            segment_handle,
+            path: path.as_ref().to_path_buf(),
+            uuid,
+            read_lock_path: None,
+            config,
+        })
+    }
+
+    /// Returns true if this repository is in append only mode
+    pub fn append_only(&self) -> bool {
+        self.config.append_only
+    }
+
+    /// Returns true if this connection was opened read only
+    pub fn read_only(&self) -> bool {
+        self.read_lock_path.is_none()
+    }
+
     /// Reads the encrypted key off the disk
     ///
     /// Does not require that the repository be opened first
@@ -115,6 +468,18 @@ impl MultiFile {
         let file = File::open(&key_path)?;
         Ok(rmps::decode::from_read(&file)?)
     }
+
+    /// Takes the exclusive lock on the repository, returning `None` if any other connection
+    /// to the repository is open
+    ///
+    /// Will return `Err(ReadOnly)` on a read only connection, as everything requiring the
+    /// exclusive lock removes or rewrites data.
+    fn lock_exclusive(&self) -> Result<Option<lock::ExclusiveLock>> {
+        let read_lock_path = self.read_lock_path.as_ref().ok_or_else(|| {
+            BackendError::ReadOnly("Attempted to take the exclusive lock".to_string())
+        })?;
+        lock::ExclusiveLock::acquire(&self.path, read_lock_path)
+    }
 }
 
 #[async_trait]
@@ -132,9 +497,20 @@ impl Backend for MultiFile {
     }
     /// Locks the keyfile and writes the key
     ///
-    /// Will return Err if writing the key fails
+    /// Will return Err if writing the key fails, if this would replace the key of an append
+    /// only repository, or if this connection is read only
     async fn write_key(&self, key: &EncryptedKey) -> Result<()> {
+        if self.read_only() {
+            return Err(BackendError::ReadOnly(
+                "Attempted to write the repository key".to_string(),
+            ));
+        }
         let key_path = self.path.join("key");
+        if self.config.append_only && key_path.exists() {
+            return Err(BackendError::AppendOnly(
+                "Attempted to replace the repository key".to_string(),
+            ));
+        }
         let mut file =
             LockedFile::open_read_write(&key_path)?.ok_or(BackendError::FileLockError)?;
         Ok(rmps::encode::write(&mut file, key)?)
@@ -157,6 +533,14 @@ impl Backend for MultiFile {
     async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         self.segment_handle.write_chunk(chunk).await
     }
+    /// Writes the chunks with a single append to each segment they land in
+    async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        self.segment_handle.write_chunks(chunks).await
+    }
+    /// Writes out the header of the segment currently being written to
+    async fn flush(&mut self) -> Result<()> {
+        self.segment_handle.flush().await
+    }
 
     /// Closes out the index, segment handler, and manifest cleanly, making sure all operations are
     /// completed and all drop impls from inside the tasks are called
@@ -165,11 +549,184 @@ impl Backend for MultiFile {
         self.manifest_handle.close().await;
         self.segment_handle.close().await;
         // Check if the read_lock_file exists and delete it
-        if self.read_lock_path.exists() {
+        if let Some(read_lock_path) = self.read_lock_path.as_ref().filter(|x| x.exists()) {
             // FIXME: We ignore this error for now, as this method does not currently return a
             // result
-            let _ = remove_file(self.read_lock_path.as_ref());
+            let _ = remove_file(read_lock_path.as_ref());
+        }
+    }
+
+    /// Copies the live chunks out of underutilized segments, commits their new locations to the
+    /// index, and then removes the old segments
+    ///
+    /// As other connections may be reading from the segments being removed, this will refuse to
+    /// run while any other connection to the repository is open.
+    ///
+    /// Will return `Err(AppendOnly)` on an append only repository.
+    async fn compact(&mut self, threshold: f64) -> Result<CompactionStats> {
+        if self.config.append_only {
+            return Err(BackendError::AppendOnly(
+                "Attempted to compact segments".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(BackendError::SegmentError(format!(
+                "Compaction threshold must be between 0 and 1, not {threshold}"
+            )));
+        }
+        let _lock = self.lock_exclusive()?.ok_or_else(|| {
+            BackendError::SegmentError(
+                "Unable to compact while other connections to the repository are open".to_string(),
+            )
+        })?;
+        // Find out which chunks are live, and where they are
+        let mut index = self.get_index();
+        let mut live: HashMap<SegmentDescriptor, Vec<ChunkID>> = HashMap::new();
+        for id in index.known_chunks().await {
+            if let Some(location) = index.lookup_chunk(id).await {
+                live.entry(location).or_default().push(id);
+            }
+        }
+        let compaction = self
+            .segment_handle
+            .compact(live.keys().copied().collect(), threshold)
+            .await?;
+        for (old_location, new_location) in &compaction.moved {
+            for id in &live[old_location] {
+                index.set_chunk(*id, *new_location).await?;
+            }
+        }
+        // The index must point at the new copies before any of the old ones are removed
+        index.commit_index().await?;
+        self.segment_handle
+            .remove_segments(compaction.segments.clone())
+            .await?;
+        Ok(CompactionStats {
+            segments_removed: compaction.segments.len(),
+            chunks_moved: compaction.moved.len(),
+            bytes_reclaimed: compaction.reclaimed_bytes,
+        })
+    }
+
+    /// Verifies every chunk in every segment, including chunks no longer in the index
+    ///
+    /// Repairing only ever restores the data a chunk was originally written with, so it is
+    /// permitted on append only repositories.
+    async fn check(&mut self, repair: bool) -> Result<CheckReport> {
+        self.segment_handle.check(repair).await
+    }
+
+    /// Lists every chunk in every segment, including chunks no longer in the index
+    async fn chunk_descriptors(&mut self) -> Result<ChunkDescriptors> {
+        let segment_ids = self.segment_handle.segment_ids().await?;
+        let handle = self.segment_handle.clone();
+        Ok(descriptors_by_segment(segment_ids, move |segment_id| {
+            let mut handle = handle.clone();
+            async move { handle.segment_descriptors(segment_id).await }
+        }))
+    }
+
+    async fn checkpoint(&mut self, keep_squashed: bool) -> Result<CheckpointStats> {
+        let _lock = self.lock_exclusive()?.ok_or_else(|| {
+            BackendError::ManifestError(
+                "Unable to checkpoint while other connections to the repository are open"
+                    .to_string(),
+            )
+        })?;
+        self.manifest_handle.checkpoint(keep_squashed).await
+    }
+
+    async fn heads(&mut self) -> Result<Vec<ManifestHead>> {
+        self.manifest_handle.heads().await
+    }
+
+    /// Merges the manifest's heads
+    ///
+    /// Unlike checkpointing, this only ever appends a transaction, so it is permitted while
+    /// other connections are open, and on append only repositories.
+    async fn merge_heads(&mut self) -> Result<Option<ManifestID>> {
+        self.manifest_handle.merge_heads().await
+    }
+
+    /// Removes archives from the manifest through a checkpoint
+    ///
+    /// Like checkpointing, this will refuse to run while any other connection to the
+    /// repository is open.
+    async fn remove_archives(&mut self, archives: HashSet<ChunkID>) -> Result<CheckpointStats> {
+        let _lock = self.lock_exclusive()?.ok_or_else(|| {
+            BackendError::ManifestError(
+                "Unable to remove archives while other connections to the repository are open"
+                    .to_string(),
+            )
+        })?;
+        self.manifest_handle.remove_archives(archives).await
+    }
+
+    /// Removes chunks from the index, by rewriting it into a single new index file
+    ///
+    /// As another connection may be about to reference one of the chunks being removed, this
+    /// will refuse to run while any other connection to the repository is open.
+    ///
+    /// Will return `Err(AppendOnly)` on an append only repository.
+    async fn remove_chunks(&mut self, chunks: HashSet<ChunkID>) -> Result<usize> {
+        if self.config.append_only {
+            return Err(BackendError::AppendOnly(
+                "Attempted to remove chunks".to_string(),
+            ));
         }
+        let _lock = self.lock_exclusive()?.ok_or_else(|| {
+            BackendError::IndexError(
+                "Unable to remove chunks while other connections to the repository are open"
+                    .to_string(),
+            )
+        })?;
+        self.index_handle.remove_chunks(chunks).await
+    }
+
+    async fn counted_archives(&mut self) -> Result<Option<HashSet<ChunkID>>> {
+        self.index_handle.counted_archives().await
+    }
+
+    async fn add_references(&mut self, archive: ChunkID, chunks: HashSet<ChunkID>) -> Result<()> {
+        self.index_handle.add_references(archive, chunks).await
+    }
+
+    async fn release_references(
+        &mut self,
+        archives: HashMap<ChunkID, HashSet<ChunkID>>,
+    ) -> Result<HashSet<ChunkID>> {
+        self.index_handle.release_references(archives).await
+    }
+
+    /// Replaces the reference counts, by rewriting the index into a single new index file
+    ///
+    /// The `REFERENCE_COUNTS` feature is recorded in the repository's configuration first, so
+    /// clients that would not keep the counts up to date refuse to open it. As with
+    /// `remove_chunks`, this will refuse to run while any other connection to the repository
+    /// is open.
+    ///
+    /// Will return `Err(AppendOnly)` on an append only repository.
+    async fn reset_references(
+        &mut self,
+        archives: HashSet<ChunkID>,
+        counts: HashMap<ChunkID, u64>,
+    ) -> Result<()> {
+        if self.config.append_only {
+            return Err(BackendError::AppendOnly(
+                "Attempted to reset the reference counts".to_string(),
+            ));
+        }
+        let _lock = self.lock_exclusive()?.ok_or_else(|| {
+            BackendError::IndexError(
+                "Unable to reset the reference counts while other connections to the repository are open"
+                    .to_string(),
+            )
+        })?;
+        let mut config = MultiFileConfig::load(&self.path)?;
+        if config.features.insert(REFERENCE_COUNTS.to_string()) {
+            config.store(&self.path)?;
+        }
+        self.index_handle.reset_references(archives, counts).await
     }
 
     fn get_object_handle(&self) -> BackendObject {
@@ -180,7 +737,11 @@ impl Backend for MultiFile {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::repository::Encryption;
+    use crate::manifest::StoredArchive;
+    use crate::repository::{Compression, Encryption, HMAC};
+    use futures::stream::StreamExt;
+    use std::collections::HashSet;
+    use std::fs::OpenOptions;
     use tempfile::{tempdir, TempDir};
 
     // Utility function, sets up a tempdir and opens a MultiFile Backend
@@ -251,7 +812,7 @@ mod tests {
         smol::run(async {
             let key = Key::random(32);
             let (tempdir, mut mf) = setup(&key).await;
-            let lock_path: Arc<PathBuf> = mf.read_lock_path.clone();
+            let lock_path: Arc<PathBuf> = mf.read_lock_path.clone().unwrap();
             // the connection is open, assert that the lock exists
             assert!(lock_path.exists());
             // Close the connection
@@ -261,4 +822,729 @@ mod tests {
             assert!(!lock_path.exists());
         });
     }
+
+    // Makes sure that a repository opened in low memory mode can still read back chunks from more
+    // segments than it is allowed to keep open at once
+    #[test]
+    fn low_memory_cache_eviction() {
+        smol::run(async {
+            let key = Key::random(32);
+            let tempdir = tempdir().unwrap();
+            let settings = MultiFileSettings {
+                size_limit: 1024,
+                ..MultiFileSettings::low_memory()
+            };
+            let chunk_settings = ChunkSettings::lightweight();
+            let mut mf = MultiFile::open_with_settings(
+                tempdir.path(),
+                Some(chunk_settings),
+                &key,
+                1,
+                settings,
+            )
+            .await
+            .unwrap();
+            let mut locations = Vec::new();
+            // Write out several times as many segments as we can hold open
+            for i in 0..16_u8 {
+                let data = vec![i; 2048];
+                let chunk = Chunk::pack(
+                    data.clone(),
+                    chunk_settings.compression,
+                    chunk_settings.encryption,
+                    chunk_settings.hmac,
+                    &key,
+                );
+                let location = mf.write_chunk(chunk).await.unwrap();
+                locations.push((data, location));
+            }
+            for (data, location) in locations {
+                let chunk = mf.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), data);
+            }
+            mf.close().await;
+        });
+    }
+
+    // Makes sure that every durability setting leaves behind a repository that can be reopened and
+    // read back
+    #[test]
+    fn durability_round_trip() {
+        smol::run(async {
+            for durability in [Durability::Always, Durability::OnCommit, Durability::Never] {
+                let key = Key::random(32);
+                let tempdir = tempdir().unwrap();
+                let settings = MultiFileSettings {
+                    size_limit: 4096,
+                    durability,
+                    ..MultiFileSettings::default()
+                };
+                let chunk_settings = ChunkSettings::lightweight();
+                let mut mf = MultiFile::open_with_settings(
+                    tempdir.path(),
+                    Some(chunk_settings),
+                    &key,
+                    4,
+                    settings,
+                )
+                .await
+                .unwrap();
+                let data: Vec<Vec<u8>> = (0..8_u8).map(|i| vec![i; 1024]).collect();
+                let chunks: Vec<Chunk> = data
+                    .iter()
+                    .map(|data| {
+                        Chunk::pack(
+                            data.clone(),
+                            chunk_settings.compression,
+                            chunk_settings.encryption,
+                            chunk_settings.hmac,
+                            &key,
+                        )
+                    })
+                    .collect();
+                let ids: Vec<_> = chunks.iter().map(Chunk::get_id).collect();
+                let mut chunks = chunks.into_iter();
+                let mut locations = vec![mf.write_chunk(chunks.next().unwrap()).await.unwrap()];
+                locations.extend(mf.write_chunks(chunks.collect()).await.unwrap());
+                for (id, location) in ids.iter().zip(&locations) {
+                    mf.get_index().set_chunk(*id, *location).await.unwrap();
+                }
+                mf.get_index().commit_index().await.unwrap();
+                mf.close().await;
+
+                let mut mf = MultiFile::open_with_settings(
+                    tempdir.path(),
+                    Some(chunk_settings),
+                    &key,
+                    4,
+                    settings,
+                )
+                .await
+                .unwrap();
+                for (id, data) in ids.iter().zip(&data) {
+                    let location = mf.get_index().lookup_chunk(*id).await.unwrap();
+                    let chunk = mf.read_chunk(location).await.unwrap();
+                    assert_eq!(&chunk.unpack(&key).unwrap(), data);
+                }
+                mf.close().await;
+            }
+        });
+    }
+
+    // Issues a pile of reads at once, which the segment handler batches up, and makes sure
+    // every one of them gets back its own chunk
+    #[test]
+    fn concurrent_reads() {
+        smol::run(async {
+            let key = Key::random(32);
+            let tempdir = tempdir().unwrap();
+            let settings = MultiFileSettings {
+                size_limit: 4096,
+                ..MultiFileSettings::default()
+            };
+            let chunk_settings = ChunkSettings::lightweight();
+            let mut mf = MultiFile::open_with_settings(
+                tempdir.path(),
+                Some(chunk_settings),
+                &key,
+                16,
+                settings,
+            )
+            .await
+            .unwrap();
+            let data: Vec<Vec<u8>> = (0..32_u8).map(|i| vec![i; 1000]).collect();
+            let chunks: Vec<Chunk> = data
+                .iter()
+                .map(|data| {
+                    Chunk::pack(
+                        data.clone(),
+                        chunk_settings.compression,
+                        chunk_settings.encryption,
+                        chunk_settings.hmac,
+                        &key,
+                    )
+                })
+                .collect();
+            let locations = mf.write_chunks(chunks).await.unwrap();
+            let reads = locations.iter().map(|location| {
+                let mut mf = mf.clone();
+                let location = *location;
+                async move { mf.read_chunk(location).await }
+            });
+            let chunks = futures::future::join_all(reads).await;
+            for (chunk, data) in chunks.into_iter().zip(data) {
+                assert_eq!(chunk.unwrap().unpack(&key).unwrap(), data);
+            }
+            mf.close().await;
+        });
+    }
+
+    // Lists the chunks in a repository spanning several segments, including one that never made
+    // it into the index, while reading chunks in the middle of the listing
+    #[test]
+    fn chunk_descriptors() {
+        smol::run(async {
+            let key = Key::random(32);
+            let tempdir = tempdir().unwrap();
+            let settings = MultiFileSettings {
+                size_limit: 4096,
+                ..MultiFileSettings::default()
+            };
+            let chunk_settings = ChunkSettings::lightweight();
+            let mut mf = MultiFile::open_with_settings(
+                tempdir.path(),
+                Some(chunk_settings),
+                &key,
+                4,
+                settings,
+            )
+            .await
+            .unwrap();
+            let mut written = HashMap::new();
+            for i in 0..16_u8 {
+                let chunk = Chunk::pack(
+                    vec![i; 1000],
+                    chunk_settings.compression,
+                    chunk_settings.encryption,
+                    chunk_settings.hmac,
+                    &key,
+                );
+                let id = chunk.get_id();
+                let location = mf.write_chunk(chunk).await.unwrap();
+                if i > 0 {
+                    mf.get_index().set_chunk(id, location).await.unwrap();
+                }
+                written.insert(id, location);
+            }
+
+            let mut descriptors = mf.chunk_descriptors().await.unwrap();
+            let mut listed = HashMap::new();
+            while let Some(descriptor) = descriptors.next().await {
+                let (id, location) = descriptor.unwrap();
+                let chunk = mf.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.get_id(), id);
+                listed.insert(id, location);
+            }
+            assert!(listed.values().any(|x| x.segment_id > 0));
+            assert_eq!(listed, written);
+            mf.close().await;
+        });
+    }
+
+    // Writes a batch of chunks large enough to span several segments, and makes sure they all
+    // land in order, and segments are split the same way as with single writes
+    #[test]
+    fn batch_write() {
+        smol::run(async {
+            let key = Key::random(32);
+            let batch_dir = tempdir().unwrap();
+            let settings = MultiFileSettings {
+                size_limit: 4096,
+                ..MultiFileSettings::default()
+            };
+            let chunk_settings = ChunkSettings::lightweight();
+            let mut mf = MultiFile::open_with_settings(
+                batch_dir.path(),
+                Some(chunk_settings),
+                &key,
+                4,
+                settings,
+            )
+            .await
+            .unwrap();
+            let data: Vec<Vec<u8>> = (0..64_u8).map(|i| vec![i; 500]).collect();
+            let chunks: Vec<Chunk> = data
+                .iter()
+                .map(|data| {
+                    Chunk::pack(
+                        data.clone(),
+                        chunk_settings.compression,
+                        chunk_settings.encryption,
+                        chunk_settings.hmac,
+                        &key,
+                    )
+                })
+                .collect();
+            let locations = mf.write_chunks(chunks.clone()).await.unwrap();
+            assert_eq!(locations.len(), data.len());
+            let segments: HashSet<u64> = locations.iter().map(|x| x.segment_id).collect();
+            assert!(segments.len() > 1);
+            for (data, location) in data.iter().zip(&locations) {
+                let chunk = mf.read_chunk(*location).await.unwrap();
+                assert_eq!(&chunk.unpack(&key).unwrap(), data);
+            }
+
+            // The same chunks written one at a time end up split across segments identically
+            let single_dir = tempdir().unwrap();
+            let mut single = MultiFile::open_with_settings(
+                single_dir.path(),
+                Some(chunk_settings),
+                &key,
+                4,
+                settings,
+            )
+            .await
+            .unwrap();
+            let mut single_locations = Vec::new();
+            for chunk in chunks {
+                single_locations.push(single.write_chunk(chunk).await.unwrap());
+            }
+            assert_eq!(locations, single_locations);
+            mf.close().await;
+            single.close().await;
+        });
+    }
+
+    // Writes out several segments, leaves most of the chunks in some of them out of the index, and
+    // makes sure compaction removes exactly those segments without losing any indexed chunks
+    #[test]
+    fn compact() {
+        smol::run(async {
+            let key = Key::random(32);
+            let tempdir = tempdir().unwrap();
+            let settings = MultiFileSettings {
+                size_limit: 4096,
+                segments_per_directory: 2,
+                ..MultiFileSettings::default()
+            };
+            let chunk_settings = ChunkSettings::lightweight();
+            let mut mf = MultiFile::open_with_settings(
+                tempdir.path(),
+                Some(chunk_settings),
+                &key,
+                4,
+                settings,
+            )
+            .await
+            .unwrap();
+            let mut live = Vec::new();
+            let mut chunk_size = 0;
+            for i in 0..16_u8 {
+                let chunk = Chunk::pack(
+                    vec![i; 1024],
+                    Compression::NoCompression,
+                    Encryption::NoEncryption,
+                    HMAC::Blake3,
+                    &key,
+                );
+                chunk_size = chunk.get_bytes().len() as u64;
+                let id = chunk.get_id();
+                let location = mf.write_chunk(chunk).await.unwrap();
+                // Only a quarter of the chunks in the first two segments are live
+                if i >= 8 || i % 4 == 0 {
+                    mf.get_index().set_chunk(id, location).await.unwrap();
+                    live.push((id, i, location));
+                }
+            }
+            mf.get_index().commit_index().await.unwrap();
+            // Compaction is refused while another connection is open
+            let mut other = MultiFile::open_with_settings(
+                tempdir.path(),
+                Some(chunk_settings),
+                &key,
+                4,
+                settings,
+            )
+            .await
+            .unwrap();
+            assert!(mf.compact(0.5).await.is_err());
+            other.close().await;
+
+            let stats = mf.compact(0.5).await.unwrap();
+            assert_eq!(
+                stats,
+                CompactionStats {
+                    segments_removed: 2,
+                    chunks_moved: 2,
+                    bytes_reclaimed: 6 * chunk_size,
+                }
+            );
+            let data_path = tempdir.path().join("data");
+            for (_, _, location) in &live {
+                let folder = data_path.join((location.segment_id / 2).to_string());
+                let exists = folder.join(location.segment_id.to_string()).exists();
+                assert_eq!(exists, location.segment_id >= 2);
+            }
+            mf.close().await;
+
+            // The moved chunks should be readable at their new locations after a reopen
+            let mut mf = MultiFile::open_with_settings(
+                tempdir.path(),
+                Some(chunk_settings),
+                &key,
+                4,
+                settings,
+            )
+            .await
+            .unwrap();
+            for (id, i, _) in &live {
+                let location = mf.get_index().lookup_chunk(*id).await.unwrap();
+                assert!(location.segment_id >= 2);
+                let chunk = mf.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), vec![*i; 1024]);
+            }
+            // Nothing left is underutilized
+            assert_eq!(mf.compact(0.5).await.unwrap(), CompactionStats::default());
+            assert!(mf.compact(1.5).await.is_err());
+            mf.close().await;
+        });
+    }
+
+    // Damages chunks in a repository written with parity, and makes sure that they are rebuilt
+    // on read and repaired by check where possible
+    #[test]
+    fn parity_repair() {
+        smol::run(async {
+            let key = Key::random(32);
+            let tempdir = tempdir().unwrap();
+            MultiFileConfig {
+                parity: Some(ParitySettings::new(4, 2).unwrap()),
+                ..MultiFileConfig::default()
+            }
+            .store(tempdir.path())
+            .unwrap();
+            let mut mf = MultiFile::open_defaults(
+                tempdir.path(),
+                Some(ChunkSettings::lightweight()),
+                &key,
+                4,
+            )
+            .await
+            .unwrap();
+            let mut locations = Vec::new();
+            for i in 0..3_u8 {
+                let chunk = Chunk::pack(
+                    vec![i; 1024],
+                    Compression::NoCompression,
+                    Encryption::NoEncryption,
+                    HMAC::Blake3,
+                    &key,
+                );
+                locations.push(mf.write_chunk(chunk).await.unwrap());
+            }
+            mf.close().await;
+
+            // Each chunk is preceded by a single byte gap, and followed by two 256 byte
+            // parity shards
+            let segment_path = tempdir.path().join("data").join("0").join("0");
+            let mut data = std::fs::read(&segment_path).unwrap();
+            let stride = 1 + 1024 + 512;
+            let header_length = data.len() - 3 * stride;
+            let body_start = |index: usize| header_length + 1 + index * stride;
+            // One damaged shard in the first chunk, three in the second
+            data[body_start(0) + 10] ^= 0xFF;
+            for offset in &[0, 300, 600] {
+                data[body_start(1) + offset] ^= 0xFF;
+            }
+            std::fs::write(&segment_path, data).unwrap();
+
+            let mut mf = MultiFile::open_defaults(
+                tempdir.path(),
+                Some(ChunkSettings::lightweight()),
+                &key,
+                4,
+            )
+            .await
+            .unwrap();
+            // The first chunk is rebuilt transparently, the second can not be
+            let chunk = mf.read_chunk(locations[0]).await.unwrap();
+            assert_eq!(chunk.unpack(&key).unwrap(), vec![0_u8; 1024]);
+            let chunk = mf.read_chunk(locations[1]).await.unwrap();
+            assert!(chunk.unpack(&key).is_err());
+
+            let report = mf.check(false).await.unwrap();
+            assert_eq!(report.chunks_checked, 3);
+            assert_eq!(report.repairable, vec![locations[0]]);
+            assert_eq!(report.unrecoverable, vec![locations[1]]);
+            assert_eq!(report.repaired, 0);
+
+            let report = mf.check(true).await.unwrap();
+            assert_eq!(report.repaired, 1);
+            assert_eq!(report.unrecoverable, vec![locations[1]]);
+
+            let report = mf.check(false).await.unwrap();
+            assert!(report.repairable.is_empty());
+            assert_eq!(report.unrecoverable, vec![locations[1]]);
+            let chunk = mf.read_chunk(locations[2]).await.unwrap();
+            assert_eq!(chunk.unpack(&key).unwrap(), vec![2_u8; 1024]);
+            mf.close().await;
+        });
+    }
+
+    // Makes sure that an append only repository refuses to rewrite existing data, and that append
+    // only mode can not be turned back off
+    #[test]
+    fn append_only() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (tempdir, mut mf) = setup(&key).await;
+            let enc_key = EncryptedKey::encrypt(&key, 512, 1, Encryption::new_aes256ctr(), b"");
+            mf.write_key(&enc_key).await.unwrap();
+            let chunk = Chunk::pack(
+                vec![1_u8; 1024],
+                Compression::NoCompression,
+                Encryption::NoEncryption,
+                HMAC::Blake3,
+                &key,
+            );
+            let id = chunk.get_id();
+            let location = mf.write_chunk(chunk.clone()).await.unwrap();
+            mf.get_index().set_chunk(id, location).await.unwrap();
+            mf.get_index().commit_index().await.unwrap();
+            mf.close().await;
+            // Turn on append only mode and reopen
+            MultiFileConfig {
+                append_only: true,
+                ..MultiFileConfig::default()
+            }
+            .store(tempdir.path())
+            .unwrap();
+            let mut mf = MultiFile::open_defaults(
+                tempdir.path(),
+                Some(ChunkSettings::lightweight()),
+                &key,
+                4,
+            )
+            .await
+            .unwrap();
+            assert!(mf.append_only());
+            // Writing new data is still allowed
+            let new_location = mf.write_chunk(chunk).await.unwrap();
+            // Setting a chunk to its existing location is a no-op
+            mf.get_index().set_chunk(id, location).await.unwrap();
+            // But rewriting any existing data is not
+            assert!(matches!(
+                mf.get_index().set_chunk(id, new_location).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            assert!(matches!(
+                mf.write_key(&enc_key).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            assert!(matches!(
+                mf.get_manifest()
+                    .write_chunk_settings(ChunkSettings::lightweight())
+                    .await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            assert!(matches!(
+                mf.compact(1.0).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            mf.close().await;
+            // Append only mode can not be turned off through the backend
+            assert!(matches!(
+                MultiFileConfig::default().store(tempdir.path()),
+                Err(BackendError::AppendOnly(_))
+            ));
+        });
+    }
+
+    // Makes sure that configs written before the format was versioned are read as version 1, and
+    // that repositories using a newer format or unknown features are refused
+    #[test]
+    fn format_version() {
+        smol::run(async {
+            let key = Key::random(32);
+            let tempdir = tempdir().unwrap();
+            // A config as written by a client predating format versioning
+            let mut file = File::create(tempdir.path().join("config")).unwrap();
+            rmps::encode::write(&mut file, &(true, None::<ParitySettings>)).unwrap();
+            let config = MultiFileConfig::load(tempdir.path()).unwrap();
+            assert!(config.append_only);
+            assert_eq!(config.format_version, FORMAT_VERSION);
+            assert!(config.features.is_empty());
+            // A config written by a newer client, with a field this client does not know about
+            let mut file = File::create(tempdir.path().join("config")).unwrap();
+            let parity: Option<ParitySettings> = None;
+            let features: BTreeSet<String> = BTreeSet::new();
+            let config = (false, parity, FORMAT_VERSION + 1, features, 7_u8);
+            rmps::encode::write(&mut file, &config).unwrap();
+            let config = MultiFileConfig::load(tempdir.path()).unwrap();
+            assert_eq!(config.format_version, FORMAT_VERSION + 1);
+            assert!(matches!(
+                MultiFile::open_defaults(tempdir.path(), None, &key, 4).await,
+                Err(BackendError::ClientTooOld(_))
+            ));
+            assert!(matches!(
+                MultiFile::open_with_settings(
+                    tempdir.path(),
+                    None,
+                    &key,
+                    4,
+                    MultiFileSettings {
+                        read_only: true,
+                        ..MultiFileSettings::default()
+                    }
+                )
+                .await,
+                Err(BackendError::ClientTooOld(_))
+            ));
+            // Overwriting it would lose the unknown field
+            assert!(matches!(
+                MultiFileConfig::default().store(tempdir.path()),
+                Err(BackendError::ClientTooOld(_))
+            ));
+            // A current format version using a feature this client does not support
+            remove_file(tempdir.path().join("config")).unwrap();
+            let mut config = MultiFileConfig::default();
+            config.features.insert("from-the-future".to_string());
+            assert!(matches!(
+                config.store(tempdir.path()),
+                Err(BackendError::ClientTooOld(_))
+            ));
+            let mut file = File::create(tempdir.path().join("config")).unwrap();
+            rmps::encode::write(&mut file, &config).unwrap();
+            match MultiFile::open_defaults(tempdir.path(), None, &key, 4).await {
+                Err(BackendError::ClientTooOld(message)) => {
+                    assert!(message.contains("from-the-future"));
+                }
+                _ => panic!("Opened a repository using an unsupported feature"),
+            }
+            // Nothing was created while refusing to open the repository
+            assert!(!tempdir.path().join("index").exists());
+            assert!(!tempdir.path().join("manifest").exists());
+        });
+    }
+
+    // Opens a repository read only, and makes sure it can be read from, refuses to be written
+    // to, and leaves every file on disk untouched
+    #[test]
+    fn read_only() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (tempdir, mut mf) = setup(&key).await;
+            let chunk = Chunk::pack(
+                vec![1_u8; 1024],
+                Compression::NoCompression,
+                Encryption::NoEncryption,
+                HMAC::Blake3,
+                &key,
+            );
+            let id = chunk.get_id();
+            let location = mf.write_chunk(chunk.clone()).await.unwrap();
+            mf.get_index().set_chunk(id, location).await.unwrap();
+            mf.get_index().commit_index().await.unwrap();
+            mf.close().await;
+            // Record every file in the repository, along with when it was last modified, which
+            // for directories includes files being created in or removed from them
+            let snapshot = || {
+                walkdir::WalkDir::new(tempdir.path())
+                    .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+                    .into_iter()
+                    .map(|entry| {
+                        let entry = entry.unwrap();
+                        let modified = entry.metadata().unwrap().modified().unwrap();
+                        (entry.into_path(), modified)
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let before = snapshot();
+            let settings = MultiFileSettings {
+                read_only: true,
+                ..MultiFileSettings::default()
+            };
+            let mut mf = MultiFile::open_with_settings(tempdir.path(), None, &key, 4, settings)
+                .await
+                .unwrap();
+            assert!(mf.read_only());
+            // Reading works as usual
+            assert_eq!(mf.get_index().lookup_chunk(id).await, Some(location));
+            let read = mf.read_chunk(location).await.unwrap();
+            assert_eq!(read.unpack(&key).unwrap(), vec![1_u8; 1024]);
+            assert_eq!(mf.get_manifest().archive_iterator().await.count(), 0);
+            // But nothing can be written
+            assert!(matches!(
+                mf.write_chunk(chunk).await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            assert!(matches!(
+                mf.get_index().set_chunk(id, location).await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            assert!(matches!(
+                mf.get_manifest()
+                    .write_chunk_settings(ChunkSettings::lightweight())
+                    .await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            assert!(matches!(
+                mf.compact(1.0).await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            assert!(matches!(
+                mf.checkpoint(false).await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            mf.get_index().commit_index().await.unwrap();
+            mf.close().await;
+            assert_eq!(snapshot(), before);
+        });
+    }
+
+    // Commits an archive while a snapshot connection and a regular one are open, and makes sure
+    // only the regular one picks it up
+    #[test]
+    fn snapshot() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (tempdir, mut writer) = setup(&key).await;
+            let pack = |byte| {
+                Chunk::pack(
+                    vec![byte; 1024],
+                    Compression::NoCompression,
+                    Encryption::NoEncryption,
+                    HMAC::Blake3,
+                    &key,
+                )
+            };
+            let store = |mut mf: MultiFile, chunk: Chunk| async move {
+                let id = chunk.get_id();
+                let location = mf.write_chunk(chunk).await.unwrap();
+                mf.get_index().set_chunk(id, location).await.unwrap();
+                mf.get_index().commit_index().await.unwrap();
+                mf.get_manifest()
+                    .write_archive(StoredArchive::dummy_archive())
+                    .await
+                    .unwrap();
+                (id, location)
+            };
+            let (old_id, old_location) = store(writer.clone(), pack(1)).await;
+
+            let settings = MultiFileSettings {
+                snapshot: true,
+                ..MultiFileSettings::default()
+            };
+            let mut frozen = MultiFile::open_with_settings(tempdir.path(), None, &key, 4, settings)
+                .await
+                .unwrap();
+            let mut live = MultiFile::open_defaults(tempdir.path(), None, &key, 4)
+                .await
+                .unwrap();
+            let (new_id, new_location) = store(writer.clone(), pack(2)).await;
+
+            assert_eq!(live.get_manifest().archive_iterator().await.count(), 2);
+            assert_eq!(
+                live.get_index().lookup_chunk(new_id).await,
+                Some(new_location)
+            );
+            assert_eq!(frozen.get_manifest().archive_iterator().await.count(), 1);
+            assert_eq!(frozen.get_manifest().heads().await.unwrap().len(), 1);
+            assert_eq!(
+                frozen.get_index().lookup_chunk(old_id).await,
+                Some(old_location)
+            );
+            assert_eq!(frozen.get_index().lookup_chunk(new_id).await, None);
+
+            frozen.close().await;
+            live.close().await;
+            writer.close().await;
+        });
+    }
+
+    crate::backend_conformance_tests!(conformance, |setup| async move {
+        MultiFile::open_defaults(&setup.path, Some(setup.settings), &setup.key, 4)
+            .await
+            .unwrap()
+    });
 }
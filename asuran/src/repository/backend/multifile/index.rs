@@ -1,5 +1,7 @@
-use crate::repository::backend::common::{IndexTransaction, LockedFile};
-use crate::repository::backend::{self, BackendError, Result, SegmentDescriptor};
+use crate::repository::backend::common::{
+    ChunkFilter, IndexTransaction, LockedFile, SharedChunkFilter,
+};
+use crate::repository::backend::{self, BackendError, Durability, Result, SegmentDescriptor};
 use crate::repository::ChunkID;
 
 use async_trait::async_trait;
@@ -8,19 +10,417 @@ use futures::channel::oneshot;
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 use rmp_serde as rmps;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
 use smol::block_on;
 
-use std::collections::{HashMap, HashSet};
-use std::fs::{create_dir, read_dir, File};
-use std::io::{BufWriter, Seek, SeekFrom};
-use std::path::Path;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::{
+    create_dir, create_dir_all, read_dir, remove_dir_all, remove_file, DirEntry, File, OpenOptions,
+};
+use std::io::{BufReader, BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::thread;
 
+mod pages;
+
+use pages::Pages;
+
 #[derive(Debug)]
 struct InternalIndex {
+    /// The location of every known chunk, or only of those learned since the pages were built
+    /// if the index is paged
     state: HashMap<ChunkID, SegmentDescriptor>,
-    file: LockedFile,
+    /// The locations of the chunks that were in the index when it was opened, if it is paged
+    pages: Option<Pages>,
+    /// The index file this connection appends to, `None` if the index was opened read only
+    file: Option<LockedFile>,
     changes: Vec<IndexTransaction>,
+    append_only: bool,
+    durability: Durability,
+    path: PathBuf,
+    /// How far into each index file we have read, so transactions committed by other
+    /// connections can be picked up later
+    offsets: HashMap<PathBuf, u64>,
+    /// Never read in what other connections commit, see `Index::snapshot`
+    snapshot: bool,
+    /// The chunk reference counts, `None` if they have not been established
+    references: Option<References>,
+    /// Set if only the counted archives were read out of the reference logs, leaving the
+    /// counts to be read once they are needed
+    partial_references: bool,
+    reference_changes: Vec<ReferenceTransaction>,
+}
+
+/// A change to the chunk reference counts, recorded in the reference log of an index file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum ReferenceTransaction {
+    /// An archive was committed, referring to each of the chunks
+    Add {
+        archive: ChunkID,
+        chunks: Vec<ChunkID>,
+    },
+    /// An archive was removed, releasing its references to each of the chunks
+    Release {
+        archive: ChunkID,
+        chunks: Vec<ChunkID>,
+    },
+    /// Every counted archive and reference count, written when the index is rewritten
+    ///
+    /// This establishes the reference counts, which are only kept from then on.
+    Counts {
+        archives: Vec<ChunkID>,
+        counts: Vec<(ChunkID, u64)>,
+    },
+}
+
+/// A `ReferenceTransaction`, read without the chunks it refers to
+#[derive(Deserialize)]
+enum ReferenceSummary {
+    Add {
+        archive: ChunkID,
+        #[allow(dead_code)]
+        chunks: IgnoredAny,
+    },
+    Release {
+        archive: ChunkID,
+        #[allow(dead_code)]
+        chunks: IgnoredAny,
+    },
+    Counts {
+        archives: Vec<ChunkID>,
+        #[allow(dead_code)]
+        counts: IgnoredAny,
+    },
+}
+
+/// Chunk reference counts, along with the archives whose references they include
+#[derive(Clone, Debug, Default)]
+struct References {
+    archives: HashSet<ChunkID>,
+    counts: HashMap<ChunkID, u64>,
+}
+
+impl References {
+    /// Applies a transaction, returning the chunks whose count dropped to zero
+    ///
+    /// Adding or releasing an archive twice only counts it once.
+    fn apply(&mut self, tx: &ReferenceTransaction) -> Vec<ChunkID> {
+        match tx {
+            ReferenceTransaction::Add { archive, chunks } => {
+                if self.archives.insert(*archive) {
+                    for chunk in chunks {
+                        *self.counts.entry(*chunk).or_insert(0) += 1;
+                    }
+                }
+                Vec::new()
+            }
+            ReferenceTransaction::Release { archive, chunks } => {
+                if !self.archives.remove(archive) {
+                    return Vec::new();
+                }
+                let mut released = Vec::new();
+                for chunk in chunks {
+                    if let Entry::Occupied(mut count) = self.counts.entry(*chunk) {
+                        *count.get_mut() -= 1;
+                        if *count.get() == 0 {
+                            count.remove();
+                            released.push(*chunk);
+                        }
+                    }
+                }
+                released
+            }
+            ReferenceTransaction::Counts { archives, counts } => {
+                self.archives.extend(archives);
+                for (chunk, count) in counts {
+                    *self.counts.entry(*chunk).or_insert(0) += count;
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    /// Returns a transaction recreating these reference counts from nothing
+    fn to_transaction(&self) -> ReferenceTransaction {
+        ReferenceTransaction::Counts {
+            archives: self.archives.iter().copied().collect(),
+            counts: self
+                .counts
+                .iter()
+                .map(|(chunk, count)| (*chunk, *count))
+                .collect(),
+        }
+    }
+}
+
+/// Number of shards each index file's transactions are split across, one for each possible
+/// first byte of a chunk ID
+const SHARDS: usize = 256;
+
+/// Lists the index files in the given index directory, sorted by ID
+///
+/// Files who's names are not strictly base 10 integers are left out.
+fn list_index_files(index_path: &Path) -> Result<Vec<(usize, DirEntry)>> {
+    let mut items = read_dir(index_path)?
+        .filter_map(std::result::Result::ok)
+        .filter(|x| x.path().is_file())
+        .filter_map(|x| {
+            x.path()
+                .file_name()?
+                .to_str()
+                .and_then(|y| std::result::Result::ok(y.parse::<usize>()))
+                .map(|z| (z, x))
+        })
+        .collect::<Vec<_>>();
+    items.sort_by_key(|a| a.0);
+    Ok(items)
+}
+
+/// Returns the directory holding the shards of the index file at `path`
+///
+/// Index files themselves only hold transactions written by older versions, and serve as the
+/// lock on their shards.
+fn shard_directory(path: &Path) -> PathBuf {
+    path.with_extension("shards")
+}
+
+/// Returns the shard a chunk's transactions are recorded in
+fn shard_of(id: ChunkID) -> usize {
+    id.get_id()[0] as usize
+}
+
+/// Returns the path of a shard, in the given shard directory
+fn shard_path(directory: &Path, shard: usize) -> PathBuf {
+    directory.join(format!("{shard:02x}"))
+}
+
+/// Returns the reference log of the index file at `path`
+fn references_path(path: &Path) -> PathBuf {
+    path.with_extension("refs")
+}
+
+/// Replays the transactions in the given index files, and their shards, in order
+fn read_state(items: &[(usize, DirEntry)]) -> Result<HashMap<ChunkID, SegmentDescriptor>> {
+    let mut state = HashMap::new();
+    read_new_transactions(items, &mut HashMap::new(), |tx| {
+        state.insert(tx.chunk_id, tx.descriptor);
+    })?;
+    Ok(state)
+}
+
+/// Replays the reference logs of the given index files, returning the reference counts, or
+/// `None` if they have not been established
+fn read_references(
+    items: &[(usize, DirEntry)],
+    offsets: &mut HashMap<PathBuf, u64>,
+) -> Result<Option<References>> {
+    let mut references = References::default();
+    let mut established = false;
+    read_new_references(items, offsets, |tx| {
+        established |= matches!(tx, ReferenceTransaction::Counts { .. });
+        references.apply(&tx);
+    })?;
+    Ok(if established { Some(references) } else { None })
+}
+
+/// Replays the reference logs of the given index files like `read_references`, but only keeps
+/// track of the counted archives, leaving the counts empty
+fn read_counted_archives(
+    items: &[(usize, DirEntry)],
+    offsets: &mut HashMap<PathBuf, u64>,
+) -> Result<Option<References>> {
+    let mut references = References::default();
+    let mut established = false;
+    read_new_references(items, offsets, |tx| match tx {
+        ReferenceSummary::Add { archive, .. } => {
+            references.archives.insert(archive);
+        }
+        ReferenceSummary::Release { archive, .. } => {
+            references.archives.remove(&archive);
+        }
+        ReferenceSummary::Counts { archives, .. } => {
+            established = true;
+            references.archives.extend(archives);
+        }
+    })?;
+    Ok(if established { Some(references) } else { None })
+}
+
+/// Replays the transactions in the reference logs of the given index files that come after the
+/// recorded offsets, recording how far each log has been read
+fn read_new_references<T: DeserializeOwned>(
+    items: &[(usize, DirEntry)],
+    offsets: &mut HashMap<PathBuf, u64>,
+    mut apply: impl FnMut(T),
+) -> Result<()> {
+    for (_, entry) in items {
+        let path = references_path(&entry.path());
+        let offset = offsets.get(&path).copied().unwrap_or(0);
+        let (transactions, offset) = read_file(&path, offset)?;
+        offsets.insert(path, offset);
+        transactions.into_iter().for_each(&mut apply);
+    }
+    Ok(())
+}
+
+/// Reads the transactions in a file, starting at `offset`, returning them along with how far
+/// into the file they went
+///
+/// A transaction that is still being written by another connection fails to decode, and is
+/// left to be read on a later call. Files that do not exist hold no transactions.
+fn read_file<T: DeserializeOwned>(path: &Path, offset: u64) -> Result<(Vec<T>, u64)> {
+    let mut transactions = Vec::new();
+    let offset = for_each_transaction(path, offset, |tx| {
+        transactions.push(tx);
+        Ok(())
+    })?;
+    Ok((transactions, offset))
+}
+
+/// Passes the transactions in a file, starting at `offset`, to `apply` one at a time, returning
+/// how far into the file they went
+///
+/// Stops at the first error `apply` returns, see `read_file` for everything else.
+fn for_each_transaction<T: DeserializeOwned>(
+    path: &Path,
+    offset: u64,
+    mut apply: impl FnMut(T) -> Result<()>,
+) -> Result<u64> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(offset),
+        Err(e) => return Err(e.into()),
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file);
+    let mut offset = offset;
+    // Keep deserializing transactions until we encouter an error
+    while let Ok(tx) = rmps::decode::from_read::<_, T>(&mut reader) {
+        offset = reader.stream_position()?;
+        apply(tx)?;
+    }
+    Ok(offset)
+}
+
+/// Replays the transactions in the given index files, and their shards, that come after the
+/// recorded offsets, recording how far each file has been read
+///
+/// The index files themselves are read first, in order. The shards are then read on several
+/// threads, each handling some of the shards of every index file, in order. As a chunk's
+/// transactions all land in the same shard, this replays every chunk's transactions in the
+/// order they were written.
+fn read_new_transactions(
+    items: &[(usize, DirEntry)],
+    offsets: &mut HashMap<PathBuf, u64>,
+    mut apply: impl FnMut(IndexTransaction),
+) -> Result<()> {
+    for (_, entry) in items {
+        let path = entry.path();
+        let offset = offsets.get(&path).copied().unwrap_or(0);
+        let (transactions, offset) = read_file(&path, offset)?;
+        offsets.insert(path, offset);
+        transactions.into_iter().for_each(&mut apply);
+    }
+    let directories = items
+        .iter()
+        .map(|(_, entry)| shard_directory(&entry.path()))
+        .filter(|directory| directory.is_dir())
+        .collect::<Vec<_>>();
+    let threads = num_cpus::get().clamp(1, SHARDS);
+    let offsets_ref = &*offsets;
+    let directories = &directories;
+    let results = thread::scope(|scope| {
+        let handles = (0..threads)
+            .map(|thread| {
+                scope.spawn(move || -> Result<_> {
+                    let mut transactions = Vec::new();
+                    let mut read = Vec::new();
+                    for shard in (thread..SHARDS).step_by(threads) {
+                        for directory in directories {
+                            let path = shard_path(directory, shard);
+                            let offset = offsets_ref.get(&path).copied().unwrap_or(0);
+                            let (new, offset) = read_file::<IndexTransaction>(&path, offset)?;
+                            transactions.extend(new);
+                            read.push((path, offset));
+                        }
+                    }
+                    Ok((transactions, read))
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Index shard reader panicked"))
+            .collect::<Vec<_>>()
+    });
+    for result in results {
+        let (transactions, read) = result?;
+        offsets.extend(read);
+        transactions.into_iter().for_each(&mut apply);
+    }
+    Ok(())
+}
+
+/// Appends transactions to the shards in the given shard directory, returning the shards that
+/// were written to
+fn write_shards<'a>(
+    directory: &Path,
+    transactions: impl IntoIterator<Item = &'a IndexTransaction>,
+) -> Result<Vec<File>> {
+    let mut shards: BTreeMap<usize, Vec<&IndexTransaction>> = BTreeMap::new();
+    for tx in transactions {
+        shards.entry(shard_of(tx.chunk_id)).or_default().push(tx);
+    }
+    if shards.is_empty() {
+        return Ok(Vec::new());
+    }
+    create_dir_all(directory)?;
+    let mut files = Vec::new();
+    for (shard, transactions) in shards {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(shard_path(directory, shard))?;
+        let mut writer = BufWriter::new(&file);
+        for tx in transactions {
+            rmps::encode::write(&mut writer, tx)?;
+        }
+        writer.flush()?;
+        std::mem::drop(writer);
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// Appends transactions to the reference log of the index file at `path`, returning the log
+fn write_references(path: &Path, transactions: &[ReferenceTransaction]) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(references_path(path))?;
+    let mut writer = BufWriter::new(&file);
+    for tx in transactions {
+        rmps::encode::write(&mut writer, tx)?;
+    }
+    writer.flush()?;
+    std::mem::drop(writer);
+    Ok(file)
+}
+
+/// Removes an index file along with its shards and reference log
+fn remove_index_file(path: &Path) -> Result<()> {
+    remove_file(path)?;
+    let references = references_path(path);
+    if references.exists() {
+        remove_file(references)?;
+    }
+    let directory = shard_directory(path);
+    if directory.exists() {
+        remove_dir_all(directory)?;
+    }
+    Ok(())
 }
 
 impl InternalIndex {
@@ -28,7 +428,22 @@ impl InternalIndex {
     ///
     /// The index this creates is not thread safe, see `Index` for the thread safe implementation on
     /// top of this.
-    fn open(repository_path: impl AsRef<Path>) -> Result<InternalIndex> {
+    ///
+    /// If `append_only` is set, the index will refuse to change the location of any chunk it
+    /// already knows about.
+    ///
+    /// If `read_only` is set, no files or directories are created or locked, and every change
+    /// to the index is refused.
+    ///
+    /// If `paged` is set, the locations of the chunks in the index are kept in pages on disk,
+    /// see `Index::open`.
+    fn open(
+        repository_path: impl AsRef<Path>,
+        append_only: bool,
+        read_only: bool,
+        paged: bool,
+        durability: Durability,
+    ) -> Result<InternalIndex> {
         // construct the path of the index folder
         let index_path = repository_path.as_ref().join("index");
         // Check to see if it exists
@@ -40,37 +455,61 @@ impl InternalIndex {
                     index_path
                 )));
             }
+        } else if read_only {
+            // A repository without an index has no chunks to look up
+            return Ok(InternalIndex {
+                state: HashMap::new(),
+                pages: None,
+                file: None,
+                changes: Vec::new(),
+                append_only,
+                durability,
+                path: index_path,
+                offsets: HashMap::new(),
+                snapshot: false,
+                references: None,
+                partial_references: false,
+                reference_changes: Vec::new(),
+            });
         } else {
             // Create the index directory
             create_dir(&index_path)?;
         }
-        // Create the state map
-        let mut state: HashMap<ChunkID, SegmentDescriptor> = HashMap::new();
-
         // Get the list of files, and sort them by ID
-        let mut items = read_dir(&index_path)?
-            .filter_map(std::result::Result::ok)
-            .filter(|x| x.path().is_file())
-            .filter_map(|x| {
-                x.path()
-                    .file_name()?
-                    .to_str()
-                    .map(|y| std::result::Result::ok(y.parse::<usize>()))
-                    .flatten()
-                    .map(|z| (z, x))
-            })
-            .collect::<Vec<_>>();
-        items.sort_by(|a, b| a.0.cmp(&b.0));
+        let items = list_index_files(&index_path)?;
 
-        // Add all the seen transactions to our state hashmap
-        for (_, file) in &items {
-            // Open the file
-            let mut file = File::open(file.path())?;
-            // Keep deserializing transactions until we encouter an error
-            while let Ok(tx) = rmps::decode::from_read::<_, IndexTransaction>(&mut file) {
-                // Insert each item into the state
+        // Add all the seen transactions to our state hashmap, or write them out to pages
+        let mut state = HashMap::new();
+        let mut offsets = HashMap::new();
+        let pages = if paged {
+            Some(Pages::build(&items, &mut offsets)?)
+        } else {
+            read_new_transactions(&items, &mut offsets, |tx| {
                 state.insert(tx.chunk_id, tx.descriptor);
-            }
+            })?;
+            None
+        };
+        let references = if paged {
+            read_counted_archives(&items, &mut offsets)?
+        } else {
+            read_references(&items, &mut offsets)?
+        };
+
+        if read_only {
+            return Ok(InternalIndex {
+                state,
+                pages,
+                file: None,
+                changes: Vec::new(),
+                append_only,
+                durability,
+                path: index_path,
+                offsets,
+                snapshot: false,
+                references,
+                partial_references: paged,
+                reference_changes: Vec::new(),
+            });
         }
 
         // Check to see if there are any unlocked index files, and if so, use the first ones
@@ -79,8 +518,17 @@ impl InternalIndex {
             if let Some(file) = locked_file {
                 return Ok(InternalIndex {
                     state,
-                    file,
+                    pages,
+                    file: Some(file),
                     changes: Vec::new(),
+                    append_only,
+                    durability,
+                    path: index_path,
+                    offsets,
+                    snapshot: false,
+                    references,
+                    partial_references: paged,
+                    reference_changes: Vec::new(),
                 });
             }
         }
@@ -88,29 +536,428 @@ impl InternalIndex {
         // If we have gotten here there are no unlocked index files, creating one
 
         // Check the length of the items list, if it is empty, there are no index files,
-        // so we must create the first
-        let id = if items.is_empty() {
-            0
-        } else {
-            items[items.len() - 1].0 + 1
+        // so we must create the first. Another connection may create and lock the file we
+        // picked before we do, in which case we move on to the next one.
+        let mut id = items.last().map_or(0, |(id, _)| id + 1);
+        let file = loop {
+            if let Some(file) = LockedFile::open_read_write(index_path.join(id.to_string()))? {
+                break file;
+            }
+            id += 1;
         };
-
-        let path = index_path.join(id.to_string());
-        let file = LockedFile::open_read_write(path)?
-            .expect("Somehow, our newly created index file is locked.");
         Ok(InternalIndex {
             state,
-            file,
+            pages,
+            file: Some(file),
             changes: Vec::new(),
+            append_only,
+            durability,
+            path: index_path,
+            offsets,
+            snapshot: false,
+            references,
+            partial_references: paged,
+            reference_changes: Vec::new(),
+        })
+    }
+
+    /// Looks up the location of a chunk
+    ///
+    /// If the chunk is not known, the transactions other connections have committed since we
+    /// last looked are read in first. The IDs of any chunks learned this way are passed to
+    /// `learned`.
+    fn lookup(&mut self, id: ChunkID, learned: impl FnMut(ChunkID)) -> Option<SegmentDescriptor> {
+        if let Some(descriptor) = self.get(id) {
+            return Some(descriptor);
+        }
+        // Failing to refresh only means we can not see the latest chunks from other
+        // connections, which is no worse than never looking
+        self.refresh(learned).ok()?;
+        self.state.get(&id).copied()
+    }
+
+    /// Returns the location of a chunk, if we already know it
+    ///
+    /// A page that fails to read hides the chunks on it, just like failing to refresh does.
+    fn get(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+        if let Some(descriptor) = self.state.get(&id) {
+            return Some(*descriptor);
+        }
+        self.pages.as_mut()?.get(id).ok().flatten()
+    }
+
+    /// Returns the number of chunks we know the location of
+    fn len(&mut self) -> usize {
+        let Some(pages) = &mut self.pages else {
+            return self.state.len();
+        };
+        // Chunks learned since the pages were built may have been in them already
+        let learned = self
+            .state
+            .keys()
+            .filter(|id| !matches!(pages.get(**id), Ok(Some(_))))
+            .count();
+        usize::try_from(pages.len())
+            .unwrap_or(usize::MAX)
+            .saturating_add(learned)
+    }
+
+    /// Returns the IDs of every chunk we know the location of
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the pages of a paged index can not be read back, as leaving their chunks
+    /// out would have them treated as missing.
+    fn known_chunks(&self) -> HashSet<ChunkID> {
+        let mut ids = self.state.keys().copied().collect::<HashSet<_>>();
+        if let Some(pages) = &self.pages {
+            pages
+                .for_each_id(|id| {
+                    ids.insert(id);
+                })
+                .expect("Unable to read the index pages");
+        }
+        ids
+    }
+
+    /// Fills `filter` with every chunk we know the location of
+    ///
+    /// If the pages of a paged index can not be read back, the filter is left unpopulated,
+    /// which only sends every check through to the index.
+    fn populate_filter(&self, filter: &SharedChunkFilter) {
+        let Some(pages) = &self.pages else {
+            return filter.populate(self.state.keys().copied());
+        };
+        let capacity = usize::try_from(pages.len()).unwrap_or(usize::MAX);
+        let mut ids = ChunkFilter::with_capacity(capacity.saturating_add(self.state.len()) * 2);
+        self.state.keys().for_each(|id| ids.insert(*id));
+        let populated = pages.for_each_id(|id| ids.insert(id));
+        filter.replace(populated.ok().map(|()| ids));
+    }
+
+    /// Reads in the transactions committed to the other index files since we last read them
+    ///
+    /// Chunks we already know the location of keep it, as both copies are equally valid. The
+    /// IDs of newly learned chunks are passed to `learned`.
+    fn refresh(&mut self, mut learned: impl FnMut(ChunkID)) -> Result<()> {
+        if self.snapshot {
+            return Ok(());
+        }
+        let own_path = self.file.as_ref().map(|file| file.path().to_path_buf());
+        let items = list_index_files(&self.path)?
+            .into_iter()
+            .filter(|(_, entry)| Some(entry.path()) != own_path)
+            .collect::<Vec<_>>();
+        let state = &mut self.state;
+        let pages = &mut self.pages;
+        read_new_transactions(&items, &mut self.offsets, |tx| {
+            if let Some(Ok(Some(_))) = pages.as_mut().map(|pages| pages.get(tx.chunk_id)) {
+                return;
+            }
+            if let Entry::Vacant(slot) = state.entry(tx.chunk_id) {
+                slot.insert(tx.descriptor);
+                learned(tx.chunk_id);
+            }
+        })
+    }
+
+    /// Sets the location of a chunk, recording the change for the next commit
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(AppendOnly)` if this index is append only, and the chunk is already
+    /// known to be at a different location, and `Err(ReadOnly)` if this index was opened read
+    /// only
+    fn set_chunk(&mut self, id: ChunkID, descriptor: SegmentDescriptor) -> Result<()> {
+        if self.file.is_none() {
+            return Err(BackendError::ReadOnly(format!(
+                "Attempted to set the location of chunk {id:?}"
+            )));
+        }
+        if self.append_only {
+            match self.get(id) {
+                Some(existing) if existing == descriptor => return Ok(()),
+                Some(existing) => {
+                    return Err(BackendError::AppendOnly(format!(
+                        "Attempted to move chunk {id:?} from {existing:?} to {descriptor:?}"
+                    )))
+                }
+                None => (),
+            }
+        }
+        // TODO: dont insert the item into the changes list if it its already in the index
+        self.state.insert(id, descriptor);
+        let transaction = IndexTransaction {
+            chunk_id: id,
+            descriptor,
+        };
+        self.changes.push(transaction);
+        Ok(())
+    }
+
+    /// Removes the given chunks from the index, returning how many of them it contained
+    ///
+    /// As the index files are append only logs, this rewrites the entire index into a single
+    /// new file, see `rewrite`. The reference counts of the removed chunks are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(AppendOnly)` if this index is append only, `Err(ReadOnly)` if it was
+    /// opened read only, and an error if any other connection currently holds an index file.
+    fn remove_chunks(&mut self, ids: &HashSet<ChunkID>) -> Result<usize> {
+        let mut removed = 0;
+        self.rewrite("remove chunks from the index", |state, references| {
+            let before = state.len();
+            state.retain(|id, _| !ids.contains(id));
+            removed = before - state.len();
+            if let Some(references) = references {
+                references.counts.retain(|id, _| !ids.contains(id));
+            }
+        })?;
+        Ok(removed)
+    }
+
+    /// Replaces the reference counts, establishing them if they were not already
+    ///
+    /// This rewrites the entire index, see `rewrite`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(AppendOnly)` if this index is append only, `Err(ReadOnly)` if it was
+    /// opened read only, and an error if any other connection currently holds an index file.
+    fn reset_references(&mut self, references: References) -> Result<()> {
+        self.rewrite("reset the reference counts", |_, existing| {
+            *existing = Some(references);
+        })
+    }
+
+    /// Rewrites the entire index into a single new file, after letting `update` change its
+    /// state and reference counts
+    ///
+    /// The state and reference counts are read from every index file, as other connections may
+    /// have committed to them since we read them, with our uncommitted changes on top. The new
+    /// file is committed to disk before the old files are removed.
+    ///
+    /// This holds the whole index in memory even if it is paged, building new pages from the
+    /// new file once it is written.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(AppendOnly)` if this index is append only, `Err(ReadOnly)` if it was
+    /// opened read only, and an error if any other connection currently holds an index file.
+    fn rewrite(
+        &mut self,
+        action: &str,
+        update: impl FnOnce(&mut HashMap<ChunkID, SegmentDescriptor>, &mut Option<References>),
+    ) -> Result<()> {
+        if self.append_only {
+            return Err(BackendError::AppendOnly(format!("Attempted to {action}")));
+        }
+        let own_path = match &self.file {
+            Some(file) => file.path().to_path_buf(),
+            None => return Err(BackendError::ReadOnly(format!("Attempted to {action}"))),
+        };
+        let items = list_index_files(&self.path)?;
+        // Lock every index file other than our own, so nobody can write to them while we work
+        let mut locks = Vec::new();
+        for (_, entry) in &items {
+            let path = entry.path();
+            if path == own_path {
+                continue;
+            }
+            let lock = LockedFile::open_read_write(&path)?.ok_or_else(|| {
+                BackendError::IndexError(format!(
+                    "Unable to {action} while other connections to the repository are open"
+                ))
+            })?;
+            locks.push(lock);
+        }
+        // Other connections may have added chunks since we read the index, so start over from
+        // what is on disk, with our uncommitted changes on top
+        let mut state = read_state(&items)?;
+        for tx in &self.changes {
+            state.insert(tx.chunk_id, tx.descriptor);
+        }
+        let mut references = read_references(&items, &mut HashMap::new())?;
+        if let Some(references) = &mut references {
+            for tx in &self.reference_changes {
+                references.apply(tx);
+            }
+        }
+        update(&mut state, &mut references);
+        self.state = state;
+        self.references = references;
+        self.partial_references = false;
+        // Write the new state out to a fresh file
+        let id = items.last().map_or(0, |(id, _)| id + 1);
+        let file = LockedFile::open_read_write(self.path.join(id.to_string()))?
+            .ok_or(BackendError::FileLockError)?;
+        let transactions = self
+            .state
+            .iter()
+            .map(|(chunk_id, descriptor)| IndexTransaction {
+                chunk_id: *chunk_id,
+                descriptor: *descriptor,
+            })
+            .collect::<Vec<_>>();
+        // The old files are removed right after this, so the new shards and reference log get
+        // synced regardless of the durability setting
+        for shard in write_shards(&shard_directory(file.path()), &transactions)? {
+            shard.sync_all()?;
+        }
+        if let Some(references) = &self.references {
+            write_references(file.path(), &[references.to_transaction()])?.sync_all()?;
+        }
+        self.changes.clear();
+        self.reference_changes.clear();
+        // Switch over to the new file, and remove the old ones, including our own
+        locks.extend(self.file.replace(file));
+        for (_, entry) in &items {
+            remove_index_file(&entry.path())?;
+        }
+        // Everything that was in the old files is in our new one
+        self.offsets.clear();
+        // Dropping the locks removes their lock files
+        std::mem::drop(locks);
+        if self.pages.is_some() {
+            let items = list_index_files(&self.path)?;
+            self.pages = Some(Pages::build(&items, &mut self.offsets)?);
+            self.state = HashMap::new();
+            if let Some(references) = &mut self.references {
+                references.counts = HashMap::new();
+                self.partial_references = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the archives whose references are counted, or `None` if the reference counts
+    /// have not been established
+    ///
+    /// The archives other connections have committed since we last looked are read in first.
+    fn counted_archives(&mut self) -> Result<Option<HashSet<ChunkID>>> {
+        self.refresh_references()?;
+        Ok(self
+            .references
+            .as_ref()
+            .map(|references| references.archives.clone()))
+    }
+
+    /// Records that `archive` refers to each of `chunks`, for the next commit
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ReadOnly)` if this index was opened read only, and `Err(Unsupported)` if
+    /// the reference counts have not been established.
+    fn add_references(&mut self, archive: ChunkID, chunks: Vec<ChunkID>) -> Result<()> {
+        let transaction = ReferenceTransaction::Add { archive, chunks };
+        self.references_mut("Attempted to add references")?
+            .apply(&transaction);
+        self.reference_changes.push(transaction);
+        Ok(())
+    }
+
+    /// Releases the references held by each of the archives, and commits the index, returning
+    /// the chunks that are no longer referenced by any archive
+    ///
+    /// The references other connections have committed are read in first. Archives whose
+    /// references are not counted are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ReadOnly)` if this index was opened read only, and `Err(Unsupported)` if
+    /// the reference counts have not been established.
+    fn release_references(
+        &mut self,
+        archives: Vec<(ChunkID, Vec<ChunkID>)>,
+    ) -> Result<HashSet<ChunkID>> {
+        self.load_reference_counts()?;
+        self.refresh_references()?;
+        let references = self.references_mut("Attempted to release references")?;
+        let mut released = HashSet::new();
+        let mut transactions = Vec::new();
+        for (archive, chunks) in archives {
+            let transaction = ReferenceTransaction::Release { archive, chunks };
+            released.extend(references.apply(&transaction));
+            transactions.push(transaction);
+        }
+        self.reference_changes.extend(transactions);
+        self.drain_changes()?;
+        Ok(released)
+    }
+
+    /// Returns the reference counts, for changing them
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ReadOnly)` if this index was opened read only, and `Err(Unsupported)` if
+    /// the reference counts have not been established.
+    fn references_mut(&mut self, operation: &str) -> Result<&mut References> {
+        if self.file.is_none() {
+            return Err(BackendError::ReadOnly(operation.to_string()));
+        }
+        self.references.as_mut().ok_or_else(|| {
+            BackendError::Unsupported(
+                "Reference counting, as the reference counts have not been established".to_string(),
+            )
         })
     }
 
+    /// Reads in the reference counts, if only the counted archives were read when the index was
+    /// opened
+    ///
+    /// Every reference log is read from the start, with our uncommitted changes on top.
+    fn load_reference_counts(&mut self) -> Result<()> {
+        if !self.partial_references {
+            return Ok(());
+        }
+        let items = list_index_files(&self.path)?;
+        let mut offsets = HashMap::new();
+        let mut references = read_references(&items, &mut offsets)?;
+        if let Some(references) = &mut references {
+            for tx in &self.reference_changes {
+                references.apply(tx);
+            }
+        }
+        self.references = references;
+        self.offsets.extend(offsets);
+        self.partial_references = false;
+        Ok(())
+    }
+
+    /// Reads in the references committed to the other index files since we last read them
+    fn refresh_references(&mut self) -> Result<()> {
+        if let Some(references) = &mut self.references {
+            let own_path = self.file.as_ref().map(|file| file.path().to_path_buf());
+            let items = list_index_files(&self.path)?
+                .into_iter()
+                .filter(|(_, entry)| Some(entry.path()) != own_path)
+                .collect::<Vec<_>>();
+            read_new_references(&items, &mut self.offsets, |tx| {
+                references.apply(&tx);
+            })?;
+        }
+        Ok(())
+    }
+
     /// Drains the changes out of the internal buffer and commits them to disk
+    ///
+    /// Only the shards that have changed are written to, along with the reference log if any
+    /// references have changed. As a read only index refuses every change, there is never
+    /// anything for it to commit.
     fn drain_changes(&mut self) -> Result<()> {
-        let mut file = BufWriter::new(&mut self.file);
-        file.seek(SeekFrom::End(0))?;
-        for tx in self.changes.drain(0..self.changes.len()) {
-            rmps::encode::write(&mut file, &tx)?;
+        if let Some(file) = &self.file {
+            let mut files = write_shards(&shard_directory(file.path()), &self.changes)?;
+            self.changes.clear();
+            if !self.reference_changes.is_empty() {
+                files.push(write_references(file.path(), &self.reference_changes)?);
+                self.reference_changes.clear();
+            }
+            if self.durability.sync_commits() {
+                for file in files {
+                    file.sync_data()?;
+                }
+            }
         }
         Ok(())
     }
@@ -121,13 +968,23 @@ enum IndexCommand {
     Set(ChunkID, SegmentDescriptor, oneshot::Sender<Result<()>>),
     KnownChunks(oneshot::Sender<HashSet<ChunkID>>),
     Commit(oneshot::Sender<Result<()>>),
+    Remove(HashSet<ChunkID>, oneshot::Sender<Result<usize>>),
+    CountedArchives(oneshot::Sender<Result<Option<HashSet<ChunkID>>>>),
+    AddReferences(ChunkID, Vec<ChunkID>, oneshot::Sender<Result<()>>),
+    ReleaseReferences(
+        Vec<(ChunkID, Vec<ChunkID>)>,
+        oneshot::Sender<Result<HashSet<ChunkID>>>,
+    ),
+    ResetReferences(References, oneshot::Sender<Result<()>>),
     Count(oneshot::Sender<usize>),
+    Snapshot(oneshot::Sender<()>),
     Close(oneshot::Sender<()>),
 }
 
 #[derive(Clone)]
 pub struct Index {
     input: mpsc::Sender<IndexCommand>,
+    filter: SharedChunkFilter,
     path: String,
 }
 
@@ -145,6 +1002,18 @@ impl Index {
     ///
     /// This method will create the index folder if it does not exist.
     ///
+    /// If `append_only` is set, attempts to change the location of an already indexed chunk will
+    /// be refused.
+    ///
+    /// If `paged` is set, the locations of the chunks already in the index are written out to
+    /// sorted pages in the system's temporary directory and looked up from there, rather than
+    /// kept in memory, with only a small cache of pages and the chunks learned afterwards held in
+    /// memory. Likewise, only the counted archives are read out of the reference logs, with the
+    /// reference counts themselves only read in for releasing references. Rewriting the index
+    /// still holds all of it in memory for the duration.
+    ///
+    /// `durability` controls whether or not committed changes are synced to disk.
+    ///
     /// Files who's names are not strictly base 10 integers are ignored, and will not be added to the
     /// state or written to.
     ///
@@ -164,12 +1033,45 @@ impl Index {
     /// # TODOs:
     ///
     /// 1. Return an error if deserializing a transaction fails before the end of the file is reached
-    /// 2. This function can currently panic if we have to create a new index file, but someone else
-    ///    that while we were parsing the transaction. Resolution for this conflict needs to be
-    ///    implemented.
-    pub fn open(repository_path: impl AsRef<Path>, queue_depth: usize) -> Result<Index> {
+    pub fn open(
+        repository_path: impl AsRef<Path>,
+        queue_depth: usize,
+        append_only: bool,
+        paged: bool,
+        durability: Durability,
+    ) -> Result<Index> {
         // Open the index
-        let mut index = InternalIndex::open(&repository_path)?;
+        let index = InternalIndex::open(&repository_path, append_only, false, paged, durability)?;
+        Ok(Index::spawn(repository_path.as_ref(), queue_depth, index))
+    }
+
+    /// Opens and reads the index without creating, locking, or writing to anything
+    ///
+    /// A repository without an index folder is treated as having an empty index. Attempts to
+    /// change the index will be refused with `Err(ReadOnly)`, committing succeeds, as there is
+    /// never anything to commit.
+    ///
+    /// `paged` works just like it does for `open`.
+    ///
+    /// # Errors
+    ///
+    /// Will return Err if there is a file called "index" in the repository folder, or reading the
+    /// index files fails.
+    pub fn open_read_only(
+        repository_path: impl AsRef<Path>,
+        queue_depth: usize,
+        paged: bool,
+    ) -> Result<Index> {
+        let index =
+            InternalIndex::open(&repository_path, false, true, paged, Durability::default())?;
+        Ok(Index::spawn(repository_path.as_ref(), queue_depth, index))
+    }
+
+    /// Starts the event processing loop for an opened index
+    fn spawn(repository_path: &Path, queue_depth: usize, mut index: InternalIndex) -> Index {
+        let filter = SharedChunkFilter::default();
+        index.populate_filter(&filter);
+        let task_filter = filter.clone();
         // Create the communication channel and open the event processing loop in it own task
         let (input, mut output) = mpsc::channel(queue_depth);
         thread::spawn(move || {
@@ -177,28 +1079,55 @@ impl Index {
             while let Some(command) = block_on(output.next()) {
                 match command {
                     IndexCommand::Lookup(id, ret) => {
-                        ret.send(index.state.get(&id).copied()).unwrap();
+                        let location = index.lookup(id, |id| task_filter.insert(id));
+                        ret.send(location).unwrap();
                     }
                     IndexCommand::Set(id, descriptor, ret) => {
-                        // TODO: dont insert the item into the changes list if it its already in the index
-                        index.state.insert(id, descriptor);
-                        let transaction = IndexTransaction {
-                            chunk_id: id,
-                            descriptor,
-                        };
-                        index.changes.push(transaction);
-                        ret.send(Ok(())).unwrap();
+                        let result = index.set_chunk(id, descriptor);
+                        if result.is_ok() {
+                            task_filter.insert(id);
+                        }
+                        ret.send(result).unwrap();
                     }
                     IndexCommand::KnownChunks(ret) => {
-                        ret.send(index.state.keys().copied().collect::<HashSet<_>>())
-                            .unwrap();
+                        ret.send(index.known_chunks()).unwrap();
                     }
                     IndexCommand::Count(ret) => {
-                        ret.send(index.state.len()).unwrap();
+                        ret.send(index.len()).unwrap();
+                    }
+                    IndexCommand::Snapshot(ret) => {
+                        index.snapshot = true;
+                        ret.send(()).unwrap();
                     }
                     IndexCommand::Commit(ret) => {
                         ret.send({ index.drain_changes() }).unwrap();
                     }
+                    IndexCommand::Remove(ids, ret) => {
+                        let result = index.remove_chunks(&ids);
+                        // Removing re-reads the index, which may turn up chunks added by
+                        // other connections
+                        if result.is_ok() {
+                            index.populate_filter(&task_filter);
+                        }
+                        ret.send(result).unwrap();
+                    }
+                    IndexCommand::CountedArchives(ret) => {
+                        ret.send(index.counted_archives()).unwrap();
+                    }
+                    IndexCommand::AddReferences(archive, chunks, ret) => {
+                        ret.send(index.add_references(archive, chunks)).unwrap();
+                    }
+                    IndexCommand::ReleaseReferences(archives, ret) => {
+                        ret.send(index.release_references(archives)).unwrap();
+                    }
+                    IndexCommand::ResetReferences(references, ret) => {
+                        let result = index.reset_references(references);
+                        // Rewriting the index re-reads it, just like removing chunks
+                        if result.is_ok() {
+                            index.populate_filter(&task_filter);
+                        }
+                        ret.send(result).unwrap();
+                    }
                     IndexCommand::Close(ret) => {
                         final_ret = Some(ret);
                         break;
@@ -214,10 +1143,101 @@ impl Index {
             };
         });
 
-        Ok(Index {
+        Index {
             input,
-            path: repository_path.as_ref().to_str().unwrap().to_string(),
-        })
+            filter,
+            path: repository_path.to_str().unwrap().to_string(),
+        }
+    }
+
+    /// Removes the given chunks from the index, returning how many of them it contained
+    ///
+    /// See `Backend::remove_chunks` for details.
+    pub async fn remove_chunks(&mut self, ids: HashSet<ChunkID>) -> Result<usize> {
+        let (input, output) = oneshot::channel();
+        self.input.send(IndexCommand::Remove(ids, input)).await?;
+        output.await?
+    }
+
+    /// Returns the archives whose references are counted, or `None` if the reference counts
+    /// have not been established
+    ///
+    /// See `Backend::counted_archives` for details.
+    pub async fn counted_archives(&mut self) -> Result<Option<HashSet<ChunkID>>> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(IndexCommand::CountedArchives(input))
+            .await?;
+        output.await?
+    }
+
+    /// Records that `archive` refers to each of `chunks`, adding one to their reference counts
+    ///
+    /// See `Backend::add_references` for details.
+    pub async fn add_references(
+        &mut self,
+        archive: ChunkID,
+        chunks: HashSet<ChunkID>,
+    ) -> Result<()> {
+        let (input, output) = oneshot::channel();
+        let chunks = chunks.into_iter().collect();
+        self.input
+            .send(IndexCommand::AddReferences(archive, chunks, input))
+            .await?;
+        output.await?
+    }
+
+    /// Releases the references held by each of the archives, returning the chunks that are no
+    /// longer referenced
+    ///
+    /// See `Backend::release_references` for details.
+    pub async fn release_references(
+        &mut self,
+        archives: HashMap<ChunkID, HashSet<ChunkID>>,
+    ) -> Result<HashSet<ChunkID>> {
+        let (input, output) = oneshot::channel();
+        let archives = archives
+            .into_iter()
+            .map(|(archive, chunks)| (archive, chunks.into_iter().collect()))
+            .collect();
+        self.input
+            .send(IndexCommand::ReleaseReferences(archives, input))
+            .await?;
+        output.await?
+    }
+
+    /// Replaces the reference counts with `counts`, covering the references of `archives`
+    ///
+    /// See `Backend::reset_references` for details.
+    pub async fn reset_references(
+        &mut self,
+        archives: HashSet<ChunkID>,
+        counts: HashMap<ChunkID, u64>,
+    ) -> Result<()> {
+        let (input, output) = oneshot::channel();
+        let references = References { archives, counts };
+        self.input
+            .send(IndexCommand::ResetReferences(references, input))
+            .await?;
+        output.await?
+    }
+
+    /// Stops reading in the chunks other connections commit
+    ///
+    /// From here on, chunks are only found if they were in the index when it was opened, or
+    /// were stored through this connection.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the index's event loop has already been closed
+    pub async fn snapshot(&mut self) {
+        let (tx, rx) = oneshot::channel();
+        self.input
+            .send(IndexCommand::Snapshot(tx))
+            .await
+            .expect("Called snapshot on an already closed repository.");
+        rx.await
+            .expect("Called snapshot on an already closed repository.");
     }
 
     pub async fn close(&mut self) {
@@ -256,6 +1276,9 @@ impl backend::Index for Index {
             .await?;
         output.await?
     }
+    async fn contains_chunk(&mut self, id: ChunkID) -> bool {
+        self.filter.may_contain(id) && self.lookup_chunk(id).await.is_some()
+    }
     async fn known_chunks(&mut self) -> HashSet<ChunkID> {
         let (input, output) = oneshot::channel();
         self.input
@@ -310,7 +1333,8 @@ mod tests {
         smol::run(async {
             let (tempdir, path) = setup();
             // Create the index
-            let index = Index::open(&path, 4).expect("Index creation failed");
+            let index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index creation failed");
             // Walk the directory and print some debugging info
             for entry in WalkDir::new(&path) {
                 let entry = entry.unwrap();
@@ -339,8 +1363,10 @@ mod tests {
         smol::run(async {
             let (tempdir, path) = setup();
             // Create the first index
-            let index1 = Index::open(&path, 4).expect("Index 1 creation failed");
-            let index2 = Index::open(&path, 4).expect("Index 2 creation failed");
+            let index1 = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index 1 creation failed");
+            let index2 = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index 2 creation failed");
             // Walk the directory and print some debugging info
             for entry in WalkDir::new(&path) {
                 let entry = entry.unwrap();
@@ -367,7 +1393,8 @@ mod tests {
         smol::run(async {
             let (tempdir, path) = setup();
             // Open an index and drop it
-            let mut index = Index::open(&path, 4).expect("Index creation failed");
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index creation failed");
             index.close().await;
             // check for the index file and the absense of the lock file
             let index_dir = path.join("index");
@@ -399,7 +1426,8 @@ mod tests {
                 txs.insert(chunk_id, descriptor);
             }
             // Open the index
-            let mut index = Index::open(&path, 4).expect("Index creation failed");
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index creation failed");
             // Insert the transactions
             for (id, desc) in &txs {
                 index
@@ -415,7 +1443,8 @@ mod tests {
             // Drop the index and let it complete
             index.close().await;
             // Load the index back up
-            let mut index = Index::open(&path, 4).expect("Index recreation failed");
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index recreation failed");
             // Walk the directory and print some debugging info
             for entry in WalkDir::new(&path) {
                 let entry = entry.unwrap();
@@ -431,4 +1460,379 @@ mod tests {
             }
         });
     }
+
+    // Test to make sure transactions are written to the shard of their chunk's first byte,
+    // and that transactions written to the index file itself, by older versions, are still
+    // read, and overridden by later ones in the shards
+    #[test]
+    fn sharded_layout() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let location = |segment_id| SegmentDescriptor {
+                segment_id,
+                start: 0,
+            };
+            let old = ChunkID::new(&[0x12; 32]);
+            let moved = ChunkID::new(&[0xab; 32]);
+            create_dir(path.join("index")).unwrap();
+            let mut legacy = File::create(path.join("index").join("0")).unwrap();
+            for id in &[old, moved] {
+                let tx = IndexTransaction {
+                    chunk_id: *id,
+                    descriptor: location(0),
+                };
+                rmps::encode::write(&mut legacy, &tx).unwrap();
+            }
+
+            let mut index = Index::open(&path, 4, false, false, Durability::default()).unwrap();
+            index.set_chunk(moved, location(1)).await.unwrap();
+            index
+                .set_chunk(ChunkID::new(&[0xac; 32]), location(2))
+                .await
+                .unwrap();
+            index.commit_index().await.unwrap();
+            index.close().await;
+            let shards = path.join("index").join("0.shards");
+            let mut names = read_dir(&shards)
+                .unwrap()
+                .map(|x| x.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(names, vec!["ab", "ac"]);
+
+            let mut index = Index::open(&path, 4, false, false, Durability::default()).unwrap();
+            assert_eq!(index.count_chunk().await, 3);
+            assert_eq!(index.lookup_chunk(old).await, Some(location(0)));
+            assert_eq!(index.lookup_chunk(moved).await, Some(location(1)));
+        });
+    }
+
+    // Test to verify that `contains_chunk` sees chunks both from before the index was opened,
+    // and chunks set through any handle since
+    #[test]
+    fn contains_chunk() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let descriptor = SegmentDescriptor {
+                segment_id: 0,
+                start: 0,
+            };
+            let old = ChunkID::random_id();
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index creation failed");
+            index.set_chunk(old, descriptor).await.unwrap();
+            index.commit_index().await.unwrap();
+            index.close().await;
+
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index recreation failed");
+            let mut other_handle = index.clone();
+            let new = ChunkID::random_id();
+            assert!(index.contains_chunk(old).await);
+            assert!(!index.contains_chunk(new).await);
+            other_handle.set_chunk(new, descriptor).await.unwrap();
+            assert!(index.contains_chunk(new).await);
+        });
+    }
+
+    // Chunks committed by another open connection should be found once they miss, and only
+    // ones we do not already know about should be taken from it
+    #[test]
+    fn concurrent_writers() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let ours = SegmentDescriptor {
+                segment_id: 0,
+                start: 0,
+            };
+            let theirs = SegmentDescriptor {
+                segment_id: 1,
+                start: 0,
+            };
+            let mut index1 = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index 1 creation failed");
+            let mut index2 = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index 2 creation failed");
+            let shared = ChunkID::random_id();
+            let new = ChunkID::random_id();
+            index1.set_chunk(shared, ours).await.unwrap();
+            index2.set_chunk(shared, theirs).await.unwrap();
+            index2.set_chunk(new, theirs).await.unwrap();
+            assert_eq!(index1.lookup_chunk(new).await, None);
+            index2.commit_index().await.unwrap();
+            assert_eq!(index1.lookup_chunk(new).await, Some(theirs));
+            assert_eq!(index1.lookup_chunk(shared).await, Some(ours));
+            // Once learned, the chunk can also be deduplicated against
+            assert!(index1.contains_chunk(new).await);
+            index1.close().await;
+            index2.close().await;
+        });
+    }
+
+    // Removing chunks should rewrite the index into a single file without them, and be refused
+    // while another connection holds an index file
+    #[test]
+    fn remove_chunks() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let descriptor = SegmentDescriptor {
+                segment_id: 0,
+                start: 0,
+            };
+            let ids = (0..8).map(|_| ChunkID::random_id()).collect::<Vec<_>>();
+            // Spread the chunks over two index files
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index creation failed");
+            let mut holder = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index creation failed");
+            for (i, id) in ids.iter().enumerate() {
+                let target = if i % 2 == 0 { &mut index } else { &mut holder };
+                target.set_chunk(*id, descriptor).await.unwrap();
+                target.commit_index().await.unwrap();
+            }
+            let removed = ids[..4].iter().copied().collect::<HashSet<_>>();
+            assert!(index.remove_chunks(removed.clone()).await.is_err());
+            holder.close().await;
+
+            assert_eq!(index.remove_chunks(removed).await.unwrap(), 4);
+            // Chunks set afterwards must land in the new file
+            let late = ChunkID::random_id();
+            index.set_chunk(late, descriptor).await.unwrap();
+            index.commit_index().await.unwrap();
+            index.close().await;
+
+            assert_eq!(list_index_files(&path.join("index")).unwrap().len(), 1);
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index recreation failed");
+            let expected = ids[4..]
+                .iter()
+                .copied()
+                .chain(std::iter::once(late))
+                .collect::<HashSet<_>>();
+            assert_eq!(index.known_chunks().await, expected);
+            index.close().await;
+
+            let mut index = Index::open(&path, 4, true, false, Durability::default())
+                .expect("Index recreation failed");
+            assert!(matches!(
+                index.remove_chunks(expected).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            index.close().await;
+        });
+    }
+
+    // Reference counts are only kept once established, are shared between connections and
+    // carried through rewrites of the index, and report the chunks no archive refers to anymore
+    #[test]
+    fn reference_counts() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let descriptor = SegmentDescriptor {
+                segment_id: 0,
+                start: 0,
+            };
+            let chunks = (0..4).map(|_| ChunkID::random_id()).collect::<Vec<_>>();
+            let archives = (0..2).map(|_| ChunkID::random_id()).collect::<Vec<_>>();
+            let references = |range: std::ops::Range<usize>| {
+                chunks[range].iter().copied().collect::<HashSet<_>>()
+            };
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index creation failed");
+            for chunk in &chunks {
+                index.set_chunk(*chunk, descriptor).await.unwrap();
+            }
+            index.commit_index().await.unwrap();
+            assert_eq!(index.counted_archives().await.unwrap(), None);
+            assert!(matches!(
+                index.add_references(archives[0], references(0..2)).await,
+                Err(BackendError::Unsupported(_))
+            ));
+            index
+                .reset_references(HashSet::new(), HashMap::new())
+                .await
+                .unwrap();
+            assert_eq!(
+                index.counted_archives().await.unwrap(),
+                Some(HashSet::new())
+            );
+            // Add the references from two connections
+            let mut other = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index creation failed");
+            index
+                .add_references(archives[0], references(0..3))
+                .await
+                .unwrap();
+            index.commit_index().await.unwrap();
+            other
+                .add_references(archives[1], references(1..4))
+                .await
+                .unwrap();
+            other.commit_index().await.unwrap();
+            let counted = archives.iter().copied().collect::<HashSet<_>>();
+            assert_eq!(index.counted_archives().await.unwrap(), Some(counted));
+            other.close().await;
+            // Rewriting the index keeps the counts
+            let removed = std::iter::once(chunks[3]).collect::<HashSet<_>>();
+            assert_eq!(index.remove_chunks(removed).await.unwrap(), 1);
+            index.close().await;
+
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index recreation failed");
+            let mut released = HashMap::new();
+            released.insert(archives[0], references(0..3));
+            assert_eq!(
+                index.release_references(released.clone()).await.unwrap(),
+                references(0..1)
+            );
+            // Releasing an archive twice does nothing
+            assert!(index.release_references(released).await.unwrap().is_empty());
+            index.close().await;
+
+            let mut index =
+                Index::open_read_only(&path, 4, false).expect("Index recreation failed");
+            let counted = std::iter::once(archives[1]).collect::<HashSet<_>>();
+            assert_eq!(index.counted_archives().await.unwrap(), Some(counted));
+            assert!(matches!(
+                index.add_references(archives[0], references(0..1)).await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            let mut released = HashMap::new();
+            released.insert(archives[1], references(1..4));
+            assert!(matches!(
+                index.release_references(released).await,
+                Err(BackendError::ReadOnly(_))
+            ));
+            index.close().await;
+
+            let mut index = Index::open(&path, 4, false, false, Durability::default())
+                .expect("Index recreation failed");
+            let mut released = HashMap::new();
+            released.insert(archives[1], references(1..4));
+            // The removed chunk's count was dropped along with it
+            assert_eq!(
+                index.release_references(released).await.unwrap(),
+                references(1..3)
+            );
+            index.close().await;
+        });
+    }
+
+    // A paged index should find every chunk, from the legacy index files, the shards, and other
+    // connections, and keep working through a rewrite
+    #[test]
+    fn paged_index() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let location = |segment_id| SegmentDescriptor {
+                segment_id,
+                start: 0,
+            };
+            let old = ChunkID::new(&[0x12; 32]);
+            let moved = ChunkID::new(&[0xab; 32]);
+            create_dir(path.join("index")).unwrap();
+            let mut legacy = File::create(path.join("index").join("0")).unwrap();
+            for id in &[old, moved] {
+                let tx = IndexTransaction {
+                    chunk_id: *id,
+                    descriptor: location(0),
+                };
+                rmps::encode::write(&mut legacy, &tx).unwrap();
+            }
+            // Enough chunks to fill several pages
+            let ids = (0..5000).map(|_| ChunkID::random_id()).collect::<Vec<_>>();
+            let mut index = Index::open(&path, 4, false, false, Durability::default()).unwrap();
+            index.set_chunk(moved, location(1)).await.unwrap();
+            for (i, id) in ids.iter().enumerate() {
+                index.set_chunk(*id, location(i as u64)).await.unwrap();
+            }
+            index.commit_index().await.unwrap();
+            index.close().await;
+
+            let mut index = Index::open(&path, 4, true, true, Durability::default()).unwrap();
+            let mut other = Index::open(&path, 4, false, false, Durability::default()).unwrap();
+            assert_eq!(index.count_chunk().await, ids.len() + 2);
+            assert_eq!(index.lookup_chunk(old).await, Some(location(0)));
+            assert_eq!(index.lookup_chunk(moved).await, Some(location(1)));
+            for (i, id) in ids.iter().enumerate() {
+                assert_eq!(index.lookup_chunk(*id).await, Some(location(i as u64)));
+            }
+            assert!(!index.contains_chunk(ChunkID::random_id()).await);
+            // Chunks in the pages can not be moved in append only mode either
+            assert!(matches!(
+                index.set_chunk(ids[0], location(7)).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            // Chunks from other connections, and ones we set ourselves, are found on top
+            let theirs = ChunkID::random_id();
+            other.set_chunk(theirs, location(2)).await.unwrap();
+            other.commit_index().await.unwrap();
+            assert_eq!(index.lookup_chunk(theirs).await, Some(location(2)));
+            let ours = ChunkID::random_id();
+            index.set_chunk(ours, location(3)).await.unwrap();
+            index.set_chunk(ids[1], location(1)).await.unwrap();
+            assert_eq!(index.count_chunk().await, ids.len() + 4);
+            let mut expected = ids.iter().copied().collect::<HashSet<_>>();
+            expected.extend(vec![old, moved, theirs, ours]);
+            assert_eq!(index.known_chunks().await, expected);
+            index.commit_index().await.unwrap();
+            index.close().await;
+            other.close().await;
+
+            let mut index = Index::open(&path, 4, false, true, Durability::default()).unwrap();
+            let removed = ids[..100].iter().copied().collect::<HashSet<_>>();
+            assert_eq!(index.remove_chunks(removed.clone()).await.unwrap(), 100);
+            assert_eq!(index.count_chunk().await, ids.len() + 4 - 100);
+            assert_eq!(index.lookup_chunk(ids[0]).await, None);
+            assert_eq!(index.lookup_chunk(ids[100]).await, Some(location(100)));
+            assert_eq!(index.lookup_chunk(ours).await, Some(location(3)));
+            assert!(!index.contains_chunk(ids[0]).await);
+            index.close().await;
+        });
+    }
+
+    // A paged index only reads the counted archives up front, and the counts themselves once
+    // references are released
+    #[test]
+    fn paged_reference_counts() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let chunks = (0..4).map(|_| ChunkID::random_id()).collect::<Vec<_>>();
+            let archives = (0..2).map(|_| ChunkID::random_id()).collect::<Vec<_>>();
+            let references = |range: std::ops::Range<usize>| {
+                chunks[range].iter().copied().collect::<HashSet<_>>()
+            };
+            let mut index = Index::open(&path, 4, false, false, Durability::default()).unwrap();
+            index
+                .reset_references(HashSet::new(), HashMap::new())
+                .await
+                .unwrap();
+            index
+                .add_references(archives[0], references(0..3))
+                .await
+                .unwrap();
+            index.commit_index().await.unwrap();
+            index.close().await;
+
+            let mut index = Index::open(&path, 4, false, true, Durability::default()).unwrap();
+            let counted = std::iter::once(archives[0]).collect::<HashSet<_>>();
+            assert_eq!(index.counted_archives().await.unwrap(), Some(counted));
+            index
+                .add_references(archives[1], references(1..4))
+                .await
+                .unwrap();
+            let mut released = HashMap::new();
+            released.insert(archives[0], references(0..3));
+            assert_eq!(
+                index.release_references(released).await.unwrap(),
+                references(0..1)
+            );
+            index.close().await;
+
+            let mut index = Index::open_read_only(&path, 4, true).unwrap();
+            let counted = std::iter::once(archives[1]).collect::<HashSet<_>>();
+            assert_eq!(index.counted_archives().await.unwrap(), Some(counted));
+            index.close().await;
+        });
+    }
 }
@@ -0,0 +1,254 @@
+//! Sorted pages of chunk locations, kept on disk so the index does not have to hold them all in
+//! memory
+//!
+//! The pages are built from the index files when the index is opened, one shard at a time, so
+//! only the chunks of a single shard are ever in memory at once. Every chunk is written out as a
+//! fixed size record, and as the shards are split by the first byte of the chunk ID, writing
+//! each shard's records out sorted leaves the whole file sorted.
+//!
+//! Looking a chunk up binary searches the records of its shard, keeping the most recently read
+//! pages of records in a small cache. The file lives in the system's temporary directory, and is
+//! removed once the index is closed.
+use super::{for_each_transaction, read_file, shard_directory, shard_of, shard_path, SHARDS};
+use crate::repository::backend::common::IndexTransaction;
+use crate::repository::backend::{Result, SegmentDescriptor};
+use crate::repository::ChunkID;
+
+use lru::LruCache;
+use rmp_serde as rmps;
+use uuid::Uuid;
+
+use std::cmp::Ordering;
+use std::collections::{btree_map::Entry, BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::{create_dir, remove_dir_all, remove_file, DirEntry, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of a single record, the chunk ID followed by the segment ID and start of its location
+const RECORD_SIZE: usize = 48;
+/// Number of records read from the file at once, and cached together
+const RECORDS_PER_PAGE: u64 = 256;
+/// Number of pages kept in the cache, 768KiB worth of records
+const CACHED_PAGES: usize = 64;
+
+/// A chunk and its location
+type Record = (ChunkID, SegmentDescriptor);
+
+pub(super) struct Pages {
+    path: PathBuf,
+    file: File,
+    /// The number of the first record of each shard, followed by the total number of records
+    shards: Vec<u64>,
+    cache: LruCache<u64, Vec<Record>>,
+}
+
+impl Pages {
+    /// Builds the pages from the transactions in the given index files and their shards,
+    /// recording how far each file has been read
+    ///
+    /// Transactions written directly to the index files by older versions are not split up by
+    /// shard, so they get spread out over temporary files first.
+    pub(super) fn build(
+        items: &[(usize, DirEntry)],
+        offsets: &mut HashMap<PathBuf, u64>,
+    ) -> Result<Pages> {
+        let path = std::env::temp_dir().join(format!("asuran-index-{}", Uuid::new_v4()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut pages = Pages {
+            path,
+            file,
+            shards: Vec::with_capacity(SHARDS + 1),
+            cache: LruCache::new(CACHED_PAGES),
+        };
+        let spill = pages.path.with_extension("spill");
+        let spilled = spill_index_files(items, offsets, &spill);
+        let written = spilled.and_then(|spilled| pages.write_shards(items, offsets, spilled));
+        if spill.exists() {
+            remove_dir_all(&spill)?;
+        }
+        written?;
+        Ok(pages)
+    }
+
+    /// Writes out the records of every shard, in order, reading the spilled transactions of the
+    /// index files first if there are any
+    fn write_shards(
+        &mut self,
+        items: &[(usize, DirEntry)],
+        offsets: &mut HashMap<PathBuf, u64>,
+        spill: Option<&Path>,
+    ) -> Result<()> {
+        let directories = items
+            .iter()
+            .map(|(_, entry)| shard_directory(&entry.path()))
+            .filter(|directory| directory.is_dir())
+            .collect::<Vec<_>>();
+        let mut writer = BufWriter::new(&self.file);
+        let mut count = 0;
+        for shard in 0..SHARDS {
+            let mut state = HashMap::new();
+            if let Some(spill) = spill {
+                for_each_transaction(&shard_path(spill, shard), 0, |tx: IndexTransaction| {
+                    state.insert(tx.chunk_id, tx.descriptor);
+                    Ok(())
+                })?;
+            }
+            for directory in &directories {
+                let path = shard_path(directory, shard);
+                let offset = offsets.get(&path).copied().unwrap_or(0);
+                let (transactions, offset) = read_file::<IndexTransaction>(&path, offset)?;
+                offsets.insert(path, offset);
+                for tx in transactions {
+                    state.insert(tx.chunk_id, tx.descriptor);
+                }
+            }
+            let mut records = state.into_iter().collect::<Vec<_>>();
+            records.sort_unstable_by(|a, b| a.0.get_id().cmp(b.0.get_id()));
+            self.shards.push(count);
+            for (id, descriptor) in records {
+                writer.write_all(id.get_id())?;
+                writer.write_all(&descriptor.segment_id.to_be_bytes())?;
+                writer.write_all(&descriptor.start.to_be_bytes())?;
+                count += 1;
+            }
+        }
+        self.shards.push(count);
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Returns the number of chunks in the pages
+    pub(super) fn len(&self) -> u64 {
+        self.shards[SHARDS]
+    }
+
+    /// Looks up the location of a chunk
+    pub(super) fn get(&mut self, id: ChunkID) -> Result<Option<SegmentDescriptor>> {
+        let shard = shard_of(id);
+        let (mut low, mut high) = (self.shards[shard], self.shards[shard + 1]);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let (found, descriptor) = self.record(middle)?;
+            match found.get_id().cmp(id.get_id()) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Ok(Some(descriptor)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Passes the ID of every chunk in the pages to `f`, in order
+    ///
+    /// This reads straight through the file, leaving the cache alone.
+    pub(super) fn for_each_id(&self, mut f: impl FnMut(ChunkID)) -> Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut buffer = [0_u8; RECORD_SIZE];
+        for _ in 0..self.len() {
+            reader.read_exact(&mut buffer)?;
+            f(ChunkID::new(&buffer[..32]));
+        }
+        Ok(())
+    }
+
+    /// Returns the record with the given number, reading in its page if it is not cached
+    fn record(&mut self, number: u64) -> Result<Record> {
+        let page = number / RECORDS_PER_PAGE;
+        if self.cache.get(&page).is_none() {
+            let records = self.read_page(page)?;
+            self.cache.put(page, records);
+        }
+        let records = self.cache.get(&page).expect("Page was just cached");
+        Ok(records[usize::try_from(number % RECORDS_PER_PAGE).expect("Record fits in a page")])
+    }
+
+    fn read_page(&self, page: u64) -> Result<Vec<Record>> {
+        let first = page * RECORDS_PER_PAGE;
+        let count = RECORDS_PER_PAGE.min(self.len() - first);
+        let mut buffer = vec![0_u8; RECORD_SIZE * usize::try_from(count).expect("Page fits")];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(first * RECORD_SIZE as u64))?;
+        file.read_exact(&mut buffer)?;
+        Ok(buffer
+            .chunks_exact(RECORD_SIZE)
+            .map(decode_record)
+            .collect())
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        // Nothing else ever looks at the file, so failing to remove it only leaves some litter
+        // in the temporary directory
+        let _ = remove_file(&self.path);
+    }
+}
+
+impl std::fmt::Debug for Pages {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pages")
+            .field("path", &self.path)
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+fn decode_record(bytes: &[u8]) -> Record {
+    let mut segment_id = [0_u8; 8];
+    let mut start = [0_u8; 8];
+    segment_id.copy_from_slice(&bytes[32..40]);
+    start.copy_from_slice(&bytes[40..48]);
+    let descriptor = SegmentDescriptor {
+        segment_id: u64::from_be_bytes(segment_id),
+        start: u64::from_be_bytes(start),
+    };
+    (ChunkID::new(&bytes[..32]), descriptor)
+}
+
+/// Spreads the transactions in the given index files out over one file per shard in the
+/// `spill` directory, in order, recording how far each index file has been read
+///
+/// Returns the directory if any transactions were spilled into it.
+fn spill_index_files<'a>(
+    items: &[(usize, DirEntry)],
+    offsets: &mut HashMap<PathBuf, u64>,
+    spill: &'a Path,
+) -> Result<Option<&'a Path>> {
+    let mut shards: BTreeMap<usize, BufWriter<File>> = BTreeMap::new();
+    for (_, entry) in items {
+        let path = entry.path();
+        let offset = offsets.get(&path).copied().unwrap_or(0);
+        let offset = for_each_transaction(&path, offset, |tx: IndexTransaction| {
+            let shard = shard_of(tx.chunk_id);
+            let writer = match shards.entry(shard) {
+                Entry::Occupied(writer) => writer.into_mut(),
+                Entry::Vacant(slot) => slot.insert(spill_file(spill, shard)?),
+            };
+            rmps::encode::write(writer, &tx)?;
+            Ok(())
+        })?;
+        offsets.insert(path, offset);
+    }
+    if shards.is_empty() {
+        return Ok(None);
+    }
+    for writer in shards.values_mut() {
+        writer.flush()?;
+    }
+    Ok(Some(spill))
+}
+
+/// Creates the spill file for a shard, creating the spill directory along with the first one
+fn spill_file(spill: &Path, shard: usize) -> Result<BufWriter<File>> {
+    if !spill.exists() {
+        create_dir(spill)?;
+    }
+    Ok(BufWriter::new(File::create(shard_path(spill, shard))?))
+}
@@ -0,0 +1,303 @@
+//! Repository level locks for `MultiFile` repositories
+//!
+//! Every open connection holds a shared lock, a file in the `readlocks` directory named
+//! after the connection's uuid. Operations that delete or rewrite data, such as
+//! compaction and pruning, additionally take the exclusive lock, the `lock` file in the
+//! root of the repository, which can only be taken while no other connection holds a
+//! shared lock, and prevents any new connections from being opened while it is held.
+//!
+//! Both kinds of lock record the process holding them, so that the locks left behind by
+//! a crashed process can be identified and removed with `break_locks`.
+use crate::repository::backend::{BackendError, Result};
+use crate::time::Timestamp;
+
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use std::fmt;
+use std::fs::{read_dir, remove_file, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// The process holding a lock
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LockHolder {
+    /// The id of the holding process
+    pub pid: u32,
+    /// The hostname of the machine the holding process runs on
+    pub hostname: String,
+    /// When the lock was taken
+    pub timestamp: Timestamp,
+}
+
+impl LockHolder {
+    /// Describes the current process
+    pub fn current() -> LockHolder {
+        LockHolder {
+            pid: std::process::id(),
+            hostname: gethostname::gethostname().to_string_lossy().to_string(),
+            timestamp: Timestamp::now(),
+        }
+    }
+
+    /// Reads the holder recorded in a lock file
+    ///
+    /// Returns `None` if the file does not record a holder, such as lock files written by
+    /// older versions.
+    fn read(path: &Path) -> Option<LockHolder> {
+        let file = File::open(path).ok()?;
+        rmps::decode::from_read(file).ok()
+    }
+
+    /// Creates a lock file recording the current process, failing if it already exists
+    fn create(path: &Path) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        rmps::encode::write(&mut file, &LockHolder::current()).map_err(std::io::Error::other)?;
+        file.sync_all()
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "process {} on {} since {}",
+            self.pid,
+            self.hostname,
+            self.timestamp.to_rfc2822()
+        )
+    }
+}
+
+/// The kind of a lock found in a repository
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockKind {
+    /// The repository wide exclusive lock
+    Exclusive,
+    /// The shared lock of an open connection
+    Shared,
+    /// The lock on an individual index, manifest, or segment file
+    File,
+}
+
+impl fmt::Display for LockKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LockKind::Exclusive => "exclusive",
+            LockKind::Shared => "shared",
+            LockKind::File => "file",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// A lock found in a repository
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lock {
+    pub kind: LockKind,
+    /// The lock file
+    pub path: PathBuf,
+    /// The process holding the lock, if it was recorded
+    pub holder: Option<LockHolder>,
+}
+
+/// Describes the holder of the exclusive lock at `path`, for error messages
+fn describe_holder(path: &Path) -> String {
+    LockHolder::read(path).map_or_else(
+        || "an unknown process".to_string(),
+        |holder| holder.to_string(),
+    )
+}
+
+/// Returns an error if the exclusive lock on the repository is held
+pub(crate) fn check_exclusive(repository_path: &Path) -> Result<()> {
+    let lock_path = repository_path.join("lock");
+    if lock_path.exists() {
+        Err(BackendError::RepositoryGloballyLocked(format!(
+            "Global lock for this repository already exists at: {}, held by {}",
+            lock_path.display(),
+            describe_holder(&lock_path)
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Takes a shared lock on the repository for the connection with the given uuid
+pub(crate) fn acquire_shared(repository_path: &Path, uuid: &str) -> Result<PathBuf> {
+    let read_lock_path = repository_path.join("readlocks").join(uuid);
+    LockHolder::create(&read_lock_path)?;
+    // The exclusive lock is taken before looking for shared locks, so checking for it after
+    // creating ours makes sure at least one of the two connections notices the other
+    if let Err(error) = check_exclusive(repository_path) {
+        remove_file(&read_lock_path)?;
+        return Err(error);
+    }
+    Ok(read_lock_path)
+}
+
+/// The exclusive lock on a repository, released when dropped
+#[derive(Debug)]
+pub(crate) struct ExclusiveLock {
+    path: PathBuf,
+}
+
+impl ExclusiveLock {
+    /// Takes the exclusive lock on the repository, on behalf of the connection holding the
+    /// shared lock at `read_lock_path`
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(RepositoryGloballyLocked)` if another connection holds the exclusive
+    /// lock, and `None` if any other connection holds a shared lock.
+    pub(crate) fn acquire(
+        repository_path: &Path,
+        read_lock_path: &Path,
+    ) -> Result<Option<ExclusiveLock>> {
+        let path = repository_path.join("lock");
+        match LockHolder::create(&path) {
+            Ok(()) => (),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+                return Err(BackendError::RepositoryGloballyLocked(format!(
+                    "Global lock for this repository already exists at: {}, held by {}",
+                    path.display(),
+                    describe_holder(&path)
+                )))
+            }
+            Err(error) => return Err(error.into()),
+        }
+        let lock = ExclusiveLock { path };
+        for entry in read_dir(repository_path.join("readlocks"))? {
+            if entry?.path() != read_lock_path {
+                return Ok(None);
+            }
+        }
+        Ok(Some(lock))
+    }
+}
+
+impl Drop for ExclusiveLock {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Lists every lock currently present in the repository at `repository_path`
+///
+/// # Errors
+///
+/// Will return `Err` if the repository can not be read
+pub fn locks(repository_path: impl AsRef<Path>) -> Result<Vec<Lock>> {
+    let repository_path = repository_path.as_ref();
+    let mut locks = Vec::new();
+    let lock_path = repository_path.join("lock");
+    if lock_path.exists() {
+        locks.push(Lock {
+            kind: LockKind::Exclusive,
+            holder: LockHolder::read(&lock_path),
+            path: lock_path,
+        });
+    }
+    let read_locks = repository_path.join("readlocks");
+    if read_locks.exists() {
+        for entry in read_dir(&read_locks)? {
+            let path = entry?.path();
+            locks.push(Lock {
+                kind: LockKind::Shared,
+                holder: LockHolder::read(&path),
+                path,
+            });
+        }
+    }
+    for entry in WalkDir::new(repository_path).min_depth(1) {
+        let entry = entry.map_err(|error| BackendError::Unknown(error.to_string()))?;
+        let is_lock = entry
+            .path()
+            .extension()
+            .is_some_and(|extension| extension.to_string_lossy().ends_with("lock"));
+        if entry.file_type().is_file() && is_lock {
+            locks.push(Lock {
+                kind: LockKind::File,
+                path: entry.path().to_path_buf(),
+                holder: None,
+            });
+        }
+    }
+    Ok(locks)
+}
+
+/// Removes every lock in the repository at `repository_path`, returning the locks removed
+///
+/// This is intended for recovering from a process that crashed or was killed while it had
+/// the repository open. Removing the locks of a connection that is still open will lead
+/// to corruption, so this must only be done when no process is using the repository.
+///
+/// # Errors
+///
+/// Will return `Err` if the repository can not be read, or any of the locks can not be
+/// removed
+pub fn break_locks(repository_path: impl AsRef<Path>) -> Result<Vec<Lock>> {
+    let locks = locks(repository_path)?;
+    for lock in &locks {
+        remove_file(&lock.path)?;
+    }
+    Ok(locks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::create_dir_all;
+    use tempfile::tempdir;
+
+    #[test]
+    fn exclusive_excludes_shared() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path();
+        create_dir_all(path.join("readlocks")).unwrap();
+        let ours = acquire_shared(path, "ours").unwrap();
+        // Only our own shared lock is present
+        let exclusive = ExclusiveLock::acquire(path, &ours).unwrap().unwrap();
+        // Which keeps anyone else from connecting, or taking the exclusive lock
+        assert!(matches!(
+            acquire_shared(path, "theirs"),
+            Err(BackendError::RepositoryGloballyLocked(_))
+        ));
+        assert!(!path.join("readlocks").join("theirs").exists());
+        assert!(ExclusiveLock::acquire(path, &ours).is_err());
+        std::mem::drop(exclusive);
+        // With another connection open, the exclusive lock can not be had
+        acquire_shared(path, "theirs").unwrap();
+        assert!(ExclusiveLock::acquire(path, &ours).unwrap().is_none());
+        assert!(!path.join("lock").exists());
+    }
+
+    #[test]
+    fn list_and_break() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path();
+        create_dir_all(path.join("readlocks")).unwrap();
+        create_dir_all(path.join("index")).unwrap();
+        acquire_shared(path, "crashed").unwrap();
+        File::create(path.join("index").join("0.lock")).unwrap();
+        // Lock files from older versions do not record their holder
+        File::create(path.join("lock")).unwrap();
+        let mut found = locks(path).unwrap();
+        found.sort_by_key(|lock| lock.path.clone());
+        assert_eq!(found.len(), 3);
+        let shared = found
+            .iter()
+            .find(|lock| lock.kind == LockKind::Shared)
+            .unwrap();
+        assert_eq!(shared.holder.as_ref().unwrap().pid, std::process::id());
+        let exclusive = found
+            .iter()
+            .find(|lock| lock.kind == LockKind::Exclusive)
+            .unwrap();
+        assert!(exclusive.holder.is_none());
+        assert_eq!(break_locks(path).unwrap().len(), 3);
+        assert!(locks(path).unwrap().is_empty());
+        acquire_shared(path, "fresh").unwrap();
+    }
+}
@@ -2,13 +2,15 @@
 use crate::manifest::StoredArchive;
 use crate::repository::backend::{
     self,
-    common::{LockedFile, ManifestID, ManifestTransaction},
-    BackendError, Result,
+    common::{
+        archives_from_transactions, may_be_missing, LockedFile, ManifestID, ManifestTransaction,
+    },
+    BackendError, CheckpointStats, Durability, ManifestHead, Result,
 };
-use crate::repository::{ChunkSettings, Key};
+use crate::repository::{ChunkID, ChunkSettings, Key};
+use crate::time::Timestamp;
 
 use async_trait::async_trait;
-use chrono::prelude::*;
 use futures::channel::mpsc;
 use futures::channel::oneshot;
 use futures::sink::SinkExt;
@@ -18,20 +20,109 @@ use rmp_serde as rmps;
 use smol::block_on;
 
 use std::collections::{HashMap, HashSet};
-use std::fs::{create_dir, read_dir, File};
-use std::io::{Seek, SeekFrom};
+use std::ffi::OsString;
+use std::fs::{create_dir, create_dir_all, read_dir, remove_file, rename, DirEntry, File};
+use std::io::{ErrorKind, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::thread;
 
+/// Lists the transaction files in the manifest directory, sorted by ID
+///
+/// Files whose names are not strictly base 10 integers are ignored.
+fn list_transaction_files(manifest_path: &Path) -> Result<Vec<(usize, DirEntry)>> {
+    let mut items = read_dir(manifest_path)?
+        .filter_map(std::result::Result::ok)
+        .filter(|x| x.path().is_file())
+        .filter_map(|x| {
+            x.path()
+                .file_name()?
+                .to_str()
+                .and_then(|y| std::result::Result::ok(y.parse::<usize>()))
+                .map(|z| (z, x))
+        })
+        .collect::<Vec<_>>();
+    items.sort_by_key(|a| a.0);
+    Ok(items)
+}
+
+/// Reads the transactions in the given transaction files that come after the recorded offsets,
+/// recording how far each file has been read
+///
+/// A transaction that is still being written by another connection fails to decode, and is left
+/// to be read on a later call. Files that have been removed since they were listed are skipped.
+fn read_new_transactions(
+    items: &[(usize, DirEntry)],
+    offsets: &mut HashMap<PathBuf, u64>,
+) -> Result<Vec<ManifestTransaction>> {
+    let mut transactions = Vec::new();
+    for (_, entry) in items {
+        let path = entry.path();
+        // Open the file
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let offset = offsets.entry(path).or_insert(0);
+        file.seek(SeekFrom::Start(*offset))?;
+        // Keep deserializing transactions until we encounter an error
+        while let Ok(tx) = rmps::decode::from_read::<_, ManifestTransaction>(&mut file) {
+            *offset = file.stream_position()?;
+            transactions.push(tx);
+        }
+    }
+    Ok(transactions)
+}
+
+/// Stores `settings` as the chunk settings of the manifest at `manifest_path`, unless they are
+/// already the stored ones
+///
+/// The settings are written to a scratch file named after `own_file`, which only this connection
+/// holds the lock for, and then renamed over `chunk.settings`, so other connections only ever see
+/// either the old settings or the new ones.
+fn store_chunk_settings(
+    manifest_path: &Path,
+    own_file: &Path,
+    settings: ChunkSettings,
+    durability: Durability,
+) -> Result<()> {
+    let path = manifest_path.join("chunk.settings");
+    if let Ok(mut existing) = File::open(&path) {
+        if rmps::decode::from_read::<_, ChunkSettings>(&mut existing).ok() == Some(settings) {
+            return Ok(());
+        }
+    }
+    let mut scratch_name = OsString::from("chunk.settings.");
+    scratch_name.push(own_file.file_name().unwrap_or_default());
+    let scratch_path = manifest_path.join(scratch_name);
+    let mut scratch = File::create(&scratch_path)?;
+    rmps::encode::write(&mut scratch, &settings)?;
+    if durability.sync_commits() {
+        scratch.sync_data()?;
+    }
+    std::mem::drop(scratch);
+    rename(&scratch_path, &path)?;
+    Ok(())
+}
+
 #[derive(Debug)]
 struct InternalManifest {
     known_entries: HashMap<ManifestID, ManifestTransaction>,
     verified_memo_pad: HashSet<ManifestID>,
     heads: Vec<ManifestID>,
-    file: LockedFile,
+    /// The transaction file this connection appends to, `None` if the manifest was opened read
+    /// only
+    file: Option<LockedFile>,
     key: Key,
     chunk_settings: ChunkSettings,
     path: PathBuf,
+    append_only: bool,
+    durability: Durability,
+    /// How far into each transaction file we have read, so transactions committed by other
+    /// connections can be picked up later
+    offsets: HashMap<PathBuf, u64>,
+    /// Never merge in what other connections commit, see `Manifest::snapshot`
+    snapshot: bool,
 }
 
 impl InternalManifest {
@@ -43,10 +134,19 @@ impl InternalManifest {
     /// Optionally sets the chunk settings.
     ///
     /// Will return error if this is a new repository and the chunk settings are not set
+    ///
+    /// If `append_only` is set, and the chunk settings have already been persisted, the provided
+    /// chunk settings are ignored rather than overwriting the stored ones.
+    ///
+    /// If `read_only` is set, the stored chunk settings are always used, no files or directories
+    /// are created or locked, and every change to the manifest is refused.
     fn open(
         repository_path: impl AsRef<Path>,
         key: &Key,
         settings: Option<ChunkSettings>,
+        append_only: bool,
+        read_only: bool,
+        durability: Durability,
     ) -> Result<InternalManifest> {
         // Construct the path of the manifest folder
         let manifest_path = repository_path.as_ref().join("manifest");
@@ -59,40 +159,24 @@ impl InternalManifest {
                     manifest_path
                 )));
             }
-        } else {
+        } else if !read_only {
             // Create the manifest directory
             create_dir(&manifest_path)?;
         }
 
         // Get the list of manifest files and sort them by ID
-        let mut items = read_dir(&manifest_path)?
-            .filter_map(std::result::Result::ok)
-            .filter(|x| x.path().is_file())
-            .filter_map(|x| {
-                x.path()
-                    .file_name()?
-                    .to_str()
-                    .map(|y| std::result::Result::ok(y.parse::<usize>()))
-                    .flatten()
-                    .map(|z| (z, x))
-            })
-            .collect::<Vec<_>>();
-        items.sort_by(|a, b| a.0.cmp(&b.0));
+        let items = list_transaction_files(&manifest_path)?;
 
         // Collect all known transactions
-        let mut known_entries = HashMap::new();
-        for (_, file) in &items {
-            // Open the file
-            let mut file = File::open(file.path())?;
-            // Keep deserializing transactions until we encounter an error
-            while let Ok(tx) = rmps::decode::from_read::<_, ManifestTransaction>(&mut file) {
-                known_entries.insert(tx.tag(), tx);
-            }
-        }
+        let mut offsets = HashMap::new();
+        let known_entries = read_new_transactions(&items, &mut offsets)?
+            .into_iter()
+            .map(|tx| (tx.tag(), tx))
+            .collect::<HashMap<_, _>>();
 
         let mut file = None;
         // Attempt to find an unlocked file
-        for (_, f) in &items {
+        for (_, f) in items.iter().filter(|_| !read_only) {
             let locked_file = LockedFile::open_read_write(f.path())?;
             if let Some(f) = locked_file {
                 file = Some(f);
@@ -100,30 +184,32 @@ impl InternalManifest {
             }
         }
 
-        // If we were unable to find an unlocked file, go ahead and make one
-        let file = if let Some(file) = file {
+        // If we were unable to find an unlocked file, go ahead and make one. Another connection
+        // may create and lock the file we picked before we do, in which case we move on to the
+        // next one.
+        let file = if file.is_some() || read_only {
             file
         } else {
-            let id = if items.is_empty() {
-                0
-            } else {
-                items[items.len() - 1].0 + 1
-            };
-            let path = manifest_path.join(id.to_string());
-            LockedFile::open_read_write(path)?
-                .expect("Somehow, our newly created manifest file is locked")
+            let mut id = items.last().map_or(0, |(id, _)| id + 1);
+            loop {
+                let path = manifest_path.join(id.to_string());
+                if let Some(file) = LockedFile::open_read_write(path)? {
+                    break Some(file);
+                }
+                id += 1;
+            }
         };
 
-        let chunk_settings = if let Some(chunk_settings) = settings {
-            // Attempt to open the chunk settings file and update it
-            let mut sfile = LockedFile::open_read_write(manifest_path.join("chunk.settings"))?
-                .ok_or_else(|| {
-                    BackendError::ManifestError("Unable to lock chunk.settings".to_string())
-                })?;
-            // Clear the file
-            sfile.set_len(0)?;
-            // Write our new chunksettings
-            rmps::encode::write(&mut sfile, &chunk_settings)?;
+        // Append only repositories never have their stored chunk settings rewritten, and read
+        // only ones never have anything written
+        let settings =
+            if read_only || (append_only && manifest_path.join("chunk.settings").exists()) {
+                None
+            } else {
+                settings
+            };
+        let chunk_settings = if let (Some(chunk_settings), Some(file)) = (settings, &file) {
+            store_chunk_settings(&manifest_path, file.path(), chunk_settings, durability)?;
             chunk_settings
         } else {
             let mut sfile = File::open(manifest_path.join("chunk.settings"))?;
@@ -139,6 +225,10 @@ impl InternalManifest {
             key: key.clone(),
             chunk_settings,
             path: manifest_path,
+            append_only,
+            durability,
+            offsets,
+            snapshot: false,
         };
         // Build the list of heads
         manifest.build_heads();
@@ -172,8 +262,10 @@ impl InternalManifest {
         for tx in self.known_entries.values() {
             let id = index_map.get(&tx.tag()).unwrap();
             for other_tx in tx.previous_heads() {
-                let other_id = index_map.get(&other_tx).unwrap();
-                graph.update_edge(*id, *other_id, ());
+                // Transactions replaced by a checkpoint may no longer be around
+                if let Some(other_id) = index_map.get(other_tx) {
+                    graph.update_edge(*id, *other_id, ());
+                }
             }
         }
         // reverse all the nodes, so they now point from old to new
@@ -203,6 +295,13 @@ impl InternalManifest {
             if tx.verify(&self.key) {
                 self.verified_memo_pad.insert(id);
                 for parent in tx.previous_heads() {
+                    if !self.known_entries.contains_key(parent) {
+                        // Only checkpoints may refer to transactions that have been removed
+                        if may_be_missing(&tx, *parent) {
+                            continue;
+                        }
+                        return false;
+                    }
                     if !self.verify_tx(*parent) {
                         return false;
                     }
@@ -214,12 +313,58 @@ impl InternalManifest {
         }
     }
 
+    /// Merges in the transactions other connections have committed since we last looked
+    ///
+    /// Each connection appends to its own transaction file, so concurrent writers never
+    /// conflict on disk. Their transactions are merged here, and a writer that commits after
+    /// the merge records every head it has seen as a parent, joining the branches back up.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if reading the transaction files fails, or if any of the new
+    /// transactions fail verification. Transactions that fail verification are left out of the
+    /// manifest.
+    fn refresh(&mut self) -> Result<()> {
+        if self.snapshot {
+            return Ok(());
+        }
+        let own_path = self.file.as_ref().map(|file| file.path().to_path_buf());
+        let items = list_transaction_files(&self.path)?
+            .into_iter()
+            .filter(|(_, entry)| Some(entry.path()) != own_path)
+            .collect::<Vec<_>>();
+        let new = read_new_transactions(&items, &mut self.offsets)?
+            .into_iter()
+            .filter(|tx| !self.known_entries.contains_key(&tx.tag()))
+            .collect::<Vec<_>>();
+        if new.is_empty() {
+            return Ok(());
+        }
+        let ids = new.iter().map(ManifestTransaction::tag).collect::<Vec<_>>();
+        self.known_entries
+            .extend(new.into_iter().map(|tx| (tx.tag(), tx)));
+        for id in &ids {
+            if !self.verify_tx(*id) {
+                for id in &ids {
+                    self.known_entries.remove(id);
+                    self.verified_memo_pad.remove(id);
+                }
+                return Err(BackendError::ManifestError(format!(
+                    "Manifest Transaction {id:?} written by another connection failed verification"
+                )));
+            }
+        }
+        self.build_heads();
+        Ok(())
+    }
+
     /// Returns the last modification timestamp of the manifest
     ///
     /// Defaults to now if there are no heads
-    fn last_modification(&self) -> Result<DateTime<FixedOffset>> {
+    fn last_modification(&mut self) -> Result<Timestamp> {
+        self.refresh()?;
         if self.heads.is_empty() {
-            Ok(Local::now().with_timezone(Local::now().offset()))
+            Ok(Timestamp::now())
         } else {
             let first_head = self
                 .known_entries
@@ -244,27 +389,98 @@ impl InternalManifest {
     }
 
     /// Returns an iterator over the archives in this repository
-    fn archive_iterator(&self) -> std::vec::IntoIter<StoredArchive> {
-        let mut items = self.known_entries.values().cloned().collect::<Vec<_>>();
-        items.sort_by(|a, b| a.timestamp().cmp(&b.timestamp()));
-        items.reverse();
-        items
-            .into_iter()
-            .map(StoredArchive::from)
-            .collect::<Vec<_>>()
-            .into_iter()
+    ///
+    /// If the archives committed by other connections can not be merged in, only the ones
+    /// already known are returned.
+    fn archive_iterator(&mut self) -> std::vec::IntoIter<StoredArchive> {
+        let _ = self.refresh();
+        archives_from_transactions(self.known_entries.values()).into_iter()
+    }
+
+    /// Describes the current heads of the manifest, newest first
+    ///
+    /// Picks up the transactions other connections have committed first, as those are what
+    /// would have diverged from ours.
+    fn describe_heads(&mut self) -> Result<Vec<ManifestHead>> {
+        self.refresh()?;
+        // The transactions reachable from each head, including the head itself
+        let reachable = self
+            .heads
+            .iter()
+            .map(|head| {
+                let mut seen = HashSet::new();
+                let mut pending = vec![*head];
+                while let Some(id) = pending.pop() {
+                    if let Some(tx) = self.known_entries.get(&id) {
+                        if seen.insert(id) {
+                            pending.extend_from_slice(tx.previous_heads());
+                        }
+                    }
+                }
+                seen
+            })
+            .collect::<Vec<_>>();
+        let mut heads = self
+            .heads
+            .iter()
+            .enumerate()
+            .map(|(index, id)| {
+                let tx = &self.known_entries[id];
+                let diverged = reachable[index]
+                    .iter()
+                    .filter(|id| {
+                        reachable
+                            .iter()
+                            .enumerate()
+                            .all(|(other, seen)| other == index || !seen.contains(id))
+                    })
+                    .count();
+                let name = if tx.checkpoint().is_some() || tx.is_merge() {
+                    None
+                } else {
+                    Some(tx.name().to_string())
+                };
+                ManifestHead {
+                    id: *id,
+                    timestamp: tx.timestamp(),
+                    name,
+                    diverged,
+                }
+            })
+            .collect::<Vec<_>>();
+        heads.sort_by_key(|head| std::cmp::Reverse(head.timestamp));
+        Ok(heads)
+    }
+
+    /// Writes a merge transaction following every head, if there is more than one
+    ///
+    /// Merges only ever append to the manifest, so are permitted on append only manifests.
+    fn merge_heads(&mut self) -> Result<Option<ManifestID>> {
+        self.refresh()?;
+        if self.heads.len() < 2 {
+            return Ok(None);
+        }
+        // Keep the order of the parents stable, so the merge does not depend on hash order
+        let mut heads = self.heads.clone();
+        heads.sort();
+        let tx = ManifestTransaction::new_merge(&heads, self.chunk_settings.hmac, &self.key);
+        self.append_transaction(tx).map(Some)
     }
 
     /// Sets the chunk settings
+    ///
+    /// Will return `Err(AppendOnly)` if this manifest is append only
     fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
-        let mut sfile =
-            LockedFile::open_read_write(self.path.join("chunk.settings"))?.ok_or_else(|| {
-                BackendError::Unknown("Failed to open chunk settings file for writing.".to_string())
-            })?;
-        // Clear the file
-        sfile.set_len(0)?;
-        // Write our new chunksettings
-        rmps::encode::write(&mut sfile, &settings)?;
+        if self.append_only {
+            return Err(BackendError::AppendOnly(
+                "Attempted to rewrite the chunk settings".to_string(),
+            ));
+        }
+        let own_file = self
+            .own_file("Attempted to rewrite the chunk settings")?
+            .path()
+            .to_path_buf();
+        store_chunk_settings(&self.path, &own_file, settings, self.durability)?;
         self.chunk_settings = settings;
         Ok(())
     }
@@ -272,34 +488,174 @@ impl InternalManifest {
     /// Adds an archive to the manifest
     #[allow(clippy::needless_pass_by_value)]
     fn write_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        // Pick up any archives other connections have committed, so our transaction follows
+        // all of them
+        self.refresh()?;
         // Create the transaction
         let tx = ManifestTransaction::new(
             &self.heads,
             archive.id(),
             archive.timestamp(),
             archive.name(),
+            archive.metadata().clone(),
+            archive.integrity().cloned(),
+            archive.signature().cloned(),
+            archive.duration(),
             self.chunk_settings.hmac,
             &self.key,
         );
+        self.append_transaction(tx)?;
+        Ok(())
+    }
+
+    /// Writes a transaction following every current head to our file, making it the only
+    /// head, and returns its tag
+    fn append_transaction(&mut self, tx: ManifestTransaction) -> Result<ManifestID> {
+        let durability = self.durability;
         // Write the transaction to the file
-        let file = &mut self.file;
+        let file = self.own_file("Attempted to add a transaction to the manifest")?;
         file.seek(SeekFrom::End(0))?;
-        rmps::encode::write(file, &tx)?;
+        rmps::encode::write(&mut *file, &tx)?;
+        if durability.sync_commits() {
+            file.sync_data()?;
+        }
         // Add the transaction to our entries list
         let id = tx.tag();
         self.known_entries.insert(id, tx);
         // Update our heads to only contain this transaction
         self.heads = vec![id];
-        Ok(())
+        Ok(id)
+    }
+
+    /// Returns the transaction file this connection appends to
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ReadOnly)`, describing the refused `operation`, if this manifest was
+    /// opened read only
+    fn own_file(&mut self, operation: &str) -> Result<&mut LockedFile> {
+        self.file
+            .as_mut()
+            .ok_or_else(|| BackendError::ReadOnly(operation.to_string()))
+    }
+}
+
+impl InternalManifest {
+    /// Replaces every transaction in the manifest with a single checkpoint
+    ///
+    /// The checkpoint is written to a new transaction file, and committed to disk, before any
+    /// of the old files are touched, so an interrupted checkpoint leaves behind at most some
+    /// redundant transactions. The old files are then moved into the `squashed` directory if
+    /// `keep_squashed` is set, and deleted otherwise.
+    ///
+    /// Archives whose pointers are in `removed` are left out of the checkpoint, removing them
+    /// from the manifest.
+    ///
+    /// Will return `Err(AppendOnly)` if this manifest is append only, `Err(ReadOnly)` if it was
+    /// opened read only, and an error if any other connection currently holds a transaction file.
+    fn checkpoint(
+        &mut self,
+        keep_squashed: bool,
+        removed: &HashSet<ChunkID>,
+    ) -> Result<CheckpointStats> {
+        if self.append_only {
+            return Err(BackendError::AppendOnly(
+                "Attempted to squash manifest transactions".to_string(),
+            ));
+        }
+        let own_path = self
+            .own_file("Attempted to squash manifest transactions")?
+            .path()
+            .to_path_buf();
+        let items = list_transaction_files(&self.path)?;
+        // Lock every transaction file other than our own, so nobody can write to them while we
+        // work
+        let mut locks = Vec::new();
+        for (_, entry) in &items {
+            let path = entry.path();
+            if path == own_path {
+                continue;
+            }
+            let lock = LockedFile::open_read_write(&path)?.ok_or_else(|| {
+                BackendError::ManifestError(
+                    "Unable to checkpoint while other connections to the repository are open"
+                        .to_string(),
+                )
+            })?;
+            locks.push(lock);
+        }
+        // Other connections may have committed archives since we last looked, which need to make
+        // it into the checkpoint
+        self.refresh()?;
+        let archives = archives_from_transactions(self.known_entries.values())
+            .into_iter()
+            .filter(|archive| !removed.contains(&archive.id()))
+            .collect::<Vec<_>>();
+        let mut squashed = self.known_entries.keys().copied().collect::<Vec<_>>();
+        squashed.sort();
+        let stats = CheckpointStats {
+            transactions_squashed: squashed.len(),
+            archives: archives.len(),
+            files_removed: items.len(),
+        };
+        let tx = ManifestTransaction::new_checkpoint(
+            &self.heads,
+            archives,
+            squashed,
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        // Write the checkpoint out to a fresh file
+        let id = items.last().map_or(0, |(id, _)| id + 1);
+        let mut file = LockedFile::open_read_write(self.path.join(id.to_string()))?
+            .ok_or(BackendError::FileLockError)?;
+        rmps::encode::write(&mut *file, &tx)?;
+        // The old files are moved out right after this, so the checkpoint gets synced regardless
+        // of the durability setting
+        file.sync_all()?;
+        // Switch over to the new file, releasing our old one along with the others
+        locks.extend(self.file.replace(file));
+        let squashed_path = self.path.join("squashed");
+        if keep_squashed {
+            create_dir_all(&squashed_path)?;
+        }
+        for (_, entry) in &items {
+            let path = entry.path();
+            if keep_squashed {
+                rename(&path, squashed_path.join(entry.file_name()))?;
+            } else {
+                remove_file(&path)?;
+            }
+        }
+        // Dropping the locks removes their lock files
+        std::mem::drop(locks);
+
+        let tag = tx.tag();
+        self.known_entries = HashMap::new();
+        self.known_entries.insert(tag, tx);
+        self.verified_memo_pad = HashSet::new();
+        self.verified_memo_pad.insert(tag);
+        self.heads = vec![tag];
+        // Everything that was in the old files is in our new one
+        self.offsets.clear();
+        Ok(stats)
     }
 }
 
 enum ManifestCommand {
-    LastMod(oneshot::Sender<Result<DateTime<FixedOffset>>>),
+    LastMod(oneshot::Sender<Result<Timestamp>>),
     ChunkSettings(oneshot::Sender<ChunkSettings>),
     ArchiveIterator(oneshot::Sender<std::vec::IntoIter<StoredArchive>>),
     WriteChunkSettings(ChunkSettings, oneshot::Sender<Result<()>>),
     WriteArchive(StoredArchive, oneshot::Sender<Result<()>>),
+    Heads(oneshot::Sender<Result<Vec<ManifestHead>>>),
+    MergeHeads(oneshot::Sender<Result<Option<ManifestID>>>),
+    Checkpoint(
+        bool,
+        HashSet<ChunkID>,
+        oneshot::Sender<Result<CheckpointStats>>,
+    ),
+    Snapshot(oneshot::Sender<()>),
     Close(oneshot::Sender<()>),
 }
 
@@ -333,6 +689,12 @@ impl Manifest {
     /// This method can optinally set the chunksettings for the manifest, but it is an error to not
     /// provide chunk settings if the manifest has not been created yet
     ///
+    /// If `append_only` is set, the chunk settings will only be written if the manifest has not
+    /// been created yet, and all later attempts to change them will be refused.
+    ///
+    /// `durability` controls whether or not new transactions are synced to disk as they are
+    /// written.
+    ///
     /// # Errors
     ///
     /// Will return Err if
@@ -345,15 +707,64 @@ impl Manifest {
     ///
     /// # TODOs:
     /// 1. Return an error if deserializing a transaciton fails before the end of the file is reached
-    /// 2. This function can currently panic if we have to create a new manifest file, but someone
-    ///    else creates the same file we are trying to first.
     pub fn open(
         repository_path: impl AsRef<Path>,
         chunk_settings: Option<ChunkSettings>,
         key: &Key,
         queue_depth: usize,
+        append_only: bool,
+        durability: Durability,
     ) -> Result<Manifest> {
-        let mut manifest = InternalManifest::open(repository_path.as_ref(), key, chunk_settings)?;
+        let manifest = InternalManifest::open(
+            repository_path.as_ref(),
+            key,
+            chunk_settings,
+            append_only,
+            false,
+            durability,
+        )?;
+        Ok(Manifest::spawn(
+            repository_path.as_ref(),
+            queue_depth,
+            manifest,
+        ))
+    }
+
+    /// Opens and reads the manifest without creating, locking, or writing to anything
+    ///
+    /// The chunk settings stored in the manifest are always used. Attempts to write archives
+    /// or chunk settings, merge heads, or checkpoint will be refused with `Err(ReadOnly)`.
+    ///
+    /// # Errors
+    ///
+    /// Will return Err if the manifest folder or its chunk settings do not exist, there is a file
+    /// called "manifest" in the repository folder, or reading the manifest fails.
+    pub fn open_read_only(
+        repository_path: impl AsRef<Path>,
+        key: &Key,
+        queue_depth: usize,
+    ) -> Result<Manifest> {
+        let manifest = InternalManifest::open(
+            repository_path.as_ref(),
+            key,
+            None,
+            false,
+            true,
+            Durability::default(),
+        )?;
+        Ok(Manifest::spawn(
+            repository_path.as_ref(),
+            queue_depth,
+            manifest,
+        ))
+    }
+
+    /// Starts the event processing loop for an opened manifest
+    fn spawn(
+        repository_path: &Path,
+        queue_depth: usize,
+        mut manifest: InternalManifest,
+    ) -> Manifest {
         let (input, mut output) = mpsc::channel(queue_depth);
         thread::spawn(move || {
             let mut final_ret = None;
@@ -374,6 +785,20 @@ impl Manifest {
                     ManifestCommand::WriteArchive(archive, ret) => {
                         ret.send(manifest.write_archive(archive)).unwrap();
                     }
+                    ManifestCommand::Heads(ret) => {
+                        ret.send(manifest.describe_heads()).unwrap();
+                    }
+                    ManifestCommand::MergeHeads(ret) => {
+                        ret.send(manifest.merge_heads()).unwrap();
+                    }
+                    ManifestCommand::Checkpoint(keep_squashed, removed, ret) => {
+                        ret.send(manifest.checkpoint(keep_squashed, &removed))
+                            .unwrap();
+                    }
+                    ManifestCommand::Snapshot(ret) => {
+                        manifest.snapshot = true;
+                        ret.send(()).unwrap();
+                    }
                     ManifestCommand::Close(ret) => {
                         final_ret = Some(ret);
                         break;
@@ -389,15 +814,94 @@ impl Manifest {
             };
         });
 
-        Ok(Manifest {
+        Manifest {
             input,
             path: repository_path
-                .as_ref()
                 .join("manifest")
                 .to_str()
                 .unwrap()
                 .to_string(),
-        })
+        }
+    }
+
+    /// Replaces every transaction in the manifest with a single checkpoint
+    ///
+    /// See `Backend::checkpoint` for details.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the manifest's event loop has already been closed
+    pub async fn checkpoint(&mut self, keep_squashed: bool) -> Result<CheckpointStats> {
+        let (i, o) = oneshot::channel();
+        self.input
+            .send(ManifestCommand::Checkpoint(
+                keep_squashed,
+                HashSet::new(),
+                i,
+            ))
+            .await
+            .unwrap();
+        o.await?
+    }
+
+    /// Lists the heads of the manifest, newest first
+    ///
+    /// See `Backend::heads` for details.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the manifest's event loop has already been closed
+    pub async fn heads(&mut self) -> Result<Vec<ManifestHead>> {
+        let (i, o) = oneshot::channel();
+        self.input.send(ManifestCommand::Heads(i)).await.unwrap();
+        o.await?
+    }
+
+    /// Joins every head of the manifest with a merge transaction, if there is more than one
+    ///
+    /// See `Backend::merge_heads` for details.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the manifest's event loop has already been closed
+    pub async fn merge_heads(&mut self) -> Result<Option<ManifestID>> {
+        let (i, o) = oneshot::channel();
+        self.input
+            .send(ManifestCommand::MergeHeads(i))
+            .await
+            .unwrap();
+        o.await?
+    }
+
+    /// Removes the archives with the given pointers from the manifest, by writing a checkpoint
+    /// without them
+    ///
+    /// See `Backend::remove_archives` for details.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the manifest's event loop has already been closed
+    pub async fn remove_archives(&mut self, archives: HashSet<ChunkID>) -> Result<CheckpointStats> {
+        let (i, o) = oneshot::channel();
+        self.input
+            .send(ManifestCommand::Checkpoint(false, archives, i))
+            .await
+            .unwrap();
+        o.await?
+    }
+
+    /// Stops merging in the transactions other connections commit
+    ///
+    /// From here on, the heads and archives are those that were in the manifest when it was
+    /// opened, along with any committed through this connection.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the manifest's event loop has already been closed
+    pub async fn snapshot(&mut self) {
+        let (i, o) = oneshot::channel();
+        self.input.send(ManifestCommand::Snapshot(i)).await.unwrap();
+        o.await.unwrap();
     }
 
     pub async fn close(&mut self) {
@@ -416,7 +920,7 @@ impl std::fmt::Debug for Manifest {
 #[async_trait]
 impl backend::Manifest for Manifest {
     type Iterator = std::vec::IntoIter<StoredArchive>;
-    async fn last_modification(&mut self) -> Result<DateTime<FixedOffset>> {
+    async fn last_modification(&mut self) -> Result<Timestamp> {
         let (i, o) = oneshot::channel();
         self.input.send(ManifestCommand::LastMod(i)).await.unwrap();
         o.await?
@@ -492,7 +996,8 @@ mod tests {
             let key = Key::random(32);
             // Create the manifest
             let mut manifest =
-                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest creation failed");
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .expect("Manifest creation failed");
             // Walk the directory and print some debugging info
             for entry in WalkDir::new(&path) {
                 let entry = entry.unwrap();
@@ -531,9 +1036,11 @@ mod tests {
             let key = Key::random(32);
             // Create the manifest
             let mut manifest1 =
-                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest 1 creation failed");
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .expect("Manifest 1 creation failed");
             let mut manifest2 =
-                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest 2 creation failed");
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .expect("Manifest 2 creation failed");
             // Walk the directory and print some debugging info
             for entry in WalkDir::new(&path) {
                 let entry = entry.unwrap();
@@ -566,7 +1073,8 @@ mod tests {
             let key = Key::random(32);
             // Create the manifest
             let mut manifest =
-                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest 1 creation failed");
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .expect("Manifest 1 creation failed");
             manifest.close().await;
             // check for the manifest file and the absense of the lock file
             let manifest_dir = path.join("manifest");
@@ -592,7 +1100,8 @@ mod tests {
             let key = Key::random(32);
             // Create the manifest
             let mut manifest =
-                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest creation failed");
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .expect("Manifest creation failed");
 
             // Create some dummy archives
             let len = 10;
@@ -615,7 +1124,8 @@ mod tests {
 
             // Reopen the manifest
             let mut manifest =
-                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest reopen failed");
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .expect("Manifest reopen failed");
             // Pull the archives out of it
             let archives: Vec<StoredArchive> = manifest.archive_iterator().await.collect();
             // Make sure we have the correct number of archives
@@ -627,6 +1137,289 @@ mod tests {
         });
     }
 
+    // Writes `count` dummy archives to the manifest at `path` in a single session
+    async fn write_archives(path: &Path, key: &Key, count: usize) -> Vec<StoredArchive> {
+        use smol::Timer;
+        let settings = ChunkSettings::lightweight();
+        let mut manifest =
+            Manifest::open(path, Some(settings), key, 4, false, Durability::default()).unwrap();
+        let mut archives = Vec::new();
+        for _ in 0..count {
+            let archive = StoredArchive::dummy_archive();
+            manifest.write_archive(archive.clone()).await.unwrap();
+            archives.push(archive);
+            Timer::after(time::Duration::from_millis(5)).await;
+        }
+        manifest.close().await;
+        archives
+    }
+
+    // Test to verify that:
+    // 1. Checkpointing squashes every transaction file into a single new one
+    // 2. The squashed files are kept in the squashed directory when asked to
+    // 3. All archives survive the checkpoint, and reopening the manifest
+    // 4. New archives can be added on top of a checkpoint
+    #[test]
+    fn checkpoint_keep() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let mut archives = write_archives(&path, &key, 5).await;
+            // Force a second transaction file by holding the first open
+            let holder =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            archives.extend(write_archives(&path, &key, 5).await);
+            let mut holder = holder;
+            holder.close().await;
+
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            let stats = manifest.checkpoint(true).await.unwrap();
+            assert_eq!(stats.transactions_squashed, 10);
+            assert_eq!(stats.archives, 10);
+            assert_eq!(stats.files_removed, 2);
+            manifest.close().await;
+
+            let manifest_dir = path.join("manifest");
+            assert_eq!(list_transaction_files(&manifest_dir).unwrap().len(), 1);
+            assert_eq!(read_dir(manifest_dir.join("squashed")).unwrap().count(), 2);
+
+            archives.extend(write_archives(&path, &key, 1).await);
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            let found: HashSet<StoredArchive> = manifest.archive_iterator().await.collect();
+            let expected: HashSet<StoredArchive> = archives.into_iter().collect();
+            assert_eq!(found, expected);
+            manifest.close().await;
+        });
+    }
+
+    // Test to verify that:
+    // 1. Checkpointing without keeping the squashed transactions deletes them
+    // 2. Checkpointing again on top of an existing checkpoint works
+    #[test]
+    fn checkpoint_drop() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let mut archives = write_archives(&path, &key, 3).await;
+
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            manifest.checkpoint(false).await.unwrap();
+            manifest.close().await;
+            archives.extend(write_archives(&path, &key, 2).await);
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            let stats = manifest.checkpoint(false).await.unwrap();
+            assert_eq!(stats.transactions_squashed, 3);
+            assert_eq!(stats.archives, 5);
+            manifest.close().await;
+
+            let manifest_dir = path.join("manifest");
+            assert_eq!(list_transaction_files(&manifest_dir).unwrap().len(), 1);
+            assert!(!manifest_dir.join("squashed").exists());
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            let found: HashSet<StoredArchive> = manifest.archive_iterator().await.collect();
+            let expected: HashSet<StoredArchive> = archives.into_iter().collect();
+            assert_eq!(found, expected);
+            manifest.close().await;
+        });
+    }
+
+    // Removing archives should leave every other archive, and nothing else, in the manifest
+    #[test]
+    fn remove_archives() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let mut archives = write_archives(&path, &key, 4).await;
+            let removed = archives.split_off(2);
+
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            let stats = manifest
+                .remove_archives(removed.iter().map(StoredArchive::id).collect())
+                .await
+                .unwrap();
+            assert_eq!(stats.transactions_squashed, 4);
+            assert_eq!(stats.archives, 2);
+            manifest.close().await;
+
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            let found: HashSet<StoredArchive> = manifest.archive_iterator().await.collect();
+            let expected: HashSet<StoredArchive> = archives.into_iter().collect();
+            assert_eq!(found, expected);
+            manifest.close().await;
+        });
+    }
+
+    // Checkpointing must be refused while another connection holds a transaction file, or on
+    // append only repositories
+    #[test]
+    fn checkpoint_refused() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            write_archives(&path, &key, 2).await;
+
+            let mut manifest1 =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            let mut manifest2 =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            assert!(manifest1.checkpoint(false).await.is_err());
+            manifest2.close().await;
+            manifest1.close().await;
+
+            let mut manifest =
+                Manifest::open(&path, None, &key, 4, true, Durability::default()).unwrap();
+            assert!(matches!(
+                manifest.checkpoint(false).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            manifest.close().await;
+        });
+    }
+
+    // Test to verify that:
+    // 1. Archives written by one connection are seen by another that is already open
+    // 2. Archives written by connections open at the same time end up on a single branch
+    // 3. Reopening with the same chunk settings leaves chunk.settings alone, and new settings
+    //    replace it without leaving scratch files behind
+    #[test]
+    fn concurrent_writers() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let mut manifest1 =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            let mut manifest2 =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            let archives = (0..3)
+                .map(|_| StoredArchive::dummy_archive())
+                .collect::<Vec<_>>();
+            manifest1.write_archive(archives[0].clone()).await.unwrap();
+            let seen: Vec<StoredArchive> = manifest2.archive_iterator().await.collect();
+            assert_eq!(seen, vec![archives[0].clone()]);
+            manifest2.write_archive(archives[1].clone()).await.unwrap();
+            manifest1.write_archive(archives[2].clone()).await.unwrap();
+
+            let settings_path = path.join("manifest").join("chunk.settings");
+            let modified = settings_path.metadata().unwrap().modified().unwrap();
+            let mut manifest3 =
+                Manifest::open(&path, Some(settings), &key, 4, false, Durability::default())
+                    .unwrap();
+            assert_eq!(
+                settings_path.metadata().unwrap().modified().unwrap(),
+                modified
+            );
+            let mut new_settings = settings;
+            new_settings.compression = crate::repository::Compression::ZStd { level: 1 };
+            manifest3.write_chunk_settings(new_settings).await.unwrap();
+            manifest3.close().await;
+            manifest2.close().await;
+            manifest1.close().await;
+
+            let mut manifest =
+                InternalManifest::open(&path, &key, None, false, false, Durability::default())
+                    .unwrap();
+            assert_eq!(manifest.chunk_settings(), new_settings);
+            assert_eq!(manifest.heads.len(), 1);
+            let found: HashSet<StoredArchive> = manifest.archive_iterator().collect();
+            let expected: HashSet<StoredArchive> = archives.into_iter().collect();
+            assert_eq!(found, expected);
+            let scratch = read_dir(path.join("manifest"))
+                .unwrap()
+                .filter_map(std::result::Result::ok)
+                .filter(|x| {
+                    x.file_name()
+                        .to_string_lossy()
+                        .starts_with("chunk.settings.")
+                })
+                .count();
+            assert_eq!(scratch, 0);
+        });
+    }
+
+    // Test to verify that:
+    // 1. Archives committed by two connections without seeing each other leave two heads
+    // 2. Merging them leaves a single head, which the other connection picks up
+    // 3. Merging a manifest that has not diverged does nothing
+    #[test]
+    fn merge_diverged_heads() {
+        let (tempdir, path) = setup();
+        let settings = ChunkSettings::lightweight();
+        let key = Key::random(32);
+        let mut first = InternalManifest::open(
+            &path,
+            &key,
+            Some(settings),
+            false,
+            false,
+            Durability::default(),
+        )
+        .unwrap();
+        let mut second = InternalManifest::open(
+            &path,
+            &key,
+            Some(settings),
+            false,
+            false,
+            Durability::default(),
+        )
+        .unwrap();
+        let archives = (0..2)
+            .map(|_| StoredArchive::dummy_archive())
+            .collect::<Vec<_>>();
+        first.write_archive(archives[0].clone()).unwrap();
+        // Commit the second archive as if it had raced the first, without refreshing
+        let tx = ManifestTransaction::new(
+            &second.heads,
+            archives[1].id(),
+            archives[1].timestamp(),
+            archives[1].name(),
+            archives[1].metadata().clone(),
+            None,
+            None,
+            None,
+            settings.hmac,
+            &key,
+        );
+        second.append_transaction(tx).unwrap();
+
+        let heads = first.describe_heads().unwrap();
+        assert_eq!(heads.len(), 2);
+        assert!(heads.iter().all(|head| head.diverged == 1));
+        assert!(heads.iter().all(|head| head.name.is_some()));
+        let merge = first.merge_heads().unwrap().unwrap();
+        let heads = second.describe_heads().unwrap();
+        assert_eq!(heads.len(), 1);
+        assert_eq!(heads[0].id, merge);
+        assert_eq!(heads[0].name, None);
+        assert_eq!(heads[0].diverged, 3);
+        assert_eq!(second.merge_heads().unwrap(), None);
+        assert_eq!(second.archive_iterator().count(), 2);
+    }
+
     // Test to verify that:
     // 1. Attempting to open a manifest with a path that points to an existing file Errs
     // 2. Attempting to create a manifest without chunk settings errors
@@ -641,12 +1434,19 @@ mod tests {
             let _test_file = File::create(&file_path).expect("Unable to create test file");
 
             // Attempt to open a manifest at that location
-            let mf = Manifest::open(&file_path, Some(settings), &key, 4);
+            let mf = Manifest::open(
+                &file_path,
+                Some(settings),
+                &key,
+                4,
+                false,
+                Durability::default(),
+            );
             // This should error
             assert!(mf.is_err());
 
             // Attempt to open a manifest without setting chunk settings
-            let mf = Manifest::open(&file_path, None, &key, 4);
+            let mf = Manifest::open(&file_path, None, &key, 4, false, Durability::default());
             assert!(mf.is_err());
         });
     }
@@ -1,7 +1,11 @@
+use crate::metrics;
 use crate::repository::backend::common::files::LockedFile;
-use crate::repository::backend::common::segment::Segment;
-use crate::repository::backend::{BackendError, Result, SegmentDescriptor};
-use crate::repository::{Chunk, ChunkSettings, Key};
+use crate::repository::backend::common::parity::ParitySettings;
+use crate::repository::backend::common::segment::{ChunkHealth, Segment};
+use crate::repository::backend::{
+    BackendError, CheckReport, Durability, Result, SegmentDescriptor,
+};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, Key};
 
 use futures::channel::mpsc;
 use futures::channel::oneshot;
@@ -11,12 +15,72 @@ use lru::LruCache;
 use smol::block_on;
 use walkdir::WalkDir;
 
-use std::fs::{create_dir, File};
+#[cfg(all(target_os = "linux", feature = "uring"))]
+use super::uring::{ReadRequest, Ring};
+#[cfg(all(target_os = "linux", feature = "uring"))]
+use asuran_core::repository::chunk::ChunkBody;
+
+use std::collections::HashSet;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+use std::collections::{hash_map::Entry, HashMap};
+#[cfg(all(target_os = "linux", feature = "uring"))]
+use std::convert::TryInto;
+use std::fs::{create_dir_all, remove_file, File};
 use std::io::{Read, Seek, Write};
+#[cfg(all(target_os = "linux", feature = "uring"))]
+use std::os::unix::io::{AsRawFd, OwnedFd};
 use std::path::{Path, PathBuf};
 use std::thread;
 
 struct SegmentPair<R: Read + Write + Seek>(u64, Segment<R>);
+
+/// The outcome of copying the live chunks out of underutilized segments
+#[derive(Debug, Clone, Default)]
+pub struct SegmentCompaction {
+    /// The old and new locations of every chunk that was copied
+    pub moved: Vec<(SegmentDescriptor, SegmentDescriptor)>,
+    /// The segments that were copied out of, which can be removed once the index has been
+    /// updated
+    pub segments: Vec<u64>,
+    /// The number of bytes of dead chunk data in those segments
+    pub reclaimed_bytes: u64,
+}
+
+/// Checks every chunk in a segment, recording any damage found in `report`
+fn check_segment<T: Read + Write + Seek>(
+    segment_id: u64,
+    segment: &mut Segment<T>,
+    repair: bool,
+    report: &mut CheckReport,
+) -> Result<()> {
+    for start in 0..segment.chunk_count() {
+        let location = SegmentDescriptor { segment_id, start };
+        report.chunks_checked += 1;
+        match segment.check_chunk(start, repair)? {
+            ChunkHealth::Intact => (),
+            ChunkHealth::Repairable => report.repairable.push(location),
+            ChunkHealth::Repaired => report.repaired += 1,
+            ChunkHealth::Unrecoverable => report.unrecoverable.push(location),
+        }
+    }
+    Ok(())
+}
+
+/// Walks the data directory, returning the IDs of all the segments in it
+fn list_segments(data_path: &Path) -> Vec<u64> {
+    WalkDir::new(data_path)
+        .into_iter()
+        .filter_map(std::result::Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            e.path()
+                .file_name()
+                .map(|x| String::from(x.to_string_lossy()))
+        })
+        .filter_map(|e| std::result::Result::ok(e.parse::<u64>()))
+        .collect()
+}
+
 /// An internal struct for handling the state of the segments
 ///
 /// Maintains a handle to the currently being written segment, and will keep it up to date as the
@@ -49,6 +113,15 @@ struct InternalSegmentHandler {
     chunk_settings: ChunkSettings,
     /// They key used for encrypting/decrypting headers
     key: Key,
+    /// The parity written along with new chunks, if any
+    parity: Option<ParitySettings>,
+    /// When the segment being written to gets synced to disk
+    durability: Durability,
+    /// Refuse to write to, repair, or remove any segment
+    read_only: bool,
+    /// The ring used for batching up reads and appends, if the kernel supports `io_uring`
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    ring: Option<Ring>,
 }
 
 impl InternalSegmentHandler {
@@ -59,7 +132,14 @@ impl InternalSegmentHandler {
     /// Note: the `repository_path` is the path of the root folder of the repository, not the data
     /// folder
     ///
-    /// Will default to a cache size of 100 file handles.
+    /// `cache_size` is the number of read only segment file handles that will be kept open in the
+    /// LRU cache. It will be clamped to a minimum of 1.
+    ///
+    /// `durability` controls whether the segment being written to is synced to disk after every
+    /// write, every time it is flushed, or never.
+    ///
+    /// If `read_only` is set, the data folder is neither created nor locked, and no segment is
+    /// opened for writing.
     ///
     /// This implementation is not thread safe, please see `SegmentHandler` for a thread safe
     /// implementation on top of this
@@ -77,47 +157,48 @@ impl InternalSegmentHandler {
     /// 1. This function currently recursively walks the entire data directory to find the highest
     /// numbered segment, when we can skip the recursion and only inspect the highest numbered
     /// segment folder, and still have correct behavior
+    #[allow(clippy::too_many_arguments)]
     fn open(
         repository_path: impl AsRef<Path>,
         size_limit: u64,
         segments_per_directory: u64,
         chunk_settings: ChunkSettings,
         key: Key,
+        cache_size: usize,
+        parity: Option<ParitySettings>,
+        durability: Durability,
+        read_only: bool,
     ) -> Result<InternalSegmentHandler> {
         // Construct the path of the data foler
         let data_path = repository_path.as_ref().join("data");
         // Create it if it does not exist
-        if !data_path.exists() {
-            create_dir(&data_path)?;
+        if !read_only {
+            create_dir_all(&data_path)?;
         }
 
         // Walk the data directory to find the higest numbered segment
-        let max_segment = WalkDir::new(&data_path)
-            .into_iter()
-            .filter_map(std::result::Result::ok)
-            .filter(|e| e.file_type().is_file())
-            .filter_map(|e| {
-                e.path()
-                    .file_name()
-                    .map(|x| String::from(x.to_string_lossy()))
-            })
-            .filter_map(|e| std::result::Result::ok(e.parse::<u64>()))
-            .max()
-            .unwrap_or(0);
+        let max_segment = list_segments(&data_path).into_iter().max().unwrap_or(0);
 
         let mut segment_handler = InternalSegmentHandler {
             current_segment: None,
             highest_segment: max_segment,
             size_limit,
-            ro_segment_cache: LruCache::new(100),
+            ro_segment_cache: LruCache::new(cache_size.max(1)),
             path: data_path,
             segments_per_directory,
             chunk_settings,
             key,
+            parity,
+            durability,
+            read_only,
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            ring: Ring::new(),
         };
 
         // Open the writing segment to ensure that the data directory is lockable
-        segment_handler.open_segment_write()?;
+        if !read_only {
+            segment_handler.open_segment_write()?;
+        }
 
         Ok(segment_handler)
     }
@@ -155,7 +236,9 @@ impl InternalSegmentHandler {
         //
         // Since this implementation is not thread safe, we do not have to worry about concurrent
         // writers, so we can ensure this refrence will be valid for as long as we need it
-        if !cache.contains(&segment_id) {
+        let cached = cache.contains(&segment_id);
+        metrics::segment_cache("multifile", cached);
+        if !cached {
             // Figure out which subfolder this belongs in and construct the path of the folder
             let folder_id = segment_id / self.segments_per_directory;
             // Find the folder it belongs to and check to see if it exists
@@ -198,6 +281,24 @@ impl InternalSegmentHandler {
         Ok(segment_pair)
     }
 
+    /// Opens the segment containing the chunk at `location` for reading
+    ///
+    /// Another connection may have appended to the segment since we cached its header, so a
+    /// cached segment that does not yet know about the chunk is reopened.
+    fn open_segment_containing(
+        &mut self,
+        location: SegmentDescriptor,
+    ) -> Result<&mut SegmentPair<File>> {
+        let stale = self
+            .ro_segment_cache
+            .peek(&location.segment_id)
+            .is_some_and(|segment| location.start >= segment.1.chunk_count());
+        if stale {
+            self.ro_segment_cache.pop(&location.segment_id);
+        }
+        self.open_segement_read(location.segment_id)
+    }
+
     /// Tests if a segment exists or not
     fn segment_exists(&self, segment_id: u64) -> bool {
         let folder_id = segment_id / self.segments_per_directory;
@@ -222,7 +323,13 @@ impl InternalSegmentHandler {
     ///    directory
     /// 3. We need to create a new segement, but some other instance beats us to the punch and the
     ///    new name we have chosen gets created and locked while we are running
+    /// 4. The segment handler was opened read only
     fn open_segment_write(&mut self) -> Result<&mut SegmentPair<LockedFile>> {
+        if self.read_only {
+            return Err(BackendError::ReadOnly(
+                "Attempted to write to a segment".to_string(),
+            ));
+        }
         // Check to see if we have a currently open segment, and open one up if we do not
         //
         // To make the lifetime juggling eaiser, we are going much the same route as
@@ -249,9 +356,7 @@ impl InternalSegmentHandler {
                 // Find the folder that the segment needs to go into, creating it if it does not exist
                 let folder_id = segment_id / self.segments_per_directory;
                 let folder_path = self.path.join(folder_id.to_string());
-                if !folder_path.exists() {
-                    create_dir(&folder_path)?;
-                }
+                create_dir_all(&folder_path)?;
                 // Construct the path for the segment proper, and construct the segment
                 let segment_path = folder_path.join(segment_id.to_string());
                 let header_path = folder_path.join(format!("{}.header", segment_id.to_string()));
@@ -269,6 +374,7 @@ impl InternalSegmentHandler {
                                 self.key.clone(),
                             )?,
                         );
+                        segment.1.set_parity(self.parity);
                         if segment.1.size() < self.size_limit {
                             // If the segment is in the cache, we need to invalidate it
                             self.ro_segment_cache.pop(&segment.0);
@@ -279,33 +385,33 @@ impl InternalSegmentHandler {
                 }
             }
 
-            let segment_id = self.highest_segment;
-            // Find the folder that the segment needs to go into, creating it if it does not exist
-            let folder_id = segment_id / self.segments_per_directory;
-            let folder_path = self.path.join(folder_id.to_string());
-            if !folder_path.exists() {
-                create_dir(&folder_path)?;
-            }
-            // Construct the path for the segment proper, and construct the segment
-            let segment_path = folder_path.join(segment_id.to_string());
-            let header_path = folder_path.join(format!("{}.header", segment_id.to_string()));
-            let segment_file = LockedFile::open_read_write(&segment_path)?.ok_or_else(|| {
-                BackendError::SegmentError(format!(
-                    "Unable to lock newly created segment. File: {:?} Src File: {} Line: {}",
-                    &segment_path,
-                    file!(),
-                    line!()
-                ))
-            })?;
-            let header_file = LockedFile::open_read_write(&header_path)?.ok_or_else(|| {
-                BackendError::SegmentError(format!(
-                    "Unable to lock newly created segment. File: {:?} Src File: {} Line: {}",
-                    &header_path,
-                    file!(),
-                    line!()
-                ))
-            })?;
-            let segment = SegmentPair(
+            // Another writer may create and lock the segment we picked before we get to it, in
+            // which case we move on to the next one
+            let (segment_id, segment_file, header_file) = loop {
+                let segment_id = self.highest_segment;
+                // Find the folder that the segment needs to go into, creating it if it does not
+                // exist
+                let folder_id = segment_id / self.segments_per_directory;
+                let folder_path = self.path.join(folder_id.to_string());
+                create_dir_all(&folder_path)?;
+                // Construct the path for the segment proper, and construct the segment
+                let segment_path = folder_path.join(segment_id.to_string());
+                let header_path = folder_path.join(format!("{segment_id}.header"));
+                if let Some(segment_file) = LockedFile::open_read_write(&segment_path)? {
+                    let header_file =
+                        LockedFile::open_read_write(&header_path)?.ok_or_else(|| {
+                            BackendError::SegmentError(format!(
+                                "Unable to lock newly created segment. File: {} Src File: {} Line: {}",
+                                header_path.display(),
+                                file!(),
+                                line!()
+                            ))
+                        })?;
+                    break (segment_id, segment_file, header_file);
+                }
+                self.highest_segment += 1;
+            };
+            let mut segment = SegmentPair(
                 segment_id,
                 Segment::new(
                     segment_file,
@@ -315,6 +421,7 @@ impl InternalSegmentHandler {
                     self.key.clone(),
                 )?,
             );
+            segment.1.set_parity(self.parity);
             self.current_segment = Some(segment);
         }
 
@@ -325,35 +432,332 @@ impl InternalSegmentHandler {
 
     /// Attempts to read a chunk from its associated segment
     fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
-        let segment_id = location.segment_id;
-        let segment = self.open_segement_read(segment_id)?;
+        let segment = self.open_segment_containing(location)?;
         segment.1.read_chunk(location.start)
     }
 
+    /// Attempts to read several chunks, returning the result of each read in order
+    ///
+    /// With the `uring` feature enabled on Linux, all of the reads are handed to the kernel at
+    /// once, otherwise they are performed one after the other.
+    fn read_chunks(&mut self, locations: &[SegmentDescriptor]) -> Vec<Result<Chunk>> {
+        #[cfg(all(target_os = "linux", feature = "uring"))]
+        {
+            // If anything goes wrong with the batch, the reads are retried individually, so
+            // that each one gets its own error
+            if let Some(Ok(chunks)) = self
+                .ring
+                .is_some()
+                .then(|| self.read_chunks_uring(locations))
+            {
+                return chunks;
+            }
+        }
+        locations.iter().map(|x| self.read_chunk(*x)).collect()
+    }
+
+    /// Reads several chunks through the ring
+    ///
+    /// Chunks that fail their HMAC and have parity are read again through `read_chunk`, which
+    /// will attempt to repair them.
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    fn read_chunks_uring(&mut self, locations: &[SegmentDescriptor]) -> Result<Vec<Result<Chunk>>> {
+        // Hold our own handle to each segment, as the cache may evict them before the batch is
+        // done
+        let mut files: HashMap<u64, OwnedFd> = HashMap::new();
+        let mut entries = Vec::with_capacity(locations.len());
+        let mut requests = Vec::with_capacity(locations.len());
+        for location in locations {
+            let segment = self.open_segment_containing(*location)?;
+            let entry = segment.1.get_entry(location.start)?;
+            let fd = match files.entry(location.segment_id) {
+                Entry::Occupied(file) => file.get().as_raw_fd(),
+                Entry::Vacant(slot) => slot
+                    .insert(segment.1.data_fd().try_clone_to_owned()?)
+                    .as_raw_fd(),
+            };
+            requests.push(ReadRequest {
+                fd,
+                offset: entry.start_offset,
+                length: (entry.end_offset - entry.start_offset)
+                    .try_into()
+                    .expect("Chunk size too big to fit in memory"),
+            });
+            entries.push(entry);
+        }
+        let buffers = self
+            .ring
+            .as_mut()
+            .expect("Attempted to read through a missing ring")
+            .read_batch(&requests)?;
+        std::mem::drop(files);
+        let mut chunks = Vec::with_capacity(locations.len());
+        for ((location, entry), buffer) in locations.iter().zip(entries).zip(buffers) {
+            let has_parity = entry.parity.is_some();
+            let chunk = Chunk::unsplit(entry.header, ChunkBody(buffer));
+            if has_parity && !chunk.verify_mac(&self.key) {
+                chunks.push(self.read_chunk(*location));
+            } else {
+                chunks.push(Ok(chunk));
+            }
+        }
+        Ok(chunks)
+    }
+
     /// Attempts to write a chunk
     ///
     /// Will close out the current segment if the size, after the write completes, execeds the max
     /// size
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         // Write the chunk
-        let segment = self.open_segment_write()?;
-        let start = segment.1.write_chunk(chunk)?;
-        let descriptor = SegmentDescriptor {
-            segment_id: segment.0,
-            start,
-        };
+        let segment_id = self.open_segment_write()?.0;
+        let start = self.append_to_current(vec![chunk])?[0];
+        let descriptor = SegmentDescriptor { segment_id, start };
         // If we have exceeded the max size, close out the current segment
-        if segment.1.size() >= self.size_limit {
-            self.current_segment.as_mut().map(|x| x.1.flush());
-            self.current_segment = None
-        }
+        self.close_if_full()?;
         Ok(descriptor)
     }
 
-    /// Flushes the changes to the current segment
+    /// Attempts to write several chunks, appending as many of them at once as the current
+    /// segment has room for
+    ///
+    /// Segments are closed out at the same points as they would be with repeated calls to
+    /// `write_chunk`.
+    fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let size_limit = self.size_limit;
+        let mut descriptors = Vec::with_capacity(chunks.len());
+        let mut chunks = chunks.into_iter().peekable();
+        while chunks.peek().is_some() {
+            let segment = self.open_segment_write()?;
+            // Take chunks until the segment would be over its size limit
+            let mut room = size_limit.saturating_sub(segment.1.size());
+            let mut batch = Vec::new();
+            while let Some(chunk) = chunks.peek() {
+                if room == 0 && !batch.is_empty() {
+                    break;
+                }
+                room = room.saturating_sub(chunk.get_bytes().len() as u64 + 1);
+                batch.extend(chunks.next());
+            }
+            let segment_id = segment.0;
+            let starts = self.append_to_current(batch)?;
+            descriptors.extend(
+                starts
+                    .into_iter()
+                    .map(|start| SegmentDescriptor { segment_id, start }),
+            );
+            // If we have exceeded the max size, close out the current segment
+            self.close_if_full()?;
+        }
+        Ok(descriptors)
+    }
+
+    /// Appends the chunks to the current segment with a single write, syncing them if the
+    /// durability calls for it, and returns their indexes in the segment
+    ///
+    /// # Panics
+    ///
+    /// Will panic if there is no segment open for writing
+    fn append_to_current(&mut self, chunks: Vec<Chunk>) -> Result<Vec<u64>> {
+        let sync_writes = self.durability.sync_writes();
+        let segment = self
+            .current_segment
+            .as_mut()
+            .expect("Attempted to append without a segment open for writing");
+        #[cfg(all(target_os = "linux", feature = "uring"))]
+        {
+            if let Some(ring) = self.ring.as_mut() {
+                let fd = segment.1.data_fd().as_raw_fd();
+                let starts = segment.1.write_chunks_with(chunks, |offset, bytes| {
+                    ring.append(fd, offset, bytes, sync_writes)
+                })?;
+                if sync_writes {
+                    segment.1.sync_header()?;
+                }
+                return Ok(starts);
+            }
+        }
+        let starts = segment.1.write_chunks(chunks)?;
+        if sync_writes {
+            segment.1.sync()?;
+        }
+        Ok(starts)
+    }
+
+    /// Flushes and closes out the current segment if it has reached its size limit
+    fn close_if_full(&mut self) -> Result<()> {
+        if let Some(segment) = self.current_segment.as_mut() {
+            if segment.1.size() >= self.size_limit {
+                self.flush()?;
+                self.current_segment = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the live chunks out of every segment where they make up less than `threshold` of
+    /// the segment's chunk data, returning their new locations
+    ///
+    /// The segment currently being written to is never compacted. The copies are flushed to disk
+    /// before this method returns, but the old segments are left in place.
+    fn compact(
+        &mut self,
+        live: &HashSet<SegmentDescriptor>,
+        threshold: f64,
+    ) -> Result<SegmentCompaction> {
+        // Pin down the segment the copies will be written to, so it can be excluded
+        let current = self.open_segment_write()?.0;
+        let mut compaction = SegmentCompaction::default();
+        let mut to_move = Vec::new();
+        for segment_id in list_segments(&self.path) {
+            if segment_id == current {
+                continue;
+            }
+            let sizes = self.open_segement_read(segment_id)?.1.chunk_sizes();
+            let total_bytes: u64 = sizes.iter().sum();
+            let mut live_bytes = 0;
+            let mut live_chunks = Vec::new();
+            for (index, size) in sizes.into_iter().enumerate() {
+                let location = SegmentDescriptor {
+                    segment_id,
+                    start: index as u64,
+                };
+                if live.contains(&location) {
+                    live_bytes += size;
+                    live_chunks.push(location);
+                }
+            }
+            // Any precision lost here is far too small to matter for a threshold
+            #[allow(clippy::cast_precision_loss)]
+            let underutilized =
+                total_bytes == 0 || (live_bytes as f64) < threshold * (total_bytes as f64);
+            if underutilized {
+                compaction.segments.push(segment_id);
+                compaction.reclaimed_bytes += total_bytes - live_bytes;
+                to_move.extend(live_chunks);
+            }
+        }
+        for location in to_move {
+            let chunk = self.read_chunk(location)?;
+            let new_location = self.write_chunk(chunk)?;
+            compaction.moved.push((location, new_location));
+        }
+        self.flush()?;
+        Ok(compaction)
+    }
+
+    /// Verifies every chunk in every segment, optionally repairing any damage that can be
+    /// rebuilt from parity
+    ///
+    /// The current segment is flushed and closed first, so that it gets checked as well.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a segment can not be opened, or, when repairing, if a segment is
+    /// locked by another writer or the segment handler was opened read only.
+    fn check(&mut self, repair: bool) -> Result<CheckReport> {
+        if repair && self.read_only {
+            return Err(BackendError::ReadOnly(
+                "Attempted to repair segments".to_string(),
+            ));
+        }
+        self.flush()?;
+        self.current_segment = None;
+        let mut report = CheckReport::default();
+        let mut segments = list_segments(&self.path);
+        segments.sort_unstable();
+        for segment_id in segments {
+            if repair {
+                // Any cached read only handle may hold stale data once the segment is repaired
+                self.ro_segment_cache.pop(&segment_id);
+                let folder_path = self
+                    .path
+                    .join((segment_id / self.segments_per_directory).to_string());
+                let segment_path = folder_path.join(segment_id.to_string());
+                let header_path = folder_path.join(format!("{segment_id}.header"));
+                let locked_error = || {
+                    BackendError::SegmentError(format!(
+                        "Unable to repair segment {segment_id}, as it is locked by another writer"
+                    ))
+                };
+                let segment_file =
+                    LockedFile::open_read_write(&segment_path)?.ok_or_else(locked_error)?;
+                let header_file =
+                    LockedFile::open_read_write(&header_path)?.ok_or_else(locked_error)?;
+                let mut segment = Segment::new(
+                    segment_file,
+                    header_file,
+                    self.size_limit,
+                    self.chunk_settings,
+                    self.key.clone(),
+                )?;
+                check_segment(segment_id, &mut segment, true, &mut report)?;
+            } else {
+                let segment = &mut self.open_segement_read(segment_id)?.1;
+                check_segment(segment_id, segment, false, &mut report)?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Lists the ids of every segment on disk, flushing the current segment first so that
+    /// everything written to it so far gets listed
+    fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        self.flush()?;
+        let mut segments = list_segments(&self.path);
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    /// Returns the id and location of every chunk in the given segment
+    fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        Ok(self
+            .open_segement_read(segment_id)?
+            .1
+            .chunk_descriptors(segment_id))
+    }
+
+    /// Deletes the provided segments from disk
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if asked to remove the segment currently being written to, if the
+    /// segment handler was opened read only, or if deleting any of the files fails
+    fn remove_segments(&mut self, segments: &[u64]) -> Result<()> {
+        if self.read_only {
+            return Err(BackendError::ReadOnly(
+                "Attempted to remove segments".to_string(),
+            ));
+        }
+        for &segment_id in segments {
+            if self.current_segment.as_ref().map(|x| x.0) == Some(segment_id) {
+                return Err(BackendError::SegmentError(format!(
+                    "Refusing to remove segment {segment_id}, as it is currently being written to"
+                )));
+            }
+            self.ro_segment_cache.pop(&segment_id);
+            let folder_id = segment_id / self.segments_per_directory;
+            let folder_path = self.path.join(folder_id.to_string());
+            // Remove the data file first, so an interrupted removal can only ever leave behind
+            // a stray header, which is not considered a segment
+            remove_file(folder_path.join(segment_id.to_string()))?;
+            remove_file(folder_path.join(format!("{segment_id}.header")))?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the changes to the current segment, syncing it to disk unless durability is
+    /// `Never`
     fn flush(&mut self) -> Result<()> {
         if let Some(segment) = self.current_segment.as_mut() {
-            segment.1.flush()
+            if self.durability.sync_commits() {
+                segment.1.sync()
+            } else {
+                segment.1.flush()
+            }
         } else {
             Ok(())
         }
@@ -363,6 +767,20 @@ impl InternalSegmentHandler {
 enum SegmentHandlerCommand {
     ReadChunk(SegmentDescriptor, oneshot::Sender<Result<Chunk>>),
     WriteChunk(Chunk, oneshot::Sender<Result<SegmentDescriptor>>),
+    WriteChunks(Vec<Chunk>, oneshot::Sender<Result<Vec<SegmentDescriptor>>>),
+    Compact(
+        HashSet<SegmentDescriptor>,
+        f64,
+        oneshot::Sender<Result<SegmentCompaction>>,
+    ),
+    RemoveSegments(Vec<u64>, oneshot::Sender<Result<()>>),
+    Check(bool, oneshot::Sender<Result<CheckReport>>),
+    SegmentIds(oneshot::Sender<Result<Vec<u64>>>),
+    SegmentDescriptors(
+        u64,
+        oneshot::Sender<Result<Vec<(ChunkID, SegmentDescriptor)>>>,
+    ),
+    Flush(oneshot::Sender<Result<()>>),
     Close(oneshot::Sender<()>),
 }
 
@@ -382,10 +800,16 @@ impl SegmentHandler {
     /// Opens a `SegmentHandler`, creating the data directory and the initial
     /// segment if it does not exist
     ///
+    /// If `parity` is provided, Reed-Solomon parity will be written along with every new
+    /// chunk.
+    ///
+    /// `durability` controls when the segment being written to is synced to disk.
+    ///
     /// # Errors
     ///
     /// Will error if creating/locking a segment fails, such as if the user does
     /// not have access to that directory, or if any other I/O error occurs
+    #[allow(clippy::too_many_arguments)]
     pub fn open(
         repository_path: impl AsRef<Path>,
         size_limit: u64,
@@ -393,29 +817,110 @@ impl SegmentHandler {
         chunk_settings: ChunkSettings,
         key: Key,
         queue_depth: usize,
+        cache_size: usize,
+        parity: Option<ParitySettings>,
+        durability: Durability,
     ) -> Result<SegmentHandler> {
         // Create the internal handler
-        let mut handler = InternalSegmentHandler::open(
+        let handler = InternalSegmentHandler::open(
             repository_path,
             size_limit,
             segments_per_directory,
             chunk_settings,
             key,
+            cache_size,
+            parity,
+            durability,
+            false,
+        )?;
+        Ok(SegmentHandler::spawn(handler, queue_depth))
+    }
+
+    /// Opens a `SegmentHandler` that only reads existing segments, without creating, locking,
+    /// or writing to anything
+    ///
+    /// Attempts to write chunks, compact, repair, or remove segments will be refused with
+    /// `Err(ReadOnly)`.
+    ///
+    /// # Errors
+    ///
+    /// Will error if any I/O error occurs listing the existing segments
+    pub fn open_read_only(
+        repository_path: impl AsRef<Path>,
+        segments_per_directory: u64,
+        chunk_settings: ChunkSettings,
+        key: Key,
+        queue_depth: usize,
+        cache_size: usize,
+    ) -> Result<SegmentHandler> {
+        let handler = InternalSegmentHandler::open(
+            repository_path,
+            // Nothing is ever written, so the size limit does not matter
+            u64::MAX,
+            segments_per_directory,
+            chunk_settings,
+            key,
+            cache_size,
+            None,
+            Durability::default(),
+            true,
         )?;
+        Ok(SegmentHandler::spawn(handler, queue_depth))
+    }
+
+    /// Starts the event processing loop for an opened segment handler
+    fn spawn(mut handler: InternalSegmentHandler, queue_depth: usize) -> SegmentHandler {
         // get the path from it
         let path = String::from(handler.path.to_string_lossy());
         // Create the communication channel and open the event processing loop in its own task
         let (input, mut output) = mpsc::channel(queue_depth);
         thread::spawn(move || {
             let mut final_ret = None;
-            while let Some(command) = block_on(output.next()) {
+            // A command pulled off the queue while batching up reads, to be handled next
+            let mut next = None;
+            while let Some(command) = next.take().or_else(|| block_on(output.next())) {
                 match command {
                     SegmentHandlerCommand::ReadChunk(location, ret) => {
-                        ret.send(handler.read_chunk(location)).unwrap();
+                        // Batch up any other reads that are already waiting
+                        let mut locations = vec![location];
+                        let mut rets = vec![ret];
+                        while let Ok(Some(command)) = output.try_next() {
+                            if let SegmentHandlerCommand::ReadChunk(location, ret) = command {
+                                locations.push(location);
+                                rets.push(ret);
+                            } else {
+                                next = Some(command);
+                                break;
+                            }
+                        }
+                        for (chunk, ret) in handler.read_chunks(&locations).into_iter().zip(rets) {
+                            ret.send(chunk).unwrap();
+                        }
                     }
                     SegmentHandlerCommand::WriteChunk(chunk, ret) => {
                         ret.send(handler.write_chunk(chunk)).unwrap();
                     }
+                    SegmentHandlerCommand::WriteChunks(chunks, ret) => {
+                        ret.send(handler.write_chunks(chunks)).unwrap();
+                    }
+                    SegmentHandlerCommand::Compact(live, threshold, ret) => {
+                        ret.send(handler.compact(&live, threshold)).unwrap();
+                    }
+                    SegmentHandlerCommand::RemoveSegments(segments, ret) => {
+                        ret.send(handler.remove_segments(&segments)).unwrap();
+                    }
+                    SegmentHandlerCommand::Check(repair, ret) => {
+                        ret.send(handler.check(repair)).unwrap();
+                    }
+                    SegmentHandlerCommand::SegmentIds(ret) => {
+                        ret.send(handler.segment_ids()).unwrap();
+                    }
+                    SegmentHandlerCommand::SegmentDescriptors(segment_id, ret) => {
+                        ret.send(handler.segment_descriptors(segment_id)).unwrap();
+                    }
+                    SegmentHandlerCommand::Flush(ret) => {
+                        ret.send(handler.flush()).unwrap();
+                    }
                     SegmentHandlerCommand::Close(ret) => {
                         handler.flush().unwrap();
                         final_ret = Some(ret);
@@ -432,7 +937,7 @@ impl SegmentHandler {
             }
         });
 
-        Ok(SegmentHandler { input, path })
+        SegmentHandler { input, path }
     }
 
     pub async fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
@@ -453,6 +958,119 @@ impl SegmentHandler {
         output.await.unwrap()
     }
 
+    /// Writes several chunks with as few appends as possible, returning their locations in
+    /// order
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the segment handler has already been closed
+    pub async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::WriteChunks(chunks, input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
+    /// Copies the live chunks out of any segment where they make up less than `threshold` of its
+    /// chunk data
+    ///
+    /// The old segments are left in place, and must be removed with `remove_segments` once the
+    /// index has been updated with the new locations.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the segment handler has already been closed
+    pub async fn compact(
+        &mut self,
+        live: HashSet<SegmentDescriptor>,
+        threshold: f64,
+    ) -> Result<SegmentCompaction> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::Compact(live, threshold, input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
+    /// Deletes the provided segments from disk
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the segment handler has already been closed
+    pub async fn remove_segments(&mut self, segments: Vec<u64>) -> Result<()> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::RemoveSegments(segments, input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
+    /// Verifies every chunk in every segment, optionally repairing any damage that can be
+    /// rebuilt from parity
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the segment handler has already been closed
+    pub async fn check(&mut self, repair: bool) -> Result<CheckReport> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::Check(repair, input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
+    /// Lists the ids of every segment in the repository, in ascending order
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the segment handler has already been closed
+    pub async fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::SegmentIds(input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
+    /// Returns the id and location of every chunk in the given segment, read from its
+    /// header
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the segment handler has already been closed
+    pub async fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::SegmentDescriptors(segment_id, input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
+    /// Writes out the buffered state of the segment currently being written to, so that
+    /// every chunk written so far can be read back after a crash
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the segment handler has already been closed
+    pub async fn flush(&mut self) -> Result<()> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::Flush(input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
     pub async fn close(&mut self) {
         let (input, output) = oneshot::channel();
         self.input
@@ -0,0 +1,240 @@
+//! `io_uring` backed IO for `MultiFile` segments
+//!
+//! Only compiled in on Linux, with the `uring` feature enabled. The segment handler submits every
+//! read in a batch to the kernel at once, rather than performing them one after the other, which
+//! lets the disk reorder and overlap them. Appends are submitted along with the sync that follows
+//! them, if there is one.
+//!
+//! Kernels without `io_uring` support, or where it has been disabled, are detected when the ring
+//! is created, and the segment handler falls back to plain `std::fs` IO.
+use crate::repository::backend::{BackendError, Result};
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+use std::convert::TryInto;
+use std::io;
+use std::os::unix::io::RawFd;
+
+/// The number of submission queue entries in the ring
+///
+/// Batches larger than this are submitted in several waves.
+const RING_ENTRIES: u32 = 64;
+
+/// A read from a segment, at a known offset and of a known length
+pub struct ReadRequest {
+    /// The file to read from
+    ///
+    /// This must stay open until the batch it is a part of completes, so it should not be
+    /// borrowed from a segment that may be evicted from the cache in the meantime.
+    pub fd: RawFd,
+    pub offset: u64,
+    pub length: usize,
+}
+
+pub struct Ring {
+    ring: IoUring,
+}
+
+impl Ring {
+    /// Sets up a new ring, returning `None` if the kernel does not support `io_uring`
+    pub fn new() -> Option<Ring> {
+        IoUring::new(RING_ENTRIES).ok().map(|ring| Ring { ring })
+    }
+
+    /// Performs every read in the batch, returning the bytes read for each, in order
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the reads fail, or if any of them run off the end of their
+    /// file.
+    pub fn read_batch(&mut self, requests: &[ReadRequest]) -> Result<Vec<Vec<u8>>> {
+        let mut buffers: Vec<Vec<u8>> = requests.iter().map(|x| vec![0_u8; x.length]).collect();
+        let mut filled = vec![0_usize; requests.len()];
+        // Reads that have not yet been completely filled, reads can come back short
+        let mut pending: Vec<usize> = (0..requests.len())
+            .filter(|&i| requests[i].length > 0)
+            .collect();
+        while !pending.is_empty() {
+            let wave_len = pending.len().min(RING_ENTRIES as usize);
+            let wave = pending.drain(..wave_len).collect::<Vec<_>>();
+            for &index in &wave {
+                let request = &requests[index];
+                let remaining = &mut buffers[index][filled[index]..];
+                let entry = opcode::Read::new(
+                    types::Fd(request.fd),
+                    remaining.as_mut_ptr(),
+                    remaining.len().try_into().unwrap_or(u32::MAX),
+                )
+                .offset(request.offset + filled[index] as u64)
+                .build()
+                .user_data(index as u64);
+                // The buffers are neither moved nor dropped until all of the reads in this wave
+                // have completed, and the wave is never larger than the submission queue
+                unsafe {
+                    self.ring
+                        .submission()
+                        .push(&entry)
+                        .expect("Submission queue unexpectedly full");
+                }
+            }
+            let completions = self.complete(wave.len())?;
+            for (user_data, result) in completions {
+                let index: usize = user_data.try_into().expect("Invalid read id");
+                let read = check_result(result)?;
+                if read == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                filled[index] += read;
+                if filled[index] < requests[index].length {
+                    pending.push(index);
+                }
+            }
+        }
+        Ok(buffers)
+    }
+
+    /// Writes `bytes` to the file at `offset`, following the write with an `fdatasync` if `sync`
+    /// is set
+    ///
+    /// The sync is linked to the write, so both are handed to the kernel with a single system
+    /// call.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the write or the sync fails
+    pub fn append(&mut self, fd: RawFd, offset: u64, bytes: &[u8], sync: bool) -> Result<()> {
+        let mut written = 0;
+        let mut synced = !sync;
+        while written < bytes.len() || !synced {
+            let mut count = 0;
+            if written < bytes.len() {
+                let remaining = &bytes[written..];
+                let mut entry = opcode::Write::new(
+                    types::Fd(fd),
+                    remaining.as_ptr(),
+                    remaining.len().try_into().unwrap_or(u32::MAX),
+                )
+                .offset(offset + written as u64)
+                .build()
+                .user_data(0);
+                if sync {
+                    entry = entry.flags(squeue::Flags::IO_LINK);
+                }
+                // `bytes` outlives the call to `complete` below
+                unsafe {
+                    self.ring
+                        .submission()
+                        .push(&entry)
+                        .expect("Submission queue unexpectedly full");
+                }
+                count += 1;
+            }
+            if sync {
+                let entry = opcode::Fsync::new(types::Fd(fd))
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build()
+                    .user_data(1);
+                unsafe {
+                    self.ring
+                        .submission()
+                        .push(&entry)
+                        .expect("Submission queue unexpectedly full");
+                }
+                count += 1;
+            }
+            let mut completions = self.complete(count)?;
+            // Check the write first, so a short write can be told apart from a failed sync
+            completions.sort_unstable();
+            for (user_data, result) in completions {
+                if user_data == 0 {
+                    let count = check_result(result)?;
+                    if count == 0 {
+                        return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+                    }
+                    written += count;
+                } else if result == -libc::ECANCELED && written < bytes.len() {
+                    // A short write severs the link, the sync will be retried along with the
+                    // rest of the data
+                } else {
+                    check_result(result)?;
+                    synced = written == bytes.len();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Submits everything in the submission queue, and waits for `count` completions, returning
+    /// their user data and results
+    fn complete(&mut self, count: usize) -> Result<Vec<(u64, i32)>> {
+        self.ring.submit_and_wait(count)?;
+        let mut completions = Vec::with_capacity(count);
+        while completions.len() < count {
+            completions.extend(
+                self.ring
+                    .completion()
+                    .map(|entry| (entry.user_data(), entry.result())),
+            );
+            if completions.len() < count {
+                self.ring.submit_and_wait(count - completions.len())?;
+            }
+        }
+        Ok(completions)
+    }
+}
+
+/// Converts the result of a completion into a byte count, or the error it represents
+fn check_result(result: i32) -> Result<usize> {
+    if result < 0 {
+        Err(BackendError::from(io::Error::from_raw_os_error(-result)))
+    } else {
+        Ok(result.try_into().expect("Negative results are errors"))
+    }
+}
+
+impl std::fmt::Debug for Ring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ring").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    // Appends enough data to need several waves of reads, and reads it back in pieces
+    #[test]
+    fn append_read_batch() {
+        // Nothing to test on kernels without io_uring
+        let Some(mut ring) = Ring::new() else {
+            return;
+        };
+        let file = tempfile::tempfile().unwrap();
+        let fd = file.as_raw_fd();
+        let data: Vec<u8> = (0..200_u8).flat_map(|x| vec![x; 100]).collect();
+        ring.append(fd, 0, &data[..10_000], false).unwrap();
+        ring.append(fd, 10_000, &data[10_000..], true).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), data.len() as u64);
+
+        let requests = (0..200_u64)
+            .map(|x| ReadRequest {
+                fd,
+                offset: x * 100,
+                length: 100,
+            })
+            .collect::<Vec<_>>();
+        let buffers = ring.read_batch(&requests).unwrap();
+        for (index, buffer) in (0..200_u8).zip(&buffers) {
+            assert_eq!(buffer, &vec![index; 100]);
+        }
+
+        // Reading past the end of the file is an error
+        let past_end = ReadRequest {
+            fd,
+            offset: data.len() as u64,
+            length: 1,
+        };
+        assert!(ring.read_batch(&[past_end]).is_err());
+    }
+}
@@ -13,7 +13,7 @@ pub struct ManifestWrapper<T: Manifest>(T);
 #[async_trait]
 impl<T: Manifest> Manifest for ManifestWrapper<T> {
     type Iterator = Box<dyn Iterator<Item = StoredArchive> + 'static>;
-    async fn last_modification(&mut self) -> Result<DateTime<FixedOffset>> {
+    async fn last_modification(&mut self) -> Result<Timestamp> {
         self.0.last_modification().await
     }
     async fn chunk_settings(&mut self) -> ChunkSettings {
@@ -36,7 +36,7 @@ impl<T: Manifest> Manifest for ManifestWrapper<T> {
 #[async_trait]
 impl Manifest for ManifestObject {
     type Iterator = Box<dyn Iterator<Item = StoredArchive> + 'static>;
-    async fn last_modification(&mut self) -> Result<DateTime<FixedOffset>> {
+    async fn last_modification(&mut self) -> Result<Timestamp> {
         (**self).last_modification().await
     }
     async fn chunk_settings(&mut self) -> ChunkSettings {
@@ -64,6 +64,9 @@ impl Index for IndexObject {
     async fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
         (**self).set_chunk(id, location).await
     }
+    async fn contains_chunk(&mut self, id: ChunkID) -> bool {
+        (**self).contains_chunk(id).await
+    }
     async fn known_chunks(&mut self) -> HashSet<ChunkID> {
         (**self).known_chunks().await
     }
@@ -101,9 +104,58 @@ impl<T: Backend> Backend for BackendWrapper<T> {
     async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         self.0.write_chunk(chunk).await
     }
+    async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        self.0.write_chunks(chunks).await
+    }
+    async fn flush(&mut self) -> Result<()> {
+        self.0.flush().await
+    }
     async fn close(&mut self) {
         self.0.close().await
     }
+    async fn compact(&mut self, threshold: f64) -> Result<CompactionStats> {
+        self.0.compact(threshold).await
+    }
+    async fn check(&mut self, repair: bool) -> Result<CheckReport> {
+        self.0.check(repair).await
+    }
+    async fn chunk_descriptors(&mut self) -> Result<ChunkDescriptors> {
+        self.0.chunk_descriptors().await
+    }
+    async fn checkpoint(&mut self, keep_squashed: bool) -> Result<CheckpointStats> {
+        self.0.checkpoint(keep_squashed).await
+    }
+    async fn heads(&mut self) -> Result<Vec<ManifestHead>> {
+        self.0.heads().await
+    }
+    async fn merge_heads(&mut self) -> Result<Option<common::ManifestID>> {
+        self.0.merge_heads().await
+    }
+    async fn remove_archives(&mut self, archives: HashSet<ChunkID>) -> Result<CheckpointStats> {
+        self.0.remove_archives(archives).await
+    }
+    async fn remove_chunks(&mut self, chunks: HashSet<ChunkID>) -> Result<usize> {
+        self.0.remove_chunks(chunks).await
+    }
+    async fn counted_archives(&mut self) -> Result<Option<HashSet<ChunkID>>> {
+        self.0.counted_archives().await
+    }
+    async fn add_references(&mut self, archive: ChunkID, chunks: HashSet<ChunkID>) -> Result<()> {
+        self.0.add_references(archive, chunks).await
+    }
+    async fn release_references(
+        &mut self,
+        archives: HashMap<ChunkID, HashSet<ChunkID>>,
+    ) -> Result<HashSet<ChunkID>> {
+        self.0.release_references(archives).await
+    }
+    async fn reset_references(
+        &mut self,
+        archives: HashSet<ChunkID>,
+        counts: HashMap<ChunkID, u64>,
+    ) -> Result<()> {
+        self.0.reset_references(archives, counts).await
+    }
     fn get_object_handle(&self) -> BackendObject {
         self.0.get_object_handle()
     }
@@ -131,9 +183,58 @@ impl Backend for BackendObject {
     async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         (**self).write_chunk(chunk).await
     }
+    async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        (**self).write_chunks(chunks).await
+    }
+    async fn flush(&mut self) -> Result<()> {
+        (**self).flush().await
+    }
     async fn close(&mut self) {
         (**self).close().await
     }
+    async fn compact(&mut self, threshold: f64) -> Result<CompactionStats> {
+        (**self).compact(threshold).await
+    }
+    async fn check(&mut self, repair: bool) -> Result<CheckReport> {
+        (**self).check(repair).await
+    }
+    async fn chunk_descriptors(&mut self) -> Result<ChunkDescriptors> {
+        (**self).chunk_descriptors().await
+    }
+    async fn checkpoint(&mut self, keep_squashed: bool) -> Result<CheckpointStats> {
+        (**self).checkpoint(keep_squashed).await
+    }
+    async fn heads(&mut self) -> Result<Vec<ManifestHead>> {
+        (**self).heads().await
+    }
+    async fn merge_heads(&mut self) -> Result<Option<common::ManifestID>> {
+        (**self).merge_heads().await
+    }
+    async fn remove_archives(&mut self, archives: HashSet<ChunkID>) -> Result<CheckpointStats> {
+        (**self).remove_archives(archives).await
+    }
+    async fn remove_chunks(&mut self, chunks: HashSet<ChunkID>) -> Result<usize> {
+        (**self).remove_chunks(chunks).await
+    }
+    async fn counted_archives(&mut self) -> Result<Option<HashSet<ChunkID>>> {
+        (**self).counted_archives().await
+    }
+    async fn add_references(&mut self, archive: ChunkID, chunks: HashSet<ChunkID>) -> Result<()> {
+        (**self).add_references(archive, chunks).await
+    }
+    async fn release_references(
+        &mut self,
+        archives: HashMap<ChunkID, HashSet<ChunkID>>,
+    ) -> Result<HashSet<ChunkID>> {
+        (**self).release_references(archives).await
+    }
+    async fn reset_references(
+        &mut self,
+        archives: HashSet<ChunkID>,
+        counts: HashMap<ChunkID, u64>,
+    ) -> Result<()> {
+        (**self).reset_references(archives, counts).await
+    }
     fn get_object_handle(&self) -> BackendObject {
         (**self).get_object_handle()
     }
@@ -0,0 +1,565 @@
+//! Provides access to a repository served by `asuran-server` over HTTP
+//!
+//! Every backend operation is sent as an msgpack encoded [`Request`] in the body of a `POST` to
+//! [`RPC_PATH`], authenticated with a bearer token, and answered with an msgpack encoded
+//! [`Reply`]. The server holds the repository and applies its own policies, such as refusing to
+//! overwrite data or limiting the space a client may use, so the client never needs direct
+//! access to the storage.
+//!
+//! The protocol is plain HTTP. Deployments crossing untrusted networks should put the server
+//! behind a TLS terminating reverse proxy, as the bearer token is sent in the clear otherwise.
+use super::{BackendError, ChunkID, ChunkSettings, HashSet, Result, SegmentDescriptor};
+use crate::manifest::StoredArchive;
+use crate::repository::backend::common::sync_backend::{
+    BackendHandle, SyncBackend, SyncIndex, SyncManifest,
+};
+use crate::repository::{Chunk, EncryptedKey};
+use crate::time::Timestamp;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+
+pub(crate) mod http;
+pub mod server;
+
+/// The path all remote procedure calls are posted to
+pub const RPC_PATH: &str = "/v1/rpc";
+/// The content type of request and response bodies
+pub const CONTENT_TYPE: &str = "application/msgpack";
+
+/// A single backend operation, as sent from the client to the server
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    ReadKey,
+    WriteKey(EncryptedKey),
+    ReadChunk(SegmentDescriptor),
+    WriteChunk(Chunk),
+    WriteChunks(Vec<Chunk>),
+    LookupChunk(ChunkID),
+    SetChunk(ChunkID, SegmentDescriptor),
+    KnownChunks,
+    CommitIndex,
+    CountChunks,
+    LastModification,
+    ChunkSettings,
+    Archives,
+    WriteChunkSettings(ChunkSettings),
+    WriteArchive(StoredArchive),
+    Touch,
+}
+
+/// The successful result of a [`Request`]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    /// Returned by operations that do not produce a value
+    Done,
+    Key(EncryptedKey),
+    Chunk(Chunk),
+    Location(SegmentDescriptor),
+    Locations(Vec<SegmentDescriptor>),
+    Lookup(Option<SegmentDescriptor>),
+    Chunks(HashSet<ChunkID>),
+    Count(usize),
+    Timestamp(Timestamp),
+    Settings(ChunkSettings),
+    Archives(Vec<StoredArchive>),
+}
+
+/// The body of every response from the server
+///
+/// Stands in for `Result<Response, RemoteError>`, whose serde implementation can not read back
+/// the variant indices `rmp_serde` writes.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Reply {
+    Ok(Response),
+    Err(RemoteError),
+}
+
+impl From<std::result::Result<Response, RemoteError>> for Reply {
+    fn from(result: std::result::Result<Response, RemoteError>) -> Self {
+        match result {
+            Ok(response) => Reply::Ok(response),
+            Err(error) => Reply::Err(error),
+        }
+    }
+}
+
+/// The ways a request can be refused or fail on the server
+#[derive(Error, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RemoteError {
+    #[error("Missing or invalid access token")]
+    Unauthorized,
+    #[error("Operation not permitted on an append only repository: {0}")]
+    AppendOnly(String),
+    #[error("Storage quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Data not found")]
+    DataNotFound,
+    #[error("Malformed request: {0}")]
+    BadRequest(String),
+    #[error("Server side backend error: {0}")]
+    Backend(String),
+}
+
+impl RemoteError {
+    /// The HTTP status code and reason phrase this error is sent with
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            RemoteError::Unauthorized => (401, "Unauthorized"),
+            RemoteError::AppendOnly(_) | RemoteError::QuotaExceeded(_) => (403, "Forbidden"),
+            RemoteError::DataNotFound => (404, "Not Found"),
+            RemoteError::BadRequest(_) => (400, "Bad Request"),
+            RemoteError::Backend(_) => (500, "Internal Server Error"),
+        }
+    }
+}
+
+impl From<BackendError> for RemoteError {
+    fn from(error: BackendError) -> Self {
+        match error {
+            BackendError::AppendOnly(reason) => RemoteError::AppendOnly(reason),
+            BackendError::QuotaExceeded(reason) => RemoteError::QuotaExceeded(reason),
+            BackendError::DataNotFound => RemoteError::DataNotFound,
+            error => RemoteError::Backend(error.to_string()),
+        }
+    }
+}
+
+impl From<RemoteError> for BackendError {
+    fn from(error: RemoteError) -> Self {
+        match error {
+            RemoteError::AppendOnly(reason) => BackendError::AppendOnly(reason),
+            RemoteError::QuotaExceeded(reason) => BackendError::QuotaExceeded(reason),
+            RemoteError::DataNotFound => BackendError::DataNotFound,
+            error => BackendError::ConnectionError(error.to_string()),
+        }
+    }
+}
+
+/// Settings used for connecting to an `asuran-server`
+#[derive(Clone)]
+pub struct RemoteSettings {
+    /// The `host:port` the server is listening on
+    pub address: String,
+    /// The bearer token the server was configured with
+    pub token: String,
+}
+
+impl std::fmt::Debug for RemoteSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteSettings")
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A blocking client for an `asuran-server`
+///
+/// Holds a single keep-alive connection, which is transparently reopened if the server closed
+/// it between requests.
+pub struct Remote {
+    settings: RemoteSettings,
+    connection: Option<BufReader<TcpStream>>,
+    chunk_settings: ChunkSettings,
+}
+
+impl Remote {
+    /// Connects to the server without wrapping the connection in a `BackendHandle`
+    ///
+    /// Fetches the chunk settings of the repository, so a bad address or token is reported
+    /// here rather than on first use.
+    pub fn connect_raw(settings: RemoteSettings) -> Result<Remote> {
+        let mut remote = Remote {
+            settings,
+            connection: None,
+            // Placeholder until the real settings are fetched below
+            chunk_settings: ChunkSettings::lightweight(),
+        };
+        match remote.call(&Request::ChunkSettings)? {
+            Response::Settings(settings) => remote.chunk_settings = settings,
+            _ => return Err(unexpected()),
+        }
+        Ok(remote)
+    }
+
+    /// Connects to the server and wraps the connection in a `BackendHandle`
+    pub fn connect(settings: RemoteSettings, queue_depth: usize) -> Result<BackendHandle<Remote>> {
+        let remote = Remote::connect_raw(settings)?;
+        Ok(BackendHandle::new(queue_depth, move || remote))
+    }
+
+    /// Performs a request, returning the server's response
+    fn call(&mut self, request: &Request) -> Result<Response> {
+        let body = rmp_serde::to_vec(request)?;
+        // A reused connection may have been closed by the server while it sat idle, so give
+        // the request one more chance on a fresh connection
+        let reused = self.connection.is_some();
+        let (status, body) = match self.exchange(&body) {
+            Err(_) if reused => {
+                self.connection = None;
+                self.exchange(&body)
+            }
+            result => result,
+        }
+        .map_err(|error| {
+            self.connection = None;
+            BackendError::ConnectionError(format!("Remote request failed: {error}"))
+        })?;
+        let reply: Reply = rmp_serde::from_slice(&body).map_err(|error| {
+            BackendError::ConnectionError(format!(
+                "Server responded with status {status} and an unreadable body: {error}"
+            ))
+        })?;
+        match reply {
+            Reply::Ok(response) => Ok(response),
+            Reply::Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Sends an encoded request and reads the encoded response
+    fn exchange(&mut self, body: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+        if self.connection.is_none() {
+            let stream = TcpStream::connect(&self.settings.address)?;
+            stream.set_nodelay(true)?;
+            self.connection = Some(BufReader::new(stream));
+        }
+        let connection = self
+            .connection
+            .as_mut()
+            .expect("Connection was just opened");
+        let authorization = format!("Bearer {}", self.settings.token);
+        http::write_message(
+            connection.get_mut(),
+            &format!("POST {RPC_PATH} HTTP/1.1"),
+            &[
+                ("Host", &self.settings.address),
+                ("Authorization", &authorization),
+                ("Content-Type", CONTENT_TYPE),
+            ],
+            body,
+        )?;
+        let response = http::read_message(connection)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let status = response
+            .status()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed status line"))?;
+        if response
+            .header("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+        {
+            self.connection = None;
+        }
+        Ok((status, response.body))
+    }
+}
+
+fn unexpected() -> BackendError {
+    BackendError::ConnectionError("Unexpected response type from server".to_string())
+}
+
+impl std::fmt::Debug for Remote {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Remote")
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SyncManifest for Remote {
+    type Iterator = std::vec::IntoIter<StoredArchive>;
+    fn last_modification(&mut self) -> Result<Timestamp> {
+        match self.call(&Request::LastModification)? {
+            Response::Timestamp(timestamp) => Ok(timestamp),
+            _ => Err(unexpected()),
+        }
+    }
+    fn chunk_settings(&mut self) -> ChunkSettings {
+        self.chunk_settings
+    }
+    fn archive_iterator(&mut self) -> Self::Iterator {
+        match self.call(&Request::Archives) {
+            Ok(Response::Archives(archives)) => archives.into_iter(),
+            Ok(_) => {
+                error!("Unexpected response type while listing archives");
+                Vec::new().into_iter()
+            }
+            Err(e) => {
+                error!("Failed to list archives on remote: {}", e);
+                Vec::new().into_iter()
+            }
+        }
+    }
+    fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
+        self.call(&Request::WriteChunkSettings(settings))?;
+        self.chunk_settings = settings;
+        Ok(())
+    }
+    fn write_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        self.call(&Request::WriteArchive(archive))?;
+        Ok(())
+    }
+    fn touch(&mut self) -> Result<()> {
+        self.call(&Request::Touch)?;
+        Ok(())
+    }
+}
+
+impl SyncIndex for Remote {
+    fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+        match self.call(&Request::LookupChunk(id)) {
+            Ok(Response::Lookup(location)) => location,
+            Ok(_) => {
+                error!("Unexpected response type while looking up chunk {:?}", id);
+                None
+            }
+            Err(e) => {
+                error!("Failed to look up chunk {:?} on remote: {}", id, e);
+                None
+            }
+        }
+    }
+    fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
+        self.call(&Request::SetChunk(id, location))?;
+        Ok(())
+    }
+    fn known_chunks(&mut self) -> HashSet<ChunkID> {
+        match self.call(&Request::KnownChunks) {
+            Ok(Response::Chunks(chunks)) => chunks,
+            Ok(_) => {
+                error!("Unexpected response type while listing chunks");
+                HashSet::new()
+            }
+            Err(e) => {
+                error!("Failed to list chunks on remote: {}", e);
+                HashSet::new()
+            }
+        }
+    }
+    fn commit_index(&mut self) -> Result<()> {
+        self.call(&Request::CommitIndex)?;
+        Ok(())
+    }
+    fn chunk_count(&mut self) -> usize {
+        match self.call(&Request::CountChunks) {
+            Ok(Response::Count(count)) => count,
+            Ok(_) => {
+                error!("Unexpected response type while counting chunks");
+                0
+            }
+            Err(e) => {
+                error!("Failed to count chunks on remote: {}", e);
+                0
+            }
+        }
+    }
+}
+
+impl SyncBackend for Remote {
+    type SyncManifest = Self;
+    type SyncIndex = Self;
+    fn get_index(&mut self) -> &mut Self::SyncIndex {
+        self
+    }
+    fn get_manifest(&mut self) -> &mut Self::SyncManifest {
+        self
+    }
+    fn write_key(&mut self, key: EncryptedKey) -> Result<()> {
+        self.call(&Request::WriteKey(key))?;
+        Ok(())
+    }
+    fn read_key(&mut self) -> Result<EncryptedKey> {
+        match self.call(&Request::ReadKey)? {
+            Response::Key(key) => Ok(key),
+            _ => Err(unexpected()),
+        }
+    }
+    fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        match self.call(&Request::ReadChunk(location))? {
+            Response::Chunk(chunk) => Ok(chunk),
+            _ => Err(unexpected()),
+        }
+    }
+    fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        match self.call(&Request::WriteChunk(chunk))? {
+            Response::Location(location) => Ok(location),
+            _ => Err(unexpected()),
+        }
+    }
+    fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        match self.call(&Request::WriteChunks(chunks))? {
+            Response::Locations(locations) => Ok(locations),
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::server::{Server, ServerPolicy};
+    use super::*;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::backend::{Backend, Index, Manifest};
+    use crate::repository::{ChunkIDSettings, Compression, Encryption, Key, Repository, HMAC};
+
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    const TOKEN: &str = "correct horse battery staple";
+
+    fn settings() -> ChunkSettings {
+        ChunkSettings {
+            compression: Compression::ZStd { level: 1 },
+            encryption: Encryption::new_aes256ctr(),
+            hmac: HMAC::Blake3,
+            id: ChunkIDSettings::default(),
+            chunker: None,
+        }
+    }
+
+    fn pack(data: Vec<u8>, key: &Key) -> Chunk {
+        let settings = settings();
+        Chunk::pack(
+            data,
+            settings.compression,
+            settings.encryption,
+            settings.hmac,
+            key,
+        )
+    }
+
+    /// Incompressible data, so chunk sizes are predictable
+    fn random_bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|_| rand::random()).collect()
+    }
+
+    /// Starts a server around a fresh `Mem` backend, returning the address it listens on
+    fn start(key: &Key, policy: ServerPolicy) -> String {
+        let backend = Mem::new(settings(), key.clone(), 4);
+        let server = Arc::new(Server::new(backend, TOKEN.to_string(), policy, 0));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve(&listener));
+        address
+    }
+
+    fn connect(address: &str, token: &str) -> Result<BackendHandle<Remote>> {
+        Remote::connect(
+            RemoteSettings {
+                address: address.to_string(),
+                token: token.to_string(),
+            },
+            4,
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        smol::run(async {
+            let key = Key::random(32);
+            let address = start(&key, ServerPolicy::default());
+            let remote = connect(&address, TOKEN).unwrap();
+            // The IV in the settings is random, so only compare the deterministic parts
+            let remote_settings = remote.get_manifest().chunk_settings().await;
+            assert_eq!(remote_settings.compression, settings().compression);
+            assert_eq!(remote_settings.hmac, settings().hmac);
+
+            let encrypted_key =
+                EncryptedKey::encrypt_defaults(&key, Encryption::new_aes256ctr(), b"password");
+            remote.write_key(&encrypted_key).await.unwrap();
+            let read_key = remote.read_key().await.unwrap();
+            assert_eq!(read_key.decrypt(b"password").unwrap(), key);
+
+            let mut repo = Repository::with(remote.clone(), settings(), key, 2).unwrap();
+            let data = vec![42_u8; 8192];
+            let (id, _) = repo.write_chunk(data.clone()).await.unwrap();
+            repo.commit_index().await;
+            assert_eq!(repo.read_chunk(id).await.unwrap(), data);
+            assert!(remote.get_index().contains_chunk(id).await);
+            assert_eq!(remote.get_index().count_chunk().await, 1);
+
+            let archive = StoredArchive::dummy_archive();
+            remote
+                .get_manifest()
+                .write_archive(archive.clone())
+                .await
+                .unwrap();
+            let archives: Vec<_> = remote.get_manifest().archive_iterator().await.collect();
+            assert_eq!(archives, vec![archive]);
+            repo.close().await;
+        });
+    }
+
+    #[test]
+    fn bad_token() {
+        smol::run(async {
+            let address = start(&Key::random(32), ServerPolicy::default());
+            assert!(connect(&address, "wrong").is_err());
+        });
+    }
+
+    #[test]
+    fn append_only() {
+        smol::run(async {
+            let key = Key::random(32);
+            let policy = ServerPolicy {
+                append_only: true,
+                quota: None,
+            };
+            let address = start(&key, policy);
+            let mut remote = connect(&address, TOKEN).unwrap();
+
+            let encrypted_key =
+                EncryptedKey::encrypt_defaults(&key, Encryption::new_aes256ctr(), b"password");
+            // The first key may be written, but never replaced
+            remote.write_key(&encrypted_key).await.unwrap();
+            assert!(matches!(
+                remote.write_key(&encrypted_key).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            assert!(matches!(
+                remote.get_manifest().write_chunk_settings(settings()).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+
+            let chunk = pack(vec![1, 2, 3], &key);
+            let id = chunk.get_id();
+            let first = remote.write_chunk(chunk.clone()).await.unwrap();
+            let second = remote.write_chunk(chunk).await.unwrap();
+            let mut index = remote.get_index();
+            index.set_chunk(id, first).await.unwrap();
+            // Setting the same location again is harmless, moving it is not
+            index.set_chunk(id, first).await.unwrap();
+            assert!(matches!(
+                index.set_chunk(id, second).await,
+                Err(BackendError::AppendOnly(_))
+            ));
+            remote.close().await;
+        });
+    }
+
+    #[test]
+    fn quota() {
+        smol::run(async {
+            let key = Key::random(32);
+            let policy = ServerPolicy {
+                append_only: false,
+                quota: Some(4096),
+            };
+            let address = start(&key, policy);
+            let mut remote = connect(&address, TOKEN).unwrap();
+            let small = pack(random_bytes(1024), &key);
+            remote.write_chunk(small).await.unwrap();
+            let large = pack(random_bytes(4096), &key);
+            assert!(matches!(
+                remote.write_chunks(vec![large]).await,
+                Err(BackendError::QuotaExceeded(_))
+            ));
+            remote.close().await;
+        });
+    }
+}
@@ -0,0 +1,200 @@
+//! Just enough of HTTP/1.1 to carry the remote protocol
+//!
+//! Every message must carry a `Content-Length`, chunked transfer encoding is refused, and
+//! connections are kept alive between requests.
+use std::io::{self, BufRead, Read, Write};
+
+/// The maximum combined size of the start line and headers of a message
+pub const MAX_HEAD: usize = 16 * 1024;
+/// The maximum size of a message body
+///
+/// Large enough to hold a full batch of chunks, small enough that a hostile peer can not
+/// make us allocate arbitrary amounts of memory.
+pub const MAX_BODY: u64 = 256 * 1024 * 1024;
+
+/// A parsed request or response
+#[derive(Debug)]
+pub struct Message {
+    /// The request line or status line, without the trailing CRLF
+    pub start_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    /// Returns the value of the first header with the given name, compared case
+    /// insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Splits a request line into its method and path
+    pub fn request_target(&self) -> Option<(&str, &str)> {
+        let mut parts = self.start_line.split_whitespace();
+        let method = parts.next()?;
+        let path = parts.next()?;
+        if parts.next()?.starts_with("HTTP/1.") {
+            Some((method, path))
+        } else {
+            None
+        }
+    }
+
+    /// Parses the status code out of a status line
+    pub fn status(&self) -> Option<u16> {
+        let mut parts = self.start_line.split_whitespace();
+        if !parts.next()?.starts_with("HTTP/1.") {
+            return None;
+        }
+        parts.next()?.parse().ok()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads one message from the stream
+///
+/// Returns `Ok(None)` if the peer cleanly closed the connection before sending anything.
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Message>> {
+    let mut head_len = 0;
+    let mut lines = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        let read = reader
+            .by_ref()
+            .take((MAX_HEAD - head_len + 1) as u64)
+            .read_until(b'\n', &mut line)?;
+        if read == 0 {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head_len += read;
+        if head_len > MAX_HEAD {
+            return Err(invalid("Message head too large"));
+        }
+        if !line.ends_with(b"\n") {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+        if line.is_empty() {
+            // Tolerate stray blank lines between messages, as RFC 7230 asks servers to
+            if lines.is_empty() {
+                continue;
+            }
+            break;
+        }
+        let line = String::from_utf8(line).map_err(|_| invalid("Non utf-8 message head"))?;
+        lines.push(line);
+    }
+
+    let mut lines = lines.into_iter();
+    let start_line = lines
+        .next()
+        .expect("Loop only exits with at least one line");
+    let mut headers = Vec::new();
+    for line in lines {
+        let colon = line.find(':').ok_or_else(|| invalid("Malformed header"))?;
+        let (name, value) = line.split_at(colon);
+        headers.push((name.trim().to_string(), value[1..].trim().to_string()));
+    }
+    let mut message = Message {
+        start_line,
+        headers,
+        body: Vec::new(),
+    };
+
+    if message.header("Transfer-Encoding").is_some() {
+        return Err(invalid("Transfer encodings are not supported"));
+    }
+    let length: u64 = match message.header("Content-Length") {
+        Some(length) => length
+            .parse()
+            .map_err(|_| invalid("Malformed Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err(invalid("Message body too large"));
+    }
+    reader
+        .by_ref()
+        .take(length)
+        .read_to_end(&mut message.body)?;
+    if message.body.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(Some(message))
+}
+
+/// Writes one message to the stream, adding the `Content-Length` header
+pub fn write_message(
+    writer: &mut impl Write,
+    start_line: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> io::Result<()> {
+    let mut head = Vec::new();
+    write!(head, "{start_line}\r\n")?;
+    for (name, value) in headers {
+        write!(head, "{name}: {value}\r\n")?;
+    }
+    write!(head, "Content-Length: {}\r\n\r\n", body.len())?;
+    writer.write_all(&head)?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let mut buffer = Vec::new();
+        write_message(
+            &mut buffer,
+            "POST /v1/rpc HTTP/1.1",
+            &[("Authorization", "Bearer hunter2")],
+            b"body",
+        )
+        .unwrap();
+        write_message(&mut buffer, "HTTP/1.1 200 OK", &[], b"").unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        let request = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(request.request_target(), Some(("POST", "/v1/rpc")));
+        assert_eq!(request.header("authorization"), Some("Bearer hunter2"));
+        assert_eq!(request.body, b"body");
+        let response = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(response.status(), Some(200));
+        assert!(response.body.is_empty());
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_oversized() {
+        let head = format!(
+            "GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD)
+        );
+        assert!(read_message(&mut Cursor::new(head)).is_err());
+        let head = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert!(read_message(&mut Cursor::new(head)).is_err());
+        let head = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort";
+        assert!(read_message(&mut Cursor::new(head)).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! The server side of the remote protocol
+//!
+//! Wraps any local backend and answers requests from `Remote` clients, enforcing the
+//! configured [`ServerPolicy`] before anything reaches the wrapped backend.
+use super::http;
+use super::{RemoteError, Reply, Request, Response, CONTENT_TYPE, RPC_PATH};
+use crate::repository::backend::{BackendClone, Index, Manifest};
+
+use tracing::{debug, warn};
+
+use std::io::{self, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How long an idle client connection is kept open before the server closes it
+pub const IDLE_TIMEOUT: Duration = Duration::from_mins(5);
+
+/// Restrictions the server places on its clients
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerPolicy {
+    /// Refuse operations that would destroy or overwrite existing data
+    ///
+    /// Clients may still add chunks and archives, and set the key of a repository that does
+    /// not have one yet, but may not replace the key, change the chunk settings, or point an
+    /// indexed chunk at a different location.
+    pub append_only: bool,
+    /// The maximum number of bytes of chunk data the repository may hold
+    pub quota: Option<u64>,
+}
+
+/// Serves a backend to remote clients
+pub struct Server<B> {
+    backend: B,
+    token: String,
+    policy: ServerPolicy,
+    used: AtomicU64,
+}
+
+impl<B: BackendClone> Server<B> {
+    /// Creates a new server around `backend`
+    ///
+    /// Clients must present `token` as a bearer token. `used_bytes` is the amount of storage
+    /// the repository already takes up, and counts against the quota.
+    pub fn new(backend: B, token: String, policy: ServerPolicy, used_bytes: u64) -> Server<B> {
+        Server {
+            backend,
+            token,
+            policy,
+            used: AtomicU64::new(used_bytes),
+        }
+    }
+
+    /// Returns the number of bytes currently counted against the quota
+    pub fn used_bytes(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Accepts connections on `listener` until it fails, serving each on its own thread
+    ///
+    /// Requests are run to completion with `smol::block_on`, so the backend must be driven
+    /// by executor threads running elsewhere.
+    pub fn serve(self: Arc<Self>, listener: &TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = Arc::clone(&self);
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = server.serve_connection(stream) {
+                    debug!("Connection from {:?} closed with error: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Answers requests on a single connection until the client closes it
+    fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        while let Some(message) = http::read_message(&mut reader)? {
+            let (code, reason) = match message.request_target() {
+                Some(("POST", RPC_PATH)) => {
+                    let reply = Reply::from(self.rpc(&message));
+                    let (code, reason) = match &reply {
+                        Reply::Ok(_) => (200, "OK"),
+                        Reply::Err(e) => e.status(),
+                    };
+                    let body = rmp_serde::to_vec(&reply).map_err(io::Error::other)?;
+                    let mut headers = vec![("Content-Type", CONTENT_TYPE)];
+                    if let Reply::Err(RemoteError::Unauthorized) = reply {
+                        headers.push(("WWW-Authenticate", "Bearer"));
+                    }
+                    let status_line = format!("HTTP/1.1 {code} {reason}");
+                    http::write_message(&mut writer, &status_line, &headers, &body)?;
+                    continue;
+                }
+                Some((_, RPC_PATH)) => (405, "Method Not Allowed"),
+                Some(_) => (404, "Not Found"),
+                None => (400, "Bad Request"),
+            };
+            // Anything that is not a well formed call is answered and then hung up on
+            let mut headers = vec![("Connection", "close")];
+            if code == 405 {
+                headers.push(("Allow", "POST"));
+            }
+            let status_line = format!("HTTP/1.1 {code} {reason}");
+            http::write_message(&mut writer, &status_line, &headers, &[])?;
+            break;
+        }
+        Ok(())
+    }
+
+    /// Authenticates and decodes a call, then performs it
+    fn rpc(&self, message: &http::Message) -> Result<Response, RemoteError> {
+        let token = message
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or("");
+        // Compare in constant time, so the token can not be guessed byte by byte
+        let difference = token.len() ^ self.token.len();
+        let difference = token
+            .bytes()
+            .zip(self.token.bytes())
+            .fold(difference, |acc, (x, y)| acc | usize::from(x ^ y));
+        if difference != 0 || self.token.is_empty() {
+            warn!("Refused request with a missing or invalid token");
+            return Err(RemoteError::Unauthorized);
+        }
+        let request: Request = rmp_serde::from_slice(&message.body)
+            .map_err(|e| RemoteError::BadRequest(e.to_string()))?;
+        smol::block_on(self.handle(request))
+    }
+
+    /// Performs a single request against the backend, applying the server's policy
+    pub async fn handle(&self, request: Request) -> Result<Response, RemoteError> {
+        let mut backend = self.backend.clone();
+        let response = match request {
+            Request::ReadKey => Response::Key(backend.read_key().await?),
+            Request::WriteKey(key) => {
+                if self.policy.append_only && backend.read_key().await.is_ok() {
+                    return Err(RemoteError::AppendOnly(
+                        "The repository key can not be replaced".to_string(),
+                    ));
+                }
+                backend.write_key(&key).await?;
+                Response::Done
+            }
+            Request::ReadChunk(location) => Response::Chunk(backend.read_chunk(location).await?),
+            Request::WriteChunk(chunk) => {
+                let size = chunk.len() as u64;
+                self.reserve(size)?;
+                match backend.write_chunk(chunk).await {
+                    Ok(location) => Response::Location(location),
+                    Err(e) => {
+                        self.release(size);
+                        return Err(e.into());
+                    }
+                }
+            }
+            Request::WriteChunks(chunks) => {
+                let size = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+                self.reserve(size)?;
+                match backend.write_chunks(chunks).await {
+                    Ok(locations) => Response::Locations(locations),
+                    Err(e) => {
+                        self.release(size);
+                        return Err(e.into());
+                    }
+                }
+            }
+            Request::LookupChunk(id) => {
+                Response::Lookup(backend.get_index().lookup_chunk(id).await)
+            }
+            Request::SetChunk(id, location) => {
+                let mut index = backend.get_index();
+                if self.policy.append_only {
+                    if let Some(existing) = index.lookup_chunk(id).await {
+                        if existing != location {
+                            return Err(RemoteError::AppendOnly(format!(
+                                "Chunk {id:?} is already stored elsewhere"
+                            )));
+                        }
+                    }
+                }
+                index.set_chunk(id, location).await?;
+                Response::Done
+            }
+            Request::KnownChunks => Response::Chunks(backend.get_index().known_chunks().await),
+            Request::CommitIndex => {
+                backend.get_index().commit_index().await?;
+                Response::Done
+            }
+            Request::CountChunks => Response::Count(backend.get_index().count_chunk().await),
+            Request::LastModification => {
+                Response::Timestamp(backend.get_manifest().last_modification().await?)
+            }
+            Request::ChunkSettings => {
+                Response::Settings(backend.get_manifest().chunk_settings().await)
+            }
+            Request::Archives => {
+                Response::Archives(backend.get_manifest().archive_iterator().await.collect())
+            }
+            Request::WriteChunkSettings(settings) => {
+                if self.policy.append_only {
+                    return Err(RemoteError::AppendOnly(
+                        "The chunk settings can not be changed".to_string(),
+                    ));
+                }
+                backend
+                    .get_manifest()
+                    .write_chunk_settings(settings)
+                    .await?;
+                Response::Done
+            }
+            Request::WriteArchive(archive) => {
+                backend.get_manifest().write_archive(archive).await?;
+                Response::Done
+            }
+            Request::Touch => {
+                backend.get_manifest().touch().await?;
+                Response::Done
+            }
+        };
+        Ok(response)
+    }
+
+    /// Counts `bytes` against the quota, failing if that would exceed it
+    fn reserve(&self, bytes: u64) -> Result<(), RemoteError> {
+        if let Some(quota) = self.policy.quota {
+            self.used
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                    used.checked_add(bytes).filter(|total| *total <= quota)
+                })
+                .map_err(|used| {
+                    RemoteError::QuotaExceeded(format!(
+                        "{used} of {quota} bytes in use, {bytes} more requested"
+                    ))
+                })?;
+        } else {
+            self.used.fetch_add(bytes, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Returns bytes reserved for a write that failed
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+impl<B> std::fmt::Debug for Server<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Server")
+            .field("policy", &self.policy)
+            .field("used", &self.used)
+            .finish_non_exhaustive()
+    }
+}
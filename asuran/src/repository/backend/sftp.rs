@@ -2,18 +2,21 @@
 //! Repository
 use super::{BackendError, Result, SegmentDescriptor};
 use crate::repository::backend::common::sync_backend::{BackendHandle, SyncBackend, SyncManifest};
-use crate::repository::{Chunk, ChunkSettings, EncryptedKey, Key};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey, Key};
 
 use rmp_serde as rmps;
-use ssh2::{Session, Sftp};
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp};
 
 use std::fmt::Debug;
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::Write;
 use std::net::TcpStream;
 use std::path::PathBuf;
 use std::rc::Rc;
 
 pub mod index;
 pub mod manifest;
+pub mod pool;
 pub mod segment;
 pub mod util;
 
@@ -22,6 +25,11 @@ use self::manifest::SFTPManifest;
 use self::segment::SFTPSegmentHandler;
 use self::util::LockedFile;
 
+/// The soft size limit of each segment, in bytes
+const SIZE_LIMIT: u64 = 2_000_000_000;
+/// The number of segments stored in each data directory
+const SEGMENTS_PER_DIRECTORY: u64 = 100;
+
 // Allow our result type to accept the ssh2 errors easily
 // Maps to `BackendError::ConnectionError(error.to_string())`
 impl From<ssh2::Error> for BackendError {
@@ -41,12 +49,36 @@ pub struct SFTPSettings {
     pub username: String,
     /// Password to connect with
     ///
-    /// Optional, will attempt to use ssh-agent if not provided.
+    /// Optional, only tried if authenticating with the private key or ssh-agent fails.
     pub password: Option<String>,
+    /// Private key file to authenticate with
+    ///
+    /// Optional, will attempt to use ssh-agent if not provided.
+    pub key_file: Option<PathBuf>,
+    /// Passphrase the private key file is encrypted with, if it is
+    pub key_passphrase: Option<String>,
+    /// OpenSSH known hosts file to verify the key of the server against
+    ///
+    /// Optional, the key of the server is not checked if not provided.
+    pub known_hosts: Option<PathBuf>,
+    /// What to do with servers that are not in the known hosts file
+    pub host_key_policy: HostKeyPolicy,
     /// Path of the repository on the server
     pub path: String,
 }
 
+/// How to treat an SFTP server whose key is not in the known hosts file
+///
+/// A server whose key differs from the one in the known hosts file is always refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum HostKeyPolicy {
+    /// Refuse to connect
+    Strict,
+    /// Add the key of the server to the known hosts file, and connect
+    #[default]
+    AcceptNew,
+}
+
 #[derive(Clone)]
 pub enum SFTPConnection {
     Connected {
@@ -80,23 +112,8 @@ impl SFTPConnection {
             let mut session = Session::new()?;
             session.set_tcp_stream(tcp);
             session.handshake()?;
-            // Attempt to authenticate with the ssh agent
-            let result = session.userauth_agent(&self.settings().username);
-
-            if result.is_err() {
-                // Grab the password
-                let password = self.settings().password.as_ref().ok_or_else(|| {
-                    BackendError::ConnectionError(
-                        format!(
-                            "SFTP connection using ssh agent to {}@{}:{} failed, and no password was provided.",
-                            self.settings().username,
-                            hostname,
-                            port)
-                    )
-                })?;
-                // Attempt connecting with username/password
-                session.userauth_password(&self.settings().username, password)?;
-            }
+            self.verify_host_key(&session, hostname, port)?;
+            self.authenticate(&session, hostname, port)?;
             // If we are here and not authenticated, something is horribly wrong
             assert!(session.authenticated());
 
@@ -117,6 +134,100 @@ impl SFTPConnection {
             Ok(())
         }
     }
+    /// Checks the key presented by the server against the known hosts file, if one was set
+    ///
+    /// Servers that are not in the file yet are handled according to the `HostKeyPolicy`. New
+    /// keys are appended to the file, leaving the existing entries untouched.
+    fn verify_host_key(&self, session: &Session, hostname: &str, port: u16) -> Result<()> {
+        let Some(path) = &self.settings().known_hosts else {
+            return Ok(());
+        };
+        let (key, key_type) = session.host_key().ok_or_else(|| {
+            BackendError::ConnectionError(format!(
+                "SFTP server {hostname}:{port} did not present a host key"
+            ))
+        })?;
+        let mut known_hosts = session.known_hosts()?;
+        // A known hosts file that does not exist yet has no hosts in it
+        if path.exists() {
+            known_hosts.read_file(path, KnownHostFileKind::OpenSSH)?;
+        }
+        match known_hosts.check_port(hostname, port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(BackendError::ConnectionError(format!(
+                "The host key of SFTP server {hostname}:{port} does not match the one in {}, it \
+                 may be impersonating the server",
+                path.display()
+            ))),
+            CheckResult::NotFound if self.settings().host_key_policy == HostKeyPolicy::Strict => {
+                Err(BackendError::ConnectionError(format!(
+                    "The host key of SFTP server {hostname}:{port} is not in {}",
+                    path.display()
+                )))
+            }
+            CheckResult::NotFound => {
+                // Known hosts files only name the port when it is not the default one
+                let host = if port == 22 {
+                    hostname.to_string()
+                } else {
+                    format!("[{hostname}]:{port}")
+                };
+                known_hosts.add(&host, key, "", key_type.into())?;
+                let entry = known_hosts
+                    .hosts()?
+                    .into_iter()
+                    .find(|entry| entry.name() == Some(host.as_str()))
+                    .ok_or_else(|| {
+                        BackendError::Unknown("Added host was not in the known hosts".to_string())
+                    })?;
+                let line = known_hosts.write_string(&entry, KnownHostFileKind::OpenSSH)?;
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)?;
+                }
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", line.trim_end())?;
+                Ok(())
+            }
+            CheckResult::Failure => Err(BackendError::ConnectionError(format!(
+                "Failed to check the host key of SFTP server {hostname}:{port} against {}",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Authenticates the session, with the private key file if one was set and ssh-agent
+    /// otherwise, falling back to the password
+    fn authenticate(&self, session: &Session, hostname: &str, port: u16) -> Result<()> {
+        let settings = self.settings();
+        let result = if let Some(key_file) = &settings.key_file {
+            session.userauth_pubkey_file(
+                &settings.username,
+                None,
+                key_file,
+                settings.key_passphrase.as_deref(),
+            )
+        } else {
+            session.userauth_agent(&settings.username)
+        };
+        if let Err(error) = result {
+            let method = if settings.key_file.is_some() {
+                "private key"
+            } else {
+                "ssh agent"
+            };
+            // Grab the password
+            let password = settings.password.as_ref().ok_or_else(|| {
+                BackendError::ConnectionError(format!(
+                    "SFTP connection using {} to {}@{}:{} failed, and no password was provided: {}",
+                    method, settings.username, hostname, port, error
+                ))
+            })?;
+            // Attempt connecting with username/password
+            session.userauth_password(&settings.username, password)?;
+        }
+        Ok(())
+    }
+
     /// Connects to the backend if needed and converts to `SFTPConnection::Connected`, otherwise
     /// returns `self` unaltered
     pub fn with_connection(mut self) -> Result<Self> {
@@ -194,12 +305,10 @@ impl SFTP {
         let mut manifest = SFTPManifest::connect(connection.clone(), key, chunk_settings)?;
         let index = SFTPIndex::connect(connection.clone())?;
         let chunk_settings = manifest.chunk_settings();
-        let size_limit = 2_000_000_000;
-        let segments_per_directory = 100;
         let segment_handler = SFTPSegmentHandler::connect(
             connection.clone(),
-            size_limit,
-            segments_per_directory,
+            SIZE_LIMIT,
+            SEGMENTS_PER_DIRECTORY,
             chunk_settings,
             key.clone(),
         )?;
@@ -297,6 +406,21 @@ impl SyncBackend for SFTP {
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         self.segment_handler.write_chunk(chunk)
     }
+    fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        self.segment_handler.write_chunks(chunks)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.segment_handler.flush()
+    }
+    fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        self.segment_handler.segment_ids()
+    }
+    fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        self.segment_handler.segment_descriptors(segment_id)
+    }
 }
 
 #[cfg(test)]
@@ -327,6 +451,10 @@ mod tests {
             username,
             port: Some(port),
             password: Some(password),
+            key_file: None,
+            key_passphrase: None,
+            known_hosts: None,
+            host_key_policy: HostKeyPolicy::default(),
             path,
         }
     }
@@ -427,6 +555,10 @@ mod tests {
             username,
             port: Some(port),
             password: None,
+            key_file: None,
+            key_passphrase: None,
+            known_hosts: None,
+            host_key_policy: HostKeyPolicy::default(),
             path: "OhNo!".to_string(),
         };
 
@@ -437,6 +569,34 @@ mod tests {
         assert!(matches!(result, Err(BackendError::ConnectionError(_))));
     }
 
+    // Servers missing from the known hosts file are refused when strict, and added otherwise
+    #[test]
+    fn known_hosts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let known_hosts = tempdir.path().join("known_hosts");
+        let settings = |host_key_policy| SFTPSettings {
+            known_hosts: Some(known_hosts.clone()),
+            host_key_policy,
+            ..get_settings("asuran/known_hosts".to_string())
+        };
+        let connection: SFTPConnection = settings(HostKeyPolicy::Strict).into();
+        assert!(matches!(
+            connection.with_connection(),
+            Err(BackendError::ConnectionError(_))
+        ));
+        assert!(!known_hosts.exists());
+
+        let connection: SFTPConnection = settings(HostKeyPolicy::AcceptNew).into();
+        connection.with_connection().unwrap();
+        let contents = std::fs::read_to_string(&known_hosts).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+
+        // Now that it is known, strict checking lets it through, and nothing new is added
+        let connection: SFTPConnection = settings(HostKeyPolicy::Strict).into();
+        connection.with_connection().unwrap();
+        assert_eq!(std::fs::read_to_string(&known_hosts).unwrap(), contents);
+    }
+
     // A not connected connection should return none, and a connected one should return Some
     #[test]
     fn get_session() {
@@ -460,6 +620,10 @@ mod tests {
             username,
             port: Some(port),
             password: Some(password),
+            key_file: None,
+            key_passphrase: None,
+            known_hosts: None,
+            host_key_policy: HostKeyPolicy::default(),
             path: "yes".to_string(),
         };
 
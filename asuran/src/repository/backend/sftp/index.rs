@@ -152,7 +152,7 @@ impl SyncIndex for SFTPIndex {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::prelude::sftp::SFTPSettings;
+    use crate::prelude::sftp::{HostKeyPolicy, SFTPSettings};
     use std::env;
 
     fn get_settings(path: String) -> SFTPSettings {
@@ -176,6 +176,10 @@ mod tests {
             username,
             port: Some(port),
             password: Some(password),
+            key_file: None,
+            key_passphrase: None,
+            known_hosts: None,
+            host_key_policy: HostKeyPolicy::default(),
             path,
         }
     }
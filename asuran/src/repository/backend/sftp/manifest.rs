@@ -1,20 +1,98 @@
 use super::util::LockedFile;
 use super::SFTPConnection;
 use crate::repository::backend::common::sync_backend::SyncManifest;
-use crate::repository::backend::common::{ManifestID, ManifestTransaction};
+use crate::repository::backend::common::{
+    archives_from_transactions, may_be_missing, ManifestID, ManifestTransaction,
+};
 use crate::repository::backend::BackendError;
 use crate::repository::{ChunkSettings, Key};
+use crate::time::Timestamp;
 use crate::{manifest::StoredArchive, repository::backend::Result};
 
-use chrono::prelude::*;
 use petgraph::Graph;
 use rmp_serde as rmps;
-use ssh2::FileStat;
+use ssh2::{FileStat, Sftp};
 
 use std::collections::{HashMap, HashSet};
-use std::io::{Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::thread;
+
+/// Maximum number of additional connections to open while fetching transaction files
+const PREFETCH_CONNECTIONS: usize = 4;
+/// Minimum number of transaction files that justify opening an additional connection
+const FILES_PER_CONNECTION: usize = 4;
+
+/// Reads all the transactions out of a single transaction file
+///
+/// The file is read in full before decoding, as decoding straight off of the remote file
+/// would cost a round trip for every small read the decoder makes.
+fn read_transactions(sftp: &Sftp, path: &Path) -> Result<Vec<ManifestTransaction>> {
+    let mut buffer = Vec::new();
+    sftp.open(path)?.read_to_end(&mut buffer)?;
+    let mut reader = &buffer[..];
+    let mut transactions = Vec::new();
+    // Keep deserializing transactions until we hit an error
+    while let Ok(tx) = rmps::decode::from_read::<_, ManifestTransaction>(&mut reader) {
+        transactions.push(tx);
+    }
+    Ok(transactions)
+}
+
+/// Reads all the transactions out of the given transaction files
+///
+/// On high latency links, the time spent loading the manifest is dominated by round trips, so
+/// when there are enough files to make it worthwhile, they are split up and fetched in parallel
+/// over additional connections. If an additional connection can not be established, its share
+/// of the files is read over the existing connection instead.
+fn fetch_transactions(
+    connection: &SFTPConnection,
+    paths: &[PathBuf],
+) -> Result<Vec<ManifestTransaction>> {
+    let sftp = connection
+        .sftp()
+        .expect("Connected successful, but no sftp session?");
+    let shares = (paths.len() / FILES_PER_CONNECTION).clamp(1, PREFETCH_CONNECTIONS + 1);
+    let share_size = paths.len().div_ceil(shares);
+    let mut shares = paths.chunks(share_size.max(1));
+    let local_share = shares.next().unwrap_or(&[]);
+    // Start the fetches for the other shares on their own connections
+    let workers = shares
+        .map(|share| {
+            let settings = connection.settings().clone();
+            let paths = share.to_vec();
+            let handle = thread::spawn(move || -> Option<Result<Vec<ManifestTransaction>>> {
+                let connection = SFTPConnection::from(settings).with_connection().ok()?;
+                let sftp = connection.sftp()?;
+                let mut transactions = Vec::new();
+                for path in &paths {
+                    match read_transactions(&sftp, path) {
+                        Ok(txs) => transactions.extend(txs),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                Some(Ok(transactions))
+            });
+            (share, handle)
+        })
+        .collect::<Vec<_>>();
+    // Read our own share while the others are in flight
+    let mut transactions = Vec::new();
+    for path in local_share {
+        transactions.extend(read_transactions(&sftp, path)?);
+    }
+    for (share, handle) in workers {
+        if let Ok(Some(result)) = handle.join() {
+            transactions.extend(result?);
+        } else {
+            for path in share {
+                transactions.extend(read_transactions(&sftp, path)?);
+            }
+        }
+    }
+    Ok(transactions)
+}
 
 #[derive(Debug)]
 pub struct SFTPManifest {
@@ -83,15 +161,14 @@ impl SFTPManifest {
         items.sort_by(|a, b| a.0.cmp(&b.0));
 
         // Collect all known transactions
-        let mut known_entries = HashMap::new();
-        for (_, path) in &items {
-            // Open the file
-            let mut file = sftp.open(path)?;
-            // Keep deserializing transactions until we hit an error
-            while let Ok(tx) = rmps::decode::from_read::<_, ManifestTransaction>(&mut file) {
-                known_entries.insert(tx.tag(), tx);
-            }
-        }
+        let paths = items
+            .iter()
+            .map(|(_, path)| path.clone())
+            .collect::<Vec<_>>();
+        let known_entries = fetch_transactions(&connection, &paths)?
+            .into_iter()
+            .map(|tx| (tx.tag(), tx))
+            .collect::<HashMap<_, _>>();
 
         let mut file = None;
         // Attempt to find an unlocked file
@@ -185,8 +262,10 @@ impl SFTPManifest {
         for tx in self.known_entries.values() {
             let id = index_map.get(&tx.tag()).unwrap();
             for other_tx in tx.previous_heads() {
-                let other_id = index_map.get(&other_tx).unwrap();
-                graph.update_edge(*id, *other_id, ());
+                // Transactions replaced by a checkpoint may no longer be around
+                if let Some(other_id) = index_map.get(other_tx) {
+                    graph.update_edge(*id, *other_id, ());
+                }
             }
         }
         // reverse all the nodes, so they now point from old to new
@@ -216,6 +295,13 @@ impl SFTPManifest {
             if tx.verify(&self.key) {
                 self.verified_memo_pad.insert(id);
                 for parent in tx.previous_heads() {
+                    if !self.known_entries.contains_key(parent) {
+                        // Only checkpoints may refer to transactions that have been removed
+                        if may_be_missing(&tx, *parent) {
+                            continue;
+                        }
+                        return false;
+                    }
                     if !self.verify_tx(*parent) {
                         return false;
                     }
@@ -230,9 +316,9 @@ impl SFTPManifest {
 
 impl SyncManifest for SFTPManifest {
     type Iterator = std::vec::IntoIter<StoredArchive>;
-    fn last_modification(&mut self) -> Result<chrono::DateTime<chrono::FixedOffset>> {
+    fn last_modification(&mut self) -> Result<Timestamp> {
         if self.heads.is_empty() {
-            Ok(Local::now().with_timezone(Local::now().offset()))
+            Ok(Timestamp::now())
         } else {
             let first_head = self
                 .known_entries
@@ -254,14 +340,7 @@ impl SyncManifest for SFTPManifest {
         self.chunk_settings
     }
     fn archive_iterator(&mut self) -> Self::Iterator {
-        let mut items = self.known_entries.values().cloned().collect::<Vec<_>>();
-        items.sort_by(|a, b| a.timestamp().cmp(&b.timestamp()));
-        items.reverse();
-        items
-            .into_iter()
-            .map(StoredArchive::from)
-            .collect::<Vec<_>>()
-            .into_iter()
+        archives_from_transactions(self.known_entries.values()).into_iter()
     }
     fn write_chunk_settings(&mut self, chunk_settings: ChunkSettings) -> Result<()> {
         let sftp = self.connection.sftp().unwrap();
@@ -295,6 +374,10 @@ impl SyncManifest for SFTPManifest {
             archive.id(),
             archive.timestamp(),
             archive.name(),
+            archive.metadata().clone(),
+            archive.integrity().cloned(),
+            archive.signature().cloned(),
+            archive.duration(),
             self.chunk_settings.hmac,
             &self.key,
         );
@@ -318,8 +401,8 @@ impl SyncManifest for SFTPManifest {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::prelude::{Compression, Encryption, HMAC};
-    use crate::repository::backend::sftp::SFTPSettings;
+    use crate::prelude::{ChunkIDSettings, Compression, Encryption, HMAC};
+    use crate::repository::backend::sftp::{HostKeyPolicy, SFTPSettings};
     use std::collections::HashSet;
     use std::env;
 
@@ -344,6 +427,10 @@ mod tests {
             username,
             port: Some(port),
             password: Some(password),
+            key_file: None,
+            key_passphrase: None,
+            known_hosts: None,
+            host_key_policy: HostKeyPolicy::default(),
             path,
         }
     }
@@ -387,6 +474,8 @@ mod tests {
             compression: Compression::ZStd { level: 1 },
             encryption: Encryption::new_aes256ctr(),
             hmac: HMAC::Blake3,
+            id: ChunkIDSettings::default(),
+            chunker: None,
         };
         manifest
             .write_chunk_settings(settings)
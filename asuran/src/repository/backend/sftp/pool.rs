@@ -0,0 +1,415 @@
+//! Spreads the chunk reads and writes of an `SFTP` backend across a pool of sessions
+//!
+//! Every operation on an SFTP session waits out a round trip to the server, so on high latency
+//! links a single session spends most of its time idle. `SFTPPool` opens a number of additional
+//! sessions, each with a segment handler of its own running on its own thread, and hands the
+//! chunk reads and writes out to them in turn, so several are in flight at once. The index,
+//! manifest, and key stay with the primary `SFTP` backend.
+//!
+//! Each session writes to segments of its own, whose headers are only written out when they are
+//! flushed, so chunks in a segment a session has written to are always read through that
+//! session.
+use super::segment::SFTPSegmentHandler;
+use super::{SFTPConnection, SFTPSettings, SEGMENTS_PER_DIRECTORY, SFTP, SIZE_LIMIT};
+use crate::repository::backend::common::sync_backend::{BackendHandle, SyncBackend, SyncManifest};
+use crate::repository::backend::{
+    backend_to_object, common, Backend, BackendError, BackendObject, CheckReport, CheckpointStats,
+    Chunk, ChunkDescriptors, ChunkID, CompactionStats, EncryptedKey, HashMap, HashSet,
+    ManifestHead, Result, SegmentDescriptor,
+};
+use crate::repository::{ChunkSettings, Key};
+
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::channel::oneshot;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use smol::block_on;
+use tracing::warn;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+enum SessionCommand {
+    Read(SegmentDescriptor, oneshot::Sender<Result<Chunk>>),
+    Write(Vec<Chunk>, oneshot::Sender<Result<Vec<SegmentDescriptor>>>),
+    Flush(oneshot::Sender<Result<()>>),
+    Close(oneshot::Sender<()>),
+}
+
+/// Which session wrote each segment, by segment id
+type Owners = Arc<Mutex<HashMap<u64, usize>>>;
+
+/// Opens a session with a segment handler of its own, and starts its event loop
+///
+/// The id of every segment the session writes to is recorded in `owners` before the write is
+/// reported as done.
+fn spawn_session(
+    id: usize,
+    settings: SFTPSettings,
+    chunk_settings: ChunkSettings,
+    key: Key,
+    queue_depth: usize,
+    creation_lock: Arc<Mutex<()>>,
+    owners: Owners,
+) -> Result<mpsc::Sender<SessionCommand>> {
+    let (input, mut output) = mpsc::channel(queue_depth);
+    let (s, r) = crossbeam::channel::bounded(1);
+    thread::spawn(move || {
+        let handler = SFTPConnection::from(settings)
+            .with_connection()
+            .and_then(|connection| {
+                SFTPSegmentHandler::connect_shared(
+                    connection,
+                    SIZE_LIMIT,
+                    SEGMENTS_PER_DIRECTORY,
+                    chunk_settings,
+                    key,
+                    Some(creation_lock),
+                )
+            });
+        let mut handler = match handler {
+            Ok(handler) => {
+                s.send(None).unwrap();
+                handler
+            }
+            Err(e) => {
+                s.send(Some(e)).unwrap();
+                return;
+            }
+        };
+        let mut final_ret = None;
+        while let Some(command) = block_on(output.next()) {
+            match command {
+                SessionCommand::Read(location, ret) => {
+                    let _ = ret.send(handler.read_chunk(location));
+                }
+                SessionCommand::Write(chunks, ret) => {
+                    let result = handler.write_chunks(chunks);
+                    if let Ok(descriptors) = &result {
+                        let mut owners = owners.lock().unwrap_or_else(PoisonError::into_inner);
+                        for descriptor in descriptors {
+                            owners.insert(descriptor.segment_id, id);
+                        }
+                    }
+                    let _ = ret.send(result);
+                }
+                SessionCommand::Flush(ret) => {
+                    let _ = ret.send(handler.flush());
+                }
+                SessionCommand::Close(ret) => {
+                    final_ret = Some(ret);
+                    break;
+                }
+            }
+        }
+        // Make sure the segment is flushed and unlocked before reporting that we are closed
+        std::mem::drop(handler);
+        if let Some(ret) = final_ret {
+            let _ = ret.send(());
+        }
+    });
+    match r.recv() {
+        Ok(None) => Ok(input),
+        Ok(Some(error)) => Err(error),
+        Err(_) => Err(BackendError::Unknown(
+            "SFTP session thread died before it could send us its result".to_string(),
+        )),
+    }
+}
+
+/// An `SFTP` backend that reads and writes chunks over a pool of sessions
+///
+/// See the module level documentation for details.
+#[derive(Clone)]
+pub struct SFTPPool {
+    primary: BackendHandle<SFTP>,
+    sessions: Arc<Vec<mpsc::Sender<SessionCommand>>>,
+    owners: Owners,
+    /// The session the next chunk read or write not tied to a session goes to
+    next: Arc<AtomicUsize>,
+}
+
+impl SFTPPool {
+    /// Connects to the repository, then opens `sessions` additional sessions that chunks are
+    /// read and written through
+    ///
+    /// Sessions that can not be established are left out of the pool, with a warning. If none
+    /// of them can, chunks are read and written through the primary session.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if connecting the primary `SFTP` backend fails
+    pub async fn connect(
+        settings: SFTPSettings,
+        key: Key,
+        chunk_settings: Option<ChunkSettings>,
+        queue_depth: usize,
+        sessions: usize,
+    ) -> Result<SFTPPool> {
+        let mut primary =
+            SFTP::connect(settings.clone(), key.clone(), chunk_settings, queue_depth)?;
+        let chunk_settings = primary
+            .with_backend(|backend| backend.get_manifest().chunk_settings())
+            .await;
+        let creation_lock = Arc::new(Mutex::new(()));
+        let owners = Owners::default();
+        let mut senders = Vec::with_capacity(sessions);
+        for _ in 0..sessions {
+            let session = spawn_session(
+                senders.len(),
+                settings.clone(),
+                chunk_settings,
+                key.clone(),
+                queue_depth,
+                Arc::clone(&creation_lock),
+                Arc::clone(&owners),
+            );
+            match session {
+                Ok(session) => senders.push(session),
+                Err(e) => warn!("Unable to open an additional SFTP session: {}", e),
+            }
+        }
+        Ok(SFTPPool {
+            primary,
+            sessions: Arc::new(senders),
+            owners,
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Returns the number of sessions chunks are read and written through, not counting the
+    /// primary one
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Picks the session to send a chunk read or write to
+    ///
+    /// Reads from a segment written by one of the sessions go to that session, everything else
+    /// is handed out in turn.
+    fn session(&self, segment_id: Option<u64>) -> mpsc::Sender<SessionCommand> {
+        let owner = segment_id.and_then(|segment_id| {
+            self.owners
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(&segment_id)
+                .copied()
+        });
+        let index = owner
+            .unwrap_or_else(|| self.next.fetch_add(1, Ordering::Relaxed) % self.sessions.len());
+        self.sessions[index].clone()
+    }
+
+    /// Flushes the segments every session is writing to
+    async fn flush_sessions(&self) -> Result<()> {
+        for session in self.sessions.iter() {
+            let (i, o) = oneshot::channel();
+            session.clone().send(SessionCommand::Flush(i)).await?;
+            o.await??;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for SFTPPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SFTPPool")
+            .field("primary", &self.primary)
+            .field("sessions", &self.sessions.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl Backend for SFTPPool {
+    type Manifest = <BackendHandle<SFTP> as Backend>::Manifest;
+    type Index = <BackendHandle<SFTP> as Backend>::Index;
+    fn get_index(&self) -> Self::Index {
+        self.primary.get_index()
+    }
+    async fn write_key(&self, key: &EncryptedKey) -> Result<()> {
+        self.primary.write_key(key).await
+    }
+    async fn read_key(&self) -> Result<EncryptedKey> {
+        self.primary.read_key().await
+    }
+    fn get_manifest(&self) -> Self::Manifest {
+        self.primary.get_manifest()
+    }
+    async fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        if self.sessions.is_empty() {
+            return self.primary.read_chunk(location).await;
+        }
+        let (i, o) = oneshot::channel();
+        self.session(Some(location.segment_id))
+            .send(SessionCommand::Read(location, i))
+            .await?;
+        o.await?
+    }
+    async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        self.write_chunks(vec![chunk])
+            .await?
+            .pop()
+            .ok_or_else(|| BackendError::Unknown("SFTP session lost a chunk".to_string()))
+    }
+    async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        if self.sessions.is_empty() {
+            return self.primary.write_chunks(chunks).await;
+        }
+        let (i, o) = oneshot::channel();
+        self.session(None)
+            .send(SessionCommand::Write(chunks, i))
+            .await?;
+        o.await?
+    }
+    async fn flush(&mut self) -> Result<()> {
+        self.flush_sessions().await?;
+        self.primary.flush().await
+    }
+    async fn close(&mut self) {
+        for session in self.sessions.iter() {
+            let (i, o) = oneshot::channel();
+            // Sessions closed through a clone of this pool have nothing left to do
+            if session.clone().send(SessionCommand::Close(i)).await.is_ok() {
+                let _ = o.await;
+            }
+        }
+        self.primary.close().await;
+    }
+    async fn compact(&mut self, threshold: f64) -> Result<CompactionStats> {
+        self.flush_sessions().await?;
+        self.primary.compact(threshold).await
+    }
+    async fn check(&mut self, repair: bool) -> Result<CheckReport> {
+        self.flush_sessions().await?;
+        self.primary.check(repair).await
+    }
+    async fn chunk_descriptors(&mut self) -> Result<ChunkDescriptors> {
+        self.flush_sessions().await?;
+        self.primary.chunk_descriptors().await
+    }
+    async fn checkpoint(&mut self, keep_squashed: bool) -> Result<CheckpointStats> {
+        self.primary.checkpoint(keep_squashed).await
+    }
+    async fn heads(&mut self) -> Result<Vec<ManifestHead>> {
+        self.primary.heads().await
+    }
+    async fn merge_heads(&mut self) -> Result<Option<common::ManifestID>> {
+        self.primary.merge_heads().await
+    }
+    async fn remove_archives(&mut self, archives: HashSet<ChunkID>) -> Result<CheckpointStats> {
+        self.primary.remove_archives(archives).await
+    }
+    async fn remove_chunks(&mut self, chunks: HashSet<ChunkID>) -> Result<usize> {
+        self.primary.remove_chunks(chunks).await
+    }
+    async fn counted_archives(&mut self) -> Result<Option<HashSet<ChunkID>>> {
+        self.primary.counted_archives().await
+    }
+    async fn add_references(&mut self, archive: ChunkID, chunks: HashSet<ChunkID>) -> Result<()> {
+        self.primary.add_references(archive, chunks).await
+    }
+    async fn release_references(
+        &mut self,
+        archives: HashMap<ChunkID, HashSet<ChunkID>>,
+    ) -> Result<HashSet<ChunkID>> {
+        self.primary.release_references(archives).await
+    }
+    async fn reset_references(
+        &mut self,
+        archives: HashSet<ChunkID>,
+        counts: HashMap<ChunkID, u64>,
+    ) -> Result<()> {
+        self.primary.reset_references(archives, counts).await
+    }
+    fn get_object_handle(&self) -> BackendObject {
+        backend_to_object(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::sftp::HostKeyPolicy;
+    use crate::repository::{Compression, Encryption, HMAC};
+    use std::env;
+
+    fn get_settings(path: String) -> SFTPSettings {
+        let hostname = env::var_os("ASURAN_SFTP_HOSTNAME")
+            .map(|x| x.into_string().unwrap())
+            .expect("Server must be set");
+        let username = env::var_os("ASURAN_SFTP_USER")
+            .map_or("asuran".to_string(), |x| x.into_string().unwrap());
+        let password = env::var_os("ASURAN_SFTP_PASS")
+            .map_or("asuran".to_string(), |x| x.into_string().unwrap());
+        let port = env::var_os("ASURAN_SFTP_PORT")
+            .map_or("22".to_string(), |x| x.into_string().unwrap())
+            .parse::<u16>()
+            .expect("Unable to parse port");
+
+        SFTPSettings {
+            hostname,
+            username,
+            port: Some(port),
+            password: Some(password),
+            key_file: None,
+            key_passphrase: None,
+            known_hosts: None,
+            host_key_policy: HostKeyPolicy::default(),
+            path,
+        }
+    }
+
+    // Writes chunks through a pool, reading them back both before and after they are flushed,
+    // and then through a plain connection
+    #[test]
+    fn pool_round_trip() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = get_settings("asuran/pool_round_trip".to_string());
+            let mut pool = SFTPPool::connect(
+                settings.clone(),
+                key.clone(),
+                Some(ChunkSettings::lightweight()),
+                4,
+                3,
+            )
+            .await
+            .unwrap();
+            assert_eq!(pool.session_count(), 3);
+            let chunks = (0..12_u8)
+                .map(|i| {
+                    Chunk::pack(
+                        vec![i; 1024],
+                        Compression::NoCompression,
+                        Encryption::NoEncryption,
+                        HMAC::Blake3,
+                        &key,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let mut locations = Vec::new();
+            for chunk in &chunks {
+                locations.push(pool.write_chunk(chunk.clone()).await.unwrap());
+            }
+            // The writes were spread out over several segments
+            let segments = locations
+                .iter()
+                .map(|location| location.segment_id)
+                .collect::<HashSet<_>>();
+            assert_eq!(segments.len(), 3);
+            for (chunk, location) in chunks.iter().zip(&locations) {
+                assert_eq!(&pool.read_chunk(*location).await.unwrap(), chunk);
+            }
+            pool.close().await;
+
+            let mut plain = SFTP::connect(settings, key.clone(), None, 4).unwrap();
+            for (chunk, location) in chunks.iter().zip(&locations) {
+                assert_eq!(&plain.read_chunk(*location).await.unwrap(), chunk);
+            }
+            plain.close().await;
+        });
+    }
+}
@@ -1,18 +1,54 @@
 use super::util::LockedFile;
 use super::SFTPConnection;
+use crate::metrics;
 use crate::repository::backend::common::segment::Segment;
 use crate::repository::backend::{BackendError, Result, SegmentDescriptor};
-use crate::repository::{Chunk, ChunkSettings, Key};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, Key};
 
 use lru::LruCache;
-use ssh2::File;
+use ssh2::{File, Sftp};
 
 use std::io::{Read, Seek, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::{Arc, Mutex, PoisonError};
 
 pub struct SegmentPair<R: Read + Write + Seek>(u64, Segment<R>);
 
+/// Lists the ids of the segments in the given data directory
+#[allow(clippy::manual_filter_map)]
+fn list_segments(sftp: &Sftp, data_path: &Path) -> Result<Vec<u64>> {
+    // Walk the data directory, and each of the segment folders in it
+    Ok(sftp
+        // Read The folders in the data directory
+        .readdir(data_path)?
+        // Filter those that are folders whose names are numbers
+        .into_iter()
+        .filter(|(_, file_stat)| file_stat.file_type().is_dir())
+        .filter(|(path, _)| {
+            path.components()
+                .next_back()
+                .and_then(|x| x.as_os_str().to_string_lossy().parse::<u64>().ok())
+                .is_some()
+        })
+        // Read each of those directories
+        .map(|(path, _)| sftp.readdir(&path))
+        // Turn any errors into backend errors
+        .map(|x| x.map_err(BackendError::from))
+        // Collect to stop on error
+        .collect::<Result<Vec<_>>>()?
+        // Bring it into an iterator over segments
+        .into_iter()
+        .flatten()
+        // Include only files whose names are numbers
+        .filter(|(_path, file_stat)| file_stat.file_type().is_file())
+        .filter_map(|(path, _)| {
+            path.file_name()
+                .and_then(|x| x.to_string_lossy().parse::<u64>().ok())
+        })
+        .collect())
+}
+
 pub struct SFTPSegmentHandler {
     /// The connection this SegmentHandler is using
     connection: SFTPConnection,
@@ -34,16 +70,44 @@ pub struct SFTPSegmentHandler {
     chunk_settings: ChunkSettings,
     /// The key used for encrypting/decrypting headers
     key: Key,
+    /// Held while picking and locking a new segment, when several handlers in this process
+    /// create segments in the same repository
+    creation_lock: Option<Arc<Mutex<()>>>,
 }
 
 impl SFTPSegmentHandler {
-    #[allow(clippy::filter_map)]
     pub fn connect(
         settings: impl Into<SFTPConnection>,
         size_limit: u64,
         segments_per_directory: u64,
         chunk_settings: ChunkSettings,
         key: Key,
+    ) -> Result<SFTPSegmentHandler> {
+        Self::connect_shared(
+            settings,
+            size_limit,
+            segments_per_directory,
+            chunk_settings,
+            key,
+            None,
+        )
+    }
+
+    /// Connects like `connect`, holding `creation_lock` whenever a new segment is picked and
+    /// locked
+    ///
+    /// Handlers sharing a lock never race each other for the same new segment.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the connection is made, but has no sftp session
+    pub fn connect_shared(
+        settings: impl Into<SFTPConnection>,
+        size_limit: u64,
+        segments_per_directory: u64,
+        chunk_settings: ChunkSettings,
+        key: Key,
+        creation_lock: Option<Arc<Mutex<()>>>,
     ) -> Result<SFTPSegmentHandler> {
         let connection = settings.into().with_connection()?;
         let sftp = connection.sftp().unwrap();
@@ -58,37 +122,7 @@ impl SFTPSegmentHandler {
             sftp.mkdir(&data_path, 0o775)?;
         }
 
-        // Walk the data directory to find the highest numbered segment
-        let max_segment: Option<u64> = sftp
-            // Read The folders in the data directory
-            .readdir(&data_path)?
-            // Filter those that are folders whose names are numbers
-            .into_iter()
-            .filter(|(_, file_stat)| file_stat.file_type().is_dir())
-            .filter(|(path, _)| {
-                path.components()
-                    .next_back()
-                    .map(|x| x.as_os_str().to_string_lossy().parse::<u64>().ok())
-                    .flatten()
-                    .is_some()
-            })
-            // Read each of those directories
-            .map(|(path, _)| sftp.readdir(&path))
-            // Turn any errors into backend errors
-            .map(|x| x.map_err(|y| y.into()))
-            // Collect to stop on error
-            .collect::<Result<Vec<_>>>()?
-            // Bring it into an iterator over segments
-            .into_iter()
-            .flatten()
-            // Include only files whose names are numbers
-            .filter(|(_path, file_stat)| file_stat.file_type().is_file())
-            .filter_map(|(path, _)| {
-                path.file_name()
-                    .map(|x| x.to_string_lossy().parse::<u64>().ok())
-                    .flatten()
-            })
-            .max();
+        let max_segment = list_segments(&sftp, &data_path)?.into_iter().max();
 
         let mut segment_handler = SFTPSegmentHandler {
             connection,
@@ -100,6 +134,7 @@ impl SFTPSegmentHandler {
             segments_per_directory,
             chunk_settings,
             key,
+            creation_lock,
         };
         // Open the writing segment, to ensure that the data directory is lockable
         segment_handler.open_segment_write()?;
@@ -117,7 +152,9 @@ impl SFTPSegmentHandler {
 
         // Check the cache
         // Insert the segment into the cache if it doesn't exist
-        if !self.ro_segment_cache.contains(&segment_id) {
+        let cached = self.ro_segment_cache.contains(&segment_id);
+        metrics::segment_cache("sftp", cached);
+        if !cached {
             if !self.segment_exists(segment_id) {
                 return Err(BackendError::SegmentError(format!(
                     "Segment with id {} or its containing folder does not exist",
@@ -157,6 +194,34 @@ impl SFTPSegmentHandler {
         Ok(segment_pair)
     }
 
+    /// Lists the ids of every segment in the repository
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if reading the data directory fails
+    pub fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        let sftp = self
+            .connection
+            .sftp()
+            .ok_or_else(|| BackendError::Unknown("Unable to get sftp connection".to_string()))?;
+        list_segments(&sftp, &self.path)
+    }
+
+    /// Returns the id and location of every chunk in the given segment
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the segment can not be opened
+    pub fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        Ok(self
+            .open_segment_read(segment_id)?
+            .1
+            .chunk_descriptors(segment_id))
+    }
+
     pub fn segment_exists(&self, segment_id: u64) -> bool {
         let folder_id = segment_id / self.segments_per_directory;
         // Find the folder it belongs to and check to see if it exists
@@ -177,6 +242,10 @@ impl SFTPSegmentHandler {
     pub fn open_segment_write(&mut self) -> Result<&mut SegmentPair<LockedFile>> {
         // Check to see if we already have an open segment
         if self.current_segment.is_none() {
+            let creation_lock = self.creation_lock.clone();
+            let _guard = creation_lock
+                .as_ref()
+                .map(|lock| lock.lock().unwrap_or_else(PoisonError::into_inner));
             while self.segment_exists(self.highest_segment) {
                 self.highest_segment += 1;
             }
@@ -280,6 +349,43 @@ impl SFTPSegmentHandler {
         Ok(descriptor)
     }
 
+    /// Attempts to write several chunks, appending as many of them at once as the current
+    /// segment has room for
+    ///
+    /// Segments are closed out at the same points as they would be with repeated calls to
+    /// `write_chunk`.
+    pub fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let size_limit = self.size_limit;
+        let mut descriptors = Vec::with_capacity(chunks.len());
+        let mut chunks = chunks.into_iter().peekable();
+        while chunks.peek().is_some() {
+            let segment = self.open_segment_write()?;
+            // Take chunks until the segment would be over its size limit
+            let mut room = size_limit.saturating_sub(segment.1.size());
+            let mut batch = Vec::new();
+            while let Some(chunk) = chunks.peek() {
+                if room == 0 && !batch.is_empty() {
+                    break;
+                }
+                room = room.saturating_sub(chunk.get_bytes().len() as u64 + 1);
+                batch.extend(chunks.next());
+            }
+            let segment_id = segment.0;
+            let starts = segment.1.write_chunks(batch)?;
+            descriptors.extend(
+                starts
+                    .into_iter()
+                    .map(|start| SegmentDescriptor { segment_id, start }),
+            );
+            // If we have exceeded the max size, close out the current segment
+            if segment.1.size() >= size_limit {
+                self.current_segment.as_mut().map(|x| x.1.flush());
+                self.current_segment = None;
+            }
+        }
+        Ok(descriptors)
+    }
+
     pub fn flush(&mut self) -> Result<()> {
         if let Some(segment) = self.current_segment.as_mut() {
             segment.1.flush()
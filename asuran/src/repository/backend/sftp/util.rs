@@ -141,6 +141,10 @@ mod tests {
             username,
             port: Some(port),
             password: Some(password),
+            key_file: None,
+            key_passphrase: None,
+            known_hosts: None,
+            host_key_policy: HostKeyPolicy::default(),
             path,
         }
     }
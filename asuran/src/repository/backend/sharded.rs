@@ -0,0 +1,296 @@
+//! A backend wrapper that spreads the chunks of a repository across several
+//! underlying backends.
+//!
+//! The manifest, index, and key of a `Sharded` repository all live on a designated
+//! primary backend, while chunks are distributed across the shards based on the prefix
+//! of their `ChunkID`. This allows repositories to grow larger than any one of the
+//! volumes they are stored on, and allows IO to proceed on several disks in parallel.
+//!
+//! # Segment Descriptors
+//!
+//! The index of the shard a chunk lives on is packed into the upper 16 bits
+//! of the `segment_id` of the `SegmentDescriptor` stored in the index. As such, the
+//! shards must always be provided in the same order. New shards may be added to the end
+//! of the list at any time, as the shard a chunk has been written to is always recovered
+//! from its descriptor, not its ID.
+use crate::repository::backend::{
+    backend_to_object, Backend, BackendClone, BackendError, BackendObject, Chunk, ChunkDescriptors,
+    ChunkID, EncryptedKey, Result, SegmentDescriptor,
+};
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+
+use std::convert::TryInto;
+
+/// The number of bits the shard index is shifted into the `segment_id`
+const SHARD_SHIFT: u32 = 48;
+
+/// The maximum number of shards a `Sharded` backend can have
+pub const MAX_SHARDS: usize = 1 << (64 - SHARD_SHIFT);
+
+/// Distributes chunks across a number of backends by `ChunkID` prefix
+///
+/// See module level documentation for details.
+#[derive(Debug, Clone)]
+pub struct Sharded<B: BackendClone> {
+    primary: B,
+    shards: Vec<B>,
+}
+
+impl<B: BackendClone> Sharded<B> {
+    /// Creates a new `Sharded` backend, with the manifest, index, and key stored on
+    /// `primary`, and chunks distributed among `shards`.
+    ///
+    /// As closing this backend closes the primary and every shard, the primary must not
+    /// also be provided as one of the shards.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if no shards, or more than `MAX_SHARDS` shards, are provided
+    pub fn new(primary: B, shards: Vec<B>) -> Result<Sharded<B>> {
+        if shards.is_empty() || shards.len() > MAX_SHARDS {
+            return Err(BackendError::SegmentError(format!(
+                "A sharded backend requires between 1 and {} shards, {} were provided",
+                MAX_SHARDS,
+                shards.len()
+            )));
+        }
+        Ok(Sharded { primary, shards })
+    }
+
+    /// Returns the number of shards chunks are distributed among
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Determines which shard a new chunk with the given ID will be written to
+    pub fn shard_for(&self, id: ChunkID) -> usize {
+        let bytes = id.get_id();
+        let prefix = u16::from_be_bytes([bytes[0], bytes[1]]);
+        usize::from(prefix) % self.shards.len()
+    }
+
+    /// Packs the index of a shard into a descriptor from that shard
+    fn encode_location(shard: usize, location: SegmentDescriptor) -> Result<SegmentDescriptor> {
+        if location.segment_id >> SHARD_SHIFT != 0 {
+            return Err(BackendError::SegmentError(format!(
+                "Segment id {} of shard {} is too large to be sharded",
+                location.segment_id, shard
+            )));
+        }
+        // The constructor ensures that every shard index fits in the upper bits
+        let shard: u64 = shard.try_into().expect("Shard index did not fit in a u64");
+        Ok(SegmentDescriptor {
+            segment_id: (shard << SHARD_SHIFT) | location.segment_id,
+            start: location.start,
+        })
+    }
+
+    /// Splits a descriptor from the index into the index of its shard, and the descriptor
+    /// within that shard
+    fn decode_location(location: SegmentDescriptor) -> (usize, SegmentDescriptor) {
+        let shard = (location.segment_id >> SHARD_SHIFT)
+            .try_into()
+            .expect("Shard index did not fit in a usize");
+        let inner = SegmentDescriptor {
+            segment_id: location.segment_id & ((1 << SHARD_SHIFT) - 1),
+            start: location.start,
+        };
+        (shard, inner)
+    }
+}
+
+#[async_trait]
+impl<B: BackendClone> Backend for Sharded<B> {
+    type Manifest = B::Manifest;
+    type Index = B::Index;
+    /// Returns the index of the primary
+    fn get_index(&self) -> Self::Index {
+        self.primary.get_index()
+    }
+    /// Writes the key to the primary
+    ///
+    /// The shards are not touched, and must be initialized by the caller
+    async fn write_key(&self, key: &EncryptedKey) -> Result<()> {
+        self.primary.write_key(key).await
+    }
+    /// Reads the key from the primary
+    async fn read_key(&self) -> Result<EncryptedKey> {
+        self.primary.read_key().await
+    }
+    /// Returns the manifest of the primary
+    fn get_manifest(&self) -> Self::Manifest {
+        self.primary.get_manifest()
+    }
+    /// Reads the chunk from the shard encoded in its location
+    async fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        let (shard, location) = Self::decode_location(location);
+        let shard_count = self.shards.len();
+        let backend = self.shards.get_mut(shard).ok_or_else(|| {
+            BackendError::SegmentError(format!(
+                "Chunk was located on shard {shard}, but only {shard_count} shards are present"
+            ))
+        })?;
+        backend.read_chunk(location).await
+    }
+    /// Writes the chunk to the shard selected by the prefix of its ID
+    async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        let shard = self.shard_for(chunk.get_id());
+        let location = self.shards[shard].write_chunk(chunk).await?;
+        Self::encode_location(shard, location)
+    }
+    /// Splits the chunks up by shard, and writes each shard's share as a single batch
+    async fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let mut batches: Vec<(Vec<usize>, Vec<Chunk>)> =
+            vec![(Vec::new(), Vec::new()); self.shards.len()];
+        let count = chunks.len();
+        for (position, chunk) in chunks.into_iter().enumerate() {
+            let shard = self.shard_for(chunk.get_id());
+            batches[shard].0.push(position);
+            batches[shard].1.push(chunk);
+        }
+        let mut locations = vec![None; count];
+        for (shard, (positions, chunks)) in batches.into_iter().enumerate() {
+            if chunks.is_empty() {
+                continue;
+            }
+            let written = self.shards[shard].write_chunks(chunks).await?;
+            for (position, location) in positions.into_iter().zip(written) {
+                locations[position] = Some(Self::encode_location(shard, location)?);
+            }
+        }
+        Ok(locations.into_iter().flatten().collect())
+    }
+    /// Flushes the primary and all of the shards
+    async fn flush(&mut self) -> Result<()> {
+        self.primary.flush().await?;
+        for shard in &mut self.shards {
+            shard.flush().await?;
+        }
+        Ok(())
+    }
+    /// Lists the chunks stored on every shard in turn
+    async fn chunk_descriptors(&mut self) -> Result<ChunkDescriptors> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for (shard, backend) in self.shards.iter_mut().enumerate() {
+            let descriptors = backend.chunk_descriptors().await?;
+            shards.push(descriptors.map(move |descriptor| {
+                let (id, location) = descriptor?;
+                Ok((id, Self::encode_location(shard, location)?))
+            }));
+        }
+        Ok(stream::iter(shards).flatten().boxed())
+    }
+    /// Closes the primary and all of the shards
+    async fn close(&mut self) {
+        self.primary.close().await;
+        for shard in &mut self.shards {
+            shard.close().await;
+        }
+    }
+    fn get_object_handle(&self) -> BackendObject {
+        backend_to_object(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::common::sync_backend::BackendHandle;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::*;
+    use futures::stream::StreamExt;
+
+    fn setup(key: &Key, shards: usize) -> Sharded<BackendHandle<Mem>> {
+        let settings = ChunkSettings::lightweight();
+        let primary = Mem::new(settings, key.clone(), 8);
+        let shards = (0..shards)
+            .map(|_| Mem::new(settings, key.clone(), 8))
+            .collect();
+        Sharded::new(primary, shards).unwrap()
+    }
+
+    #[test]
+    fn location_round_trip() {
+        let location = SegmentDescriptor {
+            segment_id: 12,
+            start: 345,
+        };
+        let encoded = Sharded::<BackendHandle<Mem>>::encode_location(7, location).unwrap();
+        assert_eq!(
+            Sharded::<BackendHandle<Mem>>::decode_location(encoded),
+            (7, location)
+        );
+        let too_large = SegmentDescriptor {
+            segment_id: 1 << SHARD_SHIFT,
+            start: 0,
+        };
+        assert!(Sharded::<BackendHandle<Mem>>::encode_location(0, too_large).is_err());
+    }
+
+    #[test]
+    fn no_shards() {
+        let key = Key::random(32);
+        let primary = Mem::new(ChunkSettings::lightweight(), key, 8);
+        assert!(Sharded::new(primary, Vec::new()).is_err());
+    }
+
+    // Write chunks through a repository on top of a sharded backend, and make sure they
+    // are spread out across the shards and can be read back
+    #[test]
+    fn distributes_chunks() {
+        smol::run(async {
+            let key = Key::random(32);
+            let backend = setup(&key, 4);
+            let mut repo = Repository::with(
+                backend.clone(),
+                ChunkSettings::lightweight(),
+                key.clone(),
+                2,
+            )
+            .unwrap();
+            let mut ids = Vec::new();
+            for i in 0..64_u8 {
+                let (id, _) = repo.write_chunk(vec![i; 1024]).await.unwrap();
+                ids.push((id, i));
+            }
+            let mut used = vec![false; backend.shard_count()];
+            for (id, i) in ids {
+                let location = backend.get_index().lookup_chunk(id).await.unwrap();
+                let (shard, _) = Sharded::<BackendHandle<Mem>>::decode_location(location);
+                assert_eq!(shard, backend.shard_for(id));
+                used[shard] = true;
+                assert_eq!(repo.read_chunk(id).await.unwrap(), vec![i; 1024]);
+            }
+            assert!(used.into_iter().all(|x| x));
+            repo.close().await;
+        });
+    }
+
+    // Every chunk should be listed with the same location the index has for it
+    #[test]
+    fn chunk_descriptors() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut backend = setup(&key, 4);
+            let mut repo = Repository::with(
+                backend.clone(),
+                ChunkSettings::lightweight(),
+                key.clone(),
+                2,
+            )
+            .unwrap();
+            for i in 0..64_u8 {
+                repo.write_chunk(vec![i; 1024]).await.unwrap();
+            }
+            let descriptors: Vec<_> = backend.chunk_descriptors().await.unwrap().collect().await;
+            assert_eq!(descriptors.len(), 64);
+            for descriptor in descriptors {
+                let (id, location) = descriptor.unwrap();
+                assert_eq!(backend.get_index().lookup_chunk(id).await, Some(location));
+            }
+            repo.close().await;
+        });
+    }
+}
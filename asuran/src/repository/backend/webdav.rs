@@ -0,0 +1,370 @@
+//! Stores a repository on a WebDAV share, such as Nextcloud, or a Hetzner Storage Box over
+//! HTTPS, without having to mount it first
+//!
+//! The layout on the server mirrors a `MultiFile` repository, with the key, a manifest
+//! directory, an index directory, and segments in the data directory. As WebDAV can not
+//! append to a file, segments are built up in memory and uploaded once they fill up or the
+//! backend is flushed, and the index and manifest add a new file for every commit. Chunks are
+//! read back with ranged `GET`s, so restoring a file never downloads whole segments.
+//!
+//! New files are created with `If-None-Match: *`, which the server must honor for multiple
+//! connections to safely write to the same repository at once.
+use super::{Result, SegmentDescriptor};
+use crate::repository::backend::common::sync_backend::{BackendHandle, SyncBackend, SyncManifest};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey, Key};
+
+use rmp_serde as rmps;
+
+pub mod client;
+pub mod index;
+pub mod manifest;
+pub mod segment;
+
+use self::client::DavClient;
+use self::index::WebDavIndex;
+use self::manifest::WebDavManifest;
+use self::segment::WebDavSegmentHandler;
+
+/// The size at which segments are closed and uploaded
+///
+/// Smaller than the other backends, as the segment being written is held in memory.
+const SEGMENT_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+/// The number of segments in each folder of the data directory
+const SEGMENTS_PER_DIRECTORY: u64 = 250;
+
+/// Settings used for connecting to a WebDAV share
+#[derive(Clone)]
+pub struct WebDavSettings {
+    /// The URL of the collection the repository lives in, e.g.
+    /// `https://cloud.example.com/remote.php/dav/files/user/backups`
+    pub url: String,
+    /// Username to authenticate with, using HTTP basic authentication
+    ///
+    /// Optional, no authentication is attempted if not set
+    pub username: Option<String>,
+    /// Password to authenticate with
+    pub password: Option<String>,
+}
+
+impl std::fmt::Debug for WebDavSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebDavSettings")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub struct WebDav {
+    client: DavClient,
+    manifest: WebDavManifest,
+    index: WebDavIndex,
+    segment_handler: WebDavSegmentHandler,
+}
+
+impl WebDav {
+    /// Connects to the repository without wrapping it in a `BackendHandle`
+    ///
+    /// The repository's collection is created if it does not exist, but its parent must.
+    /// If `chunk_settings` is provided, they are written out as the repository's defaults.
+    pub fn connect_raw(
+        settings: &WebDavSettings,
+        key: &Key,
+        chunk_settings: Option<ChunkSettings>,
+    ) -> Result<Self> {
+        let client = DavClient::new(settings)?;
+        client.mkcol("")?;
+        let mut manifest = WebDavManifest::connect(client.clone(), key, chunk_settings)?;
+        let index = WebDavIndex::connect(client.clone())?;
+        let chunk_settings = manifest.chunk_settings();
+        let segment_handler = WebDavSegmentHandler::connect(
+            client.clone(),
+            SEGMENT_SIZE_LIMIT,
+            SEGMENTS_PER_DIRECTORY,
+            chunk_settings,
+            key.clone(),
+        )?;
+        Ok(WebDav {
+            client,
+            manifest,
+            index,
+            segment_handler,
+        })
+    }
+
+    /// Connects to the repository and wraps it in a `BackendHandle`
+    pub fn connect(
+        settings: &WebDavSettings,
+        key: &Key,
+        chunk_settings: Option<ChunkSettings>,
+        queue_depth: usize,
+    ) -> Result<BackendHandle<WebDav>> {
+        let webdav = WebDav::connect_raw(settings, key, chunk_settings)?;
+        Ok(BackendHandle::new(queue_depth, move || webdav))
+    }
+
+    /// Reads the key of the repository, without opening the rest of it
+    pub fn read_key(settings: &WebDavSettings) -> Result<EncryptedKey> {
+        let client = DavClient::new(settings)?;
+        Ok(rmps::decode::from_slice(&client.get("key")?)?)
+    }
+}
+
+impl SyncBackend for WebDav {
+    type SyncManifest = WebDavManifest;
+    type SyncIndex = WebDavIndex;
+    fn get_index(&mut self) -> &mut Self::SyncIndex {
+        &mut self.index
+    }
+    fn get_manifest(&mut self) -> &mut Self::SyncManifest {
+        &mut self.manifest
+    }
+    fn write_key(&mut self, key: EncryptedKey) -> Result<()> {
+        self.client.put("key", &rmps::encode::to_vec(&key)?)
+    }
+    fn read_key(&mut self) -> Result<EncryptedKey> {
+        Ok(rmps::decode::from_slice(&self.client.get("key")?)?)
+    }
+    fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        self.segment_handler.read_chunk(location)
+    }
+    fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        self.segment_handler.write_chunk(chunk)
+    }
+    fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        self.segment_handler.write_chunks(chunks)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.segment_handler.flush()
+    }
+    fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        self.segment_handler.segment_ids()
+    }
+    fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        self.segment_handler.segment_descriptors(segment_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::StoredArchive;
+    use crate::repository::backend::common::sync_backend::SyncIndex;
+    use crate::repository::backend::remote::http;
+    use crate::repository::backend::{Backend, Index, Manifest};
+    use crate::repository::{ChunkIDSettings, Compression, Encryption, Repository, HMAC};
+
+    use futures::stream::StreamExt;
+    use std::collections::BTreeMap;
+    use std::fmt::Write as _;
+    use std::io::BufReader;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// The files and collections on the test server, collections having no contents
+    type Tree = Arc<Mutex<BTreeMap<String, Option<Vec<u8>>>>>;
+
+    fn settings() -> ChunkSettings {
+        ChunkSettings {
+            compression: Compression::ZStd { level: 1 },
+            encryption: Encryption::new_aes256ctr(),
+            hmac: HMAC::Blake3,
+            id: ChunkIDSettings::default(),
+            chunker: None,
+        }
+    }
+
+    /// Handles a single request against the tree, returning the status and body to reply with
+    fn handle(tree: &Tree, request: &http::Message) -> (&'static str, Vec<u8>) {
+        let (method, path) = request.request_target().unwrap();
+        let path = path.trim_end_matches('/').to_string();
+        let parent = path
+            .rsplit_once('/')
+            .map_or("", |(parent, _)| parent)
+            .to_string();
+        let mut tree = tree.lock().unwrap();
+        match method {
+            "MKCOL" if tree.contains_key(&path) => ("405 Method Not Allowed", Vec::new()),
+            "MKCOL" => {
+                tree.insert(path, None);
+                ("201 Created", Vec::new())
+            }
+            "PUT" if !tree.contains_key(&parent) => ("409 Conflict", Vec::new()),
+            "PUT" if request.header("If-None-Match").is_some() && tree.contains_key(&path) => {
+                ("412 Precondition Failed", Vec::new())
+            }
+            "PUT" => {
+                tree.insert(path, Some(request.body.clone()));
+                ("201 Created", Vec::new())
+            }
+            "GET" => match tree.get(&path) {
+                Some(Some(contents)) => match request.header("Range") {
+                    Some(range) => {
+                        let range = range.trim_start_matches("bytes=");
+                        let mut bounds = range.split('-').map(|x| x.parse::<usize>().unwrap());
+                        let start = bounds.next().unwrap();
+                        let end = bounds.next().unwrap().min(contents.len() - 1);
+                        ("206 Partial Content", contents[start..=end].to_vec())
+                    }
+                    None => ("200 OK", contents.clone()),
+                },
+                _ => ("404 Not Found", Vec::new()),
+            },
+            "PROPFIND" => {
+                if !tree.contains_key(&path) {
+                    return ("404 Not Found", Vec::new());
+                }
+                let mut children = String::new();
+                let listed = tree.iter().filter(|(key, _)| {
+                    **key == path
+                        || (request.header("Depth") == Some("1")
+                            && key.rsplit_once('/').map(|(parent, _)| parent)
+                                == Some(path.as_str()))
+                });
+                for (key, value) in listed {
+                    let prop = match value {
+                        None => "<d:resourcetype><d:collection/></d:resourcetype>".to_string(),
+                        Some(contents) => format!(
+                            "<d:resourcetype/><d:getcontentlength>{}</d:getcontentlength>",
+                            contents.len()
+                        ),
+                    };
+                    write!(
+                        children,
+                        "<d:response><d:href>{}</d:href><d:propstat><d:prop>{prop}</d:prop>\
+                         </d:propstat></d:response>",
+                        key.replace(' ', "%20"),
+                    )
+                    .unwrap();
+                }
+                let body = format!(
+                    r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">{children}</d:multistatus>"#
+                );
+                ("207 Multi-Status", body.into_bytes())
+            }
+            _ => ("405 Method Not Allowed", Vec::new()),
+        }
+    }
+
+    fn serve(tree: &Tree, stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        while let Ok(Some(request)) = http::read_message(&mut reader) {
+            let (status, body) = handle(tree, &request);
+            let start_line = format!("HTTP/1.1 {status}");
+            if http::write_message(&mut writer, &start_line, &[], &body).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Starts a server holding an empty share, returning the URL of a repository in it
+    fn start() -> (WebDavSettings, Tree) {
+        let tree: Tree = Arc::default();
+        tree.lock().unwrap().insert("/share".to_string(), None);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_tree = Arc::clone(&tree);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let tree = Arc::clone(&server_tree);
+                thread::spawn(move || serve(&tree, stream.unwrap()));
+            }
+        });
+        let settings = WebDavSettings {
+            url: format!("http://{address}/share/my repo").replace(' ', "%20"),
+            username: Some("asuran".to_string()),
+            password: Some("hunter2".to_string()),
+        };
+        (settings, tree)
+    }
+
+    #[test]
+    fn round_trip() {
+        smol::run(async {
+            let (settings, _tree) = start();
+            let key = Key::random(32);
+            let backend =
+                WebDav::connect(&settings, &key, Some(super::tests::settings()), 4).unwrap();
+            let encrypted_key =
+                EncryptedKey::encrypt_defaults(&key, Encryption::new_aes256ctr(), b"password");
+            backend.write_key(&encrypted_key).await.unwrap();
+
+            let mut repo =
+                Repository::with(backend.clone(), super::tests::settings(), key.clone(), 2)
+                    .unwrap();
+            let data: Vec<Vec<u8>> = (0..10_u8).map(|x| vec![x; 100_000]).collect();
+            let mut ids = Vec::new();
+            for chunk in &data {
+                ids.push(repo.write_chunk(chunk.clone()).await.unwrap().0);
+            }
+            // Chunks can be read back before the segment they are in is uploaded
+            assert_eq!(repo.read_chunk(ids[3]).await.unwrap(), data[3]);
+            repo.commit_index().await;
+            let archive = StoredArchive::dummy_archive();
+            backend
+                .get_manifest()
+                .write_archive(archive.clone())
+                .await
+                .unwrap();
+            repo.close().await;
+
+            let read_key = WebDav::read_key(&settings).unwrap();
+            assert_eq!(read_key.decrypt(b"password").unwrap(), key);
+            let mut backend = WebDav::connect(&settings, &key, None, 4).unwrap();
+            assert_eq!(backend.get_index().count_chunk().await, 10);
+            let archives: Vec<_> = backend.get_manifest().archive_iterator().await.collect();
+            assert_eq!(archives, vec![archive]);
+            let descriptors: Vec<_> = backend.chunk_descriptors().await.unwrap().collect().await;
+            assert_eq!(descriptors.len(), 10);
+            let mut repo =
+                Repository::with(backend.clone(), super::tests::settings(), key, 2).unwrap();
+            for (id, chunk) in ids.iter().zip(&data) {
+                assert_eq!(&repo.read_chunk(*id).await.unwrap(), chunk);
+            }
+            // New chunks go to a new segment, as uploaded ones can not be appended to
+            let (id, _) = repo.write_chunk(vec![42_u8; 1000]).await.unwrap();
+            repo.commit_index().await;
+            let location = backend.get_index().lookup_chunk(id).await.unwrap();
+            assert_eq!(location.segment_id, 1);
+            repo.close().await;
+        });
+    }
+
+    #[test]
+    fn concurrent_commits() {
+        let (settings, tree) = start();
+        let key = Key::random(32);
+        let first = WebDav::connect_raw(&settings, &key, Some(super::tests::settings())).unwrap();
+        let second = WebDav::connect_raw(&settings, &key, None).unwrap();
+        let mut backends = vec![first, second];
+        for backend in &mut backends {
+            let chunk = Chunk::pack(
+                vec![1, 2, 3],
+                Compression::NoCompression,
+                Encryption::NoEncryption,
+                HMAC::Blake3,
+                &key,
+            );
+            let location = backend.write_chunk(chunk.clone()).unwrap();
+            backend.flush().unwrap();
+            backend
+                .get_index()
+                .set_chunk(chunk.get_id(), location)
+                .unwrap();
+            backend.get_index().commit_index().unwrap();
+        }
+        // Both connections claimed their own segment and index file, rather than one
+        // overwriting the other
+        let tree = tree.lock().unwrap();
+        for file in &["data/0/0", "data/0/1", "index/1", "index/2"] {
+            let contents = tree.get(&format!("/share/my%20repo/{file}")).unwrap();
+            assert!(!contents.as_ref().unwrap().is_empty(), "{} is empty", file);
+        }
+    }
+}
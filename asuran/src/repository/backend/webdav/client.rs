@@ -0,0 +1,392 @@
+//! A minimal blocking WebDAV client, covering the handful of methods the backend needs
+use super::WebDavSettings;
+use crate::repository::backend::{BackendError, Result};
+
+use std::io::Read;
+use std::time::Duration;
+
+/// The body of every `PROPFIND`, asking only for the properties the backend looks at
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/></d:prop></d:propfind>"#;
+/// The namespace all WebDAV properties live in
+const DAV_NAMESPACE: &str = "DAV:";
+/// The maximum size of a `PROPFIND` response
+///
+/// Listings of the data directory have one entry per segment, which is nowhere near this.
+const MAX_LISTING: u64 = 64 * 1024 * 1024;
+
+/// A file or collection on the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The last component of the entry's path, percent decoded
+    pub name: String,
+    pub is_collection: bool,
+    /// The length of the file in bytes, zero for collections
+    pub length: u64,
+}
+
+/// A connection to a WebDAV server, rooted at the repository's collection
+///
+/// All paths handed to the client are relative to the repository, using `/` as the separator.
+#[derive(Clone)]
+pub struct DavClient {
+    agent: ureq::Agent,
+    /// The URL of the repository's collection, always ending in a `/`
+    base: String,
+    /// The path component of `base`, percent decoded, used to make sense of `PROPFIND` hrefs
+    base_path: String,
+    authorization: Option<String>,
+}
+
+impl DavClient {
+    /// Creates a new client for the repository at the given settings
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the URL is not an `http` or `https` URL
+    pub fn new(settings: &WebDavSettings) -> Result<DavClient> {
+        let url = settings.url.trim_end_matches('/');
+        let rest = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))
+            .ok_or_else(|| {
+                BackendError::ConnectionError(format!(
+                    "WebDAV URL must start with http:// or https://, got {}",
+                    settings.url
+                ))
+            })?;
+        let base_path = match rest.find('/') {
+            Some(index) => percent_decode(&rest[index..]),
+            None => String::new(),
+        };
+        let authorization = settings.username.as_ref().map(|username| {
+            let password = settings.password.as_deref().unwrap_or("");
+            let credentials = format!("{username}:{password}");
+            format!("Basic {}", base64::encode(credentials))
+        });
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(30))
+            .timeout_read(Duration::from_mins(5))
+            .timeout_write(Duration::from_mins(5))
+            .build();
+        Ok(DavClient {
+            agent,
+            base: format!("{url}/"),
+            base_path: format!("{base_path}/"),
+            authorization,
+        })
+    }
+
+    /// Builds a request for the given path, relative to the repository
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self
+            .agent
+            .request(method, &format!("{}{}", self.base, path));
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    /// Fetches the full contents of a file
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(DataNotFound)` if the file does not exist
+    pub fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let response = self.request("GET", path).call().map_err(to_backend)?;
+        read_body(response, u64::MAX)
+    }
+
+    /// Fetches up to `length` bytes of a file, starting at `offset`
+    ///
+    /// Fewer bytes are returned if the file ends first, and none if `offset` is past its end.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(DataNotFound)` if the file does not exist
+    pub fn get_range(&self, path: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        match self.request("GET", path).set("Range", &range).call() {
+            Ok(response) if response.status() == 206 => read_body(response, length),
+            // The server ignored the range, so skip up to the part we asked for
+            Ok(response) => {
+                let mut reader = response.into_reader();
+                std::io::copy(&mut (&mut reader).take(offset), &mut std::io::sink())?;
+                let mut buffer = Vec::new();
+                reader.take(length).read_to_end(&mut buffer)?;
+                Ok(buffer)
+            }
+            Err(ureq::Error::Status(416, _)) => Ok(Vec::new()),
+            Err(error) => Err(to_backend(error)),
+        }
+    }
+
+    /// Uploads a file, replacing it if it already exists
+    pub fn put(&self, path: &str, body: &[u8]) -> Result<()> {
+        self.request("PUT", path)
+            .send_bytes(body)
+            .map_err(to_backend)?;
+        Ok(())
+    }
+
+    /// Uploads a file only if nothing exists at its path yet, returning `false` if
+    /// something did
+    ///
+    /// Relies on the server honoring `If-None-Match: *` on `PUT`, which makes claiming a
+    /// name a single atomic operation, so two connections can never both believe they
+    /// created the same file.
+    pub fn create(&self, path: &str, body: &[u8]) -> Result<bool> {
+        match self
+            .request("PUT", path)
+            .set("If-None-Match", "*")
+            .send_bytes(body)
+        {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(412, _)) => Ok(false),
+            Err(error) => Err(to_backend(error)),
+        }
+    }
+
+    /// Uploads `body` as a new file in `directory`, named with the lowest number above
+    /// `after` that is not taken yet, and returns that number
+    pub fn create_numbered(&self, directory: &str, after: u64, body: &[u8]) -> Result<u64> {
+        let mut id = after + 1;
+        while !self.create(&format!("{directory}/{id}"), body)? {
+            id += 1;
+        }
+        Ok(id)
+    }
+
+    /// Creates a collection, doing nothing if it already exists
+    pub fn mkcol(&self, path: &str) -> Result<()> {
+        match self.request("MKCOL", path).call() {
+            // 405 is the response to creating a collection that already exists
+            Ok(_) | Err(ureq::Error::Status(405, _)) => Ok(()),
+            Err(error) => Err(to_backend(error)),
+        }
+    }
+
+    /// Describes the file or collection at the given path, or returns `None` if there is
+    /// nothing there
+    pub fn stat(&self, path: &str) -> Result<Option<Entry>> {
+        match self.propfind(path, "0") {
+            Ok(mut entries) => Ok(entries.pop().map(|(_, entry)| entry)),
+            Err(BackendError::DataNotFound) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Lists the contents of a collection
+    pub fn list(&self, path: &str) -> Result<Vec<Entry>> {
+        let collection = format!("{}/", path.trim_end_matches('/'));
+        let own_path = format!("{}{}", self.base_path, collection);
+        Ok(self
+            .propfind(&collection, "1")?
+            .into_iter()
+            .filter(|(href, _)| href.trim_end_matches('/') != own_path.trim_end_matches('/'))
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+
+    /// Lists the files in a collection whose names are numbers, sorted by that number
+    pub fn numbered_files(&self, directory: &str) -> Result<Vec<u64>> {
+        let mut ids = self
+            .list(directory)?
+            .into_iter()
+            .filter(|entry| !entry.is_collection)
+            .filter_map(|entry| entry.name.parse::<u64>().ok())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Performs a `PROPFIND`, returning the decoded path of each entry along with it
+    fn propfind(&self, path: &str, depth: &str) -> Result<Vec<(String, Entry)>> {
+        let response = self
+            .request("PROPFIND", path)
+            .set("Depth", depth)
+            .set("Content-Type", "application/xml; charset=utf-8")
+            .send_string(PROPFIND_BODY)
+            .map_err(to_backend)?;
+        let body = read_body(response, MAX_LISTING)?;
+        let body = String::from_utf8(body).map_err(|_| {
+            BackendError::ConnectionError("WebDAV listing was not valid utf-8".to_string())
+        })?;
+        parse_multistatus(&body)
+    }
+}
+
+impl std::fmt::Debug for DavClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DavClient")
+            .field("base", &self.base)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Reads at most `limit` bytes of a response body
+fn read_body(response: ureq::Response, limit: u64) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    response
+        .into_reader()
+        .take(limit)
+        .read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Converts a failed request into a `BackendError`
+fn to_backend(error: ureq::Error) -> BackendError {
+    match error {
+        ureq::Error::Status(404, _) => BackendError::DataNotFound,
+        ureq::Error::Status(507, _) => {
+            BackendError::QuotaExceeded("WebDAV server is out of space".to_string())
+        }
+        ureq::Error::Status(code, response) => BackendError::ConnectionError(format!(
+            "WebDAV server responded to {} with status {} {}",
+            response.get_url(),
+            code,
+            response.status_text()
+        )),
+        ureq::Error::Transport(transport) => {
+            BackendError::ConnectionError(format!("WebDAV request failed: {transport}"))
+        }
+    }
+}
+
+/// Parses the body of a `207 Multi-Status` response to a `PROPFIND`
+fn parse_multistatus(body: &str) -> Result<Vec<(String, Entry)>> {
+    let document = roxmltree::Document::parse(body).map_err(|error| {
+        BackendError::ConnectionError(format!("Unable to parse WebDAV listing: {error}"))
+    })?;
+    let is = |node: &roxmltree::Node, name: &str| {
+        node.is_element()
+            && node.tag_name().name() == name
+            && node.tag_name().namespace() == Some(DAV_NAMESPACE)
+    };
+    let mut entries = Vec::new();
+    for response in document.descendants().filter(|node| is(node, "response")) {
+        let href = match response
+            .children()
+            .find(|node| is(node, "href"))
+            .and_then(|node| node.text())
+        {
+            Some(href) => percent_decode(strip_origin(href.trim())),
+            None => continue,
+        };
+        let is_collection = response
+            .descendants()
+            .filter(|node| is(node, "resourcetype"))
+            .any(|node| node.children().any(|child| is(&child, "collection")));
+        let length = response
+            .descendants()
+            .find(|node| is(node, "getcontentlength"))
+            .and_then(|node| node.text())
+            .and_then(|text| text.trim().parse().ok())
+            .unwrap_or(0);
+        let name = href
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or("")
+            .to_string();
+        entries.push((
+            href,
+            Entry {
+                name,
+                is_collection,
+                length,
+            },
+        ));
+    }
+    Ok(entries)
+}
+
+/// Strips the scheme and host from an href, as some servers send full URLs
+fn strip_origin(href: &str) -> &str {
+    match href.find("://") {
+        Some(index) => {
+            let rest = &href[index + 3..];
+            rest.find('/').map_or("/", |slash| &rest[slash..])
+        }
+        None => href,
+    }
+}
+
+/// Decodes the `%XX` escapes in a URL path
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(digits, 16) {
+                output.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        output.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multistatus() {
+        let body = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/dav/my%20repo/data/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>https://example.com/dav/my%20repo/data/0/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <D:response xmlns:D="DAV:">
+    <D:href>/dav/my%20repo/data/0.header</D:href>
+    <D:propstat>
+      <D:prop><D:resourcetype/><D:getcontentlength>1234</D:getcontentlength></D:prop>
+    </D:propstat>
+  </D:response>
+</d:multistatus>"#;
+        let entries = parse_multistatus(body).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].0, "/dav/my repo/data/");
+        assert_eq!(entries[1].0, "/dav/my repo/data/0/");
+        assert_eq!(
+            entries[1].1,
+            Entry {
+                name: "0".to_string(),
+                is_collection: true,
+                length: 0
+            }
+        );
+        assert_eq!(
+            entries[2].1,
+            Entry {
+                name: "0.header".to_string(),
+                is_collection: false,
+                length: 1234
+            }
+        );
+    }
+
+    #[test]
+    fn decoding() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(strip_origin("http://host:80/a/b"), "/a/b");
+        assert_eq!(strip_origin("/a/b"), "/a/b");
+    }
+}
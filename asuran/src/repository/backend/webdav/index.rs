@@ -0,0 +1,81 @@
+use super::client::DavClient;
+use crate::repository::backend::common::sync_backend::SyncIndex;
+use crate::repository::backend::common::IndexTransaction;
+use crate::repository::backend::{Result, SegmentDescriptor};
+use crate::repository::ChunkID;
+
+use rmp_serde as rmps;
+
+use std::collections::{HashMap, HashSet};
+
+/// The index of a WebDAV repository
+///
+/// Files can not be appended to over WebDAV, so every commit uploads the changes since the
+/// last one as a new file in the index directory.
+#[derive(Debug)]
+pub struct WebDavIndex {
+    client: DavClient,
+    state: HashMap<ChunkID, SegmentDescriptor>,
+    changes: Vec<IndexTransaction>,
+    /// The highest numbered index file seen so far
+    last_file: u64,
+}
+
+impl WebDavIndex {
+    pub fn connect(client: DavClient) -> Result<Self> {
+        client.mkcol("index")?;
+        let mut state = HashMap::new();
+        let ids = client.numbered_files("index")?;
+        for id in &ids {
+            let buffer = client.get(&format!("index/{id}"))?;
+            let mut reader = &buffer[..];
+            // Keep deserializing transactions until we encounter an error
+            while let Ok(tx) = rmps::decode::from_read::<_, IndexTransaction>(&mut reader) {
+                state.insert(tx.chunk_id, tx.descriptor);
+            }
+        }
+        Ok(WebDavIndex {
+            client,
+            state,
+            changes: Vec::new(),
+            last_file: ids.last().copied().unwrap_or(0),
+        })
+    }
+}
+
+impl SyncIndex for WebDavIndex {
+    fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+        self.state.get(&id).copied()
+    }
+    #[allow(clippy::map_entry)]
+    fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
+        if !self.state.contains_key(&id) {
+            self.state.insert(id, location);
+            self.changes.push(IndexTransaction {
+                chunk_id: id,
+                descriptor: location,
+            });
+        }
+        Ok(())
+    }
+    fn known_chunks(&mut self) -> HashSet<ChunkID> {
+        self.state.keys().copied().collect()
+    }
+    fn commit_index(&mut self) -> Result<()> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+        let mut buffer = Vec::new();
+        for tx in &self.changes {
+            rmps::encode::write(&mut buffer, tx)?;
+        }
+        self.last_file = self
+            .client
+            .create_numbered("index", self.last_file, &buffer)?;
+        self.changes.clear();
+        Ok(())
+    }
+    fn chunk_count(&mut self) -> usize {
+        self.state.len()
+    }
+}
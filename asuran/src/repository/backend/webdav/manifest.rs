@@ -0,0 +1,206 @@
+use super::client::DavClient;
+use crate::manifest::StoredArchive;
+use crate::repository::backend::common::sync_backend::SyncManifest;
+use crate::repository::backend::common::{
+    archives_from_transactions, may_be_missing, ManifestID, ManifestTransaction,
+};
+use crate::repository::backend::{BackendError, Result};
+use crate::repository::{ChunkSettings, Key};
+use crate::time::Timestamp;
+
+use petgraph::Graph;
+use rmp_serde as rmps;
+
+use std::collections::{HashMap, HashSet};
+
+/// The path of the repository's default chunk settings
+const SETTINGS_PATH: &str = "manifest/chunk.settings";
+
+/// The manifest of a WebDAV repository
+///
+/// Every archive written adds its transaction to the manifest directory as a new file, as
+/// files can not be appended to over WebDAV.
+#[derive(Debug)]
+pub struct WebDavManifest {
+    client: DavClient,
+    known_entries: HashMap<ManifestID, ManifestTransaction>,
+    verified_memo_pad: HashSet<ManifestID>,
+    heads: Vec<ManifestID>,
+    key: Key,
+    chunk_settings: ChunkSettings,
+    /// The highest numbered transaction file seen so far
+    last_file: u64,
+}
+
+impl WebDavManifest {
+    /// Will attempt to open or create a manifest in the repository, writing out the given
+    /// chunk settings if any are provided
+    pub fn connect(
+        client: DavClient,
+        key: &Key,
+        chunk_settings: Option<ChunkSettings>,
+    ) -> Result<Self> {
+        client.mkcol("manifest")?;
+        let ids = client.numbered_files("manifest")?;
+        let mut known_entries = HashMap::new();
+        for id in &ids {
+            let buffer = client.get(&format!("manifest/{id}"))?;
+            let mut reader = &buffer[..];
+            // Keep deserializing transactions until we hit an error
+            while let Ok(tx) = rmps::decode::from_read::<_, ManifestTransaction>(&mut reader) {
+                known_entries.insert(tx.tag(), tx);
+            }
+        }
+
+        let chunk_settings = if let Some(chunk_settings) = chunk_settings {
+            client.put(SETTINGS_PATH, &rmps::encode::to_vec(&chunk_settings)?)?;
+            chunk_settings
+        } else {
+            rmps::decode::from_slice(&client.get(SETTINGS_PATH)?)?
+        };
+
+        let mut manifest = WebDavManifest {
+            client,
+            known_entries,
+            verified_memo_pad: HashSet::new(),
+            heads: Vec::new(),
+            key: key.clone(),
+            chunk_settings,
+            last_file: ids.last().copied().unwrap_or(0),
+        };
+        // Build the list of heads
+        manifest.build_heads();
+        // Verify each head
+        for head in manifest.heads.clone() {
+            if !manifest.verify_tx(head) {
+                return Err(BackendError::ManifestError(format!(
+                    "Manifest Transaction failed verification! {:?}",
+                    manifest.known_entries.get(&head)
+                )));
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Gets the heads from a list of transactions
+    fn build_heads(&mut self) {
+        // Create the graph
+        let mut graph: Graph<ManifestID, ()> = Graph::new();
+        let mut index_map = HashMap::new();
+        // Add each transaction to our map
+        for tx in self.known_entries.values() {
+            let tag = tx.tag();
+            let id = graph.add_node(tag);
+            index_map.insert(tag, id);
+        }
+        // Go through each transaction in the graph, adding an edge in the new -> old direction
+        // These unwraps are safe because we just added these entries to our hashmap
+        for tx in self.known_entries.values() {
+            let id = index_map.get(&tx.tag()).unwrap();
+            for other_tx in tx.previous_heads() {
+                // Transactions replaced by a checkpoint may no longer be around
+                if let Some(other_id) = index_map.get(other_tx) {
+                    graph.update_edge(*id, *other_id, ());
+                }
+            }
+        }
+        // reverse all the nodes, so they now point from old to new
+        graph.reverse();
+        // Find all nodes with no outgoing edges, these are our heads
+        let mut heads = Vec::new();
+        for (tag, id) in &index_map {
+            let mut edges = graph.edges(*id);
+            if edges.next().is_none() {
+                heads.push(*tag);
+            }
+        }
+
+        self.heads = heads;
+    }
+
+    /// Verifies a transaction and all of its parents
+    fn verify_tx(&mut self, id: ManifestID) -> bool {
+        if self.verified_memo_pad.contains(&id) {
+            true
+        } else {
+            let tx = self
+                .known_entries
+                .get(&id)
+                .expect("Item in verified memo pad was not in known_entries")
+                .clone();
+            if tx.verify(&self.key) {
+                self.verified_memo_pad.insert(id);
+                for parent in tx.previous_heads() {
+                    if !self.known_entries.contains_key(parent) {
+                        // Only checkpoints may refer to transactions that have been removed
+                        if may_be_missing(&tx, *parent) {
+                            continue;
+                        }
+                        return false;
+                    }
+                    if !self.verify_tx(*parent) {
+                        return false;
+                    }
+                }
+                true
+            } else {
+                false
+            }
+        }
+    }
+}
+
+impl SyncManifest for WebDavManifest {
+    type Iterator = std::vec::IntoIter<StoredArchive>;
+    fn last_modification(&mut self) -> Result<Timestamp> {
+        let timestamp = self
+            .heads
+            .iter()
+            .filter_map(|id| self.known_entries.get(id))
+            .map(ManifestTransaction::timestamp)
+            .max();
+        Ok(timestamp.unwrap_or_else(Timestamp::now))
+    }
+    fn chunk_settings(&mut self) -> ChunkSettings {
+        self.chunk_settings
+    }
+    fn archive_iterator(&mut self) -> Self::Iterator {
+        archives_from_transactions(self.known_entries.values()).into_iter()
+    }
+    fn write_chunk_settings(&mut self, chunk_settings: ChunkSettings) -> Result<()> {
+        self.client
+            .put(SETTINGS_PATH, &rmps::encode::to_vec(&chunk_settings)?)?;
+        self.chunk_settings = chunk_settings;
+        Ok(())
+    }
+    fn write_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        // Create the transaction
+        let tx = ManifestTransaction::new(
+            &self.heads,
+            archive.id(),
+            archive.timestamp(),
+            archive.name(),
+            archive.metadata().clone(),
+            archive.integrity().cloned(),
+            archive.signature().cloned(),
+            archive.duration(),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        let body = rmps::encode::to_vec(&tx)?;
+        self.last_file = self
+            .client
+            .create_numbered("manifest", self.last_file, &body)?;
+        // Add the transaction to our entries list
+        let id = tx.tag();
+        self.known_entries.insert(id, tx);
+        // Update our heads to only contain this transaction
+        self.heads = vec![id];
+        Ok(())
+    }
+    fn touch(&mut self) -> Result<()> {
+        // Touch doesn't actually do anything with this implementation
+        Ok(())
+    }
+}
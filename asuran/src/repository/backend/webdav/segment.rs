@@ -0,0 +1,406 @@
+use super::client::DavClient;
+use crate::metrics;
+use crate::repository::backend::common::segment::Segment;
+use crate::repository::backend::{BackendError, Result, SegmentDescriptor};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, Key};
+
+use lru::LruCache;
+use tracing::error;
+
+use std::convert::TryFrom;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+/// The number of bytes fetched by each ranged read of a segment
+///
+/// Reads are rounded up to this, so restoring neighbouring chunks does not cost a round trip
+/// for each of them.
+const READ_WINDOW: u64 = 1024 * 1024;
+
+/// A file on the WebDAV server, as seen by a `Segment`
+///
+/// WebDAV has no way to append to or modify part of a file, so segments are built up in
+/// memory and uploaded in full once they are closed, and are never written again after that.
+pub enum DavFile {
+    /// A file already on the server, fetched with ranged `GET`s as it is read
+    Remote {
+        client: DavClient,
+        path: String,
+        length: u64,
+        position: u64,
+        /// The most recently fetched part of the file, and the offset it starts at
+        window: (u64, Vec<u8>),
+    },
+    /// A file that has not been uploaded yet
+    Buffer(Arc<Mutex<Cursor<Vec<u8>>>>),
+}
+
+impl DavFile {
+    /// Opens a file on the server for reading, given its length
+    pub fn remote(client: DavClient, path: String, length: u64) -> DavFile {
+        DavFile::Remote {
+            client,
+            path,
+            length,
+            position: 0,
+            window: (0, Vec::new()),
+        }
+    }
+}
+
+impl Read for DavFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            DavFile::Remote {
+                client,
+                path,
+                length,
+                position,
+                window,
+            } => {
+                if *position >= *length || buf.is_empty() {
+                    return Ok(0);
+                }
+                let (start, bytes) = window;
+                let end = *start + bytes.len() as u64;
+                if *position < *start || *position >= end {
+                    let wanted = (buf.len() as u64).max(READ_WINDOW);
+                    let fetched = client
+                        .get_range(path, *position, wanted)
+                        .map_err(|e| io::Error::other(e.to_string()))?;
+                    if fetched.is_empty() {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *window = (*position, fetched);
+                }
+                let (start, bytes) = window;
+                let offset = usize::try_from(*position - *start)
+                    .expect("Position lies within the fetched window");
+                let count = buf.len().min(bytes.len() - offset);
+                buf[..count].copy_from_slice(&bytes[offset..offset + count]);
+                *position += count as u64;
+                Ok(count)
+            }
+            DavFile::Buffer(buffer) => buffer.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl Write for DavFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            DavFile::Remote { path, .. } => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{path} has already been uploaded and can not be written"),
+            )),
+            DavFile::Buffer(buffer) => buffer.lock().unwrap().write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for DavFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            DavFile::Remote {
+                length, position, ..
+            } => {
+                let new = match pos {
+                    SeekFrom::Start(offset) => Some(offset),
+                    SeekFrom::End(offset) => length.checked_add_signed(offset),
+                    SeekFrom::Current(offset) => position.checked_add_signed(offset),
+                };
+                *position = new.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position")
+                })?;
+                Ok(*position)
+            }
+            DavFile::Buffer(buffer) => buffer.lock().unwrap().seek(pos),
+        }
+    }
+}
+
+/// The segment being written, along with the buffers its data and header are built up in
+struct OpenSegment {
+    id: u64,
+    segment: Segment<DavFile>,
+    data: Arc<Mutex<Cursor<Vec<u8>>>>,
+    header: Arc<Mutex<Cursor<Vec<u8>>>>,
+    /// Whether any chunks have been written to the segment
+    dirty: bool,
+}
+
+pub struct WebDavSegmentHandler {
+    client: DavClient,
+    /// The segment currently being written, if there is one
+    current_segment: Option<OpenSegment>,
+    /// The lowest segment ID that may still be free
+    next_segment: u64,
+    /// The size limit of each segment in bytes
+    ///
+    /// As segments are held in memory until they are uploaded, this is also the most memory
+    /// the segment being written will take up.
+    size_limit: u64,
+    /// An LRU cache of recently read segments
+    ro_segment_cache: LruCache<u64, Segment<DavFile>>,
+    /// The number of segments per directory
+    segments_per_directory: u64,
+    /// The chunk settings used for encrypting headers
+    chunk_settings: ChunkSettings,
+    /// The key used for encrypting/decrypting headers
+    key: Key,
+}
+
+impl WebDavSegmentHandler {
+    pub fn connect(
+        client: DavClient,
+        size_limit: u64,
+        segments_per_directory: u64,
+        chunk_settings: ChunkSettings,
+        key: Key,
+    ) -> Result<WebDavSegmentHandler> {
+        client.mkcol("data")?;
+        let mut handler = WebDavSegmentHandler {
+            client,
+            current_segment: None,
+            next_segment: 0,
+            size_limit,
+            ro_segment_cache: LruCache::new(25),
+            segments_per_directory,
+            chunk_settings,
+            key,
+        };
+        handler.next_segment = handler
+            .list_segments(true)?
+            .into_iter()
+            .max()
+            .map_or(0, |highest| highest + 1);
+        Ok(handler)
+    }
+
+    /// Returns the path of the folder holding the segment
+    fn folder_path(&self, segment_id: u64) -> String {
+        format!("data/{}", segment_id / self.segments_per_directory)
+    }
+
+    /// Returns the paths of the data and header files of a segment
+    fn segment_paths(&self, segment_id: u64) -> (String, String) {
+        let folder = self.folder_path(segment_id);
+        (
+            format!("{folder}/{segment_id}"),
+            format!("{folder}/{segment_id}.header"),
+        )
+    }
+
+    /// Lists the ids of the segments in the data directory
+    ///
+    /// Segments that have been claimed, but not yet uploaded, are only included if
+    /// `include_claimed` is set.
+    fn list_segments(&self, include_claimed: bool) -> Result<Vec<u64>> {
+        let mut segments = Vec::new();
+        for folder in self.client.list("data")? {
+            if !folder.is_collection || folder.name.parse::<u64>().is_err() {
+                continue;
+            }
+            let files = self.client.list(&format!("data/{}", folder.name))?;
+            segments.extend(
+                files
+                    .into_iter()
+                    .filter(|file| !file.is_collection && (include_claimed || file.length > 0))
+                    .filter_map(|file| file.name.parse::<u64>().ok()),
+            );
+        }
+        Ok(segments)
+    }
+
+    /// Lists the ids of every segment in the repository
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if listing the data directory fails
+    pub fn segment_ids(&mut self) -> Result<Vec<u64>> {
+        self.list_segments(false)
+    }
+
+    /// Returns the id and location of every chunk in the given segment
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the segment can not be opened
+    pub fn segment_descriptors(
+        &mut self,
+        segment_id: u64,
+    ) -> Result<Vec<(ChunkID, SegmentDescriptor)>> {
+        if let Some(open) = self.current_segment.as_ref() {
+            if open.id == segment_id {
+                return Ok(open.segment.chunk_descriptors(segment_id));
+            }
+        }
+        Ok(self
+            .open_segment_read(segment_id)?
+            .chunk_descriptors(segment_id))
+    }
+
+    fn open_segment_read(&mut self, segment_id: u64) -> Result<&mut Segment<DavFile>> {
+        let cached = self.ro_segment_cache.contains(&segment_id);
+        metrics::segment_cache("webdav", cached);
+        if !cached {
+            let (data_path, header_path) = self.segment_paths(segment_id);
+            let length = match self.client.stat(&data_path)? {
+                Some(entry) if entry.length > 0 => entry.length,
+                _ => {
+                    return Err(BackendError::SegmentError(format!(
+                        "Segment with id {segment_id} does not exist, or has not been uploaded yet"
+                    )))
+                }
+            };
+            // Headers are small and always read in full, so fetch them in one go
+            let header = self.client.get(&header_path)?;
+            let segment = Segment::new(
+                DavFile::remote(self.client.clone(), data_path, length),
+                DavFile::Buffer(Arc::new(Mutex::new(Cursor::new(header)))),
+                self.size_limit,
+                self.chunk_settings,
+                self.key.clone(),
+            )?;
+            self.ro_segment_cache.put(segment_id, segment);
+        }
+        Ok(self.ro_segment_cache.get_mut(&segment_id).unwrap())
+    }
+
+    /// Claims the next free segment id and starts building the segment in memory
+    fn open_segment_write(&mut self) -> Result<&mut OpenSegment> {
+        if self.current_segment.is_none() {
+            let mut segment_id = self.next_segment;
+            loop {
+                let folder_path = self.folder_path(segment_id);
+                self.client.mkcol(&folder_path)?;
+                let (data_path, _) = self.segment_paths(segment_id);
+                // Uploading an empty placeholder claims the id, so other connections move on
+                if self.client.create(&data_path, &[])? {
+                    break;
+                }
+                segment_id += 1;
+            }
+            self.next_segment = segment_id + 1;
+            let data = Arc::new(Mutex::new(Cursor::new(Vec::new())));
+            let header = Arc::new(Mutex::new(Cursor::new(Vec::new())));
+            let segment = Segment::new(
+                DavFile::Buffer(Arc::clone(&data)),
+                DavFile::Buffer(Arc::clone(&header)),
+                self.size_limit,
+                self.chunk_settings,
+                self.key.clone(),
+            )?;
+            self.current_segment = Some(OpenSegment {
+                id: segment_id,
+                segment,
+                data,
+                header,
+                dirty: false,
+            });
+        }
+        Ok(self.current_segment.as_mut().unwrap())
+    }
+
+    /// Uploads the segment being written, if any chunks have been written to it
+    ///
+    /// The data is uploaded before the header, and the segment is closed for good, as it
+    /// can not be appended to once it is on the server.
+    fn close_segment(&mut self) -> Result<()> {
+        if let Some(mut open) = self.current_segment.take() {
+            if !open.dirty {
+                self.current_segment = Some(open);
+                return Ok(());
+            }
+            open.segment.flush()?;
+            let (data_path, header_path) = self.segment_paths(open.id);
+            let upload = |path: &str, buffer: &Arc<Mutex<Cursor<Vec<u8>>>>| {
+                let buffer = buffer.lock().unwrap();
+                self.client.put(path, buffer.get_ref())
+            };
+            let result =
+                upload(&data_path, &open.data).and_then(|()| upload(&header_path, &open.header));
+            if let Err(e) = result {
+                // Keep the segment around, so the upload can be tried again
+                self.current_segment = Some(open);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        if let Some(open) = self.current_segment.as_mut() {
+            if open.id == location.segment_id {
+                return open.segment.read_chunk(location.start);
+            }
+        }
+        self.open_segment_read(location.segment_id)?
+            .read_chunk(location.start)
+    }
+
+    pub fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        Ok(self.write_chunks(vec![chunk])?[0])
+    }
+
+    /// Writes several chunks, appending as many of them at once as the current segment has
+    /// room for, and uploading each segment as it fills up
+    pub fn write_chunks(&mut self, chunks: Vec<Chunk>) -> Result<Vec<SegmentDescriptor>> {
+        let size_limit = self.size_limit;
+        let mut descriptors = Vec::with_capacity(chunks.len());
+        let mut chunks = chunks.into_iter().peekable();
+        while chunks.peek().is_some() {
+            let open = self.open_segment_write()?;
+            let mut room = open.segment.free_bytes();
+            let mut batch = Vec::new();
+            // Always take at least one chunk, so oversized chunks still make progress
+            while let Some(chunk) =
+                chunks.next_if(|chunk| batch.is_empty() || chunk.get_bytes().len() as u64 <= room)
+            {
+                room = room.saturating_sub(chunk.get_bytes().len() as u64);
+                batch.push(chunk);
+            }
+            let segment_id = open.id;
+            let indexes = open.segment.write_chunks(batch)?;
+            open.dirty = true;
+            descriptors.extend(
+                indexes
+                    .into_iter()
+                    .map(|start| SegmentDescriptor { segment_id, start }),
+            );
+            if open.segment.size() >= size_limit {
+                self.close_segment()?;
+            }
+        }
+        Ok(descriptors)
+    }
+
+    /// Uploads the segment being written
+    pub fn flush(&mut self) -> Result<()> {
+        self.close_segment()
+    }
+}
+
+impl Drop for WebDavSegmentHandler {
+    fn drop(&mut self) {
+        if let Err(e) = self.close_segment() {
+            error!(
+                "Failed to upload segment while closing WebDAV backend: {}",
+                e
+            );
+        }
+    }
+}
+
+impl std::fmt::Debug for WebDavSegmentHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebDavSegmentHandler")
+            .field("client", &self.client)
+            .field("next_segment", &self.next_segment)
+            .field("size_limit", &self.size_limit)
+            .finish_non_exhaustive()
+    }
+}
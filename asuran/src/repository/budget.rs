@@ -0,0 +1,161 @@
+//! Limits how much chunk data can be in flight at once
+//!
+//! Storing an object hands every chunk the chunker produces off to its own task, which
+//! holds on to the chunk while it is packed and handed to the backend. Without a limit, a
+//! fast chunker and a slow backend can pile up gigabytes of chunks waiting their turn.
+//!
+//! A `MemoryBudget` is an async semaphore counting bytes: each chunk reserves its length
+//! before it is handed off, and gives it back once the backend has taken it, so the
+//! chunker is simply not polled while the budget is used up.
+use futures::channel::oneshot;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+struct BudgetState {
+    /// The number of bytes that can still be reserved
+    available: u64,
+    /// Reservations waiting for enough bytes to be returned, in the order they were made
+    waiters: VecDeque<(u64, oneshot::Sender<()>)>,
+}
+
+/// A limit on the number of bytes of chunk data in flight
+///
+/// Clones share the same budget.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    limit: u64,
+    state: Arc<Mutex<BudgetState>>,
+}
+
+/// Bytes reserved from a `MemoryBudget`, which are returned when this is dropped
+#[derive(Debug)]
+pub struct Reservation {
+    bytes: u64,
+    state: Arc<Mutex<BudgetState>>,
+}
+
+impl MemoryBudget {
+    /// Creates a budget allowing up to `limit` bytes to be reserved at once
+    ///
+    /// A limit of zero is treated as one byte, so that reservations can still be made one
+    /// at a time.
+    pub fn new(limit: u64) -> MemoryBudget {
+        let limit = limit.max(1);
+        MemoryBudget {
+            limit,
+            state: Arc::new(Mutex::new(BudgetState {
+                available: limit,
+                waiters: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Returns the number of bytes that can be reserved at once
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Returns the number of bytes that are currently not reserved
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the budget was poisoned
+    pub fn available(&self) -> u64 {
+        self.state.lock().expect("Budget lock poisoned").available
+    }
+
+    /// Reserves `bytes` bytes, waiting until enough have been returned if needed
+    ///
+    /// Reservations are granted in the order they were made, so a large one is not starved
+    /// by a stream of small ones. Reservations larger than the whole budget wait for the
+    /// entire budget to be free, and take all of it.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the budget was poisoned
+    pub async fn reserve(&self, bytes: u64) -> Reservation {
+        let bytes = bytes.min(self.limit);
+        let granted = {
+            let mut state = self.state.lock().expect("Budget lock poisoned");
+            if state.waiters.is_empty() && state.available >= bytes {
+                state.available -= bytes;
+                None
+            } else {
+                let (sender, receiver) = oneshot::channel();
+                state.waiters.push_back((bytes, sender));
+                Some(receiver)
+            }
+        };
+        if let Some(granted) = granted {
+            // The sender is only ever dropped after the bytes have been set aside for us
+            granted
+                .await
+                .expect("Memory budget dropped a waiting reservation");
+        }
+        Reservation {
+            bytes,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("Budget lock poisoned");
+        state.available += self.bytes;
+        while let Some((bytes, _)) = state.waiters.front() {
+            if *bytes > state.available {
+                break;
+            }
+            let (bytes, sender) = state.waiters.pop_front().unwrap();
+            // Waiters that gave up leave their bytes in the budget
+            if sender.send(()).is_ok() {
+                state.available -= bytes;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::FutureExt;
+
+    #[test]
+    fn reserve_and_return() {
+        smol::run(async {
+            let budget = MemoryBudget::new(100);
+            let first = budget.reserve(60).await;
+            assert_eq!(budget.available(), 40);
+            // Does not fit until the first reservation is returned
+            let mut second = Box::pin(budget.reserve(50));
+            assert!((&mut second).now_or_never().is_none());
+            // Later reservations wait their turn, even if they would fit
+            let mut third = Box::pin(budget.reserve(10));
+            assert!((&mut third).now_or_never().is_none());
+            drop(first);
+            let second = second.await;
+            let third = third.await;
+            assert_eq!(budget.available(), 40);
+            drop((second, third));
+            assert_eq!(budget.available(), 100);
+        });
+    }
+
+    #[test]
+    fn oversized_and_abandoned() {
+        smol::run(async {
+            let budget = MemoryBudget::new(100);
+            // Larger than the budget, so takes all of it
+            let whole = budget.reserve(1000).await;
+            assert_eq!(budget.available(), 0);
+            let mut abandoned = Box::pin(budget.reserve(10));
+            assert!((&mut abandoned).now_or_never().is_none());
+            drop(abandoned);
+            drop(whole);
+            assert_eq!(budget.available(), 100);
+        });
+    }
+}
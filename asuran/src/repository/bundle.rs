@@ -0,0 +1,515 @@
+//! Exports an entire repository as a single, portable bundle stream, and imports it
+//! back into a repository on any backend.
+//!
+//! A bundle starts with a short magic number and version, followed by a series of
+//! length prefixed, `MessagePack` encoded records:
+//!
+//! 1. The repository's encrypted key
+//! 2. The repository's chunk settings and list of archives, packed into a chunk
+//! 3. Every chunk in the repository, exactly as it is stored in the backend
+//! 4. A trailer, packed into a chunk, containing the number of chunks and a BLAKE3
+//!    hash of everything in the bundle before it
+//!
+//! As every chunk is copied as is, the contents of the bundle are encrypted with the
+//! repository's key, and the bundle can only be imported into a repository using that
+//! same key. The trailer is protected by the repository's HMAC, so truncation,
+//! reordering, or tampering with any part of the bundle is detected.
+//!
+//! Bundles can be split across several fixed size volumes with `VolumeWriter`, and read
+//! back with `VolumeReader`.
+#![allow(clippy::used_underscore_binding)] // TODO: Fix this after clippy and thiserror start
+                                           // playing nice
+use crate::manifest::StoredArchive;
+use crate::repository::backend::{BackendError, Manifest};
+use crate::repository::{
+    BackendClone, Chunk, ChunkID, ChunkSettings, EncryptedKey, Repository, RepositoryError,
+};
+
+use asuran_core::repository::chunk::ChunkError;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The magic number at the start of every bundle
+const MAGIC: &[u8; 12] = b"ASURANBUNDLE";
+
+/// The version of the bundle format written by this version of asuran
+const VERSION: u8 = 1;
+
+/// The largest record that will be read from a bundle
+///
+/// Chunks are never anywhere near this large, so anything larger is a sign of damage.
+const MAX_RECORD_LENGTH: u64 = 1 << 32;
+
+/// An error for things that can go wrong while exporting or importing a bundle
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("I/O Error: {0}")]
+    IOError(#[from] io::Error),
+    #[error("MessagePack Decode Error")]
+    MsgPackDecodeError(#[from] rmp_serde::decode::Error),
+    #[error("MessagePack Encode Error")]
+    MsgPackEncodeError(#[from] rmp_serde::encode::Error),
+    #[error("Repository Error: {0}")]
+    RepositoryError(#[from] RepositoryError),
+    #[error("Backend Error: {0}")]
+    BackendError(#[from] BackendError),
+    #[error("Chunk Error: {0}")]
+    ChunkError(#[from] ChunkError),
+    #[error("Not an asuran bundle")]
+    BadMagic,
+    #[error("Unsupported bundle version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Malformed bundle: {0}")]
+    Malformed(String),
+    #[error("Bundle failed integrity verification: {0}")]
+    IntegrityFailure(String),
+}
+
+type Result<T> = std::result::Result<T, BundleError>;
+
+/// Summary of the contents of an exported or imported bundle
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct BundleStats {
+    /// The number of chunks in the bundle
+    pub chunks: u64,
+    /// The number of archives in the bundle
+    pub archives: usize,
+    /// The total length of the bundle, in bytes
+    pub bytes: u64,
+}
+
+/// A single record of a bundle
+#[derive(Serialize, Deserialize, Debug)]
+enum Record {
+    Key(EncryptedKey),
+    Manifest(Chunk),
+    Chunk(Chunk),
+    Trailer(Chunk),
+}
+
+/// The repository level state carried in a bundle
+#[derive(Serialize, Deserialize, Debug)]
+struct BundleManifest {
+    settings: ChunkSettings,
+    /// Oldest first
+    archives: Vec<StoredArchive>,
+}
+
+/// The last record of a bundle
+#[derive(Serialize, Deserialize, Debug)]
+struct Trailer {
+    chunks: u64,
+    /// BLAKE3 hash of every byte of the bundle before the trailer
+    hash: Vec<u8>,
+}
+
+/// Writes length prefixed records, keeping a running hash of everything written
+struct RecordWriter<W: Write> {
+    writer: W,
+    hasher: blake3::Hasher,
+    bytes: u64,
+}
+
+impl<W: Write> RecordWriter<W> {
+    fn write_raw(&mut self, bytes: &[u8], hash: bool) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        if hash {
+            self.hasher.update(bytes);
+        }
+        self.bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &Record) -> Result<()> {
+        let bytes = rmp_serde::to_vec(record)?;
+        let length = bytes.len() as u64;
+        let hash = !matches!(record, Record::Trailer(_));
+        self.write_raw(&length.to_le_bytes(), hash)?;
+        self.write_raw(&bytes, hash)
+    }
+}
+
+/// Reads length prefixed records, keeping a running hash of everything but the trailer
+struct RecordReader<R: Read> {
+    reader: R,
+    hasher: blake3::Hasher,
+    bytes: u64,
+}
+
+impl<R: Read> RecordReader<R> {
+    fn read_record(&mut self) -> Result<Record> {
+        let mut length = [0_u8; 8];
+        self.reader.read_exact(&mut length)?;
+        let length = u64::from_le_bytes(length);
+        if length > MAX_RECORD_LENGTH {
+            return Err(BundleError::Malformed(format!(
+                "Record of {length} bytes is too large"
+            )));
+        }
+        let mut bytes = vec![0_u8; length.try_into().expect("Record too large for memory")];
+        self.reader.read_exact(&mut bytes)?;
+        self.bytes += 8 + length;
+        let record: Record = rmp_serde::from_slice(&bytes)?;
+        // The trailer carries the hash of everything before it, so it is not hashed itself
+        if !matches!(record, Record::Trailer(_)) {
+            self.hasher.update(&u64::to_le_bytes(length));
+            self.hasher.update(&bytes);
+        }
+        Ok(record)
+    }
+}
+
+/// Writes every chunk and archive in a repository, along with its key, to `writer` as
+/// a bundle
+///
+/// `encrypted_key` must be the key material stored in the repository's backend, so that
+/// the bundle can be opened with the same password.
+///
+/// # Errors
+///
+/// Will return `Err` if any chunk can not be read from the repository, or if writing
+/// the bundle fails
+pub async fn export_bundle(
+    repository: &mut Repository<impl BackendClone>,
+    encrypted_key: &EncryptedKey,
+    writer: impl Write,
+) -> Result<BundleStats> {
+    let mut writer = RecordWriter {
+        writer,
+        hasher: blake3::Hasher::new(),
+        bytes: 0,
+    };
+    writer.write_raw(MAGIC, true)?;
+    writer.write_raw(&[VERSION], true)?;
+    writer.write_record(&Record::Key(encrypted_key.clone()))?;
+
+    let settings = repository.chunk_settings();
+    let mut archives: Vec<StoredArchive> = repository
+        .backend_manifest()
+        .archive_iterator()
+        .await
+        .collect();
+    archives.sort_by_key(StoredArchive::timestamp);
+    let manifest = BundleManifest { settings, archives };
+    let archive_count = manifest.archives.len();
+    let manifest = pack(repository, &rmp_serde::to_vec(&manifest)?);
+    writer.write_record(&Record::Manifest(manifest))?;
+
+    // Sort the chunks, so their order does not depend on the backend's index
+    let mut ids: Vec<ChunkID> = repository.known_chunks().await.into_iter().collect();
+    ids.sort_by(|a, b| a.get_id().cmp(b.get_id()));
+    let chunks = ids.len() as u64;
+    for id in ids {
+        let chunk = repository.read_raw(id).await?;
+        writer.write_record(&Record::Chunk(chunk))?;
+    }
+
+    let trailer = Trailer {
+        chunks,
+        hash: writer.hasher.finalize().as_bytes().to_vec(),
+    };
+    let trailer = pack(repository, &rmp_serde::to_vec(&trailer)?);
+    writer.write_record(&Record::Trailer(trailer))?;
+    writer.writer.flush()?;
+
+    Ok(BundleStats {
+        chunks,
+        archives: archive_count,
+        bytes: writer.bytes,
+    })
+}
+
+/// Packs bundle metadata into a chunk with the repository's settings and key
+fn pack(repository: &Repository<impl BackendClone>, data: &[u8]) -> Chunk {
+    let settings = repository.chunk_settings();
+    Chunk::pack(
+        data.to_vec(),
+        settings.compression,
+        settings.encryption,
+        settings.hmac,
+        repository.key(),
+    )
+}
+
+/// Reads a bundle back into a repository
+///
+/// The encrypted key is available as soon as the reader is opened, so that the
+/// repository the bundle is imported into can be created with it.
+pub struct BundleReader<R: Read> {
+    reader: RecordReader<R>,
+    encrypted_key: EncryptedKey,
+}
+
+impl<R: Read> BundleReader<R> {
+    /// Opens a bundle, reading its header and key
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the stream is not a bundle, or was written by a newer
+    /// version of asuran
+    pub fn open(reader: R) -> Result<BundleReader<R>> {
+        let mut reader = RecordReader {
+            reader,
+            hasher: blake3::Hasher::new(),
+            bytes: 0,
+        };
+        let mut header = [0_u8; 13];
+        reader.reader.read_exact(&mut header)?;
+        reader.hasher.update(&header);
+        reader.bytes += header.len() as u64;
+        if header[..MAGIC.len()] != MAGIC[..] {
+            return Err(BundleError::BadMagic);
+        }
+        if header[MAGIC.len()] != VERSION {
+            return Err(BundleError::UnsupportedVersion(header[MAGIC.len()]));
+        }
+        match reader.read_record()? {
+            Record::Key(encrypted_key) => Ok(BundleReader {
+                reader,
+                encrypted_key,
+            }),
+            _ => Err(BundleError::Malformed(
+                "Bundle does not start with a key".to_string(),
+            )),
+        }
+    }
+
+    /// Returns the key material of the repository the bundle was exported from
+    pub fn encrypted_key(&self) -> &EncryptedKey {
+        &self.encrypted_key
+    }
+
+    /// Writes every chunk in the bundle to `repository`, and then, once the whole bundle
+    /// has been verified, its chunk settings and archives
+    ///
+    /// `repository` must be using the key the bundle was exported with. Chunks already
+    /// present in the repository are not rewritten.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the bundle is damaged, truncated, or was exported with a
+    /// different key. Chunks read before the damage was found will have been written to
+    /// the repository, but no archives will have been, so the repository should be
+    /// discarded.
+    pub async fn import(
+        mut self,
+        repository: &mut Repository<impl BackendClone>,
+    ) -> Result<BundleStats> {
+        let key = repository.shared_key();
+        let manifest: BundleManifest = match self.reader.read_record()? {
+            Record::Manifest(chunk) => rmp_serde::from_slice(&chunk.unpack(&key)?)?,
+            _ => {
+                return Err(BundleError::Malformed(
+                    "Bundle is missing its manifest".to_string(),
+                ))
+            }
+        };
+
+        let mut chunks = 0;
+        let trailer: Trailer = loop {
+            match self.reader.read_record()? {
+                Record::Chunk(chunk) => {
+                    if !chunk.verify_mac(&key) {
+                        return Err(BundleError::IntegrityFailure(format!(
+                            "Chunk {:?} failed to verify",
+                            chunk.get_id()
+                        )));
+                    }
+                    repository.write_raw(chunk).await?;
+                    chunks += 1;
+                }
+                Record::Trailer(chunk) => break rmp_serde::from_slice(&chunk.unpack(&key)?)?,
+                _ => {
+                    return Err(BundleError::Malformed(
+                        "Unexpected record in bundle".to_string(),
+                    ))
+                }
+            }
+        };
+        if trailer.chunks != chunks {
+            return Err(BundleError::IntegrityFailure(format!(
+                "Expected {} chunks, found {}",
+                trailer.chunks, chunks
+            )));
+        }
+        if trailer.hash != self.reader.hasher.finalize().as_bytes() {
+            return Err(BundleError::IntegrityFailure(
+                "Bundle contents do not match their hash".to_string(),
+            ));
+        }
+
+        repository.commit_index().await;
+        let mut backend_manifest = repository.backend_manifest();
+        backend_manifest
+            .write_chunk_settings(manifest.settings)
+            .await?;
+        let archives = manifest.archives.len();
+        for archive in manifest.archives {
+            backend_manifest.write_archive(archive).await?;
+        }
+
+        Ok(BundleStats {
+            chunks,
+            archives,
+            bytes: self.reader.bytes,
+        })
+    }
+}
+
+/// Returns the path of a particular volume of a bundle
+pub fn volume_path(base: &Path, volume: usize) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{volume:03}"));
+    PathBuf::from(path)
+}
+
+/// Splits a bundle across a series of files, each no larger than `volume_size`
+///
+/// Volumes are named after `base`, with a three digit volume number appended as an
+/// extension, starting from `.000`.
+pub struct VolumeWriter {
+    base: PathBuf,
+    volume_size: u64,
+    volume: usize,
+    current: Option<File>,
+    written: u64,
+}
+
+impl VolumeWriter {
+    /// Creates a new `VolumeWriter`, no files are created until data is written
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `volume_size` is zero
+    pub fn new(base: impl AsRef<Path>, volume_size: u64) -> VolumeWriter {
+        assert!(volume_size > 0, "Volume size must be non-zero");
+        VolumeWriter {
+            base: base.as_ref().to_path_buf(),
+            volume_size,
+            volume: 0,
+            current: None,
+            written: 0,
+        }
+    }
+
+    /// Returns the number of volumes that have been created so far
+    pub fn volumes(&self) -> usize {
+        self.volume + usize::from(self.current.is_some())
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written == self.volume_size {
+            if let Some(mut file) = self.current.take() {
+                file.flush()?;
+                self.volume += 1;
+            }
+            self.written = 0;
+        }
+        if self.current.is_none() {
+            self.current = Some(File::create(volume_path(&self.base, self.volume))?);
+        }
+        let file = self.current.as_mut().expect("Volume was just opened");
+        let space = (self.volume_size - self.written)
+            .try_into()
+            .unwrap_or(usize::MAX);
+        let written = file.write(&buf[..buf.len().min(space)])?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads a bundle that was split into volumes by `VolumeWriter`, in order, until the
+/// next volume does not exist
+pub struct VolumeReader {
+    base: PathBuf,
+    volume: usize,
+    current: Option<File>,
+}
+
+impl VolumeReader {
+    /// Opens the first volume of a bundle
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the first volume can not be opened
+    pub fn open(base: impl AsRef<Path>) -> io::Result<VolumeReader> {
+        let base = base.as_ref().to_path_buf();
+        let current = Some(File::open(volume_path(&base, 0))?);
+        Ok(VolumeReader {
+            base,
+            volume: 0,
+            current,
+        })
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(file) = &mut self.current {
+            let read = file.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.volume += 1;
+            let path = volume_path(&self.base, self.volume);
+            self.current = if path.exists() {
+                Some(File::open(path)?)
+            } else {
+                None
+            };
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn volumes_round_trip() {
+        let tempdir = tempdir().unwrap();
+        let base = tempdir.path().join("bundle");
+        let data: Vec<u8> = (0..10_000_u32).map(|x| x.to_le_bytes()[0]).collect();
+        let mut writer = VolumeWriter::new(&base, 1024);
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.volumes(), 10);
+        assert_eq!(
+            std::fs::metadata(volume_path(&base, 0)).unwrap().len(),
+            1024
+        );
+        assert!(!volume_path(&base, 10).exists());
+        let mut output = Vec::new();
+        VolumeReader::open(&base)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let bytes = b"NOTABUNDLE!!\x01".to_vec();
+        match BundleReader::open(&bytes[..]) {
+            Err(BundleError::BadMagic) => (),
+            x => panic!("Unexpected result: {:?}", x.map(|_| ())),
+        }
+    }
+}
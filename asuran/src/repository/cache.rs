@@ -0,0 +1,164 @@
+//! Keeps the plaintext of recently read chunks in memory
+//!
+//! Archives with a lot of deduplication inside of them refer to the same chunks over and
+//! over, and filesystem mounts tend to read the same regions of a file several times. Without
+//! a cache, every one of those references reads the chunk back from the backend, then checks,
+//! decrypts, and decompresses it all over again.
+//!
+//! A `ChunkCache` holds the unpacked plaintext of chunks, keyed by their `ChunkID`, evicting
+//! the least recently used chunks once the plaintext it holds would go over its limit in bytes.
+//! Only chunks that have been checked against their MAC are ever added, so a hit is exactly as
+//! trustworthy as reading the chunk again.
+use asuran_core::repository::chunk::ChunkID;
+use lru::LruCache;
+
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+struct CacheState {
+    /// The cached plaintexts, from most to least recently used
+    chunks: LruCache<ChunkID, Arc<Vec<u8>>>,
+    /// The bytes of plaintext currently held
+    size: u64,
+}
+
+/// A cache of chunk plaintexts, bounded in bytes
+///
+/// Clones share the same cache.
+#[derive(Debug, Clone)]
+pub struct ChunkCache {
+    limit: u64,
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl ChunkCache {
+    /// Creates a cache holding up to `limit` bytes of plaintext
+    pub fn new(limit: u64) -> ChunkCache {
+        ChunkCache {
+            limit,
+            state: Arc::new(Mutex::new(CacheState {
+                chunks: LruCache::unbounded(),
+                size: 0,
+            })),
+        }
+    }
+
+    /// Returns the number of bytes of plaintext this cache can hold
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Returns the number of bytes of plaintext currently held
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the cache was poisoned
+    pub fn size(&self) -> u64 {
+        self.state.lock().expect("Cache lock poisoned").size
+    }
+
+    /// Returns the number of chunks currently held
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the cache was poisoned
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("Cache lock poisoned").chunks.len()
+    }
+
+    /// Returns true if the cache holds no chunks
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the cache was poisoned
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Looks up the plaintext of a chunk, marking it as the most recently used
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the cache was poisoned
+    pub fn get(&self, id: ChunkID) -> Option<Arc<Vec<u8>>> {
+        let hit = self
+            .state
+            .lock()
+            .expect("Cache lock poisoned")
+            .chunks
+            .get(&id)
+            .cloned();
+        crate::metrics::chunk_cache(hit.is_some());
+        hit
+    }
+
+    /// Adds the plaintext of a chunk, evicting the least recently used chunks until it fits
+    ///
+    /// Plaintexts larger than the whole cache are not added.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the cache was poisoned
+    pub fn insert(&self, id: ChunkID, plaintext: Arc<Vec<u8>>) {
+        let length = plaintext.len() as u64;
+        if length > self.limit {
+            return;
+        }
+        let mut state = self.state.lock().expect("Cache lock poisoned");
+        if let Some(old) = state.chunks.put(id, plaintext) {
+            state.size -= old.len() as u64;
+        }
+        state.size += length;
+        while state.size > self.limit {
+            match state.chunks.pop_lru() {
+                Some((_, evicted)) => state.size -= evicted.len() as u64,
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(byte: u8) -> ChunkID {
+        ChunkID::new(&[byte; 32])
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ChunkCache::new(300);
+        cache.insert(id(0), Arc::new(vec![0; 100]));
+        cache.insert(id(1), Arc::new(vec![1; 100]));
+        cache.insert(id(2), Arc::new(vec![2; 100]));
+        assert_eq!(cache.size(), 300);
+        // Touch the oldest chunk, so the second one is evicted instead
+        assert!(cache.get(id(0)).is_some());
+        cache.insert(id(3), Arc::new(vec![3; 100]));
+        assert_eq!(cache.size(), 300);
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(id(1)).is_none());
+        assert_eq!(*cache.get(id(0)).unwrap(), vec![0; 100]);
+        assert_eq!(*cache.get(id(3)).unwrap(), vec![3; 100]);
+    }
+
+    #[test]
+    fn oversized_chunks_are_skipped() {
+        let cache = ChunkCache::new(100);
+        cache.insert(id(0), Arc::new(vec![0; 50]));
+        cache.insert(id(1), Arc::new(vec![1; 101]));
+        assert!(cache.get(id(1)).is_none());
+        assert!(cache.get(id(0)).is_some());
+        assert_eq!(cache.size(), 50);
+    }
+
+    #[test]
+    fn reinserting_replaces() {
+        let cache = ChunkCache::new(100);
+        cache.insert(id(0), Arc::new(vec![0; 60]));
+        cache.insert(id(0), Arc::new(vec![0; 60]));
+        assert_eq!(cache.size(), 60);
+        assert_eq!(cache.len(), 1);
+    }
+}
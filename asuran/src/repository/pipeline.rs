@@ -1,22 +1,212 @@
-use crate::repository::{Chunk, Compression, Encryption, Key, HMAC};
+use crate::chunker::Compressibility;
+use crate::metrics;
+use crate::repository::{Chunk, ChunkIDSettings, Compression, Encryption, Key, Padding, HMAC};
+
+use asuran_core::repository::compression::ZStdDictionary;
 
 use futures::channel::oneshot;
 use smol::block_on;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use tracing::instrument;
+use std::time::Duration;
+use tracing::{instrument, trace, trace_span};
+
+/// How many times the number of packing tasks the in-flight limit may grow to
+const MAX_IN_FLIGHT_FACTOR: usize = 8;
+/// How far the smoothed write latency may rise above the lowest seen before the backend is
+/// considered to be falling behind
+const CONGESTION_FACTOR: f64 = 2.0;
+/// Weight given to each new latency sample in the smoothed latency
+const LATENCY_SMOOTHING: f64 = 0.1;
 
 #[derive(Debug)]
 struct Message {
     compression: Compression,
     encryption: Encryption,
     hmac: HMAC,
-    key: Key,
+    id: ChunkIDSettings,
+    key: Arc<Key>,
+    dictionary: Option<Arc<ZStdDictionary>>,
+    /// Estimated compressibility of the chunk, if the level of compression should be
+    /// picked to suit it
+    compressibility: Option<Compressibility>,
+    padding: Padding,
     ret_chunk: oneshot::Sender<Chunk>,
 }
 
+#[derive(Debug)]
+struct LimitState {
+    /// The current limit on chunks in flight, kept fractional so it can grow slowly
+    limit: f64,
+    min: usize,
+    max: usize,
+    in_flight: usize,
+    /// Admissions waiting for a chunk to leave, in the order they arrived
+    waiters: VecDeque<oneshot::Sender<()>>,
+    /// The lowest write latency seen, a stand in for how fast the backend is when idle
+    min_latency: Option<Duration>,
+    /// Exponentially smoothed write latency
+    smoothed_latency: Option<f64>,
+    /// Completions left before the limit may be cut again
+    cooldown: usize,
+}
+
+/// Adapts the number of chunks allowed in flight between being handed to the pipeline and
+/// being accepted by the backend
+///
+/// The limit grows by about one chunk each time a limit's worth of chunks complete while
+/// the backend keeps up, and is cut by a quarter when the smoothed write latency rises
+/// well above the lowest seen, which is what a backend falling behind looks like from here.
+/// It never drops below the number of packing tasks, so they can always be kept busy.
+#[derive(Debug, Clone)]
+pub struct AdaptiveLimit {
+    state: Arc<Mutex<LimitState>>,
+}
+
+/// A chunk admitted by an `AdaptiveLimit`
+///
+/// Dropping this without calling `complete` lets the next chunk in without adjusting the
+/// limit.
+#[derive(Debug)]
+pub struct Admission {
+    state: Arc<Mutex<LimitState>>,
+}
+
+// The limit only ever holds small, positive, values
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+impl AdaptiveLimit {
+    /// Creates a limit that starts at, and never goes below, `min` chunks, and never grows
+    /// past `max` chunks
+    pub fn new(min: usize, max: usize) -> AdaptiveLimit {
+        let min = min.max(1);
+        let max = max.max(min);
+        AdaptiveLimit {
+            state: Arc::new(Mutex::new(LimitState {
+                limit: min as f64,
+                min,
+                max,
+                in_flight: 0,
+                waiters: VecDeque::new(),
+                min_latency: None,
+                smoothed_latency: None,
+                cooldown: 0,
+            })),
+        }
+    }
+
+    /// Returns the current limit on chunks in flight
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the limit was poisoned
+    pub fn limit(&self) -> usize {
+        let state = self.state.lock().expect("Limit lock poisoned");
+        state.limit as usize
+    }
+
+    /// Waits until another chunk may be put in flight
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the limit was poisoned
+    pub async fn admit(&self) -> Admission {
+        let waiting = {
+            let mut state = self.state.lock().expect("Limit lock poisoned");
+            if state.waiters.is_empty() && state.in_flight < state.limit as usize {
+                state.in_flight += 1;
+                None
+            } else {
+                let (sender, receiver) = oneshot::channel();
+                state.waiters.push_back(sender);
+                Some(receiver)
+            }
+        };
+        if let Some(waiting) = waiting {
+            // The sender is only ever dropped after the slot has been handed to us
+            waiting
+                .await
+                .expect("Adaptive limit dropped a waiting admission");
+        }
+        Admission {
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+impl Admission {
+    /// Marks the chunk as accepted by the backend, `latency` after it was handed to it,
+    /// adjusting the limit accordingly
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the lock on the limit was poisoned
+    pub fn complete(self, latency: Duration) {
+        let mut state = self.state.lock().expect("Limit lock poisoned");
+        let min_latency = state.min_latency.map_or(latency, |x| x.min(latency));
+        state.min_latency = Some(min_latency);
+        let sample = latency.as_secs_f64();
+        let smoothed = state.smoothed_latency.map_or(sample, |x| {
+            x * (1.0 - LATENCY_SMOOTHING) + sample * LATENCY_SMOOTHING
+        });
+        state.smoothed_latency = Some(smoothed);
+        state.cooldown = state.cooldown.saturating_sub(1);
+        let congested = smoothed > min_latency.as_secs_f64() * CONGESTION_FACTOR;
+        if congested && state.cooldown == 0 {
+            state.limit = (state.limit * 0.75).max(state.min as f64);
+            // Give the cut a chance to take effect before cutting again
+            state.cooldown = state.limit as usize;
+            trace!(
+                "Backend falling behind, in flight limit now {}",
+                state.limit
+            );
+        } else if !congested {
+            state.limit = (state.limit + 1.0 / state.limit).min(state.max as f64);
+        }
+    }
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("Limit lock poisoned");
+        state.in_flight -= 1;
+        while state.in_flight < state.limit as usize {
+            match state.waiters.pop_front() {
+                // Waiters that gave up do not take a slot
+                Some(waiter) => {
+                    if waiter.send(()).is_ok() {
+                        state.in_flight += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Packs chunks on a pool of threads
+///
+/// Chunks have to be admitted by the pipeline's `AdaptiveLimit` before they are packed, so
+/// a slow backend holds back packing, rather than letting packed chunks pile up waiting to
+/// be written.
 #[derive(Clone)]
 pub struct Pipeline {
     input: piper::Sender<(Vec<u8>, Message)>,
+    limit: AdaptiveLimit,
 }
 
 impl Pipeline {
@@ -32,37 +222,79 @@ impl Pipeline {
             thread::spawn(move || {
                 while let Some(input) = block_on(rx.recv()) {
                     let (chunk, message): (Vec<u8>, Message) = input;
-                    let c = Chunk::pack(
+                    let length = chunk.len() as u64;
+                    let span = trace_span!("Packing chunk", length);
+                    let _guard = span.enter();
+                    let id = message.id.derive(&chunk, message.hmac, &message.key);
+                    let compression = match message.compressibility {
+                        Some(estimate) => message.compression.for_entropy(estimate.entropy),
+                        None => message.compression,
+                    };
+                    let c = Chunk::pack_padded(
                         chunk,
-                        message.compression,
+                        compression,
                         message.encryption,
                         message.hmac,
                         &message.key,
+                        id,
+                        message.dictionary.as_deref(),
+                        message.padding,
                     );
+                    metrics::chunk_packed(length, c.len() as u64);
                     // If sending to this channel fails, we have no way to communicate to
                     // the outside anymore. Just let this task die.
                     message.ret_chunk.send(c).unwrap();
                 }
             });
         }
-        Pipeline { input }
+        Pipeline {
+            input,
+            limit: AdaptiveLimit::new(task_count, task_count * MAX_IN_FLIGHT_FACTOR),
+        }
     }
 
-    #[instrument(skip(self, data))]
+    /// Waits until another chunk may be packed and written
+    ///
+    /// The returned `Admission` should be completed once the backend has accepted the
+    /// chunk.
+    pub async fn admit(&self) -> Admission {
+        self.limit.admit().await
+    }
+
+    /// Returns the number of chunks currently allowed to be in flight
+    pub fn in_flight_limit(&self) -> usize {
+        self.limit.limit()
+    }
+
+    /// Packs a chunk on one of the pipeline's tasks
+    ///
+    /// If an estimate of the chunk's compressibility is provided, the level of
+    /// `compression` is adjusted to suit it, see `Compression::for_entropy`. The compressed
+    /// data is padded with `padding` before it is encrypted.
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, data, key, dictionary))]
     pub async fn process(
         &self,
         data: Vec<u8>,
         compression: Compression,
         encryption: Encryption,
         hmac: HMAC,
-        key: Key,
+        id: ChunkIDSettings,
+        key: Arc<Key>,
+        dictionary: Option<Arc<ZStdDictionary>>,
+        compressibility: Option<Compressibility>,
+        padding: Padding,
     ) -> Chunk {
         let (c_tx, c_rx) = oneshot::channel();
         let message = Message {
             compression,
             encryption,
             hmac,
+            id,
             key,
+            dictionary,
+            compressibility,
+            padding,
             ret_chunk: c_tx,
         };
         let input = self.input.clone();
@@ -78,3 +310,39 @@ impl Default for Pipeline {
         Self::new(num_cpus::get_physical())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::FutureExt;
+
+    #[test]
+    fn limit_adapts() {
+        smol::run(async {
+            let limit = AdaptiveLimit::new(2, 8);
+            assert_eq!(limit.limit(), 2);
+            // A backend that keeps up lets the limit grow to its maximum
+            for _ in 0..100 {
+                limit.admit().await.complete(Duration::from_millis(10));
+            }
+            assert_eq!(limit.limit(), 8);
+            // A backend that slows down shrinks it back to the minimum
+            for _ in 0..100 {
+                limit.admit().await.complete(Duration::from_millis(100));
+            }
+            assert_eq!(limit.limit(), 2);
+        });
+    }
+
+    #[test]
+    fn admissions_wait() {
+        smol::run(async {
+            let limit = AdaptiveLimit::new(1, 1);
+            let first = limit.admit().await;
+            let mut second = Box::pin(limit.admit());
+            assert!((&mut second).now_or_never().is_none());
+            drop(first);
+            second.await.complete(Duration::from_millis(1));
+        });
+    }
+}
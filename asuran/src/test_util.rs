@@ -0,0 +1,603 @@
+//! Generators and round trip assertions for testing `Backend` implementations
+//!
+//! This module is only compiled in with the `test-util` feature (or in asuran's own tests),
+//! and is intended for the test suites of backends, in tree or out, to validate that they
+//! faithfully store what they are handed.
+//!
+//! Everything here is driven by a seeded `StdRng`, so a failing case can be replayed.
+//! `for_each_seed` runs a property over a number of random seeds, and prints the seed of
+//! the first failing case, which can then be rerun on its own by setting the
+//! `ASURAN_TEST_SEED` environment variable.
+//!
+//! # Example
+//!
+//! ```
+//! use asuran::repository::backend::mem::Mem;
+//! use asuran::repository::*;
+//! use asuran::test_util::*;
+//!
+//! for_each_seed(4, |rng| {
+//!     let settings = random_chunk_settings(rng);
+//!     let key = random_key(rng);
+//!     let backend = Mem::new(settings, key.clone(), 4);
+//!     let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+//!     let chunks = random_chunks(rng, 8, 16 * 1024);
+//!     smol::run(async {
+//!         assert_chunks_round_trip(&mut repo, &chunks).await;
+//!         repo.close().await;
+//!     });
+//! });
+//! ```
+use crate::manifest::archive::ArchiveMetadata;
+use crate::manifest::driver::{BackupDriver, RestoreDriver};
+use crate::manifest::target::filesystem::FileSystemTarget;
+use crate::manifest::target::{BackupTarget, RestoreTarget};
+use crate::manifest::{ActiveArchive, Manifest, StoredArchive};
+use crate::repository::backend::{Backend, BackendError, Index, Manifest as BackendManifest};
+use crate::repository::{
+    BackendClone, Chunk, ChunkID, ChunkIDAlgorithm, ChunkIDSettings, ChunkSettings, Compression,
+    EncryptedKey, Encryption, Key, Repository, HMAC,
+};
+use crate::time::Timestamp;
+
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use walkdir::WalkDir;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+pub mod conformance;
+
+/// The environment variable that pins `for_each_seed` to a single seed
+pub const SEED_VAR: &str = "ASURAN_TEST_SEED";
+
+/// Runs `test` once for each of `cases` random seeds, handing it an `StdRng` seeded with
+/// that seed
+///
+/// If `ASURAN_TEST_SEED` is set, `test` is only run once, with that seed, so a failing case
+/// can be reproduced.
+///
+/// # Panics
+///
+/// Will panic if `test` panics, after printing the seed of the failing case, or if
+/// `ASURAN_TEST_SEED` is set to something other than an integer.
+pub fn for_each_seed(cases: usize, mut test: impl FnMut(&mut StdRng)) {
+    let seeds: Vec<u64> = if let Ok(seed) = std::env::var(SEED_VAR) {
+        let seed = seed
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be an integer, got {:?}", SEED_VAR, seed));
+        vec![seed]
+    } else {
+        let mut rng = rand::thread_rng();
+        (0..cases).map(|_| rng.next_u64()).collect()
+    };
+    for seed in seeds {
+        let mut rng = StdRng::seed_from_u64(seed);
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| test(&mut rng))) {
+            eprintln!("Case failed with seed {seed}, rerun it with {SEED_VAR}={seed}");
+            panic::resume_unwind(panic);
+        }
+    }
+}
+
+/// Generates a random `Key`
+pub fn random_key(rng: &mut impl Rng) -> Key {
+    let mut bytes = [0_u8; 96];
+    rng.fill_bytes(&mut bytes);
+    Key::from_bytes(&bytes, rng.next_u64())
+}
+
+/// Generates random `ChunkSettings`, drawn from every compression, encryption, and HMAC
+/// algorithm this build supports
+///
+/// Compression levels and `ChunkID` lengths are also randomized. The chunker is left unset,
+/// so the repository's default is used.
+pub fn random_chunk_settings(rng: &mut impl Rng) -> ChunkSettings {
+    let compressions = Compression::supported();
+    let compression = match compressions[rng.gen_range(0, compressions.len())] {
+        Compression::ZStd { .. } => Compression::ZStd {
+            level: rng.gen_range(1, 10),
+        },
+        Compression::LZ4 { .. } => Compression::LZ4 {
+            level: rng.gen_range(1, 10),
+        },
+        Compression::LZ4HC { .. } => Compression::LZ4HC {
+            level: rng.gen_range(3, 13),
+        },
+        // The higher levels of these are too slow to test with
+        Compression::LZMA { .. } => Compression::LZMA {
+            level: rng.gen_range(0, 4),
+        },
+        Compression::Brotli { .. } => Compression::Brotli {
+            level: rng.gen_range(0, 7),
+        },
+        other => other,
+    };
+    let hmacs = HMAC::supported();
+    let hmac = hmacs[rng.gen_range(0, hmacs.len())];
+    let id = ChunkIDSettings {
+        algorithm: if rng.gen() {
+            ChunkIDAlgorithm::HMAC
+        } else {
+            ChunkIDAlgorithm::Blake3
+        },
+        length: rng.gen_range(ChunkIDSettings::MIN_LENGTH, ChunkIDSettings::MAX_LENGTH + 1),
+    };
+    ChunkSettings {
+        compression,
+        encryption: random_encryption(rng),
+        hmac,
+        id,
+        chunker: None,
+    }
+}
+
+/// Generates a random `Encryption`, including `NoEncryption`, with a random IV
+pub fn random_encryption(rng: &mut impl Rng) -> Encryption {
+    match rng.gen_range(0, 4) {
+        0 => Encryption::NoEncryption,
+        1 => Encryption::AES256CBC { iv: rng.gen() },
+        2 => Encryption::AES256CTR { iv: rng.gen() },
+        _ => Encryption::ChaCha20 { iv: rng.gen() },
+    }
+}
+
+/// Generates `len` bytes of random data
+///
+/// The data is either uniformly random, or made of a short repeated pattern, so that both
+/// the compressible and incompressible paths are exercised.
+pub fn random_data(rng: &mut impl Rng, len: usize) -> Vec<u8> {
+    let mut data = vec![0_u8; len];
+    if rng.gen() {
+        rng.fill_bytes(&mut data);
+    } else {
+        let mut pattern = vec![0_u8; rng.gen_range(1, 64)];
+        rng.fill_bytes(&mut pattern);
+        for (byte, pattern) in data.iter_mut().zip(pattern.iter().cycle()) {
+            *byte = *pattern;
+        }
+    }
+    data
+}
+
+/// Generates `count` chunks of random data, each up to `max_len` bytes long
+///
+/// Some of the chunks are empty, and some are duplicates of earlier ones.
+pub fn random_chunks(rng: &mut impl Rng, count: usize, max_len: usize) -> Vec<Vec<u8>> {
+    let mut chunks: Vec<Vec<u8>> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let chunk = match rng.gen_range(0, 8) {
+            0 => Vec::new(),
+            1 if !chunks.is_empty() => chunks[rng.gen_range(0, chunks.len())].clone(),
+            _ => {
+                let len = rng.gen_range(1, max_len + 1);
+                random_data(rng, len)
+            }
+        };
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Controls the shape of the trees `random_file_tree` generates
+#[derive(Clone, Debug)]
+pub struct FileTreeSettings {
+    /// How many directories deep the tree may go
+    pub max_depth: usize,
+    /// The most entries a single directory may hold
+    pub max_entries: usize,
+    /// The largest a single file may be, in bytes
+    pub max_file_size: usize,
+}
+
+impl Default for FileTreeSettings {
+    fn default() -> Self {
+        FileTreeSettings {
+            max_depth: 3,
+            max_entries: 6,
+            max_file_size: 64 * 1024,
+        }
+    }
+}
+
+/// Generates a random tree of files and directories under `root`, which must already exist,
+/// returning the paths of the files created
+///
+/// Every directory contains at least one file. Some of the files are empty, and some
+/// duplicate the contents of earlier ones, to exercise deduplication.
+///
+/// # Errors
+///
+/// Will return `Err` if creating any of the files or directories fails
+pub fn random_file_tree(
+    rng: &mut impl Rng,
+    root: &Path,
+    settings: &FileTreeSettings,
+) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut contents = Vec::new();
+    fill_directory(rng, root, settings, 0, &mut files, &mut contents)?;
+    Ok(files)
+}
+
+fn fill_directory(
+    rng: &mut impl Rng,
+    directory: &Path,
+    settings: &FileTreeSettings,
+    depth: usize,
+    files: &mut Vec<PathBuf>,
+    contents: &mut Vec<Vec<u8>>,
+) -> io::Result<()> {
+    let entries = rng.gen_range(1, settings.max_entries.max(1) + 1);
+    for i in 0..entries {
+        // The first entry is always a file, so there are no empty directories
+        if i > 0 && depth < settings.max_depth && rng.gen_range(0, 3) == 0 {
+            let path = directory.join(format!("dir-{i}"));
+            fs::create_dir(&path)?;
+            fill_directory(rng, &path, settings, depth + 1, files, contents)?;
+        } else {
+            let path = directory.join(format!("file-{i}"));
+            let data = match rng.gen_range(0, 8) {
+                0 => Vec::new(),
+                1 if !contents.is_empty() => contents[rng.gen_range(0, contents.len())].clone(),
+                _ => {
+                    let len = rng.gen_range(1, settings.max_file_size.max(1) + 1);
+                    random_data(rng, len)
+                }
+            };
+            fs::write(&path, &data)?;
+            contents.push(data);
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Writes each of `chunks` to the repository, commits the index, and asserts that every chunk
+/// reads back intact, and that writing them again is deduplicated
+///
+/// # Panics
+///
+/// Will panic if any chunk fails to write, read back, or deduplicate
+pub async fn assert_chunks_round_trip(
+    repo: &mut Repository<impl BackendClone>,
+    chunks: &[Vec<u8>],
+) {
+    let mut ids = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let (id, _) = repo
+            .write_chunk(chunk.clone())
+            .await
+            .expect("Failed to write chunk");
+        ids.push(id);
+    }
+    repo.commit_index().await;
+    for (chunk, id) in chunks.iter().zip(ids.iter()) {
+        let read = repo.read_chunk(*id).await.expect("Failed to read chunk");
+        assert_eq!(&read, chunk, "Chunk {id:?} read back different data");
+        let (rewritten, present) = repo
+            .write_chunk(chunk.clone())
+            .await
+            .expect("Failed to rewrite chunk");
+        assert_eq!(rewritten, *id, "Rewriting a chunk changed its ID");
+        assert!(present, "Rewriting chunk {:?} was not deduplicated", id);
+    }
+}
+
+/// Exercises the raw `Backend` interface, asserting that the key, chunk settings, chunks,
+/// and archives written to it read back intact
+///
+/// Chunks are written both one at a time and in batches, each with its own random settings.
+/// `backend` should be freshly created, as its key and chunk settings are overwritten. The
+/// key is only checked if the backend supports writing one.
+///
+/// # Panics
+///
+/// Will panic if anything fails to write, or reads back differently than it was written
+#[allow(clippy::too_many_lines)]
+pub async fn assert_backend_round_trip(backend: &mut impl Backend, rng: &mut StdRng) {
+    let key = random_key(rng);
+    let password = random_data(rng, 16);
+    let encrypted = EncryptedKey::encrypt(&key, 16, 1, random_encryption(rng), &password);
+    match backend.write_key(&encrypted).await {
+        Ok(()) => {
+            let read = backend.read_key().await.expect("Failed to read key");
+            let read = read
+                .decrypt(&password)
+                .expect("Failed to decrypt the read key");
+            assert!(read == key, "Key read back different than it was written");
+        }
+        Err(BackendError::Unsupported(_)) => (),
+        Err(e) => panic!("Failed to write key: {}", e),
+    }
+
+    let mut manifest = backend.get_manifest();
+    let settings = random_chunk_settings(rng);
+    manifest
+        .write_chunk_settings(settings)
+        .await
+        .expect("Failed to write chunk settings");
+    assert_eq!(manifest.chunk_settings().await, settings);
+
+    let mut written = HashMap::new();
+    let mut batch = Vec::new();
+    for data in random_chunks(rng, 16, 16 * 1024) {
+        let settings = random_chunk_settings(rng);
+        let chunk = Chunk::pack(
+            data.clone(),
+            settings.compression,
+            settings.encryption,
+            settings.hmac,
+            &key,
+        );
+        let id = chunk.get_id();
+        if written.insert(id, data).is_some() {
+            continue;
+        }
+        if rng.gen() {
+            let location = backend
+                .write_chunk(chunk)
+                .await
+                .expect("Failed to write chunk");
+            backend
+                .get_index()
+                .set_chunk(id, location)
+                .await
+                .expect("Failed to index chunk");
+        } else {
+            batch.push(chunk);
+        }
+    }
+    let ids: Vec<ChunkID> = batch.iter().map(Chunk::get_id).collect();
+    let locations = backend
+        .write_chunks(batch)
+        .await
+        .expect("Failed to write batch of chunks");
+    assert_eq!(locations.len(), ids.len(), "Lost chunks from a batch write");
+    let mut index = backend.get_index();
+    for (id, location) in ids.into_iter().zip(locations) {
+        index
+            .set_chunk(id, location)
+            .await
+            .expect("Failed to index chunk");
+    }
+    backend.flush().await.expect("Failed to flush backend");
+    index.commit_index().await.expect("Failed to commit index");
+
+    assert_eq!(index.count_chunk().await, written.len());
+    for (id, data) in &written {
+        let location = index
+            .lookup_chunk(*id)
+            .await
+            .unwrap_or_else(|| panic!("Chunk {:?} is missing from the index", id));
+        let chunk = backend
+            .read_chunk(location)
+            .await
+            .expect("Failed to read chunk");
+        assert_eq!(chunk.get_id(), *id, "Read back the wrong chunk");
+        let read = chunk.unpack(&key).expect("Failed to unpack chunk");
+        assert_eq!(&read, data, "Chunk {id:?} read back different data");
+    }
+
+    let archive = StoredArchive {
+        name: format!("archive-{}", rng.next_u32()),
+        id: ChunkID::new(&rng.gen::<[u8; 32]>()),
+        timestamp: Timestamp::now(),
+        metadata: ArchiveMetadata::default(),
+        integrity: None,
+        signature: None,
+        duration: None,
+    };
+    manifest
+        .write_archive(archive.clone())
+        .await
+        .expect("Failed to write archive");
+    let archives: Vec<StoredArchive> = manifest.archive_iterator().await.collect();
+    assert!(
+        archives
+            .iter()
+            .any(|x| x.id == archive.id && x.name == archive.name),
+        "Archive read back different than it was written"
+    );
+}
+
+/// Stores the tree at `input` in a new archive, commits it, and asserts that restoring the
+/// archive into `output` reproduces every file
+///
+/// # Panics
+///
+/// Will panic if storing or restoring any object fails, or if the restored tree differs
+pub async fn assert_tree_round_trip(
+    repo: &mut Repository<impl BackendClone>,
+    input: &Path,
+    output: &Path,
+) {
+    let archive = ActiveArchive::new("round-trip");
+    let input_target = FileSystemTarget::new(input.to_str().expect("Input path is not utf-8"));
+    for node in input_target.backup_paths().await {
+        input_target
+            .store_object(repo, repo.chunker(), &archive, node)
+            .await
+            .expect("Failed to store object");
+    }
+    archive
+        .set_listing(input_target.backup_listing().await)
+        .await;
+    let mut manifest = Manifest::load(repo);
+    manifest
+        .commit_archive(repo, archive)
+        .await
+        .expect("Failed to commit archive");
+    repo.commit_index().await;
+
+    let mut manifest = Manifest::load(repo);
+    let stored = manifest
+        .archives()
+        .await
+        .into_iter()
+        .find(|x| x.name() == "round-trip")
+        .expect("Committed archive is missing from the manifest");
+    let archive = stored.load(repo).await.expect("Failed to load archive");
+    let output_target = FileSystemTarget::load_listing(
+        output.to_str().expect("Output path is not utf-8"),
+        archive.listing().await,
+    )
+    .await;
+    for node in output_target.restore_listing().await {
+        output_target
+            .retrieve_object(repo, &archive, node)
+            .await
+            .expect("Failed to retrieve object");
+    }
+
+    assert_same_files(input, output);
+}
+
+/// Asserts that the trees at `left` and `right` contain the same files, with the same
+/// contents
+///
+/// Directories are only compared by the files they contain, so empty directories are
+/// ignored.
+///
+/// # Panics
+///
+/// Will panic if the trees differ, or either can not be read
+pub fn assert_same_files(left: &Path, right: &Path) {
+    let left_files = list_files(left);
+    let right_files = list_files(right);
+    let left_names: Vec<&PathBuf> = left_files.keys().collect();
+    let right_names: Vec<&PathBuf> = right_files.keys().collect();
+    assert_eq!(left_names, right_names, "Trees contain different files");
+    for (path, left_path) in &left_files {
+        let left_data = fs::read(left_path).expect("Failed to read file");
+        let right_data = fs::read(&right_files[path]).expect("Failed to read file");
+        assert!(
+            left_data == right_data,
+            "Contents of {} differ",
+            path.display()
+        );
+    }
+}
+
+/// Maps the path of every file under `root`, relative to `root`, to its full path
+fn list_files(root: &Path) -> std::collections::BTreeMap<PathBuf, PathBuf> {
+    WalkDir::new(root)
+        .into_iter()
+        .map(|entry| entry.expect("Failed to walk tree"))
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| {
+            let relative = entry
+                .path()
+                .strip_prefix(root)
+                .expect("Walked outside of the tree")
+                .to_path_buf();
+            (relative, entry.path().to_path_buf())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::backend::multifile::MultiFile;
+    use tempfile::tempdir;
+
+    #[test]
+    fn seeds_are_reproducible() {
+        let mut first = StdRng::seed_from_u64(42);
+        let mut second = StdRng::seed_from_u64(42);
+        assert_eq!(
+            random_chunk_settings(&mut first),
+            random_chunk_settings(&mut second)
+        );
+        assert_eq!(
+            random_chunks(&mut first, 8, 1024),
+            random_chunks(&mut second, 8, 1024)
+        );
+    }
+
+    #[test]
+    fn file_tree_has_no_empty_directories() {
+        for_each_seed(8, |rng| {
+            let root = tempdir().unwrap();
+            let files = random_file_tree(rng, root.path(), &FileTreeSettings::default()).unwrap();
+            assert!(!files.is_empty());
+            for entry in WalkDir::new(root.path()) {
+                let entry = entry.unwrap();
+                if entry.file_type().is_dir() {
+                    assert!(fs::read_dir(entry.path()).unwrap().next().is_some());
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn mem_chunks_round_trip() {
+        for_each_seed(8, |rng| {
+            let settings = random_chunk_settings(rng);
+            let key = random_key(rng);
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let chunks = random_chunks(rng, 16, 32 * 1024);
+            smol::run(async {
+                assert_chunks_round_trip(&mut repo, &chunks).await;
+                repo.close().await;
+            });
+        });
+    }
+
+    #[test]
+    fn mem_backend_round_trip() {
+        for_each_seed(4, |rng| {
+            let mut backend = Mem::new(random_chunk_settings(rng), random_key(rng), 4);
+            smol::run(async {
+                assert_backend_round_trip(&mut backend, rng).await;
+                backend.close().await;
+            });
+        });
+    }
+
+    #[test]
+    fn multifile_backend_round_trip() {
+        for_each_seed(4, |rng| {
+            let root = tempdir().unwrap();
+            let key = random_key(rng);
+            let encrypted = EncryptedKey::encrypt(&key, 16, 1, Encryption::NoEncryption, b"");
+            smol::run(async {
+                let mut backend = MultiFile::open_defaults(
+                    root.path(),
+                    Some(random_chunk_settings(rng)),
+                    &key,
+                    4,
+                )
+                .await
+                .unwrap();
+                backend.write_key(&encrypted).await.unwrap();
+                assert_backend_round_trip(&mut backend, rng).await;
+                backend.close().await;
+            });
+        });
+    }
+
+    #[test]
+    fn mem_tree_round_trip() {
+        for_each_seed(4, |rng| {
+            let input = tempdir().unwrap();
+            let output = tempdir().unwrap();
+            random_file_tree(rng, input.path(), &FileTreeSettings::default()).unwrap();
+            let settings = random_chunk_settings(rng);
+            let key = random_key(rng);
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            smol::run(async {
+                assert_tree_round_trip(&mut repo, input.path(), output.path()).await;
+                repo.close().await;
+            });
+        });
+    }
+}
@@ -0,0 +1,484 @@
+//! A conformance suite for `Backend` implementations
+//!
+//! Each of the public `async fn`s in this module checks one piece of the behavior the
+//! `Backend`, `Index`, and `Manifest` traits promise, against a backend opened by a
+//! `Harness`. Rather than calling them directly, backends will usually want to instantiate
+//! all of them at once with `backend_conformance_tests!`.
+//!
+//! Every test is run over `CASES` random seeds, see `for_each_seed` for how to replay a
+//! failing one.
+//!
+//! The tests report a nonconforming backend by panicking, so they do not each document it.
+#![allow(clippy::missing_panics_doc)]
+use crate::manifest::archive::ArchiveMetadata;
+use crate::manifest::StoredArchive;
+use crate::repository::backend::{Backend, BackendError, Index, Manifest};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey, Key};
+use crate::test_util::{
+    assert_backend_round_trip, for_each_seed, random_chunk_settings, random_chunks, random_data,
+    random_encryption, random_key,
+};
+use crate::time::Timestamp;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The number of seeds each conformance test is run with
+pub const CASES: usize = 4;
+
+/// Everything a backend under test is opened with
+#[derive(Clone, Debug)]
+pub struct Setup {
+    /// An empty directory the backend can keep its repository in
+    ///
+    /// This is the same directory each time the backend is reopened during a test.
+    pub path: PathBuf,
+    /// The chunk settings to create the repository with
+    pub settings: ChunkSettings,
+    /// The key to open the repository with
+    pub key: Key,
+    /// `key`, encrypted, for backends that must be handed their key when they are created
+    pub encrypted_key: EncryptedKey,
+}
+
+/// Opens the backend under test
+///
+/// This is implemented for any `Fn(Setup) -> impl Future<Output = impl Backend>`, and only
+/// exists to name the types involved.
+pub trait Opener: Clone {
+    type Backend: Backend;
+    type Future: Future<Output = Self::Backend>;
+    /// Opens the backend described by `setup`, creating it if it does not yet exist
+    fn open(&self, setup: Setup) -> Self::Future;
+}
+
+impl<F, Fut, B> Opener for F
+where
+    F: Fn(Setup) -> Fut + Clone,
+    Fut: Future<Output = B>,
+    B: Backend,
+{
+    type Backend = B;
+    type Future = Fut;
+    fn open(&self, setup: Setup) -> Fut {
+        self(setup)
+    }
+}
+
+/// Passes `opener` through unchanged
+///
+/// This gives the compiler the `Fn` bound it needs to infer the argument type of an opener
+/// closure, which the blanket `Opener` impl alone does not.
+pub fn opener<F, Fut, B>(opener: F) -> F
+where
+    F: Fn(Setup) -> Fut + Clone,
+    Fut: Future<Output = B>,
+    B: Backend,
+{
+    opener
+}
+
+/// The state a single conformance test runs with
+pub struct Harness<O> {
+    opener: O,
+    setup: Setup,
+    /// The password `setup.encrypted_key` is encrypted with
+    pub password: Vec<u8>,
+    /// The source of randomness for the test, derived from the case's seed
+    pub rng: StdRng,
+    // Only held so the directory lives as long as the harness does
+    _directory: TempDir,
+}
+
+impl<O: Opener> Harness<O> {
+    /// Creates a harness with a fresh directory, and random settings and key
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the temporary directory can not be created
+    pub fn new(opener: O, rng: &mut StdRng) -> Harness<O> {
+        let mut rng = StdRng::from_rng(rng).expect("Failed to seed the harness rng");
+        let directory = TempDir::new().expect("Failed to create a temporary directory");
+        let key = random_key(&mut rng);
+        let password = random_data(&mut rng, 16);
+        let encrypted_key =
+            EncryptedKey::encrypt(&key, 16, 1, random_encryption(&mut rng), &password);
+        let setup = Setup {
+            path: directory.path().to_path_buf(),
+            settings: random_chunk_settings(&mut rng),
+            key,
+            encrypted_key,
+        };
+        Harness {
+            opener,
+            setup,
+            password,
+            rng,
+            _directory: directory,
+        }
+    }
+
+    /// Returns what the backend is opened with
+    pub fn setup(&self) -> &Setup {
+        &self.setup
+    }
+
+    /// Opens the backend under test, which is the same repository every time it is called
+    pub async fn open(&self) -> O::Backend {
+        self.opener.open(self.setup.clone()).await
+    }
+
+    /// Packs some random data into a chunk with the repository's settings, returning it
+    /// along with the data
+    pub fn random_chunk(&mut self) -> (Chunk, Vec<u8>) {
+        let len = self.rng.gen_range(0, 16 * 1024);
+        let data = random_data(&mut self.rng, len);
+        let settings = self.setup.settings;
+        let chunk = Chunk::pack(
+            data.clone(),
+            settings.compression,
+            settings.encryption,
+            settings.hmac,
+            &self.setup.key,
+        );
+        (chunk, data)
+    }
+}
+
+/// Runs `test` against a fresh `Harness` for each of `CASES` seeds
+#[allow(clippy::needless_pass_by_value)]
+pub fn run<O, T, F>(opener: O, test: T)
+where
+    O: Opener,
+    T: Fn(Harness<O>) -> F,
+    F: Future<Output = ()>,
+{
+    for_each_seed(CASES, |rng| {
+        smol::run(test(Harness::new(opener.clone(), rng)));
+    });
+}
+
+/// Generates an archive entry with a random name and ID
+fn random_archive(rng: &mut impl Rng, timestamp: Timestamp) -> StoredArchive {
+    StoredArchive {
+        name: format!("archive-{}", rng.gen::<u32>()),
+        id: ChunkID::new(&rng.gen::<[u8; 32]>()),
+        timestamp,
+        metadata: ArchiveMetadata::default(),
+        integrity: None,
+        signature: None,
+        duration: None,
+    }
+}
+
+/// Writes the harness's key to the backend, tolerating backends that can only be given
+/// their key when they are created
+async fn write_key(backend: &impl Backend, harness: &Harness<impl Opener>) {
+    match backend.write_key(&harness.setup.encrypted_key).await {
+        Ok(()) | Err(BackendError::Unsupported(_)) => (),
+        Err(e) => panic!("Failed to write key: {}", e),
+    }
+}
+
+/// Asserts that the backend's key decrypts to the harness's key
+async fn assert_key(backend: &impl Backend, harness: &Harness<impl Opener>) {
+    let read = backend.read_key().await.expect("Failed to read key");
+    let read = read
+        .decrypt(&harness.password)
+        .expect("Failed to decrypt the read key");
+    assert!(
+        read == harness.setup.key,
+        "Key read back different than it was written"
+    );
+}
+
+/// The key written to a backend reads back, and decrypts to the same key
+///
+/// Backends that can not change their key are expected to return `Err(Unsupported)` from
+/// `write_key`, and still read back the key they were created with.
+pub async fn key_round_trip<O: Opener>(harness: Harness<O>) {
+    let mut backend = harness.open().await;
+    write_key(&backend, &harness).await;
+    assert_key(&backend, &harness).await;
+    backend.close().await;
+}
+
+/// Chunk settings written through one view of the manifest read back through another
+pub async fn chunk_settings_round_trip<O: Opener>(mut harness: Harness<O>) {
+    let mut backend = harness.open().await;
+    let settings = random_chunk_settings(&mut harness.rng);
+    backend
+        .get_manifest()
+        .write_chunk_settings(settings)
+        .await
+        .expect("Failed to write chunk settings");
+    assert_eq!(backend.get_manifest().chunk_settings().await, settings);
+    backend.close().await;
+}
+
+/// `archive_iterator` lists archives newest first, regardless of the order they were
+/// written in
+pub async fn archive_iterator_newest_first<O: Opener>(mut harness: Harness<O>) {
+    let mut backend = harness.open().await;
+    let mut manifest = backend.get_manifest();
+    let now = Timestamp::now();
+    let mut ages = (0..8).collect::<Vec<u64>>();
+    ages.shuffle(&mut harness.rng);
+    let mut written = Vec::new();
+    for age in ages {
+        let timestamp = now
+            .checked_sub(Duration::from_secs(age * 60))
+            .expect("Timestamp out of range");
+        let archive = random_archive(&mut harness.rng, timestamp);
+        manifest
+            .write_archive(archive.clone())
+            .await
+            .expect("Failed to write archive");
+        written.push(archive);
+    }
+    written.sort_by_key(|archive| Reverse(archive.timestamp()));
+    let expected = written.iter().map(StoredArchive::id).collect::<Vec<_>>();
+    let listed = manifest
+        .archive_iterator()
+        .await
+        .map(|archive| archive.id())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        listed, expected,
+        "archive_iterator did not list the archives newest first"
+    );
+    backend.close().await;
+}
+
+/// Setting the same chunk location more than once, before and after committing, leaves a
+/// single entry in the index
+pub async fn set_chunk_is_idempotent<O: Opener>(mut harness: Harness<O>) {
+    let mut backend = harness.open().await;
+    let (chunk, _) = harness.random_chunk();
+    let id = chunk.get_id();
+    let location = backend
+        .write_chunk(chunk)
+        .await
+        .expect("Failed to write chunk");
+    backend.flush().await.expect("Failed to flush backend");
+    let mut index = backend.get_index();
+    index
+        .set_chunk(id, location)
+        .await
+        .expect("Failed to index chunk");
+    index
+        .set_chunk(id, location)
+        .await
+        .expect("Failed to index a chunk a second time");
+    index.commit_index().await.expect("Failed to commit index");
+    index
+        .set_chunk(id, location)
+        .await
+        .expect("Failed to index a committed chunk again");
+    index.commit_index().await.expect("Failed to commit index");
+
+    assert_eq!(index.count_chunk().await, 1);
+    assert_eq!(index.known_chunks().await, vec![id].into_iter().collect());
+    assert_eq!(index.lookup_chunk(id).await, Some(location));
+    assert!(index.contains_chunk(id).await);
+    backend.close().await;
+}
+
+/// A fresh backend has no chunks, and does not claim to have ones it was never given
+pub async fn missing_chunks_are_absent<O: Opener>(mut harness: Harness<O>) {
+    let mut backend = harness.open().await;
+    let mut index = backend.get_index();
+    assert_eq!(index.count_chunk().await, 0);
+    assert!(index.known_chunks().await.is_empty());
+    for _ in 0..8 {
+        let id = ChunkID::new(&harness.rng.gen::<[u8; 32]>());
+        assert_eq!(index.lookup_chunk(id).await, None);
+        assert!(!index.contains_chunk(id).await);
+    }
+    backend.close().await;
+}
+
+/// Chunks indexed through one view of the index can be found through another
+pub async fn index_views_share_state<O: Opener>(mut harness: Harness<O>) {
+    let mut backend = harness.open().await;
+    let (chunk, _) = harness.random_chunk();
+    let id = chunk.get_id();
+    let location = backend
+        .write_chunk(chunk)
+        .await
+        .expect("Failed to write chunk");
+    backend.flush().await.expect("Failed to flush backend");
+    let mut writer = backend.get_index();
+    let mut reader = backend.get_index();
+    writer
+        .set_chunk(id, location)
+        .await
+        .expect("Failed to index chunk");
+    writer.commit_index().await.expect("Failed to commit index");
+    assert_eq!(reader.lookup_chunk(id).await, Some(location));
+    backend.close().await;
+}
+
+/// `write_chunks` returns the location of each chunk in the order the chunks were given
+pub async fn write_chunks_keeps_order<O: Opener>(mut harness: Harness<O>) {
+    let mut backend = harness.open().await;
+    let settings = harness.setup.settings;
+    let mut seen = HashSet::new();
+    let chunks = random_chunks(&mut harness.rng, 16, 16 * 1024)
+        .into_iter()
+        .map(|data| {
+            Chunk::pack(
+                data,
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &harness.setup.key,
+            )
+        })
+        .filter(|chunk| seen.insert(chunk.get_id()))
+        .collect::<Vec<_>>();
+    let ids = chunks.iter().map(Chunk::get_id).collect::<Vec<_>>();
+    let locations = backend
+        .write_chunks(chunks)
+        .await
+        .expect("Failed to write batch of chunks");
+    backend.flush().await.expect("Failed to flush backend");
+    assert_eq!(locations.len(), ids.len(), "Lost chunks from a batch write");
+    for (id, location) in ids.into_iter().zip(locations) {
+        let chunk = backend
+            .read_chunk(location)
+            .await
+            .expect("Failed to read chunk");
+        assert_eq!(chunk.get_id(), id, "Batch locations are out of order");
+    }
+    backend.close().await;
+}
+
+/// Keys, chunk settings, chunks, and archives all round trip, see
+/// `assert_backend_round_trip`
+pub async fn backend_round_trip<O: Opener>(mut harness: Harness<O>) {
+    let mut backend = harness.open().await;
+    assert_backend_round_trip(&mut backend, &mut harness.rng).await;
+    backend.close().await;
+}
+
+/// Everything committed before `close` is still there when the backend is reopened
+///
+/// The chunk is deliberately not flushed before closing, as `close` is responsible for
+/// writing out anything the backend is still buffering.
+pub async fn close_persists<O: Opener>(mut harness: Harness<O>) {
+    let mut backend = harness.open().await;
+    write_key(&backend, &harness).await;
+    let (chunk, data) = harness.random_chunk();
+    let id = chunk.get_id();
+    let location = backend
+        .write_chunk(chunk)
+        .await
+        .expect("Failed to write chunk");
+    let mut index = backend.get_index();
+    index
+        .set_chunk(id, location)
+        .await
+        .expect("Failed to index chunk");
+    index.commit_index().await.expect("Failed to commit index");
+    let archive = random_archive(&mut harness.rng, Timestamp::now());
+    backend
+        .get_manifest()
+        .write_archive(archive.clone())
+        .await
+        .expect("Failed to write archive");
+    backend.close().await;
+
+    let mut backend = harness.open().await;
+    assert_key(&backend, &harness).await;
+    let location = backend
+        .get_index()
+        .lookup_chunk(id)
+        .await
+        .expect("Chunk is missing from the index after reopening");
+    let chunk = backend
+        .read_chunk(location)
+        .await
+        .expect("Failed to read chunk after reopening");
+    let read = chunk
+        .unpack(&harness.setup.key)
+        .expect("Failed to unpack chunk");
+    assert_eq!(read, data, "Chunk changed after reopening");
+    assert!(
+        backend
+            .get_manifest()
+            .archive_iterator()
+            .await
+            .any(|x| x.id() == archive.id()),
+        "Archive is missing after reopening"
+    );
+    backend.close().await;
+}
+
+/// Instantiates the conformance suite in `asuran::test_util::conformance` against a backend
+///
+/// Takes the name of the module to generate the tests in, and a closure that opens the
+/// backend from a `Setup`, returning a future. The closure is evaluated inside the
+/// generated module, which glob imports its parent.
+///
+/// Backends that do not persist anything past `close`, such as `Mem`, should be marked
+/// `volatile`, which leaves out the tests that reopen them.
+///
+/// ```
+/// use asuran::backend_conformance_tests;
+/// use asuran::repository::backend::mem::Mem;
+/// use asuran::repository::backend::multifile::MultiFile;
+///
+/// backend_conformance_tests!(multifile, |setup| async move {
+///     MultiFile::open_defaults(&setup.path, Some(setup.settings), &setup.key, 4)
+///         .await
+///         .unwrap()
+/// });
+///
+/// backend_conformance_tests!(volatile mem, |setup| async move {
+///     Mem::new(setup.settings, setup.key, 4)
+/// });
+/// # fn main() {}
+/// ```
+#[macro_export]
+macro_rules! backend_conformance_tests {
+    (@test $opener:expr, $test:ident) => {
+        #[test]
+        fn $test() {
+            $crate::test_util::conformance::run(
+                $crate::test_util::conformance::opener($opener),
+                $crate::test_util::conformance::$test,
+            );
+        }
+    };
+    (@suite $name:ident, $opener:expr, [$($extra:ident),*]) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            $crate::backend_conformance_tests!(@test $opener, key_round_trip);
+            $crate::backend_conformance_tests!(@test $opener, chunk_settings_round_trip);
+            $crate::backend_conformance_tests!(@test $opener, archive_iterator_newest_first);
+            $crate::backend_conformance_tests!(@test $opener, set_chunk_is_idempotent);
+            $crate::backend_conformance_tests!(@test $opener, missing_chunks_are_absent);
+            $crate::backend_conformance_tests!(@test $opener, index_views_share_state);
+            $crate::backend_conformance_tests!(@test $opener, write_chunks_keeps_order);
+            $crate::backend_conformance_tests!(@test $opener, backend_round_trip);
+            $($crate::backend_conformance_tests!(@test $opener, $extra);)*
+        }
+    };
+    (volatile $name:ident, $opener:expr) => {
+        $crate::backend_conformance_tests!(@suite $name, $opener, []);
+    };
+    ($name:ident, $opener:expr) => {
+        $crate::backend_conformance_tests!(@suite $name, $opener, [close_persists]);
+    };
+}
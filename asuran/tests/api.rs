@@ -0,0 +1,110 @@
+use asuran::api::{Location, Repo};
+use asuran::manifest::retention::RetentionPolicy;
+use asuran::repository::audit::Operation;
+use asuran::repository::{ChunkSettings, Encryption};
+use asuran::ErrorKind;
+use std::fs;
+use tempfile::tempdir;
+
+fn round_trip(location: Location) {
+    smol::run(async {
+        let input_dir = fs::canonicalize("tests/inputdata/scodev1/").unwrap();
+        let password = b"A Very Strong Password";
+        let settings = ChunkSettings {
+            encryption: Encryption::new_aes256ctr(),
+            ..ChunkSettings::lightweight()
+        };
+        let mut repo = Repo::create(&location, password, settings).await.unwrap();
+        let full = repo.backup("full", &input_dir, &[]).await.unwrap();
+        assert!(full.report.failed.is_empty());
+        assert!(full.report.chunks_written > 0);
+        let partial = repo
+            .backup("partial", &input_dir, &["manifest/".to_string()])
+            .await
+            .unwrap();
+        // Everything in the second backup was already in the repository
+        assert_eq!(partial.report.chunks_written, 0);
+        let missing = repo
+            .backup("missing", &input_dir, &["nothing/here".to_string()])
+            .await;
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::Io);
+        repo.close().await;
+
+        let wrong = Repo::open(&location, b"Not the password").await;
+        assert_eq!(wrong.err().unwrap().kind(), ErrorKind::WrongPassword);
+        let mut repo = Repo::open(&location, password).await.unwrap();
+        let mut names = repo
+            .archives()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["full", "partial"]);
+        let log = repo.log().await.unwrap();
+        let stored = log
+            .iter()
+            .map(|x| (x.operation, x.archive.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stored,
+            [
+                (Operation::Store, Some("full")),
+                (Operation::Store, Some("partial"))
+            ]
+        );
+        assert_eq!(repo.archive(&full.archive.id[..8]).await.unwrap().name, "full");
+
+        let output = tempdir().unwrap();
+        let restored = repo.restore("latest~1", output.path(), &[]).await.unwrap();
+        assert_eq!(restored.archive.name, "full");
+        assert!(restored.refused.is_empty());
+        assert!(!dir_diff::is_different(&input_dir, output.path()).unwrap());
+
+        let output = tempdir().unwrap();
+        let paths = ["manifest/target.src".to_string()];
+        repo.restore("latest", output.path(), &paths).await.unwrap();
+        let restored = output.path().join("manifest/target.src");
+        assert_eq!(
+            fs::read(restored).unwrap(),
+            fs::read(input_dir.join("manifest/target.src")).unwrap()
+        );
+        assert!(!output.path().join("lib.src").exists());
+        let paths = ["lib.src".to_string()];
+        let missing = repo.restore("latest", output.path(), &paths).await;
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::Io);
+        let missing = repo.restore("nightly", output.path(), &[]).await;
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::ArchiveNotFound);
+
+        // Policies without rules would remove everything
+        assert!(repo.prune(&RetentionPolicy::default()).await.is_err());
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            ..RetentionPolicy::default()
+        };
+        let pruned = repo.prune(&policy).await;
+        if let Location::MultiFile(_) = location {
+            let pruned = pruned.unwrap();
+            assert_eq!(pruned.removed, [full.archive]);
+            assert_eq!(pruned.stats.archives_kept, 1);
+            assert_eq!(repo.archives().await.unwrap().len(), 1);
+            let log = repo.log().await.unwrap();
+            assert_eq!(log.len(), 3);
+            assert_eq!(log[2].operation, Operation::Prune);
+        }
+        repo.close().await;
+    });
+}
+
+#[test]
+fn round_trip_multifile() {
+    let tempdir = tempdir().unwrap();
+    round_trip(Location::MultiFile(tempdir.path().join("repo")));
+}
+
+#[test]
+fn round_trip_flatfile() {
+    let tempdir = tempdir().unwrap();
+    round_trip(Location::FlatFile(tempdir.path().join("repo.asuran")));
+}
@@ -0,0 +1,130 @@
+use asuran::chunker::*;
+use asuran::manifest::driver::*;
+use asuran::manifest::target::filesystem::*;
+use asuran::manifest::target::*;
+use asuran::manifest::*;
+use asuran::repository::bundle::*;
+use asuran::repository::*;
+use rand::prelude::*;
+use std::fs;
+use tempfile::tempdir;
+
+mod common;
+
+/// Builds a repository containing a single archive of some random files, and returns
+/// it, along with its exported bundle
+async fn setup(
+    key: &Key,
+    encrypted_key: &EncryptedKey,
+) -> (Repository<impl BackendClone>, Vec<u8>) {
+    let tempdir = tempdir().unwrap();
+    let root = tempdir.path();
+    for (name, len) in &[("a", 100_000), ("b", 1000), ("c", 0)] {
+        let mut data = vec![0_u8; *len];
+        thread_rng().fill_bytes(&mut data);
+        fs::write(root.join(name), data).unwrap();
+    }
+
+    let mut repo = common::get_repo_mem(key.clone());
+    let chunker = FastCDC::default();
+    let archive = ActiveArchive::new("test");
+    let target = FileSystemTarget::new(root.to_str().unwrap());
+    for node in target.backup_paths().await {
+        target
+            .store_object(&mut repo, chunker, &archive, node)
+            .await
+            .unwrap();
+    }
+    archive.set_listing(target.backup_listing().await).await;
+    let mut manifest = Manifest::load(&repo);
+    manifest.commit_archive(&mut repo, archive).await.unwrap();
+
+    let mut bundle = Vec::new();
+    let stats = export_bundle(&mut repo, encrypted_key, &mut bundle)
+        .await
+        .unwrap();
+    assert_eq!(stats.archives, 1);
+    assert_eq!(stats.chunks as usize, repo.count_chunk().await);
+    assert_eq!(stats.bytes as usize, bundle.len());
+    (repo, bundle)
+}
+
+#[test]
+fn bundle_round_trip() {
+    smol::run(async {
+        let key = Key::random(32);
+        let encrypted_key =
+            EncryptedKey::encrypt_defaults(&key, Encryption::new_aes256ctr(), b"password");
+        let (mut repo, bundle) = setup(&key, &encrypted_key).await;
+
+        let reader = BundleReader::open(&bundle[..]).unwrap();
+        let imported_key = reader.encrypted_key().decrypt(b"password").unwrap();
+        assert_eq!(imported_key, key);
+        let mut restored = common::get_repo_mem(imported_key);
+        let stats = reader.import(&mut restored).await.unwrap();
+        assert_eq!(stats.archives, 1);
+        assert_eq!(stats.bytes as usize, bundle.len());
+
+        // Every chunk and archive should have made it across intact
+        assert_eq!(restored.known_chunks().await, repo.known_chunks().await);
+        for id in repo.known_chunks().await {
+            assert_eq!(
+                restored.read_chunk(id).await.unwrap(),
+                repo.read_chunk(id).await.unwrap()
+            );
+        }
+        let original = Manifest::load(&repo).archives().await;
+        let mut manifest = Manifest::load(&restored);
+        assert_eq!(manifest.archives().await, original);
+        let archive = manifest.archives().await[0]
+            .load(&mut restored)
+            .await
+            .unwrap();
+        let original = original[0].load(&mut repo).await.unwrap();
+        assert_eq!(
+            archive.listing().await.into_iter().collect::<Vec<_>>(),
+            original.listing().await.into_iter().collect::<Vec<_>>()
+        );
+
+        // Exporting the restored repository should produce an identical list of chunks
+        let mut second = Vec::new();
+        let second_stats = export_bundle(&mut restored, &encrypted_key, &mut second)
+            .await
+            .unwrap();
+        assert_eq!(second_stats.chunks, stats.chunks);
+        repo.close().await;
+        restored.close().await;
+    });
+}
+
+#[test]
+fn bundle_damage_detected() {
+    smol::run(async {
+        let key = Key::random(32);
+        let encrypted_key =
+            EncryptedKey::encrypt_defaults(&key, Encryption::new_aes256ctr(), b"password");
+        let (repo, bundle) = setup(&key, &encrypted_key).await;
+
+        // Truncated bundles must not produce any archives
+        let truncated = &bundle[..bundle.len() - 100];
+        let mut restored = common::get_repo_mem(key.clone());
+        let reader = BundleReader::open(truncated).unwrap();
+        assert!(reader.import(&mut restored).await.is_err());
+        assert!(Manifest::load(&restored).archives().await.is_empty());
+
+        // Neither should flipping a byte in the middle
+        let mut damaged = bundle.clone();
+        let middle = damaged.len() / 2;
+        damaged[middle] ^= 0xFF;
+        let mut restored = common::get_repo_mem(key.clone());
+        let reader = BundleReader::open(&damaged[..]).unwrap();
+        assert!(reader.import(&mut restored).await.is_err());
+        assert!(Manifest::load(&restored).archives().await.is_empty());
+
+        // Nor should importing with the wrong key
+        let mut restored = common::get_repo_mem(Key::random(32));
+        let reader = BundleReader::open(&bundle[..]).unwrap();
+        assert!(reader.import(&mut restored).await.is_err());
+        repo.close().await;
+    });
+}
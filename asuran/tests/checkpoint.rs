@@ -0,0 +1,98 @@
+use asuran::chunker::*;
+use asuran::manifest::*;
+use asuran::repository::backend::multifile;
+use asuran::repository::*;
+use rand::prelude::*;
+use std::io::Cursor;
+use tempfile::tempdir;
+
+mod common;
+
+fn random_object() -> Vec<u8> {
+    let mut object = vec![0_u8; 16384];
+    thread_rng().fill_bytes(&mut object);
+    object
+}
+
+// A checkpoint should survive the connection that wrote it going away without being
+// closed, and a resumed backup should deduplicate against everything it stored
+#[test]
+fn resume_from_checkpoint() {
+    smol::run(async {
+        let tempdir = tempdir().unwrap();
+        let root_path = tempdir.path().to_str().unwrap();
+        let key = Key::random(32);
+        let chunker = FastCDC::default();
+        let first = random_object();
+        let second = random_object();
+
+        let mut repo = common::get_repo_bare(root_path, key.clone()).await;
+        repo.self_test().await.unwrap();
+        let mut manifest = Manifest::load(&repo);
+        manifest
+            .set_chunk_settings(repo.chunk_settings())
+            .await
+            .unwrap();
+        let mut archive = ActiveArchive::new("interrupted");
+        archive
+            .put_object(&chunker, &mut repo, "first", Cursor::new(first.clone()))
+            .await
+            .unwrap();
+        let stored = manifest
+            .commit_checkpoint(&mut repo, archive.clone())
+            .await
+            .unwrap();
+        assert!(stored.metadata().is_checkpoint());
+        assert!(!archive.metadata().is_checkpoint());
+        archive
+            .put_object(&chunker, &mut repo, "second", Cursor::new(second.clone()))
+            .await
+            .unwrap();
+        // Simulate the backup being killed, by leaking the connection so nothing it has
+        // buffered gets written out, then clean up the locks it left behind
+        std::mem::forget(manifest);
+        std::mem::forget(archive);
+        std::mem::forget(repo);
+        multifile::lock::break_locks(root_path).unwrap();
+
+        let mut repo = common::get_repo_bare(root_path, key).await;
+        repo.self_test().await.unwrap();
+        let mut manifest = Manifest::load(&repo);
+        let checkpoints = manifest.checkpoints().await;
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(checkpoints[0].name(), "interrupted");
+        let checkpoint = checkpoints[0].load(&mut repo).await.unwrap();
+        let mut buffer = Cursor::new(Vec::<u8>::new());
+        checkpoint
+            .get_object(&mut repo, "first", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer.into_inner(), first);
+
+        // Resuming the backup only has to store what the checkpoint did not
+        let chunks_before = repo.count_chunk().await;
+        let mut archive = ActiveArchive::new("interrupted");
+        archive
+            .put_object(&chunker, &mut repo, "first", Cursor::new(first.clone()))
+            .await
+            .unwrap();
+        assert_eq!(repo.count_chunk().await, chunks_before);
+        archive
+            .put_object(&chunker, &mut repo, "second", Cursor::new(second))
+            .await
+            .unwrap();
+        manifest.commit_archive(&mut repo, archive).await.unwrap();
+        assert_eq!(
+            manifest
+                .remove_checkpoints(&mut repo, "interrupted")
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(manifest.checkpoints().await.is_empty());
+        let archives = manifest.archives().await;
+        assert_eq!(archives.len(), 1);
+        assert!(!archives[0].metadata().is_checkpoint());
+        repo.close().await;
+    });
+}
@@ -8,6 +8,8 @@ pub fn get_bare_settings() -> ChunkSettings {
         compression: Compression::NoCompression,
         hmac: HMAC::Blake2b,
         encryption: Encryption::NoEncryption,
+        id: ChunkIDSettings::default(),
+        chunker: None,
     }
 }
 
@@ -17,9 +19,11 @@ pub fn get_repo_mem(key: Key) -> Repository<impl BackendClone> {
         compression: Compression::ZStd { level: 1 },
         hmac: HMAC::Blake2b,
         encryption: Encryption::new_aes256ctr(),
+        id: ChunkIDSettings::default(),
+        chunker: None,
     };
     let backend = asuran::repository::backend::mem::Mem::new(settings, key.clone(), 4);
-    Repository::with(backend, settings, key, 2)
+    Repository::with(backend, settings, key, 2).unwrap()
 }
 
 #[allow(dead_code)]
@@ -28,6 +32,8 @@ pub async fn get_repo_bare(path: &str, key: Key) -> Repository<impl BackendClone
         compression: Compression::ZStd { level: 1 },
         hmac: HMAC::Blake2b,
         encryption: Encryption::new_aes256ctr(),
+        id: ChunkIDSettings::default(),
+        chunker: None,
     };
     let backend = asuran::repository::backend::multifile::MultiFile::open_defaults(
         path,
@@ -37,7 +43,7 @@ pub async fn get_repo_bare(path: &str, key: Key) -> Repository<impl BackendClone
     )
     .await
     .unwrap();
-    Repository::with(backend, settings, key, 2)
+    Repository::with(backend, settings, key, 2).unwrap()
 }
 
 #[allow(dead_code)]
@@ -55,7 +61,7 @@ pub fn get_repo_flat(
         4,
     )
     .unwrap();
-    Repository::with(backend, settings, key, 2)
+    Repository::with(backend, settings, key, 2).unwrap()
 }
 
 #[allow(dead_code)]
@@ -84,10 +90,14 @@ pub fn get_sftp_repo(path: impl AsRef<Path>, key: Key) -> Repository<impl Backen
         username,
         port: Some(port),
         password: Some(password),
+        key_file: None,
+        key_passphrase: None,
+        known_hosts: None,
+        host_key_policy: HostKeyPolicy::default(),
         path: String::from(path.to_string_lossy()),
     };
     let handle =
         SFTP::connect(settings, key.clone(), Some(ChunkSettings::lightweight()), 2).unwrap();
 
-    Repository::with(handle, ChunkSettings::lightweight(), key, 2)
+    Repository::with(handle, ChunkSettings::lightweight(), key, 2).unwrap()
 }
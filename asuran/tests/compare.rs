@@ -0,0 +1,165 @@
+use asuran::chunker::*;
+use asuran::manifest::compare::*;
+use asuran::manifest::driver::*;
+use asuran::manifest::target::filesystem::*;
+use asuran::manifest::target::*;
+use asuran::manifest::*;
+use asuran::repository::*;
+use rand::prelude::*;
+use std::fs;
+use std::path::Path;
+use tempfile::tempdir;
+
+mod common;
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0_u8; len];
+    thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+async fn compare(
+    repo: &mut Repository<impl BackendClone>,
+    chunker: &FastCDC,
+    archive: &ActiveArchive,
+    root: &Path,
+) -> Vec<Drift> {
+    let target = FileSystemTarget::new(root.to_str().unwrap());
+    compare_archive(repo, chunker, archive, &target)
+        .await
+        .unwrap()
+}
+
+#[test]
+fn compare_live() {
+    smol::run(async {
+        let tempdir = tempdir().unwrap();
+        let root = tempdir.path();
+        fs::write(root.join("large"), random_bytes(256 * 1024)).unwrap();
+        fs::write(root.join("small"), random_bytes(1000)).unwrap();
+        fs::write(root.join("empty"), b"").unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir").join("nested"), random_bytes(2000)).unwrap();
+
+        let key = Key::random(32);
+        let mut repo = common::get_repo_mem(key);
+        let chunker = FastCDC::default();
+
+        let archive = ActiveArchive::new("test");
+        let input_target = FileSystemTarget::new(root.to_str().unwrap());
+        let paths = input_target.backup_paths().await;
+        for node in paths {
+            input_target
+                .store_object(&mut repo, chunker, &archive, node)
+                .await
+                .unwrap();
+        }
+        archive
+            .set_listing(input_target.backup_listing().await)
+            .await;
+
+        let mut manifest = Manifest::load(&repo);
+        manifest.commit_archive(&mut repo, archive).await.unwrap();
+        let mut manifest = Manifest::load(&repo);
+        let stored_archive = &manifest.archives().await[0];
+        let archive = stored_archive.load(&mut repo).await.unwrap();
+
+        // A fresh backup should not have drifted, even when compared with a chunker that
+        // splits files differently
+        assert_eq!(compare(&mut repo, &chunker, &archive, root).await, vec![]);
+        let other_chunker = FastCDC {
+            min_size: 4096,
+            avg_size: 8192,
+            max_size: 16384,
+        };
+        assert_eq!(
+            compare(&mut repo, &other_chunker, &archive, root).await,
+            vec![]
+        );
+
+        // Now change the live tree in a few ways
+        let mut large = fs::read(root.join("large")).unwrap();
+        large[100_000] ^= 0xFF;
+        fs::write(root.join("large"), large).unwrap();
+        fs::write(root.join("small"), random_bytes(1001)).unwrap();
+        fs::remove_file(root.join("dir").join("nested")).unwrap();
+        fs::remove_file(root.join("empty")).unwrap();
+        fs::create_dir(root.join("empty")).unwrap();
+        fs::write(root.join("added"), b"new").unwrap();
+
+        for chunker in &[chunker, other_chunker] {
+            let drift = compare(&mut repo, chunker, &archive, root).await;
+            let summary: Vec<(&str, &str)> = drift
+                .iter()
+                .map(|x| {
+                    let kind = match x {
+                        Drift::Added(_) => "added",
+                        Drift::Removed(_) => "removed",
+                        Drift::TypeChanged { .. } => "type",
+                        Drift::SizeChanged { .. } => "size",
+                        Drift::ContentChanged(_) => "content",
+                    };
+                    (x.path(), kind)
+                })
+                .collect();
+            assert_eq!(
+                summary,
+                vec![
+                    ("added", "added"),
+                    ("dir/nested", "removed"),
+                    ("empty", "type"),
+                    ("large", "content"),
+                    ("small", "size"),
+                ]
+            );
+        }
+        repo.close().await;
+    });
+}
+
+#[test]
+fn compare_custom_ids() {
+    smol::run(async {
+        let tempdir = tempdir().unwrap();
+        let root = tempdir.path();
+        fs::write(root.join("large"), random_bytes(256 * 1024)).unwrap();
+        fs::write(root.join("small"), random_bytes(1000)).unwrap();
+
+        // Chunk IDs derived with BLAKE3 and truncated, rather than the full length HMAC
+        let key = Key::random(32);
+        let settings = ChunkSettings {
+            compression: Compression::ZStd { level: 1 },
+            hmac: HMAC::Blake2b,
+            encryption: Encryption::new_aes256ctr(),
+            id: ChunkIDSettings::new(ChunkIDAlgorithm::Blake3, 16).unwrap(),
+            chunker: None,
+        };
+        let backend = asuran::repository::backend::mem::Mem::new(settings, key.clone(), 4);
+        let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+        let chunker = FastCDC::default();
+
+        let archive = ActiveArchive::new("test");
+        let input_target = FileSystemTarget::new(root.to_str().unwrap());
+        let paths = input_target.backup_paths().await;
+        for node in paths {
+            input_target
+                .store_object(&mut repo, chunker, &archive, node)
+                .await
+                .unwrap();
+        }
+        archive
+            .set_listing(input_target.backup_listing().await)
+            .await;
+
+        // Unchanged chunks should be recognized by their IDs
+        assert_eq!(compare(&mut repo, &chunker, &archive, root).await, vec![]);
+
+        let mut large = fs::read(root.join("large")).unwrap();
+        large[100_000] ^= 0xFF;
+        fs::write(root.join("large"), large).unwrap();
+        let drift = compare(&mut repo, &chunker, &archive, root).await;
+        assert_eq!(drift.len(), 1);
+        assert!(matches!(&drift[0], Drift::ContentChanged(path) if path == "large"));
+        repo.close().await;
+    });
+}
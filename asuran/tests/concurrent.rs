@@ -0,0 +1,120 @@
+use asuran::chunker::*;
+use asuran::manifest::*;
+use asuran::repository::*;
+use rand::prelude::*;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tempfile::tempdir;
+
+mod common;
+
+fn random_object() -> Vec<u8> {
+    let mut object = vec![0_u8; 16384];
+    thread_rng().fill_bytes(&mut object);
+    object
+}
+
+// Stores `count` archives, each containing a unique object and `shared`, through its own
+// connection to the repository at `path`, returning the unique object of each archive by name
+async fn store_archives(
+    path: &str,
+    key: Key,
+    writer: usize,
+    count: usize,
+    shared: &[u8],
+    start: &Barrier,
+) -> HashMap<String, Vec<u8>> {
+    // Open at the same time as the other writer, so the connections race for their files
+    start.wait();
+    let mut repo = common::get_repo_bare(path, key).await;
+    let chunker = FastCDC::default();
+    let mut manifest = Manifest::load(&repo);
+    let mut stored = HashMap::new();
+    for i in 0..count {
+        let name = format!("{}-{}", writer, i);
+        let object = random_object();
+        let mut archive = ActiveArchive::new(&name);
+        archive
+            .put_object(&chunker, &mut repo, "shared", Cursor::new(shared.to_vec()))
+            .await
+            .unwrap();
+        archive
+            .put_object(&chunker, &mut repo, "unique", Cursor::new(object.clone()))
+            .await
+            .unwrap();
+        manifest.commit_archive(&mut repo, archive).await.unwrap();
+        stored.insert(name, object);
+    }
+    // Everything the other writer has committed so far should be readable from here
+    for archive in manifest.archives().await {
+        let archive = archive.load(&mut repo).await.unwrap();
+        let mut buffer = Vec::new();
+        archive
+            .get_object(&mut repo, "shared", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, shared);
+    }
+    repo.close().await;
+    stored
+}
+
+// Two connections storing into the same MultiFile repository at the same time should both
+// have all of their archives, and every chunk they refer to, end up in the repository
+#[test]
+fn concurrent_multifile_writers() {
+    let tempdir = tempdir().unwrap();
+    let root_path = tempdir.path().to_str().unwrap().to_string();
+    let key = Key::random(32);
+    let shared = Arc::new(random_object());
+    let start = Arc::new(Barrier::new(2));
+    // Create the repository before the writers race to open it
+    smol::run(async {
+        let mut repo = common::get_repo_bare(&root_path, key.clone()).await;
+        repo.self_test().await.unwrap();
+        repo.close().await;
+    });
+    let writers = (0..2)
+        .map(|writer| {
+            let root_path = root_path.clone();
+            let key = key.clone();
+            let shared = shared.clone();
+            let start = start.clone();
+            thread::spawn(move || {
+                smol::run(store_archives(&root_path, key, writer, 5, &shared, &start))
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut stored = HashMap::new();
+    for writer in writers {
+        stored.extend(writer.join().unwrap());
+    }
+
+    smol::run(async {
+        let mut repo = common::get_repo_bare(&root_path, key.clone()).await;
+        repo.self_test().await.unwrap();
+        let mut manifest = Manifest::load(&repo);
+        let archives = manifest.archives().await;
+        assert_eq!(archives.len(), stored.len());
+        for archive in archives {
+            let archive = archive.load(&mut repo).await.unwrap();
+            let mut buffer = Vec::new();
+            archive
+                .get_object(&mut repo, "unique", &mut buffer)
+                .await
+                .unwrap();
+            assert_eq!(&buffer, &stored[archive.name()]);
+            let mut buffer = Vec::new();
+            archive
+                .get_object(&mut repo, "shared", &mut buffer)
+                .await
+                .unwrap();
+            assert_eq!(&buffer, &*shared);
+        }
+        let report = repo.check(false).await.unwrap();
+        assert!(report.is_clean());
+        repo.close().await;
+    });
+}
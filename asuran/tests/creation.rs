@@ -1,4 +1,6 @@
+use asuran::manifest::Manifest;
 use asuran::prelude::*;
+use asuran::repository::backend::multifile::MultiFileSettings;
 use tempfile::tempdir;
 
 async fn create_multifile_repository(encryption: Encryption, compression: Compression, hmac: HMAC) {
@@ -17,6 +19,8 @@ async fn create_multifile_repository(encryption: Encryption, compression: Compre
         compression,
         encryption,
         hmac,
+        id: ChunkIDSettings::default(),
+        chunker: None,
     };
 
     let mut mf = MultiFile::open_defaults(repo_dir, Some(settings), &key, 4)
@@ -54,3 +58,39 @@ fn create_lzma_9() {
         .await
     });
 }
+
+// A repository that has never had a canary chunk stored in it should still be usable when
+// opened read only
+#[test]
+fn open_fresh_read_only() {
+    smol::run(async {
+        let directory = tempdir().expect("Unable to open temporary directory.");
+        let repo_dir = directory.path();
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let mut mf = MultiFile::open_defaults(repo_dir, Some(settings), &key, 4)
+            .await
+            .expect("Unable to create the multifile repository");
+        mf.close().await;
+
+        let read_only = MultiFileSettings {
+            read_only: true,
+            ..MultiFileSettings::default()
+        };
+        let mf = MultiFile::open_with_settings(repo_dir, None, &key, 4, read_only)
+            .await
+            .expect("Unable to open the repository read only");
+        let mut repo = Repository::with(mf, settings, key, 2).unwrap();
+        // Storing the canary is not possible
+        assert!(matches!(
+            repo.self_test().await,
+            Err(RepositoryError::BackendError(BackendError::ReadOnly(_)))
+        ));
+        repo.self_test_read_only()
+            .await
+            .expect("Read only self test failed");
+        let mut manifest = Manifest::load(&repo);
+        assert!(manifest.archives().await.is_empty());
+        repo.close().await;
+    });
+}
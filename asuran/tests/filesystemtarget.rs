@@ -1,5 +1,6 @@
 use asuran::chunker::*;
 use asuran::manifest::driver::*;
+use asuran::manifest::hash::ObjectHashAlgorithm;
 use asuran::manifest::target::filesystem::*;
 use asuran::manifest::target::*;
 use asuran::manifest::*;
@@ -177,6 +178,51 @@ fn backup_restore_no_empty_dirs_mem() {
     });
 }
 
+// Restoring many objects at once should produce the same tree as restoring them in turn,
+// even though objects may be restored before the directories they are in
+#[test]
+fn backup_restore_concurrent() {
+    smol::run(async {
+        let input_dir = fs::canonicalize("tests/inputdata/scodev1/").unwrap();
+        let output_tempdir = tempdir().unwrap();
+        let output_dir = output_tempdir.path();
+
+        let key = Key::random(32);
+        let mut repo = common::get_repo_mem(key);
+        let chunker = FastCDC::default();
+
+        let archive = ActiveArchive::new("test");
+        let input_target = FileSystemTarget::new(input_dir.to_str().unwrap());
+        for node in input_target.backup_paths().await {
+            input_target
+                .store_object(&mut repo, chunker, &archive, node)
+                .await
+                .unwrap();
+        }
+        archive.set_listing(input_target.backup_listing().await).await;
+
+        let output_target =
+            FileSystemTarget::load_listing(output_dir.to_str().unwrap(), archive.listing().await)
+                .await;
+        let nodes = output_target
+            .restore_listing()
+            .await
+            .into_iter()
+            .collect::<Vec<_>>();
+        let expected = nodes.len();
+        let mut restored = 0;
+        output_target
+            .retrieve_objects(&repo, &archive, nodes, 4, |_| restored += 1)
+            .await
+            .unwrap();
+        output_target.finish_restore().await;
+        assert_eq!(restored, expected);
+
+        assert!(!dir_diff::is_different(&input_dir, output_dir).unwrap());
+        repo.close().await;
+    });
+}
+
 #[test]
 #[cfg(feature = "sftp")]
 fn backup_restore_no_empty_dirs_sftp() {
@@ -232,3 +278,120 @@ fn backup_restore_no_empty_dirs_sftp() {
         repo.close().await;
     });
 }
+
+// Every file stored through the driver should have its contents hashed, and a restore
+// should fail if the restored contents do not match the hash
+#[test]
+fn backup_restore_object_hashes() {
+    smol::run(async {
+        let input_dir = fs::canonicalize("tests/inputdata/scodev1/").unwrap();
+        let output_tempdir = tempdir().unwrap();
+        let output_dir = output_tempdir.path();
+
+        let key = Key::random(32);
+        let mut repo = common::get_repo_mem(key);
+        let chunker = FastCDC::default();
+
+        let mut archive = ActiveArchive::new("test");
+        archive.set_object_hash_algorithm(ObjectHashAlgorithm::Sha256);
+
+        let input_target = FileSystemTarget::new(input_dir.to_str().unwrap());
+        let paths = input_target.backup_paths().await;
+        for node in paths {
+            input_target
+                .store_object(&mut repo, chunker, &archive, node)
+                .await
+                .unwrap();
+        }
+
+        let listing = input_target.backup_listing().await;
+        archive.set_listing(listing).await;
+
+        let mut manifest = Manifest::load(&repo);
+        manifest.commit_archive(&mut repo, archive).await.unwrap();
+        repo.commit_index().await;
+
+        let mut manifest = Manifest::load(&repo);
+        let stored_archive = &manifest.archives().await[0];
+        let archive = stored_archive.load(&mut repo).await.unwrap();
+
+        let mut listing = archive.listing().await;
+        for node in listing.clone() {
+            if node.is_file() {
+                let hash = node.hash.expect("File stored without a hash");
+                assert_eq!(hash.algorithm, ObjectHashAlgorithm::Sha256);
+                assert_eq!(hash.digest.len(), 32);
+            } else {
+                assert_eq!(node.hash, None);
+            }
+        }
+
+        // Tamper with the hash of one file
+        let tampered = listing
+            .clone()
+            .into_iter()
+            .find(|node| node.is_file() && node.total_length > 0)
+            .unwrap()
+            .path;
+        listing
+            .get_mut(&tampered)
+            .unwrap()
+            .hash
+            .as_mut()
+            .unwrap()
+            .digest[0] ^= 1;
+        archive.set_listing(listing.clone()).await;
+
+        let output_target =
+            FileSystemTarget::load_listing(output_dir.to_str().unwrap(), listing).await;
+        let paths = output_target.restore_listing().await;
+        for node in paths {
+            let path = node.path.clone();
+            let result = output_target
+                .retrieve_object(&mut repo, &archive, node)
+                .await;
+            if path == tampered {
+                match result {
+                    Err(DriverError::HashMismatch(mismatched)) => assert_eq!(mismatched, path),
+                    other => panic!("Tampered object restored with {:?}", other),
+                }
+            } else {
+                result.unwrap();
+            }
+        }
+        repo.close().await;
+    });
+}
+
+// A file that disappears between being listed and being stored is reported, and left out
+// of the archive, without stopping the rest of the backup
+#[test]
+fn vanished_file_is_reported() {
+    smol::run(async {
+        let input_dir = tempdir().unwrap();
+        fs::write(input_dir.path().join("kept"), b"still here").unwrap();
+        fs::write(input_dir.path().join("vanished"), b"about to go").unwrap();
+        let mut repo = common::get_repo_mem(Key::random(32));
+        let chunker = FastCDC::default();
+        let archive = ActiveArchive::new("test");
+
+        let input_target = FileSystemTarget::new(input_dir.path().to_str().unwrap());
+        let paths = input_target.backup_paths().await;
+        fs::remove_file(input_dir.path().join("vanished")).unwrap();
+        let mut failed = Vec::new();
+        for node in paths {
+            let report = input_target
+                .store_object(&mut repo, chunker, &archive, node)
+                .await
+                .unwrap();
+            failed.extend(report.failed);
+        }
+
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].path, "vanished");
+        assert!(matches!(failed[0].reason, SkipReason::Failed(_)));
+        let listing = input_target.backup_listing().await;
+        assert!(listing.iter().all(|x| x.path != "vanished"));
+        assert!(listing.iter().any(|x| x.path == "kept"));
+    });
+}
@@ -0,0 +1,89 @@
+use asuran::repository::backend::multifile::index::Index;
+use asuran::repository::backend::{Durability, Index as _, SegmentDescriptor};
+use asuran::repository::ChunkID;
+use tempfile::tempdir;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Wraps the system allocator, keeping track of the most memory allocated at any one time
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Returns the most memory allocated at any one time while running `f`, on top of what was
+/// already allocated beforehand
+async fn peak_during<F: std::future::Future<Output = ()>>(f: F) -> usize {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    f.await;
+    PEAK.load(Ordering::SeqCst) - before
+}
+
+const CHUNKS: u64 = 200_000;
+
+fn descriptor(i: u64) -> SegmentDescriptor {
+    SegmentDescriptor {
+        segment_id: i / 1000,
+        start: i,
+    }
+}
+
+/// Opens the index in the given mode and looks up every chunk, returning the peak memory use
+async fn open_and_look_up(path: &std::path::Path, ids: &[ChunkID], paged: bool) -> usize {
+    peak_during(async {
+        let mut index = Index::open(path, 4, false, paged, Durability::default()).unwrap();
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(index.lookup_chunk(*id).await, Some(descriptor(i as u64)));
+        }
+        assert_eq!(index.count_chunk().await, ids.len());
+        index.close().await;
+    })
+    .await
+}
+
+// Everything in this file shares the counting allocator, so this is the only test in it
+#[test]
+fn paged_index_bounds_memory() {
+    smol::run(async {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path();
+        let ids = (0..CHUNKS)
+            .map(|_| ChunkID::random_id())
+            .collect::<Vec<_>>();
+        let mut index = Index::open(path, 4, false, false, Durability::default()).unwrap();
+        for (i, id) in ids.iter().enumerate() {
+            index.set_chunk(*id, descriptor(i as u64)).await.unwrap();
+        }
+        index.commit_index().await.unwrap();
+        index.close().await;
+
+        let full = open_and_look_up(path, &ids, false).await;
+        let paged = open_and_look_up(path, &ids, true).await;
+        println!(
+            "Peak memory use, full: {} bytes, paged: {} bytes",
+            full, paged
+        );
+        // Holding every location takes at least 48 bytes per chunk, the pages only need a
+        // couple of megabytes for the cache, filter, and the shard being built
+        assert!(full > 48 * CHUNKS as usize);
+        assert!(paged < 4 * 1024 * 1024);
+    });
+}
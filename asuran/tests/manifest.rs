@@ -177,3 +177,33 @@ fn put_drop_get_sftp() {
         repo.close().await;
     });
 }
+
+// FlatFile manifests do not record the names of archives, so resolving a name has to look
+// inside of the archives
+#[test]
+fn resolve_flatfile_names() {
+    smol::run(async {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("test.asuran");
+        let key = Key::random(32);
+        let enc_key = EncryptedKey::encrypt(&key, 512, 1, Encryption::new_aes256ctr(), b"");
+        let mut repo = common::get_repo_flat(&path, key.clone(), Some(enc_key));
+        let mut manifest = Manifest::load(&repo);
+        for name in &["first", "second"] {
+            let archive = ActiveArchive::new(name);
+            manifest.commit_archive(&mut repo, archive).await.unwrap();
+        }
+        repo.close().await;
+
+        let mut repo = common::get_repo_flat(&path, key, None);
+        let mut manifest = Manifest::load(&repo);
+        let archives = manifest.archives().await;
+        assert!(archives.iter().all(|x| x.name().is_empty()));
+        let resolved = manifest.resolve_archive(&mut repo, "sec").await.unwrap();
+        // The entry comes back as the manifest recorded it
+        assert_eq!(resolved.name(), "");
+        let archive = resolved.load(&mut repo).await.unwrap();
+        assert_eq!(archive.name(), "second");
+        repo.close().await;
+    });
+}
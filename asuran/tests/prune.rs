@@ -0,0 +1,243 @@
+use asuran::chunker::*;
+use asuran::manifest::integrity::verify_integrity;
+use asuran::manifest::retention::RetentionPolicy;
+use asuran::manifest::*;
+use asuran::repository::backend::multifile::{MultiFileConfig, REFERENCE_COUNTS};
+use asuran::repository::backend::Manifest as BackendManifest;
+use asuran::repository::*;
+use asuran::time::Timestamp;
+use rand::prelude::*;
+use std::collections::HashSet;
+use std::io::Cursor;
+use tempfile::tempdir;
+
+mod common;
+
+fn random_object() -> Vec<u8> {
+    let mut object = vec![0_u8; 16384];
+    thread_rng().fill_bytes(&mut object);
+    object
+}
+
+// Pruning should remove the archives the policy does not keep, and exactly the chunks only
+// they referred to, leaving the remaining archives intact
+#[test]
+fn prune_multifile() {
+    smol::run(async {
+        let tempdir = tempdir().unwrap();
+        let root_path = tempdir.path().to_str().unwrap();
+        let key = Key::random(32);
+        let mut repo = common::get_repo_bare(root_path, key.clone()).await;
+        repo.self_test().await.unwrap();
+        let chunker = FastCDC::default();
+        let shared = random_object();
+        let mut unique = Vec::new();
+        let mut manifest = Manifest::load(&repo);
+        manifest
+            .set_chunk_settings(repo.chunk_settings())
+            .await
+            .unwrap();
+        for i in 0..3 {
+            let object = random_object();
+            let mut archive = ActiveArchive::new(&i.to_string());
+            archive
+                .put_object(&chunker, &mut repo, "shared", Cursor::new(shared.clone()))
+                .await
+                .unwrap();
+            archive
+                .put_object(&chunker, &mut repo, "unique", Cursor::new(object.clone()))
+                .await
+                .unwrap();
+            unique.push((archive.chunk_locations("unique").unwrap(), object));
+            manifest.commit_archive(&mut repo, archive).await.unwrap();
+            smol::Timer::after(std::time::Duration::from_millis(5)).await;
+        }
+        let chunks_before = repo.count_chunk().await;
+
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            ..RetentionPolicy::default()
+        };
+        let now = Timestamp::now();
+        let selection = policy.select(&manifest.archives().await, now);
+        assert_eq!(selection.keep[0].0.name(), "2");
+        let stats = manifest.prune(&mut repo, &selection.remove).await.unwrap();
+        assert_eq!(stats.archives_removed, 2);
+        assert_eq!(stats.archives_kept, 1);
+        let removed_chunks = unique[..2]
+            .iter()
+            .map(|(locations, _)| locations.len())
+            .sum::<usize>()
+            // Along with the archives themselves
+            + 2;
+        assert_eq!(stats.chunks_removed, removed_chunks);
+        assert_eq!(repo.count_chunk().await, chunks_before - removed_chunks);
+        for (locations, _) in &unique[..2] {
+            for location in locations {
+                assert!(!repo.has_chunk(location.id).await);
+            }
+        }
+        // Pruning again has nothing left to do
+        let stats = manifest.prune(&mut repo, &[]).await.unwrap();
+        assert_eq!(stats.chunks_removed, 0);
+        repo.close().await;
+
+        let mut repo = common::get_repo_bare(root_path, key).await;
+        repo.self_test().await.unwrap();
+        let mut manifest = Manifest::load(&repo);
+        let archives = manifest.archives().await;
+        assert_eq!(archives.len(), 1);
+        let archive = archives[0].load(&mut repo).await.unwrap();
+        for (path, expected) in &[("shared", &shared), ("unique", &unique[2].1)] {
+            let mut buffer = Cursor::new(Vec::<u8>::new());
+            archive
+                .get_object(&mut repo, path, &mut buffer)
+                .await
+                .unwrap();
+            assert_eq!(&buffer.into_inner(), *expected);
+        }
+        repo.close().await;
+    });
+}
+
+// Pruning should keep the chunks covered by the integrity records of the remaining archives,
+// while chunks removed behind the manifest's back should be reported as missing
+#[test]
+fn prune_integrity() {
+    smol::run(async {
+        let tempdir = tempdir().unwrap();
+        let root_path = tempdir.path().to_str().unwrap();
+        let key = Key::random(32);
+        let mut repo = common::get_repo_bare(root_path, key.clone()).await;
+        repo.record_integrity();
+        let chunker = FastCDC::default();
+        let mut manifest = Manifest::load(&repo);
+        for i in 0..3 {
+            let mut archive = ActiveArchive::new(&i.to_string());
+            archive
+                .put_object(&chunker, &mut repo, "object", Cursor::new(random_object()))
+                .await
+                .unwrap();
+            manifest.commit_archive(&mut repo, archive).await.unwrap();
+            smol::Timer::after(std::time::Duration::from_millis(5)).await;
+        }
+        let archives = manifest.archives().await;
+        manifest.prune(&mut repo, &archives[1..]).await.unwrap();
+        let archives = manifest.archives().await;
+        assert_eq!(archives.len(), 1);
+        let report = verify_integrity(&mut repo, &archives).await.unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.archives_checked, 1);
+
+        let integrity = archives[0].integrity().unwrap();
+        let chunks = integrity
+            .load_listing(&mut repo, archives[0].id())
+            .await
+            .unwrap()
+            .unwrap();
+        let removed = chunks[0].0;
+        let mut live = repo.known_chunks().await;
+        live.remove(&removed);
+        repo.collect_garbage(&live).await.unwrap();
+        let report = verify_integrity(&mut repo, &archives).await.unwrap();
+        assert_eq!(report.missing, vec![removed]);
+        repo.close().await;
+    });
+}
+
+// Once a full prune has established the reference counts, pruning should only have to load the
+// archives being removed, and still remove exactly the chunks only they referred to. An archive
+// committed without being counted should make pruning fall back to a full collection.
+#[test]
+fn prune_reference_counts() {
+    smol::run(async {
+        let tempdir = tempdir().unwrap();
+        let root_path = tempdir.path().to_str().unwrap();
+        let key = Key::random(32);
+        let mut repo = common::get_repo_bare(root_path, key.clone()).await;
+        let chunker = FastCDC::default();
+        let shared = random_object();
+        let mut manifest = Manifest::load(&repo);
+        assert_eq!(repo.counted_archives().await.unwrap(), None);
+        let stats = manifest.prune(&mut repo, &[]).await.unwrap();
+        assert!(stats.full_collection);
+        assert_eq!(repo.counted_archives().await.unwrap(), Some(HashSet::new()));
+        let config = MultiFileConfig::load(root_path).unwrap();
+        assert!(config.features.contains(REFERENCE_COUNTS));
+
+        let mut unique = Vec::new();
+        for i in 0..3 {
+            let mut archive = ActiveArchive::new(&i.to_string());
+            archive
+                .put_object(&chunker, &mut repo, "shared", Cursor::new(shared.clone()))
+                .await
+                .unwrap();
+            archive
+                .put_object(&chunker, &mut repo, "unique", Cursor::new(random_object()))
+                .await
+                .unwrap();
+            unique.push(archive.chunk_locations("unique").unwrap());
+            manifest.commit_archive(&mut repo, archive).await.unwrap();
+            smol::Timer::after(std::time::Duration::from_millis(5)).await;
+        }
+        let archives = manifest.archives().await;
+        let counted = repo.counted_archives().await.unwrap().unwrap();
+        assert_eq!(counted, archives.iter().map(StoredArchive::id).collect());
+        let named = |name: &str| {
+            archives
+                .iter()
+                .find(|archive| archive.name() == name)
+                .cloned()
+                .unwrap()
+        };
+
+        let chunks_before = repo.count_chunk().await;
+        let stats = manifest.prune(&mut repo, &[named("0")]).await.unwrap();
+        assert!(!stats.full_collection);
+        assert_eq!(stats.archives_removed, 1);
+        assert_eq!(stats.archives_kept, 2);
+        // Along with the archive itself
+        assert_eq!(stats.chunks_removed, unique[0].len() + 1);
+        assert_eq!(
+            repo.count_chunk().await,
+            chunks_before - stats.chunks_removed
+        );
+        for location in &unique[0] {
+            assert!(!repo.has_chunk(location.id).await);
+        }
+        repo.close().await;
+
+        // The counts survive reopening the repository
+        let mut repo = common::get_repo_bare(root_path, key.clone()).await;
+        let mut manifest = Manifest::load(&repo);
+        assert_eq!(repo.counted_archives().await.unwrap().unwrap().len(), 2);
+        // An archive written straight to the manifest is not counted
+        let mut archive = ActiveArchive::new("uncounted");
+        archive
+            .put_object(&chunker, &mut repo, "shared", Cursor::new(shared.clone()))
+            .await
+            .unwrap();
+        let stored = archive.store(&mut repo).await;
+        repo.backend_manifest().write_archive(stored).await.unwrap();
+        let stats = manifest.prune(&mut repo, &[named("1")]).await.unwrap();
+        assert!(stats.full_collection);
+        assert_eq!(stats.archives_removed, 1);
+        assert_eq!(repo.counted_archives().await.unwrap().unwrap().len(), 2);
+        let stats = manifest.prune(&mut repo, &[named("2")]).await.unwrap();
+        assert!(!stats.full_collection);
+        for location in unique[1].iter().chain(&unique[2]) {
+            assert!(!repo.has_chunk(location.id).await);
+        }
+        // The uncounted archive still has everything it refers to
+        let archives = manifest.archives().await;
+        assert_eq!(archives.len(), 1);
+        let archive = archives[0].load(&mut repo).await.unwrap();
+        let mut buffer = Cursor::new(Vec::<u8>::new());
+        archive
+            .get_object(&mut repo, "shared", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer.into_inner(), shared);
+        repo.close().await;
+    });
+}
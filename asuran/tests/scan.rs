@@ -0,0 +1,123 @@
+use asuran::chunker::*;
+use asuran::manifest::driver::*;
+use asuran::manifest::scan::*;
+use asuran::manifest::target::filesystem::*;
+use asuran::manifest::target::*;
+use asuran::manifest::*;
+use asuran::repository::*;
+use std::fs;
+
+mod common;
+
+/// Counts the bytes in each file, vetoing any file named `hmac.src`, and tagging all
+/// others with their length
+struct CountingHook;
+
+struct CountingScanner {
+    path: String,
+    count: usize,
+}
+
+impl FileScanner for CountingScanner {
+    fn update(&mut self, data: &[u8]) {
+        self.count += data.len();
+    }
+    fn finish(&mut self) -> ScanVerdict {
+        if self.path.ends_with("hmac.src") {
+            ScanVerdict::Veto("Forbidden file".to_string())
+        } else {
+            ScanVerdict::Tag(vec![format!("length={}", self.count)])
+        }
+    }
+}
+
+impl ScanHook for CountingHook {
+    fn begin(&self, node: &Node) -> Option<Box<dyn FileScanner>> {
+        Some(Box::new(CountingScanner {
+            path: node.path.clone(),
+            count: 0,
+        }))
+    }
+}
+
+#[test]
+fn backup_scanned() {
+    smol::run(async {
+        let input_dir = fs::canonicalize("tests/inputdata/scodev1/").unwrap();
+
+        let key = Key::random(32);
+        let mut repo = common::get_repo_mem(key);
+        let chunker = FastCDC::default();
+
+        let archive = ActiveArchive::new("test");
+
+        let input_target = FileSystemTarget::new(input_dir.to_str().unwrap());
+        let paths = input_target.backup_paths().await;
+        let input_files = paths.iter().filter(|x| x.is_file()).count();
+        let mut vetoed = Vec::new();
+        for node in paths {
+            let path = node.path.clone();
+            let (verdict, _) = input_target
+                .store_object_scanned(&mut repo, chunker, &archive, node, &CountingHook)
+                .await
+                .unwrap();
+            if let ScanVerdict::Veto(_) = verdict {
+                vetoed.push(path);
+            }
+        }
+        assert_eq!(vetoed, vec!["repository/hmac.src".to_string()]);
+
+        let mut listing = input_target.backup_listing().await;
+        for path in &vetoed {
+            listing.remove(path);
+        }
+        archive.set_listing(listing).await;
+
+        let mut manifest = Manifest::load(&repo);
+        manifest.commit_archive(&mut repo, archive).await.unwrap();
+
+        let mut manifest = Manifest::load(&repo);
+        let stored_archive = &manifest.archives().await[0];
+        let archive = stored_archive.load(&mut repo).await.unwrap();
+
+        let listing = archive.listing().await;
+        let mut files = 0;
+        for node in listing.iter().filter(|x| x.is_file()) {
+            files += 1;
+            // Each stored file should have been tagged with its full length
+            let length = fs::metadata(input_dir.join(&node.path)).unwrap().len();
+            let tags = read_tags(&mut repo, &archive, &node.path)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(tags, vec![format!("length={}", length)]);
+            // And its contents should still be intact
+            let mut contents = Vec::new();
+            archive
+                .namespace_append("")
+                .get_object(&mut repo, &node.path, &mut contents)
+                .await
+                .unwrap();
+            assert_eq!(contents, fs::read(input_dir.join(&node.path)).unwrap());
+        }
+        // Every file but the vetoed one should have been stored
+        assert_eq!(files, input_files - vetoed.len());
+
+        // The vetoed file should not be present in the listing or the archive
+        assert!(!listing.iter().any(|x| x.path == "repository/hmac.src"));
+        let mut contents = Vec::new();
+        archive
+            .namespace_append("")
+            .get_object(&mut repo, "repository/hmac.src", &mut contents)
+            .await
+            .unwrap();
+        assert!(contents.is_empty());
+        assert_eq!(
+            read_tags(&mut repo, &archive, "repository/hmac.src")
+                .await
+                .unwrap(),
+            None
+        );
+        repo.close().await;
+    });
+}
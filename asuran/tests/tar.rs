@@ -0,0 +1,54 @@
+use asuran::chunker::*;
+use asuran::interop::tar::export_archive;
+use asuran::manifest::driver::*;
+use asuran::manifest::target::filesystem::*;
+use asuran::manifest::target::*;
+use asuran::manifest::*;
+use asuran::repository::*;
+use std::fs;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn backup_export_tar() {
+    smol::run(async {
+        let input_dir = fs::canonicalize("tests/inputdata/scodev1/").unwrap();
+        let output_tempdir = tempdir().unwrap();
+        let output_dir = output_tempdir.path();
+
+        let key = Key::random(32);
+        let mut repo = common::get_repo_mem(key);
+        let chunker = FastCDC::default();
+
+        let archive = ActiveArchive::new("test");
+
+        let input_target = FileSystemTarget::new(input_dir.to_str().unwrap());
+        let paths = input_target.backup_paths().await;
+        for node in paths {
+            input_target
+                .store_object(&mut repo, chunker, &archive, node)
+                .await
+                .unwrap();
+        }
+
+        let listing = input_target.backup_listing().await;
+        archive.set_listing(listing).await;
+
+        let mut manifest = Manifest::load(&repo);
+        manifest.commit_archive(&mut repo, archive).await.unwrap();
+
+        let mut manifest = Manifest::load(&repo);
+        let stored_archive = &manifest.archives().await[0];
+        let archive = stored_archive.load(&mut repo).await.unwrap();
+
+        let tar_bytes = export_archive(&mut repo, &archive, Vec::new())
+            .await
+            .unwrap();
+        let mut tar_archive = tar::Archive::new(&tar_bytes[..]);
+        tar_archive.unpack(output_dir).unwrap();
+
+        assert!(!dir_diff::is_different(&input_dir, output_dir).unwrap());
+        repo.close().await;
+    });
+}
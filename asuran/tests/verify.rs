@@ -0,0 +1,143 @@
+use asuran::chunker::*;
+use asuran::manifest::driver::*;
+use asuran::manifest::target::filesystem::*;
+use asuran::manifest::target::*;
+use asuran::manifest::verify::*;
+use asuran::manifest::*;
+use asuran::repository::*;
+use rand::prelude::*;
+use std::fs;
+use tempfile::tempdir;
+
+mod common;
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0_u8; len];
+    thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Stores a small directory tree into a fresh archive in the provided repository
+async fn store_tree(repo: &mut Repository<impl BackendClone>) -> ActiveArchive {
+    let tempdir = tempdir().unwrap();
+    let root = tempdir.path();
+    fs::write(root.join("large"), random_bytes(256 * 1024)).unwrap();
+    fs::write(root.join("small"), random_bytes(1000)).unwrap();
+    fs::write(root.join("empty"), b"").unwrap();
+    fs::create_dir(root.join("dir")).unwrap();
+    fs::write(root.join("dir").join("nested"), random_bytes(2000)).unwrap();
+
+    let chunker = FastCDC::default();
+    let archive = ActiveArchive::new("test");
+    let input_target = FileSystemTarget::new(root.to_str().unwrap());
+    let paths = input_target.backup_paths().await;
+    for node in paths {
+        input_target
+            .store_object(repo, chunker, &archive, node)
+            .await
+            .unwrap();
+    }
+    archive
+        .set_listing(input_target.backup_listing().await)
+        .await;
+    archive
+}
+
+#[test]
+fn verify_clean() {
+    smol::run(async {
+        let key = Key::random(32);
+        let mut repo = common::get_repo_mem(key);
+        let archive = store_tree(&mut repo).await;
+
+        let report = verify_archive(&mut repo, &archive).await;
+        assert!(report.is_clean(), "{:?}", report.unreadable);
+        assert_eq!(report.objects_verified, 4);
+        assert_eq!(report.bytes_verified, 256 * 1024 + 1000 + 2000);
+        repo.close().await;
+    });
+}
+
+#[test]
+fn verify_reports_listing_mismatches() {
+    smol::run(async {
+        let key = Key::random(32);
+        let mut repo = common::get_repo_mem(key);
+        let archive = store_tree(&mut repo).await;
+
+        // Claim an object is longer than it is, and add one that was never stored
+        let mut listing = archive.listing().await;
+        let mut small = listing.remove("small").unwrap();
+        small.total_length += 1;
+        let mut ghost = small.clone();
+        ghost.path = "ghost".to_string();
+        listing.add_child("", small);
+        listing.add_child("", ghost);
+        archive.set_listing(listing).await;
+
+        let report = verify_archive(&mut repo, &archive).await;
+        let mut paths = report
+            .unreadable
+            .iter()
+            .map(|x| x.path.as_str())
+            .collect::<Vec<_>>();
+        paths.sort_unstable();
+        assert_eq!(paths, vec!["ghost", "small"]);
+        assert_eq!(report.objects_verified, 5);
+        repo.close().await;
+    });
+}
+
+#[test]
+fn verify_reports_unauthenticated_chunks() {
+    smol::run(async {
+        let settings = ChunkSettings::lightweight();
+        let key = Key::random(32);
+        let backend = asuran::repository::backend::mem::Mem::new(settings, key.clone(), 4);
+        let mut repo = Repository::with(backend.clone(), settings, key, 2).unwrap();
+        let archive = store_tree(&mut repo).await;
+        repo.commit_index().await;
+
+        // Reading the chunks back with the wrong key fails their HMAC
+        let mut wrong_repo = Repository::with(backend, settings, Key::random(32), 2).unwrap();
+        let report = verify_archive(&mut wrong_repo, &archive).await;
+        let mut paths = report
+            .unreadable
+            .iter()
+            .map(|x| x.path.as_str())
+            .collect::<Vec<_>>();
+        paths.sort_unstable();
+        assert_eq!(paths, vec!["dir/nested", "large", "small"]);
+        repo.close().await;
+    });
+}
+
+#[test]
+fn verify_reports_hash_mismatches() {
+    smol::run(async {
+        let key = Key::random(32);
+        let mut repo = common::get_repo_mem(key);
+        let archive = store_tree(&mut repo).await;
+
+        // Swap the hashes of two objects, on a copy of the archive without the hashes
+        // recorded while storing it
+        let archive = ActiveArchive::from_archive(archive.into_archive().await);
+        let mut listing = archive.listing().await;
+        let small = listing.get("small").unwrap().hash.clone();
+        let nested = listing.get("dir/nested").unwrap().hash.clone();
+        assert!(small.is_some() && nested.is_some());
+        listing.get_mut("small").unwrap().hash = nested;
+        listing.get_mut("dir/nested").unwrap().hash = small;
+        archive.set_listing(listing).await;
+
+        let report = verify_archive(&mut repo, &archive).await;
+        let mut paths = report
+            .unreadable
+            .iter()
+            .map(|x| x.path.as_str())
+            .collect::<Vec<_>>();
+        paths.sort_unstable();
+        assert_eq!(paths, vec!["dir/nested", "small"]);
+        repo.close().await;
+    });
+}